@@ -8,11 +8,38 @@ use anyhow::{Context, Result};
 use ghanima_config::KeyboardConfig;
 
 /// Generate build metadata file that is then included in code
-fn build_metadata() -> Result<()> {
+fn build_metadata(out: &Path) -> Result<()> {
     built::write_built_file()?;
+
+    // "built" only provides build time with chrono, which we do not need otherwise
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let secs = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().context("Invalid SOURCE_DATE_EPOCH")?,
+        Err(_) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    };
+    let (y, m, d) = civil_from_days((secs / 86400) as i64);
+    std::fs::write(out.join("build_date.rs"), format!("\"{:04}-{:02}-{:02}\"", y, m, d))
+        .context("While generating build_date.rs")?;
     Ok(())
 }
 
+/// Convert days since Unix epoch to (year, month, day), see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
 // Copies the `memory.x` file from the crate root into a directory where
 // the linker can always find it at build time.
 fn memory(out: &Path) -> Result<()> {
@@ -34,6 +61,25 @@ fn memory(out: &Path) -> Result<()> {
     Ok(())
 }
 
+/// FNV-1a hash, used as a simple configuration checksum
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, b| (hash ^ *b as u32).wrapping_mul(0x0100_0193))
+}
+
+fn config_checksum(out: &Path, source: &Path) -> Result<()> {
+    println!("cargo:rerun-if-changed={}", source.display());
+    let data = std::fs::read(source)
+        .context(format!("While reading {}", source.display()))?;
+    std::fs::write(out.join("config_checksum.rs"), format!("0x{:08x}", fnv1a(&data)))
+        .context("While generating config_checksum.rs")?;
+    let name = source.file_stem()
+        .and_then(|s| s.to_str())
+        .context("Invalid configuration file name")?;
+    std::fs::write(out.join("config_name.rs"), format!("{:?}", name))
+        .context("While generating config_name.rs")?;
+    Ok(())
+}
+
 fn json_config(out: &Path) -> Result<()>  {
     // Generate config schema
     KeyboardConfig::schema_to_file(&out.join("schema.json"))
@@ -41,6 +87,11 @@ fn json_config(out: &Path) -> Result<()>  {
     KeyboardConfig::schema_to_file(Path::new("./schema.json"))
         .context("While generating JSON schema")?;
 
+    // Code generation and schema depend on the config crate, which is a path dependency
+    // and would otherwise only trigger rebuilds through cargo's dependency tracking
+    println!("cargo:rerun-if-changed=ghanima-config/src");
+    println!("cargo:rerun-if-changed=ghanima-config/Cargo.toml");
+
     // Generate config from JSON if enabled
     println!("cargo:rerun-if-env-changed=CARGO_FEATURE_JSON_CONFIG");
     println!("cargo:rerun-if-env-changed=GHANIMA_JSON_CONFIG");
@@ -62,16 +113,20 @@ fn json_config(out: &Path) -> Result<()>  {
         config.to_file(&out.join("config.rs"))
             // .context(format!("With config:\n{:#?}", config))
             .context("While generating config.rs")?;
-    } else if env::var_os("GHANIMA_JSON_CONFIG").is_some() {
-        println!("cargo:warning=GHANIMA_JSON_CONFIG defined but ignored because feature \"json-config\" is not enabled");
+        config_checksum(out, json)?;
+    } else {
+        if env::var_os("GHANIMA_JSON_CONFIG").is_some() {
+            println!("cargo:warning=GHANIMA_JSON_CONFIG defined but ignored because feature \"json-config\" is not enabled");
+        }
+        config_checksum(out, Path::new("src/config.rs"))?;
     }
 
     Ok(())
 }
 
 fn main() -> Result<()>  {
-    build_metadata()?;
     let out = &PathBuf::from(env::var_os("OUT_DIR").context("Could not get OUT_DIR")?);
+    build_metadata(out)?;
     memory(out)?;
     json_config(out)?;
     Ok(())
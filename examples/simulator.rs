@@ -0,0 +1,107 @@
+use std::env;
+use std::fs;
+use std::io::Read;
+
+use ghanima::bsp::sides::BoardSide;
+use ghanima::config::{CONFIG, N_LAYERS};
+use ghanima::keyboard::sim::{Sim, Step};
+
+// Run with: just sim [SCRIPT]
+// Script is read from stdin when no file is given, see `Step::parse` for the syntax, e.g.
+//   usb left configured
+//   tick 50
+//   press 1 7
+//   tick 20
+//   release 1 7
+//   tick 20
+
+// Keyboard logic uses defmt, logs are discarded
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u32}", 0);
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+fn side_name(side: BoardSide) -> &'static str {
+    match side {
+        BoardSide::Left => "L",
+        BoardSide::Right => "R",
+    }
+}
+
+/// Print changes that occurred since the last call
+struct Printer {
+    master: Option<BoardSide>,
+    reports: [usize; 2],
+}
+
+impl Printer {
+    fn print(&mut self, sim: &Sim<N_LAYERS>) {
+        let master = sim.master();
+        if master != self.master {
+            println!("[{:6}] master: {}", sim.time(), master.map(side_name).unwrap_or("none"));
+            self.master = master;
+        }
+        for (i, side) in BoardSide::EACH.into_iter().enumerate() {
+            let reports = &sim.halves[side].usb.keyboard_reports;
+            // Reports are written on every tick, only show changes
+            for (n, report) in reports.iter().enumerate().skip(self.reports[i]) {
+                if n == 0 || reports[n - 1] != *report {
+                    println!("[{:6}] {} keyboard: {:?}", sim.time(), side_name(side), report);
+                }
+            }
+            self.reports[i] = reports.len();
+        }
+    }
+}
+
+fn main() {
+    let script = match env::args().nth(1) {
+        Some(path) => fs::read_to_string(path).expect("Could not read script"),
+        None => {
+            let mut script = String::new();
+            std::io::stdin().read_to_string(&mut script).expect("Could not read stdin");
+            script
+        },
+    };
+
+    let mut sim = Sim::new(&CONFIG);
+    let mut printer = Printer { master: None, reports: [0; 2] };
+
+    for (i, line) in script.lines().enumerate() {
+        let step = match Step::parse(line) {
+            Ok(Some(step)) => step,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Line {}: {}: {:?}", i + 1, e, line);
+                std::process::exit(1);
+            },
+        };
+        match step {
+            // Run tick by tick to print changes with correct time
+            Step::Tick(n) => for _ in 0..n {
+                sim.tick();
+                printer.print(&sim);
+            },
+            step => sim.step(&step),
+        }
+    }
+
+    for side in BoardSide::EACH {
+        let colors: Vec<_> = sim.halves[side].colors().iter()
+            .map(|c| format!("{:02x}{:02x}{:02x}", c.r, c.g, c.b))
+            .collect();
+        println!("{} LEDs: {}", side_name(side), colors.join(" "));
+    }
+}
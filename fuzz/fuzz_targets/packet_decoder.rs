@@ -0,0 +1,88 @@
+//! Feed arbitrary serial data to the packet decoder used for communication between halves
+//!
+//! First input byte controls the test: lower 7 bits select the size of chunks in which data
+//! is fed (like UART DMA transfers), the highest bit wraps the data in a valid frame (checksum,
+//! COBS encoding, sentinel) so that the fuzzer can reach message deserialization.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use ghanima::hal_ext::{ChecksumGen, crc::Crc};
+use ghanima::ioqueue::{max_packet_size, packet::{Accumulator, FeedResult}};
+use ghanima::keyboard::msg::Message;
+
+const N: usize = max_packet_size::<Message>();
+
+// Keyboard code uses defmt, logs are discarded
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u32}", 0);
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+/// Wrap payload in a frame that passes checksum verification
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut data = payload.to_vec();
+    data.extend_from_slice(&Crc::new_mock().decode(payload).to_le_bytes());
+    let mut frame = vec![0; cobs::max_encoding_length(data.len())];
+    let len = cobs::encode(&data, &mut frame);
+    frame.truncate(len);
+    frame.push(0);
+    frame
+}
+
+fuzz_target!(|input: &[u8]| {
+    let Some((&ctrl, data)) = input.split_first() else {
+        return;
+    };
+
+    // Any data may reach the deserializer if its checksum happens to match
+    let _ = postcard::from_bytes::<Message>(data);
+
+    let framed;
+    let stream = if ctrl & 0x80 != 0 {
+        framed = frame(data);
+        &framed[..]
+    } else {
+        data
+    };
+
+    let mut crc = Crc::new_mock();
+    let mut acc = Accumulator::<N>::new();
+    for chunk in stream.chunks((ctrl & 0x7f) as usize + 1) {
+        let mut window = chunk;
+        // Accumulator is reset when it does not consume anything, so it must progress next time
+        let mut stalled = false;
+        loop {
+            let remaining = match acc.feed::<Message>(&mut crc, window) {
+                FeedResult::Consumed => break,
+                FeedResult::Success { remaining, .. } => remaining,
+                FeedResult::OverFull(remaining)
+                | FeedResult::CobsDecodingError(remaining)
+                | FeedResult::ChecksumError(remaining)
+                | FeedResult::DeserError(remaining) => remaining,
+            };
+            assert!(remaining.len() <= window.len(), "Remaining data longer than input");
+            assert!(window.ends_with(remaining), "Remaining data is not a suffix of input");
+            if remaining.len() == window.len() {
+                assert!(!stalled, "Accumulator does not make progress");
+                stalled = true;
+            } else {
+                stalled = false;
+            }
+            window = remaining;
+        }
+    }
+});
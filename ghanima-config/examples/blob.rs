@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use ghanima_config::KeyboardConfig;
+
+const USAGE: &str = "\
+Usage: blob CONFIG_JSON -o FILE
+
+Serialize layers, mouse and LED configuration to the binary format that is loaded by
+firmware built with feature \"config-blob\" when appended to the firmware image.";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut path = None;
+    let mut out: Option<PathBuf> = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().context(format!("Missing value for {}\n\n{}", arg, USAGE));
+        match arg.as_str() {
+            "-o" => out = Some(value()?.into()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => anyhow::bail!("Unexpected argument: {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    let path = path.context(USAGE)?;
+    let out = out.context(USAGE)?;
+    let config = KeyboardConfig::from_file(&path)
+        .with_context(|| format!("Reading {}", path.display()))?;
+    let blob = config.to_blob()
+        .with_context(|| format!("Serializing {}", path.display()))?;
+    std::fs::write(&out, &blob).with_context(|| format!("Writing {}", out.display()))?;
+    eprintln!("Written {} bytes to {}", blob.len(), out.display());
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use ghanima_config::KeyboardConfig;
+use ghanima_config::layers::svg;
+
+const USAGE: &str = "\
+Usage: keymap CONFIG_JSON [OPTIONS]
+
+Options:
+  --svg N          output only layer N as SVG instead of HTML with all layers
+  -o FILE          write output to file instead of stdout";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut path = None;
+    let mut layer: Option<usize> = None;
+    let mut out: Option<PathBuf> = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().context(format!("Missing value for {}\n\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--svg" => layer = Some(value()?.parse()?),
+            "-o" => out = Some(value()?.into()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => anyhow::bail!("Unexpected argument: {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    let path = path.context(USAGE)?;
+    let config = KeyboardConfig::from_file(&path)
+        .with_context(|| format!("Reading {}", path.display()))?;
+    let layers = config.layers();
+
+    let output = match layer {
+        Some(i) if i >= layers.len() => anyhow::bail!("No layer with index {}", i),
+        Some(i) => svg::layer_svg(layers, i),
+        None => svg::layers_html(layers),
+    };
+    match out {
+        Some(out) => std::fs::write(&out, output).with_context(|| format!("Writing {}", out.display()))?,
+        None => print!("{}", output),
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use ghanima_config::KeyboardConfig;
+use ghanima_config::leds::preview;
+
+const USAGE: &str = "\
+Usage: led_preview CONFIG_JSON [OPTIONS]
+
+Options:
+  --config N       index of LED configuration (default 0)
+  --duration MS    length of the timeline (default 5000)
+  --step MS        time per character/cell (default 50)
+  --at X,Y         key position in mm used for pattern phase (default 0,0)
+  --ppm FILE       write PPM image instead of ASCII timeline
+  --cell N         size of PPM image cell in pixels (default 8)";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut path = None;
+    let mut index = 0;
+    let mut duration = 5000;
+    let mut step = 50;
+    let mut at = (0.0, 0.0);
+    let mut ppm: Option<PathBuf> = None;
+    let mut cell = 8;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().context(format!("Missing value for {}\n\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--config" => index = value()?.parse()?,
+            "--duration" => duration = value()?.parse()?,
+            "--step" => step = value()?.parse()?,
+            "--at" => {
+                let value = value()?;
+                let (x, y) = value.split_once(',')
+                    .with_context(|| format!("Invalid position: {}\n\n{}", value, USAGE))?;
+                at = (x.trim().parse()?, y.trim().parse()?);
+            },
+            "--ppm" => ppm = Some(value()?.into()),
+            "--cell" => cell = value()?.parse()?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => anyhow::bail!("Unexpected argument: {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    let path = path.context(USAGE)?;
+    let config = KeyboardConfig::from_file(&path)
+        .with_context(|| format!("Reading {}", path.display()))?;
+    let leds = config.leds().get(index)
+        .with_context(|| format!("No LED configuration with index {}", index))?;
+
+    match ppm {
+        Some(out) => std::fs::write(&out, preview::timeline_ppm(leds, at, duration, step, cell))
+            .with_context(|| format!("Writing {}", out.display()))?,
+        None => print!("{}", preview::ascii_timeline(leds, at, duration, step)),
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use ghanima_config::KeyboardConfig;
+use ghanima_config::layers::ascii;
+
+const USAGE: &str = "\
+Usage: preview CONFIG_JSON [OPTIONS]
+
+Options:
+  --layer N        print only layer N instead of all layers";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut path = None;
+    let mut layer: Option<usize> = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().context(format!("Missing value for {}\n\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--layer" => layer = Some(value()?.parse()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ if path.is_none() => path = Some(PathBuf::from(arg)),
+            _ => anyhow::bail!("Unexpected argument: {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    let path = path.context(USAGE)?;
+    let config = KeyboardConfig::from_file(&path)
+        .with_context(|| format!("Reading {}", path.display()))?;
+    let layers = config.layers();
+
+    let output = match layer {
+        Some(i) if i >= layers.len() => anyhow::bail!("No layer with index {}", i),
+        Some(i) => ascii::layer_ascii(layers, i),
+        None => ascii::layers_ascii(layers),
+    };
+    print!("{}", output);
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! Binary configuration format
+//!
+//! Compact serialization of layers, mouse and LED configuration that firmware built with
+//! feature `config-blob` loads from flash at boot, as an alternative to code generation.
+//! This allows to change these parts of configuration without recompiling the firmware,
+//! everything else (timings, features, etc.) still comes from the firmware build.
+//!
+//! | Bytes   | Value                                                  |
+//! |---------|--------------------------------------------------------|
+//! | 0..4    | magic `GCFG`                                           |
+//! | 4       | format version                                         |
+//! | 5       | number of layers                                       |
+//! | 6       | number of rows                                         |
+//! | 7       | number of columns                                      |
+//! | 8..12   | payload length N                                       |
+//! | 12..16  | CRC-32 of payload                                      |
+//! | 16..16+N | payload: layers (row by row), mouse, LED configurations |
+//!
+//! Numbers are little-endian. Enums are encoded as variant index followed by variant fields,
+//! structs as their fields in order of definition and lists are prefixed by length as a
+//! single byte. Key codes use USB HID usage codes.
+
+use anyhow::Context;
+
+use crate::KeyboardConfig;
+
+/// Bytes `GCFG` as little-endian word
+pub const MAGIC: u32 = u32::from_le_bytes(*b"GCFG");
+/// Incremented on incompatible changes of the format
+pub const VERSION: u8 = 6;
+
+/// Binary data being serialized
+#[derive(Default)]
+pub struct Blob {
+    data: Vec<u8>,
+}
+
+/// Serialization to the binary configuration format
+pub trait ToBlob {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()>;
+}
+
+impl Blob {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    /// Enum variant index followed by its field, if any
+    pub fn variant(&mut self, index: u8, field: Option<&dyn ToBlob>) -> anyhow::Result<()> {
+        self.u8(index);
+        field.map_or(Ok(()), |field| field.to_blob(self))
+    }
+
+    /// Length prefix of a list
+    pub fn count(&mut self, len: usize) -> anyhow::Result<()> {
+        let len = u8::try_from(len)
+            .ok()
+            .with_context(|| format!("Too many elements for binary configuration: {} > {}", len, u8::MAX))?;
+        self.u8(len);
+        Ok(())
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl ToBlob for u8 {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        blob.u8(*self);
+        Ok(())
+    }
+}
+
+impl ToBlob for u16 {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        blob.u16(*self);
+        Ok(())
+    }
+}
+
+impl ToBlob for bool {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        blob.u8(*self as u8);
+        Ok(())
+    }
+}
+
+impl ToBlob for f32 {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        blob.u32(self.to_bits());
+        Ok(())
+    }
+}
+
+impl ToBlob for (u8, u8) {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        blob.u8(self.0);
+        blob.u8(self.1);
+        Ok(())
+    }
+}
+
+impl<T: ToBlob> ToBlob for Box<T> {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        self.as_ref().to_blob(blob)
+    }
+}
+
+impl<T: ToBlob> ToBlob for Option<T> {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            None => {
+                blob.u8(0);
+                Ok(())
+            },
+            Some(value) => {
+                blob.u8(1);
+                value.to_blob(blob)
+            },
+        }
+    }
+}
+
+impl<T: ToBlob> ToBlob for Vec<T> {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        blob.count(self.len())?;
+        self.iter().try_for_each(|item| item.to_blob(blob))
+    }
+}
+
+/// CRC-32 (as in zlib), the same as used by firmware for image verification
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |mut crc: u32, b| {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+        crc
+    })
+}
+
+impl KeyboardConfig {
+    /// Serialize layers, mouse and LED configuration to the binary format
+    pub fn to_blob(&self) -> anyhow::Result<Vec<u8>> {
+        let mut payload = Blob::default();
+        // Layers are stored as firmware sees them, the same as in generated code
+        let layers = self.host_layout.translate_layers(&self.layers);
+        for (l, layer) in layers.iter().enumerate() {
+            for (r, row) in layer.iter().enumerate() {
+                for (c, act) in row.iter().enumerate() {
+                    act.to_blob(&mut payload)
+                        .with_context(|| format!("Layer {} row {} col {}", l, r, c))?;
+                }
+            }
+        }
+        self.mouse.translate(self.host_layout).to_blob(&mut payload).context("Mouse configuration")?;
+        self.leds.to_blob(&mut payload).context("LED configurations")?;
+        self.layer_leds.to_blob(&mut payload).context("Layer LED configurations")?;
+        let payload = payload.into_bytes();
+
+        let mut blob = Blob::default();
+        blob.u32(MAGIC);
+        blob.u8(VERSION);
+        for (name, n) in [("layers", self.n_layers()), ("rows", self.n_rows()), ("columns", self.n_cols())] {
+            let n = u8::try_from(n).ok().with_context(|| format!("Too many {}: {}", name, n))?;
+            blob.u8(n);
+        }
+        blob.u32(payload.len() as u32);
+        blob.u32(crc32(&payload));
+        let mut data = blob.into_bytes();
+        data.extend(payload);
+        Ok(data)
+    }
+}
+
+/// Implement ToBlob for a simple enum with variants without data, encoded as variant index
+#[macro_export]
+macro_rules! impl_enum_to_blob {
+    ( $( enum $enum:ident ),* $(,)? ) => {
+        $(
+            impl $crate::blob::ToBlob for $enum {
+                fn to_blob(&self, blob: &mut $crate::blob::Blob) -> anyhow::Result<()> {
+                    blob.u8(self.clone() as u8);
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+/// Implement ToBlob for a regular struct, encoded as the listed fields in order
+#[macro_export]
+macro_rules! impl_struct_to_blob {
+    ( $( struct $struct:ident { $( $field:ident ),* $(,)? } )* ) => {
+        $(
+            impl $crate::blob::ToBlob for $struct {
+                fn to_blob(&self, blob: &mut $crate::blob::Blob) -> anyhow::Result<()> {
+                    $( $crate::blob::ToBlob::to_blob(&self.$field, blob)?; )*
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::example_config;
+
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn header() -> anyhow::Result<()> {
+        let blob = example_config().to_blob()?;
+        assert_eq!(&blob[0..4], b"GCFG");
+        assert_eq!(blob[4..8], [VERSION, 1, 1, 9]);
+        let len = u32::from_le_bytes(blob[8..12].try_into()?);
+        let crc = u32::from_le_bytes(blob[12..16].try_into()?);
+        assert_eq!(len as usize, blob.len() - 16);
+        assert_eq!(crc, crc32(&blob[16..]));
+        Ok(())
+    }
+
+    #[test]
+    fn host_layout() -> anyhow::Result<()> {
+        let mut config = example_config();
+        config.host_layout = crate::layers::host::HostLayout::Azerty;
+        let mut translated = example_config();
+        translated.layers = config.host_layout.translate_layers(&config.layers);
+        translated.mouse = config.mouse.translate(config.host_layout);
+        assert_eq!(config.to_blob()?, translated.to_blob()?);
+        Ok(())
+    }
+
+    #[test]
+    fn primitives() -> anyhow::Result<()> {
+        let mut blob = Blob::default();
+        Some(vec![(1u8, 2u8)]).to_blob(&mut blob)?;
+        None::<u8>.to_blob(&mut blob)?;
+        0x1234u16.to_blob(&mut blob)?;
+        true.to_blob(&mut blob)?;
+        1.0f32.to_blob(&mut blob)?;
+        assert_eq!(blob.into_bytes(), [1, 1, 1, 2, 0, 0x34, 0x12, 1, 0x00, 0x00, 0x80, 0x3f]);
+        assert!(Blob::default().count(256).is_err());
+        Ok(())
+    }
+}
@@ -3,7 +3,8 @@ use quote::{quote, ToTokens, TokenStreamExt};
 use serde::{Serialize, Deserialize};
 use schemars::JsonSchema;
 
-use crate::{impl_enum_to_tokens, impl_enum_tuple_to_tokens};
+use crate::{impl_enum_to_tokens, impl_enum_tuple_to_tokens, impl_enum_to_blob};
+use crate::blob::{Blob, ToBlob};
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
 #[schemars(rename = "CustomAction")]
@@ -15,7 +16,9 @@ pub enum Action {
     /// Send USB HID consumer page keys
     Consumer(ConsumerKey),
     /// Perform special firmware-related actions
-    Firmware(FirmwareAction)
+    Firmware(FirmwareAction),
+    /// Send common shortcuts that use different key chords depending on host OS
+    Shortcut(ShortcutAction),
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -24,6 +27,14 @@ pub enum LedAction {
     Cycle(Inc),
     /// Modify global brightness
     Brightness(Inc),
+    /// Remove all LED color overrides set by host
+    ClearOverrides,
+    /// Toggle night mode (warmer colors with limited brightness)
+    NightMode,
+    BrightnessPreset,
+    Blackout,
+    /// Raise brightness to maximum while held
+    BrightnessBoost,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -34,6 +45,23 @@ pub enum MouseAction {
     Move(MouseMovement),
     /// Key changes mouse sensitivity
     Sensitivity(Inc),
+    /// Key changes joystick sensitivity
+    JoystickSensitivity(Inc),
+    /// Scale down all pointer and scroll movement while held
+    Precision,
+    /// Toggle automatic left click when joystick pointer stops moving
+    DwellClick,
+    /// Use speed profile of given tier for pointer movement keys while held
+    SpeedTier(SpeedTier),
+    /// Switch joystick between pointer movement and scrolling
+    JoystickPlane,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub enum SpeedTier {
+    Slow,
+    Medium,
+    Fast,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -41,6 +69,11 @@ pub enum MouseButton {
     Left,
     Mid,
     Right,
+    Back,
+    Forward,
+    Button6,
+    Button7,
+    Button8,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -61,6 +94,53 @@ pub enum FirmwareAction {
     JumpToBootloader,
     Reboot,
     InfiniteLoop,
+    TypeInfo,
+    ToggleEventLog,
+    KeyStats,
+    WipeKeyStats,
+    KeyTester,
+    KeyTesterTyping,
+    SwapRole,
+    GameMode,
+    ToggleJoystick,
+    ExplainKey,
+    SlowKeys,
+    BounceKeys,
+    Training,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub enum ShortcutAction {
+    /// Send key chord of the shortcut for the current OS profile while held
+    Key(Shortcut),
+    /// Switch to given OS profile
+    Profile(OsProfile),
+    /// Cycle through OS profiles
+    CycleProfile,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub enum Shortcut {
+    Copy,
+    Cut,
+    Paste,
+    Undo,
+    Redo,
+    SelectAll,
+    Save,
+    Find,
+    WordLeft,
+    WordRight,
+    LineStart,
+    LineEnd,
+    DeleteWord,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub enum OsProfile {
+    Windows,
+    MacOs,
+    Linux,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -441,15 +521,81 @@ pub enum Inc {
 impl_enum_to_tokens! {
     enum MouseButton: crate::keyboard::actions::MouseButton,
     enum MouseMovement: crate::keyboard::actions::MouseMovement,
+    enum SpeedTier: crate::keyboard::actions::SpeedTier,
     enum Inc: crate::utils::Inc,
     enum ConsumerKey: usbd_human_interface_device::page::Consumer,
     enum FirmwareAction: crate::keyboard::actions::FirmwareAction,
+    enum Shortcut: crate::keyboard::actions::Shortcut,
+    enum OsProfile: crate::keyboard::shortcuts::OsProfile,
 }
 
 impl_enum_tuple_to_tokens! {
-    enum Action: crate::keyboard::actions::Action { Led(led), Mouse(mouse), Consumer(consumer), Firmware(firmware) }
-    enum LedAction: crate::keyboard::actions::LedAction { Cycle(inc), Brightness(inc) }
-    enum MouseAction: crate::keyboard::actions::MouseAction { Click(button), Move(movement), Sensitivity(inc) }
+    enum Action: crate::keyboard::actions::Action { Led(led), Mouse(mouse), Consumer(consumer), Firmware(firmware), Shortcut(shortcut) }
+    enum LedAction: crate::keyboard::actions::LedAction { Cycle(inc), Brightness(inc), ClearOverrides, NightMode, BrightnessPreset, Blackout, BrightnessBoost }
+    enum MouseAction: crate::keyboard::actions::MouseAction { Click(button), Move(movement), Sensitivity(inc), JoystickSensitivity(inc), Precision, DwellClick, SpeedTier(tier), JoystickPlane }
+    enum ShortcutAction: crate::keyboard::actions::ShortcutAction { Key(shortcut), Profile(profile), CycleProfile }
+}
+
+impl_enum_to_blob! {
+    enum MouseButton,
+    enum MouseMovement,
+    enum SpeedTier,
+    enum Inc,
+    enum FirmwareAction,
+    enum Shortcut,
+    enum OsProfile,
+}
+
+impl ToBlob for Action {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            Action::Led(led) => blob.variant(0, Some(led)),
+            Action::Mouse(mouse) => blob.variant(1, Some(mouse)),
+            // Firmware uses HID usage codes of the consumer page, which are not known here
+            Action::Consumer(key) => anyhow::bail!("Consumer keys are not supported in binary configuration: {:?}", key),
+            Action::Firmware(firmware) => blob.variant(3, Some(firmware)),
+            Action::Shortcut(shortcut) => blob.variant(4, Some(shortcut)),
+        }
+    }
+}
+
+impl ToBlob for LedAction {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            LedAction::Cycle(inc) => blob.variant(0, Some(inc)),
+            LedAction::Brightness(inc) => blob.variant(1, Some(inc)),
+            LedAction::ClearOverrides => blob.variant(2, None),
+            LedAction::NightMode => blob.variant(3, None),
+            LedAction::BrightnessPreset => blob.variant(4, None),
+            LedAction::Blackout => blob.variant(5, None),
+            LedAction::BrightnessBoost => blob.variant(6, None),
+        }
+    }
+}
+
+impl ToBlob for MouseAction {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            MouseAction::Click(button) => blob.variant(0, Some(button)),
+            MouseAction::Move(movement) => blob.variant(1, Some(movement)),
+            MouseAction::Sensitivity(inc) => blob.variant(2, Some(inc)),
+            MouseAction::JoystickSensitivity(inc) => blob.variant(3, Some(inc)),
+            MouseAction::Precision => blob.variant(4, None),
+            MouseAction::DwellClick => blob.variant(5, None),
+            MouseAction::SpeedTier(tier) => blob.variant(6, Some(tier)),
+            MouseAction::JoystickPlane => blob.variant(7, None),
+        }
+    }
+}
+
+impl ToBlob for ShortcutAction {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            ShortcutAction::Key(shortcut) => blob.variant(0, Some(shortcut)),
+            ShortcutAction::Profile(profile) => blob.variant(1, Some(profile)),
+            ShortcutAction::CycleProfile => blob.variant(2, None),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -465,6 +611,8 @@ pub mod tests {
             { "Consumer": "VolumeIncrement" },
             { "Firmware": "AllowBootloader" },
             { "Firmware": "InfiniteLoop" },
+            { "Shortcut": { "Key": "Copy" } },
+            { "Shortcut": { "Profile": "MacOs" } },
         ])
     }
 
@@ -475,6 +623,8 @@ pub mod tests {
             Action::Consumer(ConsumerKey::VolumeIncrement),
             Action::Firmware(FirmwareAction::AllowBootloader),
             Action::Firmware(FirmwareAction::InfiniteLoop),
+            Action::Shortcut(ShortcutAction::Key(Shortcut::Copy)),
+            Action::Shortcut(ShortcutAction::Profile(OsProfile::MacOs)),
         ]
     }
 
@@ -500,6 +650,16 @@ pub mod tests {
                 crate::keyboard::actions::Action::Firmware(
                     crate::keyboard::actions::FirmwareAction::InfiniteLoop
                 ),
+                crate::keyboard::actions::Action::Shortcut(
+                    crate::keyboard::actions::ShortcutAction::Key(
+                        crate::keyboard::actions::Shortcut::Copy
+                    )
+                ),
+                crate::keyboard::actions::Action::Shortcut(
+                    crate::keyboard::actions::ShortcutAction::Profile(
+                        crate::keyboard::shortcuts::OsProfile::MacOs
+                    )
+                ),
             ]
         }
     }
@@ -516,4 +676,18 @@ pub mod tests {
         let q = example_config();
         assert_tokens_eq(quote! { [ #( #q ),* ] }, example_code())
     }
+
+    #[test]
+    fn blob() -> anyhow::Result<()> {
+        let mut blob = Blob::default();
+        Action::Mouse(MouseAction::Click(MouseButton::Back)).to_blob(&mut blob)?;
+        Action::Led(LedAction::Blackout).to_blob(&mut blob)?;
+        Action::Firmware(FirmwareAction::Training).to_blob(&mut blob)?;
+        Action::Shortcut(ShortcutAction::Profile(OsProfile::Linux)).to_blob(&mut blob)?;
+        Action::Led(LedAction::BrightnessBoost).to_blob(&mut blob)?;
+        Action::Mouse(MouseAction::SpeedTier(SpeedTier::Fast)).to_blob(&mut blob)?;
+        assert_eq!(blob.into_bytes(), [1, 0, 3, 0, 5, 3, 16, 4, 1, 2, 0, 6, 1, 6, 2]);
+        assert!(Action::Consumer(ConsumerKey::Mute).to_blob(&mut Blob::default()).is_err());
+        Ok(())
+    }
 }
@@ -1,12 +1,26 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
 use proc_macro2::{TokenStream, Ident, Span, Punct, Spacing};
 use quote::{quote, ToTokens, TokenStreamExt};
 use serde::{Serialize, Deserialize};
 use schemars::JsonSchema;
 
 use super::impl_enum_to_tokens;
+use super::blob::{Blob, ToBlob};
+
+pub mod ascii;
+pub mod host;
+pub mod svg;
 
 pub type Layers<T> = Vec<Vec<Vec<Act<T>>>>;
 
+/// Named actions that can be used in layers as `{ "Alias": "name" }`
+pub type Aliases<T> = BTreeMap<String, Act<T>>;
+
+/// Maximum depth of aliases referring to other aliases, this also detects cycles
+const MAX_ALIAS_DEPTH: usize = 8;
+
 pub fn to_tokens<T: ToTokens>(layers: &Layers<T>) -> TokenStream {
     quote! {
         [ #([ #([ #(#layers),* ]),* ]),* ]
@@ -34,6 +48,8 @@ pub enum Act<T: ToTokens> {
         tap_hold_interval: u16,
     },
     Custom(T),
+    /// Reference to an action from aliases, replaced with the action when loading configuration
+    Alias(String),
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -272,12 +288,183 @@ impl<T: ToTokens> ToTokens for Act<T> {
                     })
                 }
             },
-            Act::Custom(custom) => quote! { #act::Custom(#custom) }
+            Act::Custom(custom) => quote! { #act::Custom(#custom) },
+            Act::Alias(name) => {
+                let msg = format!("Unexpanded alias: {}", name);
+                quote! { compile_error!(#msg) }
+            },
         };
         tokens.append_all(t);
     }
 }
 
+impl ToBlob for KeyCode {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        // Variants follow USB HID usage codes starting from A (0x04) up to ExSel (0xa4),
+        // then modifiers and media keys start at 0xe0
+        let index = self.clone() as u8;
+        let modifiers = KeyCode::LCtrl as u8;
+        blob.u8(if index < modifiers { index + 0x04 } else { index - modifiers + 0xe0 });
+        Ok(())
+    }
+}
+
+impl ToBlob for HoldTapConfig {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            HoldTapConfig::Default => blob.variant(0, None),
+            HoldTapConfig::HoldOnOtherKeyPress => blob.variant(1, None),
+            HoldTapConfig::PermissiveHold => blob.variant(2, None),
+            HoldTapConfig::Custom(f) => anyhow::bail!("Custom HoldTap functions are not supported in binary configuration: {}", f),
+        }
+    }
+}
+
+impl<T: ToTokens + ToBlob> ToBlob for Act<T> {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        let layer = |l: &usize| u8::try_from(*l).with_context(|| format!("Layer out of range: {}", l));
+        match self {
+            Act::NoOp => blob.variant(0, None),
+            Act::Trans => blob.variant(1, None),
+            Act::KeyCode(keycode) => blob.variant(2, Some(keycode)),
+            Act::MultipleKeyCodes(keycodes) => blob.variant(3, Some(keycodes)),
+            Act::MultipleActions(actions) => blob.variant(4, Some(actions)),
+            Act::Layer(l) => blob.variant(5, Some(&layer(l)?)),
+            Act::DefaultLayer(l) => blob.variant(6, Some(&layer(l)?)),
+            Act::HoldTap { timeout, hold, tap, config, tap_hold_interval } => {
+                blob.u8(7);
+                timeout.to_blob(blob)?;
+                hold.to_blob(blob)?;
+                tap.to_blob(blob)?;
+                config.to_blob(blob)?;
+                tap_hold_interval.to_blob(blob)
+            },
+            Act::Custom(custom) => blob.variant(8, Some(custom)),
+            Act::Alias(name) => anyhow::bail!("Unexpanded alias: {}", name),
+        }
+    }
+}
+
+impl<T: ToTokens + Clone> Act<T> {
+    /// Replace aliases in this action (including nested actions) with actions they refer to
+    pub fn expand_aliases(&mut self, aliases: &Aliases<T>) -> anyhow::Result<()> {
+        self.expand(aliases, 0)
+    }
+
+    fn expand(&mut self, aliases: &Aliases<T>, depth: usize) -> anyhow::Result<()> {
+        match self {
+            Act::Alias(name) => {
+                anyhow::ensure!(depth < MAX_ALIAS_DEPTH, "Alias nesting too deep (cycle?): {}", name);
+                let mut act = aliases.get(name.as_str())
+                    .with_context(|| format!("Unknown alias: {}", name))?
+                    .clone();
+                act.expand(aliases, depth + 1)
+                    .with_context(|| format!("In alias: {}", name))?;
+                *self = act;
+            },
+            Act::MultipleActions(actions) => {
+                for act in actions {
+                    act.expand(aliases, depth)?;
+                }
+            },
+            Act::HoldTap { hold, tap, .. } => {
+                hold.expand(aliases, depth)?;
+                tap.expand(aliases, depth)?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+/// Replace aliases in all layers
+pub fn expand_aliases<T: ToTokens + Clone>(layers: &mut Layers<T>, aliases: &Aliases<T>) -> anyhow::Result<()> {
+    for (l, layer) in layers.iter_mut().enumerate() {
+        for (r, row) in layer.iter_mut().enumerate() {
+            for (c, act) in row.iter_mut().enumerate() {
+                act.expand_aliases(aliases)
+                    .with_context(|| format!("Layer {} row {} col {}", l, r, c))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<T: ToTokens> Act<T> {
+    /// Call `f` for this action and all nested actions
+    pub fn try_for_each<F>(&self, f: &mut F) -> anyhow::Result<()>
+    where
+        F: FnMut(&Act<T>) -> anyhow::Result<()>,
+    {
+        f(self)?;
+        match self {
+            Act::MultipleActions(actions) => actions.iter().try_for_each(|act| act.try_for_each(f)),
+            Act::HoldTap { hold, tap, .. } => {
+                hold.try_for_each(f)?;
+                tap.try_for_each(f)
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Number of actions and key codes stored outside of the layers array
+///
+/// These are referenced by multiple-action, multiple-key-code and hold-tap actions.
+pub fn nested_counts<T: ToTokens>(layers: &Layers<T>) -> (usize, usize) {
+    let (mut actions, mut keycodes) = (0, 0);
+    for act in layers.iter().flatten().flatten() {
+        act.try_for_each(&mut |act| {
+            match act {
+                Act::MultipleActions(acts) => actions += acts.len(),
+                Act::MultipleKeyCodes(kcs) => keycodes += kcs.len(),
+                Act::HoldTap { .. } => actions += 2,
+                _ => {},
+            }
+            Ok(())
+        }).unwrap();
+    }
+    (actions, keycodes)
+}
+
+/// Check layer dimensions and parameters of all actions
+///
+/// Custom actions are checked using `check_custom`, as their validity depends on other parts
+/// of the configuration.
+pub fn validate<T, F>(layers: &Layers<T>, mut check_custom: F) -> anyhow::Result<()>
+where
+    T: ToTokens,
+    F: FnMut(&T) -> anyhow::Result<()>,
+{
+    let n_layers = layers.len();
+    anyhow::ensure!(n_layers > 0, "At least one layer is required");
+    let n_rows = layers[0].len();
+    let n_cols = layers[0].first().map_or(0, |r| r.len());
+    for (l, layer) in layers.iter().enumerate() {
+        anyhow::ensure!(layer.len() == n_rows,
+            "Wrong number of rows in layer {}: {} vs {}", l, layer.len(), n_rows);
+        for (r, row) in layer.iter().enumerate() {
+            anyhow::ensure!(row.len() == n_cols,
+                "Wrong number of columns in layer {} row {}: {} vs {}", l, r, row.len(), n_cols);
+            for (c, act) in row.iter().enumerate() {
+                act.try_for_each(&mut |act| match act {
+                    Act::Layer(i) | Act::DefaultLayer(i) => {
+                        anyhow::ensure!(*i < n_layers, "Layer {} out of range, there are {} layers", i, n_layers);
+                        Ok(())
+                    },
+                    Act::HoldTap { timeout, .. } => {
+                        anyhow::ensure!(*timeout > 0, "HoldTap timeout must not be 0");
+                        Ok(())
+                    },
+                    Act::Custom(custom) => check_custom(custom),
+                    _ => Ok(()),
+                }).with_context(|| format!("Layer {} row {} col {}", l, r, c))?;
+            }
+        }
+    }
+    Ok(())
+}
+
 impl ToTokens for HoldTapConfig {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let c = quote! { keyberon::action::HoldTapConfig };
@@ -442,4 +629,106 @@ pub mod tests {
     fn tokenize() {
         assert_tokens_eq(to_tokens(&example_config()), example_code())
     }
+
+    #[test]
+    fn blob() -> anyhow::Result<()> {
+        let mut blob = Blob::default();
+        for act in example_config().iter().flatten().flatten() {
+            act.to_blob(&mut blob)?;
+        }
+        assert_eq!(blob.into_bytes(), [
+            0,
+            1,
+            2, 0x1f,
+            3, 2, 0xe0, 0x06,
+            4, 2, 2, 0x14, 5, 2,
+            5, 3,
+            6, 2,
+            7, 180, 0, 5, 2, 2, 0x2c, 0, 100, 0,
+            8, 1, 1, 5,
+        ]);
+        // Last key codes of both ranges
+        let mut blob = Blob::default();
+        vec![KeyCode::ExSel, KeyCode::MediaCalc].to_blob(&mut blob)?;
+        assert_eq!(blob.into_bytes(), [2, 0xa4, 0xfb]);
+        Ok(())
+    }
+
+    fn example_aliases() -> Aliases<custom::Action> {
+        let mut aliases = Aliases::new();
+        aliases.insert("Copy".to_string(), Act::MultipleKeyCodes(vec![KeyCode::LCtrl, KeyCode::C]));
+        aliases.insert("Nav".to_string(), Act::Layer(2));
+        aliases.insert("SpaceNav".to_string(), Act::HoldTap {
+            timeout: 180,
+            hold: Box::new(Act::Alias("Nav".to_string())),
+            tap: Box::new(Act::KeyCode(KeyCode::Space)),
+            config: HoldTapConfig::Default,
+            tap_hold_interval: 0,
+        });
+        aliases.insert("Loop".to_string(), Act::Alias("Loop".to_string()));
+        aliases
+    }
+
+    #[test]
+    fn aliases_expanded() -> anyhow::Result<()> {
+        let mut layers: Layers<custom::Action> = serde_json::from_value(serde_json::json!([
+            [[
+                { "Alias": "Copy" },
+                { "MultipleActions": [{ "KeyCode": "Q" }, { "Alias": "Nav" }] },
+                { "Alias": "SpaceNav" },
+            ]]
+        ]))?;
+        expand_aliases(&mut layers, &example_aliases())?;
+        assert_eq!(layers, vec![vec![vec![
+            Act::MultipleKeyCodes(vec![KeyCode::LCtrl, KeyCode::C]),
+            Act::MultipleActions(vec![Act::KeyCode(KeyCode::Q), Act::Layer(2)]),
+            Act::HoldTap {
+                timeout: 180,
+                hold: Box::new(Act::Layer(2)),
+                tap: Box::new(Act::KeyCode(KeyCode::Space)),
+                config: HoldTapConfig::Default,
+                tap_hold_interval: 0,
+            },
+        ]]]);
+        Ok(())
+    }
+
+    #[test]
+    fn aliases_invalid() {
+        let aliases = example_aliases();
+        let mut unknown = Act::Alias("Paste".to_string());
+        assert!(unknown.expand_aliases(&aliases).is_err());
+        let mut cycle = Act::Alias("Loop".to_string());
+        assert!(cycle.expand_aliases(&aliases).is_err());
+    }
+
+    #[test]
+    fn validate_layers() {
+        let no_custom = |_: &custom::Action| -> anyhow::Result<()> { Ok(()) };
+        let mut layers = vec![example_config(); 4];
+        assert!(validate(&layers, no_custom).is_ok());
+        assert!(validate(&example_config(), no_custom).is_err());
+        assert!(validate(&layers, |_| anyhow::bail!("Custom")).is_err());
+        layers[1][0].pop();
+        assert!(validate(&layers, no_custom).is_err());
+    }
+
+    #[test]
+    fn count_nested() {
+        // MultipleActions(2) + HoldTap(2)
+        assert_eq!(nested_counts(&example_config()), (4, 2));
+    }
+
+    #[test]
+    fn validate_nested() {
+        let layers = vec![vec![vec![Act::<custom::Action>::HoldTap {
+            timeout: 200,
+            hold: Box::new(Act::MultipleActions(vec![Act::KeyCode(KeyCode::A), Act::Layer(1)])),
+            tap: Box::new(Act::NoOp),
+            config: HoldTapConfig::Default,
+            tap_hold_interval: 0,
+        }]]];
+        let err = validate(&layers, |_| Ok(())).unwrap_err();
+        assert_eq!(format!("{:#}", err), "Layer 0 row 0 col 0: Layer 1 out of range, there are 1 layers");
+    }
 }
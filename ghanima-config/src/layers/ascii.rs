@@ -0,0 +1,162 @@
+//! Keymap as text
+//!
+//! Renders layers as aligned ASCII grids of key legends for quick review in a terminal.
+//! Uses the same approximate geometry as [`super::svg`], with each key drawn as a box
+//! containing the tap legend and, for hold-tap actions, the hold legend below it.
+
+use std::fmt::Debug;
+
+use quote::ToTokens;
+
+use super::{Act, Layers};
+use super::svg::{key_position, label};
+
+/// Horizontal distance between key borders in characters
+const PITCH: usize = 7;
+/// Vertical distance between key borders in lines
+const ROW_LINES: usize = 3;
+/// Maximum number of legend characters that fit inside a key
+const LEGEND: usize = PITCH - 1;
+
+/// Grid of characters that grows as needed
+#[derive(Default)]
+struct Canvas {
+    lines: Vec<Vec<char>>,
+}
+
+impl Canvas {
+    fn put(&mut self, x: usize, y: usize, c: char) {
+        if self.lines.len() <= y {
+            self.lines.resize(y + 1, Vec::new());
+        }
+        let line = &mut self.lines[y];
+        if line.len() <= x {
+            line.resize(x + 1, ' ');
+        }
+        // Keep corners where borders of neighbouring keys meet
+        if line[x] != '+' {
+            line[x] = c;
+        }
+    }
+
+    /// Draw key outline with top left corner at `x`, `y`
+    fn key(&mut self, x: usize, y: usize, joystick: bool) {
+        let (corner, left, right) = if joystick { (' ', '(', ')') } else { ('+', '|', '|') };
+        for line in [y, y + ROW_LINES] {
+            self.put(x, line, corner);
+            self.put(x + PITCH, line, corner);
+            for i in 1..PITCH {
+                self.put(x + i, line, '-');
+            }
+        }
+        for line in y + 1..y + ROW_LINES {
+            self.put(x, line, left);
+            self.put(x + PITCH, line, right);
+        }
+    }
+
+    /// Draw legend centered inside a key, truncating it if too long
+    fn legend(&mut self, x: usize, y: usize, text: &str) {
+        let text: Vec<char> = text.chars().take(LEGEND).collect();
+        let start = x + 1 + (LEGEND - text.len()) / 2;
+        for (i, c) in text.into_iter().enumerate() {
+            self.put(start + i, y, c);
+        }
+    }
+
+    fn into_string(self) -> String {
+        self.lines.into_iter()
+            .map(|line| line.into_iter().collect::<String>().trim_end().to_string() + "\n")
+            .collect()
+    }
+}
+
+/// Render a single layer as text
+pub fn layer_ascii<T: ToTokens + Debug>(layers: &Layers<T>, index: usize) -> String {
+    let layer = &layers[index];
+    let n_rows = layer.len();
+    let half_cols = layer.first().map_or(0, |r| r.len()) / 2;
+
+    let mut canvas = Canvas::default();
+    for (row, keys) in layer.iter().enumerate() {
+        for (col, action) in keys.iter().enumerate() {
+            let (x, y, joystick) = match key_position(row, col, n_rows, half_cols) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let x = (x * PITCH as f32).round() as usize;
+            let y = (y * ROW_LINES as f32).round() as usize;
+            canvas.key(x, y, joystick);
+            canvas.legend(x, y + 1, &label(action));
+            if let Act::HoldTap { hold, .. } = action {
+                canvas.legend(x, y + 2, &label(hold));
+            }
+        }
+    }
+    format!("Layer {}\n{}", index, canvas.into_string())
+}
+
+/// Render all layers as text separated by empty lines
+pub fn layers_ascii<T: ToTokens + Debug>(layers: &Layers<T>) -> String {
+    (0..layers.len())
+        .map(|i| layer_ascii(layers, i))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom;
+    use crate::layers::{HoldTapConfig, KeyCode};
+
+    fn layers() -> Layers<custom::Action> {
+        vec![vec![vec![Act::KeyCode(KeyCode::A); 12]; 5]; 2]
+    }
+
+    #[test]
+    fn aligned_grid() {
+        let text = layer_ascii(&layers(), 0);
+        let lines: Vec<_> = text.lines().collect();
+        let border = "+------".repeat(6) + "+";
+        let legend = "|  A   ".repeat(6) + "|";
+        assert_eq!(lines[0], "Layer 0");
+        assert_eq!(lines[1], format!("{}{}{}", border, " ".repeat(13), border));
+        assert_eq!(lines[2], format!("{}{}{}", legend, " ".repeat(13), legend));
+        let empty = legend.replace('A', " ");
+        assert_eq!(lines[3], format!("{}{}{}", empty, " ".repeat(13), empty));
+        // Rows share borders
+        assert_eq!(lines[4], lines[1]);
+        assert_eq!(lines[13], lines[1]);
+    }
+
+    #[test]
+    fn thumb_cluster() {
+        let text = layer_ascii(&layers(), 0);
+        let lines: Vec<_> = text.lines().collect();
+        // Joystick next to thumb keys which are shifted towards the center
+        assert!(lines[14].starts_with("     ------   +------+------+------+------+ "), "{}", lines[14]);
+        assert!(lines[15].starts_with("    (  A   )  |  A   |  A   |  A   |  A   | "), "{}", lines[15]);
+        assert_eq!(lines.len(), 18);
+    }
+
+    #[test]
+    fn legends() {
+        let mut layers = layers();
+        layers[1][0][0] = Act::HoldTap {
+            timeout: 200,
+            hold: Box::new(Act::Layer(1)),
+            tap: Box::new(Act::KeyCode(KeyCode::Space)),
+            config: HoldTapConfig::Default,
+            tap_hold_interval: 0,
+        };
+        layers[1][0][1] = Act::MultipleKeyCodes(vec![KeyCode::LCtrl, KeyCode::LShift, KeyCode::C]);
+        let text = layers_ascii(&layers);
+        assert!(text.starts_with("Layer 0\n"));
+        assert!(text.contains("\n\nLayer 1\n"));
+        let layer = layer_ascii(&layers, 1);
+        let lines: Vec<_> = layer.lines().collect();
+        assert!(lines[2].starts_with("|Space |LCtrl+|"), "{}", lines[2]);
+        assert!(lines[3].starts_with("|  L1  |      |"), "{}", lines[3]);
+    }
+}
@@ -0,0 +1,170 @@
+//! Translation of key codes for non-QWERTY host layouts
+//!
+//! USB HID key codes identify key positions of the US QWERTY layout and the host OS decides
+//! which character each position produces. With a [`HostLayout`] other than QWERTY the keymap
+//! is written in terms of what the keys should produce on the host, and the key codes are
+//! replaced with the ones that the host translates to these characters when generating code.
+//!
+//! Only characters that the host layout produces without modifiers are translated, other key
+//! codes are left unchanged (e.g. "Dot" on AZERTY still sends the key that produces ':').
+
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens, TokenStreamExt};
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use super::{Act, KeyCode, Layers};
+
+/// Keyboard layout configured in the host OS
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone, Copy)]
+pub enum HostLayout {
+    /// US QWERTY, key codes are used as-is
+    Qwerty,
+    /// German-style QWERTZ, only letters are translated
+    Qwertz,
+    /// French AZERTY, only letters and unshifted punctuation are translated
+    Azerty,
+    /// Colemak
+    Colemak,
+}
+
+impl HostLayout {
+    /// Pairs of (key code sent, key code it produces on the host) that differ from QWERTY
+    ///
+    /// Key codes sent are a permutation of key codes produced, so each translated key code
+    /// is sent by exactly one key position.
+    fn differences(&self) -> &'static [(KeyCode, KeyCode)] {
+        use KeyCode as K;
+        match self {
+            Self::Qwerty => &[],
+            Self::Qwertz => &[(K::Y, K::Z), (K::Z, K::Y)],
+            Self::Azerty => &[
+                (K::Q, K::A), (K::W, K::Z), (K::A, K::Q), (K::Z, K::W),
+                (K::SColon, K::M), (K::M, K::Comma), (K::Comma, K::SColon),
+            ],
+            Self::Colemak => &[
+                (K::E, K::F), (K::R, K::P), (K::T, K::G), (K::Y, K::J), (K::U, K::L),
+                (K::I, K::U), (K::O, K::Y), (K::P, K::SColon),
+                (K::S, K::R), (K::D, K::S), (K::F, K::T), (K::G, K::D),
+                (K::J, K::N), (K::K, K::E), (K::L, K::I), (K::SColon, K::O),
+                (K::N, K::K),
+            ],
+        }
+    }
+
+    /// Key code to send so that the host produces `key`
+    pub fn translate(&self, key: &KeyCode) -> KeyCode {
+        self.differences().iter()
+            .find(|(_, produced)| produced == key)
+            .map_or_else(|| key.clone(), |(sent, _)| sent.clone())
+    }
+
+    /// Translate key codes in an action and all nested actions
+    pub fn translate_action<T: ToTokens>(&self, action: &mut Act<T>) {
+        match action {
+            Act::KeyCode(kc) => *kc = self.translate(kc),
+            Act::MultipleKeyCodes(kcs) => kcs.iter_mut().for_each(|kc| *kc = self.translate(kc)),
+            Act::MultipleActions(actions) => actions.iter_mut().for_each(|act| self.translate_action(act)),
+            Act::HoldTap { hold, tap, .. } => {
+                self.translate_action(hold);
+                self.translate_action(tap);
+            },
+            _ => {},
+        }
+    }
+
+    /// Copy of `layers` with key codes translated for the host layout
+    pub fn translate_layers<T: ToTokens + Clone>(&self, layers: &Layers<T>) -> Layers<T> {
+        let mut layers = layers.clone();
+        layers.iter_mut()
+            .flatten()
+            .flatten()
+            .for_each(|act| self.translate_action(act));
+        layers
+    }
+}
+
+/// Generates the firmware table of (key code as written, key code sent), used for key chords
+/// that the firmware builds itself (shortcut actions)
+impl ToTokens for HostLayout {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let pairs = self.differences().iter().map(|(sent, produced)| quote! { (#produced, #sent) });
+        tokens.append_all(quote! { &[ #( #pairs ),* ] })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom;
+    use crate::layers::HoldTapConfig;
+
+    const LAYOUTS: [HostLayout; 4] = [HostLayout::Qwerty, HostLayout::Qwertz, HostLayout::Azerty, HostLayout::Colemak];
+
+    #[test]
+    fn differences_are_permutations() {
+        for layout in LAYOUTS {
+            let diffs = layout.differences();
+            for (sent, _) in diffs {
+                assert_eq!(diffs.iter().filter(|(_, produced)| produced == sent).count(), 1,
+                    "{:?}: {:?} not produced exactly once", layout, sent);
+            }
+            for (_, produced) in diffs {
+                assert_eq!(diffs.iter().filter(|(sent, _)| sent == produced).count(), 1,
+                    "{:?}: {:?} not sent exactly once", layout, produced);
+            }
+        }
+    }
+
+    #[test]
+    fn translate_keys() {
+        assert_eq!(HostLayout::Qwerty.translate(&KeyCode::Q), KeyCode::Q);
+        assert_eq!(HostLayout::Qwertz.translate(&KeyCode::Z), KeyCode::Y);
+        assert_eq!(HostLayout::Azerty.translate(&KeyCode::A), KeyCode::Q);
+        assert_eq!(HostLayout::Azerty.translate(&KeyCode::M), KeyCode::SColon);
+        // Not produced without modifiers on AZERTY
+        assert_eq!(HostLayout::Azerty.translate(&KeyCode::Dot), KeyCode::Dot);
+        assert_eq!(HostLayout::Colemak.translate(&KeyCode::T), KeyCode::F);
+        assert_eq!(HostLayout::Colemak.translate(&KeyCode::SColon), KeyCode::P);
+        assert_eq!(HostLayout::Colemak.translate(&KeyCode::Space), KeyCode::Space);
+    }
+
+    #[test]
+    fn translate_nested() {
+        let layers: Layers<custom::Action> = vec![vec![vec![
+            Act::KeyCode(KeyCode::Z),
+            Act::MultipleKeyCodes(vec![KeyCode::LCtrl, KeyCode::Z]),
+            Act::HoldTap {
+                timeout: 200,
+                hold: Box::new(Act::Layer(1)),
+                tap: Box::new(Act::MultipleActions(vec![Act::KeyCode(KeyCode::Y), Act::Trans])),
+                config: HoldTapConfig::Default,
+                tap_hold_interval: 0,
+            },
+        ]]];
+        let translated = HostLayout::Qwertz.translate_layers(&layers);
+        assert_eq!(translated, vec![vec![vec![
+            Act::KeyCode(KeyCode::Y),
+            Act::MultipleKeyCodes(vec![KeyCode::LCtrl, KeyCode::Y]),
+            Act::HoldTap {
+                timeout: 200,
+                hold: Box::new(Act::Layer(1)),
+                tap: Box::new(Act::MultipleActions(vec![Act::KeyCode(KeyCode::Z), Act::Trans])),
+                config: HoldTapConfig::Default,
+                tap_hold_interval: 0,
+            },
+        ]]]);
+        assert_eq!(HostLayout::Qwerty.translate_layers(&layers), layers);
+    }
+
+    #[test]
+    fn firmware_table() {
+        assert_eq!(HostLayout::Qwerty.to_token_stream().to_string(), quote! { &[] }.to_string());
+        assert_eq!(HostLayout::Qwertz.to_token_stream().to_string(), quote! {
+            &[
+                (keyberon::key_code::KeyCode::Z, keyberon::key_code::KeyCode::Y),
+                (keyberon::key_code::KeyCode::Y, keyberon::key_code::KeyCode::Z)
+            ]
+        }.to_string());
+    }
+}
@@ -0,0 +1,198 @@
+//! Keymap visualization
+//!
+//! Renders layers as SVG with key legends laid out like the physical keyboard halves.
+//! Geometry is approximate: main rows are drawn as a grid and thumb keys are shifted
+//! towards the center, with the joystick drawn next to them.
+
+use std::fmt::{Debug, Write as _};
+
+use quote::ToTokens;
+
+use super::{Act, Layers};
+
+/// Number of keys in the thumb row of each half, further columns are the joystick
+const THUMB_KEYS: usize = 4;
+/// Key pitch in pixels
+const KEY: usize = 56;
+/// Gap between keys in pixels
+const GAP: usize = 4;
+/// Horizontal space between halves in pixels
+const HALF_GAP: usize = 2 * KEY;
+const MARGIN: usize = 16;
+const TITLE_HEIGHT: usize = 28;
+/// Labels longer than this use smaller font
+const LONG_LABEL: usize = 6;
+
+/// Short key legend of an action
+pub fn label<T: ToTokens + Debug>(action: &Act<T>) -> String {
+    match action {
+        Act::NoOp => String::new(),
+        Act::Trans => "▽".to_string(),
+        Act::KeyCode(kc) => key_label(kc),
+        Act::MultipleKeyCodes(kcs) => kcs.iter().map(key_label).collect::<Vec<_>>().join("+"),
+        Act::MultipleActions(acts) => acts.iter().map(label).collect::<Vec<_>>().join("+"),
+        Act::Layer(l) => format!("L{}", l),
+        Act::DefaultLayer(l) => format!("DL{}", l),
+        Act::HoldTap { tap, .. } => label(tap),
+        Act::Custom(custom) => format!("{:?}", custom)
+            .split(['(', ')'])
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" "),
+        Act::Alias(name) => name.clone(),
+    }
+}
+
+fn key_label(kc: &super::KeyCode) -> String {
+    let name = format!("{:?}", kc);
+    match name.strip_prefix("Kb") {
+        Some(digit) => digit.to_string(),
+        None => name,
+    }
+}
+
+/// Escape text for use in XML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Position of a key in units of key pitch for given global coordinates
+///
+/// Returns `None` for matrix positions without a key. `half_cols` is number of columns
+/// of a single half.
+pub(super) fn key_position(row: usize, col: usize, n_rows: usize, half_cols: usize) -> Option<(f32, f32, bool)> {
+    let right = col >= half_cols;
+    // Column counted from the outer edge of the half
+    let outer = if right { 2 * half_cols - 1 - col } else { col };
+    let (x, joystick) = if row + 1 == n_rows {
+        match outer {
+            // Thumb keys are located closer to the center
+            c if c < THUMB_KEYS => ((c + half_cols - THUMB_KEYS) as f32, false),
+            c if c == THUMB_KEYS => ((half_cols - THUMB_KEYS) as f32 - 1.5, true),
+            _ => return None,
+        }
+    } else {
+        (outer as f32, false)
+    };
+    let y = if row + 1 == n_rows { row as f32 + 0.25 } else { row as f32 };
+    let x = if right {
+        (2 * half_cols) as f32 - 1.0 - x + (HALF_GAP as f32 / KEY as f32)
+    } else {
+        x
+    };
+    Some((x, y, joystick))
+}
+
+/// Render a single layer as SVG
+pub fn layer_svg<T: ToTokens + Debug>(layers: &Layers<T>, index: usize) -> String {
+    let layer = &layers[index];
+    let n_rows = layer.len();
+    let half_cols = layer.first().map_or(0, |r| r.len()) / 2;
+    let width = 2 * MARGIN + 2 * half_cols * KEY + HALF_GAP;
+    let height = 2 * MARGIN + TITLE_HEIGHT + n_rows * KEY + KEY / 4;
+
+    let mut svg = String::new();
+    writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif">"#,
+        w = width, h = height).unwrap();
+    writeln!(svg, r##"<rect width="100%" height="100%" fill="#fff"/>"##).unwrap();
+    writeln!(svg, r#"<text x="{}" y="{}" font-size="18" font-weight="bold">Layer {}</text>"#,
+        MARGIN, MARGIN + 18, index).unwrap();
+
+    for (row, keys) in layer.iter().enumerate() {
+        for (col, action) in keys.iter().enumerate() {
+            let (x, y, joystick) = match key_position(row, col, n_rows, half_cols) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let x = MARGIN as f32 + x * KEY as f32;
+            let y = (MARGIN + TITLE_HEIGHT) as f32 + y * KEY as f32;
+            let size = (KEY - GAP) as f32;
+            let (cx, cy) = (x + size / 2.0, y + size / 2.0);
+
+            let fill = match action {
+                Act::NoOp => "#eee",
+                Act::Trans => "#f8f8f8",
+                Act::HoldTap { .. } => "#dde8ff",
+                Act::Layer(_) | Act::DefaultLayer(_) => "#ffe8c0",
+                Act::Custom(_) => "#e0f4e0",
+                _ => "#fff",
+            };
+            if joystick {
+                writeln!(svg, r##"<circle cx="{}" cy="{}" r="{}" fill="{}" stroke="#666"/>"##, cx, cy, size / 2.0, fill).unwrap();
+            } else {
+                writeln!(svg, r##"<rect x="{}" y="{}" width="{s}" height="{s}" rx="6" fill="{}" stroke="#666"/>"##, x, y, fill, s = size).unwrap();
+            }
+
+            let tap = label(action);
+            let font = if tap.chars().count() > LONG_LABEL { 8 } else { 12 };
+            writeln!(svg, r#"<text x="{}" y="{}" font-size="{}" text-anchor="middle">{}</text>"#,
+                cx, cy + 4.0, font, escape(&tap)).unwrap();
+            if let Act::HoldTap { hold, .. } = action {
+                writeln!(svg, r##"<text x="{}" y="{}" font-size="8" text-anchor="middle" fill="#35a">{}</text>"##,
+                    cx, y + size - 5.0, escape(&label(hold))).unwrap();
+            }
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Render all layers as a standalone HTML page
+pub fn layers_html<T: ToTokens + Debug>(layers: &Layers<T>) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Keymap</title></head>\n<body>\n");
+    for i in 0..layers.len() {
+        html.push_str(&layer_svg(layers, i));
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom;
+    use crate::layers::{HoldTapConfig, KeyCode};
+
+    #[test]
+    fn labels() {
+        let act = |a| label::<custom::Action>(&a);
+        assert_eq!(act(Act::NoOp), "");
+        assert_eq!(act(Act::KeyCode(KeyCode::Kb1)), "1");
+        assert_eq!(act(Act::MultipleKeyCodes(vec![KeyCode::LCtrl, KeyCode::C])), "LCtrl+C");
+        assert_eq!(act(Act::Layer(2)), "L2");
+        assert_eq!(act(Act::Custom(custom::Action::Mouse(custom::MouseAction::Click(custom::MouseButton::Left)))),
+            "Mouse Click Left");
+        assert_eq!(act(Act::HoldTap {
+            timeout: 200,
+            hold: Box::new(Act::Layer(1)),
+            tap: Box::new(Act::KeyCode(KeyCode::Space)),
+            config: HoldTapConfig::Default,
+            tap_hold_interval: 0,
+        }), "Space");
+    }
+
+    #[test]
+    fn positions() {
+        // Main rows are mirrored on the right half
+        assert_eq!(key_position(0, 0, 5, 6), Some((0.0, 0.0, false)));
+        assert_eq!(key_position(1, 11, 5, 6), Some((13.0, 1.0, false)));
+        // Thumb row
+        assert_eq!(key_position(4, 0, 5, 6), Some((2.0, 4.25, false)));
+        assert_eq!(key_position(4, 4, 5, 6), Some((0.5, 4.25, true)));
+        assert_eq!(key_position(4, 5, 5, 6), None);
+        assert_eq!(key_position(4, 6, 5, 6), None);
+        assert_eq!(key_position(4, 11, 5, 6), Some((11.0, 4.25, false)));
+    }
+
+    #[test]
+    fn escaped_html() {
+        let layers: Layers<custom::Action> = vec![vec![vec![Act::KeyCode(KeyCode::A); 12]; 5]; 2];
+        let html = layers_html(&layers);
+        assert_eq!(html.matches("<svg").count(), 2);
+        assert!(html.contains(">Layer 1<"));
+        assert_eq!(escape("<&>\""), "&lt;&amp;&gt;&quot;");
+    }
+}
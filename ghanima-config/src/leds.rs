@@ -1,9 +1,13 @@
+use anyhow::Context;
 use proc_macro2::{TokenStream, Ident, Span};
 use quote::{quote, ToTokens, TokenStreamExt};
 use serde::{Serialize, Deserialize};
 use schemars::JsonSchema;
 
-use crate::{impl_struct_to_tokens, impl_enum_to_tokens};
+use crate::{impl_struct_to_tokens, impl_enum_to_tokens, impl_struct_to_blob, impl_enum_to_blob};
+use crate::blob::{Blob, ToBlob};
+
+pub mod preview;
 
 pub type LedConfigurations = Vec<LedConfig>;
 
@@ -16,13 +20,67 @@ pub struct LedRule {
     pattern: Pattern,
 }
 
+/// Maximum brightness of LEDs under given keys (all keys if `None`)
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct BrightnessLimit {
+    pub keys: Option<Keys>,
+    pub max: u8,
+}
+
+/// Keys matched by a rule, rows span both halves while columns and keys are global
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
 pub enum Keys {
     Rows(Vec<u8>),
+    /// Global columns, i.e. columns of the right half follow the left half
     Cols(Vec<u8>),
+    /// Global (row, column) coordinates
     Keys(Vec<(u8, u8)>),
 }
 
+impl Keys {
+    /// Check that rows and global columns are within keyboard dimensions
+    pub fn validate(&self, n_rows: usize, n_cols: usize) -> anyhow::Result<()> {
+        let check_row = |row: u8| -> anyhow::Result<()> {
+            anyhow::ensure!((row as usize) < n_rows, "Row {} out of range, there are {} rows", row, n_rows);
+            Ok(())
+        };
+        let check_col = |col: u8| -> anyhow::Result<()> {
+            anyhow::ensure!((col as usize) < n_cols, "Column {} out of range, there are {} columns", col, n_cols);
+            Ok(())
+        };
+        match self {
+            Keys::Rows(rows) => rows.iter().try_for_each(|row| check_row(*row)),
+            Keys::Cols(cols) => cols.iter().try_for_each(|col| check_col(*col)),
+            Keys::Keys(keys) => keys.iter().try_for_each(|(row, col)| {
+                check_row(*row).and_then(|_| check_col(*col))
+                    .with_context(|| format!("Key ({}, {})", row, col))
+            }),
+        }
+    }
+
+    /// Add keys mirrored on the other half, which side-local columns of config version 1 matched
+    pub fn add_mirrored(&mut self, n_cols: usize) {
+        let mirror = |col: u8| ((col as usize) < n_cols).then(|| (n_cols - 1 - col as usize) as u8);
+        match self {
+            Keys::Rows(_) => {},
+            Keys::Cols(cols) => {
+                let mirrored: Vec<_> = cols.iter().filter_map(|col| mirror(*col)).collect();
+                cols.extend(mirrored);
+                cols.sort();
+                cols.dedup();
+            },
+            Keys::Keys(keys) => {
+                let mirrored: Vec<_> = keys.iter()
+                    .filter_map(|(row, col)| mirror(*col).map(|col| (*row, col)))
+                    .collect();
+                keys.extend(mirrored);
+                keys.sort();
+                keys.dedup();
+            },
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
 pub enum Condition {
     Always,
@@ -34,6 +92,11 @@ pub enum Condition {
     KeyPressed(u8, u8),
     Layer(u8),
     BootloaderAllowed,
+    MouseButton(crate::custom::MouseButton),
+    GameMode,
+    BrightnessPreset(u8),
+    JoystickEnabled,
+    BarGraph(ValueSource),
     Not(Box<Condition>),
     And(Vec<Condition>),
     Or(Vec<Condition>),
@@ -52,6 +115,12 @@ pub enum KeyAction {
     Custom,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub enum ValueSource {
+    Brightness,
+    Layer,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
 pub enum Role {
     Master,
@@ -101,7 +170,7 @@ pub enum Interpolation {
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
-pub struct RGB8(u8, u8, u8);
+pub struct RGB8(pub u8, pub u8, pub u8);
 
 pub fn to_tokens(configs: &LedConfigurations) -> TokenStream {
     quote! {
@@ -109,12 +178,39 @@ pub fn to_tokens(configs: &LedConfigurations) -> TokenStream {
     }
 }
 
+/// Check keys of all rules
+pub fn validate(configs: &LedConfigurations, n_rows: usize, n_cols: usize) -> anyhow::Result<()> {
+    for (i, config) in configs.iter().enumerate() {
+        for (j, rule) in config.iter().enumerate() {
+            if let Some(keys) = &rule.keys {
+                keys.validate(n_rows, n_cols)
+                    .with_context(|| format!("Configuration {} rule {}", i, j))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add mirrored keys to all rules, see [`Keys::add_mirrored`]
+pub fn add_mirrored(configs: &mut LedConfigurations, n_cols: usize) {
+    for keys in configs.iter_mut().flatten().filter_map(|rule| rule.keys.as_mut()) {
+        keys.add_mirrored(n_cols);
+    }
+}
+
+/// Number of rules and transitions in all configurations
+pub fn counts(configs: &LedConfigurations) -> (usize, usize) {
+    let rules = configs.iter().flatten();
+    (rules.clone().count(), rules.map(|r| r.pattern.transitions.len()).sum())
+}
+
 impl_enum_to_tokens! {
     enum KeyAction: crate::keyboard::leds::KeyAction,
     enum KeyboardLed: crate::keyboard::leds::KeyboardLed,
     enum Repeat: crate::keyboard::leds::Repeat,
     enum Interpolation: crate::keyboard::leds::Interpolation,
     enum Role: crate::keyboard::leds::Role,
+    enum ValueSource: crate::keyboard::leds::ValueSource,
 }
 
 impl_struct_to_tokens! {
@@ -122,6 +218,66 @@ impl_struct_to_tokens! {
     struct Pattern: crate::keyboard::leds::Pattern { repeat, &[transitions], phase, }
     struct Transition: crate::keyboard::leds::Transition { color, duration, interpolation, }
     struct Phase: crate::keyboard::leds::Phase { x, y, }
+    struct BrightnessLimit: crate::keyboard::leds::BrightnessLimit { &?keys, max, }
+}
+
+impl_enum_to_blob! {
+    enum KeyAction,
+    enum KeyboardLed,
+    enum Repeat,
+    enum Interpolation,
+    enum Role,
+    enum ValueSource,
+}
+
+impl_struct_to_blob! {
+    struct LedRule { keys, condition, pattern }
+    struct Pattern { repeat, transitions, phase }
+    struct Transition { color, duration, interpolation }
+    struct Phase { x, y }
+}
+
+impl ToBlob for RGB8 {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        blob.u8(self.0);
+        blob.u8(self.1);
+        blob.u8(self.2);
+        Ok(())
+    }
+}
+
+impl ToBlob for Keys {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            Keys::Rows(rows) => blob.variant(0, Some(rows)),
+            Keys::Cols(cols) => blob.variant(1, Some(cols)),
+            Keys::Keys(keys) => blob.variant(2, Some(keys)),
+        }
+    }
+}
+
+impl ToBlob for Condition {
+    fn to_blob(&self, blob: &mut Blob) -> anyhow::Result<()> {
+        match self {
+            Condition::Always => blob.variant(0, None),
+            Condition::Led(led) => blob.variant(1, Some(led)),
+            Condition::UsbOn => blob.variant(2, None),
+            Condition::Role(role) => blob.variant(3, Some(role)),
+            Condition::Pressed => blob.variant(4, None),
+            Condition::KeyAction(act) => blob.variant(5, Some(act)),
+            Condition::KeyPressed(row, col) => blob.variant(6, Some(&(*row, *col))),
+            Condition::Layer(layer) => blob.variant(7, Some(layer)),
+            Condition::BootloaderAllowed => blob.variant(8, None),
+            Condition::MouseButton(button) => blob.variant(9, Some(button)),
+            Condition::GameMode => blob.variant(10, None),
+            Condition::BrightnessPreset(i) => blob.variant(11, Some(i)),
+            Condition::JoystickEnabled => blob.variant(12, None),
+            Condition::BarGraph(source) => blob.variant(13, Some(source)),
+            Condition::Not(cond) => blob.variant(14, Some(cond)),
+            Condition::And(conds) => blob.variant(15, Some(conds)),
+            Condition::Or(conds) => blob.variant(16, Some(conds)),
+        }
+    }
 }
 
 impl ToTokens for Keys {
@@ -151,6 +307,11 @@ impl ToTokens for Condition {
             Condition::KeyPressed(row, col) => quote! { #leds::Condition::KeyPressed(#row, #col) },
             Condition::Layer(layer) => quote! { #leds::Condition::Layer(#layer) },
             Condition::BootloaderAllowed => quote! { #leds::Condition::BootloaderAllowed },
+            Condition::MouseButton(button) => quote! { #leds::Condition::MouseButton(#button) },
+            Condition::GameMode => quote! { #leds::Condition::GameMode },
+            Condition::BrightnessPreset(i) => quote! { #leds::Condition::BrightnessPreset(#i) },
+            Condition::JoystickEnabled => quote! { #leds::Condition::JoystickEnabled },
+            Condition::BarGraph(source) => quote! { #leds::Condition::BarGraph(#source) },
             Condition::Not(cond) => quote! { #leds::Condition::Not(&#cond) },
             Condition::And(conds) => quote! { #leds::Condition::And(&[ #(#conds),* ]) },
             Condition::Or(conds) => quote! { #leds::Condition::Or(&[ #(#conds),* ]) },
@@ -213,6 +374,8 @@ pub mod tests {
                                 { "KeyPressed": [2, 3] },
                                 { "KeyAction": "HoldTap" },
                                 "BootloaderAllowed",
+                                { "MouseButton": "Left" },
+                                { "BarGraph": "Brightness" },
                             ]
                         },
                         "pattern": {
@@ -271,6 +434,8 @@ pub mod tests {
                         Condition::KeyPressed(2, 3),
                         Condition::KeyAction(KeyAction::HoldTap),
                         Condition::BootloaderAllowed,
+                        Condition::MouseButton(crate::custom::MouseButton::Left),
+                        Condition::BarGraph(ValueSource::Brightness),
                     ]),
                     pattern: Pattern {
                         repeat: Repeat::Once,
@@ -329,6 +494,12 @@ pub mod tests {
                                 crate::keyboard::leds::KeyAction::HoldTap
                             ),
                             crate::keyboard::leds::Condition::BootloaderAllowed,
+                            crate::keyboard::leds::Condition::MouseButton(
+                                crate::keyboard::actions::MouseButton::Left
+                            ),
+                            crate::keyboard::leds::Condition::BarGraph(
+                                crate::keyboard::leds::ValueSource::Brightness
+                            ),
                         ]),
                         pattern: crate::keyboard::leds::Pattern {
                             repeat: crate::keyboard::leds::Repeat::Once,
@@ -359,8 +530,60 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn count_rules() {
+        assert_eq!(counts(&example_config()), (2, 4));
+    }
+
+    #[test]
+    fn validate_keys() {
+        assert!(validate(&example_config(), 5, 12).is_ok());
+        assert!(validate(&example_config(), 3, 12).is_err());
+        assert!(Keys::Cols(vec![0, 11]).validate(5, 12).is_ok());
+        assert!(Keys::Cols(vec![12]).validate(5, 12).is_err());
+        assert!(Keys::Keys(vec![(4, 11)]).validate(5, 12).is_ok());
+        assert!(Keys::Keys(vec![(5, 0)]).validate(5, 12).is_err());
+        assert!(Keys::Keys(vec![(0, 12)]).validate(5, 12).is_err());
+    }
+
+    #[test]
+    fn mirrored_keys() {
+        let mut rows = Keys::Rows(vec![1]);
+        rows.add_mirrored(12);
+        assert_eq!(rows, Keys::Rows(vec![1]));
+        let mut cols = Keys::Cols(vec![0, 6, 11]);
+        cols.add_mirrored(12);
+        assert_eq!(cols, Keys::Cols(vec![0, 5, 6, 11]));
+        let mut keys = Keys::Keys(vec![(4, 2), (1, 7), (0, 12)]);
+        keys.add_mirrored(12);
+        assert_eq!(keys, Keys::Keys(vec![(0, 12), (1, 4), (1, 7), (4, 2), (4, 9)]));
+    }
+
     #[test]
     fn tokenize() {
         assert_tokens_eq(to_tokens(&example_config()), example_code())
     }
+
+    #[test]
+    fn blob() -> anyhow::Result<()> {
+        let rule = LedRule {
+            keys: Some(Keys::Keys(vec![(1, 2)])),
+            condition: Condition::Not(Box::new(Condition::KeyPressed(2, 3))),
+            pattern: Pattern {
+                repeat: Repeat::Reflect,
+                transitions: vec![
+                    Transition { color: RGB8(1, 2, 3), duration: 0x0102, interpolation: Interpolation::Linear },
+                ],
+                phase: Phase { x: 0.0, y: -2.0 },
+            },
+        };
+        let mut blob = Blob::default();
+        rule.to_blob(&mut blob)?;
+        assert_eq!(blob.into_bytes(), [
+            1, 2, 1, 1, 2,
+            14, 6, 2, 3,
+            2, 1, 1, 2, 3, 0x02, 0x01, 1, 0, 0, 0, 0, 0, 0, 0, 0xc0,
+        ]);
+        Ok(())
+    }
 }
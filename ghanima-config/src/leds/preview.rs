@@ -0,0 +1,307 @@
+//! Host-side preview of LED patterns
+//!
+//! Pattern evaluation mirrors the firmware color generator, assuming that the pattern starts
+//! at time 0 and is never interrupted by a change of conditions. Pattern phase is applied for
+//! a key at given position (in mm, relative to the reference key as in firmware). Interpolation
+//! uses integer rounding, so colors may differ from the firmware by 1 in some channels.
+
+use std::fmt::Write as _;
+
+use super::{LedConfig, Pattern, Repeat, Interpolation, Transition, RGB8};
+
+/// Characters used in ASCII timeline, from darkest to brightest
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+/// Simulation of a single pattern over time
+pub struct PatternSim<'a> {
+    pattern: &'a Pattern,
+    prev: Option<usize>,
+    index: usize,
+    rev: bool,
+    remaining_time: u32,
+}
+
+impl<'a> PatternSim<'a> {
+    pub fn new(pattern: &'a Pattern) -> Self {
+        let mut sim = Self { pattern, prev: None, index: 0, rev: false, remaining_time: 0 };
+        sim.remaining_time = sim.curr().map_or(0, |t| t.duration as u32);
+        sim
+    }
+
+    /// Advance time by `time_delta` milliseconds and return the current color
+    pub fn tick(&mut self, time_delta: u32) -> RGB8 {
+        self.advance_time(time_delta);
+        self.color().unwrap_or(RGB8(0, 0, 0))
+    }
+
+    /// Simulation of a pattern on a key at position `(x, y)` in mm, shifted according to phase
+    pub fn at(pattern: &'a Pattern, (x, y): (f32, f32)) -> Self {
+        let mut sim = Self::new(pattern);
+        if let Some(cycle) = pattern.cycle_time() {
+            let offset = (pattern.phase.x * x + pattern.phase.y * y) as i32;
+            sim.advance_time(offset.rem_euclid(cycle as i32) as u32);
+        }
+        sim
+    }
+
+    /// Colors sampled every `step` ms during `duration` ms on a key at position `at`
+    pub fn timeline(pattern: &'a Pattern, at: (f32, f32), duration: u32, step: u32) -> Vec<RGB8> {
+        let step = step.max(1);
+        let mut sim = Self::at(pattern, at);
+        (0..duration.div_ceil(step))
+            .map(|i| sim.tick(if i == 0 { 0 } else { step }))
+            .collect()
+    }
+
+    fn transitions_count(&self) -> usize {
+        self.pattern.transitions.len().min((u8::MAX - 1) as usize)
+    }
+
+    fn curr(&self) -> Option<&'a Transition> {
+        self.pattern.transitions.get(self.index)
+    }
+
+    fn prev(&self) -> Option<&'a Transition> {
+        self.prev.and_then(|i| self.pattern.transitions.get(i))
+    }
+
+    fn advance(&mut self) {
+        let count = self.transitions_count();
+        if count == 0 {
+            return;
+        }
+        self.prev = Some(self.index);
+        match self.pattern.repeat {
+            Repeat::Once => if self.index < count {
+                self.index += 1;
+            },
+            Repeat::Wrap => self.index = (self.index + 1) % count,
+            Repeat::Reflect => if self.rev {
+                if self.index > 0 {
+                    self.index -= 1;
+                } else {
+                    self.rev = false;
+                    self.index = 1 % count;
+                }
+            } else {
+                self.index += 1;
+                if self.index >= count {
+                    self.index = self.index.saturating_sub(2);
+                    self.rev = true;
+                }
+            },
+        }
+    }
+
+    fn advance_time(&mut self, mut time_delta: u32) {
+        while let Some(transition) = self.curr() {
+            // Duration 0 means that this is endless transition
+            if transition.duration == 0 {
+                return;
+            }
+            if time_delta < self.remaining_time {
+                self.remaining_time -= time_delta;
+                return;
+            }
+            time_delta -= self.remaining_time;
+            self.advance();
+            self.remaining_time = self.curr().map_or(0, |t| t.duration as u32);
+        }
+    }
+
+    fn color(&self) -> Option<RGB8> {
+        let transition = self.curr()?;
+        let duration = transition.duration as u32;
+        if duration == 0 {
+            return Some(transition.color.clone());
+        }
+        let curr = transition.color.clone();
+        let color = match transition.interpolation {
+            Interpolation::Piecewise => curr,
+            Interpolation::Linear => {
+                let prev = self.prev().map_or(RGB8(0, 0, 0), |t| t.color.clone());
+                let (prev, curr, time) = if self.rev {
+                    (curr, prev, self.remaining_time)
+                } else {
+                    (prev, curr, duration - self.remaining_time)
+                };
+                interpolate(time, duration, &prev, &curr)
+            },
+        };
+        Some(color)
+    }
+}
+
+impl Pattern {
+    /// Duration of a single cycle, `None` if the pattern does not repeat
+    fn cycle_time(&self) -> Option<u32> {
+        if self.transitions.iter().any(|t| t.duration == 0) {
+            return None;
+        }
+        let sum: u32 = self.transitions.iter().map(|t| t.duration as u32).sum();
+        match (&self.repeat, self.transitions.as_slice()) {
+            (Repeat::Once, _) | (_, []) => None,
+            (Repeat::Wrap, _) | (Repeat::Reflect, [_]) => Some(sum),
+            (Repeat::Reflect, [first, .., last]) => Some(2 * sum - first.duration as u32 - last.duration as u32),
+        }
+    }
+}
+
+fn interpolate(time: u32, duration: u32, c1: &RGB8, c2: &RGB8) -> RGB8 {
+    let channel = |a: u8, b: u8| {
+        ((a as u32 * (duration - time) + b as u32 * time + duration / 2) / duration) as u8
+    };
+    RGB8(channel(c1.0, c2.0), channel(c1.1, c2.1), channel(c1.2, c2.2))
+}
+
+impl RGB8 {
+    /// Relative luminance in range 0-255
+    fn luminance(&self) -> u8 {
+        ((2126 * self.0 as u32 + 7152 * self.1 as u32 + 722 * self.2 as u32) / 10000) as u8
+    }
+
+    fn shade(&self) -> char {
+        SHADES[self.luminance() as usize * SHADES.len() / 256] as char
+    }
+}
+
+/// Render patterns of all rules in a configuration as ASCII timeline, one line per rule
+///
+/// Brightness is represented with characters, each one covering `step` milliseconds.
+/// Patterns are shown as on a key at position `at` (see [`PatternSim::at`]).
+pub fn ascii_timeline(config: &LedConfig, at: (f32, f32), duration: u32, step: u32) -> String {
+    let mut out = String::new();
+    write!(out, "time 0-{} ms, {} ms per character", duration, step.max(1)).unwrap();
+    if at != (0.0, 0.0) {
+        write!(out, ", key at ({}, {}) mm", at.0, at.1).unwrap();
+    }
+    writeln!(out).unwrap();
+    for (i, rule) in config.iter().enumerate() {
+        let line: String = PatternSim::timeline(&rule.pattern, at, duration, step).iter()
+            .map(RGB8::shade)
+            .collect();
+        writeln!(out, "rule {:2} |{}|", i, line).unwrap();
+    }
+    out
+}
+
+/// Render patterns of all rules in a configuration as PPM image
+///
+/// Time flows along X axis with `step` ms per cell, each rule is one row of cells.
+/// Binary PPM (P6) can be viewed or converted to PNG with most image tools.
+pub fn timeline_ppm(config: &LedConfig, at: (f32, f32), duration: u32, step: u32, cell_size: usize) -> Vec<u8> {
+    let rows: Vec<_> = config.iter()
+        .map(|rule| PatternSim::timeline(&rule.pattern, at, duration, step))
+        .collect();
+    let cell_size = cell_size.max(1);
+    let cols = rows.first().map_or(0, |r| r.len());
+    let (width, height) = (cols * cell_size, rows.len() * cell_size);
+
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for row in &rows {
+        for _ in 0..cell_size {
+            for color in row {
+                for _ in 0..cell_size {
+                    out.extend_from_slice(&[color.0, color.1, color.2]);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::leds::{LedRule, Condition, Phase};
+
+    fn pattern(repeat: Repeat, transitions: &[(RGB8, u16, Interpolation)]) -> Pattern {
+        with_phase(repeat, transitions, 0.0, 0.0)
+    }
+
+    fn with_phase(repeat: Repeat, transitions: &[(RGB8, u16, Interpolation)], x: f32, y: f32) -> Pattern {
+        Pattern {
+            repeat,
+            transitions: transitions.iter()
+                .map(|(color, duration, interpolation)| Transition {
+                    color: color.clone(),
+                    duration: *duration,
+                    interpolation: interpolation.clone(),
+                })
+                .collect(),
+            phase: Phase { x, y },
+        }
+    }
+
+    const WHITE: RGB8 = RGB8(255, 255, 255);
+    const BLACK: RGB8 = RGB8(0, 0, 0);
+
+    #[test]
+    fn linear_fade() {
+        let p = pattern(Repeat::Wrap, &[
+            (BLACK, 100, Interpolation::Piecewise),
+            (WHITE, 100, Interpolation::Linear),
+        ]);
+        let timeline = PatternSim::timeline(&p, (0.0, 0.0), 250, 50);
+        assert_eq!(timeline, [BLACK, BLACK, BLACK, RGB8(128, 128, 128), BLACK]);
+    }
+
+    #[test]
+    fn once_finishes() {
+        let p = pattern(Repeat::Once, &[(WHITE, 100, Interpolation::Piecewise)]);
+        assert_eq!(PatternSim::timeline(&p, (0.0, 0.0), 200, 50), [WHITE, WHITE, BLACK, BLACK]);
+    }
+
+    #[test]
+    fn reflect() {
+        let p = pattern(Repeat::Reflect, &[
+            (BLACK, 10, Interpolation::Piecewise),
+            (RGB8(100, 0, 0), 10, Interpolation::Piecewise),
+            (WHITE, 10, Interpolation::Piecewise),
+        ]);
+        let timeline = PatternSim::timeline(&p, (0.0, 0.0), 60, 10);
+        assert_eq!(timeline, [BLACK, RGB8(100, 0, 0), WHITE, RGB8(100, 0, 0), BLACK, RGB8(100, 0, 0)]);
+    }
+
+    #[test]
+    fn static_color() {
+        let p = pattern(Repeat::Once, &[(WHITE, 0, Interpolation::Linear)]);
+        assert_eq!(PatternSim::timeline(&p, (0.0, 0.0), 1000, 500), [WHITE, WHITE]);
+    }
+
+    #[test]
+    fn render() {
+        let config = vec![LedRule {
+            keys: None,
+            condition: Condition::Always,
+            pattern: pattern(Repeat::Wrap, &[
+                (BLACK, 20, Interpolation::Piecewise),
+                (WHITE, 20, Interpolation::Piecewise),
+            ]),
+        }];
+        assert_eq!(ascii_timeline(&config, (0.0, 0.0), 80, 10), "time 0-80 ms, 10 ms per character\nrule  0 |  @@  @@|\n");
+        let ppm = timeline_ppm(&config, (0.0, 0.0), 80, 10, 2);
+        let header = b"P6\n16 2\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + 16 * 2 * 3);
+    }
+
+    #[test]
+    fn phase_shift() {
+        let transitions = [
+            (BLACK, 10, Interpolation::Piecewise),
+            (RGB8(100, 0, 0), 10, Interpolation::Piecewise),
+            (WHITE, 10, Interpolation::Piecewise),
+        ];
+        // 2 ms per mm, so key at x=5 mm is 10 ms ahead, y=-5 mm is 10 ms behind
+        let p = with_phase(Repeat::Wrap, &transitions, 2.0, 2.0);
+        assert_eq!(PatternSim::timeline(&p, (5.0, 0.0), 30, 10), [RGB8(100, 0, 0), WHITE, BLACK]);
+        assert_eq!(PatternSim::timeline(&p, (0.0, -5.0), 30, 10), [WHITE, BLACK, RGB8(100, 0, 0)]);
+        // Reflect cycle is 40 ms: black, red, white, red
+        let p = with_phase(Repeat::Reflect, &transitions, 1.0, 0.0);
+        assert_eq!(PatternSim::timeline(&p, (30.0, 0.0), 20, 10), [RGB8(100, 0, 0), BLACK]);
+        // Not repeating patterns are never shifted
+        let p = with_phase(Repeat::Once, &transitions, 2.0, 2.0);
+        assert_eq!(PatternSim::timeline(&p, (5.0, 0.0), 10, 10), [BLACK]);
+    }
+}
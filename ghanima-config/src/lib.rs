@@ -1,3 +1,4 @@
+pub mod blob;
 pub mod custom;
 pub mod format;
 pub mod layers;
@@ -12,34 +13,315 @@ use quote::{quote, ToTokens, TokenStreamExt};
 use serde::{Serialize, Deserialize};
 use schemars::{JsonSchema, schema_for, schema::RootSchema};
 
+/// Maximum number of SOCD key pairs, must match `MAX_PAIRS` in firmware
+const MAX_SOCD_PAIRS: usize = 8;
+
+/// Current version of the configuration format, older configurations are converted by [`KeyboardConfig::migrate`]
+pub const CONFIG_VERSION: u32 = 2;
+
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 pub struct KeyboardConfig {
+    /// Format version, configurations without it are version 1
+    #[serde(default = "legacy_version")]
+    version: u32,
+    aliases: layers::Aliases<custom::Action>,
     layers: layers::Layers<custom::Action>,
+    /// Layout configured in the host OS, layers are written in terms of what keys produce on it
+    host_layout: layers::host::HostLayout,
     mouse: mouse::MouseConfig,
     leds: leds::LedConfigurations,
+    /// LED rules applied on top of the current configuration while given layer (index) is active
+    layer_leds: leds::LedConfigurations,
     timeout: u32,
     bootload_strict: bool,
+    serial_baud_rate: u32,
+    tick_frequency_hz: u32,
+    debounce: DebounceConfig,
+    accessibility: AccessibilityConfig,
+    prescalers: Prescalers,
+    auto_repeat: AutoRepeatConfig,
+    consumer_repeat: ConsumerRepeatConfig,
+    num_lock: NumLockMode,
+    reactive: ReactiveConfig,
+    socd: Vec<SocdPair>,
+    /// Initial OS profile of shortcut actions
+    os_profile: custom::OsProfile,
+    brightness_presets: Vec<u8>,
+    led_brightness_limits: Vec<leds::BrightnessLimit>,
+    suspend_heartbeat: HeartbeatConfig,
+    training: TrainingConfig,
+    watchdog: WatchdogConfig,
+    features: Vec<Feature>,
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct DebounceConfig {
+    defer_ms: u16,
+    eager_ms: u16,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct AccessibilityConfig {
+    /// Slow keys: key must be held for this long before its press is reported
+    slow_keys_ms: u16,
+    /// Bounce keys: press of a key within this time after its release is ignored
+    bounce_keys_ms: u16,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct Prescalers {
+    leds: u32,
+    joystick: u32,
+    mouse: u32,
+    debug: u32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct AutoRepeatConfig {
+    enabled: bool,
+    navigation: RepeatTiming,
+    default: RepeatTiming,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct RepeatTiming {
+    delay: u16,
+    period: u16,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct ConsumerRepeatConfig {
+    keys: Vec<custom::ConsumerKey>,
+    profile: mouse::SpeedProfile,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct ReactiveConfig {
+    enabled: bool,
+    color: leds::RGB8,
+    fade: u16,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct HeartbeatConfig {
+    enabled: bool,
+    /// Key (global coordinates) with the LED used as the indicator
+    key: (u8, u8),
+    color: leds::RGB8,
+    /// Time between starts of subsequent pulses in milliseconds
+    period_ms: u16,
+    /// Duration of a single pulse in milliseconds
+    duration_ms: u16,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct TrainingConfig {
+    /// Teaching set of keys (e.g. home row) that is not dimmed in training mode
+    keys: leds::Keys,
+    /// Maximum brightness of LEDs outside of the teaching set
+    dim_brightness: u8,
+    /// How long the hint is shown after a wrong-layer press in milliseconds
+    hint_duration_ms: u16,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct SocdPair {
+    keys: [layers::KeyCode; 2],
+    mode: SocdMode,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub enum SocdMode {
+    /// Key pressed most recently overrides the other one
+    LastWins,
+    /// Neither key is reported while both are held
+    Neutral,
+    /// Key pressed first is kept until released
+    FirstWins,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct WatchdogConfig {
+    /// Start the watchdog, can be forced on by building firmware with "watchdog" feature
+    enabled: bool,
+    kind: WatchdogKind,
+    /// Minimum time between feeding the watchdog
+    window_start_ms: u32,
+    /// Maximum time between feeding the watchdog
+    window_end_ms: u32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub enum WatchdogKind {
+    /// Window watchdog (WWDG), period limited to ~87 ms
+    Window,
+    /// Independent watchdog (IWDG) clocked from inaccurate LSI, use wide window
+    Independent,
+}
+
+/// Firmware features required by the configuration
+///
+/// Building firmware without any of these features results in a compilation error.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub enum Feature {
+    /// Sleep in idle task
+    IdleSleep,
+    /// Use external crystal oscillator
+    Crystal,
+    /// Independent watchdog
+    Watchdog,
+    /// Send defmt logs over USB
+    DefmtUsb,
+    /// Debug shell on the serial console
+    DebugShell,
+}
+
+impl Feature {
+    /// Name of the cargo feature
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::IdleSleep => "idle-sleep",
+            Self::Crystal => "crystal",
+            Self::Watchdog => "watchdog",
+            Self::DefmtUsb => "defmt-usb",
+            Self::DebugShell => "debug-shell",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub enum NumLockMode {
+    /// NumLock is fully controlled by the user
+    Off,
+    /// Keep NumLock always enabled
+    On,
+    /// Keep NumLock enabled while one of the given layers is active
+    Layers(Vec<u8>),
 }
 
 impl ToTokens for KeyboardConfig {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let layers = layers::to_tokens(&self.layers);
+        let layers = layers::to_tokens(&self.host_layout.translate_layers(&self.layers));
         let leds = leds::to_tokens(&self.leds);
-        let mouse = &self.mouse;
+        let layer_leds = leds::to_tokens(&self.layer_leds);
+        let mouse = self.mouse.translate(self.host_layout);
         let timeout = &self.timeout;
         let bootload_strict = &self.bootload_strict;
+        let serial_baud_rate = &self.serial_baud_rate;
+        let tick_frequency_hz = &self.tick_frequency_hz;
+        let debounce = &self.debounce;
+        let accessibility = &self.accessibility;
+        let prescalers = &self.prescalers;
+        let auto_repeat = &self.auto_repeat;
+        let consumer_repeat = &self.consumer_repeat;
+        let num_lock = &self.num_lock;
+        let reactive = &self.reactive;
+        let socd = self.socd.iter().map(|pair| pair.translate(self.host_layout));
+        let os_profile = &self.os_profile;
+        let host_keys = &self.host_layout;
+        let brightness_presets = &self.brightness_presets;
+        let led_brightness_limits = &self.led_brightness_limits;
+        let suspend_heartbeat = &self.suspend_heartbeat;
+        let training = &self.training;
+        let watchdog = &self.watchdog;
         tokens.append_all(quote! {
             crate::keyboard::KeyboardConfig {
                 layers: &#layers,
                 mouse: &#mouse,
                 leds: #leds,
+                layer_leds: #layer_leds,
                 timeout: #timeout,
                 bootload_strict: #bootload_strict,
+                serial_baud_rate: #serial_baud_rate,
+                tick_frequency_hz: #tick_frequency_hz,
+                debounce: #debounce,
+                accessibility: #accessibility,
+                prescalers: #prescalers,
+                auto_repeat: #auto_repeat,
+                consumer_repeat: #consumer_repeat,
+                num_lock: #num_lock,
+                reactive: #reactive,
+                socd: &[ #( #socd ),* ],
+                os_profile: #os_profile,
+                host_keys: #host_keys,
+                brightness_presets: &[ #( #brightness_presets ),* ],
+                led_brightness_limits: &[ #( #led_brightness_limits ),* ],
+                suspend_heartbeat: #suspend_heartbeat,
+                training: #training,
+                watchdog: #watchdog,
             }
         })
     }
 }
 
+impl ToTokens for NumLockMode {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        tokens.append_all(match self {
+            Self::Off => quote! { crate::keyboard::num_lock::NumLockMode::Off },
+            Self::On => quote! { crate::keyboard::num_lock::NumLockMode::On },
+            Self::Layers(layers) => quote! {
+                crate::keyboard::num_lock::NumLockMode::Layers(&[ #( #layers ),* ])
+            },
+        })
+    }
+}
+
+impl SocdPair {
+    /// Pair with key codes translated for the host layout
+    fn translate(&self, host_layout: layers::host::HostLayout) -> Self {
+        let [a, b] = &self.keys;
+        Self { keys: [host_layout.translate(a), host_layout.translate(b)], mode: self.mode.clone() }
+    }
+}
+
+impl ToTokens for SocdPair {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let [a, b] = &self.keys;
+        let mode = &self.mode;
+        tokens.append_all(quote! {
+            crate::keyboard::socd::SocdPair { keys: [#a, #b], mode: #mode }
+        })
+    }
+}
+
+impl ToTokens for SocdMode {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        tokens.append_all(match self {
+            Self::LastWins => quote! { crate::keyboard::socd::SocdMode::LastWins },
+            Self::Neutral => quote! { crate::keyboard::socd::SocdMode::Neutral },
+            Self::FirstWins => quote! { crate::keyboard::socd::SocdMode::FirstWins },
+        })
+    }
+}
+
+impl ToTokens for HeartbeatConfig {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let HeartbeatConfig { enabled, key: (row, col), color, period_ms, duration_ms } = self;
+        tokens.append_all(quote! {
+            crate::keyboard::leds::HeartbeatConfig {
+                enabled: #enabled,
+                key: (#row, #col),
+                color: #color,
+                period_ms: #period_ms,
+                duration_ms: #duration_ms,
+            }
+        })
+    }
+}
+
+impl ToTokens for WatchdogKind {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        tokens.append_all(match self {
+            Self::Window => quote! { crate::hal_ext::watchdog::WatchdogKind::Window },
+            Self::Independent => quote! { crate::hal_ext::watchdog::WatchdogKind::Independent },
+        })
+    }
+}
+
 impl KeyboardConfig {
     fn n_layers(&self) -> usize {
         self.layers.len()
@@ -78,13 +360,35 @@ impl KeyboardConfig {
         let n_layers = self.n_layers();
         let n_cols = self.n_cols();
         let n_rows = self.n_rows();
+        let (n_actions, n_keycodes) = layers::nested_counts(&self.layers);
+        let (n_rules, n_transitions) = leds::counts(&self.leds);
+        let (n_layer_rules, n_layer_transitions) = leds::counts(&self.layer_leds);
+        let (n_rules, n_transitions) = (n_rules + n_layer_rules, n_transitions + n_layer_transitions);
+        let action = quote! { keyberon::action::Action<crate::keyboard::actions::Action> };
+        let features = self.features.iter().map(|f| {
+            let name = f.name();
+            let msg = format!("Configuration requires firmware feature \"{}\"", name);
+            quote! {
+                #[cfg(not(feature = #name))]
+                compile_error!(#msg);
+            }
+        });
         quote! {
+            #( #features )*
+
             pub const CONFIG: crate::keyboard::KeyboardConfig<N_LAYERS> = #self;
             pub const N_LAYERS: usize = #n_layers;
             #[allow(dead_code)]
             pub const N_COLS: usize = #n_cols;
             #[allow(dead_code)]
             pub const N_ROWS: usize = #n_rows;
+            /// Approximate size of configuration data stored in flash
+            pub const DATA_SIZE: usize =
+                core::mem::size_of::<[[[#action; N_COLS]; N_ROWS]; N_LAYERS]>()
+                + #n_actions * core::mem::size_of::<#action>()
+                + #n_keycodes * core::mem::size_of::<keyberon::key_code::KeyCode>()
+                + #n_rules * core::mem::size_of::<crate::keyboard::leds::LedRule>()
+                + #n_transitions * core::mem::size_of::<crate::keyboard::leds::Transition>();
         }
     }
 
@@ -105,10 +409,95 @@ impl KeyboardConfig {
     pub fn from_file(path: &Path) -> anyhow::Result<Self> {
         let file = File::open(path)?;
         let mut reader = BufReader::new(file);
-        let config = serde_json::from_reader(&mut reader)?;
+        let mut config: Self = serde_json::from_reader(&mut reader)?;
+        config.migrate()?;
+        config.expand_aliases()?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Check parameters that would otherwise result in firmware misbehaving at runtime
+    pub fn validate(&self) -> anyhow::Result<()> {
+        layers::validate(&self.layers, |action| self.validate_custom(action))?;
+        self.mouse.validate().context("Mouse configuration")?;
+        anyhow::ensure!(self.brightness_presets.len() <= u8::MAX as usize,
+            "Too many brightness presets: {} > {}", self.brightness_presets.len(), u8::MAX);
+        for (i, percent) in self.brightness_presets.iter().enumerate() {
+            anyhow::ensure!(*percent <= 100, "Brightness preset {} out of range: {}% > 100%", i, percent);
+        }
+        anyhow::ensure!(self.watchdog.window_start_ms < self.watchdog.window_end_ms,
+            "Watchdog window start must be before its end: {} ms >= {} ms",
+            self.watchdog.window_start_ms, self.watchdog.window_end_ms);
+        let (n_rows, n_cols) = (self.n_rows(), self.n_cols());
+        leds::validate(&self.leds, n_rows, n_cols).context("LED configurations")?;
+        leds::validate(&self.layer_leds, n_rows, n_cols).context("Layer LED configurations")?;
+        for limit in &self.led_brightness_limits {
+            if let Some(keys) = &limit.keys {
+                keys.validate(n_rows, n_cols).context("LED brightness limits")?;
+            }
+        }
+        self.training.keys.validate(n_rows, n_cols).context("Training keys")?;
+        anyhow::ensure!(self.layer_leds.len() <= self.n_layers(),
+            "Too many layer LED configurations: {} > {} layers", self.layer_leds.len(), self.n_layers());
+        anyhow::ensure!(self.socd.len() <= MAX_SOCD_PAIRS,
+            "Too many SOCD key pairs: {} > {}", self.socd.len(), MAX_SOCD_PAIRS);
+        if let NumLockMode::Layers(layers) = &self.num_lock {
+            for layer in layers {
+                anyhow::ensure!((*layer as usize) < self.n_layers(),
+                    "NumLock layer {} out of range, there are {} layers", layer, self.n_layers());
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_custom(&self, action: &custom::Action) -> anyhow::Result<()> {
+        use custom::{Action, LedAction};
+        match action {
+            Action::Led(LedAction::Cycle(_)) => anyhow::ensure!(!self.leds.is_empty(),
+                "LED Cycle action requires at least one LED configuration"),
+            Action::Led(LedAction::BrightnessPreset) => anyhow::ensure!(!self.brightness_presets.is_empty(),
+                "BrightnessPreset action requires non-empty brightness_presets"),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Convert configuration of an older format version to [`CONFIG_VERSION`]
+    ///
+    /// Version 1 used side-local columns in LED `Cols` and `Keys`, so each entry also matched the
+    /// mirrored key on the other half. Version 2 uses global columns, so mirrored keys are added.
+    pub fn migrate(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.version <= CONFIG_VERSION,
+            "Unsupported configuration version: {} > {}", self.version, CONFIG_VERSION);
+        if self.version < 2 {
+            let n_cols = self.n_cols();
+            leds::add_mirrored(&mut self.leds, n_cols);
+            leds::add_mirrored(&mut self.layer_leds, n_cols);
+            for keys in self.led_brightness_limits.iter_mut().filter_map(|limit| limit.keys.as_mut()) {
+                keys.add_mirrored(n_cols);
+            }
+            self.training.keys.add_mirrored(n_cols);
+        }
+        self.version = CONFIG_VERSION;
+        Ok(())
+    }
+
+    /// Replace aliases used in layers and flick actions with actions they refer to
+    pub fn expand_aliases(&mut self) -> anyhow::Result<()> {
+        layers::expand_aliases(&mut self.layers, &self.aliases)?;
+        self.mouse.expand_aliases(&self.aliases).context("Mouse flick actions")
+    }
+
+    /// Keyboard layers, e.g. for [`layers::svg`]
+    pub fn layers(&self) -> &layers::Layers<custom::Action> {
+        &self.layers
+    }
+
+    /// LED configurations, e.g. for [`leds::preview`]
+    pub fn leds(&self) -> &leds::LedConfigurations {
+        &self.leds
+    }
+
     pub fn schema() -> RootSchema {
         schema_for!(Self)
     }
@@ -140,16 +529,16 @@ macro_rules! impl_enum_to_tokens {
     };
 }
 
-/// Implement ToTokens for a simple enum with tuple-like variants.
+/// Implement ToTokens for a simple enum with tuple-like or unit variants.
 #[macro_export]
 macro_rules! impl_enum_tuple_to_tokens {
-    ( $( enum $enum:ident: $path:path { $( $variant:ident( $( $field:ident ),* ) ),* } )* ) => {
+    ( $( enum $enum:ident: $path:path { $( $variant:ident $( ( $( $field:ident ),* ) )? ),* } )* ) => {
         $(
             impl ToTokens for $enum {
                 fn to_tokens(&self, tokens: &mut TokenStream) {
                     tokens.append_all(match self {
                         $(
-                            Self::$variant( $( $field ),* ) => quote! { $path::$variant( $( #$field ),* ) }
+                            Self::$variant $( ( $( $field ),* ) )? => quote! { $path::$variant $( ( $( #$field ),* ) )? }
                         ),*
                     });
                 }
@@ -241,6 +630,18 @@ macro_rules! impl_struct_to_tokens {
     ( @tokens $tokens:ident ) => {};
 }
 
+impl_struct_to_tokens! {
+    struct DebounceConfig: crate::keyboard::DebounceConfig { defer_ms, eager_ms, }
+    struct AccessibilityConfig: crate::keyboard::AccessibilityConfig { slow_keys_ms, bounce_keys_ms, }
+    struct Prescalers: crate::keyboard::Prescalers { leds, joystick, mouse, debug, }
+    struct AutoRepeatConfig: crate::keyboard::hid::AutoRepeatConfig { enabled, navigation, default, }
+    struct RepeatTiming: crate::keyboard::hid::RepeatTiming { delay, period, }
+    struct ConsumerRepeatConfig: crate::keyboard::hid::ConsumerRepeatConfig { &[keys], &profile, }
+    struct ReactiveConfig: crate::keyboard::leds::ReactiveConfig { enabled, color, fade, }
+    struct TrainingConfig: crate::keyboard::training::TrainingConfig { &keys, dim_brightness, hint_duration_ms, }
+    struct WatchdogConfig: crate::hal_ext::watchdog::WatchdogConfig { enabled, kind, window_start_ms, window_end_ms, }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::format::assert_tokens_eq;
@@ -249,21 +650,122 @@ mod tests {
 
     pub fn example_json() -> serde_json::Value {
         serde_json::json!({
+            "aliases": {
+                "Copy": { "MultipleKeyCodes": ["LCtrl", "C"] },
+            },
             "layers": layers::tests::example_json(),
+            "host_layout": "Qwerty",
             "leds": leds::tests::example_json(),
+            "layer_leds": [],
             "mouse": mouse::tests::example_json(),
             "timeout": 1000u32,
             "bootload_strict": true,
+            "serial_baud_rate": 460800u32,
+            "tick_frequency_hz": 1000u32,
+            "debounce": { "defer_ms": 5u16, "eager_ms": 5u16 },
+            "accessibility": { "slow_keys_ms": 300u16, "bounce_keys_ms": 250u16 },
+            "prescalers": {
+                "leds": 10u32,
+                "joystick": 10u32,
+                "mouse": 1u32,
+                "debug": 1000u32,
+            },
+            "auto_repeat": {
+                "enabled": false,
+                "navigation": { "delay": 250u16, "period": 20u16 },
+                "default": { "delay": 500u16, "period": 33u16 },
+            },
+            "consumer_repeat": {
+                "keys": ["VolumeIncrement", "VolumeDecrement"],
+                "profile": {
+                    "divider": 1000u16,
+                    "delay": 300u16,
+                    "acceleration_time": 1500u16,
+                    "start_speed": 8u16,
+                    "max_speed": 40u16,
+                },
+            },
+            "num_lock": { "Layers": [2u8] },
+            "reactive": { "enabled": true, "color": [255u8, 0u8, 0u8], "fade": 30u16 },
+            "socd": [
+                { "keys": ["A", "D"], "mode": "LastWins" },
+                { "keys": ["W", "S"], "mode": "Neutral" },
+            ],
+            "os_profile": "Windows",
+            "brightness_presets": [0u8, 25u8, 100u8],
+            "led_brightness_limits": [
+                { "keys": { "Rows": [4u8] }, "max": 100u8 },
+            ],
+            "suspend_heartbeat": { "enabled": true, "key": [0u8, 5u8], "color": [0u8, 0u8, 80u8], "period_ms": 4000u16, "duration_ms": 300u16 },
+            "training": { "keys": { "Rows": [2u8] }, "dim_brightness": 30u8, "hint_duration_ms": 1500u16 },
+            "watchdog": { "enabled": true, "kind": "Independent", "window_start_ms": 100u32, "window_end_ms": 500u32 },
+            "features": ["Watchdog"],
         })
     }
 
     pub fn example_config() -> KeyboardConfig {
         KeyboardConfig {
+            version: CONFIG_VERSION,
+            aliases: [
+                ("Copy".to_string(), layers::Act::MultipleKeyCodes(vec![layers::KeyCode::LCtrl, layers::KeyCode::C])),
+            ].into(),
             layers: layers::tests::example_config(),
+            host_layout: layers::host::HostLayout::Qwerty,
             leds: leds::tests::example_config(),
+            layer_leds: vec![],
             mouse: mouse::tests::example_config(),
             timeout: 1000,
             bootload_strict: true,
+            serial_baud_rate: 460800,
+            tick_frequency_hz: 1000,
+            debounce: DebounceConfig { defer_ms: 5, eager_ms: 5 },
+            accessibility: AccessibilityConfig { slow_keys_ms: 300, bounce_keys_ms: 250 },
+            prescalers: Prescalers { leds: 10, joystick: 10, mouse: 1, debug: 1000 },
+            auto_repeat: AutoRepeatConfig {
+                enabled: false,
+                navigation: RepeatTiming { delay: 250, period: 20 },
+                default: RepeatTiming { delay: 500, period: 33 },
+            },
+            consumer_repeat: ConsumerRepeatConfig {
+                keys: vec![custom::ConsumerKey::VolumeIncrement, custom::ConsumerKey::VolumeDecrement],
+                profile: mouse::SpeedProfile {
+                    divider: 1000,
+                    delay: 300,
+                    acceleration_time: 1500,
+                    start_speed: 8,
+                    max_speed: 40,
+                },
+            },
+            num_lock: NumLockMode::Layers(vec![2]),
+            reactive: ReactiveConfig { enabled: true, color: leds::RGB8(255, 0, 0), fade: 30 },
+            socd: vec![
+                SocdPair { keys: [layers::KeyCode::A, layers::KeyCode::D], mode: SocdMode::LastWins },
+                SocdPair { keys: [layers::KeyCode::W, layers::KeyCode::S], mode: SocdMode::Neutral },
+            ],
+            os_profile: custom::OsProfile::Windows,
+            brightness_presets: vec![0, 25, 100],
+            led_brightness_limits: vec![
+                leds::BrightnessLimit { keys: Some(leds::Keys::Rows(vec![4])), max: 100 },
+            ],
+            suspend_heartbeat: HeartbeatConfig {
+                enabled: true,
+                key: (0, 5),
+                color: leds::RGB8(0, 0, 80),
+                period_ms: 4000,
+                duration_ms: 300,
+            },
+            training: TrainingConfig {
+                keys: leds::Keys::Rows(vec![2]),
+                dim_brightness: 30,
+                hint_duration_ms: 1500,
+            },
+            watchdog: WatchdogConfig {
+                enabled: true,
+                kind: WatchdogKind::Independent,
+                window_start_ms: 100,
+                window_end_ms: 500,
+            },
+            features: vec![Feature::Watchdog],
         }
     }
 
@@ -276,8 +778,92 @@ mod tests {
                 layers: &#layers,
                 mouse: &#mouse,
                 leds: #leds,
+                layer_leds: &[],
                 timeout: 1000u32,
                 bootload_strict: true,
+                serial_baud_rate: 460800u32,
+                tick_frequency_hz: 1000u32,
+                debounce: crate::keyboard::DebounceConfig {
+                    defer_ms: 5u16,
+                    eager_ms: 5u16,
+                },
+                accessibility: crate::keyboard::AccessibilityConfig {
+                    slow_keys_ms: 300u16,
+                    bounce_keys_ms: 250u16,
+                },
+                prescalers: crate::keyboard::Prescalers {
+                    leds: 10u32,
+                    joystick: 10u32,
+                    mouse: 1u32,
+                    debug: 1000u32,
+                },
+                auto_repeat: crate::keyboard::hid::AutoRepeatConfig {
+                    enabled: false,
+                    navigation: crate::keyboard::hid::RepeatTiming {
+                        delay: 250u16,
+                        period: 20u16,
+                    },
+                    default: crate::keyboard::hid::RepeatTiming {
+                        delay: 500u16,
+                        period: 33u16,
+                    },
+                },
+                consumer_repeat: crate::keyboard::hid::ConsumerRepeatConfig {
+                    keys: &[
+                        usbd_human_interface_device::page::Consumer::VolumeIncrement,
+                        usbd_human_interface_device::page::Consumer::VolumeDecrement,
+                    ],
+                    profile: &crate::keyboard::mouse::SpeedProfile {
+                        divider: 1000u16,
+                        delay: 300u16,
+                        acceleration_time: 1500u16,
+                        start_speed: 8u16,
+                        max_speed: 40u16,
+                    },
+                },
+                num_lock: crate::keyboard::num_lock::NumLockMode::Layers(&[2u8]),
+                reactive: crate::keyboard::leds::ReactiveConfig {
+                    enabled: true,
+                    color: rgb::RGB8::new(255u8, 0u8, 0u8),
+                    fade: 30u16,
+                },
+                socd: &[
+                    crate::keyboard::socd::SocdPair {
+                        keys: [keyberon::key_code::KeyCode::A, keyberon::key_code::KeyCode::D],
+                        mode: crate::keyboard::socd::SocdMode::LastWins,
+                    },
+                    crate::keyboard::socd::SocdPair {
+                        keys: [keyberon::key_code::KeyCode::W, keyberon::key_code::KeyCode::S],
+                        mode: crate::keyboard::socd::SocdMode::Neutral,
+                    },
+                ],
+                os_profile: crate::keyboard::shortcuts::OsProfile::Windows,
+                host_keys: &[],
+                brightness_presets: &[0u8, 25u8, 100u8],
+                led_brightness_limits: &[
+                    crate::keyboard::leds::BrightnessLimit {
+                        keys: Some(&crate::keyboard::leds::Keys::Rows(&[4u8])),
+                        max: 100u8,
+                    },
+                ],
+                suspend_heartbeat: crate::keyboard::leds::HeartbeatConfig {
+                    enabled: true,
+                    key: (0u8, 5u8),
+                    color: rgb::RGB8::new(0u8, 0u8, 80u8),
+                    period_ms: 4000u16,
+                    duration_ms: 300u16,
+                },
+                training: crate::keyboard::training::TrainingConfig {
+                    keys: &crate::keyboard::leds::Keys::Rows(&[2u8]),
+                    dim_brightness: 30u8,
+                    hint_duration_ms: 1500u16,
+                },
+                watchdog: crate::hal_ext::watchdog::WatchdogConfig {
+                    enabled: true,
+                    kind: crate::hal_ext::watchdog::WatchdogKind::Independent,
+                    window_start_ms: 100u32,
+                    window_end_ms: 500u32,
+                },
             }
         }
     }
@@ -295,6 +881,82 @@ mod tests {
         assert_tokens_eq(quote! { #config }, example_code())
     }
 
+    #[test]
+    fn host_layout() {
+        let mut config = example_config();
+        config.host_layout = layers::host::HostLayout::Azerty;
+        let socd = config.socd.iter().map(|pair| pair.translate(config.host_layout)).collect::<Vec<_>>();
+        assert_eq!(socd[0].keys, [layers::KeyCode::Q, layers::KeyCode::D]);
+        assert_eq!(socd[1].keys, [layers::KeyCode::Z, layers::KeyCode::S]);
+    }
+
+    #[test]
+    fn validate() {
+        let mut config = example_config();
+        // Enough rows for the keys used by LED rules, brightness limits and training
+        let layer = vec![config.layers[0][0].clone(); 5];
+        config.layers = vec![layer; 4];
+        assert!(config.validate().is_ok());
+
+        config.training.keys = leds::Keys::Keys(vec![(4, 9)]);
+        assert!(config.validate().is_err());
+        config.training.keys = leds::Keys::Keys(vec![(4, 8)]);
+        assert!(config.validate().is_ok());
+
+        config.layers[0][0][0] = layers::Act::Custom(custom::Action::Led(custom::LedAction::BrightnessPreset));
+        assert!(config.validate().is_ok());
+        config.brightness_presets.clear();
+        assert!(config.validate().is_err());
+        config.brightness_presets = vec![50, 120];
+        assert!(config.validate().is_err());
+        config.brightness_presets = vec![50];
+
+        config.watchdog.window_start_ms = config.watchdog.window_end_ms;
+        assert!(config.validate().is_err());
+        config.watchdog.window_start_ms = 0;
+        assert!(config.validate().is_ok());
+
+        config.num_lock = NumLockMode::Layers(vec![4]);
+        assert!(config.validate().is_err());
+        config.num_lock = NumLockMode::Off;
+
+        let pair = || SocdPair { keys: [layers::KeyCode::A, layers::KeyCode::D], mode: SocdMode::LastWins };
+        config.socd = (0..MAX_SOCD_PAIRS).map(|_| pair()).collect();
+        assert!(config.validate().is_ok());
+        config.socd.push(pair());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn migrate() {
+        let mut json = serde_json::to_value(example_config()).unwrap();
+        json.as_object_mut().unwrap().remove("version");
+        let mut config: KeyboardConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(config.version, 1);
+
+        let last = config.n_cols() as u8 - 1;
+        config.training.keys = leds::Keys::Keys(vec![(0, 1)]);
+        config.migrate().unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.training.keys, leds::Keys::Keys(vec![(0, 1), (0, last - 1)]));
+        // Already migrated
+        config.migrate().unwrap();
+        assert_eq!(config.training.keys, leds::Keys::Keys(vec![(0, 1), (0, last - 1)]));
+
+        config.version = CONFIG_VERSION + 1;
+        assert!(config.migrate().is_err());
+    }
+
+    #[test]
+    fn required_features() {
+        let code = example_config().file_tokens().to_string();
+        let check = quote! {
+            #[cfg(not(feature = "watchdog"))]
+            compile_error!("Configuration requires firmware feature \"watchdog\"");
+        };
+        assert!(code.starts_with(&check.to_string()));
+    }
+
     // #[test]
     // fn example() -> anyhow::Result<()> {
     //     let config = KeyboardConfig::from_file(Path::new("./config.json"))?;
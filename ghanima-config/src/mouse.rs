@@ -2,7 +2,10 @@ use quote::{quote, ToTokens, TokenStreamExt};
 use serde::{Serialize, Deserialize};
 use schemars::JsonSchema;
 
-use crate::impl_struct_to_tokens;
+use crate::{impl_struct_to_tokens, impl_struct_to_blob};
+use crate::custom;
+use crate::layers::{Act, Aliases};
+use crate::layers::host::HostLayout;
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
 pub struct MouseConfig {
@@ -11,6 +14,60 @@ pub struct MouseConfig {
     wheel: AxisConfig,
     pan: AxisConfig,
     joystick: JoystickConfig,
+    /// Output scale in percent applied while precision mode key is held
+    precision_scale: u8,
+    /// Pointer movement snaps to the exact axis if the speed along the other axis is at most
+    /// this percentage of it (18% is about 10 degrees), 0 disables snapping
+    angle_snap: u8,
+    /// Pointer speeds used while speed tier keys are held, the slowest one wins
+    speed_tiers: SpeedTiers,
+    /// Emission of accumulated wheel values
+    scroll: ScrollConfig,
+    /// Automatic clicking when joystick pointer stops
+    dwell: DwellConfig,
+    /// Actions triggered by quick joystick flicks
+    flick: FlickConfig,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct SpeedTiers {
+    slow: SpeedProfile,
+    medium: SpeedProfile,
+    fast: SpeedProfile,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct DwellConfig {
+    /// Time from when the joystick pointer stops to the left click in milliseconds
+    delay_ms: u16,
+    /// Key (global row, column) with the LED showing the remaining time
+    indicator: (u8, u8),
+}
+
+/// Quick tilt of the joystick that leaves and returns to the rest position
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct FlickConfig {
+    /// Deflection that has to be reached, 0 disables gestures
+    threshold: u16,
+    /// Maximum number of ticks from leaving the rest position to reaching the threshold
+    rise_time: u16,
+    /// Maximum number of ticks from leaving the rest position to returning to it
+    max_duration: u16,
+    /// Minimum number of ticks between two gestures
+    cooldown: u16,
+    /// Action tapped on a flick: key code, key chord (`MultipleKeyCodes`) or custom action
+    left: Option<Act<custom::Action>>,
+    right: Option<Act<custom::Action>>,
+    up: Option<Act<custom::Action>>,
+    down: Option<Act<custom::Action>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct ScrollConfig {
+    /// Maximum wheel value in a single report, 0 means no limit
+    max_lines: u8,
+    /// Minimum number of ticks between reports with wheel movement
+    min_interval: u16,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -22,11 +79,11 @@ pub struct AxisConfig {
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
 pub struct SpeedProfile {
-    divider: u16,
-    delay: u16,
-    acceleration_time: u16,
-    start_speed: u16,
-    max_speed: u16,
+    pub divider: u16,
+    pub delay: u16,
+    pub acceleration_time: u16,
+    pub start_speed: u16,
+    pub max_speed: u16,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -40,61 +97,118 @@ pub struct JoystickConfig {
 }
 
 impl_struct_to_tokens! {
-    struct MouseConfig: crate::keyboard::mouse::MouseConfig { x, y, wheel, pan, joystick, }
+    struct MouseConfig: crate::keyboard::mouse::MouseConfig { x, y, wheel, pan, joystick, precision_scale, angle_snap, speed_tiers, scroll, dwell, flick, }
     struct AxisConfig: crate::keyboard::mouse::AxisConfig { invert, &profile, }
     struct SpeedProfile: crate::keyboard::mouse::SpeedProfile { divider, delay, acceleration_time, start_speed, max_speed, }
+    struct SpeedTiers: crate::keyboard::mouse::SpeedTiers { &slow, &medium, &fast, }
     struct JoystickConfig: crate::keyboard::mouse::JoystickConfig { min, max, divider, swap_axes, invert_x, invert_y, }
+    struct ScrollConfig: crate::keyboard::mouse::ScrollConfig { max_lines, min_interval, }
+    struct FlickConfig: crate::keyboard::mouse::FlickConfig { threshold, rise_time, max_duration, cooldown, &?left, &?right, &?up, &?down, }
+}
+
+impl_struct_to_blob! {
+    struct MouseConfig { x, y, wheel, pan, joystick, precision_scale, angle_snap, speed_tiers, scroll, dwell, flick }
+    struct AxisConfig { invert, profile }
+    struct SpeedProfile { divider, delay, acceleration_time, start_speed, max_speed }
+    struct SpeedTiers { slow, medium, fast }
+    struct JoystickConfig { min, max, divider, swap_axes, invert_x, invert_y }
+    struct ScrollConfig { max_lines, min_interval }
+    struct DwellConfig { delay_ms, indicator }
+    struct FlickConfig { threshold, rise_time, max_duration, cooldown, left, right, up, down }
+}
+
+impl MouseConfig {
+    /// Replace aliases used in flick actions
+    pub fn expand_aliases(&mut self, aliases: &Aliases<custom::Action>) -> anyhow::Result<()> {
+        self.flick.actions_mut().try_for_each(|act| act.expand_aliases(aliases))
+    }
+
+    /// Check that flick actions can be tapped
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let f = &self.flick;
+        for act in [&f.left, &f.right, &f.up, &f.down].into_iter().flatten() {
+            anyhow::ensure!(matches!(act, Act::KeyCode(_) | Act::MultipleKeyCodes(_) | Act::Custom(_)),
+                "Flick action must be a key code, key chord or custom action: {:?}", act);
+        }
+        Ok(())
+    }
+
+    /// Copy with key codes of flick actions translated for the host layout
+    pub fn translate(&self, host_layout: HostLayout) -> Self {
+        let mut config = self.clone();
+        config.flick.actions_mut().for_each(|act| host_layout.translate_action(act));
+        config
+    }
+}
+
+impl FlickConfig {
+    fn actions_mut(&mut self) -> impl Iterator<Item = &mut Act<custom::Action>> {
+        [&mut self.left, &mut self.right, &mut self.up, &mut self.down].into_iter().flatten()
+    }
+}
+
+impl ToTokens for DwellConfig {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let DwellConfig { delay_ms, indicator: (row, col) } = self;
+        tokens.append_all(quote! {
+            crate::keyboard::mouse::DwellConfig {
+                delay_ms: #delay_ms,
+                indicator: (#row, #col),
+            }
+        })
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
     use proc_macro2::TokenStream;
     use crate::format::assert_tokens_eq;
+    use crate::layers::KeyCode;
     use super::*;
 
     pub fn example_json() -> serde_json::Value {
         serde_json::json!({
-                "x": {
+            "x": {
                 "invert": false,
                 "profile": {
-                "divider": 10000,
-                "delay": 50,
-                "acceleration_time": 750,
-                "start_speed": 5000,
-                "max_speed": 15000,
-            },
+                    "divider": 10000,
+                    "delay": 50,
+                    "acceleration_time": 750,
+                    "start_speed": 5000,
+                    "max_speed": 15000,
+                },
             },
-                "y": {
+            "y": {
                 "invert": false,
                 "profile": {
-                "divider": 10000,
-                "delay": 50,
-                "acceleration_time": 750,
-                "start_speed": 5000,
-                "max_speed": 15000,
-            },
+                    "divider": 10000,
+                    "delay": 50,
+                    "acceleration_time": 750,
+                    "start_speed": 5000,
+                    "max_speed": 15000,
+                },
             },
-                "wheel": {
+            "wheel": {
                 "invert": true,
                 "profile": {
-                "divider": 1000,
-                "delay": 50,
-                "acceleration_time": 750,
-                "start_speed": 25,
-                "max_speed": 50,
-            },
+                    "divider": 1000,
+                    "delay": 50,
+                    "acceleration_time": 750,
+                    "start_speed": 25,
+                    "max_speed": 50,
+                },
             },
-                "pan": {
+            "pan": {
                 "invert": false,
                 "profile": {
-                "divider": 1000,
-                "delay": 50,
-                "acceleration_time": 750,
-                "start_speed": 25,
-                "max_speed": 50,
-            },
+                    "divider": 1000,
+                    "delay": 50,
+                    "acceleration_time": 750,
+                    "start_speed": 25,
+                    "max_speed": 50,
+                },
             },
-                "joystick": {
+            "joystick": {
                 "min": 175,
                 "max": 4000,
                 "divider": 800,
@@ -102,6 +216,49 @@ pub mod tests {
                 "invert_x": false,
                 "invert_y": true,
             },
+            "precision_scale": 25,
+            "angle_snap": 18,
+            "speed_tiers": {
+                "slow": {
+                    "divider": 10000,
+                    "delay": 50,
+                    "acceleration_time": 0,
+                    "start_speed": 2500,
+                    "max_speed": 2500,
+                },
+                "medium": {
+                    "divider": 10000,
+                    "delay": 50,
+                    "acceleration_time": 0,
+                    "start_speed": 7500,
+                    "max_speed": 7500,
+                },
+                "fast": {
+                    "divider": 10000,
+                    "delay": 50,
+                    "acceleration_time": 0,
+                    "start_speed": 20000,
+                    "max_speed": 20000,
+                },
+            },
+            "scroll": {
+                "max_lines": 3,
+                "min_interval": 20,
+            },
+            "dwell": {
+                "delay_ms": 800,
+                "indicator": [4, 3],
+            },
+            "flick": {
+                "threshold": 3000,
+                "rise_time": 60,
+                "max_duration": 250,
+                "cooldown": 300,
+                "left": null,
+                "right": { "Custom": { "Mouse": "JoystickPlane" } },
+                "up": { "MultipleKeyCodes": ["LCtrl", "Up"] },
+                "down": null,
+            },
         })
     }
 
@@ -154,7 +311,50 @@ pub mod tests {
                 swap_axes: false,
                 invert_x: false,
                 invert_y: true,
-            }
+            },
+            precision_scale: 25,
+            angle_snap: 18,
+            speed_tiers: SpeedTiers {
+                slow: SpeedProfile {
+                    divider: 10000,
+                    delay: 50,
+                    acceleration_time: 0,
+                    start_speed: 2500,
+                    max_speed: 2500,
+                },
+                medium: SpeedProfile {
+                    divider: 10000,
+                    delay: 50,
+                    acceleration_time: 0,
+                    start_speed: 7500,
+                    max_speed: 7500,
+                },
+                fast: SpeedProfile {
+                    divider: 10000,
+                    delay: 50,
+                    acceleration_time: 0,
+                    start_speed: 20000,
+                    max_speed: 20000,
+                },
+            },
+            scroll: ScrollConfig {
+                max_lines: 3,
+                min_interval: 20,
+            },
+            dwell: DwellConfig {
+                delay_ms: 800,
+                indicator: (4, 3),
+            },
+            flick: FlickConfig {
+                threshold: 3000,
+                rise_time: 60,
+                max_duration: 250,
+                cooldown: 300,
+                left: None,
+                right: Some(Act::Custom(custom::Action::Mouse(custom::MouseAction::JoystickPlane))),
+                up: Some(Act::MultipleKeyCodes(vec![KeyCode::LCtrl, KeyCode::Up])),
+                down: None,
+            },
         }
     }
 
@@ -208,7 +408,54 @@ pub mod tests {
                     swap_axes: false,
                     invert_x: false,
                     invert_y: true,
-                }
+                },
+                precision_scale: 25u8,
+                angle_snap: 18u8,
+                speed_tiers: crate::keyboard::mouse::SpeedTiers {
+                    slow: &crate::keyboard::mouse::SpeedProfile {
+                        divider: 10000u16,
+                        delay: 50u16,
+                        acceleration_time: 0u16,
+                        start_speed: 2500u16,
+                        max_speed: 2500u16,
+                    },
+                    medium: &crate::keyboard::mouse::SpeedProfile {
+                        divider: 10000u16,
+                        delay: 50u16,
+                        acceleration_time: 0u16,
+                        start_speed: 7500u16,
+                        max_speed: 7500u16,
+                    },
+                    fast: &crate::keyboard::mouse::SpeedProfile {
+                        divider: 10000u16,
+                        delay: 50u16,
+                        acceleration_time: 0u16,
+                        start_speed: 20000u16,
+                        max_speed: 20000u16,
+                    },
+                },
+                scroll: crate::keyboard::mouse::ScrollConfig {
+                    max_lines: 3u8,
+                    min_interval: 20u16,
+                },
+                dwell: crate::keyboard::mouse::DwellConfig {
+                    delay_ms: 800u16,
+                    indicator: (4u8, 3u8),
+                },
+                flick: crate::keyboard::mouse::FlickConfig {
+                    threshold: 3000u16,
+                    rise_time: 60u16,
+                    max_duration: 250u16,
+                    cooldown: 300u16,
+                    left: None,
+                    right: Some(&keyberon::action::Action::Custom(crate::keyboard::actions::Action::Mouse(
+                        crate::keyboard::actions::MouseAction::JoystickPlane
+                    ))),
+                    up: Some(&keyberon::action::Action::MultipleKeyCodes(
+                        &[keyberon::key_code::KeyCode::LCtrl, keyberon::key_code::KeyCode::Up].as_slice()
+                    )),
+                    down: None,
+                },
             }
         }
     }
@@ -225,4 +472,17 @@ pub mod tests {
         let mouse = example_config();
         assert_tokens_eq(quote! { #mouse }, example_code())
     }
+
+    #[test]
+    fn blob() -> anyhow::Result<()> {
+        let mut blob = crate::blob::Blob::default();
+        crate::blob::ToBlob::to_blob(&example_config(), &mut blob)?;
+        let data = blob.into_bytes();
+        // 4 axes, joystick, precision scale, angle snap, speed tiers, scroll, dwell, flick
+        assert_eq!(data.len(), 4 * 11 + 9 + 1 + 1 + 3 * 10 + 3 + 4 + 8 + 1 + 5 + 5 + 1);
+        assert_eq!(data[data.len() - 11..], [0, 1, 8, 1, 7, 1, 3, 2, 0xe0, 0x52, 0]);
+        // Wheel is inverted, its divider follows
+        assert_eq!(data[22..25], [1, 0xe8, 0x03]);
+        Ok(())
+    }
 }
@@ -1,6 +1,7 @@
 MEMORY
 {
   /* STM32F072C8Tx */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* Last 2K page is reserved for key usage statistics */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 62K
   RAM :   ORIGIN = 0x20000000, LENGTH = 16K
 }
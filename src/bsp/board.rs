@@ -0,0 +1,523 @@
+use core::convert::Infallible;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::hal;
+use super::{NCOLS, NROWS, NAUX_KEYS, sides::BoardSide};
+
+/// GPIO port
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Port {
+    A = 0,
+    B = 1,
+    C = 2,
+}
+
+/// GPIO pin identifier
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct PinId {
+    pub port: Port,
+    pub pin: u8,
+}
+
+/// Description of PCB wiring
+///
+/// Collects everything that depends on how a given PCB revision has been routed, so that
+/// supporting a revised or differently wired PCB only requires adding a new description.
+/// The active description is [`BOARD`]; matrix dimensions in [`crate::bsp`] are derived from it.
+pub struct Board<const C: usize, const R: usize> {
+    /// Name of PCB revision
+    pub name: &'static str,
+    /// Number of PCB revision, exchanged between halves to detect mismatched boards
+    pub revision: u8,
+    /// Key matrix column inputs (pull-up, key press pulls them low)
+    pub cols: [PinId; C],
+    /// Key matrix row outputs (active low)
+    pub rows: [PinId; R],
+    /// Number of "column-slots" in the last (thumb) row
+    pub ncols_thumb: usize,
+    /// How to determine which half the firmware is running on
+    pub side: SideDetection,
+    /// RGB LED position in the chain for side-local key coordinates, `None` for keys without LED
+    ///
+    /// Both sides must be routed in the same way.
+    pub led_numbers: [[Option<u8>; C]; R],
+    /// Keys wired directly to GPIOs outside of the matrix
+    pub aux_keys: &'static [AuxKey],
+    /// Input connected to VBUS of the USB connector through a voltage divider (high when 5 V
+    /// is present), `None` if not routed
+    ///
+    /// Without it only dedicated chargers can be detected, using the USB data lines.
+    pub vbus_sense: Option<PinId>,
+    /// Logical side-local coordinates of the key at each matrix position, `None` when wiring
+    /// matches the reference PCB
+    ///
+    /// Allows hand-wired builds to connect keys to different rows/columns. Everything except
+    /// matrix scanning (layers, LED numbers, aux key coordinates) uses logical coordinates.
+    /// Must be a permutation of all matrix positions that keeps aux key positions in place.
+    pub remap: Option<[[(u8, u8); C]; R]>,
+}
+
+/// USB D- pin, fixed for the MCU
+pub const USB_DM: PinId = PinId { port: Port::A, pin: 11 };
+/// USB D+ pin, fixed for the MCU
+pub const USB_DP: PinId = PinId { port: Port::A, pin: 12 };
+
+/// Key wired directly to a GPIO (e.g. case-mounted function button)
+///
+/// The key is debounced together with the matrix and reported using side-local coordinates
+/// of a matrix position that has no key, so it can be bound in layers like any other key.
+/// It also wakes up the idle matrix, so its EXTI line (pin number) must not be used by columns.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct AuxKey {
+    /// Input with pull-up, key press pulls it low
+    pub pin: PinId,
+    /// Reserved side-local coordinates, must not be used by any matrix key
+    pub coords: (u8, u8),
+}
+
+/// Method of determining [`BoardSide`]
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum SideDetection {
+    /// Pin with external pull-up (left) or pull-down (right) resistor
+    Strap(PinId),
+    /// Solder jumper to ground, using internal pull-up: open is left, closed is right
+    Jumper(PinId),
+    /// Side known at compile time, e.g. hand-wired builds with separate firmware for each half
+    Fixed(BoardSide),
+    /// Option bytes user data DATA0: `b'L'` or `b'R'`, programmed once with a flashing tool
+    OptionByte,
+}
+
+/// First PCB revision
+pub const GHANIMA_V1: Board<6, 5> = Board {
+    name: "ghanima-v1",
+    revision: 1,
+    cols: [
+        PinId { port: Port::B, pin: 1 },
+        PinId { port: Port::B, pin: 0 },
+        PinId { port: Port::A, pin: 7 },
+        PinId { port: Port::A, pin: 6 },
+        PinId { port: Port::A, pin: 5 },
+        PinId { port: Port::A, pin: 4 },
+    ],
+    rows: [
+        PinId { port: Port::B, pin: 6 },
+        PinId { port: Port::B, pin: 7 },
+        PinId { port: Port::C, pin: 13 },
+        PinId { port: Port::C, pin: 14 },
+        PinId { port: Port::C, pin: 15 },
+    ],
+    ncols_thumb: 4,
+    side: SideDetection::Strap(PinId { port: Port::B, pin: 13 }),
+    // LED numbers in odd rows increase with column, and decrease in even rows.
+    // Joystick replaces key (4, 4) and has no LED.
+    led_numbers: [
+        [Some(5), Some(4), Some(3), Some(2), Some(1), Some(0)],
+        [Some(6), Some(7), Some(8), Some(9), Some(10), Some(11)],
+        [Some(17), Some(16), Some(15), Some(14), Some(13), Some(12)],
+        [Some(18), Some(19), Some(20), Some(21), Some(22), Some(23)],
+        [Some(27), Some(26), Some(25), Some(24), None, None],
+    ],
+    aux_keys: &[],
+    vbus_sense: None,
+    remap: None,
+};
+
+/// Board description used by the firmware
+///
+/// To support another PCB revision add its description and select it here using a cargo feature.
+pub const BOARD: Board<6, 5> = GHANIMA_V1;
+
+#[cfg(all(feature = "side-left", feature = "side-right"))]
+compile_error!("Features side-left and side-right are mutually exclusive");
+
+/// Board side detection used by the firmware
+///
+/// Features `side-left` and `side-right` override the board description, which allows
+/// to build firmware for each half separately when there is no other way to tell them apart.
+pub const SIDE_DETECTION: SideDetection = if cfg!(feature = "side-left") {
+    SideDetection::Fixed(BoardSide::Left)
+} else if cfg!(feature = "side-right") {
+    SideDetection::Fixed(BoardSide::Right)
+} else {
+    BOARD.side
+};
+
+impl<const C: usize, const R: usize> Board<C, R> {
+    /// Number of RGB LEDs
+    pub const fn n_leds(&self) -> usize {
+        let mut n = 0;
+        let mut row = 0;
+        while row < R {
+            let mut col = 0;
+            while col < C {
+                if self.led_numbers[row][col].is_some() {
+                    n += 1;
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+        n
+    }
+
+    /// Get side-local key coordinates of given LED
+    pub const fn led_coords(&self, led: u8) -> Option<(u8, u8)> {
+        let mut row = 0;
+        while row < R {
+            let mut col = 0;
+            while col < C {
+                if let Some(n) = self.led_numbers[row][col] {
+                    if n == led {
+                        return Some((row as u8, col as u8));
+                    }
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+        None
+    }
+
+    /// Move key states from matrix positions to logical positions described by [`Self::remap`]
+    pub fn remap_keys<T: Copy>(&self, keys: [[T; C]; R]) -> [[T; C]; R] {
+        let Some(remap) = &self.remap else {
+            return keys;
+        };
+        let mut logical = keys;
+        for (row, remap_row) in keys.iter().zip(remap.iter()) {
+            for (value, &(r, c)) in row.iter().zip(remap_row.iter()) {
+                logical[r as usize][c as usize] = *value;
+            }
+        }
+        logical
+    }
+
+    /// Check if side-local coordinates are reserved for one of the auxiliary keys
+    pub const fn is_aux_key(&self, (row, col): (u8, u8)) -> bool {
+        let mut i = 0;
+        while i < self.aux_keys.len() {
+            let coords = self.aux_keys[i].coords;
+            if coords.0 == row && coords.1 == col {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+}
+
+/// GPIO register offsets, used to access pins described by [`PinId`] at runtime
+const GPIO_MODER_OFFSET: usize = 0x00;
+const GPIO_OTYPER_OFFSET: usize = 0x04;
+const GPIO_PUPDR_OFFSET: usize = 0x0c;
+const GPIO_IDR_OFFSET: usize = 0x10;
+const GPIO_BSRR_OFFSET: usize = 0x18;
+
+impl Port {
+    fn base(self) -> *const u8 {
+        match self {
+            Port::A => hal::pac::GPIOA::ptr() as *const u8,
+            Port::B => hal::pac::GPIOB::ptr() as *const u8,
+            Port::C => hal::pac::GPIOC::ptr() as *const u8,
+        }
+    }
+
+    unsafe fn reg(self, offset: usize) -> *mut u32 {
+        self.base().add(offset) as *mut u32
+    }
+
+    fn enable_clock(self) {
+        // Need to access `.regs` but it's private
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+        rcc_regs.ahbenr.modify(|_, w| match self {
+            Port::A => w.iopaen().set_bit(),
+            Port::B => w.iopben().set_bit(),
+            Port::C => w.iopcen().set_bit(),
+        });
+    }
+}
+
+impl PinId {
+    /// Set 2-bit field for this pin in given register
+    unsafe fn modify_2bit(&self, offset: usize, value: u32) {
+        let shift = 2 * self.pin as u32;
+        let reg = self.port.reg(offset);
+        reg.write_volatile((reg.read_volatile() & !(0b11 << shift)) | (value << shift));
+    }
+
+    /// Read input state
+    pub fn is_high(&self) -> bool {
+        unsafe { self.port.reg(GPIO_IDR_OFFSET).read_volatile() & (1 << self.pin) != 0 }
+    }
+
+    /// Configure pin as input without pull-up/down
+    ///
+    /// # Safety
+    ///
+    /// The pin must not be used by anything else.
+    pub unsafe fn input_floating(&self) {
+        self.port.enable_clock();
+        cortex_m::interrupt::free(|_| {
+            self.modify_2bit(GPIO_PUPDR_OFFSET, 0b00);
+            self.modify_2bit(GPIO_MODER_OFFSET, 0b00);
+        });
+    }
+
+    /// Enable pull-down without changing pin mode, so it can be used on pins of peripherals
+    ///
+    /// # Safety
+    ///
+    /// The pull-down must not interfere with the peripheral using the pin.
+    pub unsafe fn pull_down(&self) {
+        cortex_m::interrupt::free(|_| self.modify_2bit(GPIO_PUPDR_OFFSET, 0b10));
+    }
+
+    /// Set output state
+    pub fn set(&self, high: bool) {
+        let bit = if high { 1 << self.pin } else { 1 << (self.pin + 16) };
+        unsafe { self.port.reg(GPIO_BSRR_OFFSET).write_volatile(bit) };
+    }
+}
+
+impl SideDetection {
+    /// Determine board side, `None` if option bytes have not been programmed
+    ///
+    /// # Safety
+    ///
+    /// The pin used for detection (if any) must not be used by anything else.
+    pub unsafe fn detect(&self) -> Option<BoardSide> {
+        let pin_side = |id: &PinId, pupd: u32| {
+            id.port.enable_clock();
+            cortex_m::interrupt::free(|_| {
+                id.modify_2bit(GPIO_PUPDR_OFFSET, pupd);
+                id.modify_2bit(GPIO_MODER_OFFSET, 0b00);
+            });
+            // Give the pull-up some time to charge the pin capacitance
+            cortex_m::asm::delay(1000);
+            if id.is_high() { BoardSide::Left } else { BoardSide::Right }
+        };
+        match self {
+            Self::Strap(id) => Some(pin_side(id, 0b00)),
+            Self::Jumper(id) => Some(pin_side(id, 0b01)),
+            Self::Fixed(side) => Some(*side),
+            Self::OptionByte => {
+                let flash = &*hal::pac::FLASH::ptr();
+                Self::side_from_option_bytes(flash.obr.read().bits())
+            },
+        }
+    }
+
+    /// Decode side from FLASH_OBR register value
+    fn side_from_option_bytes(obr: u32) -> Option<BoardSide> {
+        match (obr >> 16) as u8 {
+            b'L' => Some(BoardSide::Left),
+            b'R' => Some(BoardSide::Right),
+            _ => None,
+        }
+    }
+
+    /// Pin used for detection
+    pub const fn pin(&self) -> Option<PinId> {
+        match self {
+            Self::Strap(id) | Self::Jumper(id) => Some(*id),
+            Self::Fixed(_) | Self::OptionByte => None,
+        }
+    }
+}
+
+/// Key matrix GPIO configured from [`PinId`]
+///
+/// HAL only provides pins typed by port and number, so this allows to use pins from
+/// a [`Board`] description.
+pub struct MatrixPin {
+    id: PinId,
+}
+
+impl MatrixPin {
+    /// Configure pin as input with pull-up
+    ///
+    /// # Safety
+    ///
+    /// The pin must not be used by anything else.
+    pub unsafe fn input_pull_up(id: PinId) -> Self {
+        id.port.enable_clock();
+        cortex_m::interrupt::free(|_| {
+            id.modify_2bit(GPIO_PUPDR_OFFSET, 0b01);
+            id.modify_2bit(GPIO_MODER_OFFSET, 0b00);
+        });
+        Self { id }
+    }
+
+    /// Configure pin as push-pull output, initially low
+    ///
+    /// # Safety
+    ///
+    /// The pin must not be used by anything else.
+    pub unsafe fn output_push_pull(id: PinId) -> Self {
+        id.port.enable_clock();
+        id.set(false);
+        cortex_m::interrupt::free(|_| {
+            let otyper = id.port.reg(GPIO_OTYPER_OFFSET);
+            otyper.write_volatile(otyper.read_volatile() & !(1 << id.pin));
+            id.modify_2bit(GPIO_PUPDR_OFFSET, 0b00);
+            id.modify_2bit(GPIO_MODER_OFFSET, 0b01);
+        });
+        Self { id }
+    }
+}
+
+impl InputPin for MatrixPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.id.is_high())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.id.is_high())
+    }
+}
+
+impl OutputPin for MatrixPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.id.set(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.id.set(true);
+        Ok(())
+    }
+}
+
+/// Configure key matrix pins described by [`BOARD`]
+///
+/// # Safety
+///
+/// Matrix pins must not be used by anything else.
+pub unsafe fn matrix_pins() -> ([MatrixPin; NCOLS], [MatrixPin; NROWS]) {
+    (
+        BOARD.cols.map(|id| MatrixPin::input_pull_up(id)),
+        BOARD.rows.map(|id| MatrixPin::output_push_pull(id)),
+    )
+}
+
+/// Configure pins of auxiliary keys described by [`BOARD`]
+///
+/// # Safety
+///
+/// Auxiliary key pins must not be used by anything else.
+pub unsafe fn aux_key_pins() -> [MatrixPin; NAUX_KEYS] {
+    core::array::from_fn(|i| MatrixPin::input_pull_up(BOARD.aux_keys[i].pin))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn led_numbers_unique() {
+        let n = BOARD.n_leds();
+        for led in 0..n as u8 {
+            let (row, col) = BOARD.led_coords(led).unwrap();
+            assert_eq!(BOARD.led_numbers[row as usize][col as usize], Some(led));
+        }
+        assert_eq!(BOARD.led_coords(n as u8), None);
+    }
+
+    #[test]
+    fn pins_unique() {
+        let side = BOARD.side.pin();
+        let aux = BOARD.aux_keys.iter().map(|key| &key.pin);
+        let usb = [USB_DM, USB_DP];
+        let pins: Vec<_> = BOARD.cols.iter().chain(BOARD.rows.iter()).chain(side.iter()).chain(aux)
+            .chain(BOARD.vbus_sense.iter()).chain(usb.iter()).collect();
+        for (i, a) in pins.iter().enumerate() {
+            assert!(!pins[i + 1..].contains(a), "Duplicate pin {:?}", a);
+        }
+    }
+
+    fn check_aux_keys<const C: usize, const R: usize>(board: &Board<C, R>) {
+        for (i, key) in board.aux_keys.iter().enumerate() {
+            let (row, col) = key.coords;
+            assert!((row as usize) < R && (col as usize) < C, "Aux key out of matrix {:?}", key);
+            // Thumb row has unused columns, the first one after thumb keys is the joystick key
+            assert!(row as usize == R - 1 && col as usize > board.ncols_thumb, "Aux key on matrix key {:?}", key);
+            assert!(!board.cols.iter().any(|c| c.pin == key.pin.pin), "Aux key EXTI line used by column {:?}", key);
+            assert!(!board.aux_keys[i + 1..].iter().any(|k| k.coords == key.coords || k.pin.pin == key.pin.pin),
+                "Aux key conflict {:?}", key);
+        }
+    }
+
+    #[test]
+    fn aux_keys_valid() {
+        check_aux_keys(&BOARD);
+        let board = Board {
+            aux_keys: &[AuxKey { pin: PinId { port: Port::B, pin: 14 }, coords: (4, 5) }],
+            ..GHANIMA_V1
+        };
+        check_aux_keys(&board);
+        assert!(board.is_aux_key((4, 5)));
+        assert!(!board.is_aux_key((4, 4)));
+    }
+
+    fn check_remap<const C: usize, const R: usize>(board: &Board<C, R>) {
+        let Some(remap) = &board.remap else {
+            return;
+        };
+        let logical: Vec<_> = remap.iter().flatten().collect();
+        for (i, &&(row, col)) in logical.iter().enumerate() {
+            assert!((row as usize) < R && (col as usize) < C, "Remap out of matrix {:?}", (row, col));
+            assert!(!logical[i + 1..].contains(&&(row, col)), "Duplicate remap {:?}", (row, col));
+        }
+        for key in board.aux_keys {
+            let (row, col) = key.coords;
+            assert_eq!(remap[row as usize][col as usize], key.coords, "Aux key remapped {:?}", key);
+        }
+    }
+
+    #[test]
+    fn remap_valid() {
+        check_remap(&BOARD);
+    }
+
+    #[test]
+    fn remap_keys() {
+        let mut remap: [[(u8, u8); 6]; 5] = core::array::from_fn(|row| core::array::from_fn(|col| (row as u8, col as u8)));
+        // Rows 0 and 1 wired in reverse order, keys (2, 0) and (2, 1) swapped
+        remap.swap(0, 1);
+        remap[2].swap(0, 1);
+        let board = Board { remap: Some(remap), ..GHANIMA_V1 };
+        check_remap(&board);
+
+        let mut keys = [[false; 6]; 5];
+        keys[0][3] = true;
+        keys[2][1] = true;
+        keys[4][2] = true;
+        let logical = board.remap_keys(keys);
+        let pressed: Vec<_> = (0..5).flat_map(|row| (0..6).map(move |col| (row, col)))
+            .filter(|&(row, col)| logical[row][col])
+            .collect();
+        assert_eq!(pressed, [(1, 3), (2, 0), (4, 2)]);
+        assert_eq!(GHANIMA_V1.remap_keys(keys), keys);
+    }
+
+    #[test]
+    fn side_from_option_bytes() {
+        // DATA1 erased, some bits of option byte flags set
+        let obr = |data0: u8| 0xff00_0000 | (data0 as u32) << 16 | 0x0000_00f2;
+        assert_eq!(SideDetection::side_from_option_bytes(obr(b'L')), Some(BoardSide::Left));
+        assert_eq!(SideDetection::side_from_option_bytes(obr(b'R')), Some(BoardSide::Right));
+        // Erased option bytes
+        assert_eq!(SideDetection::side_from_option_bytes(obr(0xff)), None);
+    }
+}
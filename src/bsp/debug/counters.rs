@@ -1,19 +1,185 @@
-#[derive(Default)]
+#[cfg(any(test, feature = "task-profiling"))]
+use defmt::Format;
+
+#[cfg(any(test, feature = "task-profiling"))]
+use crate::hal_ext::clock::Duration;
+#[cfg(feature = "task-profiling")]
+use crate::hal_ext::clock::Instant;
+
 pub struct Counter {
     #[cfg(feature = "task-counters")]
     cnt: core::sync::atomic::AtomicU16,
+    #[cfg(feature = "task-profiling")]
+    time: cortex_m::interrupt::Mutex<core::cell::Cell<ExecStats>>,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "task-counters")]
+            cnt: Default::default(),
+            #[cfg(feature = "task-profiling")]
+            time: cortex_m::interrupt::Mutex::new(Default::default()),
+        }
+    }
+}
+
+/// Exclusive execution time of all tasks in microseconds (wrapping), used to subtract
+/// the time of preempting tasks from the measured one
+#[cfg(feature = "task-profiling")]
+static BUSY: cortex_m::interrupt::Mutex<core::cell::Cell<u32>> =
+    cortex_m::interrupt::Mutex::new(core::cell::Cell::new(0));
+
+/// Start of a task execution measurement
+#[derive(Clone, Copy)]
+pub struct Start {
+    #[cfg(feature = "task-profiling")]
+    instant: Instant,
+    #[cfg(feature = "task-profiling")]
+    busy: u32,
+}
+
+/// Task execution time statistics in microseconds
+///
+/// Min/avg/max is wall-clock time, so it includes the time spent in tasks that preempted
+/// the measured one. Busy time excludes it and can be used to estimate CPU [`Load`].
+#[cfg(any(test, feature = "task-profiling"))]
+#[derive(Default, Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ExecStats {
+    pub min: u32,
+    pub max: u32,
+    total: u32,
+    busy: u32,
+    count: u32,
+}
+
+/// CPU utilization over a reporting period in permille
+#[cfg(any(test, feature = "task-profiling"))]
+#[derive(Default, Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Load(u16);
+
+#[cfg(any(test, feature = "task-profiling"))]
+impl ExecStats {
+    /// Add new measurement of wall-clock `duration` out of which the task was running for `busy`
+    pub fn add(&mut self, duration: Duration, busy: Duration) {
+        let us = duration.as_micros();
+        self.min = if self.count == 0 { us } else { self.min.min(us) };
+        self.max = self.max.max(us);
+        self.total = self.total.saturating_add(us);
+        self.busy = self.busy.saturating_add(busy.as_micros());
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// Total execution time excluding preemption
+    pub fn busy(&self) -> Duration {
+        Duration::from_micros(self.busy)
+    }
+
+    /// Average execution time
+    pub fn avg(&self) -> u32 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Number of measurements
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+#[cfg(any(test, feature = "task-profiling"))]
+impl Format for ExecStats {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=u32}/{=u32}/{=u32}", self.min, self.avg(), self.max)
+    }
+}
+
+#[cfg(any(test, feature = "task-profiling"))]
+impl Load {
+    /// Part of `period` spent in a task that was running for `busy`
+    pub fn new(busy: Duration, period: Duration) -> Self {
+        let permille = (busy.as_micros() as u64 * 1000)
+            .checked_div(period.as_micros() as u64)
+            .unwrap_or(0);
+        Self(permille.min(1000) as u16)
+    }
+
+    /// Part of `period` not used by any of the tasks
+    pub fn headroom<'a>(stats: impl IntoIterator<Item = &'a ExecStats>, period: Duration) -> Self {
+        let busy = stats.into_iter()
+            .fold(Duration::ZERO, |sum, s| sum.saturating_add(s.busy()));
+        Self(1000 - Self::new(busy, period).0)
+    }
+
+    pub fn permille(&self) -> u16 {
+        self.0
+    }
+}
+
+#[cfg(any(test, feature = "task-profiling"))]
+impl Format for Load {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=u16}.{=u16}%", self.0 / 10, self.0 % 10)
+    }
+}
+
+#[cfg(feature = "task-profiling")]
+impl Counter {
+    /// Start measuring task execution time
+    #[inline(always)]
+    pub fn start(&self) -> Start {
+        cortex_m::interrupt::free(|cs| Start { instant: Instant::now(), busy: BUSY.borrow(cs).get() })
+    }
+
+    /// Record task execution time
+    #[inline(always)]
+    pub fn record(&self, start: Start) {
+        cortex_m::interrupt::free(|cs| {
+            let duration = start.instant.elapsed();
+            // Anything that other tasks added in the meantime must have preempted this one
+            let busy = BUSY.borrow(cs);
+            let preempted = busy.get().wrapping_sub(start.busy);
+            let own = duration.as_micros().saturating_sub(preempted);
+            busy.set(busy.get().wrapping_add(own));
+
+            let time = self.time.borrow(cs);
+            let mut stats = time.get();
+            stats.add(duration, Duration::from_micros(own));
+            time.set(stats);
+        })
+    }
+
+    /// Get execution time statistics and reset them
+    #[inline(always)]
+    pub fn pop_time(&self) -> ExecStats {
+        cortex_m::interrupt::free(|cs| self.time.borrow(cs).take())
+    }
+}
+
+#[cfg(not(feature = "task-profiling"))]
+impl Counter {
+    #[inline(always)]
+    pub fn start(&self) -> Start {
+        Start {}
+    }
+
+    #[inline(always)]
+    pub fn record(&self, _start: Start) {
+    }
 }
 
 #[cfg(feature = "task-counters")]
 impl Counter {
     #[inline(always)]
     pub fn inc(&self) {
-        let _ = atomic::fetch_saturating_add(&self.cnt, 1);
+        let _ = atomic::fetch_wrapping_add(&self.cnt, 1);
     }
 
+    /// Number of executions since boot (wrapping), see [`CounterDeltas`]
     #[inline(always)]
-    pub fn pop(&self) -> u16 {
-        atomic::swap(&self.cnt, 0)
+    pub fn count(&self) -> u16 {
+        self.cnt.load(core::sync::atomic::Ordering::Acquire)
     }
 }
 
@@ -24,11 +190,85 @@ impl Counter {
     }
 
     #[inline(always)]
-    pub fn pop(&self) -> u16 {
+    pub fn count(&self) -> u16 {
         0
     }
 }
 
+/// Counts since the previous read of cumulative counters
+///
+/// Counters are never reset, so each reader (logs, shell, raw HID) keeps its own instance
+/// and sees all executions since its own previous read.
+pub struct CounterDeltas<const N: usize> {
+    last: [u16; N],
+}
+
+impl<const N: usize> CounterDeltas<N> {
+    pub const fn new() -> Self {
+        Self { last: [0; N] }
+    }
+
+    /// Get counts since the previous call (or boot) given current values of counters
+    pub fn update(&mut self, counters: [u16; N]) -> [u16; N] {
+        let deltas = core::array::from_fn(|i| counters[i].wrapping_sub(self.last[i]));
+        self.last = counters;
+        deltas
+    }
+}
+
+impl<const N: usize> Default for CounterDeltas<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_stats() {
+        let mut stats = ExecStats::default();
+        assert_eq!(stats.avg(), 0);
+        for us in [30, 10, 20] {
+            stats.add(Duration::from_micros(us), Duration::from_micros(us / 2));
+        }
+        assert_eq!(stats.min, 10);
+        assert_eq!(stats.max, 30);
+        assert_eq!(stats.avg(), 20);
+        assert_eq!(stats.busy(), Duration::from_micros(30));
+        assert_eq!(stats.count(), 3);
+    }
+
+    #[test]
+    fn load() {
+        let period = Duration::from_millis(100);
+        assert_eq!(Load::new(Duration::from_millis(25), period).permille(), 250);
+        assert_eq!(Load::new(Duration::from_micros(150), period).permille(), 1);
+        assert_eq!(Load::new(Duration::from_millis(200), period).permille(), 1000);
+        assert_eq!(Load::new(Duration::from_millis(1), Duration::ZERO).permille(), 0);
+
+        let mut a = ExecStats::default();
+        let mut b = ExecStats::default();
+        a.add(Duration::from_millis(30), Duration::from_millis(20));
+        b.add(Duration::from_millis(10), Duration::from_millis(10));
+        assert_eq!(Load::headroom([&a, &b], period).permille(), 700);
+        assert_eq!(Load::headroom([&a, &b], Duration::from_millis(20)).permille(), 0);
+    }
+
+    #[test]
+    fn counter_deltas() {
+        let mut a = CounterDeltas::<2>::new();
+        let mut b = CounterDeltas::<2>::new();
+        assert_eq!(a.update([5, 0]), [5, 0]);
+        assert_eq!(a.update([7, 3]), [2, 3]);
+        // Other reader still sees everything since boot
+        assert_eq!(b.update([7, 3]), [7, 3]);
+        // Wrapping counter
+        assert_eq!(a.update([1, 3]), [u16::MAX - 5, 0]);
+    }
+}
+
 // ARM thumbv6 does not support atomic fetch_add so we need to use short critical sections, see:
 // https://github.com/jamesmunns/bbqueue/blob/f73423c0b1c5fe04723e5b5bd57d1a44ff106473/core/src/bbbuffer.rs#L1098
 #[allow(dead_code)]
@@ -38,10 +278,10 @@ mod atomic {
     use cortex_m::interrupt::free;
 
     #[inline(always)]
-    pub fn fetch_saturating_add(atomic: &AtomicU16, val: u16) -> u16 {
+    pub fn fetch_wrapping_add(atomic: &AtomicU16, val: u16) -> u16 {
         free(|_| {
             let prev = atomic.load(Acquire);
-            atomic.store(prev.saturating_add(val), Release);
+            atomic.store(prev.wrapping_add(val), Release);
             prev
         })
     }
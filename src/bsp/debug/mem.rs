@@ -74,6 +74,56 @@ pub fn free_stack_check_min() -> u32 {
     size - unmodified as u32
 }
 
+/// Report an error when free stack space drops below this value
+pub const STACK_HEADROOM_MIN: u32 = 1024;
+
+/// Tracks the high-water mark of stack usage over time
+///
+/// Requires the stack to be filled using [`free_stack_fill`] on startup.
+pub struct StackWatermark {
+    max_used: u32,
+}
+
+impl StackWatermark {
+    pub const fn new() -> Self {
+        Self { max_used: 0 }
+    }
+
+    /// Check stack memory, returns increase of maximum stack usage since last call
+    pub fn update(&mut self) -> Option<u32> {
+        self.update_with(stack_size() - free_stack_check_min())
+    }
+
+    fn update_with(&mut self, max_used: u32) -> Option<u32> {
+        let delta = max_used.checked_sub(self.max_used).filter(|d| *d > 0)?;
+        self.max_used = max_used;
+        Some(delta)
+    }
+
+    /// Maximum stack usage seen so far
+    pub fn max_used(&self) -> u32 {
+        self.max_used
+    }
+
+    /// Update and log stack usage if it increased, with an error if headroom is too small
+    pub fn report(&mut self) {
+        if let Some(delta) = self.update() {
+            let size = stack_size();
+            let headroom = size.saturating_sub(self.max_used);
+            defmt::info!("Stack max usage: {=u32} B (+{=u32} B) / {=u32} B", self.max_used, delta, size);
+            if headroom < STACK_HEADROOM_MIN {
+                defmt::error!("Low stack headroom: {=u32} B", headroom);
+            }
+        }
+    }
+}
+
+impl Default for StackWatermark {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn print_stack_info() {
     let stack_size = stack_size();
     let curr_used = stack_used();
@@ -85,6 +135,21 @@ pub fn print_stack_info() {
         stack_size);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watermark_deltas() {
+        let mut wm = StackWatermark::new();
+        assert_eq!(wm.update_with(100), Some(100));
+        assert_eq!(wm.update_with(100), None);
+        assert_eq!(wm.update_with(80), None);
+        assert_eq!(wm.update_with(150), Some(50));
+        assert_eq!(wm.max_used(), 150);
+    }
+}
+
 /// Values of linker symbols
 pub mod symbols {
     macro_rules! symbol_getters {
@@ -127,6 +192,7 @@ pub mod symbols {
         __sheap: heap_start,
         __sdata: data_start,
         __edata: data_end,
+        __sidata: data_load_start,
         __sbss: bss_start,
         __ebss: bss_end,
         __suninit: uninit_start,
@@ -4,6 +4,8 @@ pub mod counters;
 pub mod mem;
 /// Safer interface that allows to use GPIOs or Serial
 pub mod pins;
+/// Interactive command shell on the debug UART
+pub mod shell;
 /// Raw interface better suited for tracing execution of RTIC tasks
 pub mod tasks;
 
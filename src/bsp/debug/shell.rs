@@ -0,0 +1,335 @@
+use core::convert::Infallible;
+use rgb::RGB8;
+use ufmt::uWrite;
+
+use crate::bsp::{NCOLS, NLEDS, NROWS, sides::BoardSide};
+use crate::hal;
+use crate::keyboard::{leds::Role, PrescalerTask};
+use hal::prelude::*;
+use super::types::*;
+
+/// Maximum length of a single command line
+pub const LINE_MAX_LEN: usize = 32;
+
+/// Interactive console on the debug UART
+pub struct Console {
+    serial: Serial,
+}
+
+/// Accumulates received characters into lines
+#[derive(Default)]
+pub struct LineBuffer {
+    buf: heapless::String<LINE_MAX_LEN>,
+    overflow: bool,
+}
+
+/// Shell command
+#[derive(PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Command {
+    /// List available commands
+    Help,
+    /// Print serial link, HID queue and task statistics
+    Stats,
+    /// Clear serial link and HID queue statistics
+    StatsReset,
+    /// Print configuration checksum
+    Config,
+    /// Print or set global LED brightness
+    Brightness(Option<u8>),
+    /// Toggle or set joystick enabled state
+    Joystick(Option<bool>),
+    /// Force keyboard role, `None` restores automatic role negotiation
+    Role(Option<Role>),
+    /// Dump key event log
+    Events,
+    /// Print key matrix health (stuck keys, chatter)
+    Health,
+    /// Print key usage statistics
+    KeyStats,
+    /// Clear key usage statistics
+    KeyStatsWipe,
+    /// Enable/disable key press latency measurements (reported periodically via defmt)
+    Latency(bool),
+    /// Log hold-tap decisions of key at given global coordinates via defmt, `None` disables
+    HoldTap(Option<(u8, u8)>),
+    /// Print number of resets per cause and reboots per reason
+    Resets,
+    /// Print or set period of a periodic task in ticks, 0 disables the task
+    Prescaler(PrescalerTask, Option<u32>),
+    /// Override color of a single LED until cleared, `None` removes the override
+    LedOverride(BoardSide, u8, Option<RGB8>),
+    /// Remove all LED color overrides
+    LedClear,
+}
+
+/// Command line parsing error
+#[derive(PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum ParseError {
+    Empty,
+    UnknownCommand,
+    InvalidArgument,
+    LineTooLong,
+}
+
+pub const HELP: &str = "\
+help               this message\r\n\
+stats [reset]      link/HID queue/task statistics, or clear them\r\n\
+config             configuration checksum\r\n\
+bright [0-255]     get/set LED brightness\r\n\
+joy [on|off]       toggle/set joystick\r\n\
+role master|slave|auto  force keyboard role\r\n\
+events             dump key event log (if enabled)\r\n\
+health             stuck keys and chatter counts\r\n\
+keystats [wipe]    key usage statistics, or clear them\r\n\
+latency on|off     key press latency measurements\r\n\
+holdtap ROW COL|off  trace hold-tap decisions of a key\r\n\
+resets             reset counters per cause and reboot reason\r\n\
+prescaler leds|joy|mouse|debug [N]  get/set task period in ticks\r\n\
+led left|right N RRGGBB|off  override LED color\r\n\
+led clear          remove all LED overrides\r\n";
+
+impl Console {
+    /// Configure debug UART with RX interrupt enabled
+    pub fn new(uart: Uart, pins: (Tx, Rx), rcc: &mut hal::rcc::Rcc) -> Self {
+        let mut serial = Serial::usart2(uart, pins, 115_200.bps(), rcc);
+        serial.listen(hal::serial::Event::Rxne);
+        Self { serial }
+    }
+
+    /// Read received byte if available, reception errors are ignored
+    pub fn read(&mut self) -> Option<u8> {
+        self.serial.read().ok()
+    }
+}
+
+impl uWrite for Console {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        for b in s.bytes() {
+            nb::block!(self.serial.write(b))?;
+        }
+        Ok(())
+    }
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self { buf: heapless::String::new(), overflow: false }
+    }
+
+    /// Push received byte, returns parsed command when line is complete
+    pub fn push(&mut self, byte: u8) -> Option<Result<Command, ParseError>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let result = if self.overflow {
+                    Err(ParseError::LineTooLong)
+                } else {
+                    parse(&self.buf)
+                };
+                self.buf.clear();
+                self.overflow = false;
+                match result {
+                    // Ignore empty lines (e.g. \r\n)
+                    Err(ParseError::Empty) => None,
+                    result => Some(result),
+                }
+            },
+            // Backspace/delete
+            0x08 | 0x7f => {
+                self.buf.pop();
+                None
+            },
+            b if b.is_ascii() && !b.is_ascii_control() => {
+                if self.buf.push(b as char).is_err() {
+                    self.overflow = true;
+                }
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Parse color in hex format, e.g. `ff8000`
+fn parse_color(arg: &str) -> Result<RGB8, ParseError> {
+    if arg.len() != 6 || !arg.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidArgument);
+    }
+    let [_, r, g, b] = u32::from_str_radix(arg, 16)
+        .map_err(|_| ParseError::InvalidArgument)?
+        .to_be_bytes();
+    Ok(RGB8::new(r, g, b))
+}
+
+fn parse_on_off(arg: &str) -> Result<bool, ParseError> {
+    match arg {
+        "on" | "1" => Ok(true),
+        "off" | "0" => Ok(false),
+        _ => Err(ParseError::InvalidArgument),
+    }
+}
+
+/// Parse command line
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_ascii_whitespace();
+    let cmd = words.next().ok_or(ParseError::Empty)?;
+    let arg = words.next();
+    // Only prescaler and led commands take more arguments
+    let value = words.next();
+    let value2 = words.next();
+    let max_args = match cmd {
+        "prescaler" | "holdtap" => 2,
+        "led" => 3,
+        _ => 1,
+    };
+    let n_args = [arg, value, value2].iter().filter(|a| a.is_some()).count();
+    if words.next().is_some() || n_args > max_args {
+        return Err(ParseError::InvalidArgument);
+    }
+    let command = match (cmd, arg) {
+        ("help" | "?", None) => Command::Help,
+        ("stats", None) => Command::Stats,
+        ("stats", Some("reset")) => Command::StatsReset,
+        ("config", None) => Command::Config,
+        ("events", None) => Command::Events,
+        ("health", None) => Command::Health,
+        ("keystats", None) => Command::KeyStats,
+        ("keystats", Some("wipe")) => Command::KeyStatsWipe,
+        ("resets", None) => Command::Resets,
+        ("latency", Some(arg)) => Command::Latency(parse_on_off(arg)?),
+        ("holdtap", Some("off")) if value.is_none() => Command::HoldTap(None),
+        ("holdtap", Some(arg)) => {
+            let row = arg.parse::<u8>().ok().filter(|r| (*r as usize) < NROWS);
+            let col = value.and_then(|v| v.parse::<u8>().ok()).filter(|c| (*c as usize) < 2 * NCOLS);
+            Command::HoldTap(Some(row.zip(col).ok_or(ParseError::InvalidArgument)?))
+        },
+        ("bright", arg) => {
+            let value = arg.map(|a| a.parse().map_err(|_| ParseError::InvalidArgument)).transpose()?;
+            Command::Brightness(value)
+        },
+        ("joy", arg) => Command::Joystick(arg.map(parse_on_off).transpose()?),
+        ("role", Some(arg)) => Command::Role(match arg {
+            "master" => Some(Role::Master),
+            "slave" => Some(Role::Slave),
+            "auto" => None,
+            _ => return Err(ParseError::InvalidArgument),
+        }),
+        ("prescaler", Some(arg)) => {
+            let task = match arg {
+                "leds" => PrescalerTask::Leds,
+                "joy" => PrescalerTask::Joystick,
+                "mouse" => PrescalerTask::Mouse,
+                "debug" => PrescalerTask::Debug,
+                _ => return Err(ParseError::InvalidArgument),
+            };
+            let value = value.map(|v| v.parse().map_err(|_| ParseError::InvalidArgument)).transpose()?;
+            Command::Prescaler(task, value)
+        },
+        ("led", Some("clear")) if value.is_none() => Command::LedClear,
+        ("led", Some(arg)) => {
+            let side = match arg {
+                "left" => BoardSide::Left,
+                "right" => BoardSide::Right,
+                _ => return Err(ParseError::InvalidArgument),
+            };
+            let led = value.and_then(|v| v.parse::<u8>().ok())
+                .filter(|led| (*led as usize) < NLEDS)
+                .ok_or(ParseError::InvalidArgument)?;
+            let color = match value2.ok_or(ParseError::InvalidArgument)? {
+                "off" => None,
+                color => Some(parse_color(color)?),
+            };
+            Command::LedOverride(side, led, color)
+        },
+        ("help" | "?" | "stats" | "config" | "events" | "health" | "keystats" | "latency" | "holdtap" | "resets" | "role" | "prescaler" | "led", _) => return Err(ParseError::InvalidArgument),
+        _ => return Err(ParseError::UnknownCommand),
+    };
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(buf: &mut LineBuffer, s: &str) -> Option<Result<Command, ParseError>> {
+        let mut result = None;
+        for b in s.bytes() {
+            if let Some(r) = buf.push(b) {
+                assert!(result.is_none());
+                result = Some(r);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse("help"), Ok(Command::Help));
+        assert_eq!(parse("  stats "), Ok(Command::Stats));
+        assert_eq!(parse("stats reset"), Ok(Command::StatsReset));
+        assert_eq!(parse("bright"), Ok(Command::Brightness(None)));
+        assert_eq!(parse("bright 100"), Ok(Command::Brightness(Some(100))));
+        assert_eq!(parse("joy"), Ok(Command::Joystick(None)));
+        assert_eq!(parse("joy off"), Ok(Command::Joystick(Some(false))));
+        assert_eq!(parse("role slave"), Ok(Command::Role(Some(Role::Slave))));
+        assert_eq!(parse("role auto"), Ok(Command::Role(None)));
+        assert_eq!(parse("events"), Ok(Command::Events));
+        assert_eq!(parse("health"), Ok(Command::Health));
+        assert_eq!(parse("keystats"), Ok(Command::KeyStats));
+        assert_eq!(parse("keystats wipe"), Ok(Command::KeyStatsWipe));
+        assert_eq!(parse("latency on"), Ok(Command::Latency(true)));
+        assert_eq!(parse("resets"), Ok(Command::Resets));
+        assert_eq!(parse("holdtap 2 11"), Ok(Command::HoldTap(Some((2, 11)))));
+        assert_eq!(parse("holdtap off"), Ok(Command::HoldTap(None)));
+        assert_eq!(parse("prescaler joy"), Ok(Command::Prescaler(PrescalerTask::Joystick, None)));
+        assert_eq!(parse("prescaler leds 20"), Ok(Command::Prescaler(PrescalerTask::Leds, Some(20))));
+        assert_eq!(parse("prescaler mouse 4"), Ok(Command::Prescaler(PrescalerTask::Mouse, Some(4))));
+        assert_eq!(parse("led right 3 ff8000"), Ok(Command::LedOverride(BoardSide::Right, 3, Some(RGB8::new(255, 128, 0)))));
+        assert_eq!(parse("led left 0 off"), Ok(Command::LedOverride(BoardSide::Left, 0, None)));
+        assert_eq!(parse("led clear"), Ok(Command::LedClear));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(parse("foo"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("bright 300"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("joy maybe"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("role"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("keystats all"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("stats now"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("bright 1 2"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("prescaler"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("prescaler usb 1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("prescaler debug -1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("prescaler debug 1 2"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("led left 1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("led left 255 ffffff"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("led left 1 +fffff"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("led clear 1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("holdtap 1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("holdtap off 1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("holdtap 1 255"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("led up 1 ffffff"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn line_buffer() {
+        let mut buf = LineBuffer::new();
+        assert_eq!(feed(&mut buf, "sta"), None);
+        assert_eq!(feed(&mut buf, "ts\r\n"), Some(Ok(Command::Stats)));
+        assert_eq!(feed(&mut buf, "\r\n"), None);
+        assert_eq!(feed(&mut buf, "joyx\x08 on\n"), Some(Ok(Command::Joystick(Some(true)))));
+    }
+
+    #[test]
+    fn line_too_long() {
+        let mut buf = LineBuffer::new();
+        let long = "x".repeat(LINE_MAX_LEN + 1) + "\n";
+        assert_eq!(feed(&mut buf, &long), Some(Err(ParseError::LineTooLong)));
+        assert_eq!(feed(&mut buf, "help\n"), Some(Ok(Command::Help)));
+    }
+}
@@ -7,6 +7,9 @@ use super::{types::*, get_tx, get_rx, get_serial_tx};
 #[cfg(all(feature = "idle-sleep", feature = "debug-tasks"))]
 compile_error!("debug-tasks will not work with idle-sleep enabled");
 
+#[cfg(all(feature = "debug-shell", feature = "debug-tasks"))]
+compile_error!("debug-shell uses the same UART pins as debug-tasks");
+
 /// Grant GPIOs to this module
 pub fn init(uart: Uart, (tx, rx): (Tx, Rx), rcc: &mut hal::rcc::Rcc) {
     interrupt::free(|cs| {
@@ -109,6 +112,16 @@ macro_rules! def_tasks_debug {
                 result
             }
 
+            /// Names of all counted tasks (including idle), in order of [`Self::counters`]
+            pub const NAMES: &'static [&'static str] = &[$( stringify!($task), )* "idle"];
+            pub const N_COUNTERS: usize = Self::NAMES.len();
+
+            /// Cumulative execution counters of all tasks (including idle), use `CounterDeltas`
+            /// to get counts since the previous read
+            pub fn counters(&self) -> [u16; $name::N_COUNTERS] {
+                [$( self.$task.count(), )* self.idle.count()]
+            }
+
             /// Increment idle task counter, idle task does not set GPIO high, only low to
             /// indicate no pending tasks.
             #[inline(always)]
@@ -118,7 +131,7 @@ macro_rules! def_tasks_debug {
             }
 
             $(
-                /// Run given task with GPIO tracing and increment counter
+                /// Run given task with GPIO tracing, increment counter and measure execution time
                 #[inline(always)]
                 pub fn $task<F, T>(&self, f: F) -> T
                 where
@@ -126,7 +139,9 @@ macro_rules! def_tasks_debug {
                 {
                     $crate::bsp::debug::tasks::task::enter($task_id);
                     self.$task.inc();
+                    let start = self.$task.start();
                     let result = f();
+                    self.$task.record(start);
                     $crate::bsp::debug::tasks::task::exit($task_id);
                     result
                 }
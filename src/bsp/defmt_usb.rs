@@ -0,0 +1,141 @@
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use bbqueue::{BBBuffer, Consumer, Producer};
+use cortex_m::{interrupt, register};
+use usb_device::class_prelude::*;
+
+/// Size of buffer for encoded defmt frames waiting for transmission
+pub const LOG_BUF_SIZE: usize = 1024;
+/// Bulk endpoint packet size
+const MAX_PACKET_SIZE: u16 = 64;
+
+static BUFFER: BBBuffer<LOG_BUF_SIZE> = BBBuffer::new();
+static mut PRODUCER: Option<Producer<'static, LOG_BUF_SIZE>> = None;
+static mut CONSUMER: Option<Consumer<'static, LOG_BUF_SIZE>> = None;
+/// Number of bytes that did not fit into the buffer
+static mut DROPPED: u32 = 0;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut RESTORE_INTERRUPTS: bool = false;
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+/// Vendor-specific USB interface with a single bulk IN endpoint carrying defmt frames
+///
+/// Replaces RTT transport, so logs can be captured without a debug probe, e.g.
+/// `utils/defmt-usb | defmt-print -e target/thumbv6m-none-eabi/release/ghanima`.
+/// Frames are buffered in RAM and sent whenever the endpoint is free. When the host is not
+/// reading, the buffer fills up and new data is dropped. Frames that have been only partially
+/// written are discarded by the host decoder (which resynchronizes on frame delimiters).
+pub struct DefmtClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    ep: EndpointIn<'a, B>,
+    consumer: Consumer<'static, LOG_BUF_SIZE>,
+}
+
+/// Split the buffer on first use, must be called in a critical section
+unsafe fn split() {
+    if (*addr_of_mut!(PRODUCER)).is_none() {
+        let (producer, consumer) = BUFFER.try_split().unwrap();
+        *addr_of_mut!(PRODUCER) = Some(producer);
+        *addr_of_mut!(CONSUMER) = Some(consumer);
+    }
+}
+
+impl<'a, B: UsbBus> DefmtClass<'a, B> {
+    /// Create the class, can only be called once
+    ///
+    /// Frames logged before this call are kept in the buffer (as long as they fit).
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        let consumer = interrupt::free(|_| unsafe {
+            split();
+            (*addr_of_mut!(CONSUMER)).take()
+        });
+        Self {
+            iface: alloc.interface(),
+            ep: alloc.bulk(MAX_PACKET_SIZE),
+            consumer: consumer.expect("DefmtClass already created"),
+        }
+    }
+
+    /// Write next packet if there is any data and the endpoint is not busy
+    pub fn flush(&mut self) {
+        if let Ok(grant) = self.consumer.read() {
+            let len = grant.len().min(MAX_PACKET_SIZE as usize);
+            // WouldBlock means that previous packet has not been read by host yet
+            let written = self.ep.write(&grant[..len]).unwrap_or(0);
+            grant.release(written);
+        }
+    }
+
+    /// Total number of bytes dropped due to buffer overflow
+    pub fn dropped() -> u32 {
+        interrupt::free(|_| unsafe { DROPPED })
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DefmtClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        // Vendor-specific class, no subclass/protocol
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.ep)?;
+        Ok(())
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep.address() {
+            self.flush();
+        }
+    }
+}
+
+fn write_buffered(bytes: &[u8]) {
+    // Safety: only called from logger with interrupts disabled
+    unsafe {
+        split();
+        let producer = (*addr_of_mut!(PRODUCER)).as_mut().unwrap();
+        match producer.grant_exact(bytes.len()) {
+            Ok(mut grant) => {
+                grant.copy_from_slice(bytes);
+                grant.commit(bytes.len());
+            },
+            Err(_) => {
+                let dropped = addr_of_mut!(DROPPED);
+                *dropped = (*dropped).wrapping_add(bytes.len() as u32);
+            },
+        }
+    }
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let primask = register::primask::read();
+        interrupt::disable();
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        unsafe {
+            *addr_of_mut!(RESTORE_INTERRUPTS) = primask.is_active();
+            (*addr_of_mut!(ENCODER)).start_frame(write_buffered);
+        }
+    }
+
+    unsafe fn flush() {
+        // Cannot wait for USB transfers here, data is sent from USB interrupt
+    }
+
+    unsafe fn release() {
+        (*addr_of_mut!(ENCODER)).end_frame(write_buffered);
+        TAKEN.store(false, Ordering::Relaxed);
+        if *addr_of_mut!(RESTORE_INTERRUPTS) {
+            interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        (*addr_of_mut!(ENCODER)).write(bytes, write_buffered);
+    }
+}
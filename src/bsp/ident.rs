@@ -0,0 +1,195 @@
+use ufmt::{uWrite, uwrite};
+
+use crate::{build_info, config, hal};
+
+/// Address of the 96-bit unique device ID (RM0091, 33.1)
+const UID_ADDR: usize = 0x1fff_f7ac;
+/// Address of the flash size register, value in kB (RM0091, 33.2)
+const FLASH_SIZE_ADDR: usize = 0x1fff_f7cc;
+
+/// 96-bit unique MCU identifier
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Uid(pub [u8; 12]);
+
+/// MCU identification read from DBGMCU_IDCODE
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct McuId {
+    /// Device identifier (0x448 for STM32F07x)
+    pub dev_id: u16,
+    /// Silicon revision
+    pub rev_id: u16,
+}
+
+/// Hardware information that identifies a given keyboard half
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct HardwareInfo {
+    pub uid: Uid,
+    /// Size of MCU flash memory in kB
+    pub flash_size_kb: u16,
+    pub mcu: McuId,
+    /// PCB revision the firmware has been built for, see [`super::board::Board::revision`]
+    pub board_revision: u8,
+}
+
+/// Firmware build information
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct FirmwareInfo {
+    pub version: &'static str,
+    /// Build date as YYYY-MM-DD
+    pub build_date: &'static str,
+    /// Name of keyboard configuration source
+    pub config_name: &'static str,
+    pub config_checksum: u32,
+}
+
+impl Uid {
+    /// Read the unique ID from system memory
+    pub fn read() -> Self {
+        let mut uid = [0; 12];
+        for (i, b) in uid.iter_mut().enumerate() {
+            *b = unsafe { core::ptr::read_volatile((UID_ADDR + i) as *const u8) };
+        }
+        Self(uid)
+    }
+
+    /// Fold the ID into 32 bits, e.g. to be used in short identifiers
+    pub fn short(&self) -> u32 {
+        self.0.chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .fold(0, |acc, w| acc ^ w)
+    }
+
+    /// Write the ID as hex string, most significant byte first
+    pub fn write_hex<W: uWrite + ?Sized>(&self, w: &mut W) -> Result<(), W::Error> {
+        self.0.iter().rev().try_for_each(|b| write_hex_byte(w, *b))
+    }
+}
+
+impl McuId {
+    /// Read the ID code from DBGMCU
+    pub fn read() -> Self {
+        let dbgmcu = unsafe { &*hal::pac::DBGMCU::ptr() };
+        Self::from_idcode(dbgmcu.idcode.read().bits())
+    }
+
+    const fn from_idcode(idcode: u32) -> Self {
+        Self {
+            dev_id: (idcode & 0xfff) as u16,
+            rev_id: (idcode >> 16) as u16,
+        }
+    }
+}
+
+impl HardwareInfo {
+    /// Collect hardware information from MCU registers
+    pub fn read() -> Self {
+        let flash_size_kb = unsafe { core::ptr::read_volatile(FLASH_SIZE_ADDR as *const u16) };
+        Self {
+            uid: Uid::read(),
+            flash_size_kb,
+            mcu: McuId::read(),
+            board_revision: super::board::BOARD.revision,
+        }
+    }
+
+    /// Write human readable description, e.g. `uid=... flash=128k mcu=0448r2000 board=r1`
+    pub fn write_text<W: uWrite + ?Sized>(&self, w: &mut W) -> Result<(), W::Error> {
+        w.write_str("uid=")?;
+        self.uid.write_hex(w)?;
+        uwrite!(w, " flash={}k mcu=", self.flash_size_kb)?;
+        write_hex_u16(w, self.mcu.dev_id)?;
+        w.write_str("r")?;
+        write_hex_u16(w, self.mcu.rev_id)?;
+        uwrite!(w, " board=r{}", self.board_revision)
+    }
+}
+
+impl FirmwareInfo {
+    /// Information about the currently running firmware
+    pub const fn current() -> Self {
+        Self {
+            version: match build_info::GIT_VERSION {
+                Some(v) => v,
+                None => build_info::PKG_VERSION,
+            },
+            build_date: build_info::BUILD_DATE,
+            config_name: config::NAME,
+            config_checksum: config::CHECKSUM,
+        }
+    }
+
+    /// Write human readable description, e.g. `fw=0.1.0 built=2023-01-01 config=ghanima:01234567`
+    pub fn write_text<W: uWrite + ?Sized>(&self, w: &mut W) -> Result<(), W::Error> {
+        uwrite!(w, "fw={} built={} config={}:", self.version, self.build_date, self.config_name)?;
+        write_hex_u32(w, self.config_checksum)
+    }
+}
+
+fn write_hex_byte<W: uWrite + ?Sized>(w: &mut W, b: u8) -> Result<(), W::Error> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    w.write_char(DIGITS[(b >> 4) as usize] as char)?;
+    w.write_char(DIGITS[(b & 0xf) as usize] as char)
+}
+
+fn write_hex_u16<W: uWrite + ?Sized>(w: &mut W, v: u16) -> Result<(), W::Error> {
+    v.to_be_bytes().iter().try_for_each(|b| write_hex_byte(w, *b))
+}
+
+/// Write a 32-bit value as 8 hex digits
+pub fn write_hex_u32<W: uWrite + ?Sized>(w: &mut W, v: u32) -> Result<(), W::Error> {
+    v.to_be_bytes().iter().try_for_each(|b| write_hex_byte(w, *b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID: Uid = Uid([0x01, 0x02, 0x03, 0x04, 0x10, 0x20, 0x30, 0x40, 0xaa, 0xbb, 0xcc, 0xdd]);
+
+    #[test]
+    fn uid_hex() {
+        let mut s = heapless::String::<32>::new();
+        UID.write_hex(&mut s).unwrap();
+        assert_eq!(s.as_str(), "ddccbbaa4030201004030201");
+    }
+
+    #[test]
+    fn uid_short() {
+        assert_eq!(UID.short(), 0x04030201 ^ 0x40302010 ^ 0xddccbbaa);
+    }
+
+    #[test]
+    fn mcu_from_idcode() {
+        assert_eq!(McuId::from_idcode(0x2000_6448), McuId { dev_id: 0x448, rev_id: 0x2000 });
+    }
+
+    #[test]
+    fn info_text() {
+        let info = HardwareInfo {
+            uid: UID,
+            flash_size_kb: 128,
+            mcu: McuId { dev_id: 0x448, rev_id: 0x2000 },
+            board_revision: 2,
+        };
+        let mut s = heapless::String::<64>::new();
+        info.write_text(&mut s).unwrap();
+        assert_eq!(s.as_str(), "uid=ddccbbaa4030201004030201 flash=128k mcu=0448r2000 board=r2");
+    }
+
+    #[test]
+    fn firmware_info_text() {
+        let info = FirmwareInfo {
+            version: "v0.2.0-3-gabcdef0",
+            build_date: "2023-05-17",
+            config_name: "ghanima",
+            config_checksum: 0x00c0ffee,
+        };
+        let mut s = heapless::String::<64>::new();
+        info.write_text(&mut s).unwrap();
+        assert_eq!(s.as_str(), "fw=v0.2.0-3-gabcdef0 built=2023-05-17 config=ghanima:00c0ffee");
+    }
+}
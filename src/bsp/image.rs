@@ -0,0 +1,141 @@
+use ufmt::uWrite;
+
+use super::debug::mem::symbols;
+use super::ident::write_hex_u32;
+
+/// Address of the first byte of firmware image
+const FLASH_START: usize = 0x0800_0000;
+/// First word of the trailer appended after the image by `utils/image-crc`, followed by CRC
+pub const TRAILER_MAGIC: u32 = 0x3163_7263;
+
+/// Result of firmware image verification
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Integrity {
+    /// CRC matches the one stored in the trailer
+    Valid,
+    /// CRC differs from the one stored in the trailer
+    Corrupted { expected: u32 },
+    /// There is no trailer, e.g. when flashing ELF file with a debug probe
+    Unknown,
+}
+
+/// CRC-32 (as in zlib) of the firmware image in flash
+///
+/// The image spans from the start of flash to the end of initial values of `.data`, which is
+/// the same as the contents of the `.bin` file. A trailer with the expected CRC is appended
+/// to the `.bin` file after build, so it ends up in flash right after the image (and in the
+/// DFU upload of the whole flash). Both halves announce the CRC to each other, so running
+/// different builds can be detected.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ImageCrc {
+    pub crc: u32,
+    pub integrity: Integrity,
+}
+
+impl ImageCrc {
+    /// Compute CRC of the running firmware image and compare it with the trailer
+    ///
+    /// This reads the whole image, which takes some tens of milliseconds.
+    pub fn verify() -> Self {
+        let image = image();
+        // .data size and load address are word-aligned
+        let trailer = unsafe { core::ptr::read_volatile(image.as_ptr_range().end as *const [u32; 2]) };
+        Self::check(image, trailer)
+    }
+
+    /// Compute CRC of `image` and compare it with the one in `trailer`
+    pub fn check(image: &[u8], trailer: [u32; 2]) -> Self {
+        let crc = crc32(image);
+        let integrity = match trailer {
+            [TRAILER_MAGIC, expected] if expected == crc => Integrity::Valid,
+            [TRAILER_MAGIC, expected] => Integrity::Corrupted { expected },
+            _ => Integrity::Unknown,
+        };
+        Self { crc, integrity }
+    }
+
+    /// Write human readable description, e.g. `image=0123abcd:valid`
+    pub fn write_text<W: uWrite + ?Sized>(&self, w: &mut W) -> Result<(), W::Error> {
+        w.write_str("image=")?;
+        write_hex_u32(w, self.crc)?;
+        w.write_str(match self.integrity {
+            Integrity::Valid => ":valid",
+            Integrity::Corrupted { .. } => ":corrupted",
+            Integrity::Unknown => ":unknown",
+        })
+    }
+}
+
+/// Firmware image in flash, without the trailer
+fn image() -> &'static [u8] {
+    let start = FLASH_START as *const u8;
+    unsafe {
+        let data_len = (symbols::data_end() as *const u8).offset_from(symbols::data_start() as *const u8);
+        let end = (symbols::data_load_start() as *const u8).offset(data_len);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Flash after the image trailer up to the statistics page
+///
+/// Used for data appended to the `.bin` file, like configuration blob. Contains whatever has
+/// been left in flash when nothing has been appended (usually erased memory).
+pub fn appended() -> &'static [u8] {
+    let start = image().as_ptr_range().end as usize + 8;
+    let end = super::stats_flash::ADDRESS;
+    unsafe { core::slice::from_raw_parts(start as *const u8, end.saturating_sub(start)) }
+}
+
+/// CRC-32/ISO-HDLC computed with a 16-entry table to save flash
+pub fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 16] = {
+        let mut table = [0; 16];
+        let mut i = 0;
+        while i < 16 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 4 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    let crc = data.iter().fold(!0, |mut crc: u32, b| {
+        crc ^= *b as u32;
+        crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+        (crc >> 4) ^ TABLE[(crc & 0xf) as usize]
+    });
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn integrity() {
+        let image = b"123456789";
+        assert_eq!(ImageCrc::check(image, [TRAILER_MAGIC, 0xcbf4_3926]).integrity, Integrity::Valid);
+        assert_eq!(ImageCrc::check(image, [TRAILER_MAGIC, 0x1234_5678]).integrity,
+            Integrity::Corrupted { expected: 0x1234_5678 });
+        assert_eq!(ImageCrc::check(image, [u32::MAX, u32::MAX]).integrity, Integrity::Unknown);
+    }
+
+    #[test]
+    fn text() {
+        let mut s = heapless::String::<32>::new();
+        ImageCrc::check(b"123456789", [TRAILER_MAGIC, 0]).write_text(&mut s).unwrap();
+        assert_eq!(s.as_str(), "image=cbf43926:corrupted");
+    }
+}
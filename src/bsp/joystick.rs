@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
 use embedded_hal::adc::OneShot;
 use micromath::F32Ext;
 
@@ -8,12 +10,32 @@ use hal::{gpio::{Analog, gpioa}, adc};
 type GpioX = gpioa::PA1<Analog>;
 type GpioY = gpioa::PA0<Analog>;
 
+/// ADC channels of both axes (PA0, PA1)
+const CHANNELS: u32 = (1 << 0) | (1 << 1);
+/// Maximum 12-bit ADC reading
+const ADC_MAX: u16 = (1 << 12) - 1;
+
+/// ADC register bits not easily accessible through HAL, control bits can only be set by writing 1
+const ADC_ISR_ADRDY: u32 = 1 << 0;
+const ADC_ISR_EOC: u32 = 1 << 2;
+const ADC_ISR_AWD: u32 = 1 << 7;
+const ADC_IER_AWDIE: u32 = 1 << 7;
+const ADC_CR_ADEN: u32 = 1 << 0;
+const ADC_CR_ADDIS: u32 = 1 << 1;
+const ADC_CR_ADSTART: u32 = 1 << 2;
+const ADC_CR_ADSTP: u32 = 1 << 4;
+const ADC_CFGR1_OVRMOD: u32 = 1 << 12;
+const ADC_CFGR1_CONT: u32 = 1 << 13;
+const ADC_CFGR1_AWDSGL: u32 = 1 << 22;
+const ADC_CFGR1_AWDEN: u32 = 1 << 23;
+
 /// Two-axis joystick read via ADC
 pub struct Joystick {
     adc: hal::adc::Adc,
     zero: (u16, u16),
     x: GpioX,
     y: GpioY,
+    wake_armed: bool,
 }
 
 impl Joystick {
@@ -26,13 +48,14 @@ impl Joystick {
         adc.set_precision(adc::AdcPrecision::B_12);
         adc.set_sample_time(adc::AdcSampleTime::T_239);
 
-        let mut joy = Self { adc, x, y, zero: (0, 0) };
+        let mut joy = Self { adc, x, y, zero: (0, 0), wake_armed: false };
         joy.calibrate_zero();
 
         joy
     }
 
     fn read_raw(&mut self) -> (u16, u16) {
+        debug_assert!(!self.wake_armed, "ADC used by wake up");
         // Current HAL implementation cannot return any error.
         let x = self.adc.read(&mut self.x).unwrap();
         let y = self.adc.read(&mut self.y).unwrap();
@@ -100,6 +123,91 @@ impl Joystick {
         x || y
     }
 
+    /// ADC window of a single axis, `deadzone` around its zero position
+    fn axis_window(zero: u16, deadzone: u16) -> (u16, u16) {
+        (zero.saturating_sub(deadzone), zero.saturating_add(deadzone).min(ADC_MAX))
+    }
+
+    /// Analog watchdog window for both axes
+    ///
+    /// There is a single watchdog for all channels. The intersection of per-axis windows makes
+    /// each axis trigger no later than at its own deadzone; the interrupt handler then filters out
+    /// the axis that is still within its own window. If an axis rests close to the edge of the
+    /// intersection (zeros far apart compared to the deadzones) this would trigger all the time,
+    /// so the union is used instead, at the cost of reduced sensitivity of one axis.
+    fn wake_window(zero: (u16, u16), windows: [(u16, u16); 2]) -> (u16, u16) {
+        let [(x_low, x_high), (y_low, y_high)] = windows;
+        let (low, high) = (x_low.max(y_low), x_high.min(y_high));
+        let margin = (x_high - x_low).min(y_high - y_low) / 4;
+        let inside = |z: u16| low.saturating_add(margin) <= z && z.saturating_add(margin) <= high;
+        if inside(zero.0) && inside(zero.1) {
+            (low, high)
+        } else {
+            (x_low.min(y_low), x_high.max(y_high))
+        }
+    }
+
+    /// Start continuous conversions guarded by ADC analog watchdog
+    ///
+    /// When an axis leaves the window of its `deadzone` (x, y) around zero, ADC interrupt
+    /// is generated, which wakes up the MCU without the need of sampling the joystick in software.
+    /// The handler stops conversions (see [`on_interrupt`]), use [`Self::wake_triggered`] to check it.
+    /// Regular readings are not possible until [`Self::disarm_wake`].
+    pub fn arm_wake(&mut self, deadzone: (u16, u16)) -> Result<(), AdcTimeout> {
+        let windows = [
+            Self::axis_window(self.zero.0, deadzone.0),
+            Self::axis_window(self.zero.1, deadzone.1),
+        ];
+        let (low, high) = Self::wake_window(self.zero, windows);
+        defmt::debug!("Joystick wake window: {=u16}..{=u16}", low, high);
+        for (axis, (low, high)) in WAKE_WINDOWS.iter().zip(windows) {
+            axis.store((high as u32) << 16 | low as u32, Ordering::Relaxed);
+        }
+
+        let adc = regs();
+        // HAL disables ADC after each reading
+        adc.isr.write(|w| unsafe { w.bits(ADC_ISR_ADRDY | ADC_ISR_AWD) });
+        adc.cr.write(|w| unsafe { w.bits(ADC_CR_ADEN) });
+        if let Err(e) = wait_until(|| adc.isr.read().bits() & ADC_ISR_ADRDY != 0) {
+            adc.cr.write(|w| unsafe { w.bits(ADC_CR_ADDIS) });
+            return Err(e);
+        }
+        adc.chselr.write(|w| unsafe { w.bits(CHANNELS) });
+        adc.tr.write(|w| unsafe { w.bits((high as u32) << 16 | low as u32) });
+        // Nobody reads the data, so let new conversions overwrite it; watch all channels
+        adc.cfgr1.modify(|r, w| unsafe {
+            w.bits((r.bits() & !ADC_CFGR1_AWDSGL) | ADC_CFGR1_CONT | ADC_CFGR1_OVRMOD | ADC_CFGR1_AWDEN)
+        });
+        adc.ier.modify(|r, w| unsafe { w.bits(r.bits() | ADC_IER_AWDIE) });
+        adc.cr.write(|w| unsafe { w.bits(ADC_CR_ADSTART) });
+        self.wake_armed = true;
+        Ok(())
+    }
+
+    /// Stop conversions started by [`Self::arm_wake`]
+    pub fn disarm_wake(&mut self) -> Result<(), AdcTimeout> {
+        let adc = regs();
+        adc.ier.modify(|r, w| unsafe { w.bits(r.bits() & !ADC_IER_AWDIE) });
+        let stopped = stop_conversions();
+        adc.cfgr1.modify(|r, w| unsafe {
+            w.bits(r.bits() & !(ADC_CFGR1_CONT | ADC_CFGR1_OVRMOD | ADC_CFGR1_AWDEN))
+        });
+        adc.cr.write(|w| unsafe { w.bits(ADC_CR_ADDIS) });
+        let disabled = wait_until(|| adc.cr.read().bits() & ADC_CR_ADEN == 0);
+        self.wake_armed = false;
+        stopped.and(disabled)
+    }
+
+    /// Check if conversions for wake up are running, even if already triggered
+    pub fn is_wake_armed(&self) -> bool {
+        self.wake_armed
+    }
+
+    /// Check if joystick has left the dead zone since [`Self::arm_wake`]
+    pub fn wake_triggered(&self) -> bool {
+        self.wake_armed && regs().ier.read().bits() & ADC_IER_AWDIE == 0
+    }
+
     fn detect_read<F>(&mut self, f: F, delay: u32) -> (u16, u16)
     where
         F: FnOnce(&mut hal::pac::gpioa::pupdr::W) -> &mut hal::pac::gpioa::pupdr::W
@@ -113,11 +221,113 @@ impl Joystick {
     }
 }
 
+/// ADC did not reach the expected state in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct AdcTimeout;
+
+/// Per-axis wake windows (x, y) as `high << 16 | low`, set in [`Joystick::arm_wake`]
+static WAKE_WINDOWS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+fn regs() -> &'static hal::pac::adc::RegisterBlock {
+    unsafe { &*hal::pac::ADC::ptr() }
+}
+
+/// Busy-wait for ADC state change, which takes at most a few conversion times
+fn wait_until(done: impl Fn() -> bool) -> Result<(), AdcTimeout> {
+    // Way longer than enabling ADC or a conversion (~18 us) takes at 48 MHz
+    const MAX_POLLS: u32 = 10_000;
+    (0..MAX_POLLS).any(|_| done()).then_some(()).ok_or(AdcTimeout)
+}
+
+fn stop_conversions() -> Result<(), AdcTimeout> {
+    let adc = regs();
+    if adc.cr.read().bits() & ADC_CR_ADSTART != 0 {
+        adc.cr.write(|w| unsafe { w.bits(ADC_CR_ADSTP) });
+    }
+    wait_until(|| adc.cr.read().bits() & ADC_CR_ADSTART == 0)
+}
+
+/// Convert both channels once, returns raw (x, y)
+fn convert_once() -> Result<(u16, u16), AdcTimeout> {
+    let adc = regs();
+    adc.cfgr1.modify(|r, w| unsafe { w.bits(r.bits() & !ADC_CFGR1_CONT) });
+    adc.isr.write(|w| unsafe { w.bits(ADC_ISR_EOC) });
+    adc.cr.write(|w| unsafe { w.bits(ADC_CR_ADSTART) });
+    // Channels are converted in ascending order: PA0 (y) then PA1 (x)
+    let read = || -> Result<u16, AdcTimeout> {
+        wait_until(|| adc.isr.read().bits() & ADC_ISR_EOC != 0)?;
+        // Reading data clears EOC
+        Ok(adc.dr.read().bits() as u16)
+    };
+    let y = read()?;
+    let x = read()?;
+    // Sequence end clears ADSTART
+    wait_until(|| adc.cr.read().bits() & ADC_CR_ADSTART == 0)?;
+    Ok((x, y))
+}
+
+fn outside_window(value: u16, window: u32) -> bool {
+    let (low, high) = (window as u16, (window >> 16) as u16);
+    value < low || value > high
+}
+
+/// To be called from ADC interrupt handler, returns `true` if joystick left its dead zone
+///
+/// The watchdog window is shared by both axes, so the handler converts both axes once and
+/// checks each against its own window. On wake up the watchdog interrupt is disabled and
+/// conversions are stopped, otherwise they are resumed.
+pub fn on_interrupt() -> bool {
+    let adc = regs();
+    let triggered = stop_conversions().and_then(|_| convert_once()).map_or(true, |(x, y)| {
+        outside_window(x, WAKE_WINDOWS[0].load(Ordering::Relaxed))
+            || outside_window(y, WAKE_WINDOWS[1].load(Ordering::Relaxed))
+    });
+    adc.isr.write(|w| unsafe { w.bits(ADC_ISR_AWD) });
+    if triggered {
+        // Also on ADC errors, let the joystick task take over
+        adc.ier.modify(|r, w| unsafe { w.bits(r.bits() & !ADC_IER_AWDIE) });
+    } else {
+        adc.cfgr1.modify(|r, w| unsafe { w.bits(r.bits() | ADC_CFGR1_CONT) });
+        adc.cr.write(|w| unsafe { w.bits(ADC_CR_ADSTART) });
+    }
+    triggered
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use assert_float_eq::*;
 
+    #[test]
+    fn axis_window() {
+        assert_eq!(Joystick::axis_window(2100, 100), (2000, 2200));
+        assert_eq!(Joystick::axis_window(50, 100), (0, 150));
+        assert_eq!(Joystick::axis_window(4050, 100), (3950, ADC_MAX));
+    }
+
+    #[test]
+    fn wake_window() {
+        let window = |zero: (u16, u16), deadzone: (u16, u16)| Joystick::wake_window(zero, [
+            Joystick::axis_window(zero.0, deadzone.0),
+            Joystick::axis_window(zero.1, deadzone.1),
+        ]);
+        // Intersection, so that each axis triggers at its own deadzone at the latest
+        assert_eq!(window((2050, 2000), (150, 100)), (1900, 2100));
+        assert_eq!(window((2000, 2000), (150, 100)), (1900, 2100));
+        // Zeros too far apart, one of them would be at the edge of intersection
+        assert_eq!(window((2100, 2000), (100, 100)), (1900, 2200));
+        assert_eq!(window((50, 4050), (100, 100)), (0, ADC_MAX));
+    }
+
+    #[test]
+    fn axis_outside_window() {
+        let window = 2200 << 16 | 2000;
+        assert!(outside_window(1999, window));
+        assert!(!outside_window(2000, window));
+        assert!(!outside_window(2200, window));
+        assert!(outside_window(2201, window));
+    }
+
     #[test]
     fn coordinate_offset() {
         let zero = (2100, 2200);
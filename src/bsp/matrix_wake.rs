@@ -0,0 +1,107 @@
+use crate::hal;
+use super::{NCOLS, NAUX_KEYS};
+use super::board::{BOARD, PinId};
+
+/// SYSCFG register offset not easily accessible in PAC (separate types for each register)
+const SYSCFG_EXTICR1_OFFSET: usize = 0x08;
+
+/// EXTI lines used by the columns and auxiliary keys (line number equals pin number)
+const EXTI_MASK: u32 = {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < NCOLS {
+        mask |= 1 << BOARD.cols[i].pin;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < NAUX_KEYS {
+        mask |= 1 << BOARD.aux_keys[i].pin.pin;
+        i += 1;
+    }
+    mask
+};
+
+/// Pins that trigger wake up when pulled low
+fn wake_pins() -> impl Iterator<Item = &'static PinId> {
+    BOARD.cols.iter().chain(BOARD.aux_keys.iter().map(|key| &key.pin))
+}
+
+/// Interrupt-driven wake up from key matrix
+///
+/// While idle all rows are driven low, so pressing any key pulls its column low and
+/// triggers a falling-edge EXTI interrupt, which wakes the MCU from sleep/STOP. Auxiliary
+/// keys are not part of the matrix, so they trigger the interrupt directly. Normal
+/// scanning requires all rows to be high, so the matrix must be disarmed before scanning.
+/// The interrupt handler only masks the lines (see [`on_interrupt`]), keyboard logic notices
+/// that by polling [`MatrixWake::is_armed`].
+pub struct MatrixWake(());
+
+impl MatrixWake {
+    /// Route column and auxiliary key pins to EXTI lines (falling edge), interrupts stay masked
+    pub fn new(_syscfg: hal::pac::SYSCFG, _exti: hal::pac::EXTI, _rcc: &mut hal::rcc::Rcc) -> Self {
+        // Need to access some registers outside of HAL type system
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+        rcc_regs.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        for pin in wake_pins() {
+            let (reg, shift) = (pin.pin as usize / 4, 4 * (pin.pin as u32 % 4));
+            unsafe {
+                let exticr = (hal::pac::SYSCFG::ptr() as *const u8)
+                    .add(SYSCFG_EXTICR1_OFFSET + 4 * reg) as *mut u32;
+                let value = exticr.read_volatile() & !(0xf << shift);
+                exticr.write_volatile(value | ((pin.port as u32) << shift));
+            }
+        }
+
+        let exti = Self::exti();
+        exti.imr.modify(|r, w| unsafe { w.bits(r.bits() & !EXTI_MASK) });
+        exti.rtsr.modify(|r, w| unsafe { w.bits(r.bits() & !EXTI_MASK) });
+        exti.ftsr.modify(|r, w| unsafe { w.bits(r.bits() | EXTI_MASK) });
+        exti.pr.write(|w| unsafe { w.bits(EXTI_MASK) });
+
+        Self(())
+    }
+
+    fn exti() -> &'static hal::pac::exti::RegisterBlock {
+        unsafe { &*hal::pac::EXTI::ptr() }
+    }
+
+    fn set_rows(high: bool) {
+        for row in BOARD.rows {
+            row.set(high);
+        }
+    }
+
+    /// Drive all rows low and enable column interrupts
+    pub fn arm(&mut self) {
+        let exti = Self::exti();
+        exti.pr.write(|w| unsafe { w.bits(EXTI_MASK) });
+        exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | EXTI_MASK) });
+        // Drive rows after enabling interrupts, so a key that is already pressed still
+        // generates a falling edge
+        Self::set_rows(false);
+    }
+
+    /// Disable column interrupts and restore rows to the inactive (high) state
+    pub fn disarm(&mut self) {
+        on_interrupt();
+        Self::set_rows(true);
+    }
+
+    /// Check if wake up is still armed, i.e. no key has been pressed
+    pub fn is_armed(&self) -> bool {
+        Self::exti().imr.read().bits() & EXTI_MASK != 0
+    }
+
+    /// Check if any column or auxiliary key is currently pulled low
+    pub fn any_column_low(&self) -> bool {
+        wake_pins().any(|pin| !pin.is_high())
+    }
+}
+
+/// To be called from EXTI interrupt handlers, masks and clears wake up interrupts
+pub fn on_interrupt() {
+    let exti = MatrixWake::exti();
+    exti.imr.modify(|r, w| unsafe { w.bits(r.bits() & !EXTI_MASK) });
+    exti.pr.write(|w| unsafe { w.bits(EXTI_MASK) });
+}
@@ -3,53 +3,76 @@
 //! Code that builds on top of MCU-specific HAL (hal and hal_ext) to implement
 //! support for the board and the peripherals located on it.
 
+/// PCB wiring description
+pub mod board;
 /// Low-level debugging via GPIO/UART
 pub mod debug;
+/// defmt transport over USB bulk endpoint
+#[cfg(feature = "defmt-usb")]
+pub mod defmt_usb;
+/// MCU unique ID and hardware information
+pub mod ident;
+/// Firmware image checksum verification
+pub mod image;
 /// Analog joystick readings
 pub mod joystick;
 /// Definitions that depend on keyboard half side
 pub mod sides;
+/// Key usage statistics storage in flash
+pub mod stats_flash;
+/// Waking up on key press using EXTI interrupts
+pub mod matrix_wake;
+/// Persistent panic reports
+pub mod panic;
+/// Values preserved over system reset
+pub mod persistent;
 /// USB classes
 pub mod usb;
+/// Sensing of 5 V on the USB connector
+pub mod vbus;
 /// Driver for WS2812B RGB LEDs via SPI
 pub mod ws2812b;
 
-use crate::hal::{self, gpio};
+use crate::hal;
+use board::BOARD;
+
+/// Size of flash memory available for the firmware image, must match memory.x
+///
+/// The last page of the 64K flash is not included, it is reserved for [`stats_flash`].
+pub const FLASH_SIZE: usize = 62 * 1024;
+/// Size of RAM, must match memory.x
+pub const RAM_SIZE: usize = 16 * 1024;
 
 /// Number of columns keyboard half
-pub const NCOLS: usize = 6;
+pub const NCOLS: usize = BOARD.cols.len();
 /// Number of "column-slots" in the thumb cluster
 ///
 /// Note that joystick uses a separate column, but shares the "column-slot".
 /// This count is the physical number of places where keys can exist, as
 /// joystick replaces key (4, 0).
-pub const NCOLS_THUMB: usize = 4;
+pub const NCOLS_THUMB: usize = BOARD.ncols_thumb;
 /// Number of key rows
-pub const NROWS: usize = 5;
+pub const NROWS: usize = BOARD.rows.len();
+/// Number of keys wired directly to GPIOs, outside of the matrix
+pub const NAUX_KEYS: usize = BOARD.aux_keys.len();
 /// Number of LEDs on each half (this is also the number of keys)
-pub const NLEDS: usize = 28;
+pub const NLEDS: usize = BOARD.n_leds();
 
 /// List of colors for all LEDs on a single half
 pub type LedColors = [rgb::RGB8; NLEDS];
 
 /// Type of GPIOs connected to key matrix columns
-pub type ColPin = gpio::Pin<gpio::Input<gpio::PullUp>>;
+pub type ColPin = board::MatrixPin;
 /// Type of GPIOs connected to key matrix rows
-pub type RowPin = gpio::Pin<gpio::Output<gpio::PushPull>>;
+pub type RowPin = board::MatrixPin;
 
-/// Perform blocking microsecond delay assuming 48 MHz CPU frequency
-///
-/// From measurements with logic analyzer:
-/// * `cortex_m::asm::delay(1000)` takes 42 us
-/// * `cortex_m::asm::delay(0)` takes 0.25 us
-/// The above results have the overhead of `tasks::trace::run` (1.125 us) already
-/// subtracted from the measured duration.
+/// Perform blocking microsecond delay
 ///
-/// The theoretical time for 1000 cycle delay is 20.83 us.
+/// Uses [`hal_ext::clock`](crate::hal_ext::clock) so it does not depend on CPU frequency,
+/// but requires the clock to be started.
 #[inline(always)]
 pub fn delay_us(us: u32) {
-    const RATIO: u32 = 1000 / 42 + 1;
-    cortex_m::asm::delay(us * RATIO);
+    crate::hal_ext::clock::delay(crate::hal_ext::clock::Duration::from_micros(us));
 }
 
 /// Get device ID value from Option Bytes (if OPTERR is not set).
@@ -0,0 +1,163 @@
+use core::panic::PanicInfo;
+use defmt::Format;
+
+use super::persistent::Persistent;
+
+const FILE_LEN: usize = 48;
+const MSG_LEN: usize = 64;
+
+/// Information about the last panic, preserved over software reset
+///
+/// Stored in `.uninit` RAM section which is not initialized on startup, so it survives
+/// system reset (but not power loss).
+#[repr(C)]
+pub struct PanicReport {
+    /// Report has already been processed after reboot
+    seen: bool,
+    pub line: u32,
+    pub column: u32,
+    file: [u8; FILE_LEN],
+    file_len: u8,
+    msg: [u8; MSG_LEN],
+    msg_len: u8,
+    pub regs: Registers,
+}
+
+/// Register snapshot taken in panic handler
+#[derive(Clone, Copy, Default, Format)]
+#[repr(C)]
+pub struct Registers {
+    /// Main stack pointer
+    pub msp: u32,
+    /// Interrupt control and state register, lowest bits hold active exception number
+    pub icsr: u32,
+    /// CONTROL register
+    pub control: u32,
+}
+
+#[link_section = ".uninit.ghanima.panic"]
+static REPORT: Persistent<PanicReport, 0x7061_6e31> = Persistent::new();
+
+fn copy_truncated<const N: usize>(dst: &mut [u8; N], src: &str) -> u8 {
+    // Truncate on char boundary to keep valid UTF-8
+    let mut len = src.len().min(N);
+    while !src.is_char_boundary(len) {
+        len -= 1;
+    }
+    dst[..len].copy_from_slice(&src.as_bytes()[..len]);
+    len as u8
+}
+
+impl PanicReport {
+    fn from_info(info: &PanicInfo, regs: Registers) -> Self {
+        let location = info.location().map(|loc| (loc.file(), loc.line(), loc.column()));
+        // Only static messages to avoid pulling in core::fmt
+        Self::new(location, info.message().as_str(), regs)
+    }
+
+    fn new(location: Option<(&str, u32, u32)>, msg: Option<&str>, regs: Registers) -> Self {
+        let mut report = Self {
+            seen: false,
+            line: 0,
+            column: 0,
+            file: [0; FILE_LEN],
+            file_len: 0,
+            msg: [0; MSG_LEN],
+            msg_len: 0,
+            regs,
+        };
+        if let Some((file, line, column)) = location {
+            report.line = line;
+            report.column = column;
+            // Keep the end of the path as it is more informative
+            let start = file.len().saturating_sub(FILE_LEN);
+            let start = (start..file.len()).find(|i| file.is_char_boundary(*i)).unwrap_or(file.len());
+            report.file_len = copy_truncated(&mut report.file, &file[start..]);
+        }
+        if let Some(msg) = msg {
+            report.msg_len = copy_truncated(&mut report.msg, msg);
+        }
+        report
+    }
+
+    fn is_valid(&self) -> bool {
+        self.file_len as usize <= FILE_LEN
+            && self.msg_len as usize <= MSG_LEN
+    }
+
+    /// Source file of panic location (possibly truncated)
+    pub fn file(&self) -> &str {
+        core::str::from_utf8(&self.file[..self.file_len as usize]).unwrap_or("?")
+    }
+
+    /// Panic message, empty if it required formatting
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.msg[..self.msg_len as usize]).unwrap_or("?")
+    }
+}
+
+impl Format for PanicReport {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}:{=u32}:{=u32} '{=str}' {}",
+            self.file(), self.line, self.column, self.message(), self.regs);
+    }
+}
+
+/// Store panic report, to be called from panic handler
+///
+/// # Safety
+///
+/// Must be called with interrupts disabled, as it writes to a global without synchronization.
+pub unsafe fn store(info: &PanicInfo) {
+    let scb = &*cortex_m::peripheral::SCB::PTR;
+    let regs = Registers {
+        msp: cortex_m::register::msp::read(),
+        icsr: scb.icsr.read(),
+        control: cortex_m::register::control::read().bits(),
+    };
+    REPORT.store(PanicReport::from_info(info, regs));
+}
+
+/// Check for a new panic report on boot
+///
+/// Returns the report only once after the panic, later it is still available via [`last`].
+pub fn on_boot() -> Option<&'static PanicReport> {
+    // Safety: called during init, before any task can access it
+    let report = unsafe { REPORT.get_mut() }.filter(|r| r.is_valid())?;
+    if report.seen {
+        return None;
+    }
+    report.seen = true;
+    Some(report)
+}
+
+/// Get the last panic report if there was any since power-on
+pub fn last() -> Option<&'static PanicReport> {
+    // Safety: report is only modified by panic handler and during init
+    unsafe { REPORT.get() }.filter(|r| r.is_valid())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Report with given location and message, for tests of code that reads reports
+    pub fn report(file: &str, line: u32, msg: &str) -> PanicReport {
+        let regs = Registers { msp: 0x2000_3f00, icsr: 3, control: 0 };
+        PanicReport::new(Some((file, line, 7)), Some(msg), regs)
+    }
+
+    #[test]
+    fn truncate_path_and_message() {
+        let long = "a/".repeat(40) + "main.rs";
+        let r = report(&long, 12, &"x".repeat(100));
+        assert!(r.is_valid());
+        assert_eq!(r.file().len(), FILE_LEN);
+        assert!(r.file().ends_with("/main.rs"));
+        assert_eq!(r.message().len(), MSG_LEN);
+        assert_eq!((r.line, r.column), (12, 7));
+
+        let r = PanicReport::new(None, None, Registers::default());
+        assert_eq!((r.file(), r.message(), r.line), ("", "", 0));
+    }
+}
@@ -0,0 +1,104 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+
+/// Value preserved over system reset
+///
+/// Statics of this type should be placed in `.uninit` RAM section (using `#[link_section]`),
+/// which is not initialized on startup, so the value survives system reset (but not power loss).
+/// `MAGIC` marks a stored value, any other memory content (e.g. after power-on) means no value.
+#[repr(C)]
+pub struct Persistent<T, const MAGIC: u32> {
+    magic: UnsafeCell<MaybeUninit<u32>>,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: each value is only written from a single context (init, a single task or panic handler)
+unsafe impl<T, const MAGIC: u32> Sync for Persistent<T, MAGIC> {}
+
+impl<T, const MAGIC: u32> Persistent<T, MAGIC> {
+    pub const fn new() -> Self {
+        Self {
+            magic: UnsafeCell::new(MaybeUninit::uninit()),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    fn is_stored(&self) -> bool {
+        // SAFETY: memory is reserved for that purpose, any value is valid
+        unsafe { (*self.magic.get()).assume_init() == MAGIC }
+    }
+
+    /// Preserve value over system reset
+    pub fn store(&self, value: T) {
+        // SAFETY: we're writing to memory that is reserved for that purpose
+        unsafe {
+            (*self.value.get()).write(value);
+            (*self.magic.get()).write(MAGIC);
+        }
+    }
+
+    /// Remove stored value
+    pub fn clear(&self) {
+        // SAFETY: as in store()
+        unsafe { (*self.magic.get()).write(0) };
+    }
+
+    /// Reference to the stored value, if any
+    ///
+    /// # Safety
+    ///
+    /// There must be no [`Self::store`] or other mutable access while the reference is alive.
+    pub unsafe fn get(&self) -> Option<&T> {
+        self.is_stored().then(|| (*self.value.get()).assume_init_ref())
+    }
+
+    /// Mutable reference to the stored value, if any
+    ///
+    /// # Safety
+    ///
+    /// There must be no other access to the value while the reference is alive.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut(&self) -> Option<&mut T> {
+        self.is_stored().then(|| (*self.value.get()).assume_init_mut())
+    }
+}
+
+impl<T: Copy, const MAGIC: u32> Persistent<T, MAGIC> {
+    /// Value stored before the last system reset, if any
+    pub fn load(&self) -> Option<T> {
+        // SAFETY: value is copied out, so no reference outlives this call
+        unsafe { self.get().copied() }
+    }
+}
+
+impl<T, const MAGIC: u32> Default for Persistent<T, MAGIC> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_load_clear() {
+        let state = Persistent::<u16, 0x1234_5678>::new();
+        state.clear();
+        assert_eq!(state.load(), None);
+        state.store(37);
+        assert_eq!(state.load(), Some(37));
+        state.store(0);
+        assert_eq!(state.load(), Some(0));
+        state.clear();
+        assert_eq!(state.load(), None);
+    }
+
+    #[test]
+    fn modify_in_place() {
+        let state = Persistent::<[u8; 3], 1>::new();
+        state.store([1, 2, 3]);
+        unsafe { state.get_mut().unwrap()[1] = 5 };
+        assert_eq!(state.load(), Some([1, 5, 3]));
+    }
+}
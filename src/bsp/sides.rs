@@ -1,9 +1,6 @@
-use core::convert::Infallible;
-use embedded_hal::digital::v2::InputPin;
 use serde::{Serialize, Deserialize};
 
-use crate::utils::InfallibleResult;
-use super::{NCOLS, NCOLS_THUMB, NROWS};
+use super::{NCOLS, NCOLS_THUMB, NROWS, board::BOARD};
 
 /// Side of a half of a split-keyboard
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -24,15 +21,6 @@ pub struct PerSide<T> {
 impl BoardSide {
     pub const EACH: [Self; 2] = [Self::Left, Self::Right];
 
-    /// Board side can be determined via pull-up/down on a pin
-    pub fn get(pin: impl InputPin<Error = Infallible>) -> Self {
-        if pin.is_high().infallible() {
-            Self::Left
-        } else {
-            Self::Right
-        }
-    }
-
     /// Get the other side
     pub const fn other(&self) -> BoardSide {
         match self {
@@ -50,7 +38,13 @@ impl BoardSide {
             Self::Left => col < ncols,
             Self::Right => (col < 2 * NCOLS) && (col >= 2 * NCOLS - ncols),
         };
-        row_valid && col_valid
+        // Auxiliary keys use reserved coordinates outside of the regular keys
+        let aux = match self {
+            Self::Left => col < NCOLS && BOARD.is_aux_key((row as u8, col as u8)),
+            Self::Right => col >= NCOLS && col < 2 * NCOLS
+                && BOARD.is_aux_key((row as u8, Self::reflect_col(col as u8))),
+        };
+        (row_valid && col_valid) || aux
     }
 
     const fn reflect_col(col: u8) -> u8 {
@@ -183,32 +177,16 @@ impl BoardSide {
     ///
     /// Row and column must be valid, side-local key coordinates.
     pub const fn led_number((row, col): (u8, u8)) -> Option<u8> {
-        // Special case for joystick which has no LED
-        if row == 4 && col == 4 {
-            None
-        } else {
-            // Both sides are routed in the same way
-            // LED numbers in odd rows increase with column, and decrease in even rows
-            let rows_before = row * NCOLS as u8;
-            let this_row = if row % 2 == 0 {
-                (Self::n_cols(row) - 1) - col
-            } else {
-                col
-            };
-            Some(rows_before + this_row)
-        }
+        // Both sides are routed in the same way
+        BOARD.led_numbers[row as usize][col as usize]
     }
 
     /// Get side-local key coordinates for given RGB LED
     pub const fn led_coords(led: u8) -> (u8, u8) {
-        let row = led / (NCOLS as u8);
-        let led_rem = led % (NCOLS as u8);
-        let col = if row % 2 == 0 {
-            (Self::n_cols(row) - 1) - led_rem
-        } else {
-            led_rem
-        };
-        (row, col)
+        match BOARD.led_coords(led) {
+            Some(coords) => coords,
+            None => panic!("Invalid LED number"),
+        }
     }
 }
 
@@ -0,0 +1,40 @@
+use crate::hal_ext::flash::{self, Flash, PAGE_SIZE};
+use crate::keyboard::stats::{self, Flush, KeyStats};
+
+/// Last flash page, right after the region available for the firmware image in memory.x
+pub const ADDRESS: usize = 0x0800_0000 + super::FLASH_SIZE;
+
+const _: () = assert!(ADDRESS + PAGE_SIZE == 0x0800_0000 + 64 * 1024, "Statistics must use the last flash page");
+
+fn page() -> &'static [u32] {
+    // SAFETY: the page is reserved for statistics, flash is always readable and any value is valid
+    unsafe { core::slice::from_raw_parts(ADDRESS as *const u32, PAGE_SIZE / 4) }
+}
+
+/// Restore statistics from the latest record in flash, returns `false` if there is none
+pub fn load<const L: usize>(stats: &mut KeyStats<L>) -> bool {
+    stats::latest_record(page(), KeyStats::<L>::RECORD_LEN)
+        .map_or(false, |record| stats.load(record))
+}
+
+/// Perform the next step of writing pending statistics record, returns `true` when it is written
+///
+/// The page is erased when full or on [`Flush::Erase`]. Erasing and programming are separate
+/// steps, so that the CPU stall of each one fits in a single watchdog period.
+pub fn store<const L: usize>(flash: &mut Flash, stats: &mut KeyStats<L>) -> Result<bool, flash::Error> {
+    let Some(flush) = stats.pending_flush() else {
+        return Ok(true);
+    };
+    match (flush, stats::free_slot(page(), KeyStats::<L>::RECORD_LEN)) {
+        (Flush::Append, Some(offset)) => {
+            flash.program(ADDRESS + 4 * offset, stats.record())?;
+            stats.take_flush();
+            Ok(true)
+        },
+        _ => {
+            flash.erase_page(ADDRESS)?;
+            stats.page_erased();
+            Ok(false)
+        },
+    }
+}
@@ -3,15 +3,20 @@ use pkg_version::{pkg_version_major, pkg_version_minor};
 use static_assertions::const_assert;
 use usb_device::UsbError;
 use usb_device::bus::UsbBusAllocator;
-use usb_device::device::{UsbDevice, UsbVidPid, UsbDeviceBuilder};
+use usb_device::device::{UsbDevice, UsbDeviceState, UsbVidPid, UsbDeviceBuilder};
+use usbd_human_interface_device::UsbHidError;
 use usbd_dfu_rt::DfuRuntimeClass;
 use usbd_microsoft_os::MsOsUsbClass;
 
 use crate::build_info;
 use crate::hal::usb;
 use crate::hal_ext::reboot;
-use crate::keyboard::hid;
+use crate::keyboard::{hid, UsbHost, power::Vbus, ticks::Millis};
+#[cfg(feature = "defmt-usb")]
+use super::defmt_usb::DefmtClass;
+use super::ident;
 use super::sides::BoardSide;
+use super::vbus::VbusSense;
 
 pub use reboot::DfuBootloader;
 
@@ -23,8 +28,14 @@ pub struct Usb {
     pub hid: hid::HidClass<'static, Bus>,
     // this does not need to be share but it should be cleaner to have it here
     pub dfu: DfuRuntimeClass<reboot::DfuBootloader>,
+    #[cfg(feature = "defmt-usb")]
+    defmt: DefmtClass<'static, Bus>,
+    raw_hid: hid::RawHidClass<'static, Bus>,
+    /// Responses waiting until host reads the previous ones
+    raw_responses: heapless::Deque<hid::RawReport, RAW_RESPONSE_QUEUE_LEN>,
     ms_os: MsOsUsbClass,
-    wake_up_counter: u16,
+    vbus: VbusSense,
+    wake_up_remaining: Millis,
     keyboard_leds: hid::KeyboardLeds,
 }
 
@@ -34,10 +45,15 @@ pub struct UsbConfig<const N: usize> {
     pub bootload_strict: bool,
     pub serial_num: &'static mut heapless::String<N>,
     pub device_id: Option<u16>,
+    pub uid: ident::Uid,
+    pub vbus: VbusSense,
 }
 
-/// Storage for serial number string, e.g. `v1.10.100:65535`
-pub const SERIAL_NUM_MAX_LEN: usize = 32;
+/// Raw HID responses that can be pending at once, e.g. from several requests in one tick
+const RAW_RESPONSE_QUEUE_LEN: usize = 4;
+
+/// Storage for serial number string, e.g. `v1.10.100:65535` or `v1.10.100:u1234abcd`
+pub const SERIAL_NUM_MAX_LEN: usize = 36;
 
 impl Usb {
     pub fn new<const N: usize>(cfg: UsbConfig<N>) -> Self {
@@ -47,6 +63,11 @@ impl Usb {
         // does not like having DFU interface with number 0 and will report invalid configuration
         // descriptor.
         let dfu = usbd_dfu_rt::DfuRuntimeClass::new(cfg.bus, reboot::DfuBootloader::new(!cfg.bootload_strict));
+        // Keep it after DFU to avoid changing DFU interface number used in MS OS descriptors
+        #[cfg(feature = "defmt-usb")]
+        let defmt = DefmtClass::new(cfg.bus);
+        // Separate from HidClass so that it can be created after DFU for the same reason
+        let raw_hid = hid::RawHidClass::new(cfg.bus);
 
         let ms_os = ms_os::class();
 
@@ -54,7 +75,7 @@ impl Usb {
         // TODO: follow guidelines from https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
         // VID:PID recognised as Van Ooijen Technische Informatica:Keyboard
         let generic_keyboard = UsbVidPid(0x16c0, 0x27db);
-        let serial_number = Self::format_serial_num(cfg.serial_num, cfg.device_id).unwrap();
+        let serial_number = Self::format_serial_num(cfg.serial_num, cfg.device_id, &cfg.uid).unwrap();
         let dev = UsbDeviceBuilder::new(cfg.bus, generic_keyboard)
             .composite_with_iads()
             // From my measurements, with all LEDs set to constant white, the keyboard (both halves)
@@ -72,17 +93,42 @@ impl Usb {
             .device_release(Self::bcd_device())
             .build();
 
-        Self { dev, hid, dfu, ms_os, wake_up_counter: 0, keyboard_leds: Default::default() }
+        Self {
+            dev,
+            hid,
+            dfu,
+            #[cfg(feature = "defmt-usb")]
+            defmt,
+            raw_hid,
+            raw_responses: heapless::Deque::new(),
+            ms_os,
+            vbus: cfg.vbus,
+            wake_up_remaining: Millis(0),
+            keyboard_leds: Default::default(),
+        }
     }
 
     /// Periodic USB poll
     pub fn poll(&mut self) -> bool {
+        #[cfg(not(feature = "defmt-usb"))]
+        let mut got_data = self.dev.poll(&mut [
+            &mut self.hid,
+            &mut self.dfu,
+            &mut self.raw_hid,
+            &mut self.ms_os,
+        ]);
+        #[cfg(feature = "defmt-usb")]
         let mut got_data = self.dev.poll(&mut [
             &mut self.hid,
             &mut self.dfu,
+            &mut self.defmt,
+            &mut self.raw_hid,
             &mut self.ms_os,
         ]);
 
+        // Endpoint may have been freed by host reading the previous response
+        self.flush_raw_responses();
+
         if got_data {
             let keyboard: &hid::KeyboardInterface<'_, _> = self.hid.interface();
             match keyboard.read_report() {
@@ -98,18 +144,42 @@ impl Usb {
         got_data
     }
 
-    pub fn keyboard_leds(&self) -> hid::KeyboardLeds {
-        self.keyboard_leds
+    /// Start sending buffered defmt logs if USB transport is used
+    ///
+    /// Further packets are sent from USB interrupt, but something must start the transfer.
+    pub fn flush_logs(&mut self) {
+        #[cfg(feature = "defmt-usb")]
+        self.defmt.flush();
     }
 
-    /// Set wake up state; call repeatedly, ticks should take 1-15 ms
-    pub fn wake_up_update(&mut self, wake_up: bool, ticks: u16) {
-        if wake_up && self.wake_up_counter == 0 {
-            self.dev.bus().remote_wakeup(true);
-            self.wake_up_counter = ticks;
-        } else {
-            self.wake_up_counter = self.wake_up_counter.saturating_sub(1);
-            self.dev.bus().remote_wakeup(self.wake_up_counter != 0);
+    /// Advance time of DFU class, may reboot to bootloader when detach timeout ends
+    pub fn dfu_tick(&mut self, elapsed: Millis) {
+        self.dfu.ops_mut().tick(elapsed.as_u16());
+        self.dfu.tick(elapsed.as_u16());
+    }
+
+    /// Fraction of time remaining until detaching to DFU bootloader, see [`DfuBootloader::detach_remaining`]
+    pub fn dfu_countdown(&self) -> Option<u8> {
+        self.dfu.ops().detach_remaining()
+    }
+
+    /// Send response to host software on the raw HID interface
+    ///
+    /// Responses are queued until host reads the previous ones, dropped if the queue is full.
+    pub fn write_raw_report(&mut self, report: &hid::RawReport) {
+        if self.raw_responses.push_back(*report).is_err() {
+            defmt::warn!("Raw HID response dropped");
+        }
+        self.flush_raw_responses();
+    }
+
+    fn flush_raw_responses(&mut self) {
+        while let Some(report) = self.raw_responses.front() {
+            match self.raw_hid.write_report(report) {
+                Err(UsbError::WouldBlock) => break,
+                // Also drop the report on other errors, there is no point in retrying
+                _ => self.raw_responses.pop_front(),
+            };
         }
     }
 
@@ -121,20 +191,123 @@ impl Usb {
         major | minor
     }
 
-    fn format_serial_num<const N: usize>(s: &mut heapless::String<N>, device_id: Option<u16>) -> Result<&str, ()> {
+    /// Use device ID from option bytes if available, else fall back to MCU unique ID
+    fn format_serial_num<'a, const N: usize>(
+        s: &'a mut heapless::String<N>,
+        device_id: Option<u16>,
+        uid: &ident::Uid,
+    ) -> Result<&'a str, ()> {
         let version = build_info::GIT_VERSION.unwrap_or(build_info::PKG_VERSION);
         if let Some(id) = device_id {
             uwrite!(s, "{}:{}", version, id)?;
         } else {
-            uwrite!(s, "{}:?", version)?;
+            uwrite!(s, "{}:u", version)?;
+            ident::write_hex_u32(s, uid.short())?;
         };
         Ok(s.as_str())
     }
 }
 
+impl UsbHost for Usb {
+    fn state(&self) -> UsbDeviceState {
+        self.dev.state()
+    }
+
+    fn vbus(&mut self) -> Vbus {
+        self.vbus.sample()
+    }
+
+    fn keyboard_leds(&self) -> hid::KeyboardLeds {
+        self.keyboard_leds
+    }
+
+    fn bootloader_allowed(&self) -> bool {
+        self.dfu.ops().is_allowed()
+    }
+
+    fn allow_bootloader(&mut self) {
+        self.dfu.ops_mut().set_allowed(true);
+    }
+
+    fn reboot(&mut self, bootloader: bool) {
+        let bus = self.dev.bus();
+        self.dfu.ops_mut().reboot(bootloader, Some(bus));
+    }
+
+    fn wake_up_update(&mut self, wake_up: bool, duration: Millis, elapsed: Millis) {
+        if wake_up && self.wake_up_remaining == Millis(0) {
+            self.dev.bus().remote_wakeup(true);
+            self.wake_up_remaining = duration;
+        } else {
+            self.wake_up_remaining = self.wake_up_remaining.saturating_sub(elapsed);
+            self.dev.bus().remote_wakeup(self.wake_up_remaining != Millis(0));
+        }
+    }
+
+    fn hid_tick(&mut self) {
+        let keyboard: &hid::KeyboardInterface<'_, _> = self.hid.interface();
+        keyboard.tick().ok();
+    }
+
+    fn write_keyboard_report(&mut self, report: &hid::KeyboardReport) -> Result<(), UsbHidError> {
+        let keyboard: &hid::KeyboardInterface<'_, _> = self.hid.interface();
+        keyboard.write_report(report)
+    }
+
+    fn write_consumer_report(&mut self, report: &hid::ConsumerReport) -> Result<usize, UsbError> {
+        let consumer: &hid::ConsumerInterface<'_, _> = self.hid.interface();
+        consumer.write_report(report)
+    }
+
+    fn write_mouse_report(&mut self, report: &hid::MouseReport) -> Result<(), UsbHidError> {
+        let mouse: &hid::MouseInterface<'_, _> = self.hid.interface();
+        mouse.write_report(report)
+    }
+
+    fn read_raw_report(&mut self) -> Option<hid::RawReport> {
+        self.raw_hid.take_report()
+    }
+}
+
 mod ms_os {
     use usbd_microsoft_os::{os_20, MsOsUsbClass, WindowsVersion, utf16_lit, utf16_null_le_bytes};
 
+    const DFU_FUNCTION: os_20::FunctionSubset = os_20::FunctionSubset {
+        first_interface: 3,
+        features: &[
+            os_20::FeatureDescriptor::CompatibleId {
+                id: b"WINUSB\0\0",
+                sub_id: b"\0\0\0\0\0\0\0\0",
+            },
+            os_20::FeatureDescriptor::RegistryProperty {
+                data_type: os_20::PropertyDataType::RegMutliSz,
+                name: &utf16_lit::utf16_null!("DeviceInterfaceGUIDs"),
+                data: &utf16_null_le_bytes!("{897d7b90-5aae-43e5-9c36-aa0f2fdbafc9}\0"),
+            },
+        ]
+    };
+
+    #[cfg(feature = "defmt-usb")]
+    const DEFMT_FUNCTION: os_20::FunctionSubset = os_20::FunctionSubset {
+        first_interface: 4,
+        features: &[
+            os_20::FeatureDescriptor::CompatibleId {
+                id: b"WINUSB\0\0",
+                sub_id: b"\0\0\0\0\0\0\0\0",
+            },
+            os_20::FeatureDescriptor::RegistryProperty {
+                data_type: os_20::PropertyDataType::RegMutliSz,
+                name: &utf16_lit::utf16_null!("DeviceInterfaceGUIDs"),
+                data: &utf16_null_le_bytes!("{3c1a9d52-6f0e-4b8a-a2d4-7e15c0b9f361}\0"),
+            },
+        ]
+    };
+
+    #[cfg(not(feature = "defmt-usb"))]
+    const FUNCTIONS: &[os_20::FunctionSubset] = &[DFU_FUNCTION];
+    #[cfg(feature = "defmt-usb")]
+    const FUNCTIONS: &[os_20::FunctionSubset] = &[DFU_FUNCTION, DEFMT_FUNCTION];
+
     const DESCRIPTOR_SET: os_20::DescriptorSet = os_20::DescriptorSet {
         version: WindowsVersion::MINIMAL,
         features: &[],
@@ -142,22 +315,7 @@ mod ms_os {
             os_20::ConfigurationSubset {
                 configuration: 0,
                 features: &[],
-                functions: &[
-                    os_20::FunctionSubset {
-                        first_interface: 3,
-                        features: &[
-                            os_20::FeatureDescriptor::CompatibleId {
-                                id: b"WINUSB\0\0",
-                                sub_id: b"\0\0\0\0\0\0\0\0",
-                            },
-                            os_20::FeatureDescriptor::RegistryProperty {
-                                data_type: os_20::PropertyDataType::RegMutliSz,
-                                name: &utf16_lit::utf16_null!("DeviceInterfaceGUIDs"),
-                                data: &utf16_null_le_bytes!("{897d7b90-5aae-43e5-9c36-aa0f2fdbafc9}\0"),
-                            },
-                        ]
-                    }
-                ]
+                functions: FUNCTIONS,
             }
         ],
     };
@@ -188,20 +346,21 @@ mod tests {
 
     const PKG_VER: &str = env!("CARGO_PKG_VERSION");
     const GIT_VER: Option<&str> = build_info::GIT_VERSION;
+    const UID: ident::Uid = ident::Uid([0x01, 0x02, 0x03, 0x04, 0, 0, 0, 0, 0, 0, 0, 0xa0]);
 
     #[test]
     fn format_serial_num_none() {
         let git_ver = GIT_VER.unwrap();
         let mut s = heapless::String::<SERIAL_NUM_MAX_LEN>::new();
-        Usb::format_serial_num(&mut s, None).unwrap();
-        assert_eq!(s.as_str(), format!("{git_ver}:?"));
+        Usb::format_serial_num(&mut s, None, &UID).unwrap();
+        assert_eq!(s.as_str(), format!("{git_ver}:ua4030201"));
     }
 
     #[test]
     fn format_serial_num_small() {
         let git_ver = GIT_VER.unwrap();
         let mut s = heapless::String::<SERIAL_NUM_MAX_LEN>::new();
-        Usb::format_serial_num(&mut s, Some(42)).unwrap();
+        Usb::format_serial_num(&mut s, Some(42), &UID).unwrap();
         assert_eq!(s.as_str(), format!("{git_ver}:42"));
     }
 
@@ -209,7 +368,7 @@ mod tests {
     fn format_serial_num_huge() {
         let git_ver = GIT_VER.unwrap();
         let mut s = heapless::String::<SERIAL_NUM_MAX_LEN>::new();
-        Usb::format_serial_num(&mut s, Some(0xfffa)).unwrap();
+        Usb::format_serial_num(&mut s, Some(0xfffa), &UID).unwrap();
         assert_eq!(s.as_str(), format!("{git_ver}:65530"));
     }
 
@@ -217,6 +376,6 @@ mod tests {
     fn format_serial_num_huge_pkgver() {
         // Check that SERIAL_NUM_MAX_LEN is enough
         let mut s = heapless::String::<SERIAL_NUM_MAX_LEN>::new();
-        uwrite!(s, "v999.999.999-999-g99887766:65535").unwrap();
+        uwrite!(s, "v999.999.999-999-g99887766:u99887766").unwrap();
     }
 }
@@ -0,0 +1,77 @@
+use crate::keyboard::power::Vbus;
+use super::board::{BOARD, PinId, USB_DM, USB_DP};
+
+/// Number of consecutive equal samples needed to change the detected state
+const DEBOUNCE_SAMPLES: u8 = 16;
+
+/// Sensing of 5 V on the USB connector independent of USB enumeration
+///
+/// Uses VBUS sense pin from [`BOARD`] if it has been routed. Dedicated chargers are detected
+/// from the state of USB data lines, which can be read even when used by the USB peripheral.
+pub struct VbusSense {
+    pin: Option<PinId>,
+    state: Vbus,
+    candidate: Vbus,
+    count: u8,
+}
+
+impl VbusSense {
+    /// Configure VBUS sense pin and D- pull-down
+    ///
+    /// The pull-down keeps D- low when nothing is connected (D+ is pulled up by the USB
+    /// peripheral), it is weak enough not to interfere with host pull-downs.
+    ///
+    /// # Safety
+    ///
+    /// VBUS sense pin must not be used by anything else.
+    pub unsafe fn new() -> Self {
+        if let Some(pin) = BOARD.vbus_sense {
+            pin.input_floating();
+        }
+        USB_DM.pull_down();
+        Self { pin: BOARD.vbus_sense, state: Vbus::Unknown, candidate: Vbus::Unknown, count: 0 }
+    }
+
+    /// Sample the pins, returns debounced state
+    pub fn sample(&mut self) -> Vbus {
+        let pin = self.pin.map(|pin| pin.is_high());
+        self.update(Vbus::sense(pin, USB_DP.is_high(), USB_DM.is_high()))
+    }
+
+    fn update(&mut self, sensed: Vbus) -> Vbus {
+        if sensed == self.state {
+            self.candidate = sensed;
+            self.count = 0;
+        } else if sensed != self.candidate {
+            self.candidate = sensed;
+            self.count = 1;
+        } else {
+            self.count += 1;
+            if self.count >= DEBOUNCE_SAMPLES {
+                self.state = sensed;
+                self.count = 0;
+            }
+        }
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounce() {
+        let mut vbus = VbusSense { pin: None, state: Vbus::Unknown, candidate: Vbus::Unknown, count: 0 };
+        for _ in 0..DEBOUNCE_SAMPLES - 1 {
+            assert_eq!(vbus.update(Vbus::Charger), Vbus::Unknown);
+        }
+        // Glitch restarts counting
+        assert_eq!(vbus.update(Vbus::Unknown), Vbus::Unknown);
+        for _ in 0..DEBOUNCE_SAMPLES - 1 {
+            assert_eq!(vbus.update(Vbus::Charger), Vbus::Unknown);
+        }
+        assert_eq!(vbus.update(Vbus::Charger), Vbus::Charger);
+        assert_eq!(vbus.update(Vbus::Present), Vbus::Charger);
+    }
+}
@@ -38,6 +38,10 @@ const fn bytes_for_bits(bits: usize) -> usize {
 
 const SERIAL_SIZE: usize = bytes_for_bits(SERIAL_BITS);
 
+/// Number of bytes of serialized data of a single LED
+pub const LED_SIZE: usize = bytes_for_bits(led_bits(1));
+const RESET_SIZE_BEFORE: usize = bytes_for_bits(RESET_BITS_BEFORE);
+
 /// Structure holding RGB LED colors for the whole board
 ///
 /// Provides methods to serialize RGB data into format suitable for transmission
@@ -47,12 +51,8 @@ pub struct Leds<const N: usize> {
 }
 
 impl<const N: usize> Leds<N> {
-    /// Size of buffer needed for serialized LED data
-    pub const BUFFER_SIZE: usize = bytes_for_bits(all_bits(N));
-    // /// Zero-initialized buffer for serialized data
-    // pub const fn buffer_zeroed() -> [u8; Leds::<{ N }>::BUFFER_SIZE] {
-    //     [0u8; Leds::BUFFER_SIZE]
-    // }
+    /// Size of the whole serialized frame, including reset periods
+    pub const FRAME_SIZE: usize = bytes_for_bits(all_bits(N));
 
     /// Intialize with all LEDs diabled (black)
     pub const fn new() -> Self {
@@ -117,15 +117,9 @@ impl<const N: usize> Leds<N> {
         }
     }
 
-    /// Serialize all RGB values to given buffer
-    ///
-    /// # Panics
-    ///
-    /// If the buffer is not large enough - it must be at least [`Self::BUFFER_SIZE`] bytes.
-    pub fn serialize_to_slice(&self, buf: &mut [u8]) -> usize {
-        let data = &mut buf[RESET_BITS_BEFORE/8..(RESET_BITS_BEFORE+led_bits(self.colors.len()))/8];
-        Self::serialize_colors(&self.colors, data);
-        Self::BUFFER_SIZE
+    /// Create serializer streaming a snapshot of current colors
+    pub fn serializer(&self) -> Serializer<N> {
+        Serializer::new(self.colors)
     }
 
     /// Apply gamma correction
@@ -175,6 +169,62 @@ impl<const N: usize> Leds<N> {
     }
 }
 
+/// Streaming serializer of LED data
+///
+/// Encodes a frame (reset period, LED data, reset period) in chunks of arbitrary size, so
+/// that data can be generated just before DMA consumes it instead of keeping the whole
+/// frame in memory. Holds a copy of colors, so the source can be modified meanwhile.
+pub struct Serializer<const N: usize> {
+    colors: [RGB8; N],
+    pos: usize,
+}
+
+impl<const N: usize> Serializer<N> {
+    pub const fn new(colors: [RGB8; N]) -> Self {
+        Self { colors, pos: 0 }
+    }
+
+    /// Check if the whole frame has been serialized
+    pub fn is_done(&self) -> bool {
+        self.pos >= Leds::<N>::FRAME_SIZE
+    }
+
+    /// Serialize next part of the frame into `buf`
+    ///
+    /// Returns number of bytes written, which is less than `buf.len()` only at the end of
+    /// the frame (0 when the whole frame has already been serialized).
+    pub fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let data_end = RESET_SIZE_BEFORE + N * LED_SIZE;
+        let len = buf.len().min(Leds::<N>::FRAME_SIZE.saturating_sub(self.pos));
+        let mut i = 0;
+        while i < len {
+            let pos = self.pos + i;
+            let n = if pos < RESET_SIZE_BEFORE || pos >= data_end {
+                // Reset periods
+                let end = if pos < RESET_SIZE_BEFORE { RESET_SIZE_BEFORE } else { Leds::<N>::FRAME_SIZE };
+                let n = (end - pos).min(len - i);
+                buf[i..i + n].fill(0);
+                n
+            } else {
+                let (led, offset) = ((pos - RESET_SIZE_BEFORE) / LED_SIZE, (pos - RESET_SIZE_BEFORE) % LED_SIZE);
+                let n = (LED_SIZE - offset).min(len - i);
+                if n == LED_SIZE {
+                    Leds::<N>::serialize_colors(&self.colors[led..led + 1], &mut buf[i..i + n]);
+                } else {
+                    // Only a part of LED data fits
+                    let mut tmp = [0; LED_SIZE];
+                    Leds::<N>::serialize_colors(&self.colors[led..led + 1], &mut tmp);
+                    buf[i..i + n].copy_from_slice(&tmp[offset..offset + n]);
+                }
+                n
+            };
+            i += n;
+        }
+        self.pos += len;
+        len
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -201,7 +251,8 @@ mod tests {
             (false, true) | (true, false) => 441,  // bits: 840 + 2688 = 3528
             (false, false) => 336,  // bits: 2688
         };
-        assert_eq!(Leds::<28>::BUFFER_SIZE, bytes);
+        assert_eq!(Leds::<28>::FRAME_SIZE, bytes);
+        assert_eq!(LED_SIZE, 12);
     }
 
     #[test]
@@ -235,4 +286,50 @@ mod tests {
         ];
         assert_eq!(buf, expected, "\n  {:02x?}\n  vs\n  {:02x?}\n", buf, expected);
     }
+
+    fn serialize_frame<const N: usize>(leds: &Leds<N>, chunk: usize) -> std::vec::Vec<u8> {
+        let mut serializer = leds.serializer();
+        let mut frame = std::vec::Vec::new();
+        let mut buf = std::vec![0xff; chunk];
+        loop {
+            let n = serializer.fill(&mut buf);
+            frame.extend_from_slice(&buf[..n]);
+            if n < chunk {
+                break;
+            }
+        }
+        assert!(serializer.is_done());
+        frame
+    }
+
+    #[test]
+    fn serialize_frame_layout() {
+        let mut leds = Leds::<3>::new();
+        leds.colors = [RGB8::new(0xff, 0xaa, 0x31), RGB8::new(0xaa, 0x31, 0xff), RGB8::new(0, 0, 0)];
+        let frame = serialize_frame(&leds, 1000);
+        assert_eq!(frame.len(), Leds::<3>::FRAME_SIZE);
+        let mut data = [0u8; 3 * LED_SIZE];
+        Leds::<3>::serialize_colors(&leds.colors, &mut data);
+        assert!(frame[..RESET_SIZE_BEFORE].iter().all(|b| *b == 0));
+        assert_eq!(frame[RESET_SIZE_BEFORE..RESET_SIZE_BEFORE + data.len()], data);
+        assert!(frame[RESET_SIZE_BEFORE + data.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn serialize_chunks() {
+        let mut leds = Leds::<28>::new();
+        leds.set_test_pattern(1234, 200);
+        let expected = serialize_frame(&leds, Leds::<28>::FRAME_SIZE);
+        for chunk in [1, 5, 12, 48, 100] {
+            assert_eq!(serialize_frame(&leds, chunk), expected, "chunk = {}", chunk);
+        }
+    }
+
+    #[test]
+    fn serializer_finished() {
+        let mut serializer = Leds::<2>::new().serializer();
+        let mut buf = [0u8; 1024];
+        assert_eq!(serializer.fill(&mut buf), Leds::<2>::FRAME_SIZE);
+        assert_eq!(serializer.fill(&mut buf), 0);
+    }
 }
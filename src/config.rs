@@ -1,10 +1,47 @@
 //! Keyboard configuration
 
+use crate::bsp;
+
+/// Loading of layers, mouse and LED configuration from a binary blob
+pub mod blob;
+
+/// Checksum of the configuration source file (JSON or code)
+pub const CHECKSUM: u32 = include!(concat!(env!("OUT_DIR"), "/config_checksum.rs"));
+/// Name of the configuration source file without extension
+pub const NAME: &str = include!(concat!(env!("OUT_DIR"), "/config_name.rs"));
+
 #[cfg(feature = "json-config")]
-pub use generated::{CONFIG, N_LAYERS};
+pub use generated::{CONFIG, N_LAYERS, DATA_SIZE};
 
 #[cfg(not(feature = "json-config"))]
-pub use code::{CONFIG, N_LAYERS};
+pub use code::{CONFIG, N_LAYERS, DATA_SIZE};
+
+/// Flash reserved for firmware code
+///
+/// Code of a release build takes about 41 KB (see example section addresses in
+/// [`bsp::debug::mem`]), the rest is a margin for code growth. Debugging features noticeably
+/// increase code size, so they get a larger budget. Linker still detects the actual overflow,
+/// this only allows to fail early with an error that points to the configuration.
+const CODE_SIZE_BUDGET: usize = if cfg!(any(feature = "debug-shell", feature = "defmt-usb", feature = "task-profiling")) {
+    54 * 1024
+} else {
+    46 * 1024
+};
+
+/// Part of flash that configuration data may use, the rest is needed for firmware code
+pub const DATA_SIZE_MAX: usize = bsp::FLASH_SIZE - CODE_SIZE_BUDGET;
+
+const _: () = assert!(CONFIG.socd.len() <= crate::keyboard::socd::MAX_PAIRS,
+    "Too many SOCD key pairs, further pairs would be ignored");
+
+// Sizes only make sense for the target, host builds (tests, simulator) use 64-bit pointers
+#[cfg(target_os = "none")]
+const _: () = {
+    assert!(DATA_SIZE <= DATA_SIZE_MAX,
+        "Configuration does not fit in flash, reduce the number of layers, nested actions or LED rules");
+    assert!(core::mem::size_of::<crate::keyboard::Keyboard<N_LAYERS>>() <= bsp::RAM_SIZE / 2,
+        "Keyboard state takes too much RAM, reduce the number of layers");
+};
 
 #[cfg(feature = "json-config")]
 mod generated {
@@ -22,9 +59,14 @@ mod code {
 
     use crate::keyboard::actions::{Action as CustomAction, FirmwareAction};
     use crate::keyboard::actions::{MouseAction, MouseButton, MouseMovement, Inc, LedAction, ConsumerKey};
-    use crate::keyboard::mouse::{MouseConfig, SpeedProfile, AxisConfig, JoystickConfig};
-    use crate::keyboard::KeyboardConfig;
+    use crate::keyboard::mouse::{MouseConfig, SpeedProfile, AxisConfig, JoystickConfig, ScrollConfig, DwellConfig, FlickConfig};
+    use crate::keyboard::{KeyboardConfig, Prescalers, DebounceConfig, AccessibilityConfig};
+    use crate::keyboard::hid::{AutoRepeatConfig, RepeatTiming, ConsumerRepeatConfig};
+    use crate::keyboard::num_lock::NumLockMode;
+    use crate::keyboard::training::TrainingConfig;
+    use crate::keyboard::shortcuts::OsProfile;
     use crate::keyboard::leds::*;
+    use crate::hal_ext::watchdog::{WatchdogConfig, WatchdogKind};
     use crate::bsp::{NCOLS, NROWS};
 
     type Layers = layout::Layers<{ 2 * NCOLS }, NROWS, N_LAYERS, CustomAction>;
@@ -34,8 +76,63 @@ mod code {
         layers: &LAYERS,
         mouse: &MOUSE,
         leds: LEDS,
+        layer_leds: &[],
         timeout: 1000,
         bootload_strict: true,
+        serial_baud_rate: 460_800,
+        tick_frequency_hz: 1000,
+        debounce: DebounceConfig {
+            defer_ms: 5,
+            eager_ms: 5,
+        },
+        accessibility: AccessibilityConfig {
+            slow_keys_ms: 300,
+            bounce_keys_ms: 250,
+        },
+        prescalers: Prescalers {
+            leds: 10,
+            joystick: 10,
+            mouse: 1,
+            debug: 1000,
+        },
+        auto_repeat: AutoRepeatConfig {
+            enabled: false,
+            navigation: RepeatTiming { delay: 250, period: 20 },
+            default: RepeatTiming { delay: 500, period: 33 },
+        },
+        consumer_repeat: ConsumerRepeatConfig {
+            keys: &[],
+            profile: &CONSUMER_REPEAT_PROFILE,
+        },
+        num_lock: NumLockMode::Off,
+        reactive: ReactiveConfig {
+            enabled: false,
+            color: RGB8::new(255, 255, 255),
+            fade: 30,
+        },
+        socd: &[],
+        os_profile: OsProfile::Linux,
+        host_keys: &[],
+        brightness_presets: &[0, 25, 60, 100],
+        led_brightness_limits: &[],
+        suspend_heartbeat: HeartbeatConfig {
+            enabled: true,
+            key: (0, 5),
+            color: RGB8::new(0, 0, 80),
+            period_ms: 4000,
+            duration_ms: 300,
+        },
+        training: TrainingConfig {
+            keys: &Keys::Rows(&[2]),
+            dim_brightness: 30,
+            hint_duration_ms: 1500,
+        },
+        watchdog: WatchdogConfig {
+            enabled: false,
+            kind: WatchdogKind::Window,
+            window_start_ms: 30,
+            window_end_ms: 60,
+        },
     };
 
     const HOLDTAP_TIMEOUT: u16 = 180;
@@ -111,6 +208,8 @@ mod code {
     const L_DOWN: Action = Action::Custom(CustomAction::Led(LedAction::Brightness(Inc::Down)));
 
     pub const N_LAYERS: usize = 5;
+    /// Approximate size of configuration data stored in flash (without nested actions and LED rules)
+    pub const DATA_SIZE: usize = core::mem::size_of::<Layers>();
     const LAYERS: Layers = layout! {
         { // Default
             [ '`'           1 2 3 4 5   6 7 8 9 0   '\\'          ]
@@ -282,6 +381,31 @@ mod code {
             invert_y: true,
             swap_axes: false,
         },
+        precision_scale: 25,
+        angle_snap: 0,
+        speed_tiers: SpeedTiers {
+            slow: &MOUSE_SLOW_PROFILE,
+            medium: &MOUSE_MEDIUM_PROFILE,
+            fast: &MOUSE_FAST_PROFILE,
+        },
+        scroll: ScrollConfig {
+            max_lines: 0,
+            min_interval: 0,
+        },
+        dwell: DwellConfig {
+            delay_ms: 800,
+            indicator: (4, 3),
+        },
+        flick: FlickConfig {
+            threshold: 0,
+            rise_time: 60,
+            max_duration: 250,
+            cooldown: 300,
+            left: None,
+            right: None,
+            up: None,
+            down: None,
+        },
     };
 
     const MOUSE_PROFILE: SpeedProfile = SpeedProfile {
@@ -292,6 +416,38 @@ mod code {
         max_speed: 15000,
     };
 
+    const MOUSE_SLOW_PROFILE: SpeedProfile = SpeedProfile {
+        divider: 10000,
+        delay: 50,
+        acceleration_time: 0,
+        start_speed: 2500,
+        max_speed: 2500,
+    };
+
+    const MOUSE_MEDIUM_PROFILE: SpeedProfile = SpeedProfile {
+        divider: 10000,
+        delay: 50,
+        acceleration_time: 0,
+        start_speed: 7500,
+        max_speed: 7500,
+    };
+
+    const MOUSE_FAST_PROFILE: SpeedProfile = SpeedProfile {
+        divider: 10000,
+        delay: 50,
+        acceleration_time: 0,
+        start_speed: 20000,
+        max_speed: 20000,
+    };
+
+    const CONSUMER_REPEAT_PROFILE: SpeedProfile = SpeedProfile {
+        divider: 1000,
+        delay: 300,
+        acceleration_time: 1500,
+        start_speed: 8,
+        max_speed: 40,
+    };
+
     const WHEEL_PROFILE: SpeedProfile = SpeedProfile {
         divider: 1000,
         delay: 50,
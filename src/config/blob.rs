@@ -0,0 +1,722 @@
+//! Binary configuration interpreter
+//!
+//! Decodes layers, mouse and LED configuration serialized by `ghanima-config` (see its `blob`
+//! module for description of the format), so that these can be changed by appending a blob to
+//! the firmware image instead of recompiling. Other parts of configuration always come from
+//! [`CONFIG`](super::CONFIG), including the number of layers, as keyboard state depends on it.
+//!
+//! Decoded structures are stored in a RAM buffer, as firmware uses `&'static` references to
+//! configuration data. When the blob is missing, invalid or does not fit in the buffer, the
+//! compiled-in configuration is used.
+
+use core::mem::MaybeUninit;
+
+use keyberon::action::{self, HoldTapAction, HoldTapConfig};
+use keyberon::key_code::KeyCode;
+use keyberon::layout;
+use rgb::RGB8;
+
+use crate::bsp::{NCOLS, NROWS};
+use super::N_LAYERS;
+use crate::keyboard::{keymap, KeyboardConfig};
+use crate::keyboard::actions::{Action as CustomAction, LedAction, MouseAction, ShortcutAction};
+use crate::keyboard::actions::{FirmwareAction, Inc, MouseButton, MouseMovement, OsProfile, Shortcut, SpeedTier};
+use crate::keyboard::leds::{Condition, Interpolation, KeyAction, KeyActionCache, KeyboardLed, Keys};
+use crate::keyboard::leds::{LedConfigurations, LedRule, Pattern, Phase, Repeat, Role, Transition, ValueSource};
+use crate::keyboard::mouse::{AxisConfig, DwellConfig, FlickConfig, JoystickConfig, MouseConfig, ScrollConfig, SpeedProfile, SpeedTiers};
+
+/// Bytes `GCFG` as little-endian word
+const MAGIC: u32 = u32::from_le_bytes(*b"GCFG");
+/// Supported version of the format
+const VERSION: u8 = 6;
+/// Magic, version, dimensions, payload length and CRC
+const HEADER_LEN: usize = 16;
+/// RAM budget for data referenced from layers, mouse and LED configuration (hold-taps, lists
+/// of key codes and actions, LED rules), in words as most of it are references
+const NESTED_SIZE: usize = 384 * core::mem::size_of::<usize>();
+/// Size of RAM buffer for decoded configuration
+///
+/// Holds layers of the compiled-in size, key action cache and keyboard configuration, plus
+/// [`NESTED_SIZE`] for the rest, which fits the shipped configuration with a margin for
+/// padding and larger configurations. This is about 5 KiB on target, which is only reserved
+/// with feature `config-blob`, as otherwise [`load`] is never called.
+pub const ARENA_SIZE: usize = core::mem::size_of::<Layers<N_LAYERS>>()
+    + core::mem::size_of::<[KeyActionCache; N_LAYERS]>()
+    + core::mem::size_of::<KeyboardConfig<N_LAYERS>>()
+    + NESTED_SIZE;
+
+// Keyboard state takes up to half of RAM, leave enough for stacks, LED and USB buffers
+#[cfg(target_os = "none")]
+const _: () = assert!(ARENA_SIZE <= crate::bsp::RAM_SIZE / 3,
+    "Configuration blob buffer takes too much RAM, reduce the number of layers");
+
+type Action = action::Action<CustomAction>;
+type Layers<const L: usize> = layout::Layers<{ 2 * NCOLS }, NROWS, L, CustomAction>;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    /// There is no configuration blob
+    Missing,
+    /// Blob uses a different version of the format
+    Version(u8),
+    /// Blob has been generated for a different number of layers or matrix size
+    Dimensions { layers: u8, rows: u8, cols: u8 },
+    /// CRC of payload does not match, e.g. due to incomplete flashing
+    Crc { expected: u32, actual: u32 },
+    /// Data ends in the middle of a value
+    Truncated,
+    /// Unknown enum variant or value out of range at given payload offset
+    Invalid { offset: u32, value: u8 },
+    /// There is data left after decoding all parts of configuration
+    TrailingData,
+    /// Decoded configuration does not fit in the RAM buffer
+    OutOfMemory,
+}
+
+/// Parts of configuration decoded from a blob
+pub struct BlobConfig<const L: usize> {
+    pub layers: &'static Layers<L>,
+    pub mouse: &'static MouseConfig,
+    pub leds: LedConfigurations,
+    pub layer_leds: LedConfigurations,
+}
+
+/// Bump allocator for decoded configuration, which is never freed
+pub struct Arena {
+    ptr: *mut u8,
+    len: usize,
+    used: usize,
+}
+
+impl Arena {
+    pub fn new(buf: &'static mut [MaybeUninit<u8>]) -> Self {
+        Self { ptr: buf.as_mut_ptr().cast(), len: buf.len(), used: 0 }
+    }
+
+    /// Bytes left, not counting padding needed for alignment
+    pub fn free(&self) -> usize {
+        self.len - self.used
+    }
+
+    fn reserve<T>(&mut self, n: usize) -> Result<*mut T, Error> {
+        // SAFETY: `used` never exceeds `len`
+        let start = unsafe { self.ptr.add(self.used) };
+        let padding = start.align_offset(core::mem::align_of::<T>());
+        let size = core::mem::size_of::<T>().checked_mul(n).ok_or(Error::OutOfMemory)?;
+        let used = self.used
+            .checked_add(padding + size)
+            .filter(|used| *used <= self.len)
+            .ok_or(Error::OutOfMemory)?;
+        self.used = used;
+        // SAFETY: in bounds as checked above
+        Ok(unsafe { start.add(padding) }.cast())
+    }
+
+    /// Move `value` to the arena
+    pub fn alloc<T>(&mut self, value: T) -> Result<&'static T, Error> {
+        let ptr = self.reserve::<T>(1)?;
+        // SAFETY: memory is reserved, aligned and never handed out again
+        unsafe {
+            ptr.write(value);
+            Ok(&*ptr)
+        }
+    }
+
+    /// Allocate a slice of `n` elements created by `f`, which may allocate nested data
+    pub fn alloc_slice<T, F>(&mut self, n: usize, mut f: F) -> Result<&'static [T], Error>
+    where
+        F: FnMut(&mut Self) -> Result<T, Error>,
+    {
+        let ptr = self.reserve::<T>(n)?;
+        for i in 0..n {
+            let value = f(self)?;
+            // SAFETY: memory is reserved for `n` elements
+            unsafe { ptr.add(i).write(value) };
+        }
+        // SAFETY: all elements have been initialized
+        Ok(unsafe { core::slice::from_raw_parts(ptr, n) })
+    }
+}
+
+/// Cursor over blob payload
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Number of layers for validation of layer actions
+    layers: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], layers: usize) -> Self {
+        Self { data, pos: 0, layers }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let bytes = self.data.get(self.pos..self.pos + N).ok_or(Error::Truncated)?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        self.take::<1>().map(|[b]| b)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    /// Error for the last byte read
+    fn invalid(&self, value: u8) -> Error {
+        Error::Invalid { offset: self.pos as u32 - 1, value }
+    }
+
+    fn layer(&mut self) -> Result<usize, Error> {
+        let layer = self.u8()?;
+        if (layer as usize) < self.layers {
+            Ok(layer as usize)
+        } else {
+            Err(self.invalid(layer))
+        }
+    }
+}
+
+/// Decode configuration blob storing all data in `arena`
+pub fn decode<const L: usize>(data: &[u8], arena: &mut Arena) -> Result<BlobConfig<L>, Error> {
+    let mut header = Reader::new(data, L);
+    if header.u32().ok() != Some(MAGIC) {
+        return Err(Error::Missing);
+    }
+    let version = header.u8()?;
+    if version != VERSION {
+        return Err(Error::Version(version));
+    }
+    let [layers, rows, cols] = header.take()?;
+    if (layers as usize, rows as usize, cols as usize) != (L, NROWS, 2 * NCOLS) {
+        return Err(Error::Dimensions { layers, rows, cols });
+    }
+    let len = header.u32()? as usize;
+    let expected = header.u32()?;
+    let payload = data.get(HEADER_LEN..HEADER_LEN + len).ok_or(Error::Truncated)?;
+    let actual = crate::bsp::image::crc32(payload);
+    if actual != expected {
+        return Err(Error::Crc { expected, actual });
+    }
+
+    let mut r = Reader::new(payload, L);
+    let actions = arena.alloc_slice(L * NROWS * 2 * NCOLS, |arena| Action::decode(&mut r, arena))?;
+    // SAFETY: nested arrays have the same layout as a slice with all their elements
+    let layers = unsafe { &*(actions.as_ptr() as *const Layers<L>) };
+    let mouse = Decode::decode(&mut r, arena)?;
+    let leds = Decode::decode(&mut r, arena)?;
+    let layer_leds = Decode::decode(&mut r, arena)?;
+    if r.pos != payload.len() {
+        return Err(Error::TrailingData);
+    }
+    Ok(BlobConfig { layers, mouse, leds, layer_leds })
+}
+
+/// Load configuration blob appended to the firmware image
+///
+/// Returns configuration with layers, mouse and LEDs replaced by the ones from the blob and
+/// cache of key actions for the new layers.
+///
+/// # Safety
+///
+/// Must be called only once, as it uses a static buffer.
+pub unsafe fn load<const L: usize>(base: KeyboardConfig<L>) -> Result<(&'static KeyboardConfig<L>, &'static [KeyActionCache; L]), Error> {
+    static mut BUF: [MaybeUninit<u8>; ARENA_SIZE] = [MaybeUninit::uninit(); ARENA_SIZE];
+    let mut arena = Arena::new(&mut *(&raw mut BUF));
+    let loaded = decode_config(crate::bsp::image::appended(), base, &mut arena)?;
+    defmt::info!("Loaded configuration blob, {=usize} B of RAM left", arena.free());
+    Ok(loaded)
+}
+
+/// Decode blob into `base` configuration, allocating everything in `arena`
+fn decode_config<const L: usize>(data: &[u8], base: KeyboardConfig<L>, arena: &mut Arena) -> Result<(&'static KeyboardConfig<L>, &'static [KeyActionCache; L]), Error> {
+    let blob = decode::<L>(data, arena)?;
+    let cache = arena.alloc(KeyActionCache::for_layers(blob.layers))?;
+    let config = arena.alloc(KeyboardConfig {
+        layers: blob.layers,
+        mouse: blob.mouse,
+        leds: blob.leds,
+        layer_leds: blob.layer_leds,
+        ..base
+    })?;
+    Ok((config, cache))
+}
+
+/// Decoding from the binary configuration format
+trait Decode: Sized {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error>;
+}
+
+impl Decode for u8 {
+    fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+        r.u8()
+    }
+}
+
+impl Decode for u16 {
+    fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+        r.u16()
+    }
+}
+
+impl Decode for bool {
+    fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+        match r.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(r.invalid(v)),
+        }
+    }
+}
+
+impl Decode for f32 {
+    fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+        r.u32().map(f32::from_bits)
+    }
+}
+
+impl Decode for (u8, u8) {
+    fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+        Ok((r.u8()?, r.u8()?))
+    }
+}
+
+impl Decode for RGB8 {
+    fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+        Ok(RGB8::new(r.u8()?, r.u8()?, r.u8()?))
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        match r.u8()? {
+            0 => Ok(None),
+            1 => T::decode(r, arena).map(Some),
+            v => Err(r.invalid(v)),
+        }
+    }
+}
+
+impl<T: Decode + 'static> Decode for &'static T {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        let value = T::decode(r, arena)?;
+        arena.alloc(value)
+    }
+}
+
+impl<T: Decode + 'static> Decode for &'static [T] {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        let len = r.u8()?;
+        arena.alloc_slice(len as usize, |arena| T::decode(r, arena))
+    }
+}
+
+/// Implement Decode for enums without data encoded as variant index, variants must be in order
+macro_rules! impl_enum_decode {
+    ( $( $enum:ident { $( $variant:ident ),* $(,)? } )* ) => {
+        $(
+            impl Decode for $enum {
+                fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+                    let index = r.u8()?;
+                    [$( Self::$variant ),*].into_iter()
+                        .nth(index as usize)
+                        .ok_or_else(|| r.invalid(index))
+                }
+            }
+        )*
+    };
+}
+
+/// Implement Decode for structs encoded as their fields in order
+macro_rules! impl_struct_decode {
+    ( $( $struct:ident { $( $field:ident ),* $(,)? } )* ) => {
+        $(
+            impl Decode for $struct {
+                fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+                    Ok(Self { $( $field: Decode::decode(r, arena)? ),* })
+                }
+            }
+        )*
+    };
+}
+
+impl_enum_decode! {
+    HoldTapConfig { Default, HoldOnOtherKeyPress, PermissiveHold }
+    Inc { Up, Down }
+    MouseButton { Left, Mid, Right, Back, Forward, Button6, Button7, Button8 }
+    MouseMovement { Up, Down, Left, Right, WheelUp, WheelDown, PanLeft, PanRight }
+    SpeedTier { Slow, Medium, Fast }
+    FirmwareAction {
+        AllowBootloader, JumpToBootloader, Reboot, InfiniteLoop, TypeInfo, ToggleEventLog, KeyStats,
+        WipeKeyStats, KeyTester, KeyTesterTyping, SwapRole, GameMode, ToggleJoystick, ExplainKey,
+        SlowKeys, BounceKeys, Training,
+    }
+    Shortcut {
+        Copy, Cut, Paste, Undo, Redo, SelectAll, Save, Find, WordLeft, WordRight, LineStart,
+        LineEnd, DeleteWord,
+    }
+    OsProfile { Windows, MacOs, Linux }
+    KeyAction { NoOp, Trans, KeyCode, MultipleKeyCodes, MultipleActions, Layer, DefaultLayer, HoldTap, Custom }
+    KeyboardLed { NumLock, CapsLock, ScrollLock, Compose, Kana }
+    Repeat { Once, Wrap, Reflect }
+    Interpolation { Piecewise, Linear }
+    Role { Master, Slave }
+    ValueSource { Brightness, Layer }
+}
+
+impl_struct_decode! {
+    MouseConfig { x, y, wheel, pan, joystick, precision_scale, angle_snap, speed_tiers, scroll, dwell, flick }
+    AxisConfig { invert, profile }
+    SpeedProfile { divider, delay, acceleration_time, start_speed, max_speed }
+    SpeedTiers { slow, medium, fast }
+    JoystickConfig { min, max, divider, swap_axes, invert_x, invert_y }
+    ScrollConfig { max_lines, min_interval }
+    DwellConfig { delay_ms, indicator }
+    FlickConfig { threshold, rise_time, max_duration, cooldown, left, right, up, down }
+    LedRule { keys, condition, pattern }
+    Pattern { repeat, transitions, phase }
+    Transition { color, duration, interpolation }
+    Phase { x, y }
+}
+
+impl Decode for KeyCode {
+    fn decode(r: &mut Reader, _arena: &mut Arena) -> Result<Self, Error> {
+        let code = r.u8()?;
+        keymap::key_code(code).ok_or_else(|| r.invalid(code))
+    }
+}
+
+impl Decode for HoldTapAction<CustomAction> {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(Self {
+            timeout: Decode::decode(r, arena)?,
+            hold: Decode::decode(r, arena)?,
+            tap: Decode::decode(r, arena)?,
+            config: Decode::decode(r, arena)?,
+            tap_hold_interval: Decode::decode(r, arena)?,
+        })
+    }
+}
+
+impl Decode for Action {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Action::NoOp,
+            1 => Action::Trans,
+            2 => Action::KeyCode(Decode::decode(r, arena)?),
+            3 => Action::MultipleKeyCodes(Decode::decode(r, arena)?),
+            4 => Action::MultipleActions(Decode::decode(r, arena)?),
+            5 => Action::Layer(r.layer()?),
+            6 => Action::DefaultLayer(r.layer()?),
+            7 => Action::HoldTap(Decode::decode(r, arena)?),
+            8 => Action::Custom(Decode::decode(r, arena)?),
+            v => return Err(r.invalid(v)),
+        })
+    }
+}
+
+impl Decode for CustomAction {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => CustomAction::Led(Decode::decode(r, arena)?),
+            1 => CustomAction::Mouse(Decode::decode(r, arena)?),
+            // Consumer keys (2) are not supported by the format
+            3 => CustomAction::Firmware(Decode::decode(r, arena)?),
+            4 => CustomAction::Shortcut(Decode::decode(r, arena)?),
+            v => return Err(r.invalid(v)),
+        })
+    }
+}
+
+impl Decode for LedAction {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => LedAction::Cycle(Decode::decode(r, arena)?),
+            1 => LedAction::Brightness(Decode::decode(r, arena)?),
+            2 => LedAction::ClearOverrides,
+            3 => LedAction::NightMode,
+            4 => LedAction::BrightnessPreset,
+            5 => LedAction::Blackout,
+            6 => LedAction::BrightnessBoost,
+            v => return Err(r.invalid(v)),
+        })
+    }
+}
+
+impl Decode for MouseAction {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => MouseAction::Click(Decode::decode(r, arena)?),
+            1 => MouseAction::Move(Decode::decode(r, arena)?),
+            2 => MouseAction::Sensitivity(Decode::decode(r, arena)?),
+            3 => MouseAction::JoystickSensitivity(Decode::decode(r, arena)?),
+            4 => MouseAction::Precision,
+            5 => MouseAction::DwellClick,
+            6 => MouseAction::SpeedTier(Decode::decode(r, arena)?),
+            7 => MouseAction::JoystickPlane,
+            v => return Err(r.invalid(v)),
+        })
+    }
+}
+
+impl Decode for ShortcutAction {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => ShortcutAction::Key(Decode::decode(r, arena)?),
+            1 => ShortcutAction::Profile(Decode::decode(r, arena)?),
+            2 => ShortcutAction::CycleProfile,
+            v => return Err(r.invalid(v)),
+        })
+    }
+}
+
+impl Decode for Keys {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Keys::Rows(Decode::decode(r, arena)?),
+            1 => Keys::Cols(Decode::decode(r, arena)?),
+            2 => Keys::Keys(Decode::decode(r, arena)?),
+            v => return Err(r.invalid(v)),
+        })
+    }
+}
+
+impl Decode for Condition {
+    fn decode(r: &mut Reader, arena: &mut Arena) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Condition::Always,
+            1 => Condition::Led(Decode::decode(r, arena)?),
+            2 => Condition::UsbOn,
+            3 => Condition::Role(Decode::decode(r, arena)?),
+            4 => Condition::Pressed,
+            5 => Condition::KeyAction(Decode::decode(r, arena)?),
+            6 => Condition::KeyPressed(r.u8()?, r.u8()?),
+            7 => Condition::Layer(r.u8()?),
+            8 => Condition::BootloaderAllowed,
+            9 => Condition::MouseButton(Decode::decode(r, arena)?),
+            10 => Condition::GameMode,
+            11 => Condition::BrightnessPreset(r.u8()?),
+            12 => Condition::JoystickEnabled,
+            13 => Condition::BarGraph(Decode::decode(r, arena)?),
+            14 => Condition::Not(Decode::decode(r, arena)?),
+            15 => Condition::And(Decode::decode(r, arena)?),
+            16 => Condition::Or(Decode::decode(r, arena)?),
+            v => return Err(r.invalid(v)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use crate::bsp::image::crc32;
+    use super::*;
+
+    const KEYS: usize = NROWS * 2 * NCOLS;
+
+    fn arena(size: usize) -> Arena {
+        Arena::new(Box::leak(vec![MaybeUninit::uninit(); size].into_boxed_slice()))
+    }
+
+    fn blob(layers: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(MAGIC.to_le_bytes());
+        data.extend([VERSION, layers, NROWS as u8, 2 * NCOLS as u8]);
+        data.extend((payload.len() as u32).to_le_bytes());
+        data.extend(crc32(payload).to_le_bytes());
+        data.extend(payload);
+        data
+    }
+
+    fn mouse() -> Vec<u8> {
+        // invert, divider 10000, delay 50, acceleration 750, start 5000, max 15000
+        let axis = [0, 0x10, 0x27, 50, 0, 0xee, 0x02, 0x88, 0x13, 0x98, 0x3a];
+        let mut data = [axis; 4].concat();
+        // min 300, max 3200, divider 10, swap axes, invert y
+        data.extend([0x2c, 0x01, 0x80, 0x0c, 10, 0, 1, 0, 1]);
+        // precision scale, angle snap
+        data.extend([25, 18]);
+        // slow, medium and fast speed tiers of constant speed 2500, 7500, 20000
+        for speed in [2500u16, 7500, 20000] {
+            data.extend([0x10, 0x27, 50, 0, 0, 0]);
+            data.extend([speed.to_le_bytes(), speed.to_le_bytes()].concat());
+        }
+        // scroll max 3 lines every 20 ticks, dwell 1000 ms on key 0,5
+        data.extend([3, 20, 0, 0xe8, 0x03, 0, 5]);
+        // flick threshold 2500, rise 60, max 250, cooldown 300, right: [LCtrl, Right], up: Mouse(JoystickPlane)
+        data.extend([0xc4, 0x09, 60, 0, 250, 0, 0x2c, 0x01, 0, 1, 3, 2, 0xe0, 0x4f, 1, 8, 1, 7, 0]);
+        data
+    }
+
+    fn payload() -> Vec<u8> {
+        let mut data = Vec::new();
+        // HoldTap: timeout 200, hold Layer(0), tap Space, HoldOnOtherKeyPress, interval 0
+        data.extend([7, 200, 0, 5, 0, 2, 0x2c, 1, 0, 0]);
+        data.extend([3, 2, 0xe0, 0x06]);
+        data.extend([8, 4, 0, 0]);
+        data.extend([1; KEYS - 3]);
+        data.extend(mouse());
+        // 1 configuration with 1 rule for row 2
+        data.extend([1, 1, 1, 0, 1, 2]);
+        // And(Pressed, Not(Layer(0)))
+        data.extend([15, 2, 4, 14, 7, 0]);
+        // Wrap, red for 500 ms, no phase shift
+        data.extend([1, 1, 255, 0, 0, 0xf4, 0x01, 1]);
+        data.extend([0; 8]);
+        // No rules on layer 0, 1 rule for all keys on layer 1 (Always, Once, no transitions, no phase shift)
+        data.extend([2, 0, 1, 0, 0, 0, 0]);
+        data.extend([0; 8]);
+        data
+    }
+
+    #[test]
+    fn key_code_ranges() {
+        assert_eq!(KeyCode::No as u8, 0x00);
+        assert_eq!(KeyCode::ExSel as u8, 0xa4);
+        assert_eq!(KeyCode::LCtrl as u8, 0xe0);
+        assert_eq!(KeyCode::MediaCalc as u8, 0xfb);
+    }
+
+    #[test]
+    fn decode_config() {
+        let config = decode::<1>(&blob(1, &payload()), &mut arena(ARENA_SIZE)).unwrap();
+        let keys = &config.layers[0];
+        assert!(matches!(keys[0][0], Action::HoldTap(&HoldTapAction {
+            timeout: 200,
+            hold: Action::Layer(0),
+            tap: Action::KeyCode(KeyCode::Space),
+            config: HoldTapConfig::HoldOnOtherKeyPress,
+            tap_hold_interval: 0,
+        })));
+        assert!(matches!(keys[0][1], Action::MultipleKeyCodes(&&[KeyCode::LCtrl, KeyCode::C])));
+        assert!(matches!(keys[0][2], Action::Custom(CustomAction::Shortcut(ShortcutAction::Key(Shortcut::Copy)))));
+        assert!(matches!(keys[NROWS - 1][2 * NCOLS - 1], Action::Trans));
+
+        let mouse = config.mouse;
+        assert_eq!(mouse.pan.profile.max_speed, 15000);
+        assert_eq!((mouse.joystick.min, mouse.joystick.max), (300, 3200));
+        assert!(mouse.joystick.swap_axes && !mouse.joystick.invert_x && mouse.joystick.invert_y);
+        assert_eq!(mouse.angle_snap, 18);
+        assert_eq!(mouse.speed_tiers.medium.max_speed, 7500);
+        assert_eq!(mouse.speed_tiers.fast.acceleration_time, 0);
+        assert_eq!(mouse.scroll.min_interval, 20);
+        assert_eq!((mouse.dwell.delay_ms, mouse.dwell.indicator), (1000, (0, 5)));
+        assert_eq!((mouse.flick.threshold, mouse.flick.cooldown), (2500, 300));
+        assert!(mouse.flick.left.is_none() && mouse.flick.down.is_none());
+        assert!(matches!(mouse.flick.right, Some(Action::MultipleKeyCodes(&&[KeyCode::LCtrl, KeyCode::Right]))));
+        assert!(matches!(mouse.flick.up, Some(Action::Custom(CustomAction::Mouse(MouseAction::JoystickPlane)))));
+
+        assert_eq!(config.leds.len(), 1);
+        let rule = &config.leds[0][0];
+        assert!(matches!(rule.keys, Some(Keys::Rows(&[2]))));
+        assert!(matches!(rule.condition, Condition::And(&[Condition::Pressed, Condition::Not(&Condition::Layer(0))])));
+        assert!(matches!(rule.pattern.repeat, Repeat::Wrap));
+        assert_eq!(rule.pattern.transitions, [
+            Transition { color: RGB8::new(255, 0, 0), duration: 500, interpolation: Interpolation::Linear },
+        ]);
+        assert_eq!(config.layer_leds.len(), 2);
+        assert!(config.layer_leds[0].is_empty());
+        assert!(matches!(config.layer_leds[1][0].condition, Condition::Always));
+    }
+
+    #[test]
+    fn header_errors() {
+        let decode = |data: &[u8]| decode::<1>(data, &mut arena(ARENA_SIZE)).err();
+        let valid = blob(1, &payload());
+        // Erased flash
+        assert_eq!(decode(&[0xff; 32]), Some(Error::Missing));
+        assert_eq!(decode(&[]), Some(Error::Missing));
+        let mut data = valid.clone();
+        data[4] = VERSION + 1;
+        assert_eq!(decode(&data), Some(Error::Version(VERSION + 1)));
+        assert_eq!(decode(&blob(2, &payload())), Some(Error::Dimensions {
+            layers: 2,
+            rows: NROWS as u8,
+            cols: 2 * NCOLS as u8,
+        }));
+        assert_eq!(decode(&valid[..valid.len() - 1]), Some(Error::Truncated));
+        let mut data = valid.clone();
+        *data.last_mut().unwrap() ^= 1;
+        assert!(matches!(decode(&data), Some(Error::Crc { .. })));
+    }
+
+    #[test]
+    fn payload_errors() {
+        let decode = |payload: &[u8]| decode::<1>(&blob(1, payload), &mut arena(ARENA_SIZE)).err();
+        let valid = payload();
+        assert_eq!(decode(&valid[..KEYS]), Some(Error::Truncated));
+        assert_eq!(decode(&[&valid[..], &[0][..]].concat()), Some(Error::TrailingData));
+        // Unknown action, layer out of range, consumer key
+        assert_eq!(decode(&[&[9][..], &valid[1..]].concat()), Some(Error::Invalid { offset: 0, value: 9 }));
+        assert_eq!(decode(&[&[5, 1][..], &valid[1..]].concat()), Some(Error::Invalid { offset: 1, value: 1 }));
+        assert_eq!(decode(&[&[8, 2, 0, 0][..], &valid[1..]].concat()), Some(Error::Invalid { offset: 1, value: 2 }));
+        // Key code between ranges
+        assert_eq!(decode(&[&[2, 0xb0][..], &valid[1..]].concat()), Some(Error::Invalid { offset: 1, value: 0xb0 }));
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let data = blob(1, &payload());
+        assert_eq!(decode::<1>(&data, &mut arena(64)).err(), Some(Error::OutOfMemory));
+    }
+
+    /// Blob generated by `ghanima-config` from the shipped JSON configuration
+    fn shipped_blob() -> (serde_json::Value, Vec<u8>) {
+        /// Consumer keys are not supported by the format
+        fn remove_consumer_keys(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(map) if map.get("Custom").is_some_and(|c| c.get("Consumer").is_some()) => {
+                    *value = serde_json::Value::from("NoOp");
+                },
+                serde_json::Value::Object(map) => map.values_mut().for_each(remove_consumer_keys),
+                serde_json::Value::Array(list) => list.iter_mut().for_each(remove_consumer_keys),
+                _ => {},
+            }
+        }
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/ghanima.json");
+        let mut json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        remove_consumer_keys(&mut json);
+        let mut config: ghanima_config::KeyboardConfig = serde_json::from_value(json.clone()).unwrap();
+        config.migrate().unwrap();
+        config.expand_aliases().unwrap();
+        config.validate().unwrap();
+        (json, config.to_blob().unwrap())
+    }
+
+    #[test]
+    fn shipped_config_round_trip() {
+        let (json, data) = shipped_blob();
+        let mut arena = arena(ARENA_SIZE);
+        let (config, cache) = decode_config::<N_LAYERS>(&data, crate::config::CONFIG, &mut arena).unwrap();
+        assert!(matches!(config.layers[0][0][0], Action::KeyCode(KeyCode::Grave)));
+        assert_eq!(cache, &KeyActionCache::for_layers(config.layers));
+        let joystick = &json["mouse"]["joystick"];
+        assert_eq!(config.mouse.joystick.min as u64, joystick["min"].as_u64().unwrap());
+        assert_eq!(config.mouse.joystick.invert_y, joystick["invert_y"].as_bool().unwrap());
+        assert_eq!(config.leds.len(), json["leds"].as_array().unwrap().len());
+        // Leave some space for configurations larger than the shipped one
+        assert!(arena.free() >= NESTED_SIZE / 4, "{} B left", arena.free());
+    }
+
+    #[test]
+    fn arena_alignment() {
+        let mut arena = arena(12);
+        let byte = arena.alloc(1u8).unwrap();
+        let word = arena.alloc(0x1234_5678u32).unwrap();
+        assert_eq!((*byte, *word), (1, 0x1234_5678));
+        assert_eq!(word as *const u32 as usize % 4, 0);
+        assert!(arena.alloc(0u64).is_err());
+        assert!(arena.alloc_slice(0, |_| Ok(0u32)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use core::ops::{Add, Sub};
+use defmt::Format;
+
+use crate::hal;
+
+/// Timer frequency
+const TICK_HZ: u32 = 1_000_000;
+
+/// Free-running microsecond clock using 32-bit TIM2
+///
+/// Cortex-M0 has no DWT cycle counter, so a general purpose timer is used as the
+/// timestamp source. The counter wraps around after ~71 minutes, which is fine as long as
+/// measured durations are shorter than that.
+pub struct MicrosClock {
+    _tim: hal::pac::TIM2,
+}
+
+/// Point in time with microsecond resolution
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Instant(u32);
+
+/// Time span with microsecond resolution
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Duration(u32);
+
+impl MicrosClock {
+    /// Configure and start the timer
+    pub fn new(tim: hal::pac::TIM2, rcc: &mut hal::rcc::Rcc) -> Self {
+        // Need to access some registers outside of HAL type system (field `regs` is private)
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+
+        rcc_regs.apb1enr.modify(|_, w| w.tim2en().enabled());
+        rcc_regs.apb1rstr.modify(|_, w| w.tim2rst().set_bit());
+        rcc_regs.apb1rstr.modify(|_, w| w.tim2rst().clear_bit());
+
+        // Timer clock is doubled when APB prescaler is not 1
+        let pclk = rcc.clocks.pclk().0;
+        let tclk = if rcc.clocks.hclk().0 == pclk { pclk } else { 2 * pclk };
+        let psc = tclk / TICK_HZ - 1;
+        debug_assert_eq!(tclk % TICK_HZ, 0);
+
+        tim.psc.write(|w| w.psc().bits(psc as u16));
+        tim.arr.write(|w| unsafe { w.bits(u32::MAX) });
+        // Load prescaler value
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.cr1.modify(|_, w| w.cen().enabled());
+
+        Self { _tim: tim }
+    }
+
+    /// Current time
+    pub fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl Instant {
+    /// Current time, always 0 if [`MicrosClock`] has not been created
+    pub fn now() -> Self {
+        let tim = unsafe { &*hal::pac::TIM2::ptr() };
+        Self(tim.cnt.read().bits())
+    }
+
+    /// Time elapsed since `earlier`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration(self.0.wrapping_sub(earlier.0))
+    }
+
+    /// Time elapsed since this instant
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    /// Raw timer ticks
+    pub const fn ticks(&self) -> u32 {
+        self.0
+    }
+
+    /// Create from raw timer ticks
+    pub const fn from_ticks(ticks: u32) -> Self {
+        Self(ticks)
+    }
+}
+
+impl Duration {
+    pub const ZERO: Self = Self(0);
+
+    pub const fn from_micros(us: u32) -> Self {
+        Self(us)
+    }
+
+    pub const fn from_millis(ms: u32) -> Self {
+        Self(ms * 1000)
+    }
+
+    pub const fn as_micros(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn as_millis(&self) -> u32 {
+        self.0 / 1000
+    }
+
+    pub fn saturating_add(self, other: Self) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self(self.0 - rhs.0)
+    }
+}
+
+/// Busy-wait for given duration
+///
+/// Requires [`MicrosClock`] to be running, else it would never return.
+#[inline(always)]
+pub fn delay(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_since() {
+        assert_eq!(Instant(1500).duration_since(Instant(500)), Duration::from_micros(1000));
+        assert_eq!(Instant(1500) - Instant(500), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn duration_since_wrapping() {
+        assert_eq!(Instant(10).duration_since(Instant(u32::MAX - 9)), Duration::from_micros(20));
+        assert_eq!(Instant(u32::MAX - 9) + Duration::from_micros(20), Instant(10));
+    }
+
+    #[test]
+    fn duration_conversions() {
+        assert_eq!(Duration::from_millis(3).as_micros(), 3000);
+        assert_eq!(Duration::from_micros(3999).as_millis(), 3);
+        assert_eq!(Duration::from_micros(u32::MAX).saturating_add(Duration::from_micros(1)).as_micros(), u32::MAX);
+    }
+}
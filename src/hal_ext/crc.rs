@@ -1,34 +1,61 @@
 use crate::hal;
 use super::checksum::ChecksumGen;
 
-#[cfg(not(test))]
+#[cfg(not(any(test, feature = "sim")))]
 pub use hw::Crc;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "sim"))]
 pub use mock::Crc;
 
-#[cfg_attr(test, allow(dead_code))]
+#[cfg_attr(any(test, feature = "sim"), allow(dead_code))]
 mod hw {
     use super::*;
 
-    /// Wrapper around CRC peripheral
+    /// CRC register offsets not available in PAC
+    const INIT_OFFSET: usize = 0x10;
+    const POLY_OFFSET: usize = 0x14;
+
+    /// Initial value of CRC-16/MODBUS
+    const INIT: u16 = 0xffff;
+
+    /// Handle to the shared CRC peripheral
+    ///
+    /// Each handle keeps its own checksum state in software. On every [`ChecksumGen::push`]
+    /// the state is loaded into the peripheral (through the INIT register), data is fed and
+    /// the new state is read back, all in a short critical section. This way handles used
+    /// from tasks of different priorities never interleave their computations and there is
+    /// no need to lock the peripheral for the whole packet serialization.
+    ///
+    /// Output bit reversal is done in software, so that the data register holds the raw
+    /// CRC state that can be written back to INIT.
     pub struct Crc {
-        crc: hal::pac::CRC,
+        state: u16,
     }
 
     impl Crc {
-        /// Configure CRC peripheral
-        pub fn new(crc: hal::pac::CRC, _rcc: &mut hal::rcc::Rcc) -> Self {
+        /// Configure CRC peripheral and get first handle, use [`Crc::handle`] to get more
+        pub fn new(_crc: hal::pac::CRC, _rcc: &mut hal::rcc::Rcc) -> Self {
             // Need to access `.regs` but it's private
             let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
 
             rcc_regs.ahbenr.modify(|_, w| w.crcen().enabled());
 
-            let mut crc = Self { crc };
-            crc.set_variant();
-            crc.crc.cr.modify(|_, w| w.reset().reset());
+            Self::set_variant();
+
+            Self { state: INIT }
+        }
+
+        /// Create another independent handle
+        pub fn handle(&self) -> Self {
+            Self { state: INIT }
+        }
+
+        fn regs() -> &'static hal::pac::crc::RegisterBlock {
+            unsafe { &*hal::pac::CRC::ptr() }
+        }
 
-            crc
+        unsafe fn reg_at(offset: usize) -> *mut u32 {
+            (hal::pac::CRC::ptr() as *const u8).add(offset) as *mut u32
         }
 
         // TODO: type safe variants, something like Crc<Crc32MPEG2>
@@ -47,18 +74,17 @@ mod hw {
         // CRC-16-IBM / CRC-16-ANSI
         // Warning: works only on STM32F07x/STM32F09x
         // see: https://crccalc.com/ (CRC-16/MODBUS)
-        fn set_variant(&mut self) {
-            self.crc.cr.write(|w| {
+        fn set_variant() {
+            // Output reversal done in software, see [`Crc`]
+            Self::regs().cr.write(|w| {
                 w
-                    .rev_out().reversed()
+                    .rev_out().normal()
                     .rev_in().byte()
                     .polysize().polysize16()
             });
             // CRC polynomial register is not available in PAC?
-            let poly_offset = 0x14;
             unsafe {
-                let crc_poly = (hal::pac::CRC::ptr() as *const u8).add(poly_offset) as *mut u32;
-                *crc_poly = 0x8005;
+                Self::reg_at(POLY_OFFSET).write_volatile(0x8005);
             }
         }
     }
@@ -68,34 +94,43 @@ mod hw {
         type Output = u16;
 
         fn reset(&mut self) {
-            self.crc.cr.modify(|_, w| w.reset().reset());
+            self.state = INIT;
         }
 
         fn push(&mut self, data: &[u8]) {
+            let crc = Self::regs();
             let chunks16 = data.chunks_exact(2);
             let tail = chunks16.remainder();
 
-            // Feed most of the buffer as 16-bit values for faster calculation
-            for chunk in chunks16 {
-                let hword = u16::from_be_bytes(chunk.try_into().unwrap());
-                self.crc.dr16().write(|w| w.dr16().bits(hword));
-            }
-
-            // Process the remainder
-            match tail.len() {
-                0 => {},
-                1 => self.crc.dr8().write(|w| w.dr8().bits(tail[0])),
-                _ => unreachable!(),
-            }
+            cortex_m::interrupt::free(|_| {
+                // Restore state of this handle
+                unsafe { Self::reg_at(INIT_OFFSET).write_volatile(self.state as u32) };
+                crc.cr.modify(|_, w| w.reset().reset());
+
+                // Feed most of the buffer as 16-bit values for faster calculation
+                for chunk in chunks16 {
+                    let hword = u16::from_be_bytes(chunk.try_into().unwrap());
+                    crc.dr16().write(|w| w.dr16().bits(hword));
+                }
+
+                // Process the remainder
+                match tail.len() {
+                    0 => {},
+                    1 => crc.dr8().write(|w| w.dr8().bits(tail[0])),
+                    _ => unreachable!(),
+                }
+
+                self.state = crc.dr16().read().bits();
+            });
         }
 
         fn get(&self) -> Self::Output {
-            self.crc.dr16().read().bits()
+            self.state.reverse_bits()
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "sim"))]
 mod mock {
     use super::*;
     use std::vec::Vec;
@@ -110,6 +145,10 @@ mod mock {
         pub fn new_mock() -> Self {
             Self(Vec::new())
         }
+
+        pub fn handle(&self) -> Self {
+            Self::new_mock()
+        }
     }
 
     impl ChecksumGen for Crc {
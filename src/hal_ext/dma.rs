@@ -1,3 +1,5 @@
+use core::sync::atomic;
+
 use crate::hal;
 
 /// Extension trait to split DMA into separate channels
@@ -39,6 +41,140 @@ pub enum InterruptResult {
     Error,
 }
 
+/// Direction of DMA transfer
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Direction {
+    /// Read from peripheral (CPAR) and write to memory (CMAR)
+    FromPeripheral,
+    /// Read from memory (CMAR) and write to peripheral (CPAR)
+    FromMemory,
+    /// Copy from memory (CMAR) to memory (CPAR), started without any peripheral request
+    MemToMem,
+}
+
+/// Size of a single data item transferred
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum WordSize {
+    Bits8 = 0b00,
+    Bits16 = 0b01,
+    Bits32 = 0b10,
+}
+
+/// Channel priority level, used when multiple channels request transfers
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Priority {
+    Low = 0b00,
+    Medium = 0b01,
+    High = 0b10,
+    VeryHigh = 0b11,
+}
+
+/// DMA channel configuration
+///
+/// Describes all the settings of channel configuration register (CCR) except of the
+/// enable bit. Use with [`DmaChannel::configure`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ChannelConfig {
+    pub direction: Direction,
+    pub circular: bool,
+    pub mem_increment: bool,
+    pub periph_increment: bool,
+    pub mem_size: WordSize,
+    pub periph_size: WordSize,
+    pub priority: Priority,
+    pub half_transfer_irq: bool,
+    pub full_transfer_irq: bool,
+    pub error_irq: bool,
+}
+
+impl ChannelConfig {
+    /// Default configuration: byte transfers, memory address increment, transfer complete
+    /// and error interrupts enabled
+    pub const fn new(direction: Direction) -> Self {
+        Self {
+            direction,
+            circular: false,
+            mem_increment: true,
+            periph_increment: matches!(direction, Direction::MemToMem),
+            mem_size: WordSize::Bits8,
+            periph_size: WordSize::Bits8,
+            priority: Priority::Low,
+            half_transfer_irq: false,
+            full_transfer_irq: true,
+            error_irq: true,
+        }
+    }
+
+    pub const fn circular(mut self, circular: bool) -> Self {
+        self.circular = circular;
+        self
+    }
+
+    pub const fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub const fn word_size(mut self, mem: WordSize, periph: WordSize) -> Self {
+        self.mem_size = mem;
+        self.periph_size = periph;
+        self
+    }
+
+    pub const fn interrupts(mut self, half: bool, full: bool, error: bool) -> Self {
+        self.half_transfer_irq = half;
+        self.full_transfer_irq = full;
+        self.error_irq = error;
+        self
+    }
+
+    /// Value of CCR register for this configuration (with EN=0)
+    pub const fn ccr(&self) -> u32 {
+        const fn bit(b: bool, n: u32) -> u32 {
+            (b as u32) << n
+        }
+        let (dir, mem2mem) = match self.direction {
+            Direction::FromPeripheral => (false, false),
+            Direction::FromMemory => (true, false),
+            Direction::MemToMem => (true, true),
+        };
+        bit(self.full_transfer_irq, 1)
+            | bit(self.half_transfer_irq, 2)
+            | bit(self.error_irq, 3)
+            | bit(dir, 4)
+            | bit(self.circular, 5)
+            | bit(self.periph_increment, 6)
+            | bit(self.mem_increment, 7)
+            | (self.periph_size as u32) << 8
+            | (self.mem_size as u32) << 10
+            | (self.priority as u32) << 12
+            | bit(mem2mem, 14)
+    }
+}
+
+/// Get channels that have any interrupt flag set as a bitmask (bit 0 is channel 1)
+///
+/// Useful in handlers of interrupt lines shared by multiple channels, see `DmaChannel::IRQ`.
+pub fn pending_channels() -> u8 {
+    let dma = unsafe { &*hal::pac::DMA1::ptr() };
+    pending_from_isr(dma.isr.read().bits())
+}
+
+fn pending_from_isr(isr: u32) -> u8 {
+    (0..7).fold(0, |acc, ch| acc | ((((isr >> (4 * ch)) & 1) as u8) << ch))
+}
+
+/// Flags of a channel that are set but have their interrupts disabled
+///
+/// TCIE/HTIE/TEIE in CCR use the same bit positions as TCIF/HTIF/TEIF in channel flags.
+fn masked_flags(status: u8, ccr: u32) -> u8 {
+    status & !(ccr as u8) & 0b1110
+}
+
 /// All DMA channels on the MCU
 pub struct Dma {
     pub ch1: DmaChannel<1>,
@@ -133,7 +269,7 @@ impl DmaSplit for hal::pac::DMA1 {
 }
 
 macro_rules! dma_channels {
-    ($($C:literal => $ch:ident),+ $(,)?) => {
+    ($($C:literal => $ch:ident: $irq:ident),+ $(,)?) => {
         $(
             impl DmaChannel<$C> {
                 /// Access DMA register associated with this channel
@@ -145,6 +281,70 @@ macro_rules! dma_channels {
                 const OFFSET: usize = 4 * ($C - 1);
                 const MASK: u32 = 0b1111;
 
+                /// Interrupt line of this channel (may be shared with other channels)
+                pub const IRQ: hal::pac::Interrupt = hal::pac::Interrupt::$irq;
+
+                /// Disable the channel and apply new configuration
+                pub fn configure(&mut self, config: &ChannelConfig) {
+                    self.disable();
+                    self.ch().cr.write(|w| unsafe { w.bits(config.ccr()) });
+                }
+
+                /// Set peripheral address (or destination address in memory-to-memory mode)
+                pub fn set_peripheral_address(&mut self, addr: u32) {
+                    self.ch().par.write(|w| unsafe { w.pa().bits(addr) });
+                }
+
+                /// Set memory address (or source address in memory-to-memory mode)
+                pub fn set_memory_address(&mut self, addr: u32) {
+                    self.ch().mar.write(|w| unsafe { w.ma().bits(addr) });
+                }
+
+                /// Set number of data items to transfer; only valid when channel is disabled
+                pub fn set_transfer_length(&mut self, len: u16) {
+                    self.ch().ndtr.write(|w| w.ndt().bits(len));
+                }
+
+                /// Number of data items remaining to be transferred
+                pub fn remaining(&mut self) -> u16 {
+                    self.ch().ndtr.read().ndt().bits()
+                }
+
+                /// Enable the channel, starting transfers on peripheral requests
+                pub fn enable(&mut self) {
+                    atomic::compiler_fence(atomic::Ordering::Release);
+                    self.ch().cr.modify(|_, w| w.en().enabled());
+                }
+
+                /// Disable the channel
+                pub fn disable(&mut self) {
+                    self.ch().cr.modify(|_, w| w.en().disabled());
+                    atomic::compiler_fence(atomic::Ordering::Acquire);
+                }
+
+                /// Check if the channel is enabled (hardware disables it on transfer error)
+                pub fn is_enabled(&mut self) -> bool {
+                    self.ch().cr.read().en().is_enabled()
+                }
+
+                /// Start memory-to-memory copy of `min(src.len(), dst.len())` bytes
+                ///
+                /// Channel must be configured using [`Direction::MemToMem`] with 8-bit sizes.
+                ///
+                /// # Safety
+                ///
+                /// Both buffers must stay valid and must not be accessed until the transfer
+                /// completes (transfer complete flag or [`Self::remaining`] equal 0).
+                pub unsafe fn start_copy(&mut self, src: &[u8], dst: &mut [u8]) {
+                    let len = src.len().min(dst.len());
+                    debug_assert!(len <= u16::MAX as usize);
+                    self.disable();
+                    self.set_memory_address(src.as_ptr() as u32);
+                    self.set_peripheral_address(dst.as_mut_ptr() as u32);
+                    self.set_transfer_length(len as u16);
+                    self.enable();
+                }
+
                 /// Read interrupt status flags for this channel
                 pub fn isr(&self) -> InterruptStatus {
                     let dma = unsafe { &*hal::pac::DMA1::ptr() };
@@ -163,6 +363,27 @@ macro_rules! dma_channels {
                     let ifcr = f(&mut ifcr);
                     let mask = (ifcr.0 as u32 & Self::MASK) << Self::OFFSET;
                     unsafe { dma.ifcr.write(|w| w.bits(mask)); }
+                    // Read back to make sure that the write has completed, otherwise when called
+                    // at the end of an interrupt handler the interrupt may be re-triggered
+                    // before the flags are actually cleared, resulting in a spurious interrupt.
+                    let _ = dma.isr.read();
+                }
+
+                /// Clear flags of events that have their interrupts disabled
+                ///
+                /// Hardware sets the flags regardless of interrupt enable bits, e.g. half transfer
+                /// flag of a channel that only uses transfer complete interrupt. These never
+                /// trigger the interrupt but keep GIF set, which is confusing when debugging.
+                /// Returns true if any flag has been cleared.
+                pub fn clear_masked(&mut self) -> bool {
+                    let masked = masked_flags(self.isr().0, self.ch().cr.read().bits());
+                    if masked != 0 {
+                        self.ifcr(|w| {
+                            w.0 |= masked;
+                            w
+                        });
+                    }
+                    masked != 0
                 }
 
                 /// Handle transfer completion (or error) interrupt if it occured
@@ -205,13 +426,13 @@ macro_rules! dma_channels {
 }
 
 dma_channels!(
-    1 => ch1,
-    2 => ch2,
-    3 => ch3,
-    4 => ch4,
-    5 => ch5,
-    6 => ch6,
-    7 => ch7,
+    1 => ch1: DMA1_CH1,
+    2 => ch2: DMA1_CH2_3,
+    3 => ch3: DMA1_CH2_3,
+    4 => ch4: DMA1_CH4_5_6_7,
+    5 => ch5: DMA1_CH4_5_6_7,
+    6 => ch6: DMA1_CH4_5_6_7,
+    7 => ch7: DMA1_CH4_5_6_7,
 );
 
 impl InterruptStatus {
@@ -382,6 +603,44 @@ mod tests {
         assert_eq!(DmaChannel::<7>::MASK << DmaChannel::<7>::OFFSET, 0b0000_1111_0000_0000_0000_0000_0000_0000);
     }
 
+    #[test]
+    fn config_ccr() {
+        // Settings previously used by UART TX
+        let cfg = ChannelConfig::new(Direction::FromMemory);
+        assert_eq!(cfg.ccr(), 0b000_0000_1001_1010);
+        // UART RX
+        let cfg = ChannelConfig::new(Direction::FromPeripheral)
+            .circular(true)
+            .priority(Priority::Medium)
+            .interrupts(true, true, true);
+        assert_eq!(cfg.ccr(), 0b001_0000_1010_1110);
+        let cfg = ChannelConfig::new(Direction::MemToMem)
+            .word_size(WordSize::Bits32, WordSize::Bits16)
+            .priority(Priority::VeryHigh)
+            .interrupts(false, false, false);
+        assert_eq!(cfg.ccr(), 0b111_1001_1101_0000);
+    }
+
+    #[test]
+    fn masked_interrupt_flags() {
+        let tx = ChannelConfig::new(Direction::FromMemory).ccr();
+        // Half transfer flag set (with GIF) but only TC/TE interrupts enabled
+        assert_eq!(masked_flags(0b0101, tx), 0b0100);
+        assert_eq!(masked_flags(0b0111, tx), 0b0100);
+        assert_eq!(masked_flags(0b0011, tx), 0);
+        let rx = ChannelConfig::new(Direction::FromPeripheral).interrupts(true, true, true).ccr();
+        assert_eq!(masked_flags(0b1111, rx), 0);
+    }
+
+    #[test]
+    fn pending_channels_from_isr() {
+        assert_eq!(pending_from_isr(0), 0);
+        assert_eq!(pending_from_isr(0b0001), 0b0000_0001);
+        assert_eq!(pending_from_isr(0b1110), 0);
+        assert_eq!(pending_from_isr(0b0001_0000_0000_0001_0000), 0b0001_0010);
+        assert_eq!(pending_from_isr(0x0111_1111), 0b0111_1111);
+    }
+
     #[test]
     fn interrupt_status() {
         assert_eq!(InterruptStatus(0b0000).any(), false);
@@ -0,0 +1,110 @@
+use crate::hal::pac;
+
+/// Size of a flash page, the smallest unit that can be erased
+pub const PAGE_SIZE: usize = 2 * 1024;
+/// Maximum duration of page erase (DS9826, 6.3.10)
+pub const PAGE_ERASE_MAX_MS: u32 = 40;
+
+// Register values (RM0091, 3.5)
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xcdef_89ab;
+const SR_BSY: u32 = 1 << 0;
+const SR_PGERR: u32 = 1 << 2;
+const SR_WRPRTERR: u32 = 1 << 4;
+const SR_EOP: u32 = 1 << 5;
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Error {
+    /// Address not aligned to page (erase) or word (program)
+    Alignment,
+    /// Programming a location that has not been erased
+    Programming,
+    /// Programming a write-protected page
+    WriteProtection,
+}
+
+/// Runtime erasing and programming of flash pages
+///
+/// Code is executed from flash, so the CPU stalls (including interrupts) while flash is busy.
+/// Erasing a page takes up to ~40 ms, so it should be done rarely and outside of timing-critical
+/// code.
+pub struct Flash {
+    flash: pac::FLASH,
+}
+
+impl Flash {
+    pub fn new(flash: pac::FLASH) -> Self {
+        Self { flash }
+    }
+
+    /// Erase page that starts at given address
+    pub fn erase_page(&mut self, address: usize) -> Result<(), Error> {
+        if address % PAGE_SIZE != 0 {
+            return Err(Error::Alignment);
+        }
+        self.unlock();
+        self.set_cr(CR_PER, true);
+        self.flash.ar.write(|w| unsafe { w.bits(address as u32) });
+        self.set_cr(CR_STRT, true);
+        let result = self.wait();
+        self.set_cr(CR_PER, false);
+        self.lock();
+        result
+    }
+
+    /// Program consecutive words starting at given address, the words must be erased
+    pub fn program(&mut self, address: usize, words: impl Iterator<Item = u32>) -> Result<(), Error> {
+        if address % 4 != 0 {
+            return Err(Error::Alignment);
+        }
+        self.unlock();
+        self.set_cr(CR_PG, true);
+        let result = words.enumerate().try_for_each(|(i, word)| {
+            // Flash is programmed in half-words
+            let ptr = (address + 4 * i) as *mut u16;
+            unsafe { core::ptr::write_volatile(ptr, word as u16) };
+            self.wait()?;
+            unsafe { core::ptr::write_volatile(ptr.add(1), (word >> 16) as u16) };
+            self.wait()
+        });
+        self.set_cr(CR_PG, false);
+        self.lock();
+        result
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().bits() & CR_LOCK != 0 {
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.set_cr(CR_LOCK, true);
+    }
+
+    fn set_cr(&mut self, mask: u32, set: bool) {
+        self.flash.cr.modify(|r, w| unsafe {
+            w.bits(if set { r.bits() | mask } else { r.bits() & !mask })
+        });
+    }
+
+    /// Wait until operation finishes, clears status flags
+    fn wait(&self) -> Result<(), Error> {
+        while self.flash.sr.read().bits() & SR_BSY != 0 {}
+        let sr = self.flash.sr.read().bits();
+        // Flags are cleared by writing 1
+        self.flash.sr.write(|w| unsafe { w.bits(SR_EOP | SR_PGERR | SR_WRPRTERR) });
+        if sr & SR_PGERR != 0 {
+            Err(Error::Programming)
+        } else if sr & SR_WRPRTERR != 0 {
+            Err(Error::WriteProtection)
+        } else {
+            Ok(())
+        }
+    }
+}
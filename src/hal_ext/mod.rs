@@ -3,12 +3,18 @@
 //! This module is an extension to [`stm32f0xx_hal`] that covers some more
 //! project-specific hardware - mainly DMA abstractions.
 
+/// Free-running microsecond clock for timestamps
+pub mod clock;
 /// CRC peripheral
 pub mod crc;
 /// DMA HAL for stm32f0
 pub mod dma;
+/// Runtime flash programming
+pub mod flash;
 /// Rebooting to embedded bootloader
 pub mod reboot;
+/// Reset cause decoding and persistent reset counters
+pub mod reset;
 /// TX only SPI with DMA
 pub mod spi;
 /// UART with DMA
@@ -1,23 +1,123 @@
 use core::mem::MaybeUninit;
 use cortex_m::{peripheral::SCB, asm::bootload};
+use defmt::Format;
+use static_assertions as sa;
 use usbd_dfu_rt::DfuRuntimeOps;
 
 use crate::hal::{pac, usb};
+use super::reset;
 
 const MAGIC_JUMP_BOOTLOADER: u32 = 0xdeadbeef;
 const SYSTEM_MEMORY_BASE: u32 = 0x1fffc800;
 
+/// Number of distinct reboot reasons stored in backup register
+const N_STORED: usize = 3;
+/// Marks backup register content as valid reboot counters
+const COUNTERS_MAGIC: u8 = 0x42;
+/// Backup register used for reboot counters, right after reset counters
+const COUNTERS_REGISTER: usize = reset::N_REGISTERS;
+
+sa::const_assert!(COUNTERS_REGISTER < reset::N_BACKUP_REGISTERS);
+
 #[link_section = ".uninit.MAGIC"]
 static mut MAGIC: MaybeUninit<u32> = MaybeUninit::uninit();
 
+/// Reason of an intentional reboot
+#[derive(Clone, Copy, PartialEq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum RebootReason {
+    /// Reboot requested with a key action
+    User = 0,
+    /// Reboot to DFU bootloader, by key action or USB DFU detach request
+    Dfu = 1,
+    /// Reset from panic handler
+    Panic = 2,
+}
+
+/// Number of reboots per reason, persisted in RTC backup register
+///
+/// Software resets cannot be distinguished from reset flags, so the reason is counted
+/// right before reset. Watchdog resets are counted on boot with [`reset::ResetCounters`]
+/// and included here to have a complete picture. Counters saturate at 255.
+#[derive(Clone, Default, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct RebootCounters {
+    counts: [u8; N_STORED],
+    watchdog: u16,
+}
+
+impl RebootReason {
+    pub const ALL: [Self; N_STORED] = [Self::User, Self::Dfu, Self::Panic];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Dfu => "dfu",
+            Self::Panic => "panic",
+        }
+    }
+}
+
+impl RebootCounters {
+    pub fn get(&self, reason: RebootReason) -> u8 {
+        self.counts[reason as usize]
+    }
+
+    /// Number of watchdog resets
+    pub fn watchdog(&self) -> u16 {
+        self.watchdog
+    }
+
+    /// Read counters from backup register
+    pub fn load() -> Self {
+        let reg = unsafe { reset::backup_reg(COUNTERS_REGISTER).read_volatile() };
+        let mut counters = Self::from_register(reg);
+        counters.watchdog = reset::ResetCounters::load().watchdog();
+        counters
+    }
+
+    /// Count a reboot with given reason
+    ///
+    /// Does not require [`reset::BackupAccess`] as it is used right before reset, e.g. from
+    /// panic handler. If backup domain write access has not been enabled, nothing is stored.
+    fn count(reason: RebootReason) {
+        let mut counters = Self::load();
+        let cnt = &mut counters.counts[reason as usize];
+        *cnt = cnt.saturating_add(1);
+        unsafe { reset::backup_reg(COUNTERS_REGISTER).write_volatile(counters.to_register()) };
+    }
+
+    fn from_register(reg: u32) -> Self {
+        let [magic, counts @ ..] = reg.to_le_bytes();
+        let mut counters = Self::default();
+        if magic == COUNTERS_MAGIC {
+            counters.counts = counts;
+        }
+        counters
+    }
+
+    fn to_register(&self) -> u32 {
+        let [a, b, c] = self.counts;
+        u32::from_le_bytes([COUNTERS_MAGIC, a, b, c])
+    }
+}
+
+impl Format for RebootCounters {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "user={=u8} dfu={=u8} panic={=u8} watchdog={=u16}",
+            self.counts[0], self.counts[1], self.counts[2], self.watchdog);
+    }
+}
+
 /// Reboot the MCU
 ///
-/// Triggers system reset. If `bootloader` is true, then a flag will be set
-/// such that after reset, before any code execution we will jump to the embedded
-/// MCU bootloader. Some USB hosts may have problems with enumerating the bootloader
-/// after a reset. If `usb_bus` is passed, then USB reenumeration will be triggered
-/// before system reset, which may prevent the issue.
-pub fn reboot(bootloader: bool, usb_bus: Option<&usb::UsbBusType>) -> ! {
+/// Triggers system reset, counting the reboot `reason`. If `bootloader` is true, then
+/// a flag will be set such that after reset, before any code execution we will jump to
+/// the embedded MCU bootloader. Some USB hosts may have problems with enumerating the
+/// bootloader after a reset. If `usb_bus` is passed, then USB reenumeration will be
+/// triggered before system reset, which may prevent the issue.
+pub fn reboot(reason: RebootReason, bootloader: bool, usb_bus: Option<&usb::UsbBusType>) -> ! {
+    RebootCounters::count(reason);
     if bootloader {
         // SAFETY: we're writing to memory that is reserved for that purpose
         unsafe {
@@ -59,11 +159,19 @@ pub unsafe fn maybe_jump_bootloader() {
 /// Implements switching to USB DFU mode via rebooting to an embedded DFU bootloader
 pub struct DfuBootloader {
     allow: bool,
+    detach: Option<DetachCountdown>,
+}
+
+/// Time until detach after DFU_DETACH request has been accepted, in milliseconds
+#[derive(Clone, Copy)]
+struct DetachCountdown {
+    total: u16,
+    remaining: u16,
 }
 
 impl DfuBootloader {
     pub fn new(allow: bool) -> Self {
-        Self { allow }
+        Self { allow, detach: None }
     }
 
     pub fn set_allowed(&mut self, allowed: bool) {
@@ -74,8 +182,26 @@ impl DfuBootloader {
         self.allow
     }
 
+    /// Advance detach countdown, should be called along with `DfuRuntimeClass::tick`
+    pub fn tick(&mut self, elapsed_ms: u16) {
+        if let Some(detach) = self.detach.as_mut() {
+            detach.remaining = detach.remaining.saturating_sub(elapsed_ms);
+        }
+    }
+
+    /// Fraction of time remaining until detach to bootloader (255 when just armed)
+    ///
+    /// Returns `None` if host has not requested detach.
+    pub fn detach_remaining(&self) -> Option<u8> {
+        self.detach.map(|d| match d.total {
+            0 => 0,
+            total => (d.remaining as u32 * u8::MAX as u32 / total as u32) as u8,
+        })
+    }
+
     pub fn reboot(&mut self, bootloader: bool, usb_bus: Option<&usb::UsbBusType>) {
-        reboot(bootloader, usb_bus)
+        let reason = if bootloader { RebootReason::Dfu } else { RebootReason::User };
+        reboot(reason, bootloader, usb_bus)
     }
 }
 
@@ -83,11 +209,13 @@ impl DfuRuntimeOps for DfuBootloader {
     fn detach(&mut self) {
         // I suspect this works without force_reenumeration because we actually reset
         // the system twice: once on sys_reset, then in jump_bootloader, but not sure.
-        reboot(true, None);
+        reboot(RebootReason::Dfu, true, None);
     }
 
     fn allow(&mut self, timeout: u16) -> Option<u16> {
         if self.allow {
+            // DFU class calls detach after this timeout, show the countdown meanwhile
+            self.detach = Some(DetachCountdown { total: timeout, remaining: timeout });
             Some(timeout)
         } else {
             None
@@ -97,3 +225,46 @@ impl DfuRuntimeOps for DfuBootloader {
     // On Windows USB reset does not work so we must do it manually
     const WILL_DETACH: bool = true;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reason_indices() {
+        for (i, reason) in RebootReason::ALL.iter().enumerate() {
+            assert_eq!(*reason as usize, i);
+        }
+    }
+
+    #[test]
+    fn counters_roundtrip() {
+        let counters = RebootCounters { counts: [1, 2, 255], watchdog: 0 };
+        let reg = counters.to_register();
+        assert_eq!(reg, 0xff02_0142);
+        let loaded = RebootCounters::from_register(reg);
+        assert_eq!(loaded, counters);
+        assert_eq!(loaded.get(RebootReason::Dfu), 2);
+        assert_eq!(loaded.get(RebootReason::Panic), 255);
+    }
+
+    #[test]
+    fn detach_countdown() {
+        let mut dfu = DfuBootloader::new(false);
+        assert_eq!(dfu.allow(1000), None);
+        assert_eq!(dfu.detach_remaining(), None);
+
+        dfu.set_allowed(true);
+        assert_eq!(dfu.allow(1000), Some(1000));
+        assert_eq!(dfu.detach_remaining(), Some(255));
+        dfu.tick(500);
+        assert_eq!(dfu.detach_remaining(), Some(127));
+        dfu.tick(600);
+        assert_eq!(dfu.detach_remaining(), Some(0));
+    }
+
+    #[test]
+    fn counters_invalid_magic() {
+        assert_eq!(RebootCounters::from_register(0xdead_beef), RebootCounters::default());
+    }
+}
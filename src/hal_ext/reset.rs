@@ -0,0 +1,276 @@
+use defmt::Format;
+
+use crate::hal;
+
+/// Number of distinct reset causes
+pub const N_CAUSES: usize = 7;
+
+/// Marks backup registers content as valid reset counters
+const MAGIC: u16 = 0x5243;
+/// Offset of first backup register (RTC_BKP0R), not available in PAC
+const BKP0R_OFFSET: usize = 0x50;
+/// Backup registers used for storing counters (each holds 2 counters, first slot is the magic)
+pub(super) const N_REGISTERS: usize = (N_CAUSES + 1 + 1) / 2;
+/// Number of RTC backup registers on STM32F072
+pub(super) const N_BACKUP_REGISTERS: usize = 5;
+
+/// Reset flags from RCC_CSR
+///
+/// Multiple flags are usually set at once, e.g. any internal reset also pulls NRST low,
+/// so pin reset flag is set together with the actual cause.
+#[derive(Clone, Copy, Default, PartialEq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ResetFlags {
+    pub low_power: bool,
+    pub window_watchdog: bool,
+    pub independent_watchdog: bool,
+    pub software: bool,
+    pub power_on: bool,
+    pub pin: bool,
+    pub option_bytes: bool,
+}
+
+/// Most specific reason of the last reset
+#[derive(Clone, Copy, PartialEq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum ResetCause {
+    PowerOn = 0,
+    Pin = 1,
+    Software = 2,
+    LowPower = 3,
+    WindowWatchdog = 4,
+    IndependentWatchdog = 5,
+    OptionBytes = 6,
+}
+
+/// Number of resets per cause, persisted in RTC backup registers
+///
+/// Backup domain survives system resets, but on this board it is not battery-backed,
+/// so counters are lost on power loss (thus power-on counter is mostly useful to detect
+/// brown-outs while powered).
+#[derive(Clone, Default, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ResetCounters {
+    counts: [u16; N_CAUSES],
+}
+
+impl ResetFlags {
+    /// Read reset flags (does not clear them)
+    pub fn read(_rcc: &mut hal::rcc::Rcc) -> Self {
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+        Self::from_bits(rcc_regs.csr.read().bits())
+    }
+
+    /// Clear all reset flags
+    pub fn clear(_rcc: &mut hal::rcc::Rcc) {
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+        rcc_regs.csr.modify(|_, w| w.rmvf().set_bit());
+    }
+
+    fn from_bits(csr: u32) -> Self {
+        let bit = |n: u32| csr & (1 << n) != 0;
+        Self {
+            option_bytes: bit(25),
+            pin: bit(26),
+            power_on: bit(27),
+            software: bit(28),
+            independent_watchdog: bit(29),
+            window_watchdog: bit(30),
+            low_power: bit(31),
+        }
+    }
+
+    /// Decode the most specific reset cause
+    pub fn cause(&self) -> ResetCause {
+        if self.low_power {
+            ResetCause::LowPower
+        } else if self.window_watchdog {
+            ResetCause::WindowWatchdog
+        } else if self.independent_watchdog {
+            ResetCause::IndependentWatchdog
+        } else if self.software {
+            ResetCause::Software
+        } else if self.option_bytes {
+            ResetCause::OptionBytes
+        } else if self.power_on {
+            ResetCause::PowerOn
+        } else {
+            // Also when no flag is set, which should not happen
+            ResetCause::Pin
+        }
+    }
+}
+
+impl ResetCause {
+    pub const ALL: [Self; N_CAUSES] = [
+        Self::PowerOn,
+        Self::Pin,
+        Self::Software,
+        Self::LowPower,
+        Self::WindowWatchdog,
+        Self::IndependentWatchdog,
+        Self::OptionBytes,
+    ];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::PowerOn => "power-on",
+            Self::Pin => "pin",
+            Self::Software => "software",
+            Self::LowPower => "low-power",
+            Self::WindowWatchdog => "wwdg",
+            Self::IndependentWatchdog => "iwdg",
+            Self::OptionBytes => "option-bytes",
+        }
+    }
+
+    /// Whether this cause indicates a problem
+    pub fn is_abnormal(&self) -> bool {
+        matches!(self, Self::LowPower | Self::WindowWatchdog | Self::IndependentWatchdog)
+    }
+}
+
+impl ResetCounters {
+    pub fn get(&self, cause: ResetCause) -> u16 {
+        self.counts[cause as usize]
+    }
+
+    /// Number of resets by any of the watchdogs
+    pub fn watchdog(&self) -> u16 {
+        self.get(ResetCause::WindowWatchdog).saturating_add(self.get(ResetCause::IndependentWatchdog))
+    }
+
+    pub fn increment(&mut self, cause: ResetCause) {
+        let cnt = &mut self.counts[cause as usize];
+        *cnt = cnt.saturating_add(1);
+    }
+
+    /// Read counters from backup registers, zeros if these do not hold valid data
+    pub fn load() -> Self {
+        let mut regs = [0; N_REGISTERS];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            *reg = unsafe { backup_reg(i).read_volatile() };
+        }
+        Self::from_registers(&regs)
+    }
+
+    /// Write counters to backup registers
+    pub fn store(&self, _access: &BackupAccess) {
+        for (i, reg) in self.to_registers().iter().enumerate() {
+            unsafe { backup_reg(i).write_volatile(*reg) };
+        }
+    }
+
+    fn from_registers(regs: &[u32; N_REGISTERS]) -> Self {
+        let slot = |i: usize| (regs[i / 2] >> (16 * (i % 2))) as u16;
+        let mut counters = Self::default();
+        if slot(0) == MAGIC {
+            for (i, cnt) in counters.counts.iter_mut().enumerate() {
+                *cnt = slot(i + 1);
+            }
+        }
+        counters
+    }
+
+    fn to_registers(&self) -> [u32; N_REGISTERS] {
+        let mut regs = [0; N_REGISTERS];
+        let slots = core::iter::once(MAGIC).chain(self.counts.iter().copied());
+        for (i, value) in slots.enumerate() {
+            regs[i / 2] |= (value as u32) << (16 * (i % 2));
+        }
+        regs
+    }
+}
+
+impl Format for ResetCounters {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "por={=u16} pin={=u16} sw={=u16} lpwr={=u16} wwdg={=u16} iwdg={=u16} obl={=u16}",
+            self.counts[0], self.counts[1], self.counts[2], self.counts[3],
+            self.counts[4], self.counts[5], self.counts[6]);
+    }
+}
+
+/// Token proving that write access to backup domain has been enabled
+pub struct BackupAccess(());
+
+impl BackupAccess {
+    /// Disable backup domain write protection
+    pub fn enable(_pwr: &mut hal::pac::PWR, _rcc: &mut hal::rcc::Rcc) -> Self {
+        // Need to access some registers outside of HAL type system
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+        let pwr_regs = unsafe { &*hal::pac::PWR::ptr() };
+
+        rcc_regs.apb1enr.modify(|_, w| w.pwren().enabled());
+        pwr_regs.cr.modify(|_, w| w.dbp().set_bit());
+
+        Self(())
+    }
+}
+
+pub(super) unsafe fn backup_reg(i: usize) -> *mut u32 {
+    (hal::pac::RTC::ptr() as *const u8).add(BKP0R_OFFSET + 4 * i) as *mut u32
+}
+
+/// Decode reset flags, clear them and update persistent counters
+pub fn on_boot(access: &BackupAccess, rcc: &mut hal::rcc::Rcc) -> (ResetFlags, ResetCounters) {
+    let flags = ResetFlags::read(rcc);
+    ResetFlags::clear(rcc);
+    let mut counters = ResetCounters::load();
+    counters.increment(flags.cause());
+    counters.store(access);
+    (flags, counters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_flags() {
+        // Power-on sets both POR and PIN flags
+        let flags = ResetFlags::from_bits((1 << 27) | (1 << 26) | (1 << 24));
+        assert!(flags.power_on && flags.pin && !flags.software);
+        assert_eq!(flags.cause(), ResetCause::PowerOn);
+        assert_eq!(ResetFlags::from_bits(1 << 26).cause(), ResetCause::Pin);
+        assert_eq!(ResetFlags::from_bits((1 << 28) | (1 << 26)).cause(), ResetCause::Software);
+        assert_eq!(ResetFlags::from_bits((1 << 30) | (1 << 26)).cause(), ResetCause::WindowWatchdog);
+        assert_eq!(ResetFlags::from_bits(1 << 29).cause(), ResetCause::IndependentWatchdog);
+        assert_eq!(ResetFlags::from_bits(1 << 31).cause(), ResetCause::LowPower);
+        assert_eq!(ResetFlags::from_bits(0).cause(), ResetCause::Pin);
+    }
+
+    #[test]
+    fn cause_indices() {
+        for (i, cause) in ResetCause::ALL.iter().enumerate() {
+            assert_eq!(*cause as usize, i);
+        }
+    }
+
+    #[test]
+    fn counters_roundtrip() {
+        let mut counters = ResetCounters::default();
+        counters.increment(ResetCause::Software);
+        counters.increment(ResetCause::Software);
+        counters.increment(ResetCause::OptionBytes);
+        let regs = counters.to_registers();
+        assert_eq!(regs[0], MAGIC as u32);
+        let loaded = ResetCounters::from_registers(&regs);
+        assert_eq!(loaded, counters);
+        assert_eq!(loaded.get(ResetCause::Software), 2);
+        assert_eq!(loaded.get(ResetCause::OptionBytes), 1);
+        assert_eq!(loaded.get(ResetCause::PowerOn), 0);
+    }
+
+    #[test]
+    fn counters_invalid_magic() {
+        let regs = [0xdead_beef; N_REGISTERS];
+        assert_eq!(ResetCounters::from_registers(&regs), ResetCounters::default());
+    }
+
+    #[test]
+    fn counters_saturate() {
+        let mut counters = ResetCounters { counts: [u16::MAX; N_CAUSES] };
+        counters.increment(ResetCause::Pin);
+        assert_eq!(counters.get(ResetCause::Pin), u16::MAX);
+    }
+}
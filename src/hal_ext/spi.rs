@@ -1,5 +1,4 @@
-use core::{sync::atomic, convert::Infallible};
-use embedded_dma::ReadBuffer;
+use core::convert::Infallible;
 
 use crate::hal;
 use crate::utils::InfallibleResult;
@@ -7,15 +6,44 @@ use super::dma;
 
 type DmaChannel = dma::DmaChannel<5>;
 
+// Circular mode with interrupts on both halves of the buffer
+const DMA_CONFIG: dma::ChannelConfig = dma::ChannelConfig::new(dma::Direction::FromMemory)
+    .priority(dma::Priority::High)
+    .circular(true)
+    .interrupts(true, true, true);
+
+/// Half of the ping-pong buffer
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Half {
+    First,
+    Second,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// No transfer ongoing
+    Ready,
+    /// Data source has not been exhausted yet
+    Streaming,
+    /// Data source has ended in given half, stop after it has been transmitted
+    Draining(Half),
+}
+
 /// TX only, asynchronious SPI implementation
 ///
 /// Implementation that uses SPI2 to just send arbitrary data.
 /// MISO/SCK pins are not used.
+///
+/// Data is streamed through a small ping-pong buffer: DMA runs in circular mode and when
+/// one half has been transmitted it is refilled from the data source while the other half
+/// is being transmitted. Unused space at the end of stream is filled with zeros.
 pub struct SpiTx {
     spi: hal::pac::SPI2,
     dma: DmaChannel,
     buf: &'static mut [u8],
-    ready: bool,
+    state: State,
+    baudrate: u8,
+    errors: u32,
 }
 
 impl SpiTx {
@@ -32,28 +60,36 @@ impl SpiTx {
         MOSIPIN: hal::spi::MosiPin<hal::pac::SPI2>,
         F: Into<hal::time::Hertz>
     {
+        assert!(buf.len() % 2 == 0 && buf.len() <= u16::MAX as usize, "Invalid ping-pong buffer size");
+
         // Need to access some registers outside of HAL type system (field `regs` is private)
         let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
 
-        // Enable SPI clock & reset it
+        // Enable SPI & DMA clocks
         rcc_regs.apb1enr.modify(|_, w| w.spi2en().enabled());
-        rcc_regs.apb1rstr.modify(|_, w| w.spi2rst().set_bit());
-        rcc_regs.apb1rstr.modify(|_, w| w.spi2rst().clear_bit());
-
-        // Enable DMA clock
         rcc_regs.ahbenr.modify(|_, w| w.dmaen().enabled());
 
-        let mut s = Self { spi, dma, buf, ready: true };
+        // Calculate baud rate
+        let baudrate = Self::get_baudrate_divisor(rcc.clocks.pclk().0, freq.into().0);
 
-        // Disable SPI & DMA
-        s.spi.cr1.modify(|_, w| w.spe().disabled());
-        s.dma.ch().cr.modify(|_, w| w.en().disabled());
+        let mut s = Self { spi, dma, buf, state: State::Ready, baudrate, errors: 0 };
+        s.setup();
+        s
+    }
 
-        // Calculate baud rate
-        let br = Self::get_baudrate_divisor(rcc.clocks.pclk().0, freq.into().0);
+    /// Reset SPI peripheral and (re)configure SPI and DMA
+    fn setup(&mut self) {
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+        rcc_regs.apb1rstr.modify(|_, w| w.spi2rst().set_bit());
+        rcc_regs.apb1rstr.modify(|_, w| w.spi2rst().clear_bit());
+
+        // Disable SPI & DMA
+        self.spi.cr1.modify(|_, w| w.spe().disabled());
+        self.dma.disable();
 
         // Ignore CPHA/CPOL as we don't even use clock
-        s.spi.cr1.write(|w|  {
+        let br = self.baudrate;
+        self.spi.cr1.write(|w|  {
             w
                 .br().bits(br)
                 .lsbfirst().msbfirst()
@@ -68,7 +104,7 @@ impl SpiTx {
                 .rxonly().full_duplex()
         });
 
-        s.spi.cr2.write(|w| {
+        self.spi.cr2.write(|w| {
             w
                 .ssoe().disabled()
                 // TODO: 16-bit could potentially be faster (less memory operations), with dma 16->16
@@ -77,25 +113,29 @@ impl SpiTx {
                 .txdmaen().disabled()  // enabled later to trigger transfer
         });
 
-        s.dma.ch().cr.write(|w| {
-            w
-                .dir().from_memory()
-                .mem2mem().disabled()
-                .circ().disabled()
-                .minc().enabled()
-                .pinc().disabled()
-                .msize().bits8()
-                .psize().bits8()
-                .pl().high()
-                .htie().disabled()
-                .teie().enabled()
-                .tcie().enabled()
-        });
+        self.dma.configure(&DMA_CONFIG);
+        // Drop any flags left from an aborted transfer, so that they do not trigger interrupts
+        self.dma.ifcr(|w| w.all());
 
-        s.spi.cr1.modify(|_, w| w.spe().enabled());
+        self.spi.cr1.modify(|_, w| w.spe().enabled());
 
         // Do NOT enable SPI (see RM0091; SPI functional description; Communication using DMA)
-        s
+    }
+
+    /// Recover from DMA transfer error
+    ///
+    /// Resets SPI peripheral and reconfigures DMA channel (re-enabling its interrupts, which
+    /// are disabled on error). Current frame is dropped, so the next one can be started.
+    pub fn recover(&mut self) {
+        self.spi.cr2.modify(|_, w| w.txdmaen().disabled());
+        self.setup();
+        self.state = State::Ready;
+        self.errors = self.errors.saturating_add(1);
+    }
+
+    /// Number of DMA transfer errors since startup
+    pub fn errors(&self) -> u32 {
+        self.errors
     }
 
     fn get_baudrate_divisor(pclk: u32, freq: u32) -> u8 {
@@ -124,39 +164,20 @@ impl SpiTx {
         }
     }
 
-    fn configure_dma_transfer(&mut self, len: usize) {
-        let src = self.buf.as_ptr();
-        let dst = self.spi.dr.as_ptr() as u32;
-        self.dma.ch().mar.write(|w| unsafe { w.ma().bits(src as u32) });
-        self.dma.ch().par.write(|w| unsafe { w.pa().bits(dst) });
-        self.dma.ch().ndtr.write(|w| w.ndt().bits(len as u16));
-    }
-
-    fn len(&mut self) -> u16 {
-        self.dma.ch().ndtr.read().ndt().bits()
-    }
-}
-
-impl dma::DmaTx for SpiTx {
-    fn capacity(&self) -> usize {
-        let (_, len) = unsafe { self.buf.read_buffer() };
-        len
-    }
-
-    fn is_ready(&self) -> bool {
-        self.ready
-    }
-
-    fn push<F: FnOnce(&mut [u8]) -> usize>(&mut self, writer: F) -> Result<(), dma::TransferOngoing> {
-        if !self.is_ready() {
-            return Err(dma::TransferOngoing);
-        }
-        let len = writer(self.buf);
-        self.configure_dma_transfer(len);
-        Ok(())
+    /// Check if the previous transfer has finished
+    pub fn is_ready(&self) -> bool {
+        self.state == State::Ready
     }
 
-    fn start(&mut self) -> nb::Result<(), dma::TransferOngoing> {
+    /// Start streaming data from `source`
+    ///
+    /// The `source` is called to fill the buffer with data and should return the number
+    /// of bytes written. Returning less than the length of the buffer ends the stream.
+    /// The same source must then be passed to [`Self::on_interrupt`] until the transfer ends.
+    ///
+    /// If the previous transfer is not complete returns [`dma::TransferOngoing`], if it is
+    /// complete but SPI still transmits the last data returns [`nb::Error::WouldBlock`].
+    pub fn start<F: FnMut(&mut [u8]) -> usize>(&mut self, mut source: F) -> nb::Result<(), dma::TransferOngoing> {
         if !self.is_ready() {
             return Err(nb::Error::Other(dma::TransferOngoing));
         }
@@ -170,43 +191,84 @@ impl dma::DmaTx for SpiTx {
             Ok(()) => {},
         };
 
-        // Copy new data
-        if self.len() == 0 {
+        self.state = State::Streaming;
+        if self.fill(Half::First, &mut source) == 0 {
+            // Nothing to send
+            self.state = State::Ready;
             return Ok(());
         }
+        self.fill(Half::Second, &mut source);
 
-        self.ready = false;
-
-        // "Preceding reads and writes cannot be moved past subsequent writes"
-        atomic::compiler_fence(atomic::Ordering::Release);
-
-        // reload buffer length
-        let (_, len) = unsafe { self.buf.read_buffer() };
-        self.dma.ch().ndtr.write(|w| w.ndt().bits(len as u16));
+        let src = self.buf.as_ptr() as u32;
+        let dst = self.spi.dr.as_ptr() as u32;
+        self.dma.set_memory_address(src);
+        self.dma.set_peripheral_address(dst);
+        self.dma.set_transfer_length(self.buf.len() as u16);
 
-        // Enable channel, then trigger DMA request
-        self.dma.ch().cr.modify(|_, w| w.en().enabled());
+        // Enable channel (with release fence), then trigger DMA request
+        self.dma.enable();
         self.spi.cr2.modify(|_, w| w.txdmaen().enabled());
 
         Ok(())
     }
 
-    fn on_interrupt(&mut self) -> dma::InterruptResult {
-        let res = self.dma.handle_interrupt(dma::Interrupt::FullTransfer);
-        if let Some(status) = res.as_option() {
-            // Disable DMA request and channel
-            self.spi.cr2.modify(|_, w| w.txdmaen().disabled());
-            self.dma.ch().cr.modify(|_, w| w.en().disabled());
-
-            // "Subsequent reads and writes cannot be moved ahead of preceding reads"
-            atomic::compiler_fence(atomic::Ordering::Acquire);
+    /// Handle DMA half/full transfer interrupts, refilling the transmitted half from `source`
+    ///
+    /// The return value has the same meaning as in [`dma::DmaChannel::handle_interrupt`].
+    pub fn on_interrupt<F: FnMut(&mut [u8]) -> usize>(&mut self, mut source: F) -> dma::InterruptResult {
+        // Transfer error (TEIF) disables the channel. Check it before half/full transfer flags,
+        // as these may be set as well but the stream cannot continue, caller should recover.
+        if self.dma.isr().error() {
+            self.dma.ifcr(|w| w.all());
+            self.stop();
+            return dma::InterruptResult::Error;
+        }
 
-            if status.is_ok() {
-                assert!(!self.ready, "Transfer completion but transfer have not been started");
-                self.ready = true;
+        let mut result = dma::InterruptResult::NotSet;
+        for (interrupt, half) in [(dma::Interrupt::HalfTransfer, Half::First), (dma::Interrupt::FullTransfer, Half::Second)] {
+            match self.dma.handle_interrupt(interrupt) {
+                dma::InterruptResult::NotSet => {},
+                dma::InterruptResult::Error => {
+                    self.stop();
+                    return dma::InterruptResult::Error;
+                },
+                dma::InterruptResult::Done => {
+                    result = dma::InterruptResult::Done;
+                    match self.state {
+                        State::Ready => {},
+                        State::Draining(last) if last == half => self.stop(),
+                        // This half has been transmitted, DMA now reads the other one
+                        _ => {
+                            self.fill(half, &mut source);
+                        },
+                    }
+                },
             }
         }
-        res
+        result
+    }
+
+    /// Fill given half of the buffer from source, padding with zeros
+    fn fill<F: FnMut(&mut [u8]) -> usize>(&mut self, half: Half, source: &mut F) -> usize {
+        let mid = self.buf.len() / 2;
+        let (first, second) = self.buf.split_at_mut(mid);
+        let chunk = match half {
+            Half::First => first,
+            Half::Second => second,
+        };
+        let len = if self.state == State::Streaming { source(chunk) } else { 0 };
+        chunk[len..].fill(0);
+        if self.state == State::Streaming && len < chunk.len() {
+            self.state = State::Draining(half);
+        }
+        len
+    }
+
+    fn stop(&mut self) {
+        // Disable DMA request and channel
+        self.spi.cr2.modify(|_, w| w.txdmaen().disabled());
+        self.dma.disable();
+        self.state = State::Ready;
     }
 }
 
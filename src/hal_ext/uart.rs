@@ -1,9 +1,10 @@
 use core::convert::Infallible;
 use core::sync::atomic;
+use defmt::Format;
 use embedded_dma::WriteBuffer;
 use bbqueue::{Producer, Consumer, BBBuffer, GrantR};
 
-use crate::utils::InfallibleResult;
+use crate::utils::{InfallibleResult, Watermark};
 use crate::hal;
 use hal::gpio;
 use super::circ_buf::CircularBuffer;
@@ -36,6 +37,8 @@ pub struct Tx<const N: usize> {
     dma: TxDma,
     consumer: Consumer<'static, N>,
     transfer: Option<GrantR<'static, N>>,
+    pclk: u32,
+    watermark: Watermark,
 }
 
 /// DMA UART RX half
@@ -58,6 +61,32 @@ pub struct Rx<const N: usize, BUF> {
     dma: RxDma,
     producer: Producer<'static, N>,
     buf: CircularBuffer<BUF>,
+    errors: LineErrors,
+}
+
+/// Counters of errors detected by UART hardware during reception
+#[derive(Format, Default, Clone, PartialEq)]
+pub struct LineErrors {
+    pub framing: u32,
+    pub noise: u32,
+    pub parity: u32,
+    pub overrun: u32,
+    /// Bytes lost due to intermediate buffer being overwritten or main queue being full
+    pub dropped_bytes: u32,
+    /// DMA interrupts of UART channels without any enabled event to handle
+    pub spurious_dma: u32,
+}
+
+impl LineErrors {
+    /// Add counters from other
+    pub fn accumulate(&mut self, other: &LineErrors) {
+        self.framing = self.framing.saturating_add(other.framing);
+        self.noise = self.noise.saturating_add(other.noise);
+        self.parity = self.parity.saturating_add(other.parity);
+        self.overrun = self.overrun.saturating_add(other.overrun);
+        self.dropped_bytes = self.dropped_bytes.saturating_add(other.dropped_bytes);
+        self.spurious_dma = self.spurious_dma.saturating_add(other.spurious_dma);
+    }
 }
 
 #[allow(dead_code)]
@@ -90,14 +119,14 @@ where
         rcc_regs.apb2rstr.modify(|_, w| w.usart1rst().clear_bit());
 
         // Calculate baudrate divisor
-        let brr = rcc.clocks.pclk().0 / baud_rate.0;
-        uart.brr.write(|w| unsafe { w.bits(brr) });
+        let pclk = rcc.clocks.pclk().0;
+        uart.brr.write(|w| unsafe { w.bits(pclk / baud_rate.0) });
 
         // Common UART configuration - mostly defaults (CR1/2/3 reset via APB2RSTR)
         // TX/RX-specific configuration in respective constructors
         uart.cr1.write(|w| w.ue().enabled());
 
-        let (tx, tx_queue) = Tx::new(tx, tx_dma, tx_buf);
+        let (tx, tx_queue) = Tx::new(tx, tx_dma, tx_buf, pclk);
         let (rx, rx_queue) = Rx::new(rx, rx_dma, rx_bbbuf, rx_buf);
         Self { tx, tx_queue, rx, rx_queue }
     }
@@ -109,39 +138,25 @@ where
 }
 
 impl<const N: usize> Tx<N> {
-    fn new(_pin: TxPin, mut dma: TxDma, buf: &'static BBBuffer<N>) -> (Self, Producer<'static, N>) {
+    fn new(_pin: TxPin, mut dma: TxDma, buf: &'static BBBuffer<N>, pclk: u32) -> (Self, Producer<'static, N>) {
         let (producer, consumer) = buf.try_split().unwrap();
 
         // Configure DMA
-        dma.ch().cr.write(|w| {
-            w
-                .dir().from_memory()
-                .mem2mem().disabled()
-                .circ().disabled()
-                .minc().enabled()
-                .pinc().disabled()
-                .msize().bits8()
-                .psize().bits8()
-                .pl().low()
-                .htie().disabled()
-                .tcie().enabled()
-                .teie().enabled()
-        });
-
+        dma.configure(&dma::ChannelConfig::new(dma::Direction::FromMemory));
 
         // Enable UART. This will send an Idle Frame as first transmission, but
         // we no need to wait as we check transfer complete in transmit() anyway.
         Self::uart().cr1.modify(|_, w| w.te().enabled());
 
-        (Self { dma, consumer, transfer: None }, producer)
+        (Self { dma, consumer, transfer: None, pclk, watermark: Watermark::new(N) }, producer)
     }
 
     fn configure_dma_transfer(&mut self, buf: &'static [u8]) {
         let src = buf.as_ptr();
         let dst = Self::uart().tdr.as_ptr() as u32;
-        self.dma.ch().mar.write(|w| unsafe { w.ma().bits(src as u32) });
-        self.dma.ch().par.write(|w| unsafe { w.pa().bits(dst) });
-        self.dma.ch().ndtr.write(|w| w.ndt().bits(buf.len() as u16));
+        self.dma.set_memory_address(src as u32);
+        self.dma.set_peripheral_address(dst);
+        self.dma.set_transfer_length(buf.len() as u16);
     }
 
     fn uart() -> &'static UartRegisterBlock {
@@ -157,10 +172,8 @@ impl<const N: usize> Tx<N> {
             return Err(nb::Error::WouldBlock);
         }
 
-        atomic::compiler_fence(atomic::Ordering::Release);
-
         // Enable DMA channel and trigger DMA TX request
-        self.dma.ch().cr.modify(|_, w| w.en().enabled());
+        self.dma.enable();
         Self::uart().cr3.modify(|_, w| w.dmat().enabled());
 
         Ok(())
@@ -169,18 +182,26 @@ impl<const N: usize> Tx<N> {
     fn stop_dma(&mut self) {
         // Disable DMA request and channel
         Self::uart().cr3.modify(|_, w| w.dmat().disabled());
-        self.dma.ch().cr.modify(|_, w| w.en().disabled());
-
-        atomic::compiler_fence(atomic::Ordering::Acquire);
+        self.dma.disable();
     }
 
     /// Start next transfer if there is data available. This may block until UART transmission
     /// complete flag is set.
     pub fn tick(&mut self) -> bool {
+        self.start_transfer(0)
+    }
+
+    /// Start next transfer, `released` is the length of the just finished one, which has
+    /// been in the queue together with the data that is waiting now
+    fn start_transfer(&mut self, released: usize) -> bool {
         if self.transfer.is_some() {
             return false;
         }
 
+        // Split grant is needed to include data wrapping around the buffer end
+        let waiting = self.consumer.split_read().map_or(0, |grant| grant.combined_len());
+        self.watermark.update(released + waiting);
+
         let grant = match self.consumer.read() {
             Ok(grant) => grant,
             Err(e) => match e {
@@ -198,26 +219,67 @@ impl<const N: usize> Tx<N> {
         true
     }
 
+    /// Maximum number of bytes in the queue since creation or the last [`Self::reset_watermark`]
+    pub fn watermark(&self) -> &Watermark {
+        &self.watermark
+    }
+
+    pub fn reset_watermark(&mut self) {
+        self.watermark.reset();
+    }
+
+    /// Change baud rate of the whole UART (both TX and RX)
+    ///
+    /// Returns WouldBlock if there is a transmission in progress. Any data being received
+    /// during the change will most likely be lost.
+    pub fn set_baud_rate(&mut self, baud_rate: hal::time::Bps) -> nb::Result<(), Infallible> {
+        let uart = Self::uart();
+        if self.transfer.is_some() || uart.isr.read().tc().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // BRR can only be written when UART is disabled, TE/RE will be preserved
+        uart.cr1.modify(|_, w| w.ue().disabled());
+        uart.brr.write(|w| unsafe { w.bits(self.pclk / baud_rate.0) });
+        uart.cr1.modify(|_, w| w.ue().enabled());
+
+        Ok(())
+    }
+
     pub fn on_dma_interrupt(&mut self) -> dma::InterruptResult {
         let res = self.dma.handle_interrupt(dma::Interrupt::FullTransfer);
         if let Some(status) = res.as_option() {
             self.stop_dma();
+            // Half transfer interrupt is not used but its flag is set during each transfer
+            self.dma.clear_masked();
 
             if status.is_ok() {
-                if let Some(grant) = self.transfer.take() {
+                let len = if let Some(grant) = self.transfer.take() {
                     let len = grant.len();
                     grant.release(len);
+                    len
                 } else {
                     unreachable!("Transfer completion but transfer have not been started");
-                }
+                };
 
-                self.tick();
+                self.start_transfer(len);
             }
         }
         res
     }
 }
 
+impl<const N: usize> Tx<N> {
+    /// Recover from a spurious DMA interrupt
+    ///
+    /// Makes sure that the half transfer interrupt is disabled and clears flags of disabled
+    /// interrupts. Returns true if any flag had to be cleared.
+    pub fn recover_dma(&mut self) -> bool {
+        self.dma.ch().cr.modify(|_, w| w.htie().disabled());
+        self.dma.clear_masked()
+    }
+}
+
 // impl dma::DmaTx for Tx {
 //     fn capacity(&self) -> usize {
 //         self.buf.len()
@@ -299,40 +361,29 @@ where
 
         let uart = Self::uart();
 
-        // Enable UART RX half with idle interrupt
-        uart.cr1.modify(|_, w| w.idleie().enabled().re().enabled());
-        uart.cr3.modify(|_, w| w.dmar().enabled());
+        // Enable UART RX half with idle and error interrupts
+        uart.cr1.modify(|_, w| w.idleie().enabled().peie().enabled().re().enabled());
+        uart.cr3.modify(|_, w| w.dmar().enabled().eie().enabled());
 
         // Configure DMA
-        dma.ch().cr.write(|w| {
-            w
-                .dir().from_peripheral()
-                .mem2mem().disabled()
-                .circ().enabled()
-                .minc().enabled()
-                .pinc().disabled()
-                .msize().bits8()
-                .psize().bits8()
-                .pl().medium()
-                .htie().enabled()
-                .tcie().enabled()
-                .teie().enabled()
-        });
+        dma.configure(&dma::ChannelConfig::new(dma::Direction::FromPeripheral)
+            .circular(true)
+            .priority(dma::Priority::Medium)
+            .interrupts(true, true, true));
 
         let mut buf = CircularBuffer::new(buf);
 
         // Configure circular DMA data transfers to the intermediate buffer
         let src = uart.rdr.as_ptr() as u32;
         let (dst, len) = unsafe { buf.write_buffer() };
-        dma.ch().par.write(|w| unsafe { w.pa().bits(src) });
-        dma.ch().mar.write(|w| unsafe { w.ma().bits(dst as u32) });
-        dma.ch().ndtr.write(|w| w.ndt().bits(len as u16));
+        dma.set_peripheral_address(src);
+        dma.set_memory_address(dst as u32);
+        dma.set_transfer_length(len as u16);
 
         // Start reception
-        atomic::compiler_fence(atomic::Ordering::Release);
-        dma.ch().cr.modify(|_, w| w.en().enabled());
+        dma.enable();
 
-        let rx = Self { dma, producer, buf };
+        let rx = Self { dma, producer, buf, errors: Default::default() };
         (rx, consumer)
     }
 
@@ -342,7 +393,7 @@ where
 
     fn tail(&mut self) -> u16 {
         let buf_len = unsafe { self.buf.write_buffer().1 as u16 };
-        let remaining = self.dma.ch().ndtr.read().ndt().bits();
+        let remaining = self.dma.remaining();
         // Tail is where DMA is currently writing
         buf_len - remaining
     }
@@ -403,12 +454,60 @@ where
         }
     }
 
+    /// Get line error counters accumulated since last call, resetting them
+    pub fn take_line_errors(&mut self) -> LineErrors {
+        core::mem::take(&mut self.errors)
+    }
+
+    /// Count DMA interrupt that had no events to handle
+    pub fn count_spurious_dma(&mut self) {
+        self.errors.spurious_dma = self.errors.spurious_dma.saturating_add(1);
+    }
+
+    fn count_lost(&mut self, result: ConsumeResult) {
+        self.errors.dropped_bytes = self.errors.dropped_bytes.saturating_add(result.lost as u32);
+    }
+
     /// Handle UART interrupt
+    ///
+    /// Line errors (framing, noise, parity, overrun) are only counted; the data
+    /// is passed further and will be rejected by checksum verification.
     pub fn on_uart_interrupt(&mut self) -> dma::InterruptResult { // TODO: custom return type?
+        let inc = |val: &mut u32| *val = val.saturating_add(1);
+
         let uart = Self::uart();
-        if uart.isr.read().idle().bit_is_set() {
+        let isr = uart.isr.read();
+        let mut handled = false;
+
+        if isr.pe().bit_is_set() {
+            uart.icr.write(|w| w.pecf().clear());
+            inc(&mut self.errors.parity);
+            handled = true;
+        }
+        if isr.fe().bit_is_set() {
+            uart.icr.write(|w| w.fecf().clear());
+            inc(&mut self.errors.framing);
+            handled = true;
+        }
+        if isr.nf().bit_is_set() {
+            uart.icr.write(|w| w.ncf().clear());
+            inc(&mut self.errors.noise);
+            handled = true;
+        }
+        if isr.ore().bit_is_set() {
+            uart.icr.write(|w| w.orecf().clear());
+            inc(&mut self.errors.overrun);
+            handled = true;
+        }
+
+        if isr.idle().bit_is_set() {
             uart.icr.write(|w| w.idlecf().clear());
-            self.consume();
+            let result = self.consume();
+            self.count_lost(result);
+            handled = true;
+        }
+
+        if handled {
             dma::InterruptResult::Done
         } else {
             dma::InterruptResult::NotSet
@@ -423,7 +522,8 @@ where
             self.buf.tail_wrapped();
         }
         if half == dma::InterruptResult::Done || full == dma::InterruptResult::Done {
-            self.consume();
+            let result = self.consume();
+            self.count_lost(result);
         }
         match (half, full) {
             (dma::InterruptResult::Error, _) => dma::InterruptResult::Error,
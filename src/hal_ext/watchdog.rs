@@ -1,5 +1,38 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use crate::hal;
 
+/// Watchdog selection and timing
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct WatchdogConfig {
+    /// Start the watchdog, "watchdog" feature starts it regardless of this setting
+    pub enabled: bool,
+    /// Which watchdog peripheral to use
+    pub kind: WatchdogKind,
+    /// Minimum time between feeding the watchdog
+    pub window_start_ms: u32,
+    /// Maximum time between feeding the watchdog
+    pub window_end_ms: u32,
+}
+
+/// Watchdog peripheral
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum WatchdogKind {
+    /// Window watchdog clocked from PCLK, accurate but limited to short periods
+    Window,
+    /// Independent watchdog clocked from LSI, allows long periods but timing is inaccurate
+    Independent,
+}
+
+/// Any of the watchdogs or none
+pub enum Watchdog {
+    Disabled,
+    Window(WindowWatchdog),
+    Independent(IndependentWatchdog),
+}
+
 /// System window watchdog - WWDG
 pub struct WindowWatchdog {
     wwdg: hal::pac::WWDG,
@@ -12,23 +45,6 @@ pub struct WindowParams {
     window: u8,
 }
 
-/// Control system reset reason flags
-pub mod reset_flags {
-    use super::hal;
-
-    /// Clear all reset flags
-    pub fn clear(_rcc: &mut hal::rcc::Rcc) {
-        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
-        rcc_regs.csr.write(|w| w.rmvf().set_bit());
-    }
-
-    /// Check if last system reset was due to window watchdog
-    pub fn was_window_watchdog(_rcc: &mut hal::rcc::Rcc) -> bool {
-        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
-        rcc_regs.csr.read().wwdgrstf().bit_is_set()
-    }
-}
-
 impl WindowWatchdog {
     /// Create watchdog instance, must be started using [`Self::start`]
     pub fn new(
@@ -137,3 +153,286 @@ impl WindowParams {
         Self { counter, window }
     }
 }
+
+impl WatchdogConfig {
+    /// Window watchdog parameters, `None` if other kind is selected
+    ///
+    /// Use in `const` context to get compile-time errors for invalid timing.
+    pub const fn window_params(&self, f_pclk_hz: u32) -> Option<WindowParams> {
+        match self.kind {
+            WatchdogKind::Window => Some(WindowParams::new(
+                f_pclk_hz,
+                self.window_start_ms * 1000,
+                self.window_end_ms * 1000,
+            )),
+            WatchdogKind::Independent => None,
+        }
+    }
+
+    /// Independent watchdog parameters, `None` if other kind is selected
+    ///
+    /// Use in `const` context to get compile-time errors for invalid timing.
+    pub const fn independent_params(&self) -> Option<IndependentParams> {
+        match self.kind {
+            WatchdogKind::Window => None,
+            WatchdogKind::Independent => Some(IndependentParams::new(self.window_start_ms, self.window_end_ms)),
+        }
+    }
+}
+
+impl Watchdog {
+    /// Prepare for periodic feeding with time counted from `now_ms`
+    ///
+    /// Initialization may take arbitrary time, so this should be called at its end.
+    pub fn start_feeding(&mut self, now_ms: u32) {
+        match self {
+            Self::Disabled => {},
+            Self::Window(wwdg) => {
+                wwdg.maybe_feed();
+            },
+            Self::Independent(iwdg) => iwdg.start_window(now_ms),
+        }
+    }
+
+    /// Feed the watchdog if it is in the feeding window, `now_ms` is the current time
+    pub fn maybe_feed(&mut self, now_ms: u32) -> bool {
+        match self {
+            Self::Disabled => false,
+            Self::Window(wwdg) => wwdg.maybe_feed(),
+            Self::Independent(iwdg) => iwdg.maybe_feed(now_ms),
+        }
+    }
+}
+
+/// Independent watchdog - IWDG
+///
+/// IWDG counter cannot be read, so feeding time is tracked in software. Because LSI
+/// frequency varies between 30 and 50 kHz, the watchdog is fed in the middle of the
+/// window, which must be wide enough to account for the inaccuracy.
+pub struct IndependentWatchdog {
+    iwdg: hal::pac::IWDG,
+    params: IndependentParams,
+    last_feed_ms: u32,
+}
+
+/// Parameters for IWDG configuration
+#[derive(Clone, Copy)]
+pub struct IndependentParams {
+    prescaler: u8,
+    reload: u16,
+    window: u16,
+    feed_after_ms: u32,
+}
+
+impl IndependentWatchdog {
+    /// Create watchdog instance, must be started using [`Self::start`]
+    pub fn new(iwdg: hal::pac::IWDG, params: IndependentParams) -> Self {
+        Self { iwdg, params, last_feed_ms: 0 }
+    }
+
+    /// Configure and enable independent watchdog (this also enables LSI)
+    ///
+    /// Window is not enabled until [`Self::start_window`] so the watchdog can be fed at
+    /// any time before the end of the period.
+    pub fn start(&mut self) {
+        const KEY_START: u32 = 0xcccc;
+        const KEY_ACCESS: u32 = 0x5555;
+
+        self.iwdg.kr.write(|w| unsafe { w.bits(KEY_START) });
+        self.iwdg.kr.write(|w| unsafe { w.bits(KEY_ACCESS) });
+        self.iwdg.pr.write(|w| unsafe { w.bits(self.params.prescaler as u32) });
+        self.iwdg.rlr.write(|w| unsafe { w.bits(self.params.reload as u32) });
+        // Wait for registers to be updated in the LSI clock domain
+        while self.iwdg.sr.read().bits() != 0 {}
+        self.feed(0);
+    }
+
+    /// Enable the window, reloading the counter, with `now_ms` as time of feeding
+    pub fn start_window(&mut self, now_ms: u32) {
+        const KEY_ACCESS: u32 = 0x5555;
+
+        self.iwdg.kr.write(|w| unsafe { w.bits(KEY_ACCESS) });
+        // Writing the window register also reloads the counter
+        self.iwdg.winr.write(|w| unsafe { w.bits(self.params.window as u32) });
+        while self.iwdg.sr.read().bits() != 0 {}
+        self.last_feed_ms = now_ms;
+    }
+
+    /// Stop independent watchdog when the core is halted during MCU debugging
+    pub fn stop_on_debug(&mut self, stop: bool, dbg: &mut hal::pac::DBGMCU, _rcc: &mut hal::rcc::Rcc) {
+        let rcc_regs = unsafe { &*hal::pac::RCC::ptr() };
+        rcc_regs.apb2enr.modify(|_, w| w.dbgmcuen().enabled());
+
+        dbg.apb1_fz.modify(|_, w| w.dbg_iwdg_stop().bit(stop));
+    }
+
+    /// Feed the watchdog, must be done during the configured time window
+    pub fn feed(&mut self, now_ms: u32) {
+        const KEY_RELOAD: u32 = 0xaaaa;
+        self.iwdg.kr.write(|w| unsafe { w.bits(KEY_RELOAD) });
+        self.last_feed_ms = now_ms;
+    }
+
+    /// Feed the watchdog if enough time passed since last feeding
+    pub fn maybe_feed(&mut self, now_ms: u32) -> bool {
+        let ready = now_ms.wrapping_sub(self.last_feed_ms) >= self.params.feed_after_ms;
+        if ready {
+            self.feed(now_ms);
+        }
+        ready
+    }
+}
+
+impl IndependentParams {
+    /// Nominal LSI frequency, actual frequency is in range 30-50 kHz
+    pub const LSI_HZ: u32 = 40_000;
+
+    /// Pre-calculate independent watchdog parameters
+    ///
+    /// Uses the smallest prescaler for which the reload value fits in its 12-bit register
+    /// to get best resolution. Timing is calculated for nominal LSI frequency.
+    ///
+    /// # Panics
+    ///
+    /// When window start is not before window end or the period is too long (~26 s).
+    /// Use this function to compute a `const` value to get compile-time errors.
+    pub const fn new(window_start_ms: u32, window_end_ms: u32) -> Self {
+        assert!(window_start_ms < window_end_ms);
+
+        // Prescaler divides LSI by 4 * 2^pr
+        let mut pr = 0;
+        let (tick_us, reload) = loop {
+            assert!(pr <= 6, "Independent watchdog period too long");
+            let tick_us = (4 << pr) * 1_000_000 / Self::LSI_HZ;
+            let reload = window_end_ms * 1000 / tick_us;
+            if reload <= 0xfff {
+                break (tick_us, reload);
+            }
+            pr += 1;
+        };
+        assert!(reload > 0);
+
+        // Reload is allowed only when the down-counter is below window value
+        let window = reload - window_start_ms * 1000 / tick_us;
+
+        Self {
+            prescaler: pr as u8,
+            reload: reload as u16,
+            window: window as u16,
+            feed_after_ms: (window_start_ms + window_end_ms) / 2,
+        }
+    }
+}
+
+/// Monitor of progress of periodic tasks
+///
+/// Each monitored task checks in whenever it runs. The watchdog should only be fed when
+/// no task has stalled, so a wedged task results in a reset instead of the watchdog being
+/// fed from idle while parts of the firmware silently stopped working. Tasks are identified
+/// by index and time is expressed in arbitrary units (milliseconds in practice) that must
+/// be consistent with deadlines. Time starts at 0, so all tasks are considered to have
+/// checked in at time 0.
+pub struct TaskHealth<const N: usize> {
+    deadlines: [AtomicU32; N],
+    last: [AtomicU32; N],
+    monitored: [AtomicBool; N],
+}
+
+impl<const N: usize> TaskHealth<N> {
+    /// Create monitor with maximum allowed time between check-ins of each task
+    pub fn new(deadlines: [u32; N]) -> Self {
+        Self {
+            deadlines: deadlines.map(AtomicU32::new),
+            last: core::array::from_fn(|_| AtomicU32::new(0)),
+            monitored: core::array::from_fn(|_| AtomicBool::new(true)),
+        }
+    }
+
+    /// Report progress of a task
+    pub fn checkin(&self, task: usize, now: u32) {
+        self.last[task].store(now, Ordering::Relaxed);
+    }
+
+    /// Change deadline of a task, e.g. when its period has been changed
+    pub fn set_deadline(&self, task: usize, deadline: u32) {
+        self.deadlines[task].store(deadline, Ordering::Relaxed);
+    }
+
+    /// Enable/disable monitoring of a task, e.g. when it is not being run on purpose
+    ///
+    /// Monitoring re-enabled after the deadline would have passed needs a check-in first.
+    pub fn set_monitored(&self, task: usize, monitored: bool, now: u32) {
+        if monitored && !self.monitored[task].load(Ordering::Relaxed) {
+            self.checkin(task, now);
+        }
+        self.monitored[task].store(monitored, Ordering::Relaxed);
+    }
+
+    /// Get the first task that did not check in before its deadline
+    pub fn stalled(&self, now: u32) -> Option<usize> {
+        (0..N).find(|&i| {
+            self.monitored[i].load(Ordering::Relaxed)
+                && now.wrapping_sub(self.last[i].load(Ordering::Relaxed)) > self.deadlines[i].load(Ordering::Relaxed)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn independent_params() {
+        let p = IndependentParams::new(30, 60);
+        assert_eq!((p.prescaler, p.reload, p.window, p.feed_after_ms), (0, 600, 300, 45));
+        let p = IndependentParams::new(1000, 2000);
+        assert_eq!((p.prescaler, p.reload, p.window, p.feed_after_ms), (3, 2500, 1250, 1500));
+    }
+
+    #[test]
+    #[should_panic(expected = "period too long")]
+    fn independent_params_too_long() {
+        IndependentParams::new(1000, 30_000);
+    }
+
+    #[test]
+    fn task_stall() {
+        let health = TaskHealth::new([10, 100]);
+        assert_eq!(health.stalled(10), None);
+        assert_eq!(health.stalled(11), Some(0));
+        health.checkin(0, 11);
+        assert_eq!(health.stalled(21), None);
+        assert_eq!(health.stalled(101), Some(1));
+        health.checkin(0, 100);
+        health.checkin(1, 100);
+        assert_eq!(health.stalled(105), None);
+    }
+
+    #[test]
+    fn task_deadline_changed() {
+        let health = TaskHealth::new([10]);
+        health.set_deadline(0, 100);
+        assert_eq!(health.stalled(50), None);
+        assert_eq!(health.stalled(101), Some(0));
+    }
+
+    #[test]
+    fn task_unmonitored() {
+        let health = TaskHealth::new([10, 10]);
+        health.set_monitored(1, false, 0);
+        health.checkin(0, 50);
+        assert_eq!(health.stalled(50), None);
+        // Re-enabling counts as a check-in
+        health.set_monitored(1, true, 50);
+        assert_eq!(health.stalled(55), None);
+        assert_eq!(health.stalled(61), Some(1));
+    }
+
+    #[test]
+    fn task_time_wraps() {
+        let health = TaskHealth::new([10]);
+        health.checkin(0, u32::MAX - 2);
+        assert_eq!(health.stalled(5), None);
+        assert_eq!(health.stalled(8), Some(0));
+    }
+}
@@ -13,6 +13,13 @@ use crate::hal_ext::{ChecksumGen, ChecksumEncoder};
 pub trait Packet: MaxSize {
     /// Checksum generator used to add checksum to the data packets
     type Checksum: ChecksumGen;
+
+    /// Packet has been sent by a newer protocol version and is not known to this one
+    ///
+    /// Such packets are skipped by the receiver instead of being reported as errors.
+    fn is_unknown(&self) -> bool {
+        false
+    }
 }
 
 /// Imitates [`MaxSize`] as we cannot implement it with generics because it is foreign trait
@@ -5,6 +5,8 @@ use postcard::experimental::max_size::MaxSize;
 use bbqueue::Consumer;
 use serde::Deserialize;
 
+use crate::hal_ext::uart::LineErrors;
+use crate::utils::Watermark;
 use super::PacketId;
 use super::packet::{self, Packet, PacketDeser, Accumulator, PacketMaxSize};
 
@@ -31,6 +33,7 @@ where
     accumulator: Accumulator<B>,
     id_counter: Option<PacketId>,
     stats: Stats,
+    watermark: Watermark,
     _packet: PhantomData<P>,
 }
 
@@ -42,6 +45,10 @@ pub struct Stats {
     pub checksum_errors: u32,
     pub deser_errors: u32,
     pub ignored_retransmissions: u32,
+    /// Valid packets of unknown type (from newer firmware) that have been skipped
+    pub unknown_packets: u32,
+    /// Errors detected by the underlying hardware
+    pub line: LineErrors,
 }
 
 pub const fn max_packet_size<P: Packet>() -> usize {
@@ -63,6 +70,7 @@ where
             accumulator: Accumulator::new(),
             id_counter: None,
             stats: Default::default(),
+            watermark: Watermark::new(N),
             _packet: PhantomData,
         }
     }
@@ -71,9 +79,33 @@ where
         &self.stats
     }
 
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Maximum number of bytes waiting in the queue since creation or the last [`Self::reset_watermark`]
+    pub fn watermark(&self) -> &Watermark {
+        &self.watermark
+    }
+
+    pub fn reset_watermark(&mut self) {
+        self.watermark.reset();
+    }
+
+    /// Include line errors from the physical layer in statistics
+    pub fn add_line_errors(&mut self, errors: &LineErrors) {
+        self.stats.line.accumulate(errors);
+    }
+
     pub fn read(&mut self, checksum: &mut P::Checksum) -> Option<P> {
         let inc = |val: &mut u32| *val = val.saturating_add(1);
 
+        // Queue usage only decreases here, so sampling before reading catches the peaks.
+        // Split grant is needed to include data wrapping around the buffer end.
+        if let Ok(grant) = self.rx.split_read() {
+            self.watermark.update(grant.combined_len());
+        }
+
         let grant = match self.rx.read() {
             Ok(grant) => grant,
             Err(err) => match err {
@@ -108,7 +140,12 @@ where
                     None
                 } else {
                     self.id_counter = Some(p.id);
-                    Some(p.packet)
+                    if p.packet.is_unknown() {
+                        inc(&mut self.stats.unknown_packets);
+                        None
+                    } else {
+                        Some(p.packet)
+                    }
                 }
             },
         };
@@ -0,0 +1,57 @@
+use crate::bsp::persistent::Persistent;
+
+#[link_section = ".uninit.ghanima.accessibility"]
+static STATE: Persistent<u8, 0x6163_6300> = Persistent::new();
+
+/// Accessibility options toggled at runtime, timing is taken from configuration
+#[derive(Clone, Copy, Default, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Options {
+    /// Key must be held for some time before its press is reported
+    pub slow_keys: bool,
+    /// Repeated presses of a key shortly after its release are ignored
+    pub bounce_keys: bool,
+}
+
+impl Options {
+    const SLOW_KEYS: u8 = 1 << 0;
+    const BOUNCE_KEYS: u8 = 1 << 1;
+
+    fn to_bits(self) -> u8 {
+        let flag = |enabled: bool, bit: u8| if enabled { bit } else { 0 };
+        flag(self.slow_keys, Self::SLOW_KEYS) | flag(self.bounce_keys, Self::BOUNCE_KEYS)
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            slow_keys: bits & Self::SLOW_KEYS != 0,
+            bounce_keys: bits & Self::BOUNCE_KEYS != 0,
+        }
+    }
+}
+
+/// Accessibility options from before the last software reset, all disabled after power loss
+pub fn load() -> Options {
+    STATE.load().map(Options::from_bits).unwrap_or_default()
+}
+
+/// Preserve accessibility options over system reset
+pub fn store(options: Options) {
+    STATE.store(options.to_bits());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_bits() {
+        for slow_keys in [false, true] {
+            for bounce_keys in [false, true] {
+                let options = Options { slow_keys, bounce_keys };
+                assert_eq!(Options::from_bits(options.to_bits()), options);
+            }
+        }
+        assert_eq!(Options::from_bits(0), Options::default());
+    }
+}
@@ -1,5 +1,6 @@
 pub use usbd_human_interface_device::page::Consumer as ConsumerKey;
 pub use crate::utils::Inc;
+pub use super::shortcuts::OsProfile;
 
 /// Additional key actions
 pub enum Action {
@@ -10,7 +11,9 @@ pub enum Action {
     /// Send USB HID consumer page keys
     Consumer(ConsumerKey),
     /// Perform special firmware-related actions
-    Firmware(FirmwareAction)
+    Firmware(FirmwareAction),
+    /// Send common shortcuts that use different key chords depending on host OS
+    Shortcut(ShortcutAction),
 }
 
 
@@ -20,6 +23,16 @@ pub enum LedAction {
     Cycle(Inc),
     /// Modify global brightness
     Brightness(Inc),
+    /// Remove all LED color overrides set by host
+    ClearOverrides,
+    /// Toggle night mode (warmer colors with limited brightness)
+    NightMode,
+    /// Cycle through configured brightness presets
+    BrightnessPreset,
+    /// Toggle immediate turning off of all LEDs on both halves
+    Blackout,
+    /// Raise brightness to maximum while held, previous brightness is restored on release
+    BrightnessBoost,
 }
 
 
@@ -31,6 +44,16 @@ pub enum MouseAction {
     Move(MouseMovement),
     /// Key changes mouse sensitivity
     Sensitivity(Inc),
+    /// Key changes joystick sensitivity, kept until reset
+    JoystickSensitivity(Inc),
+    /// Scale down all pointer and scroll movement while held
+    Precision,
+    /// Toggle automatic left click when joystick pointer stops moving
+    DwellClick,
+    /// Use speed profile of given tier for pointer movement keys while held
+    SpeedTier(SpeedTier),
+    /// Switch joystick between pointer movement and scrolling
+    JoystickPlane,
 }
 
 /// Emulate a mouse button
@@ -38,6 +61,22 @@ pub enum MouseButton {
     Left,
     Mid,
     Right,
+    /// Button 4, usually "back" in browsers
+    Back,
+    /// Button 5, usually "forward" in browsers
+    Forward,
+    Button6,
+    Button7,
+    Button8,
+}
+
+/// Pointer speed selected with [`MouseAction::SpeedTier`], see [`super::mouse::SpeedTiers`]
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum SpeedTier {
+    Slow,
+    Medium,
+    Fast,
 }
 
 /// Emulate mouse (or mouse wheel) movement
@@ -52,6 +91,40 @@ pub enum MouseMovement {
     PanRight,
 }
 
+/// Actions for shortcuts that depend on the host operating system
+pub enum ShortcutAction {
+    /// Send key chord of the shortcut for the current OS profile while held
+    Key(Shortcut),
+    /// Switch to given OS profile
+    Profile(OsProfile),
+    /// Cycle through OS profiles
+    CycleProfile,
+}
+
+/// Common shortcut translated to a key chord according to the OS profile
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Shortcut {
+    Copy,
+    Cut,
+    Paste,
+    Undo,
+    Redo,
+    SelectAll,
+    Save,
+    Find,
+    /// Move cursor to the previous word
+    WordLeft,
+    /// Move cursor to the next word
+    WordRight,
+    /// Move cursor to the start of line
+    LineStart,
+    /// Move cursor to the end of line
+    LineEnd,
+    /// Delete the word before cursor
+    DeleteWord,
+}
+
 /// Special actions related to keyboard firmware
 pub enum FirmwareAction {
     /// Allow host to request "jump to bootloader" to flash firmware
@@ -69,4 +142,52 @@ pub enum FirmwareAction {
     /// Start infinite loop, used to test if keyboard can correctly recover
     /// from an error due to watchdog overflow
     InfiniteLoop,
+    /// Type firmware version, build date, configuration, firmware image CRC (also of the other
+    /// half), current role/layer and hardware information (MCU unique ID etc.) as text
+    TypeInfo,
+    /// Enable/disable logging of recent key events (coordinates with timestamps)
+    ///
+    /// Disabled by default for privacy, the log can be read for diagnosing key chatter
+    /// or missed key presses.
+    ToggleEventLog,
+    /// Enable/disable collection of key usage statistics (presses per key, time per layer)
+    ///
+    /// Disabled by default for privacy, statistics are stored in flash and can be read over
+    /// the debug console for keymap optimization.
+    KeyStats,
+    /// Clear key usage statistics and erase them from flash
+    WipeKeyStats,
+    /// Enter/exit key tester mode for assembly QA
+    ///
+    /// In this mode no normal keyboard reports are sent, pressed keys light their LEDs white
+    /// and key coordinates/key codes are logged.
+    KeyTester,
+    /// Same as [`Self::KeyTester`] but also types coordinates of pressed keys as text
+    KeyTesterTyping,
+    /// Hand over master role to the other half
+    ///
+    /// Only succeeds if the other half is connected to USB too (e.g. both via a hub).
+    SwapRole,
+    /// Toggle game mode
+    ///
+    /// Hold-tap keys tap immediately, GUI keys are blocked, LED animations are stopped
+    /// and eager debouncing is used on both halves.
+    GameMode,
+    /// Enable/disable joystick completely (e.g. when it drifts), preserved over reset
+    ToggleJoystick,
+    /// Type which layer and action handle the next pressed key
+    ///
+    /// The explained key press is not passed to the layout. Useful for debugging layers
+    /// with many transparent keys.
+    ExplainKey,
+    /// Toggle slow keys: key must be held for some time before it registers, preserved over reset
+    SlowKeys,
+    /// Toggle bounce keys: repeated presses of a key shortly after its release are ignored,
+    /// preserved over reset
+    BounceKeys,
+    /// Toggle layout training mode
+    ///
+    /// LEDs outside of the configured teaching set are dimmed and pressing a key that does
+    /// nothing on the current layer flashes the layer key that gives it an action.
+    Training,
 }
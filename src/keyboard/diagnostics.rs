@@ -0,0 +1,533 @@
+use rgb::RGB8;
+
+use crate::bsp::{panic::PanicReport, sides::BoardSide, NLEDS};
+use crate::hal_ext::uart::LineErrors;
+use crate::ioqueue;
+use crate::utils::Watermark;
+use super::hid::{QueueStats, RawReport, RAW_REPORT_SIZE};
+use super::{Prescalers, PrescalerTask};
+
+/// Raw HID command that reads and optionally resets runtime statistics
+pub const CMD_STATS: u8 = 0x02;
+/// Raw HID command that reads colors currently displayed by LEDs
+pub const CMD_LED_COLORS: u8 = 0x03;
+/// Raw HID command that reads the report of the last panic
+pub const CMD_PANIC: u8 = 0x05;
+/// Raw HID command that reads or changes period of a periodic task
+pub const CMD_PRESCALER: u8 = 0x06;
+/// Command, page, number of values, reserved
+const HEADER_LEN: usize = 4;
+/// Command, side, first LED, number of LEDs, total number of LEDs
+const COLORS_HEADER_LEN: usize = 5;
+/// Maximum number of LED colors that fit in a report
+pub const MAX_COLORS: usize = (RAW_REPORT_SIZE - COLORS_HEADER_LEN) / 3;
+/// Command, part, offset, number of bytes
+const PANIC_HEADER_LEN: usize = 4;
+
+/// Group of statistics that fits in a single report
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum StatsPage {
+    /// Serial link [`ioqueue::Stats`] (without line errors), as `u32` values
+    Link = 0,
+    /// UART [`LineErrors`] of the serial link, as `u32` values
+    Line = 1,
+    /// [`QueueStats`] of keyboard and consumer HID report queues, as `u32` values
+    HidQueues = 2,
+    /// Task execution counters since the last read (always reset), as `u16` values
+    Tasks = 3,
+    /// Maximum usage and capacity of serial TX/RX and keyboard/consumer HID report queues,
+    /// as pairs of `u16` values
+    Queues = 4,
+}
+
+/// Query of runtime statistics from host software
+///
+/// Sent as a raw HID report:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x02`                                                  |
+/// | 1    | page: 0 - link, 1 - line errors, 2 - HID queues, 3 - tasks, 4 - queue usage |
+/// | 2    | 1 to reset the statistics after reading, else 0         |
+///
+/// Pages 0 and 1 are reset together. Keyboard answers with an input report:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x02`                                                  |
+/// | 1    | page                                                    |
+/// | 2    | number of values N                                      |
+/// | 3    | reserved                                                |
+/// | 4..  | N little-endian values in order of struct fields        |
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct StatsRequest {
+    pub page: StatsPage,
+    pub reset: bool,
+}
+
+/// Query of LED colors from host software, e.g. to mirror keyboard lighting on screen
+///
+/// Sent as a raw HID report:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x03`                                                  |
+/// | 1    | side: 0 - left, 1 - right                               |
+/// | 2    | index of the first LED                                  |
+///
+/// Keyboard answers with an input report containing up to [`MAX_COLORS`] colors, so the host
+/// needs to request consecutive ranges until all LEDs of both sides are read:
+///
+/// | Byte     | Value                                                |
+/// |----------|------------------------------------------------------|
+/// | 0        | `0x03`                                               |
+/// | 1        | side                                                 |
+/// | 2        | index of the first LED                               |
+/// | 3        | number of colors N                                   |
+/// | 4        | total number of LEDs on a side                       |
+/// | 5..5+3N  | colors as R, G, B                                    |
+///
+/// Colors of the other half are not read back from it. This half computes them from the same
+/// state, so they are a prediction that may differ from what the other half actually shows,
+/// e.g. when the link is lost or with `remote_leds`, where the other half renders its own colors.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ColorsRequest {
+    pub side: BoardSide,
+    pub first: u8,
+}
+
+/// Part of [`PanicReport`] returned by [`PanicRequest`]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum PanicPart {
+    /// Location, registers and lengths of text parts
+    Summary = 0,
+    /// Source file path (possibly truncated)
+    File = 1,
+    Message = 2,
+}
+
+/// Query of the panic report stored over reset, see [`crate::bsp::panic`]
+///
+/// Sent as a raw HID report:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x05`                                                  |
+/// | 1    | part: 0 - summary, 1 - file, 2 - message                |
+/// | 2    | offset in text (file and message only)                  |
+///
+/// Keyboard answers summary with an input report (all values little-endian):
+///
+/// | Byte   | Value                                                 |
+/// |--------|-------------------------------------------------------|
+/// | 0      | `0x05`                                                |
+/// | 1      | `0`                                                   |
+/// | 2      | 1 if there was a panic since power-on, else 0         |
+/// | 3      | length of file path                                   |
+/// | 4      | length of message                                     |
+/// | 8..16  | line and column as `u32`                              |
+/// | 16..28 | MSP, ICSR and CONTROL registers as `u32`              |
+///
+/// Text parts are answered with up to 28 bytes of UTF-8 starting at offset, so the host
+/// needs to request consecutive ranges until it reads the length given in the summary:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x05`                                                  |
+/// | 1    | part                                                    |
+/// | 2    | offset                                                  |
+/// | 3    | number of bytes N                                       |
+/// | 4..  | N bytes of text                                         |
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct PanicRequest {
+    pub part: PanicPart,
+    pub offset: u8,
+}
+
+/// Query or change of a task prescaler from host software, see [`Prescalers`]
+///
+/// Sent as a raw HID report (all values little-endian):
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x06`                                                  |
+/// | 1    | task: 0 - LEDs, 1 - joystick, 2 - mouse, 3 - debug      |
+/// | 2    | 1 to set new prescaler, 0 to only read it               |
+/// | 4..8 | new prescaler as `u32` in ticks, 0 disables the task    |
+///
+/// Changes last until reboot. Keyboard answers with an input report:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x06`                                                  |
+/// | 1    | task                                                    |
+/// | 4..8 | current prescaler as `u32`                              |
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct PrescalerRequest {
+    pub task: PrescalerTask,
+    pub value: Option<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    UnknownPage(u8),
+    UnknownSide(u8),
+    InvalidLed(u8),
+    UnknownPart(u8),
+    UnknownTask(u8),
+}
+
+impl StatsRequest {
+    /// Parse raw HID report with [`CMD_STATS`] command
+    pub fn from_report(report: &RawReport) -> Result<Self, Error> {
+        debug_assert_eq!(report[0], CMD_STATS);
+        let page = match report[1] {
+            0 => StatsPage::Link,
+            1 => StatsPage::Line,
+            2 => StatsPage::HidQueues,
+            3 => StatsPage::Tasks,
+            4 => StatsPage::Queues,
+            p => return Err(Error::UnknownPage(p)),
+        };
+        Ok(Self { page, reset: report[2] != 0 })
+    }
+}
+
+impl ColorsRequest {
+    /// Parse raw HID report with [`CMD_LED_COLORS`] command
+    pub fn from_report(report: &RawReport) -> Result<Self, Error> {
+        debug_assert_eq!(report[0], CMD_LED_COLORS);
+        let side = match report[1] {
+            0 => BoardSide::Left,
+            1 => BoardSide::Right,
+            s => return Err(Error::UnknownSide(s)),
+        };
+        let first = report[2];
+        if first as usize >= NLEDS {
+            return Err(Error::InvalidLed(first));
+        }
+        Ok(Self { side, first })
+    }
+
+    /// Build response from colors of the requested side
+    pub fn response(&self, colors: &[RGB8; NLEDS]) -> RawReport {
+        let mut report = [0; RAW_REPORT_SIZE];
+        let colors = &colors[self.first as usize..];
+        let colors = &colors[..colors.len().min(MAX_COLORS)];
+        report[..COLORS_HEADER_LEN].copy_from_slice(&[
+            CMD_LED_COLORS,
+            self.side as u8,
+            self.first,
+            colors.len() as u8,
+            NLEDS as u8,
+        ]);
+        for (dst, color) in report[COLORS_HEADER_LEN..].chunks_exact_mut(3).zip(colors) {
+            dst.copy_from_slice(&[color.r, color.g, color.b]);
+        }
+        report
+    }
+}
+
+impl PanicRequest {
+    /// Parse raw HID report with [`CMD_PANIC`] command
+    pub fn from_report(report: &RawReport) -> Result<Self, Error> {
+        debug_assert_eq!(report[0], CMD_PANIC);
+        let part = match report[1] {
+            0 => PanicPart::Summary,
+            1 => PanicPart::File,
+            2 => PanicPart::Message,
+            p => return Err(Error::UnknownPart(p)),
+        };
+        Ok(Self { part, offset: report[2] })
+    }
+
+    /// Build response from the last panic report, if any
+    pub fn response(&self, panic: Option<&PanicReport>) -> RawReport {
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[0] = CMD_PANIC;
+        report[1] = self.part as u8;
+        let Some(panic) = panic else {
+            return report;
+        };
+        let text = match self.part {
+            PanicPart::Summary => {
+                report[2] = 1;
+                report[3] = panic.file().len() as u8;
+                report[4] = panic.message().len() as u8;
+                let regs = &panic.regs;
+                let values = [panic.line, panic.column, regs.msp, regs.icsr, regs.control];
+                for (dst, value) in report[8..].chunks_exact_mut(4).zip(values) {
+                    dst.copy_from_slice(&value.to_le_bytes());
+                }
+                return report;
+            },
+            PanicPart::File => panic.file(),
+            PanicPart::Message => panic.message(),
+        };
+        let text = text.as_bytes().get(self.offset as usize..).unwrap_or(&[]);
+        let text = &text[..text.len().min(RAW_REPORT_SIZE - PANIC_HEADER_LEN)];
+        report[2] = self.offset;
+        report[3] = text.len() as u8;
+        report[PANIC_HEADER_LEN..PANIC_HEADER_LEN + text.len()].copy_from_slice(text);
+        report
+    }
+}
+
+impl PrescalerRequest {
+    /// Parse raw HID report with [`CMD_PRESCALER`] command
+    pub fn from_report(report: &RawReport) -> Result<Self, Error> {
+        debug_assert_eq!(report[0], CMD_PRESCALER);
+        let task = match report[1] {
+            0 => PrescalerTask::Leds,
+            1 => PrescalerTask::Joystick,
+            2 => PrescalerTask::Mouse,
+            3 => PrescalerTask::Debug,
+            t => return Err(Error::UnknownTask(t)),
+        };
+        let value = (report[2] != 0)
+            .then(|| u32::from_le_bytes([report[4], report[5], report[6], report[7]]));
+        Ok(Self { task, value })
+    }
+
+    /// Apply requested change, returns the resulting prescaler
+    pub fn apply(&self, prescalers: &mut Prescalers) -> u32 {
+        let prescaler = prescalers.get_mut(self.task);
+        if let Some(value) = self.value {
+            *prescaler = value;
+        }
+        *prescaler
+    }
+
+    /// Build response with the current prescaler
+    pub fn response(&self, prescaler: u32) -> RawReport {
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[0] = CMD_PRESCALER;
+        report[1] = self.task as u8;
+        report[4..8].copy_from_slice(&prescaler.to_le_bytes());
+        report
+    }
+}
+
+/// Builder of the response to [`StatsRequest`]
+pub struct StatsResponse {
+    report: RawReport,
+    len: usize,
+}
+
+impl StatsResponse {
+    fn new(page: StatsPage) -> Self {
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[0] = CMD_STATS;
+        report[1] = page as u8;
+        Self { report, len: HEADER_LEN }
+    }
+
+    /// Append value, values that do not fit are skipped
+    fn push(&mut self, bytes: &[u8]) {
+        if let Some(dst) = self.report.get_mut(self.len..self.len + bytes.len()) {
+            dst.copy_from_slice(bytes);
+            self.len += bytes.len();
+            self.report[2] += 1;
+        }
+    }
+
+    pub fn link(stats: &ioqueue::Stats) -> RawReport {
+        let mut r = Self::new(StatsPage::Link);
+        for value in [
+            stats.queue_overflows,
+            stats.accumulator_overflows,
+            stats.cobs_errors,
+            stats.checksum_errors,
+            stats.deser_errors,
+            stats.ignored_retransmissions,
+            stats.unknown_packets,
+        ] {
+            r.push(&value.to_le_bytes());
+        }
+        r.report
+    }
+
+    pub fn line(errors: &LineErrors) -> RawReport {
+        let mut r = Self::new(StatsPage::Line);
+        for value in [
+            errors.framing,
+            errors.noise,
+            errors.parity,
+            errors.overrun,
+            errors.dropped_bytes,
+            errors.spurious_dma,
+        ] {
+            r.push(&value.to_le_bytes());
+        }
+        r.report
+    }
+
+    pub fn hid_queues(queues: &[QueueStats]) -> RawReport {
+        let mut r = Self::new(StatsPage::HidQueues);
+        for queue in queues {
+            for value in [queue.pushed, queue.sent, queue.dropped] {
+                r.push(&value.to_le_bytes());
+            }
+        }
+        r.report
+    }
+
+    pub fn queues(watermarks: &[Watermark]) -> RawReport {
+        let mut r = Self::new(StatsPage::Queues);
+        for wm in watermarks {
+            for value in [wm.max(), wm.capacity()] {
+                r.push(&value.to_le_bytes());
+            }
+        }
+        r.report
+    }
+
+    pub fn tasks(counters: &[u16]) -> RawReport {
+        let mut r = Self::new(StatsPage::Tasks);
+        for value in counters {
+            r.push(&value.to_le_bytes());
+        }
+        r.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(bytes: &[u8]) -> RawReport {
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[..bytes.len()].copy_from_slice(bytes);
+        report
+    }
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(StatsRequest::from_report(&report(&[CMD_STATS, 2])),
+            Ok(StatsRequest { page: StatsPage::HidQueues, reset: false }));
+        assert_eq!(StatsRequest::from_report(&report(&[CMD_STATS, 0, 1])),
+            Ok(StatsRequest { page: StatsPage::Link, reset: true }));
+        assert_eq!(StatsRequest::from_report(&report(&[CMD_STATS, 4])),
+            Ok(StatsRequest { page: StatsPage::Queues, reset: false }));
+        assert_eq!(StatsRequest::from_report(&report(&[CMD_STATS, 5])), Err(Error::UnknownPage(5)));
+    }
+
+    #[test]
+    fn link_response() {
+        let stats = ioqueue::Stats { checksum_errors: 0x0102, unknown_packets: 7, ..Default::default() };
+        let r = StatsResponse::link(&stats);
+        assert_eq!(r[..4], [CMD_STATS, 0, 7, 0]);
+        assert_eq!(r[16..20], [0x02, 0x01, 0, 0]);
+        assert_eq!(r[28..32], [7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn hid_queues_response() {
+        let queues = [
+            QueueStats { pushed: 10, sent: 8, dropped: 1 },
+            QueueStats { pushed: 3, sent: 3, dropped: 0 },
+        ];
+        let r = StatsResponse::hid_queues(&queues);
+        assert_eq!(r[..4], [CMD_STATS, 2, 6, 0]);
+        assert_eq!(r[4..16], [10, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(r[16..20], [3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn queues_response() {
+        let mut tx = Watermark::new(400);
+        tx.update(0x123);
+        let r = StatsResponse::queues(&[tx, Watermark::new(8)]);
+        assert_eq!(r[..4], [CMD_STATS, 4, 4, 0]);
+        assert_eq!(r[4..12], [0x23, 0x01, 0x90, 0x01, 0, 0, 8, 0]);
+    }
+
+    #[test]
+    fn parse_colors_request() {
+        assert_eq!(ColorsRequest::from_report(&report(&[CMD_LED_COLORS, 1, 9])),
+            Ok(ColorsRequest { side: BoardSide::Right, first: 9 }));
+        assert_eq!(ColorsRequest::from_report(&report(&[CMD_LED_COLORS, 2])), Err(Error::UnknownSide(2)));
+        assert_eq!(ColorsRequest::from_report(&report(&[CMD_LED_COLORS, 0, NLEDS as u8])),
+            Err(Error::InvalidLed(NLEDS as u8)));
+    }
+
+    #[test]
+    fn colors_response() {
+        let mut colors = [RGB8::default(); NLEDS];
+        for (i, c) in colors.iter_mut().enumerate() {
+            *c = RGB8::new(i as u8, 0, 255 - i as u8);
+        }
+        let r = ColorsRequest { side: BoardSide::Left, first: 0 }.response(&colors);
+        assert_eq!(r[..5], [CMD_LED_COLORS, 0, 0, MAX_COLORS as u8, NLEDS as u8]);
+        assert_eq!(r[5..11], [0, 0, 255, 1, 0, 254]);
+
+        // Last range is shorter
+        let first = NLEDS as u8 - 2;
+        let r = ColorsRequest { side: BoardSide::Right, first }.response(&colors);
+        assert_eq!(r[..5], [CMD_LED_COLORS, 1, first, 2, NLEDS as u8]);
+        assert_eq!(r[5..11], [first, 0, 255 - first, first + 1, 0, 254 - first]);
+        assert!(r[11..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn parse_panic_request() {
+        assert_eq!(PanicRequest::from_report(&report(&[CMD_PANIC, 2, 28])),
+            Ok(PanicRequest { part: PanicPart::Message, offset: 28 }));
+        assert_eq!(PanicRequest::from_report(&report(&[CMD_PANIC, 3])), Err(Error::UnknownPart(3)));
+    }
+
+    #[test]
+    fn panic_response() {
+        let summary = PanicRequest { part: PanicPart::Summary, offset: 0 };
+        assert!(summary.response(None)[1..].iter().all(|b| *b == 0));
+
+        let panic = crate::bsp::panic::tests::report("src/main.rs", 0x102, &"0123456789".repeat(4));
+        let r = summary.response(Some(&panic));
+        assert_eq!(r[..5], [CMD_PANIC, 0, 1, 11, 40]);
+        assert_eq!(r[8..16], [0x02, 0x01, 0, 0, 7, 0, 0, 0]);
+        assert_eq!(r[16..20], [0x00, 0x3f, 0x00, 0x20]);
+
+        let r = PanicRequest { part: PanicPart::File, offset: 4 }.response(Some(&panic));
+        assert_eq!(r[..4], [CMD_PANIC, 1, 4, 7]);
+        assert_eq!(&r[4..11], b"main.rs");
+        let r = PanicRequest { part: PanicPart::Message, offset: 0 }.response(Some(&panic));
+        assert_eq!(r[..4], [CMD_PANIC, 2, 0, 28]);
+        let r = PanicRequest { part: PanicPart::Message, offset: 28 }.response(Some(&panic));
+        assert_eq!(r[..4], [CMD_PANIC, 2, 28, 12]);
+        assert_eq!(&r[4..16], b"890123456789");
+        let r = PanicRequest { part: PanicPart::Message, offset: 50 }.response(Some(&panic));
+        assert_eq!(r[..4], [CMD_PANIC, 2, 50, 0]);
+    }
+
+    #[test]
+    fn prescaler_request() {
+        assert_eq!(PrescalerRequest::from_report(&report(&[CMD_PRESCALER, 1])),
+            Ok(PrescalerRequest { task: PrescalerTask::Joystick, value: None }));
+        assert_eq!(PrescalerRequest::from_report(&report(&[CMD_PRESCALER, 4])), Err(Error::UnknownTask(4)));
+
+        let request = PrescalerRequest::from_report(&report(&[CMD_PRESCALER, 0, 1, 0, 0x2c, 0x01])).unwrap();
+        assert_eq!(request, PrescalerRequest { task: PrescalerTask::Leds, value: Some(300) });
+        let mut prescalers = Prescalers { leds: 20, joystick: 10, mouse: 10, debug: 0 };
+        assert_eq!(request.apply(&mut prescalers), 300);
+        assert_eq!(prescalers, Prescalers { leds: 300, joystick: 10, mouse: 10, debug: 0 });
+        assert_eq!(request.response(300)[..8], [CMD_PRESCALER, 0, 0, 0, 0x2c, 0x01, 0, 0]);
+
+        let read = PrescalerRequest { task: PrescalerTask::Mouse, value: None };
+        assert_eq!(read.apply(&mut prescalers), 10);
+        assert_eq!(prescalers.mouse, 10);
+    }
+
+    #[test]
+    fn values_that_do_not_fit_are_skipped() {
+        let r = StatsResponse::tasks(&[0xabcd; 20]);
+        assert_eq!(r[..4], [CMD_STATS, 3, 14, 0]);
+        assert_eq!(r[30..32], [0xcd, 0xab]);
+    }
+}
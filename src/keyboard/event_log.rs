@@ -0,0 +1,100 @@
+use keyberon::layout::Event;
+
+/// Number of recent key events stored
+pub const EVENT_LOG_LEN: usize = 32;
+
+/// Key event with a timestamp
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct LoggedEvent {
+    /// Keyboard time in ticks
+    pub time: u32,
+    /// Event with global coordinates
+    pub event: Event,
+}
+
+/// Circular log of recent key events used to diagnose chatter or missed keys
+///
+/// Only key coordinates are stored, but these could still be used to recover typed text,
+/// so logging is disabled by default and must be explicitly enabled by the user. The log is
+/// cleared whenever logging is toggled.
+pub struct EventLog {
+    events: heapless::Deque<LoggedEvent, EVENT_LOG_LEN>,
+    enabled: bool,
+}
+
+impl EventLog {
+    pub const fn new() -> Self {
+        Self { events: heapless::Deque::new(), enabled: false }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enable or disable logging, clears logged events
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.events.clear();
+    }
+
+    /// Store new event if enabled, overwriting the oldest one when full
+    pub fn push(&mut self, time: u32, event: Event) {
+        if !self.enabled {
+            return;
+        }
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        self.events.push_back(LoggedEvent { time, event }).ok();
+    }
+
+    /// Iterate over logged events, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &LoggedEvent> {
+        self.events.iter()
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn disabled_by_default() {
+        let mut log = EventLog::new();
+        log.push(1, Event::Press(0, 0));
+        assert_eq!(log.iter().count(), 0);
+    }
+
+    #[test]
+    fn overwrite_oldest() {
+        let mut log = EventLog::new();
+        log.set_enabled(true);
+        for t in 0..EVENT_LOG_LEN as u32 + 3 {
+            log.push(t, Event::Press(1, 2));
+        }
+        let times: Vec<_> = log.iter().map(|e| e.time).collect();
+        assert_eq!(times.len(), EVENT_LOG_LEN);
+        assert_eq!(times[0], 3);
+        assert_eq!(*times.last().unwrap(), EVENT_LOG_LEN as u32 + 2);
+    }
+
+    #[test]
+    fn cleared_on_toggle() {
+        let mut log = EventLog::new();
+        log.set_enabled(true);
+        log.push(1, Event::Release(3, 4));
+        assert_eq!(log.iter().next(), Some(&LoggedEvent { time: 1, event: Event::Release(3, 4) }));
+        log.set_enabled(false);
+        log.set_enabled(true);
+        assert_eq!(log.iter().count(), 0);
+    }
+}
@@ -0,0 +1,230 @@
+use keyberon::action::Action;
+use keyberon::layout::{Event, Layers};
+use ufmt::{uWrite, uwrite};
+
+use crate::bsp::{NCOLS, NROWS};
+use super::actions;
+
+/// Explains which layer and action handle the next pressed key
+///
+/// When armed, the next key press is resolved in the same way as the layout does it:
+/// the action is taken from the current layer and transparent keys fall through to the
+/// default layer. The explained key press and its release are consumed, so explaining
+/// e.g. a layer key does not change layers.
+///
+/// Layout does not expose its default layer, so it is tracked by watching presses of keys
+/// with [`Action::DefaultLayer`] (directly or inside [`Action::MultipleActions`]).
+pub struct KeyExplainer<const L: usize> {
+    layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>,
+    armed: bool,
+    default_layer: usize,
+    /// Key with explained press, its release must not reach the layout
+    consumed: Option<(u8, u8)>,
+}
+
+/// How a key press has been resolved
+pub struct Explanation {
+    /// Global key coordinates
+    pub coord: (u8, u8),
+    /// Layer that was active when the key was pressed
+    pub layer: usize,
+    /// Layer that provided the action, differs from `layer` for transparent keys
+    pub resolved: usize,
+    pub action: &'static Action<actions::Action>,
+}
+
+/// Result of passing key event through [`KeyExplainer`]
+pub enum Explained {
+    /// Event should be passed to the layout
+    Pass(Event),
+    /// Key press has been explained, the event must not be passed to the layout
+    Press(Explanation),
+    /// Release of the explained key, the event must not be passed to the layout
+    Release,
+}
+
+impl<const L: usize> KeyExplainer<L> {
+    pub const fn new(layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>) -> Self {
+        Self { layers, armed: false, default_layer: 0, consumed: None }
+    }
+
+    /// Use different layers, e.g. a copy that can be modified at runtime
+    pub fn set_layers(&mut self, layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>) {
+        self.layers = layers;
+    }
+
+    /// Explain the next key press
+    pub fn arm(&mut self) {
+        defmt::info!("Explaining next key press");
+        self.armed = true;
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Handle key event with `layer` being the current layout layer
+    pub fn event(&mut self, event: Event, layer: usize) -> Explained {
+        match event {
+            Event::Press(i, j) => {
+                let (resolved, action) = self.resolve((i, j), layer);
+                if !self.armed {
+                    self.track_default_layer(action);
+                    return Explained::Pass(event);
+                }
+                self.armed = false;
+                self.consumed = Some((i, j));
+                Explained::Press(Explanation { coord: (i, j), layer, resolved, action })
+            },
+            Event::Release(i, j) if self.consumed == Some((i, j)) => {
+                self.consumed = None;
+                Explained::Release
+            },
+            Event::Release(..) => Explained::Pass(event),
+        }
+    }
+
+    /// Find the layer and action that handle key press on `layer`
+    pub fn resolve(&self, (i, j): (u8, u8), layer: usize) -> (usize, &'static Action<actions::Action>) {
+        let layers = self.layers;
+        let action = |layer: usize| layers.get(layer)
+            .and_then(|l| l.get(i as usize))
+            .and_then(|row| row.get(j as usize));
+        match action(layer) {
+            Some(Action::Trans) if layer != self.default_layer => match action(self.default_layer) {
+                Some(act) => (self.default_layer, act),
+                None => (layer, &Action::NoOp),
+            },
+            Some(act) => (layer, act),
+            None => (layer, &Action::NoOp),
+        }
+    }
+
+    fn track_default_layer(&mut self, action: &Action<actions::Action>) {
+        match action {
+            Action::DefaultLayer(l) => self.default_layer = *l,
+            Action::MultipleActions(acts) => acts.iter().for_each(|a| self.track_default_layer(a)),
+            _ => {},
+        }
+    }
+}
+
+impl Explanation {
+    /// Write explanation as text, e.g. "key 1,3: layer 2 > 0: KeyCode 4\n"
+    pub fn write_text<W: uWrite>(&self, text: &mut W) -> Result<(), W::Error> {
+        let (i, j) = self.coord;
+        uwrite!(text, "key {},{}: layer {}", i, j, self.layer)?;
+        if self.resolved != self.layer {
+            uwrite!(text, " > {}", self.resolved)?;
+        }
+        text.write_str(": ")?;
+        match self.action {
+            Action::NoOp => text.write_str("NoOp")?,
+            Action::Trans => text.write_str("Trans")?,
+            Action::KeyCode(kc) => uwrite!(text, "KeyCode {}", *kc as u8)?,
+            Action::MultipleKeyCodes(kcs) => {
+                text.write_str("KeyCodes")?;
+                for (n, kc) in kcs.iter().enumerate() {
+                    text.write_str(if n == 0 { " " } else { "+" })?;
+                    uwrite!(text, "{}", *kc as u8)?;
+                }
+            },
+            Action::MultipleActions(acts) => uwrite!(text, "MultipleActions {}", acts.len())?,
+            Action::Layer(l) => uwrite!(text, "Layer {}", l)?,
+            Action::DefaultLayer(l) => uwrite!(text, "DefaultLayer {}", l)?,
+            Action::HoldTap(_) => text.write_str("HoldTap")?,
+            Action::Custom(custom) => text.write_str(match custom {
+                actions::Action::Led(_) => "Custom Led",
+                actions::Action::Mouse(_) => "Custom Mouse",
+                actions::Action::Consumer(_) => "Custom Consumer",
+                actions::Action::Firmware(_) => "Custom Firmware",
+                actions::Action::Shortcut(_) => "Custom Shortcut",
+            })?,
+            _ => text.write_str("Unknown")?,
+        }
+        text.write_str("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keyberon::action::{k, l, d, HoldTapAction, HoldTapConfig};
+    use keyberon::key_code::KeyCode;
+    use super::*;
+
+    const HT: Action<actions::Action> = Action::HoldTap(&HoldTapAction {
+        timeout: 200,
+        hold: l(2),
+        tap: k(KeyCode::Space),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    });
+
+    const NO: Action<actions::Action> = Action::NoOp;
+    const ROW: [Action<actions::Action>; 2 * NCOLS] = [NO; 2 * NCOLS];
+    const LAYER: [[Action<actions::Action>; 2 * NCOLS]; NROWS] = [ROW; NROWS];
+
+    static LAYERS: Layers<{ 2 * NCOLS }, NROWS, 3, actions::Action> = {
+        let mut layers = [LAYER; 3];
+        layers[0][0][0] = k(KeyCode::A);
+        layers[0][0][1] = HT;
+        layers[0][1][0] = d(1);
+        layers[1][0][0] = k(KeyCode::B);
+        layers[1][1][0] = d(0);
+        layers[2][0][0] = Action::Trans;
+        layers
+    };
+
+    fn explain(explainer: &mut KeyExplainer<3>, coord: (u8, u8), layer: usize) -> heapless::String<64> {
+        explainer.arm();
+        let mut text = heapless::String::new();
+        match explainer.event(Event::Press(coord.0, coord.1), layer) {
+            Explained::Press(explanation) => explanation.write_text(&mut text).unwrap(),
+            _ => panic!("Key press not explained"),
+        }
+        assert!(matches!(explainer.event(Event::Release(coord.0, coord.1), layer), Explained::Release));
+        text
+    }
+
+    #[test]
+    fn pass_when_not_armed() {
+        let mut explainer = KeyExplainer::new(&LAYERS);
+        assert!(matches!(explainer.event(Event::Press(0, 0), 0), Explained::Pass(Event::Press(0, 0))));
+        assert!(matches!(explainer.event(Event::Release(0, 0), 0), Explained::Pass(Event::Release(0, 0))));
+    }
+
+    #[test]
+    fn explain_next_press_only() {
+        let mut explainer = KeyExplainer::new(&LAYERS);
+        explainer.arm();
+        assert!(explainer.is_armed());
+        // Key held before arming is released normally
+        assert!(matches!(explainer.event(Event::Release(3, 3), 0), Explained::Pass(_)));
+        assert!(matches!(explainer.event(Event::Press(0, 1), 0), Explained::Press(_)));
+        assert!(!explainer.is_armed());
+        assert!(matches!(explainer.event(Event::Press(0, 0), 0), Explained::Pass(_)));
+        assert!(matches!(explainer.event(Event::Release(0, 1), 0), Explained::Release));
+        assert!(matches!(explainer.event(Event::Release(0, 0), 0), Explained::Pass(_)));
+    }
+
+    #[test]
+    fn describe_actions() {
+        let mut explainer = KeyExplainer::new(&LAYERS);
+        assert_eq!(explain(&mut explainer, (0, 0), 0), "key 0,0: layer 0: KeyCode 4\n");
+        assert_eq!(explain(&mut explainer, (0, 1), 0), "key 0,1: layer 0: HoldTap\n");
+        assert_eq!(explain(&mut explainer, (1, 0), 0), "key 1,0: layer 0: DefaultLayer 1\n");
+        assert_eq!(explain(&mut explainer, (2, 0), 1), "key 2,0: layer 1: NoOp\n");
+    }
+
+    #[test]
+    fn transparent_to_default_layer() {
+        let mut explainer = KeyExplainer::new(&LAYERS);
+        assert_eq!(explain(&mut explainer, (0, 0), 2), "key 0,0: layer 2 > 0: KeyCode 4\n");
+
+        // Default layer changes only when not explaining
+        assert!(matches!(explainer.event(Event::Press(1, 0), 0), Explained::Pass(_)));
+        assert_eq!(explain(&mut explainer, (0, 0), 2), "key 0,0: layer 2 > 1: KeyCode 5\n");
+        assert_eq!(explain(&mut explainer, (1, 0), 1), "key 1,0: layer 1: DefaultLayer 0\n");
+        assert_eq!(explain(&mut explainer, (0, 0), 2), "key 0,0: layer 2 > 1: KeyCode 5\n");
+    }
+}
@@ -0,0 +1,152 @@
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Event, Layers};
+
+use crate::bsp::{NCOLS, NROWS};
+use super::actions;
+
+/// Latency-oriented mode for gaming
+///
+/// When enabled, hold-tap keys that tap a single key code send it immediately on press,
+/// bypassing hold-tap timing (so the key can also be held as a normal key), and GUI keys
+/// are blocked. Keys that are transparent on the current layer are left to the layout.
+/// Eager debouncing and static LED colors are handled by the keyboard and LED controller.
+pub struct GameMode<const L: usize> {
+    enabled: bool,
+    layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>,
+    /// Key codes of keys that are handled directly instead of by the layout
+    direct: [[Option<KeyCode>; 2 * NCOLS]; NROWS],
+}
+
+impl<const L: usize> GameMode<L> {
+    pub const fn new(layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>) -> Self {
+        Self {
+            enabled: false,
+            layers,
+            direct: [[None; 2 * NCOLS]; NROWS],
+        }
+    }
+
+    /// Use different layers, e.g. a copy that can be modified at runtime
+    pub fn set_layers(&mut self, layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>) {
+        self.layers = layers;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        defmt::info!("Game mode: {=bool}", enabled);
+        self.enabled = enabled;
+    }
+
+    /// Handle key event, returns the event if it should be passed to the layout
+    pub fn event(&mut self, event: Event, layer: usize) -> Option<Event> {
+        let (i, j) = event.coord();
+        let slot = match self.direct.get_mut(i as usize).and_then(|row| row.get_mut(j as usize)) {
+            Some(slot) => slot,
+            None => return Some(event),
+        };
+        match event {
+            Event::Press(..) if self.enabled => {
+                let tap = self.layers.get(layer)
+                    .and_then(|l| tap_key_code(&l[i as usize][j as usize]));
+                if let Some(kc) = tap {
+                    *slot = Some(kc);
+                    return None;
+                }
+            },
+            // Release keys handled directly even if game mode has been disabled in the meantime
+            Event::Release(..) if slot.take().is_some() => return None,
+            _ => {},
+        }
+        Some(event)
+    }
+
+    /// Modify key codes from layout to get the ones that should be reported
+    pub fn keycodes<'a>(&'a self, layout: impl Iterator<Item = KeyCode> + 'a) -> impl Iterator<Item = KeyCode> + 'a {
+        let direct = self.direct.iter().flatten().filter_map(|kc| *kc);
+        layout.chain(direct)
+            .filter(move |kc| !(self.enabled && matches!(kc, KeyCode::LGui | KeyCode::RGui)))
+    }
+}
+
+/// Key code tapped by a hold-tap action
+fn tap_key_code<T: 'static>(action: &Action<T>) -> Option<KeyCode> {
+    match action {
+        Action::HoldTap(ht) => match ht.tap {
+            Action::KeyCode(kc) => Some(kc),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use keyberon::action::{k, l, HoldTapAction, HoldTapConfig};
+    use super::*;
+
+    const HT: Action<actions::Action> = Action::HoldTap(&HoldTapAction {
+        timeout: 200,
+        hold: l(1),
+        tap: k(KeyCode::Space),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    });
+
+    const NO: Action<actions::Action> = Action::NoOp;
+    const ROW: [Action<actions::Action>; 2 * NCOLS] = [NO; 2 * NCOLS];
+    const LAYER: [[Action<actions::Action>; 2 * NCOLS]; NROWS] = [ROW; NROWS];
+
+    static LAYERS: Layers<{ 2 * NCOLS }, NROWS, 2, actions::Action> = {
+        let mut layers = [LAYER; 2];
+        layers[0][0][0] = k(KeyCode::LGui);
+        layers[0][0][1] = HT;
+        layers[1][0][1] = Action::Trans;
+        layers
+    };
+
+    fn keycodes(game: &GameMode<2>, layout: &[KeyCode]) -> Vec<KeyCode> {
+        game.keycodes(layout.iter().copied()).collect()
+    }
+
+    #[test]
+    fn disabled() {
+        let mut game = GameMode::new(&LAYERS);
+        assert_eq!(game.event(Event::Press(0, 1), 0), Some(Event::Press(0, 1)));
+        assert_eq!(game.event(Event::Release(0, 1), 0), Some(Event::Release(0, 1)));
+        assert_eq!(keycodes(&game, &[KeyCode::LGui, KeyCode::A]), [KeyCode::LGui, KeyCode::A]);
+    }
+
+    #[test]
+    fn hold_tap_immediate() {
+        let mut game = GameMode::new(&LAYERS);
+        game.set_enabled(true);
+        assert_eq!(game.event(Event::Press(0, 1), 0), None);
+        assert_eq!(keycodes(&game, &[KeyCode::A]), [KeyCode::A, KeyCode::Space]);
+        // Released after disabling
+        game.set_enabled(false);
+        assert_eq!(game.event(Event::Release(0, 1), 0), None);
+        assert_eq!(keycodes(&game, &[]), []);
+    }
+
+    #[test]
+    fn other_keys_passed() {
+        let mut game = GameMode::new(&LAYERS);
+        game.set_enabled(true);
+        assert_eq!(game.event(Event::Press(0, 0), 0), Some(Event::Press(0, 0)));
+        // Transparent on current layer
+        assert_eq!(game.event(Event::Press(0, 1), 1), Some(Event::Press(0, 1)));
+        assert_eq!(game.event(Event::Release(0, 1), 1), Some(Event::Release(0, 1)));
+    }
+
+    #[test]
+    fn gui_blocked() {
+        let mut game = GameMode::new(&LAYERS);
+        game.set_enabled(true);
+        assert_eq!(keycodes(&game, &[KeyCode::LGui, KeyCode::A, KeyCode::RGui]), [KeyCode::A]);
+    }
+}
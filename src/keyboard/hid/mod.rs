@@ -1,10 +1,14 @@
 mod keyboard;
+mod raw;
+mod repeat;
 
 use frunk::HList;
 use heapless::Deque;
 use usb_device::{UsbError, class_prelude::*};
 use usbd_human_interface_device::hid_class;
 
+use crate::utils::Watermark;
+
 pub use usbd_human_interface_device::device::{
     keyboard::BootKeyboardInterface as KeyboardInterface,
     keyboard::BootKeyboardReport as KeyboardReport,
@@ -15,6 +19,8 @@ pub use usbd_human_interface_device::device::{
 };
 
 pub use keyboard::{KeyboardLeds, KeyCodeIterExt};
+pub use raw::{RawHidClass, RawReport, REPORT_SIZE as RAW_REPORT_SIZE};
+pub use repeat::{AutoRepeat, AutoRepeatConfig, RepeatTiming, ConsumerRepeat, ConsumerRepeatConfig};
 
 pub type HidClass<'a, B> = hid_class::UsbHidClass<B,
     HList!(KeyboardInterface<'a, B>, ConsumerInterface<'a, B>, MouseInterface<'a, B>)>;
@@ -35,23 +41,46 @@ pub fn new_hid_class<B: UsbBus>(bus: &UsbBusAllocator<B>) -> HidClass<B> {
 /// number of missed reports.
 pub struct HidReportQueue<R, const N: usize> {
     queue: Deque<R, N>,  // push back, pop front
+    /// Sequence number of the next pushed report, reports in queue have consecutive numbers
+    next_seq: u32,
     missed: bool,
+    stats: QueueStats,
+    watermark: Watermark,
+}
+
+/// Counters of reports passing through [`HidReportQueue`]
+#[derive(Clone, Copy, Default, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct QueueStats {
+    /// Reports added to the queue
+    pub pushed: u32,
+    /// Reports accepted by the USB endpoint
+    pub sent: u32,
+    /// Reports overwritten because host did not poll fast enough
+    pub dropped: u32,
 }
 
 impl<R: PartialEq, const N: usize> HidReportQueue<R, N> {
     pub fn new() -> Self {
         Self {
             queue: Default::default(),
+            next_seq: 0,
             missed: false,
+            stats: QueueStats::default(),
+            watermark: Watermark::new(N),
         }
     }
 
     fn push_overwrite(&mut self, report: R) {
+        self.stats.pushed = self.stats.pushed.saturating_add(1);
         if self.queue.is_full() {
             self.queue.pop_front();
+            self.stats.dropped = self.stats.dropped.saturating_add(1);
         }
         self.queue.push_back(report)
             .map_err(drop).unwrap();
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.watermark.update(self.queue.len());
     }
 
     /// Push a report to queue if it changed
@@ -89,13 +118,16 @@ impl<R: PartialEq, const N: usize> HidReportQueue<R, N> {
     /// if it returns `OK(n)` with `n > 0`, which corresponds to standard endpoint write
     /// function. If it returns `Ok(0)` or `Err(UsbError::WouldBlock)` then we try later.
     ///
+    /// Returns sequence number (see [`Self::next_seq`]) of the report if it has been sent.
+    ///
     /// # Panics
     ///
     /// When `write_report` returns `Err` other than `UsbError::WouldBlock`, which means
     /// there is a bug in class implementation.
-    pub fn send<F>(&mut self, write_report: F)
+    pub fn send<F>(&mut self, write_report: F) -> Option<u32>
         where F: FnOnce(&R) -> Result<usize, UsbError>
     {
+        let seq = self.next_seq.wrapping_sub(self.queue.len() as u32);
         if let Some(report) = self.queue.front() {
             // Call to .write() will return Ok(0) if the previous report hasn't been sent yet,
             // else number of data written. Any other Err should never happen - would be
@@ -110,14 +142,48 @@ impl<R: PartialEq, const N: usize> HidReportQueue<R, N> {
             if ok {
                 // Consume the report on success
                 self.queue.pop_front().unwrap();
+                self.stats.sent = self.stats.sent.saturating_add(1);
+                return Some(seq);
             }
         }
+        None
+    }
+
+    /// Sequence number that will be assigned to the next pushed report
+    ///
+    /// Numbers identify reports independently of their contents, e.g. to find out when
+    /// the first report pushed after some event has been sent.
+    pub fn next_seq(&self) -> u32 {
+        self.next_seq
+    }
+
+    /// Check if all reports have been sent
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
     }
 
     /// Emtpy the report queue, to be called on USB disconnect/suspend
     pub fn clear(&mut self) {
         self.queue = Default::default();
     }
+
+    /// Report counters since creation or the last [`Self::reset_stats`]
+    pub fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = QueueStats::default();
+    }
+
+    /// Maximum number of queued reports since creation or the last [`Self::reset_watermark`]
+    pub fn watermark(&self) -> &Watermark {
+        &self.watermark
+    }
+
+    pub fn reset_watermark(&mut self) {
+        self.watermark.reset();
+    }
 }
 
 impl<R: PartialEq, const N: usize> Default for HidReportQueue<R, N> {
@@ -209,7 +275,9 @@ mod tests {
             Ok(1)
         };
 
-        reports.send(send_ok_handler);
+        // Oldest reports have been overwritten
+        assert_eq!(reports.next_seq(), 7);
+        assert_eq!(reports.send(send_ok_handler), Some(3));
         assert_eq!(sent.take(), Some(KbReport::new([A, B, C, D])));
         reports.send(send_ok_handler);
         assert_eq!(sent.take(), Some(KbReport::new([A, B, C])));
@@ -221,9 +289,26 @@ mod tests {
         reports.push(KbReport::new([A]));
         // This doesn't make sense? in case of empty queue we would add anyway?
 
-        reports.send(send_ok_handler);
+        assert_eq!(reports.send(send_ok_handler), Some(7));
         assert_eq!(sent.take(), Some(KbReport::new([A])));
-        reports.send(send_ok_handler);
+        assert_eq!(reports.send(send_ok_handler), None);
         assert_eq!(sent.take(), None);
     }
+
+    #[test]
+    fn queue_stats() {
+        let mut reports = HidReportQueue::<KbReport, 2>::default();
+        reports.push(KbReport::new([A]));
+        reports.push(KbReport::new([A]));
+        reports.push(KbReport::new([A, B]));
+        reports.push(KbReport::new([A, B, C]));
+        reports.send(|_| Ok(1));
+        reports.send(|_| Ok(0));
+        assert_eq!(reports.stats(), &QueueStats { pushed: 3, sent: 1, dropped: 1 });
+        assert_eq!(reports.watermark().max(), 2);
+        reports.reset_stats();
+        assert_eq!(reports.stats(), &QueueStats::default());
+        reports.reset_watermark();
+        assert_eq!(reports.watermark().max(), 0);
+    }
 }
@@ -0,0 +1,126 @@
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+
+/// Size of raw HID reports in both directions
+pub const REPORT_SIZE: usize = 32;
+
+/// Report exchanged with host software, first byte identifies the command
+pub type RawReport = [u8; REPORT_SIZE];
+
+const USB_CLASS_HID: u8 = 0x03;
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+const REQUEST_SET_IDLE: u8 = 0x0a;
+
+/// Vendor-defined usage page with 32 byte input and output reports (same as QMK raw HID)
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xff, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61, //       Usage (0x61)
+    0xa1, 0x01, //       Collection (Application)
+    0x09, 0x62, //         Usage (0x62)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x95, REPORT_SIZE as u8, // Report Count
+    0x75, 0x08, //         Report Size (8)
+    0x81, 0x02, //         Input (Data, Var, Abs)
+    0x09, 0x63, //         Usage (0x63)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x95, REPORT_SIZE as u8, // Report Count
+    0x75, 0x08, //         Report Size (8)
+    0x91, 0x02, //         Output (Data, Var, Abs)
+    0xc0, //             End Collection
+];
+
+/// HID 1.11, no country code, 1 report descriptor
+const HID_DESCRIPTOR: [u8; 7] = [
+    0x11, 0x01, 0x00, 0x01, DESCRIPTOR_REPORT,
+    REPORT_DESCRIPTOR.len() as u8, (REPORT_DESCRIPTOR.len() >> 8) as u8,
+];
+
+/// Generic HID interface for communication with host software
+///
+/// Host writes fixed-size output reports (e.g. with hidapi) that are stored until taken
+/// by keyboard logic, some commands are answered with input reports. Only the last report
+/// is kept, so host software should not send reports faster than keyboard ticks. Unlike
+/// other HID interfaces this is not handled by the OS, so it does not need the host to
+/// install any drivers.
+pub struct RawHidClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+    report: Option<RawReport>,
+}
+
+impl<'a, B: UsbBus> RawHidClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            iface: alloc.interface(),
+            ep_in: alloc.interrupt(REPORT_SIZE as u16, 1),
+            ep_out: alloc.interrupt(REPORT_SIZE as u16, 1),
+            report: None,
+        }
+    }
+
+    /// Take the last report received from host
+    pub fn take_report(&mut self) -> Option<RawReport> {
+        self.report.take()
+    }
+
+    /// Send report to host, fails with `WouldBlock` if the previous one has not been read yet
+    pub fn write_report(&mut self, report: &RawReport) -> usb_device::Result<usize> {
+        self.ep_in.write(report)
+    }
+
+    fn is_our_interface(&self, req: &Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == u8::from(self.iface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for RawHidClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.iface, USB_CLASS_HID, 0x00, 0x00)?;
+        writer.write(DESCRIPTOR_HID, &HID_DESCRIPTOR)?;
+        writer.endpoint(&self.ep_in)?;
+        writer.endpoint(&self.ep_out)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_our_interface(&req)
+            || req.request_type != RequestType::Standard
+            || req.request != Request::GET_DESCRIPTOR {
+            return;
+        }
+        match (req.value >> 8) as u8 {
+            DESCRIPTOR_REPORT => xfer.accept_with_static(REPORT_DESCRIPTOR).ok(),
+            DESCRIPTOR_HID => xfer.accept_with_static(&HID_DESCRIPTOR).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_our_interface(&req) || req.request_type != RequestType::Class {
+            return;
+        }
+        // Some hosts set idle rate on all HID interfaces, but we never send periodic reports
+        match req.request {
+            REQUEST_SET_IDLE => xfer.accept().ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_out.address() {
+            let mut report = [0; REPORT_SIZE];
+            // Shorter reports are padded with zeros
+            if let Ok(n) = self.ep_out.read(&mut report) {
+                if n > 0 {
+                    self.report = Some(report);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use keyberon::key_code::KeyCode;
+
+use crate::keyboard::actions::ConsumerKey;
+use crate::keyboard::mouse::SpeedProfile;
+
+/// Configuration of firmware key auto-repeat
+#[derive(Clone, Copy)]
+pub struct AutoRepeatConfig {
+    /// Auto-repeat is disabled by default as hosts usually handle it on their own
+    pub enabled: bool,
+    /// Timing for navigation and editing keys (arrows, page up/down, backspace, ...)
+    pub navigation: RepeatTiming,
+    /// Timing for all other keys except modifiers and lock keys
+    pub default: RepeatTiming,
+}
+
+/// Auto-repeat timing in keyboard ticks
+#[derive(Clone, Copy)]
+pub struct RepeatTiming {
+    /// Time from key press to the first repetition
+    pub delay: u16,
+    /// Time between subsequent repetitions, 0 disables repeating for given key class
+    pub period: u16,
+}
+
+/// Configuration of accelerated repetition of held consumer keys
+#[derive(Clone, Copy)]
+pub struct ConsumerRepeatConfig {
+    /// Keys that are repeated while held (e.g. volume up/down), empty disables repetition
+    pub keys: &'static [ConsumerKey],
+    /// Repetition rate, a key is repeated each time accumulated speed reaches the divider
+    pub profile: &'static SpeedProfile,
+}
+
+/// Key classes with separate auto-repeat timing
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+enum KeyClass {
+    /// Modifiers and lock keys are never repeated
+    NoRepeat,
+    Navigation,
+    Other,
+}
+
+/// Schedules additional keyboard reports that emulate key repetition
+///
+/// Like typematic on hosts, only the last pressed key is repeated. Each repetition is
+/// performed by sending a report with that key released, followed by the normal report.
+pub struct AutoRepeat {
+    config: AutoRepeatConfig,
+    /// Currently repeated key and number of ticks since it has been pressed
+    key: Option<(KeyCode, u32)>,
+    /// Bitmask of key codes active during previous tick
+    prev_keycodes: [u32; 8],
+}
+
+/// Repeats held consumer keys, the longer a key is held the faster it is repeated
+pub struct ConsumerRepeat {
+    config: ConsumerRepeatConfig,
+    key: Option<ConsumerKey>,
+    /// Number of ticks since the key has been pressed
+    time: u16,
+    accumulated: u32,
+}
+
+impl KeyClass {
+    fn of(kc: KeyCode) -> Self {
+        use KeyCode::*;
+        match kc {
+            LCtrl | LShift | LAlt | LGui | RCtrl | RShift | RAlt | RGui
+                | CapsLock | NumLock | ScrollLock | No => Self::NoRepeat,
+            Up | Down | Left | Right | PgUp | PgDown | Home | End | BSpace | Delete => Self::Navigation,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl AutoRepeat {
+    pub const fn new(config: AutoRepeatConfig) -> Self {
+        Self { config, key: None, prev_keycodes: [0; 8] }
+    }
+
+    fn timing(&self, kc: KeyCode) -> Option<&RepeatTiming> {
+        match KeyClass::of(kc) {
+            KeyClass::NoRepeat => None,
+            KeyClass::Navigation => Some(&self.config.navigation),
+            KeyClass::Other => Some(&self.config.default),
+        }
+    }
+
+    /// Advance time, returns key code that should be released in an additional report
+    pub fn tick(&mut self, keycodes: impl Iterator<Item = KeyCode>) -> Option<KeyCode> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let mut current = [0u32; 8];
+        let mut pressed = None;
+        for kc in keycodes {
+            let (word, bit) = (kc as usize / 32, kc as u8 % 32);
+            current[word] |= 1 << bit;
+            if self.prev_keycodes[word] & (1 << bit) == 0 && self.timing(kc).is_some() {
+                pressed = Some(kc);
+            }
+        }
+        self.prev_keycodes = current;
+
+        if let Some(kc) = pressed {
+            self.key = Some((kc, 0));
+        }
+
+        let (kc, time) = self.key.as_mut()?;
+        let kc = *kc;
+        if current[kc as usize / 32] & (1 << (kc as u8 % 32)) == 0 {
+            self.key = None;
+            return None;
+        }
+
+        *time = time.saturating_add(1);
+        let time = *time;
+        let timing = self.timing(kc)?;
+        let (delay, period) = (timing.delay as u32, timing.period as u32);
+        (period != 0 && time >= delay && (time - delay) % period == 0).then_some(kc)
+    }
+}
+
+impl ConsumerRepeat {
+    pub const fn new(config: ConsumerRepeatConfig) -> Self {
+        Self { config, key: None, time: 0, accumulated: 0 }
+    }
+
+    /// Update state of a consumer key, only the last pressed key is repeated
+    pub fn set_key(&mut self, key: ConsumerKey, pressed: bool) {
+        if pressed && self.config.keys.contains(&key) {
+            self.key = Some(key);
+            self.time = 0;
+            self.accumulated = 0;
+        } else if !pressed && self.key == Some(key) {
+            self.key = None;
+        }
+    }
+
+    /// Advance time, returns key that should be released and pressed again
+    pub fn tick(&mut self) -> Option<ConsumerKey> {
+        let key = self.key?;
+        let speed = self.config.profile.get_speed(self.time);
+        self.time = self.time.saturating_add(1);
+        self.accumulated += speed as u32;
+        let divider = self.config.profile.divider.max(1) as u32;
+        (self.accumulated >= divider).then(|| {
+            self.accumulated %= divider;
+            key
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const CONFIG: AutoRepeatConfig = AutoRepeatConfig {
+        enabled: true,
+        navigation: RepeatTiming { delay: 5, period: 2 },
+        default: RepeatTiming { delay: 10, period: 3 },
+    };
+
+    /// Run for given number of ticks returning ticks at which keys were repeated
+    fn run(repeat: &mut AutoRepeat, keys: &[KeyCode], ticks: u32) -> Vec<(u32, KeyCode)> {
+        (1..=ticks)
+            .filter_map(|t| repeat.tick(keys.iter().copied()).map(|kc| (t, kc)))
+            .collect()
+    }
+
+    #[test]
+    fn classify_keys() {
+        assert_eq!(KeyClass::of(KeyCode::LShift), KeyClass::NoRepeat);
+        assert_eq!(KeyClass::of(KeyCode::CapsLock), KeyClass::NoRepeat);
+        assert_eq!(KeyClass::of(KeyCode::Left), KeyClass::Navigation);
+        assert_eq!(KeyClass::of(KeyCode::A), KeyClass::Other);
+    }
+
+    #[test]
+    fn repeat_after_delay() {
+        let mut repeat = AutoRepeat::new(CONFIG);
+        let a = KeyCode::A;
+        assert_eq!(run(&mut repeat, &[a], 16), [(10, a), (13, a), (16, a)]);
+        assert_eq!(run(&mut repeat, &[], 5), []);
+        // Timing restarts after release
+        assert_eq!(run(&mut repeat, &[a], 10), [(10, a)]);
+    }
+
+    #[test]
+    fn navigation_timing() {
+        let mut repeat = AutoRepeat::new(CONFIG);
+        assert_eq!(run(&mut repeat, &[KeyCode::Up], 9), [(5, KeyCode::Up), (7, KeyCode::Up), (9, KeyCode::Up)]);
+    }
+
+    #[test]
+    fn only_last_pressed_key_repeats() {
+        let mut repeat = AutoRepeat::new(CONFIG);
+        assert_eq!(run(&mut repeat, &[KeyCode::A], 5), []);
+        // Modifiers do not interrupt repetition
+        assert_eq!(run(&mut repeat, &[KeyCode::LShift, KeyCode::A], 5), [(5, KeyCode::A)]);
+        assert_eq!(run(&mut repeat, &[KeyCode::LShift, KeyCode::A, KeyCode::B], 10), [(10, KeyCode::B)]);
+        // Releasing the repeated key stops repetition even when other keys are held
+        assert_eq!(run(&mut repeat, &[KeyCode::A], 20), []);
+    }
+
+    #[test]
+    fn consumer_acceleration() {
+        static PROFILE: SpeedProfile = SpeedProfile {
+            divider: 10,
+            delay: 5,
+            acceleration_time: 10,
+            start_speed: 2,
+            max_speed: 10,
+        };
+        let mut repeat = ConsumerRepeat::new(ConsumerRepeatConfig {
+            keys: &[ConsumerKey::VolumeIncrement],
+            profile: &PROFILE,
+        });
+        let run = |repeat: &mut ConsumerRepeat, ticks: u32| -> Vec<u32> {
+            (1..=ticks).filter(|_| repeat.tick().is_some()).collect()
+        };
+        assert_eq!(run(&mut repeat, 10), []);
+
+        repeat.set_key(ConsumerKey::PlayPause, true);
+        assert_eq!(run(&mut repeat, 10), []);
+
+        // Intervals between repetitions decrease until maximum speed is reached
+        repeat.set_key(ConsumerKey::VolumeIncrement, true);
+        assert_eq!(run(&mut repeat, 18), [9, 11, 13, 14, 15, 16, 17, 18]);
+
+        repeat.set_key(ConsumerKey::VolumeIncrement, false);
+        assert_eq!(run(&mut repeat, 10), []);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut repeat = AutoRepeat::new(AutoRepeatConfig { enabled: false, ..CONFIG });
+        assert_eq!(run(&mut repeat, &[KeyCode::A], 100), []);
+    }
+}
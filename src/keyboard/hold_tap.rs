@@ -0,0 +1,399 @@
+use defmt::Format;
+use keyberon::action::{Action, HoldTapAction};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Event, Layers};
+
+use super::actions;
+
+/// Maximum depth of nested hold-taps that is traced
+const MAX_DEPTH: u8 = 4;
+/// Ticks after release of the traced key to wait for an observable effect
+const RELEASE_GRACE_TICKS: u32 = 10;
+
+/// Traces how hold-tap presses of a single selected key are resolved
+///
+/// Layout does not expose its waiting state, so decisions are observed in its output: a hold-tap
+/// is resolved when the layout starts producing the effect of either its hold or its tap action
+/// (key codes, layer change or custom action). This works the same for any [`HoldTapConfig`]
+/// and for hold-taps nested in other hold-taps. Key events at the moment of decision are only
+/// reported as the likely reason. Branches without observable effect (e.g. `NoOp`) cannot be
+/// traced. Disabled by default, as logging every key press would flood the debug output.
+///
+/// [`HoldTapConfig`]: keyberon::action::HoldTapConfig
+pub struct HoldTapTrace {
+    key: Option<(u8, u8)>,
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    start: u32,
+    action: &'static HoldTapAction<actions::Action>,
+    /// Layer at the time of press, layer actions are only observable as a change
+    layer: usize,
+    /// Time of release of the traced key
+    released: Option<u32>,
+    /// Latest event of other key and its time
+    other: Option<(Event, u32)>,
+}
+
+/// Output of the layout after a tick
+pub struct LayoutOutput<F: Fn(KeyCode) -> bool> {
+    /// Check if key code is currently sent
+    pub pressed: F,
+    pub layer: usize,
+    /// Custom action pressed during the tick
+    pub custom: Option<&'static actions::Action>,
+}
+
+/// Resolved press of the traced key
+#[derive(Clone, Copy, PartialEq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Decision {
+    pub path: Path,
+    pub reason: Reason,
+    /// Ticks from key press to the decision
+    pub elapsed: u32,
+    pub timeout: u16,
+}
+
+/// Hold or tap chosen by the hold-tap and by the hold-taps nested in the chosen actions
+#[derive(Clone, Copy, PartialEq, Default)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Path {
+    holds: u8,
+    len: u8,
+}
+
+/// Key event that coincided with the decision
+#[derive(Clone, Copy, PartialEq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Reason {
+    /// Traced key released
+    Released,
+    /// Other key pressed
+    OtherPress((u8, u8)),
+    /// Other key released
+    OtherRelease((u8, u8)),
+    Timeout,
+    /// Resolved right on press, e.g. pressed again within `tap_hold_interval` after a tap
+    Immediate,
+    /// None of the above, e.g. decision of a custom [`HoldTapConfig`](keyberon::action::HoldTapConfig)
+    Other,
+}
+
+impl HoldTapTrace {
+    pub const fn new() -> Self {
+        Self { key: None, pending: None }
+    }
+
+    /// Select key (global coordinates) to be traced, `None` disables tracing
+    pub fn set_key(&mut self, key: Option<(u8, u8)>) {
+        match key {
+            Some((i, j)) => defmt::info!("Tracing hold-tap of key ({=u8}, {=u8})", i, j),
+            None => defmt::info!("Hold-tap trace disabled"),
+        }
+        *self = Self { key, ..Self::new() };
+    }
+
+    pub fn key(&self) -> Option<(u8, u8)> {
+        self.key
+    }
+
+    /// Forget the pending hold-tap, e.g. when the layers it comes from are replaced
+    pub fn clear_pending(&mut self) {
+        self.pending = None;
+    }
+
+    /// Handle key event passed to the layout at `time`, `action` of the traced key is pressed on `layer`
+    pub fn event(&mut self, event: Event, action: &'static Action<actions::Action>, layer: usize, time: u32) {
+        let Some(key) = self.key else {
+            return;
+        };
+        match (event, self.pending.as_mut()) {
+            (Event::Press(i, j), None) if (i, j) == key => {
+                if let Action::HoldTap(action) = action {
+                    self.pending = Some(Pending { start: time, action, layer, released: None, other: None });
+                }
+            },
+            (Event::Release(i, j), Some(pending)) if (i, j) == key => pending.released = Some(time),
+            (_, Some(pending)) if event.coord() != key => pending.other = Some((event, time)),
+            _ => {},
+        }
+    }
+
+    /// Check layout output after each layout tick
+    pub fn tick<F: Fn(KeyCode) -> bool>(&mut self, output: &LayoutOutput<F>, time: u32) -> Option<Decision> {
+        let (i, j) = self.key?;
+        let pending = self.pending.as_ref()?;
+        let decision = match resolve(pending.action, output, pending.layer, Path::default()) {
+            Some(path) => Decision {
+                path,
+                reason: pending.reason(time),
+                elapsed: time.wrapping_sub(pending.start),
+                timeout: pending.action.timeout,
+            },
+            None => {
+                if pending.released.map_or(false, |t| time.wrapping_sub(t) >= RELEASE_GRACE_TICKS) {
+                    defmt::info!("Hold-tap ({=u8}, {=u8}): no observable effect", i, j);
+                    self.pending = None;
+                }
+                return None;
+            },
+        };
+        self.pending = None;
+        defmt::info!("Hold-tap ({=u8}, {=u8}): {}", i, j, decision);
+        Some(decision)
+    }
+}
+
+impl Pending {
+    fn reason(&self, time: u32) -> Reason {
+        let elapsed = time.wrapping_sub(self.start);
+        match self.other {
+            _ if self.released.is_some() => Reason::Released,
+            Some((Event::Press(i, j), t)) if t == time => Reason::OtherPress((i, j)),
+            Some((Event::Release(i, j), t)) if t == time => Reason::OtherRelease((i, j)),
+            _ if elapsed >= self.action.timeout as u32 => Reason::Timeout,
+            _ if elapsed == 0 => Reason::Immediate,
+            _ => Reason::Other,
+        }
+    }
+}
+
+impl Path {
+    fn push(self, hold: bool) -> Self {
+        Self { holds: self.holds | (hold as u8) << self.len, len: self.len + 1 }
+    }
+
+    /// Decisions starting from the outermost hold-tap, `true` for hold
+    pub fn iter(self) -> impl Iterator<Item = bool> {
+        (0..self.len).map(move |i| self.holds & (1 << i) != 0)
+    }
+}
+
+impl Format for Path {
+    fn format(&self, f: defmt::Formatter) {
+        for (n, hold) in self.iter().enumerate() {
+            if n != 0 {
+                defmt::write!(f, " > ");
+            }
+            defmt::write!(f, "{=str}", if hold { "hold" } else { "tap" });
+        }
+    }
+}
+
+/// Action of a key on `layer` as used for tracing
+///
+/// Transparent keys use layer 0, which is the default layer unless changed by `DefaultLayer`.
+pub fn layer_action<const C: usize, const R: usize, const L: usize>(
+    layers: &'static Layers<C, R, L, actions::Action>,
+    layer: usize,
+    (i, j): (u8, u8),
+) -> &'static Action<actions::Action> {
+    let action = |layer: usize| layers.get(layer)
+        .and_then(|l| l.get(i as usize))
+        .and_then(|row| row.get(j as usize));
+    match action(layer) {
+        Some(Action::Trans) => action(0),
+        action => action,
+    }.unwrap_or(&Action::NoOp)
+}
+
+/// Find which branch of a hold-tap the layout output shows, descending into nested hold-taps
+fn resolve<F>(action: &HoldTapAction<actions::Action>, output: &LayoutOutput<F>, layer: usize, path: Path) -> Option<Path>
+where
+    F: Fn(KeyCode) -> bool,
+{
+    if path.len >= MAX_DEPTH {
+        return Some(path);
+    }
+    let (hold, branch) = match (is_active(&action.hold, output, layer), is_active(&action.tap, output, layer)) {
+        (true, false) => (true, &action.hold),
+        (false, true) => (false, &action.tap),
+        _ => return None,
+    };
+    match branch {
+        Action::HoldTap(nested) => resolve(nested, output, layer, path.push(hold)),
+        _ => Some(path.push(hold)),
+    }
+}
+
+/// Check if the effect of `action` is visible in the layout output
+fn is_active<F>(action: &Action<actions::Action>, output: &LayoutOutput<F>, layer: usize) -> bool
+where
+    F: Fn(KeyCode) -> bool,
+{
+    match action {
+        Action::KeyCode(kc) => (output.pressed)(*kc),
+        Action::MultipleKeyCodes(kcs) => !kcs.is_empty() && kcs.iter().all(|kc| (output.pressed)(*kc)),
+        Action::MultipleActions(actions) => actions.iter().any(|a| is_active(a, output, layer)),
+        Action::Layer(l) | Action::DefaultLayer(l) => output.layer == *l && layer != *l,
+        Action::HoldTap(ht) => is_active(&ht.hold, output, layer) || is_active(&ht.tap, output, layer),
+        Action::Custom(custom) => output.custom.map_or(false, |pressed| core::ptr::eq(pressed, custom)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use keyberon::action::{k, l, HoldTapConfig};
+    use keyberon::layout::{CustomEvent, Layout};
+    use super::*;
+
+    macro_rules! hold_tap {
+        ($config:ident, $interval:literal, $hold:expr, $tap:expr) => {
+            Action::HoldTap(&HoldTapAction {
+                timeout: 200,
+                hold: $hold,
+                tap: $tap,
+                config: HoldTapConfig::$config,
+                tap_hold_interval: $interval,
+            })
+        };
+    }
+
+    const DEFAULT: Action<actions::Action> = hold_tap!(Default, 100, l(1), k(KeyCode::Space));
+    const ON_PRESS: Action<actions::Action> = hold_tap!(HoldOnOtherKeyPress, 0, l(1), k(KeyCode::Space));
+    const PERMISSIVE: Action<actions::Action> = hold_tap!(PermissiveHold, 0, k(KeyCode::LShift), k(KeyCode::Space));
+    const NESTED: Action<actions::Action> = hold_tap!(Default, 0,
+        hold_tap!(Default, 0, k(KeyCode::LCtrl), k(KeyCode::C)),
+        hold_tap!(Default, 0, k(KeyCode::LAlt), k(KeyCode::B)));
+    const CUSTOM: Action<actions::Action> = hold_tap!(Default, 0,
+        Action::Custom(actions::Action::Mouse(actions::MouseAction::Precision)), k(KeyCode::Space));
+
+    static LAYERS: Layers<6, 1, 2, actions::Action> = [
+        [[DEFAULT, ON_PRESS, PERMISSIVE, NESTED, CUSTOM, k(KeyCode::A)]],
+        [[Action::Trans, Action::Trans, Action::Trans, Action::Trans, Action::Trans, k(KeyCode::B)]],
+    ];
+
+    /// Layout with the trace fed the same way as by the keyboard
+    struct Traced {
+        layout: Layout<6, 1, 2, actions::Action>,
+        trace: HoldTapTrace,
+        time: u32,
+    }
+
+    impl Traced {
+        fn new(key: u8) -> Self {
+            let mut trace = HoldTapTrace::new();
+            trace.set_key(Some((0, key)));
+            Self { layout: Layout::new(&LAYERS), trace, time: 0 }
+        }
+
+        /// Pass optional event and run a single tick
+        fn step(&mut self, event: Option<Event>) -> Option<Decision> {
+            if let Some(event) = event {
+                let layer = self.layout.current_layer();
+                self.trace.event(event, layer_action(&LAYERS, layer, event.coord()), layer, self.time);
+                self.layout.event(event);
+            }
+            let custom = match self.layout.tick() {
+                CustomEvent::Press(action) => Some(action),
+                _ => None,
+            };
+            let layout = &self.layout;
+            let output = LayoutOutput {
+                pressed: |kc| layout.keycodes().any(|k| k == kc),
+                layer: layout.current_layer(),
+                custom,
+            };
+            let decision = self.trace.tick(&output, self.time);
+            self.time += 1;
+            decision
+        }
+
+        fn press(&mut self, j: u8) -> Option<Decision> {
+            self.step(Some(Event::Press(0, j)))
+        }
+
+        fn release(&mut self, j: u8) -> Option<Decision> {
+            self.step(Some(Event::Release(0, j)))
+        }
+
+        /// Run until the first decision
+        fn run(&mut self, ticks: u32) -> Option<Decision> {
+            (0..ticks).find_map(|_| self.step(None))
+        }
+    }
+
+    fn path(decision: Option<Decision>) -> Vec<bool> {
+        decision.unwrap().path.iter().collect()
+    }
+
+    #[test]
+    fn disabled() {
+        let mut traced = Traced::new(0);
+        traced.trace.set_key(None);
+        assert_eq!(traced.press(0), None);
+        assert_eq!(traced.run(300), None);
+    }
+
+    #[test]
+    fn tap_and_timeout() {
+        let mut traced = Traced::new(0);
+        assert_eq!(traced.press(0), None);
+        assert_eq!(traced.run(50), None);
+        let tap = traced.release(0).unwrap();
+        assert_eq!((path(Some(tap)), tap.reason, tap.elapsed), (vec![false], Reason::Released, 51));
+        traced.run(5);
+        // Tapping again soon does not wait for the timeout
+        let tap = traced.press(0).unwrap();
+        assert_eq!((path(Some(tap)), tap.reason, tap.elapsed), (vec![false], Reason::Immediate, 0));
+        traced.release(0);
+
+        traced.run(300);
+        assert_eq!(traced.press(0), None);
+        // Default config ignores other keys
+        assert_eq!(traced.press(5), None);
+        assert_eq!(traced.release(5), None);
+        let hold = traced.run(300).unwrap();
+        assert_eq!((path(Some(hold)), hold.reason, hold.timeout), (vec![true], Reason::Timeout, 200));
+        assert!(hold.elapsed >= 200 && hold.elapsed <= 202, "{}", hold.elapsed);
+        assert_eq!(traced.release(0), None);
+    }
+
+    #[test]
+    fn other_keys() {
+        let mut traced = Traced::new(1);
+        assert_eq!(traced.press(1), None);
+        let hold = traced.press(5).unwrap();
+        assert_eq!((path(Some(hold)), hold.reason), (vec![true], Reason::OtherPress((0, 5))));
+
+        let mut traced = Traced::new(2);
+        assert_eq!(traced.press(2), None);
+        assert_eq!(traced.press(5), None);
+        let hold = traced.release(5).unwrap();
+        assert_eq!((path(Some(hold)), hold.reason), (vec![true], Reason::OtherRelease((0, 5))));
+    }
+
+    #[test]
+    fn nested_hold_taps() {
+        let mut traced = Traced::new(3);
+        traced.press(3);
+        traced.run(10);
+        assert_eq!(path(traced.release(3)), [false, false]);
+
+        traced.run(10);
+        traced.press(3);
+        // Nested hold-tap starts waiting after the outer one resolves
+        let hold = traced.run(500).unwrap();
+        assert_eq!((path(Some(hold)), hold.reason), (vec![true, true], Reason::Timeout));
+        assert!(hold.elapsed >= 400, "{}", hold.elapsed);
+    }
+
+    #[test]
+    fn custom_action() {
+        let mut traced = Traced::new(4);
+        traced.press(4);
+        assert_eq!(path(traced.run(300)), [true]);
+    }
+
+    #[test]
+    fn transparent_key() {
+        assert!(matches!(layer_action(&LAYERS, 1, (0, 0)), Action::HoldTap(_)));
+        assert!(matches!(layer_action(&LAYERS, 1, (0, 5)), Action::KeyCode(KeyCode::B)));
+        assert!(matches!(layer_action(&LAYERS, 1, (1, 0)), Action::NoOp));
+    }
+}
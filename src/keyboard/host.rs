@@ -0,0 +1,100 @@
+use usb_device::UsbError;
+use usb_device::device::UsbDeviceState;
+use usbd_human_interface_device::UsbHidError;
+
+use super::hid;
+use super::power::Vbus;
+use super::ticks::Millis;
+
+/// USB device functionality used by keyboard logic
+///
+/// Implemented by [`crate::bsp::usb::Usb`]. Keyboard logic only depends on this trait, so that
+/// it can be run against a fake USB device off-hardware.
+pub trait UsbHost {
+    /// Current USB device state
+    fn state(&self) -> UsbDeviceState;
+
+    /// Sample presence of 5 V on the USB connector, call once per tick for debouncing
+    fn vbus(&mut self) -> Vbus;
+
+    /// Keyboard LEDs state as set by the host
+    fn keyboard_leds(&self) -> hid::KeyboardLeds;
+
+    /// Check if jumping to DFU bootloader is currently allowed
+    fn bootloader_allowed(&self) -> bool;
+
+    /// Allow jumping to DFU bootloader
+    fn allow_bootloader(&mut self);
+
+    /// Reboot the MCU, optionally to DFU bootloader
+    fn reboot(&mut self, bootloader: bool);
+
+    /// Start remote wake up signalling lasting `duration` if `wake_up` is set and it is not
+    /// active yet, else end it after given time; call every tick with `elapsed` time
+    fn wake_up_update(&mut self, wake_up: bool, duration: Millis, elapsed: Millis);
+
+    /// Advance time of HID keyboard interface by 1 ms
+    fn hid_tick(&mut self);
+
+    /// Write keyboard report, same semantics as [`hid::KeyboardInterface::write_report`]
+    fn write_keyboard_report(&mut self, report: &hid::KeyboardReport) -> Result<(), UsbHidError>;
+
+    /// Write consumer report, same semantics as [`hid::ConsumerInterface::write_report`]
+    fn write_consumer_report(&mut self, report: &hid::ConsumerReport) -> Result<usize, UsbError>;
+
+    /// Write mouse report, same semantics as [`hid::MouseInterface::write_report`]
+    fn write_mouse_report(&mut self, report: &hid::MouseReport) -> Result<(), UsbHidError>;
+
+    /// Take the last report written by host software to raw HID interface
+    fn read_raw_report(&mut self) -> Option<hid::RawReport>;
+}
+
+impl<H: UsbHost + ?Sized> UsbHost for &mut H {
+    fn state(&self) -> UsbDeviceState {
+        (**self).state()
+    }
+
+    fn vbus(&mut self) -> Vbus {
+        (**self).vbus()
+    }
+
+    fn keyboard_leds(&self) -> hid::KeyboardLeds {
+        (**self).keyboard_leds()
+    }
+
+    fn bootloader_allowed(&self) -> bool {
+        (**self).bootloader_allowed()
+    }
+
+    fn allow_bootloader(&mut self) {
+        (**self).allow_bootloader()
+    }
+
+    fn reboot(&mut self, bootloader: bool) {
+        (**self).reboot(bootloader)
+    }
+
+    fn wake_up_update(&mut self, wake_up: bool, duration: Millis, elapsed: Millis) {
+        (**self).wake_up_update(wake_up, duration, elapsed)
+    }
+
+    fn hid_tick(&mut self) {
+        (**self).hid_tick()
+    }
+
+    fn write_keyboard_report(&mut self, report: &hid::KeyboardReport) -> Result<(), UsbHidError> {
+        (**self).write_keyboard_report(report)
+    }
+
+    fn write_consumer_report(&mut self, report: &hid::ConsumerReport) -> Result<usize, UsbError> {
+        (**self).write_consumer_report(report)
+    }
+
+    fn write_mouse_report(&mut self, report: &hid::MouseReport) -> Result<(), UsbHidError> {
+        (**self).write_mouse_report(report)
+    }
+
+    fn read_raw_report(&mut self) -> Option<hid::RawReport> {
+        (**self).read_raw_report()
+    }
+}
@@ -0,0 +1,40 @@
+use crate::bsp::persistent::Persistent;
+
+#[link_section = ".uninit.ghanima.joystick"]
+static ENABLED: Persistent<bool, 0x6a6f_7930> = Persistent::new();
+
+#[link_section = ".uninit.ghanima.joystick"]
+static DIVIDER: Persistent<u16, 0x6a64_0000> = Persistent::new();
+
+/// Joystick enabled state from before the last software reset, enabled after power loss
+pub fn load() -> bool {
+    ENABLED.load().unwrap_or(true)
+}
+
+/// Preserve joystick enabled state over system reset
+pub fn store(enabled: bool) {
+    ENABLED.store(enabled);
+}
+
+/// Joystick divider changed at runtime before the last software reset, if any
+pub fn load_divider() -> Option<u16> {
+    DIVIDER.load().filter(|d| *d != 0)
+}
+
+/// Preserve joystick divider over system reset
+pub fn store_divider(divider: u16) {
+    DIVIDER.store(divider);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider_over_reset() {
+        store_divider(37);
+        assert_eq!(load_divider(), Some(37));
+        store_divider(0);
+        assert_eq!(load_divider(), None);
+    }
+}
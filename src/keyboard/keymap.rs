@@ -0,0 +1,549 @@
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use serde::{Serialize, Deserialize};
+use postcard::experimental::max_size::MaxSize;
+
+use crate::bsp::{NCOLS, NROWS};
+use super::actions;
+use super::hid::{RawReport, RAW_REPORT_SIZE};
+use super::leds::KeyActionCache;
+
+/// Raw HID command that reads or modifies the keymap
+pub const CMD_KEYMAP: u8 = 0x04;
+
+/// Encoding of actions that cannot be transferred, such as hold-tap or custom actions
+const KIND_OTHER: u8 = 0xff;
+
+pub type Layers<const L: usize> = keyberon::layout::Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>;
+
+/// Convert USB HID usage to key code, `None` for values that keyberon does not define
+pub fn key_code(code: u8) -> Option<KeyCode> {
+    match code {
+        // SAFETY: KeyCode is repr(u8) with consecutive values in these ranges (see blob tests)
+        0x00..=0xa4 | 0xe0..=0xfb => Some(unsafe { core::mem::transmute::<u8, KeyCode>(code) }),
+        _ => None,
+    }
+}
+
+/// Key position in the keymap, in global coordinates
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, MaxSize, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Key {
+    pub layer: u8,
+    pub row: u8,
+    pub col: u8,
+}
+
+/// Keymap query or modification from host software
+///
+/// Sent as a raw HID report:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x04`                                                  |
+/// | 1    | operation: 0 - info, 1 - get, 2 - set, 3 - reset        |
+/// | 2    | layer                                                   |
+/// | 3    | row                                                     |
+/// | 4    | column (global, right half starts at number of columns of a half) |
+/// | 5    | action kind (set only)                                  |
+/// | 6    | action value (set only)                                 |
+///
+/// Actions are encoded like in the configuration blob, but only those without nested data:
+/// 0 - no-op, 1 - transparent, 2 - key code (USB HID usage), 5 - layer, 6 - default layer.
+/// Other actions are read as kind `0xff` and cannot be written. Reset restores the action
+/// from configuration. Keyboard answers every request with an input report:
+///
+/// | Byte | Value                                                   |
+/// |------|---------------------------------------------------------|
+/// | 0    | `0x04`                                                  |
+/// | 1    | operation                                               |
+/// | 2    | [`Status`]                                              |
+/// | 3..6 | layer, row and column (info: numbers of layers, rows and columns) |
+/// | 6    | action kind of the key after the operation              |
+/// | 7    | action value                                            |
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Request {
+    Info,
+    Get(Key),
+    /// Key with encoded action kind and value
+    Set(Key, [u8; 2]),
+    Reset(Key),
+}
+
+/// Result of a [`Request`]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Status {
+    Ok = 0,
+    /// Keys are held, so the keymap cannot be modified now and host should retry
+    Busy = 1,
+    /// Firmware has been built without `runtime-keymap` feature
+    ReadOnly = 2,
+}
+
+/// Modification of a key sent by master, so that the other half uses the same keymap after
+/// roles are swapped
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, MaxSize, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Change {
+    pub key: Key,
+    /// Encoded action as in [`Request::Set`], `None` restores the action from configuration
+    pub action: Option<[u8; 2]>,
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    UnknownOperation(u8),
+    InvalidKey(Key),
+    InvalidAction { kind: u8, value: u8 },
+}
+
+impl Request {
+    /// Parse raw HID report with [`CMD_KEYMAP`] command for a keymap with `layers` layers
+    pub fn from_report(report: &RawReport, layers: usize) -> Result<Self, Error> {
+        debug_assert_eq!(report[0], CMD_KEYMAP);
+        let key = Key { layer: report[2], row: report[3], col: report[4] };
+        let valid = (key.layer as usize) < layers && (key.row as usize) < NROWS && (key.col as usize) < 2 * NCOLS;
+        match report[1] {
+            0 => Ok(Self::Info),
+            1..=3 if !valid => Err(Error::InvalidKey(key)),
+            1 => Ok(Self::Get(key)),
+            2 => {
+                let (kind, value) = (report[5], report[6]);
+                decode(kind, value, layers).ok_or(Error::InvalidAction { kind, value })?;
+                Ok(Self::Set(key, [kind, value]))
+            },
+            3 => Ok(Self::Reset(key)),
+            op => Err(Error::UnknownOperation(op)),
+        }
+    }
+
+    /// Request changes the keymap (if allowed)
+    pub fn modifies(&self) -> bool {
+        matches!(self, Self::Set(..) | Self::Reset(_))
+    }
+
+    fn operation(&self) -> u8 {
+        match self {
+            Self::Info => 0,
+            Self::Get(_) => 1,
+            Self::Set(..) => 2,
+            Self::Reset(_) => 3,
+        }
+    }
+}
+
+/// Set of keys on all layers
+#[derive(Clone, Copy)]
+struct KeySet<const L: usize>([[u16; NROWS]; L]);
+
+const _: () = assert!(2 * NCOLS <= u16::BITS as usize);
+
+impl<const L: usize> KeySet<L> {
+    const EMPTY: Self = Self([[0; NROWS]; L]);
+
+    fn set(&mut self, key: Key, value: bool) {
+        let row = &mut self.0[key.layer as usize][key.row as usize];
+        if value {
+            *row |= 1 << key.col;
+        } else {
+            *row &= !(1 << key.col);
+        }
+    }
+
+    fn contains(&self, key: Key) -> bool {
+        self.0[key.layer as usize][key.row as usize] & (1 << key.col) != 0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().flatten().all(|row| *row == 0)
+    }
+
+    /// Remove and return any key from the set
+    fn pop(&mut self) -> Option<Key> {
+        let (layer, row, bits) = self.0.iter_mut().enumerate()
+            .flat_map(|(layer, rows)| rows.iter_mut().enumerate().map(move |(row, bits)| (layer, row, bits)))
+            .find(|(_, _, bits)| **bits != 0)?;
+        let col = bits.trailing_zeros();
+        *bits &= !(1 << col);
+        Some(Key { layer: layer as u8, row: row as u8, col: col as u8 })
+    }
+}
+
+/// Keymap used by the layout, optionally copied to RAM so that host can remap keys
+///
+/// Modifications are kept until reboot, configuration in flash is never changed. Master sends
+/// each modification to the other half (see [`Change`]), which applies it to its own copy, so
+/// remapped keys survive swapping roles. Layers with modified actions are reported with
+/// [`Keymap::take_key_actions`] to update caches used by LED rules.
+///
+/// Users of the layers keep references to actions, so the RAM copy must only be modified
+/// when none of them is alive, see safety requirements of the modifying methods.
+pub struct Keymap<const L: usize> {
+    /// Layers from configuration
+    configured: &'static Layers<L>,
+    /// RAM copy used by the layout when modifications are enabled
+    ram: Option<NonNull<Layers<L>>>,
+    /// Keys with actions different from configuration
+    modified: KeySet<L>,
+    /// Keys not yet sent to the other half
+    unsynced: KeySet<L>,
+    /// Layers with modified actions since the last [`Keymap::take_key_actions`]
+    outdated: [bool; L],
+}
+
+// SAFETY: the RAM copy is only accessed through the keymap and layers handed out by it, which
+// all belong to the keyboard, so they are moved between contexts together
+unsafe impl<const L: usize> Send for Keymap<L> {}
+
+impl<const L: usize> Keymap<L> {
+    pub const fn new(configured: &'static Layers<L>) -> Self {
+        Self {
+            configured,
+            ram: None,
+            modified: KeySet::EMPTY,
+            unsynced: KeySet::EMPTY,
+            outdated: [false; L],
+        }
+    }
+
+    /// Copy configured layers to `buf` to allow modifications, returns layers to be used by layout
+    pub fn enable(&mut self, buf: &'static mut MaybeUninit<Layers<L>>) -> &'static Layers<L> {
+        let ptr = buf.as_mut_ptr();
+        // SAFETY: actions are plain data that only reference other static data, so a bitwise
+        // copy is valid, and the buffer is borrowed for 'static so no one else can access it
+        unsafe {
+            ptr.copy_from_nonoverlapping(self.configured, 1);
+            self.ram = Some(NonNull::new_unchecked(ptr));
+        }
+        self.layers()
+    }
+
+    /// Keymap can be modified, i.e. [`Keymap::enable`] has been called
+    pub fn is_enabled(&self) -> bool {
+        self.ram.is_some()
+    }
+
+    /// Any key differs from configuration
+    pub fn is_modified(&self) -> bool {
+        !self.modified.is_empty()
+    }
+
+    /// Layers from configuration, never modified
+    ///
+    /// Users of the layers should be switched to these while the keymap is being modified.
+    pub fn configured(&self) -> &'static Layers<L> {
+        self.configured
+    }
+
+    /// Layers currently used by the layout
+    ///
+    /// The returned reference must not be used after the keymap has been modified.
+    pub fn layers(&self) -> &'static Layers<L> {
+        match self.ram {
+            // SAFETY: buffer is initialized in `enable`, callers of modifying methods guarantee
+            // that no references to it are alive
+            Some(ptr) => unsafe { &*ptr.as_ptr() },
+            None => self.configured,
+        }
+    }
+
+    /// Handle request and build the response
+    ///
+    /// Modifications are only applied when `idle`, i.e. no keys are held, so that no key is
+    /// released with a different action than it has been pressed with.
+    ///
+    /// # Safety
+    ///
+    /// For modifying requests (see [`Request::modifies`]) on an idle keyboard, no references
+    /// returned by [`Keymap::layers`] may be alive.
+    pub unsafe fn handle(&mut self, request: Request, idle: bool) -> RawReport {
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[0] = CMD_KEYMAP;
+        report[1] = request.operation();
+        let (status, key) = match request {
+            Request::Info => {
+                let status = if self.ram.is_some() { Status::Ok } else { Status::ReadOnly };
+                report[2] = status as u8;
+                report[3..6].copy_from_slice(&[L as u8, NROWS as u8, 2 * NCOLS as u8]);
+                return report;
+            },
+            Request::Get(key) => (Status::Ok, key),
+            Request::Set(key, [kind, value]) => {
+                let status = match decode(kind, value, L) {
+                    Some(action) => self.write(key, Some(action), idle),
+                    // Already validated when parsing, so just report the current action
+                    None => Status::Ok,
+                };
+                (status, key)
+            },
+            Request::Reset(key) => (self.write(key, None, idle), key),
+        };
+        report[2] = status as u8;
+        report[3..6].copy_from_slice(&[key.layer, key.row, key.col]);
+        let action = &self.layers()[key.layer as usize][key.row as usize][key.col as usize];
+        report[6..8].copy_from_slice(&encode(action));
+        report
+    }
+
+    /// Apply modification received from master
+    ///
+    /// # Safety
+    ///
+    /// No references returned by [`Keymap::layers`] may be alive.
+    pub unsafe fn apply(&mut self, change: Change) {
+        let Change { key, action } = change;
+        let valid = (key.layer as usize) < L && (key.row as usize) < NROWS && (key.col as usize) < 2 * NCOLS;
+        let action = action.map(|[kind, value]| decode(kind, value, L));
+        match action {
+            Some(None) => defmt::warn!("Keymap: invalid action of {}", change),
+            _ if !valid => defmt::warn!("Keymap: invalid key {}", change),
+            Some(Some(action)) => self.set(key, Some(action)),
+            None => self.set(key, None),
+        }
+    }
+
+    /// Restore configuration of all keys, e.g. before master sends its modifications
+    ///
+    /// # Safety
+    ///
+    /// No references returned by [`Keymap::layers`] may be alive.
+    pub unsafe fn reset_all(&mut self) {
+        while let Some(key) = self.modified.pop() {
+            self.set(key, None);
+        }
+        self.unsynced = KeySet::EMPTY;
+    }
+
+    /// Send all modified keys to the other half again, e.g. after it has been restarted
+    pub fn resync(&mut self) {
+        self.unsynced = self.modified;
+    }
+
+    /// Take the next modification that has not been sent to the other half
+    pub fn take_change(&mut self) -> Option<Change> {
+        let key = self.unsynced.pop()?;
+        let action = self.modified.contains(key)
+            .then(|| encode(&self.layers()[key.layer as usize][key.row as usize][key.col as usize]));
+        Some(Change { key, action })
+    }
+
+    /// Take the next layer with modified actions and its new cache for LED rules
+    pub fn take_key_actions(&mut self) -> Option<(u8, KeyActionCache)> {
+        let layer = self.outdated.iter().position(|outdated| *outdated)?;
+        self.outdated[layer] = false;
+        Some((layer as u8, KeyActionCache::new(&self.layers()[layer])))
+    }
+
+    /// Modify action of `key` on host request, `None` restores configuration
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Keymap::handle`].
+    unsafe fn write(&mut self, key: Key, action: Option<Action<actions::Action>>, idle: bool) -> Status {
+        if self.ram.is_none() {
+            return Status::ReadOnly;
+        }
+        if !idle {
+            return Status::Busy;
+        }
+        defmt::info!("Keymap: remapping {}", key);
+        self.set(key, action);
+        self.unsynced.set(key, true);
+        Status::Ok
+    }
+
+    /// # Safety
+    ///
+    /// No references returned by [`Keymap::layers`] may be alive.
+    unsafe fn set(&mut self, key: Key, action: Option<Action<actions::Action>>) {
+        let Some(ptr) = self.ram else {
+            return;
+        };
+        let (layer, row, col) = (key.layer as usize, key.row as usize, key.col as usize);
+        self.modified.set(key, action.is_some());
+        self.outdated[layer] = true;
+        // SAFETY: actions from configuration are copied like in `enable`, the source is never
+        // modified; there are no other references to the RAM copy as required by the caller
+        let action = action.unwrap_or_else(|| core::ptr::read(&self.configured[layer][row][col]));
+        core::ptr::addr_of_mut!((*ptr.as_ptr())[layer][row][col]).write(action);
+    }
+}
+
+fn encode(action: &Action<actions::Action>) -> [u8; 2] {
+    match action {
+        Action::NoOp => [0, 0],
+        Action::Trans => [1, 0],
+        Action::KeyCode(code) => [2, *code as u8],
+        Action::Layer(layer) => [5, *layer as u8],
+        Action::DefaultLayer(layer) => [6, *layer as u8],
+        _ => [KIND_OTHER, 0],
+    }
+}
+
+fn decode(kind: u8, value: u8, layers: usize) -> Option<Action<actions::Action>> {
+    match kind {
+        0 => Some(Action::NoOp),
+        1 => Some(Action::Trans),
+        2 => key_code(value).map(Action::KeyCode),
+        5 if (value as usize) < layers => Some(Action::Layer(value as usize)),
+        6 if (value as usize) < layers => Some(Action::DefaultLayer(value as usize)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keyberon::action::{k, l};
+    use super::*;
+
+    const NO: Action<actions::Action> = Action::NoOp;
+    const ROW: [Action<actions::Action>; 2 * NCOLS] = [NO; 2 * NCOLS];
+    const LAYER: [[Action<actions::Action>; 2 * NCOLS]; NROWS] = [ROW; NROWS];
+
+    static LAYERS: Layers<2> = {
+        let mut layers = [LAYER; 2];
+        layers[0][0][0] = k(KeyCode::A);
+        layers[0][1][2] = l(1);
+        layers[1][0][0] = Action::Trans;
+        layers[1][0][1] = Action::Custom(actions::Action::Mouse(actions::MouseAction::Precision));
+        layers
+    };
+
+    fn report(bytes: &[u8]) -> RawReport {
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[..bytes.len()].copy_from_slice(bytes);
+        report
+    }
+
+    fn key(layer: u8, row: u8, col: u8) -> Key {
+        Key { layer, row, col }
+    }
+
+    fn enabled() -> Keymap<2> {
+        let mut keymap = Keymap::new(&LAYERS);
+        keymap.enable(Box::leak(Box::new(MaybeUninit::uninit())));
+        keymap
+    }
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 0]), 2), Ok(Request::Info));
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 1, 1, 2, 3]), 2), Ok(Request::Get(key(1, 2, 3))));
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 2, 0, 0, 0, 2, 0x04]), 2),
+            Ok(Request::Set(key(0, 0, 0), [2, 0x04])));
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 3, 0, 1, 2]), 2), Ok(Request::Reset(key(0, 1, 2))));
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 4]), 2), Err(Error::UnknownOperation(4)));
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 1, 2]), 2), Err(Error::InvalidKey(key(2, 0, 0))));
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 1, 0, NROWS as u8]), 2),
+            Err(Error::InvalidKey(key(0, NROWS as u8, 0))));
+        assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 3, 0, 0, 2 * NCOLS as u8]), 2),
+            Err(Error::InvalidKey(key(0, 0, 2 * NCOLS as u8))));
+        // Layer out of range, key code not defined by keyberon, nested actions
+        for action in [[5, 2], [2, 0xb0], [7, 0], [KIND_OTHER, 0]] {
+            assert_eq!(Request::from_report(&report(&[CMD_KEYMAP, 2, 0, 0, 0, action[0], action[1]]), 2),
+                Err(Error::InvalidAction { kind: action[0], value: action[1] }));
+        }
+    }
+
+    #[test]
+    fn read_only() {
+        let mut keymap = Keymap::new(&LAYERS);
+        assert!(!keymap.is_enabled());
+        let r = unsafe { keymap.handle(Request::Info, true) };
+        assert_eq!(r[..6], [CMD_KEYMAP, 0, Status::ReadOnly as u8, 2, NROWS as u8, 2 * NCOLS as u8]);
+        let r = unsafe { keymap.handle(Request::Get(key(0, 0, 0)), true) };
+        assert_eq!(r[..8], [CMD_KEYMAP, 1, Status::Ok as u8, 0, 0, 0, 2, KeyCode::A as u8]);
+        let r = unsafe { keymap.handle(Request::Set(key(0, 0, 0), [1, 0]), true) };
+        assert_eq!(r[..8], [CMD_KEYMAP, 2, Status::ReadOnly as u8, 0, 0, 0, 2, KeyCode::A as u8]);
+        assert!(!keymap.is_modified());
+        assert!(keymap.take_change().is_none());
+    }
+
+    #[test]
+    fn remap_keys() {
+        let mut keymap = enabled();
+        assert!(Request::Set(key(1, 0, 1), [1, 0]).modifies() && !Request::Get(key(1, 0, 1)).modifies());
+        unsafe {
+            assert_eq!(keymap.handle(Request::Info, true)[2], Status::Ok as u8);
+            assert_eq!(keymap.handle(Request::Get(key(1, 0, 1)), true)[6..8], [KIND_OTHER, 0]);
+            assert_eq!(keymap.handle(Request::Get(key(0, 1, 2)), true)[6..8], [5, 1]);
+
+            let r = keymap.handle(Request::Set(key(1, 0, 1), [2, KeyCode::B as u8]), true);
+            assert_eq!(r[..8], [CMD_KEYMAP, 2, Status::Ok as u8, 1, 0, 1, 2, KeyCode::B as u8]);
+            assert!(matches!(keymap.layers()[1][0][1], Action::KeyCode(KeyCode::B)));
+            assert!(matches!(LAYERS[1][0][1], Action::Custom(_)));
+            assert!(matches!(keymap.configured()[1][0][1], Action::Custom(_)));
+
+            // Not applied while keys are held
+            let r = keymap.handle(Request::Set(key(0, 1, 2), [6, 1]), false);
+            assert_eq!(r[2..8], [Status::Busy as u8, 0, 1, 2, 5, 1]);
+            let r = keymap.handle(Request::Set(key(0, 1, 2), [6, 1]), true);
+            assert_eq!(r[2..8], [Status::Ok as u8, 0, 1, 2, 6, 1]);
+
+            let r = keymap.handle(Request::Reset(key(1, 0, 1)), true);
+            assert_eq!(r[2..8], [Status::Ok as u8, 1, 0, 1, KIND_OTHER, 0]);
+        }
+        assert!(matches!(keymap.layers()[1][0][1], Action::Custom(actions::Action::Mouse(actions::MouseAction::Precision))));
+        assert!(matches!(keymap.layers()[0][1][2], Action::DefaultLayer(1)));
+    }
+
+    #[test]
+    fn sync_changes() {
+        let mut master = enabled();
+        let mut slave = enabled();
+        unsafe {
+            master.handle(Request::Set(key(1, 0, 1), [2, KeyCode::B as u8]), true);
+            master.handle(Request::Set(key(0, 0, 0), [0, 0]), true);
+            master.handle(Request::Reset(key(0, 0, 0)), true);
+        }
+        let changes: std::vec::Vec<_> = core::iter::from_fn(|| master.take_change()).collect();
+        assert_eq!(changes, [
+            Change { key: key(0, 0, 0), action: None },
+            Change { key: key(1, 0, 1), action: Some([2, KeyCode::B as u8]) },
+        ]);
+        for change in changes {
+            unsafe { slave.apply(change) };
+        }
+        assert!(matches!(slave.layers()[1][0][1], Action::KeyCode(KeyCode::B)));
+        assert!(matches!(slave.layers()[0][0][0], Action::KeyCode(KeyCode::A)));
+        // Changes are not forwarded further
+        assert!(slave.take_change().is_none());
+
+        // Invalid changes are ignored
+        unsafe {
+            slave.apply(Change { key: key(2, 0, 0), action: None });
+            slave.apply(Change { key: key(0, 0, 0), action: Some([KIND_OTHER, 0]) });
+        }
+        assert!(matches!(slave.layers()[0][0][0], Action::KeyCode(KeyCode::A)));
+
+        // After renegotiation slave restores configuration and master sends all modified keys
+        unsafe { slave.reset_all() };
+        assert!(!slave.is_modified());
+        assert!(matches!(slave.layers()[1][0][1], Action::Custom(_)));
+        master.resync();
+        assert_eq!(master.take_change(), Some(Change { key: key(1, 0, 1), action: Some([2, KeyCode::B as u8]) }));
+        assert_eq!(master.take_change(), None);
+    }
+
+    #[test]
+    fn key_actions() {
+        let mut keymap = enabled();
+        assert!(keymap.take_key_actions().is_none());
+        unsafe {
+            keymap.handle(Request::Set(key(1, 0, 1), [2, KeyCode::B as u8]), true);
+            keymap.handle(Request::Set(key(0, 1, 2), [0, 0]), true);
+        }
+        let (layer, cache) = keymap.take_key_actions().unwrap();
+        assert_eq!(layer, 0);
+        assert_eq!(cache, KeyActionCache::new(&keymap.layers()[0]));
+        assert_ne!(cache, KeyActionCache::new(&LAYERS[0]));
+        assert_eq!(keymap.take_key_actions().map(|(layer, _)| layer), Some(1));
+        assert!(keymap.take_key_actions().is_none());
+    }
+}
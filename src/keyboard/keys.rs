@@ -1,51 +1,322 @@
-use keyberon::{matrix, debounce, layout};
+use embedded_hal::digital::v2::InputPin;
+use keyberon::{matrix, layout};
 
-use crate::bsp::{NCOLS, NROWS, ColPin, RowPin, sides::BoardSide, delay_us};
+use crate::bsp::{NCOLS, NROWS, NLEDS, NAUX_KEYS, ColPin, RowPin, sides::BoardSide, delay_us};
+use crate::bsp::board::{BOARD, MatrixPin};
+use crate::bsp::matrix_wake::MatrixWake;
 use crate::utils::InfallibleResult;
 use super::leds::LedsBitset;
+use super::ticks::TickRate;
 
 pub type PressedKeys = LedsBitset;
 
-/// Keyboard key matrix scanner
-pub struct Keys {
+/// Key pressed within this time from boot is considered pressed since boot
+const BOOT_WINDOW_MS: u32 = 100;
+/// Key pressed since boot and held for this long is considered stuck
+const STUCK_TIMEOUT_MS: u32 = 10_000;
+/// Press that occurs within this time after release is considered chatter
+const CHATTER_WINDOW_MS: u32 = 30;
+/// Time without any key activity before switching to interrupt-driven wake up
+const IDLE_TIMEOUT_MS: u32 = 100;
+/// Maximum number of keys held at the same time waiting for slow keys delay
+pub const SLOW_KEYS_MAX_PENDING: usize = 8;
+/// Maximum number of recently released keys remembered by bounce keys filter
+const BOUNCE_KEYS_MAX_RECENT: usize = 16;
+/// Maximum number of held keys with ignored press for bounce keys filter
+const BOUNCE_KEYS_MAX_IGNORED: usize = 8;
+
+/// Source of raw (not debounced) key matrix state
+///
+/// Abstracts the hardware so that keyboard logic can also be run off-hardware.
+pub trait KeyMatrix {
+    /// Read state of all keys in side-local coordinates
+    fn read(&mut self) -> [[bool; NCOLS]; NROWS];
+    /// Stop scanning and enable key press interrupt
+    fn arm_wake(&mut self);
+    /// Disable key press interrupt and prepare for scanning
+    fn disarm_wake(&mut self);
+    /// Check if wake up is still armed, i.e. no key has been pressed
+    fn is_wake_armed(&self) -> bool;
+    /// Check if any key is pressed, only valid while wake up is armed
+    fn any_key_down(&self) -> bool;
+}
+
+/// Debouncing timing
+pub struct DebounceConfig {
+    /// Deferred debouncing: key state must be stable for this long before a change is reported
+    pub defer_ms: u16,
+    /// Eager debouncing: key is ignored for this long after each reported change
+    pub eager_ms: u16,
+}
+
+/// Accessibility options timing, the options themselves are toggled at runtime
+pub struct AccessibilityConfig {
+    /// Slow keys: key must be held for this long before its press is reported
+    pub slow_keys_ms: u16,
+    /// Bounce keys: press of a key within this time after its release is ignored
+    pub bounce_keys_ms: u16,
+}
+
+/// Key matrix connected to MCU GPIOs
+///
+/// Auxiliary keys wired directly to GPIOs are merged into the matrix state at their
+/// reserved coordinates.
+pub struct HwMatrix {
     matrix: matrix::Matrix<ColPin, RowPin, NCOLS, NROWS>,
-    debouncer: debounce::Debouncer<[[bool; NCOLS]; NROWS]>,
+    aux_keys: [MatrixPin; NAUX_KEYS],
+    wake: MatrixWake,
+}
+
+/// Keyboard key matrix scanner
+pub struct Keys<M = HwMatrix> {
+    matrix: M,
+    /// Deferred debouncing time in ticks
+    defer: u32,
+    /// Eager debouncing lockout time in ticks
+    lockout: u32,
+    /// Eager debouncing: report changes immediately, then ignore the key for `lockout` ticks
+    eager: bool,
+    /// Timestamp of the last scan in ticks
+    time: u32,
+    raw: [[bool; NCOLS]; NROWS],
+    debounced: [[bool; NCOLS]; NROWS],
+    /// Time of the last raw state transition (deferred) or of the last reported change (eager)
+    changed_at: [[u32; NCOLS]; NROWS],
     side: BoardSide,
     pressed: LedsBitset,
+    health: MatrixHealth,
+    idle: bool,
+    quiet_scans: u32,
+    /// Idle timeout in scans
+    idle_scans: u32,
 }
 
-impl Keys {
-    /// Initialize key matrix scanner with debouncing that requires `debounce_cnt` stable states
-    pub fn new(
-        side: BoardSide,
-        cols: [ColPin; NCOLS],
-        rows: [RowPin; NROWS],
-        debounce_cnt: u16,
-    ) -> Self {
-        let initial = Default::default;
+/// Slow keys accessibility filter
+///
+/// Applied to debounced key events (global coordinates) from both halves. When enabled,
+/// key press is only reported after the key has been held for the configured time and
+/// shorter presses are ignored completely.
+pub struct SlowKeys {
+    enabled: bool,
+    /// Required hold time in ticks
+    hold: u32,
+    time: u32,
+    /// Keys waiting for the hold time to pass, with time of the press
+    pending: heapless::Vec<((u8, u8), u32), SLOW_KEYS_MAX_PENDING>,
+}
+
+/// Bounce keys accessibility filter
+///
+/// Applied to debounced key events (global coordinates) from both halves. When enabled,
+/// a press of a key within the configured interval after its release is ignored together
+/// with the corresponding release. Unlike debouncing this is meant for unintended repeated
+/// presses by the user (e.g. due to tremors), so the interval is much longer.
+pub struct BounceKeys {
+    enabled: bool,
+    /// Cooldown interval in ticks
+    interval: u32,
+    time: u32,
+    /// Keys released within the interval with time of the release, oldest first
+    released: heapless::Deque<((u8, u8), u32), BOUNCE_KEYS_MAX_RECENT>,
+    /// Keys with ignored press, their release must be ignored too
+    ignored: heapless::Vec<(u8, u8), BOUNCE_KEYS_MAX_IGNORED>,
+}
+
+/// Key matrix health monitoring
+///
+/// Detects keys that are pressed since boot and held for implausibly long time (most
+/// likely stuck switch or a short in the matrix) and counts key chatter, i.e. repeated
+/// press shortly after release that passed through debouncing. Uses side-local coordinates.
+pub struct MatrixHealth {
+    time: u32,
+    boot_checked: bool,
+    held_since_boot: [[bool; NCOLS]; NROWS],
+    stuck: [[bool; NCOLS]; NROWS],
+    last_release: [[Option<u32>; NCOLS]; NROWS],
+    chatter: [[u8; NCOLS]; NROWS],
+    // Timing constants converted to scans
+    boot_window: u32,
+    stuck_timeout: u32,
+    chatter_window: u32,
+}
+
+impl HwMatrix {
+    pub fn new(cols: [ColPin; NCOLS], rows: [RowPin; NROWS], aux_keys: [MatrixPin; NAUX_KEYS], wake: MatrixWake) -> Self {
         Self {
-            side,
             matrix: matrix::Matrix::new(cols, rows).infallible(),
-            // TODO: could use better debouncing logic
-            debouncer: debounce::Debouncer::new(initial(), initial(), debounce_cnt),
-            pressed: Default::default(),
+            aux_keys,
+            wake,
         }
     }
+}
 
-    /// Scan for key events; caller decides what to do with the events
-    pub fn scan(&mut self) -> impl Iterator<Item = layout::Event> + '_ {
+impl KeyMatrix for HwMatrix {
+    fn read(&mut self) -> [[bool; NCOLS]; NROWS] {
         // No-delay scan takes ~39 us and there seem to be no problems with signal stability,
         // but to be sure that row signal is fully stable add some delay before each row scan.
-        let scan = self.matrix.get_with_delay(|| delay_us(4)).infallible();
+        let mut keys = self.matrix.get_with_delay(|| delay_us(4)).infallible();
+        for (key, pin) in BOARD.aux_keys.iter().zip(self.aux_keys.iter()) {
+            let (row, col) = key.coords;
+            keys[row as usize][col as usize] |= pin.is_low().infallible();
+        }
+        keys
+    }
 
-        self.debouncer.events(scan)
-            .map(|e| {
+    fn arm_wake(&mut self) {
+        self.wake.arm();
+    }
+
+    fn disarm_wake(&mut self) {
+        self.wake.disarm();
+        // Let the columns settle after rows went high
+        delay_us(10);
+    }
+
+    fn is_wake_armed(&self) -> bool {
+        self.wake.is_armed()
+    }
+
+    fn any_key_down(&self) -> bool {
+        self.wake.any_column_low()
+    }
+}
+
+impl<M: KeyMatrix> Keys<M> {
+    /// Initialize key matrix scanner, debouncing times are converted to ticks of `rate`
+    pub fn new(side: BoardSide, matrix: M, debounce: &DebounceConfig, rate: TickRate) -> Self {
+        let ticks = |ms: u16| if ms == 0 { 0 } else { rate.from_ms(ms as u32) };
+        Self {
+            side,
+            matrix,
+            defer: ticks(debounce.defer_ms),
+            lockout: ticks(debounce.eager_ms),
+            eager: false,
+            time: 0,
+            raw: Default::default(),
+            debounced: Default::default(),
+            changed_at: Default::default(),
+            pressed: Default::default(),
+            health: MatrixHealth::new(rate),
+            idle: false,
+            quiet_scans: 0,
+            idle_scans: rate.from_ms(IDLE_TIMEOUT_MS),
+        }
+    }
+
+    /// Scan for key events; caller decides what to do with the events
+    ///
+    /// `now` is the current timestamp in ticks, so debouncing times stay the same even if
+    /// some scans are skipped. When idle (see [`Self::set_idle_allowed`]) the matrix is not
+    /// scanned until a key press interrupt occurs.
+    pub fn scan(&mut self, now: u32) -> impl Iterator<Item = layout::Event> + '_ {
+        self.time = now;
+
+        if self.idle && !self.matrix.is_wake_armed() {
+            self.exit_idle();
+        }
+
+        let scan = if self.idle {
+            // Idle is only entered with all keys released, so this does not generate events
+            Default::default()
+        } else {
+            self.matrix.read()
+        };
+        // Hand-wired builds may connect keys to other matrix positions
+        let scan = BOARD.remap_keys(scan);
+
+        let quiet = scan.iter().flatten().all(|pressed| !pressed) && self.pressed.is_none();
+        self.quiet_scans = if quiet { self.quiet_scans.saturating_add(1) } else { 0 };
+
+        self.health.tick();
+        scan.into_iter().enumerate()
+            .flat_map(|(i, row)| row.into_iter().enumerate().map(move |(j, raw)| (i, j, raw)))
+            .filter_map(|(i, j, raw)| {
+                if !self.debounce(i, j, raw) {
+                    return None;
+                }
+                let e = if raw {
+                    layout::Event::Press(i as u8, j as u8)
+                } else {
+                    layout::Event::Release(i as u8, j as u8)
+                };
                 self.pressed.update_keys_on_event(e);
+                self.health.on_event(e);
                 // Matrix produces local coordinates; make them global.
-                e.transform(|i, j| self.side.coords_to_global((i, j)))
+                Some(e.transform(|i, j| self.side.coords_to_global((i, j))))
             })
     }
 
+    /// Update debounced state of a key, returns true if the change should be reported
+    fn debounce(&mut self, i: usize, j: usize, raw: bool) -> bool {
+        if !self.eager && raw != self.raw[i][j] {
+            self.changed_at[i][j] = self.time;
+        }
+        self.raw[i][j] = raw;
+
+        let elapsed = self.time.wrapping_sub(self.changed_at[i][j]);
+        let ready = if self.eager {
+            // Report immediately unless the key is still locked out after the previous change
+            elapsed >= self.lockout
+        } else {
+            // Report only after the raw state has been stable long enough
+            elapsed >= self.defer
+        };
+        if raw == self.debounced[i][j] || !ready {
+            return false;
+        }
+        self.debounced[i][j] = raw;
+        if self.eager {
+            self.changed_at[i][j] = self.time;
+        }
+        true
+    }
+
+    /// Allow or disallow interrupt-driven idle mode (e.g. when USB is suspended)
+    ///
+    /// When allowed, idle is entered after a period without any key activity.
+    pub fn set_idle_allowed(&mut self, allowed: bool) {
+        if !allowed {
+            if self.idle {
+                self.exit_idle();
+            }
+        } else if !self.idle && self.quiet_scans >= self.idle_scans {
+            self.matrix.arm_wake();
+            self.idle = true;
+            defmt::info!("Matrix idle");
+            // Interrupt would be triggered anyway, but this avoids a useless wake up
+            if self.matrix.any_key_down() {
+                self.exit_idle();
+            }
+        }
+    }
+
+    /// Switch between eager and deferred (default) debouncing
+    ///
+    /// Eager debouncing reports each change on the first scan and then ignores the key
+    /// for the lockout period, which reduces latency but is susceptible to noise.
+    pub fn set_eager_debounce(&mut self, eager: bool) {
+        if eager == self.eager {
+            return;
+        }
+        self.eager = eager;
+        // Timestamps have different meaning in each mode, so restart timing, but without
+        // locking out keys in eager mode
+        let start = if eager { self.time.wrapping_sub(self.lockout) } else { self.time };
+        self.changed_at = [[start; NCOLS]; NROWS];
+    }
+
+    /// Check if matrix scanning is stopped waiting for key press interrupt
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    fn exit_idle(&mut self) {
+        self.matrix.disarm_wake();
+        self.idle = false;
+        self.quiet_scans = 0;
+        defmt::info!("Matrix wake up");
+    }
+
     /// Get board side
     pub fn side(&self) -> &BoardSide {
         &self.side
@@ -54,6 +325,226 @@ impl Keys {
     pub fn pressed(&self) -> PressedKeys {
         self.pressed
     }
+
+    /// Get matrix health statistics
+    pub fn health(&self) -> &MatrixHealth {
+        &self.health
+    }
+}
+
+impl MatrixHealth {
+    pub const fn new(rate: TickRate) -> Self {
+        Self {
+            time: 0,
+            boot_checked: false,
+            held_since_boot: [[false; NCOLS]; NROWS],
+            stuck: [[false; NCOLS]; NROWS],
+            last_release: [[None; NCOLS]; NROWS],
+            chatter: [[0; NCOLS]; NROWS],
+            boot_window: rate.from_ms(BOOT_WINDOW_MS),
+            stuck_timeout: rate.from_ms(STUCK_TIMEOUT_MS),
+            chatter_window: rate.from_ms(CHATTER_WINDOW_MS),
+        }
+    }
+
+    /// Advance time by one scan
+    pub fn tick(&mut self) {
+        self.time = self.time.wrapping_add(1);
+        if !self.boot_checked && self.time >= self.stuck_timeout {
+            self.boot_checked = true;
+            for (i, row) in self.held_since_boot.iter().enumerate() {
+                for (j, held) in row.iter().enumerate() {
+                    if *held {
+                        defmt::error!("Key ({=usize}, {=usize}) stuck since boot", i, j);
+                        self.stuck[i][j] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Process key event in side-local coordinates
+    pub fn on_event(&mut self, event: layout::Event) {
+        let (i, j) = event.coord();
+        let (i, j) = (i as usize, j as usize);
+        if i >= NROWS || j >= NCOLS {
+            return;
+        }
+        match event {
+            layout::Event::Press(..) => {
+                if !self.boot_checked && self.time < self.boot_window {
+                    self.held_since_boot[i][j] = true;
+                }
+                if let Some(t) = self.last_release[i][j] {
+                    if self.time.wrapping_sub(t) < self.chatter_window {
+                        self.chatter[i][j] = self.chatter[i][j].saturating_add(1);
+                        defmt::warn!("Key ({=usize}, {=usize}) chatter: {=u8}", i, j, self.chatter[i][j]);
+                    }
+                }
+            },
+            layout::Event::Release(..) => {
+                self.held_since_boot[i][j] = false;
+                if self.stuck[i][j] {
+                    defmt::info!("Key ({=usize}, {=usize}) released after being stuck", i, j);
+                    self.stuck[i][j] = false;
+                }
+                self.last_release[i][j] = Some(self.time);
+            },
+        }
+    }
+
+    /// Keys considered stuck (side-local coordinates)
+    pub fn stuck(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        Self::iter_coords(&self.stuck).filter(|(_, stuck)| *stuck).map(|(ij, _)| ij)
+    }
+
+    /// Chatter counts of keys that chattered at least once (side-local coordinates)
+    pub fn chatter(&self) -> impl Iterator<Item = ((u8, u8), u8)> + '_ {
+        Self::iter_coords(&self.chatter).filter(|(_, cnt)| *cnt > 0)
+    }
+
+    /// LEDs of keys that are stuck or chatter
+    pub fn offending_leds(&self) -> LedsBitset {
+        let mut leds = LedsBitset::default();
+        let keys = self.stuck().chain(self.chatter().map(|(ij, _)| ij));
+        for led in keys.filter_map(BoardSide::led_number) {
+            leds.set(led, true);
+        }
+        leds
+    }
+
+    fn iter_coords<T: Copy>(arr: &[[T; NCOLS]; NROWS]) -> impl Iterator<Item = ((u8, u8), T)> + '_ {
+        arr.iter().enumerate()
+            .flat_map(|(i, row)| row.iter().enumerate().map(move |(j, v)| ((i as u8, j as u8), *v)))
+    }
+}
+
+impl SlowKeys {
+    /// Create disabled filter, hold time is converted to ticks of [`Self::tick`] calls
+    pub fn new(config: &AccessibilityConfig, rate: TickRate) -> Self {
+        Self {
+            enabled: false,
+            hold: rate.from_ms(config.slow_keys_ms as u32),
+            time: 0,
+            pending: heapless::Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enable/disable the filter, pending presses are reported on next tick when disabled
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            defmt::info!("Slow keys: {=bool}", enabled);
+        }
+        self.enabled = enabled;
+    }
+
+    /// Filter key event, returns the event if it should be reported immediately
+    pub fn event(&mut self, event: layout::Event) -> Option<layout::Event> {
+        match event {
+            layout::Event::Press(i, j) if self.enabled => match self.pending.push(((i, j), self.time)) {
+                Ok(()) => None,
+                // Too many keys held at once, report without delay
+                Err(_) => Some(event),
+            },
+            layout::Event::Press(..) => Some(event),
+            layout::Event::Release(i, j) => match self.pending.iter().position(|(key, _)| *key == (i, j)) {
+                Some(pos) => {
+                    // Released before being reported
+                    self.pending.remove(pos);
+                    None
+                },
+                None => Some(event),
+            },
+        }
+    }
+
+    /// Advance time by one tick, returns presses of keys that have been held long enough
+    pub fn tick(&mut self) -> heapless::Vec<layout::Event, SLOW_KEYS_MAX_PENDING> {
+        self.time = self.time.wrapping_add(1);
+        let mut due = heapless::Vec::new();
+        let (time, hold, enabled) = (self.time, self.hold, self.enabled);
+        self.pending.retain(|&((i, j), pressed)| {
+            let ready = !enabled || time.wrapping_sub(pressed) >= hold;
+            if ready {
+                // Cannot fail, there are never more due events than pending ones
+                due.push(layout::Event::Press(i, j)).ok();
+            }
+            !ready
+        });
+        due
+    }
+}
+
+impl BounceKeys {
+    /// Create disabled filter, interval is converted to ticks of [`Self::tick`] calls
+    pub fn new(config: &AccessibilityConfig, rate: TickRate) -> Self {
+        Self {
+            enabled: false,
+            interval: rate.from_ms(config.bounce_keys_ms as u32),
+            time: 0,
+            released: heapless::Deque::new(),
+            ignored: heapless::Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enable/disable the filter, releases of already ignored presses are still ignored
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            defmt::info!("Bounce keys: {=bool}", enabled);
+            self.released.clear();
+        }
+        self.enabled = enabled;
+    }
+
+    /// Filter key event, returns the event if it should be reported
+    pub fn event(&mut self, event: layout::Event) -> Option<layout::Event> {
+        match event {
+            layout::Event::Press(i, j) if self.enabled => {
+                let recent = self.released.iter()
+                    .any(|(key, t)| *key == (i, j) && self.time.wrapping_sub(*t) < self.interval);
+                // If too many keys are ignored at once, it is most likely intentional
+                if recent && self.ignored.push((i, j)).is_ok() {
+                    defmt::info!("Bounce keys: ignored ({=u8}, {=u8})", i, j);
+                    return None;
+                }
+                Some(event)
+            },
+            layout::Event::Press(..) => Some(event),
+            layout::Event::Release(i, j) => {
+                if let Some(pos) = self.ignored.iter().position(|key| *key == (i, j)) {
+                    self.ignored.swap_remove(pos);
+                    return None;
+                }
+                if self.enabled {
+                    if self.released.is_full() {
+                        self.released.pop_front();
+                    }
+                    // Cannot fail, space has been made above
+                    self.released.push_back(((i, j), self.time)).ok();
+                }
+                Some(event)
+            },
+        }
+    }
+
+    /// Advance time by one tick
+    pub fn tick(&mut self) {
+        self.time = self.time.wrapping_add(1);
+        while let Some((_, t)) = self.released.front() {
+            if self.time.wrapping_sub(*t) < self.interval {
+                break;
+            }
+            self.released.pop_front();
+        }
+    }
 }
 
 impl PressedKeys {
@@ -72,4 +563,267 @@ impl PressedKeys {
     pub fn is_pressed(&self, led_key: u8) -> bool {
         self.get(led_key)
     }
+
+    /// Keys that differ in `other`, as (LED number, state in `other`)
+    pub fn changes(&self, other: PressedKeys) -> impl Iterator<Item = (u8, bool)> {
+        let diff = self.0 ^ other.0;
+        (0..NLEDS as u8)
+            .filter(move |led| diff & (1 << led) != 0)
+            .map(move |led| (led, other.get(led)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use layout::Event;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct TestMatrix {
+        keys: [[bool; NCOLS]; NROWS],
+    }
+
+    impl KeyMatrix for TestMatrix {
+        fn read(&mut self) -> [[bool; NCOLS]; NROWS] {
+            self.keys
+        }
+        fn arm_wake(&mut self) {}
+        fn disarm_wake(&mut self) {}
+        fn is_wake_armed(&self) -> bool {
+            false
+        }
+        fn any_key_down(&self) -> bool {
+            false
+        }
+    }
+
+    fn run(health: &mut MatrixHealth, ticks: u32) {
+        for _ in 0..ticks {
+            health.tick();
+        }
+    }
+
+    #[test]
+    fn pressed_changes() {
+        let old = PressedKeys(0b0110);
+        let new = PressedKeys(0b1100);
+        assert_eq!(old.changes(new).collect::<Vec<_>>(), [(1, false), (3, true)]);
+        assert_eq!(new.changes(new).count(), 0);
+    }
+
+    #[test]
+    fn stuck_key_at_boot() {
+        let mut health = MatrixHealth::new(TickRate::new(1000));
+        health.tick();
+        health.on_event(Event::Press(1, 2));
+        run(&mut health, BOOT_WINDOW_MS);
+        health.on_event(Event::Press(2, 3));
+        assert_eq!(health.stuck().count(), 0);
+        run(&mut health, STUCK_TIMEOUT_MS);
+        assert_eq!(health.stuck().collect::<Vec<_>>(), [(1, 2)]);
+        health.on_event(Event::Release(1, 2));
+        assert_eq!(health.stuck().count(), 0);
+    }
+
+    #[test]
+    fn released_key_not_stuck() {
+        let mut health = MatrixHealth::new(TickRate::new(1000));
+        health.on_event(Event::Press(0, 0));
+        run(&mut health, 10);
+        health.on_event(Event::Release(0, 0));
+        run(&mut health, STUCK_TIMEOUT_MS);
+        assert_eq!(health.stuck().count(), 0);
+    }
+
+    #[test]
+    fn chatter_counting() {
+        let mut health = MatrixHealth::new(TickRate::new(1000));
+        run(&mut health, STUCK_TIMEOUT_MS);
+        for _ in 0..3 {
+            health.on_event(Event::Press(3, 1));
+            run(&mut health, 5);
+            health.on_event(Event::Release(3, 1));
+            run(&mut health, 5);
+        }
+        // Slow presses are fine
+        run(&mut health, CHATTER_WINDOW_MS);
+        health.on_event(Event::Press(3, 1));
+        assert_eq!(health.chatter().collect::<Vec<_>>(), [((3, 1), 2)]);
+        assert!(health.offending_leds().get(BoardSide::led_number((3, 1)).unwrap()));
+    }
+
+    const DEBOUNCE: DebounceConfig = DebounceConfig { defer_ms: 5, eager_ms: 5 };
+
+    fn test_keys() -> Keys<TestMatrix> {
+        Keys::new(BoardSide::Left, TestMatrix::default(), &DEBOUNCE, TickRate::new(1000))
+    }
+
+    /// Scan keys in the next tick
+    fn scan(keys: &mut Keys<TestMatrix>, now: &mut u32) -> Vec<Event> {
+        *now += 1;
+        keys.scan(*now).collect()
+    }
+
+    #[test]
+    fn deferred_debounce() {
+        let (mut keys, mut t) = (test_keys(), 0);
+        keys.matrix.keys[0][1] = true;
+        for _ in 0..3 {
+            assert!(scan(&mut keys, &mut t).is_empty());
+        }
+        // Bounce restarts timing
+        keys.matrix.keys[0][1] = false;
+        assert!(scan(&mut keys, &mut t).is_empty());
+        keys.matrix.keys[0][1] = true;
+        for _ in 0..5 {
+            assert!(scan(&mut keys, &mut t).is_empty());
+        }
+        assert_eq!(scan(&mut keys, &mut t), [Event::Press(0, 1)]);
+    }
+
+    #[test]
+    fn debounce_time_in_ms() {
+        let config = DebounceConfig { defer_ms: 5, eager_ms: 0 };
+        let mut keys = Keys::new(BoardSide::Left, TestMatrix::default(), &config, TickRate::new(2000));
+        let mut t = 0;
+        keys.matrix.keys[2][2] = true;
+        let scans = (0..20).position(|_| !scan(&mut keys, &mut t).is_empty());
+        assert_eq!(scans, Some(10));
+    }
+
+    #[test]
+    fn debounce_skipped_scans() {
+        let mut keys = test_keys();
+        keys.matrix.keys[0][1] = true;
+        assert_eq!(keys.scan(1).count(), 0);
+        assert_eq!(keys.scan(4).count(), 0);
+        // Scans in between did not happen, but debouncing time has already passed
+        assert_eq!(keys.scan(6).collect::<Vec<_>>(), [Event::Press(0, 1)]);
+    }
+
+    #[test]
+    fn eager_debounce() {
+        let (mut keys, mut t) = (test_keys(), 0);
+        keys.matrix.keys[1][2] = true;
+        assert!(scan(&mut keys, &mut t).is_empty());
+        keys.matrix.keys[1][2] = false;
+        for _ in 0..10 {
+            assert!(scan(&mut keys, &mut t).is_empty());
+        }
+
+        keys.set_eager_debounce(true);
+        keys.matrix.keys[1][2] = true;
+        assert_eq!(scan(&mut keys, &mut t), [Event::Press(1, 2)]);
+        // Bouncing is ignored during lockout, changes are reported 5 ticks after the previous one
+        keys.matrix.keys[1][2] = false;
+        for _ in 0..4 {
+            assert!(scan(&mut keys, &mut t).is_empty());
+        }
+        assert_eq!(scan(&mut keys, &mut t), [Event::Release(1, 2)]);
+        assert!(keys.pressed().is_none());
+    }
+
+    const ACCESSIBILITY: AccessibilityConfig = AccessibilityConfig { slow_keys_ms: 5, bounce_keys_ms: 10 };
+
+    fn slow_keys() -> SlowKeys {
+        let mut slow = SlowKeys::new(&ACCESSIBILITY, TickRate::new(1000));
+        slow.set_enabled(true);
+        slow
+    }
+
+    fn ticks(slow: &mut SlowKeys, n: usize) -> Vec<Event> {
+        (0..n).flat_map(|_| slow.tick()).collect()
+    }
+
+    #[test]
+    fn slow_keys_disabled() {
+        let mut slow = slow_keys();
+        slow.set_enabled(false);
+        assert_eq!(slow.event(Event::Press(0, 1)), Some(Event::Press(0, 1)));
+        assert_eq!(slow.event(Event::Release(0, 1)), Some(Event::Release(0, 1)));
+        assert_eq!(ticks(&mut slow, 10), []);
+    }
+
+    #[test]
+    fn slow_keys_delay_press() {
+        let mut slow = slow_keys();
+        assert_eq!(slow.event(Event::Press(0, 1)), None);
+        assert_eq!(ticks(&mut slow, 4), []);
+        assert_eq!(ticks(&mut slow, 1), [Event::Press(0, 1)]);
+        assert_eq!(ticks(&mut slow, 10), []);
+        assert_eq!(slow.event(Event::Release(0, 1)), Some(Event::Release(0, 1)));
+    }
+
+    #[test]
+    fn slow_keys_ignore_short_press() {
+        let mut slow = slow_keys();
+        assert_eq!(slow.event(Event::Press(2, 7)), None);
+        assert_eq!(ticks(&mut slow, 3), []);
+        assert_eq!(slow.event(Event::Release(2, 7)), None);
+        assert_eq!(ticks(&mut slow, 10), []);
+    }
+
+    #[test]
+    fn slow_keys_disable_while_pending() {
+        let mut slow = slow_keys();
+        assert_eq!(slow.event(Event::Press(1, 1)), None);
+        slow.set_enabled(false);
+        assert_eq!(ticks(&mut slow, 1), [Event::Press(1, 1)]);
+        assert_eq!(slow.event(Event::Release(1, 1)), Some(Event::Release(1, 1)));
+    }
+
+    #[test]
+    fn slow_keys_too_many_pending() {
+        let mut slow = slow_keys();
+        for j in 0..SLOW_KEYS_MAX_PENDING as u8 {
+            assert_eq!(slow.event(Event::Press(0, j)), None);
+        }
+        assert_eq!(slow.event(Event::Press(1, 0)), Some(Event::Press(1, 0)));
+        assert_eq!(ticks(&mut slow, 5).len(), SLOW_KEYS_MAX_PENDING);
+    }
+
+    fn bounce_keys() -> BounceKeys {
+        let mut bounce = BounceKeys::new(&ACCESSIBILITY, TickRate::new(1000));
+        bounce.set_enabled(true);
+        bounce
+    }
+
+    fn tap(bounce: &mut BounceKeys, (i, j): (u8, u8)) -> Vec<Event> {
+        [Event::Press(i, j), Event::Release(i, j)].into_iter()
+            .filter_map(|e| bounce.event(e))
+            .collect()
+    }
+
+    #[test]
+    fn bounce_keys_disabled() {
+        let mut bounce = bounce_keys();
+        bounce.set_enabled(false);
+        assert_eq!(tap(&mut bounce, (0, 0)), [Event::Press(0, 0), Event::Release(0, 0)]);
+        assert_eq!(tap(&mut bounce, (0, 0)), [Event::Press(0, 0), Event::Release(0, 0)]);
+    }
+
+    #[test]
+    fn bounce_keys_ignore_repeated() {
+        let mut bounce = bounce_keys();
+        assert_eq!(tap(&mut bounce, (1, 2)), [Event::Press(1, 2), Event::Release(1, 2)]);
+        (0..9).for_each(|_| bounce.tick());
+        assert_eq!(tap(&mut bounce, (1, 2)), []);
+        // Other keys are not affected
+        assert_eq!(tap(&mut bounce, (1, 3)), [Event::Press(1, 3), Event::Release(1, 3)]);
+        // Ignored press does not restart the interval
+        bounce.tick();
+        assert_eq!(tap(&mut bounce, (1, 2)), [Event::Press(1, 2), Event::Release(1, 2)]);
+    }
+
+    #[test]
+    fn bounce_keys_ignore_release_after_disable() {
+        let mut bounce = bounce_keys();
+        assert_eq!(tap(&mut bounce, (4, 1)), [Event::Press(4, 1), Event::Release(4, 1)]);
+        assert_eq!(bounce.event(Event::Press(4, 1)), None);
+        bounce.set_enabled(false);
+        assert_eq!(bounce.event(Event::Release(4, 1)), None);
+        assert_eq!(tap(&mut bounce, (4, 1)), [Event::Press(4, 1), Event::Release(4, 1)]);
+    }
 }
@@ -0,0 +1,184 @@
+use defmt::Format;
+
+use crate::hal_ext::clock::Instant;
+
+/// Upper limits of histogram buckets in microseconds, last bucket holds all larger values
+pub const BUCKET_LIMITS_US: [u32; 7] = [250, 500, 1000, 2000, 4000, 8000, 16000];
+const N_BUCKETS: usize = BUCKET_LIMITS_US.len() + 1;
+
+/// Measures latency from key press detection (matrix scan) to USB HID report write
+///
+/// Only one press is measured at a time, presses that occur while waiting for a report
+/// are ignored. The press is tagged with the sequence number of the next report pushed to
+/// the queue, so reports queued before the press do not end the measurement. This is a test
+/// mode, disabled by default.
+pub struct LatencyMeter {
+    enabled: bool,
+    pending: Option<Pending>,
+    stats: LatencyStats,
+}
+
+#[derive(Clone, Copy)]
+struct Pending {
+    start: Instant,
+    /// Sequence number of the first report that can result from the press
+    seq: u32,
+}
+
+/// Distribution of measured latencies
+#[derive(Clone, Default, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct LatencyStats {
+    /// Number of samples in each bucket, see [`BUCKET_LIMITS_US`]
+    pub histogram: [u16; N_BUCKETS],
+    pub min: u32,
+    pub max: u32,
+    total: u32,
+    count: u32,
+}
+
+impl LatencyStats {
+    pub fn add(&mut self, us: u32) {
+        let bucket = BUCKET_LIMITS_US.iter()
+            .position(|limit| us < *limit)
+            .unwrap_or(N_BUCKETS - 1);
+        self.histogram[bucket] = self.histogram[bucket].saturating_add(1);
+        self.min = if self.count == 0 { us } else { self.min.min(us) };
+        self.max = self.max.max(us);
+        self.total = self.total.saturating_add(us);
+        self.count = self.count.saturating_add(1);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn avg(&self) -> u32 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+impl Format for LatencyStats {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "n={=u32} min={=u32} avg={=u32} max={=u32} us, hist={=[u16]}",
+            self.count, self.min, self.avg(), self.max, &self.histogram[..]);
+    }
+}
+
+impl LatencyMeter {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            pending: None,
+            stats: LatencyStats {
+                histogram: [0; N_BUCKETS],
+                min: 0,
+                max: 0,
+                total: 0,
+                count: 0,
+            },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enable/disable measurements, resets statistics
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.pending = None;
+        self.stats = LatencyStats::default();
+    }
+
+    /// Key press has been detected, `seq` is the sequence number of the next report to be queued
+    pub fn on_press(&mut self, seq: u32, now: impl FnOnce() -> Instant) {
+        if self.enabled && self.pending.is_none() {
+            self.pending = Some(Pending { start: now(), seq });
+        }
+    }
+
+    /// Keyboard HID report with sequence number `seq` has been written successfully
+    pub fn on_report_written(&mut self, seq: u32, now: impl FnOnce() -> Instant) {
+        let Some(pending) = self.pending else {
+            return;
+        };
+        // Wrapping comparison, reports older than the press have "negative" distance
+        if seq.wrapping_sub(pending.seq) < u32::MAX / 2 {
+            self.pending = None;
+            self.stats.add(now().duration_since(pending.start).as_micros());
+        }
+    }
+
+    /// Get statistics collected so far and reset them
+    pub fn take_stats(&mut self) -> LatencyStats {
+        core::mem::take(&mut self.stats)
+    }
+}
+
+impl Default for LatencyMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_ext::clock::Duration;
+
+    fn at(us: u32) -> impl FnOnce() -> Instant {
+        move || Instant::from_ticks(us)
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let mut stats = LatencyStats::default();
+        for us in [0, 249, 250, 1500, 100_000] {
+            stats.add(us);
+        }
+        assert_eq!(stats.histogram, [2, 1, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(stats.min, 0);
+        assert_eq!(stats.max, 100_000);
+        assert_eq!(stats.count(), 5);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let mut meter = LatencyMeter::new();
+        meter.on_press(0, at(0));
+        meter.on_report_written(0, at(100));
+        assert_eq!(meter.take_stats().count(), 0);
+    }
+
+    #[test]
+    fn measure_from_first_press() {
+        let mut meter = LatencyMeter::new();
+        meter.set_enabled(true);
+        meter.on_press(5, at(1000));
+        meter.on_press(6, at(1500));
+        meter.on_report_written(5, at(2200));
+        // No pending press
+        meter.on_report_written(6, at(3000));
+        let stats = meter.take_stats();
+        assert_eq!(stats.count(), 1);
+        assert_eq!(stats.min, (Instant::from_ticks(2200) - Instant::from_ticks(1000)).as_micros());
+        assert_eq!(Duration::from_micros(stats.max), Duration::from_micros(1200));
+        assert_eq!(meter.take_stats().count(), 0);
+    }
+
+    #[test]
+    fn skip_reports_queued_before_press() {
+        let mut meter = LatencyMeter::new();
+        meter.set_enabled(true);
+        meter.on_press(u32::MAX, at(1000));
+        meter.on_report_written(u32::MAX - 2, at(1100));
+        meter.on_report_written(u32::MAX - 1, at(1200));
+        assert_eq!(meter.take_stats().count(), 0);
+        // First report after the press, sequence numbers wrap around
+        meter.on_report_written(0, at(1700));
+        let stats = meter.take_stats();
+        assert_eq!(stats.count(), 1);
+        assert_eq!(Duration::from_micros(stats.max), Duration::from_micros(700));
+    }
+}
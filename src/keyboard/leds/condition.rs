@@ -6,7 +6,7 @@ use crate::bsp::sides::{BoardSide, PerSide};
 use crate::keyboard::hid::KeyboardLeds;
 use crate::keyboard::keys::PressedKeys;
 use crate::keyboard::role::Role;
-use super::{Keys, Condition, KeyboardLed, KeyAction};
+use super::{Keys, Condition, KeyboardLed, KeyAction, ValueSource};
 
 /// Collection of keyboard state variables that can be used as conditions
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
@@ -17,6 +17,22 @@ pub struct KeyboardState {
     pub layer: u8,
     pub pressed: PerSide<PressedKeys>,
     pub allow_bootloader: bool,
+    /// Key tester mode is active, LED rules are overridden
+    pub key_tester: bool,
+    /// Bitmask of held emulated mouse buttons as in HID report
+    pub mouse_buttons: u8,
+    /// Game mode is active, LED animations are stopped
+    pub game_mode: bool,
+    /// Global LED brightness, filled in by [`super::LedController`]
+    pub brightness: u8,
+    /// Selected brightness preset, `None` after brightness has been changed in steps
+    pub brightness_preset: Option<u8>,
+    /// Joystick readings are used (not disabled by the user)
+    pub joystick_enabled: bool,
+    /// Training mode is active, LEDs outside of the teaching set are dimmed
+    pub training: bool,
+    /// Key flashed in training mode (global coordinates)
+    pub training_hint: Option<(u8, u8)>,
 }
 
 /// Per-layer bitmask cache of action types ([`super::KeyAction`]) on layout
@@ -89,6 +105,17 @@ impl Condition {
             },
             Condition::Layer(layer) => PressedKeys::with_all(state.layer == *layer),
             Condition::BootloaderAllowed => PressedKeys::with_all(state.allow_bootloader),
+            Condition::MouseButton(button) => PressedKeys::with_all(state.mouse_buttons & button.mask() != 0),
+            Condition::GameMode => PressedKeys::with_all(state.game_mode),
+            Condition::BrightnessPreset(i) => PressedKeys::with_all(state.brightness_preset == Some(*i)),
+            Condition::JoystickEnabled => PressedKeys::with_all(state.joystick_enabled),
+            Condition::BarGraph(source) => {
+                let (value, max) = match source {
+                    ValueSource::Brightness => (state.brightness as u32, u8::MAX as u32),
+                    ValueSource::Layer => (state.layer as u32, layer_actions.len().saturating_sub(1) as u32),
+                };
+                Self::bar_graph(side, value, max)
+            },
             Condition::Not(c) => !c.applies_to(this_side, state, side, layer_actions),
             Condition::And(conds) => conds.iter()
                 .fold(PressedKeys::with_all(true), |acc, c| acc & c.applies_to(this_side, state, side, layer_actions)),
@@ -98,6 +125,22 @@ impl Condition {
     }
 }
 
+impl Condition {
+    /// Select LEDs in columns that lie below `value / max` of keyboard width
+    fn bar_graph(side: BoardSide, value: u32, max: u32) -> PressedKeys {
+        let mut leds = PressedKeys::NONE;
+        if max == 0 {
+            return leds;
+        }
+        let total = 2 * NCOLS as u32;
+        for led in 0..NLEDS as u8 {
+            let (_, col) = side.coords_to_global(BoardSide::led_coords(led));
+            leds.set(led, (col as u32 + 1) * max <= value * total);
+        }
+        leds
+    }
+}
+
 impl KeyActionCache {
     const EMPTY: Self = KeyActionCache {
         no_op: PerSide { left: PressedKeys::NONE, right: PressedKeys::NONE },
@@ -227,8 +270,8 @@ pub trait RuleKeys {
     /// Internal iterator over key coordinates
     fn for_each<F: FnMut(u8, u8)>(&self, f: F);
 
-    /// Internal iterator over led coordinates
-    fn for_each_led<F: FnMut(u8)>(&self, f: F);
+    /// Internal iterator over leds of matching keys on given board side
+    fn for_each_led<F: FnMut(u8)>(&self, side: BoardSide, f: F);
 }
 
 fn cols_for_row(row: u8) -> impl Iterator<Item = u8> {
@@ -301,8 +344,8 @@ impl<'a> RuleKeys for Option<&'a Keys> {
         }
     }
 
-    /// Iterate over all led positions (so always local)
-    fn for_each_led<F: FnMut(u8)>(&self, mut f: F) {
+    /// Iterate over led numbers (local to `side`) of keys located on `side`
+    fn for_each_led<F: FnMut(u8)>(&self, side: BoardSide, mut f: F) {
         match self {
             None => for led in 0..(NLEDS as u8) {
                 f(led);
@@ -317,7 +360,7 @@ impl<'a> RuleKeys for Option<&'a Keys> {
                 }
             },
             Some(Keys::Cols(cols)) => {
-                for col in cols.iter().copied() {
+                for col in cols.iter().copied().filter(|c| side.has_coords((0, *c))) {
                     if let Some(leds) = COL_LEDS_LOOKUP.get(col as usize) {
                         for led in leds.iter().copied() {
                             f(led);
@@ -326,9 +369,10 @@ impl<'a> RuleKeys for Option<&'a Keys> {
                 }
             },
             Some(Keys::Keys(keys)) => {
-                for (row, col) in keys.iter() {
-                    let (row, col) = BoardSide::coords_to_local((*row, *col));
-                    if let Some(led) = BoardSide::led_number((row, col)) {
+                let on_side = keys.iter().copied()
+                    .filter(|&(row, col)| BoardSide::global_coords_valid(row, col) && side.has_coords((row, col)));
+                for key in on_side {
+                    if let Some(led) = BoardSide::led_number(BoardSide::coords_to_local(key)) {
                         f(led);
                     }
                 }
@@ -374,13 +418,19 @@ mod tests {
             assert!(!set.contains(&coords), "Key found: {:?}", coords);
         }
 
-        // Also verify for_each_led, here the coordinates are always local so we just check if any
-        // side is in the set.
-        keys.for_each_led(|led| {
-            let coords = BoardSide::led_coords(led);
-            let other = BoardSide::Right.coords_to_global(coords);
-            assert!(set.contains(&coords) || set.contains(&other), "{coords:?}/{other:?} not in set {set:?}");
-        });
+        // Also verify for_each_led, LEDs are side-local so map them back to global coordinates
+        for side in BoardSide::EACH {
+            let mut visited = HashSet::new();
+            keys.for_each_led(side, |led| {
+                let coords = side.coords_to_global(BoardSide::led_coords(led));
+                assert!(set.contains(&coords), "{coords:?} on {side:?} not in set {set:?}");
+                visited.insert(coords);
+            });
+            let on_side = set.iter().filter(|key| side.has_coords(**key));
+            for key in on_side.filter(|key| BoardSide::led_number(BoardSide::coords_to_local(**key)).is_some()) {
+                assert!(visited.contains(key), "LED of {key:?} not visited on {side:?}");
+            }
+        }
     }
 
     #[test]
@@ -433,6 +483,14 @@ mod tests {
                 right: LedsBitset(right)
             },
             allow_bootloader: false,
+            key_tester: false,
+            mouse_buttons: 0,
+            game_mode: false,
+            brightness: 0,
+            brightness_preset: None,
+            joystick_enabled: true,
+            training: false,
+            training_hint: None,
         }
     }
 
@@ -485,6 +543,40 @@ mod tests {
         assert_eq!(leds.0, 0b_00001010_10011110_00011000_00000000);
     }
 
+    #[test]
+    fn condition_mouse_button() {
+        use crate::keyboard::actions::MouseButton;
+        let cond = Condition::MouseButton(MouseButton::Right);
+        let mut state = simple_keyboard_state(0, 0);
+        state.mouse_buttons = MouseButton::Left.mask();
+        assert!(cond.applies_to(BoardSide::Left, &state, BoardSide::Right, &CACHE).is_none());
+        state.mouse_buttons |= MouseButton::Right.mask();
+        assert!(cond.applies_to(BoardSide::Left, &state, BoardSide::Right, &CACHE).is_all());
+    }
+
+    #[test]
+    fn condition_bar_graph() {
+        let cond = Condition::BarGraph(ValueSource::Brightness);
+        let count = |state: &KeyboardState, side| {
+            let leds = cond.applies_to(BoardSide::Left, state, side, &CACHE);
+            (0..NLEDS as u8).filter(|led| leds.get(*led)).map(|led| BoardSide::led_coords(led).1).max()
+        };
+        let mut state = simple_keyboard_state(0, 0);
+        assert_eq!(count(&state, BoardSide::Left), None);
+        assert_eq!(count(&state, BoardSide::Right), None);
+        // Half brightness covers the whole left side
+        state.brightness = 128;
+        assert_eq!(count(&state, BoardSide::Left), Some(NCOLS as u8 - 1));
+        assert_eq!(count(&state, BoardSide::Right), None);
+        state.brightness = 255;
+        assert!(cond.applies_to(BoardSide::Left, &state, BoardSide::Right, &CACHE).is_all());
+
+        // Cache has 2 layers
+        let cond = Condition::BarGraph(ValueSource::Layer);
+        state.layer = 1;
+        assert!(cond.applies_to(BoardSide::Left, &state, BoardSide::Right, &CACHE).is_all());
+    }
+
     #[test]
     fn condition_not() {
         let cond = Condition::Not(&Condition::Pressed);
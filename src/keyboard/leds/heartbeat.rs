@@ -0,0 +1,130 @@
+use rgb::RGB8;
+
+use crate::bsp::sides::BoardSide;
+use crate::keyboard::ticks::TickRate;
+use super::pattern::ColorGenerator;
+
+/// Configuration of LED pulsing while USB is suspended
+#[derive(Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub enabled: bool,
+    /// Key (global coordinates) with the LED used as the indicator
+    pub key: (u8, u8),
+    /// Color at the peak of a pulse, global brightness does not apply
+    pub color: RGB8,
+    /// Time between starts of subsequent pulses in milliseconds
+    pub period_ms: u16,
+    /// Duration of a single pulse in milliseconds
+    pub duration_ms: u16,
+}
+
+/// Short periodic pulse of a single LED while the keyboard is suspended
+///
+/// All LEDs are disabled in suspend, which looks the same as keyboard without power.
+/// The pulse allows to tell "asleep" from "dead".
+pub struct Heartbeat {
+    color: RGB8,
+    /// Pulse period in ticks
+    period: u32,
+    /// Pulse duration in ticks, not longer than period
+    duration: u16,
+    side: BoardSide,
+    led: u8,
+    elapsed: u32,
+}
+
+impl Heartbeat {
+    /// Create heartbeat, `None` if disabled or the key has no LED
+    pub fn new(config: HeartbeatConfig, tick_rate: TickRate) -> Option<Self> {
+        let (row, col) = config.key;
+        if !config.enabled || config.period_ms == 0 || !BoardSide::global_coords_valid(row, col) {
+            return None;
+        }
+        let period = tick_rate.from_ms(config.period_ms as u32);
+        let duration = tick_rate.from_ms(config.duration_ms as u32).min(period).min(u16::MAX as u32) as u16;
+        BoardSide::led_number(BoardSide::coords_to_local(config.key)).map(|led| Self {
+            color: config.color,
+            period,
+            duration,
+            side: BoardSide::from_coords(config.key),
+            led,
+            elapsed: 0,
+        })
+    }
+
+    /// Side and number of the indicator LED
+    pub fn led(&self) -> (BoardSide, u8) {
+        (self.side, self.led)
+    }
+
+    /// Advance time, returns indicator color while suspended
+    ///
+    /// Pulses start from the moment of suspend.
+    pub fn tick(&mut self, time_delta: u16, suspended: bool) -> Option<RGB8> {
+        if !suspended {
+            self.elapsed = 0;
+            return None;
+        }
+        let t = self.elapsed % self.period;
+        self.elapsed = self.elapsed.wrapping_add(time_delta as u32);
+
+        let black = RGB8::default();
+        let duration = self.duration;
+        let rise = duration / 2;
+        let color = match t {
+            t if t >= duration as u32 => black,
+            t if t < rise as u32 => ColorGenerator::interpolate(t as u16, rise, black, self.color),
+            t => ColorGenerator::interpolate(t as u16 - rise, duration - rise, self.color, black),
+        };
+        Some(color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: HeartbeatConfig = HeartbeatConfig {
+        enabled: true,
+        key: (0, 11),
+        color: RGB8::new(0, 100, 0),
+        period_ms: 1000,
+        duration_ms: 100,
+    };
+
+    const RATE: TickRate = TickRate::new(1000);
+
+    #[test]
+    fn pulses_while_suspended() {
+        let mut hb = Heartbeat::new(CONFIG, RATE).unwrap();
+        assert_eq!(hb.led(), (BoardSide::Right, 5));
+        assert_eq!(hb.tick(10, false), None);
+        let colors: std::vec::Vec<_> = (0..22).map(|_| hb.tick(50, true).unwrap().g).collect();
+        assert_eq!(colors[..4], [0, 100, 0, 0]);
+        assert!(colors[3..20].iter().all(|g| *g == 0));
+        assert_eq!(colors[20..], [0, 100]);
+
+        // Restarts on next suspend
+        assert_eq!(hb.tick(50, false), None);
+        assert_eq!(hb.tick(50, true), Some(RGB8::default()));
+        assert_eq!(hb.tick(50, true), Some(CONFIG.color));
+    }
+
+    #[test]
+    fn timing_in_ms() {
+        // Twice as many ticks per pulse and period
+        let mut hb = Heartbeat::new(CONFIG, TickRate::new(2000)).unwrap();
+        let colors: std::vec::Vec<_> = (0..42).map(|_| hb.tick(50, true).unwrap().g).collect();
+        assert_eq!(colors[..6], [0, 50, 100, 50, 0, 0]);
+        assert!(colors[4..40].iter().all(|g| *g == 0));
+        assert_eq!(colors[40..], [0, 50]);
+    }
+
+    #[test]
+    fn disabled() {
+        assert!(Heartbeat::new(HeartbeatConfig { enabled: false, ..CONFIG }, RATE).is_none());
+        assert!(Heartbeat::new(HeartbeatConfig { period_ms: 0, ..CONFIG }, RATE).is_none());
+        // Joystick has no LED
+        assert!(Heartbeat::new(HeartbeatConfig { key: (4, 4), ..CONFIG }, RATE).is_none());
+    }
+}
@@ -48,15 +48,33 @@ mod condition;
 mod output;
 /// Pattern iteration and color generation logic
 mod pattern;
+/// Local reactive lighting on slave
+mod reactive;
+/// Notification effects triggered by host software
+mod notify;
+/// One-shot patterns played on top of controller output
+mod overlay;
+/// Suspend indicator
+mod heartbeat;
+/// Night mode color adjustment and its persistent state
+pub mod night;
+/// Brightness presets and persistence of the selected one
+pub mod preset;
 
 pub use output::{LedOutput, Leds};
-pub use pattern::LedController;
+pub use pattern::{LedController, ControllerState};
 pub use condition::{KeyboardState, KeyActionCache};
 pub use bitset::LedsBitset;
+pub use reactive::ReactiveConfig;
+pub use notify::{Notification, OverrideRequest, CMD_LED_OVERRIDE, MAX_NOTIFICATIONS};
+pub use overlay::{Overlay, OverlayId, OverlaysState, SOLID, MAX_OVERLAYS};
+pub use heartbeat::HeartbeatConfig;
 pub use super::role::Role;
 
 use rgb::RGB8;
 
+use super::actions::MouseButton;
+
 /// List of keyboard LED lightning configurations
 ///
 /// Configurations that can be cycled through, but only one is active at a time.
@@ -78,17 +96,29 @@ pub struct LedRule {
     pub pattern: Pattern,
 }
 
+/// Upper limit of brightness for LEDs of given keys
+///
+/// Useful for keys with different keycaps, e.g. to dim LEDs under translucent keycaps
+/// while keeping indicators bright.
+pub struct BrightnessLimit {
+    /// Keys to which the limit applies or all keys if `None`
+    pub keys: Option<&'static Keys>,
+    /// Maximum brightness, applied on top of the global brightness
+    pub max: u8,
+}
+
 /// Defines which keys to match (rows/cols must be valid)
 ///
-/// Note that joystick is not considered as a key, because it has no LED
+/// Columns and keys use global coordinates, so they match keys on one half only, while rows
+/// span both halves (configurations of version 1 used side-local columns and are converted by
+/// ghanima-config). Note that joystick is not considered as a key, because it has no LED
 /// associated.
 pub enum Keys {
     /// All keys from given rows
     Rows(&'static [u8]),
-    /// All keys from given columns
+    /// All keys from given (global) columns
     Cols(&'static [u8]),
-    /// Specific keys
-    // FIXME: should work on global coordinates instead of side-local
+    /// Specific keys in global (row, col) coordinates
     Keys(&'static [(u8, u8)]),
 }
 
@@ -112,6 +142,18 @@ pub enum Condition {
     Layer(u8),
     /// Applies if the keyboard would allow to detach to DFU bootloader
     BootloaderAllowed,
+    /// Applies while given emulated mouse button is held
+    MouseButton(MouseButton),
+    /// Applies while game mode is enabled
+    GameMode,
+    /// Applies while brightness preset with given index is selected
+    BrightnessPreset(u8),
+    /// Applies while joystick is enabled
+    JoystickEnabled,
+    /// Applies to keys covered by a bar graph of the value, growing from the leftmost column
+    ///
+    /// Usually combined with `keys` limited to a single row.
+    BarGraph(ValueSource),
     /// Applies when the internal condition does not
     Not(&'static Condition),
     /// Applies when all internal conditions apply
@@ -120,6 +162,14 @@ pub enum Condition {
     Or(&'static [Condition]),
 }
 
+/// Numeric value that can be visualized on LEDs
+pub enum ValueSource {
+    /// Global LED brightness
+    Brightness,
+    /// Current layer in relation to the number of layers
+    Layer,
+}
+
 /// Standard keyboard LED
 #[derive(PartialEq)]
 pub enum KeyboardLed {
@@ -152,7 +202,10 @@ pub struct Pattern {
 }
 
 /// Pattern phase shift depending on key position
-// TODO: rethink
+///
+/// Repeating pattern on a key at position (X, Y) in mm (see
+/// [`crate::bsp::sides::BoardSide::key_position`]) is shifted by `x * X + y * Y` ms, which
+/// allows waves running across the keyboard.
 #[derive(PartialEq)]
 pub struct Phase {
     pub x: f32,
@@ -0,0 +1,43 @@
+use rgb::RGB8;
+
+use crate::bsp::persistent::Persistent;
+
+/// Brightness limit in night mode
+pub const MAX_BRIGHTNESS: u8 = u8::MAX / 4;
+/// Per-channel scaling (as fraction of 256) shifting colors towards warmer temperature
+const CHANNEL_SCALE: RGB8 = RGB8::new(255, 170, 64);
+
+#[link_section = ".uninit.ghanima.night"]
+static STATE: Persistent<bool, 0x6e69_6768> = Persistent::new();
+
+/// Reduce blue (and partially green) channel of a color
+pub fn warm(color: RGB8) -> RGB8 {
+    let scale = |c: u8, s: u8| (((s as u16 + 1) * c as u16) >> 8) as u8;
+    RGB8::new(
+        scale(color.r, CHANNEL_SCALE.r),
+        scale(color.g, CHANNEL_SCALE.g),
+        scale(color.b, CHANNEL_SCALE.b),
+    )
+}
+
+/// Night mode state from before the last software reset, disabled after power loss
+pub fn load() -> bool {
+    STATE.load().unwrap_or(false)
+}
+
+/// Preserve night mode state over system reset
+pub fn store(enabled: bool) {
+    STATE.store(enabled);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warm_colors() {
+        assert_eq!(warm(RGB8::new(255, 255, 255)), RGB8::new(255, 170, 64));
+        assert_eq!(warm(RGB8::new(100, 0, 0)), RGB8::new(100, 0, 0));
+        assert_eq!(warm(RGB8::new(0, 0, 0)), RGB8::new(0, 0, 0));
+    }
+}
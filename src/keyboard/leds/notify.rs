@@ -0,0 +1,237 @@
+use rgb::RGB8;
+
+use crate::bsp::sides::{BoardSide, PerSide};
+use crate::keyboard::hid::{RawReport, RAW_REPORT_SIZE};
+use crate::keyboard::ticks::TickRate;
+use super::LedsBitset;
+use super::overlay::{Overlay, OverlayId, BLINK, PULSE, SOLID};
+
+/// Maximum number of notifications received between LED updates
+pub const MAX_NOTIFICATIONS: usize = 4;
+
+/// Raw HID command that shows a notification
+const CMD_NOTIFY: u8 = 0x01;
+/// Raw HID command that sets or removes LED color overrides
+pub const CMD_LED_OVERRIDE: u8 = 0x07;
+/// Command, id, effect, color (3), duration, number of keys
+const HEADER_LEN: usize = 8;
+/// Maximum number of keys that fit in a report
+const MAX_KEYS: usize = (RAW_REPORT_SIZE - HEADER_LEN) / 2;
+/// Command, set flag, color (3), number of keys
+const OVERRIDE_HEADER_LEN: usize = 6;
+/// Maximum number of keys that fit in an override report
+const MAX_OVERRIDE_KEYS: usize = (RAW_REPORT_SIZE - OVERRIDE_HEADER_LEN) / 2;
+
+/// Effect used to display notification color
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Effect {
+    Solid,
+    /// On for half of the period, off for the other half, see [`BLINK`]
+    Blink,
+    /// Fade in and out, see [`PULSE`]
+    Pulse,
+}
+
+/// Notification effect on a group of keys triggered by host software
+///
+/// Shown as an [`Overlay`], so it is played on both halves. Sent as a raw HID report:
+///
+/// | Byte      | Value                                                  |
+/// |-----------|--------------------------------------------------------|
+/// | 0         | `0x01`                                                 |
+/// | 1         | id, a notification replaces the one with the same id   |
+/// | 2         | effect: 0 - solid, 1 - blink, 2 - pulse                |
+/// | 3..6      | color as R, G, B                                       |
+/// | 6         | duration in seconds, 0 removes the notification        |
+/// | 7         | number of keys N, 0 means all keys                     |
+/// | 8..8+2N   | global coordinates of keys as row, column pairs        |
+#[derive(Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Notification {
+    id: u8,
+    effect: Effect,
+    color: RGB8,
+    /// Duration in ticks
+    duration: u32,
+    leds: PerSide<LedsBitset>,
+}
+
+/// Color override of a group of keys from host software, see [`super::LedOutput::set_override`]
+///
+/// Unlike notifications, overrides stay until removed (or cleared with
+/// [`LedAction::ClearOverrides`](crate::keyboard::actions::LedAction::ClearOverrides)).
+/// Sent as a raw HID report:
+///
+/// | Byte      | Value                                                  |
+/// |-----------|--------------------------------------------------------|
+/// | 0         | `0x07`                                                 |
+/// | 1         | 1 to set the color, 0 to remove the override           |
+/// | 2..5      | color as R, G, B                                       |
+/// | 5         | number of keys N, 0 means all keys                     |
+/// | 6..6+2N   | global coordinates of keys as row, column pairs        |
+#[derive(Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct OverrideRequest {
+    pub color: Option<RGB8>,
+    pub leds: PerSide<LedsBitset>,
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    UnknownCommand(u8),
+    UnknownEffect(u8),
+    TooManyKeys(u8),
+    InvalidKey(u8, u8),
+}
+
+impl Notification {
+    /// Parse raw HID report
+    pub fn from_report(report: &RawReport, tick_rate: TickRate) -> Result<Self, Error> {
+        if report[0] != CMD_NOTIFY {
+            return Err(Error::UnknownCommand(report[0]));
+        }
+        let effect = match report[2] {
+            0 => Effect::Solid,
+            1 => Effect::Blink,
+            2 => Effect::Pulse,
+            e => return Err(Error::UnknownEffect(e)),
+        };
+        let leds = keys_from_report(&report[HEADER_LEN..], report[7], MAX_KEYS)?;
+        Ok(Self {
+            id: report[1],
+            effect,
+            color: RGB8::new(report[3], report[4], report[5]),
+            duration: match report[6] {
+                0 => 0,
+                secs => tick_rate.from_ms(secs as u32 * 1000),
+            },
+            leds,
+        })
+    }
+
+    /// Overlay identifier, a notification replaces the one with the same id
+    pub fn id(&self) -> OverlayId {
+        OverlayId::Notification(self.id)
+    }
+
+    /// Overlay showing the notification, `None` if it removes the one with the same id
+    pub fn overlay(&self) -> Option<Overlay> {
+        if self.duration == 0 {
+            return None;
+        }
+        let pattern = match self.effect {
+            Effect::Solid => &SOLID,
+            Effect::Blink => &BLINK,
+            Effect::Pulse => &PULSE,
+        };
+        let overlay = Overlay::new(pattern, self.leds)
+            .tint(self.color)
+            .duration(self.duration)
+            .id(self.id());
+        Some(overlay)
+    }
+}
+
+impl OverrideRequest {
+    /// Parse raw HID report with [`CMD_LED_OVERRIDE`] command
+    pub fn from_report(report: &RawReport) -> Result<Self, Error> {
+        debug_assert_eq!(report[0], CMD_LED_OVERRIDE);
+        let leds = keys_from_report(&report[OVERRIDE_HEADER_LEN..], report[5], MAX_OVERRIDE_KEYS)?;
+        let color = (report[1] != 0).then_some(RGB8::new(report[2], report[3], report[4]));
+        Ok(Self { color, leds })
+    }
+}
+
+/// LEDs of `n_keys` keys given by global coordinates, 0 keys means all of them
+fn keys_from_report(keys: &[u8], n_keys: u8, max_keys: usize) -> Result<PerSide<LedsBitset>, Error> {
+    if n_keys as usize > max_keys {
+        return Err(Error::TooManyKeys(n_keys));
+    }
+    if n_keys == 0 {
+        return Ok(PerSide { left: LedsBitset::ALL, right: LedsBitset::ALL });
+    }
+    let mut leds = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+    for key in keys[..2 * n_keys as usize].chunks_exact(2) {
+        let (row, col) = (key[0], key[1]);
+        let led = BoardSide::global_coords_valid(row, col)
+            .then(|| BoardSide::led_number(BoardSide::coords_to_local((row, col))))
+            .flatten()
+            .ok_or(Error::InvalidKey(row, col))?;
+        leds[BoardSide::from_coords((row, col))].set(led, true);
+    }
+    Ok(leds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: TickRate = TickRate::new(1000);
+
+    fn report(header: [u8; HEADER_LEN], keys: &[u8]) -> RawReport {
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[..HEADER_LEN].copy_from_slice(&header);
+        report[HEADER_LEN..HEADER_LEN + keys.len()].copy_from_slice(keys);
+        report
+    }
+
+    #[test]
+    fn parse_key_group() {
+        let n = Notification::from_report(&report([1, 7, 1, 10, 20, 30, 5, 2], &[0, 0, 0, 11]), RATE).unwrap();
+        assert_eq!(n.id, 7);
+        assert_eq!(n.effect, Effect::Blink);
+        assert_eq!(n.color, RGB8::new(10, 20, 30));
+        assert_eq!(n.duration, 5000);
+        assert_eq!(n.leds.left, LedsBitset(1 << 5));
+        assert_eq!(n.leds.right, LedsBitset(1 << 5));
+    }
+
+    #[test]
+    fn parse_all_keys() {
+        let n = Notification::from_report(&report([1, 0, 0, 1, 1, 1, 0, 0], &[]), RATE).unwrap();
+        assert_eq!(n.duration, 0);
+        assert!(n.leds.left.is_all() && n.leds.right.is_all());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |header, keys: &[u8]| Notification::from_report(&report(header, keys), RATE);
+        assert_eq!(parse([2, 0, 0, 0, 0, 0, 1, 0], &[]), Err(Error::UnknownCommand(2)));
+        assert_eq!(parse([1, 0, 3, 0, 0, 0, 1, 0], &[]), Err(Error::UnknownEffect(3)));
+        assert_eq!(parse([1, 0, 0, 0, 0, 0, 1, 13], &[]), Err(Error::TooManyKeys(13)));
+        // Key without LED and out of range
+        assert_eq!(parse([1, 0, 0, 0, 0, 0, 1, 1], &[4, 4]), Err(Error::InvalidKey(4, 4)));
+        assert_eq!(parse([1, 0, 0, 0, 0, 0, 1, 1], &[9, 0]), Err(Error::InvalidKey(9, 0)));
+    }
+
+    #[test]
+    fn parse_override() {
+        let mut r = [0; RAW_REPORT_SIZE];
+        r[..10].copy_from_slice(&[CMD_LED_OVERRIDE, 1, 10, 20, 30, 2, 0, 0, 0, 11]);
+        let request = OverrideRequest::from_report(&r).unwrap();
+        assert_eq!(request.color, Some(RGB8::new(10, 20, 30)));
+        assert_eq!(request.leds.left, LedsBitset(1 << 5));
+        assert_eq!(request.leds.right, LedsBitset(1 << 5));
+
+        r[..6].copy_from_slice(&[CMD_LED_OVERRIDE, 0, 10, 20, 30, 0]);
+        let request = OverrideRequest::from_report(&r).unwrap();
+        assert_eq!(request.color, None);
+        assert!(request.leds.left.is_all() && request.leds.right.is_all());
+
+        r[5] = MAX_OVERRIDE_KEYS as u8 + 1;
+        assert_eq!(OverrideRequest::from_report(&r), Err(Error::TooManyKeys(MAX_OVERRIDE_KEYS as u8 + 1)));
+        r[5..8].copy_from_slice(&[1, 4, 4]);
+        assert_eq!(OverrideRequest::from_report(&r), Err(Error::InvalidKey(4, 4)));
+    }
+
+    #[test]
+    fn overlay() {
+        let mut n = Notification::from_report(&report([1, 3, 1, 255, 0, 0, 1, 0], &[]), RATE).unwrap();
+        assert_eq!(n.id(), OverlayId::Notification(3));
+        assert!(n.overlay().is_some());
+        n.duration = 0;
+        assert!(n.overlay().is_none());
+    }
+}
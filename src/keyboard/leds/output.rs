@@ -1,19 +1,56 @@
+use rgb::RGB8;
+
 use crate::bsp::{sides::{PerSide, BoardSide}, ws2812b, NLEDS, LedColors};
 
-use super::LedController;
+use crate::keyboard::mouse::DwellIndicator;
+use super::{LedController, ControllerState, LedsBitset, ReactiveConfig};
+use super::reactive::ReactiveOverlay;
+use super::notify::{Notification, OverrideRequest};
+use super::overlay::{Overlay, OverlayId, Overlays, OverlaysState, SOLID};
 
 pub type Leds = ws2812b::Leds<NLEDS>;
 
-/// Storage for LED colors with option to overwrite output for given time
+/// Output used during blackout
+static BLANK: Leds = Leds::new();
+
+/// Color of LEDs showing the remaining time before detaching to DFU bootloader
+const COUNTDOWN_COLOR: RGB8 = RGB8::new(0, 40, 80);
+/// Color of the dwell click indicator at the start of countdown
+const DWELL_COLOR: RGB8 = RGB8::new(80, 60, 0);
+
+/// Storage for LED colors with option to play overlays on top of the output
 pub struct LedOutput {
     this: PerSide<Leds>,
     other: Leds,
+    /// Colors from other half with local reactive overlay applied
+    blended: Leds,
+    /// Keys pressed on this half highlighted on top of colors from the other half or on top
+    /// of locally generated colors when following controller state of the other half
+    reactive: ReactiveOverlay,
+    local_pressed: Option<LedsBitset>,
+    overrides: PerSide<LedOverrides>,
+    overlays: Overlays,
     mode: OutputMode,
     time: u32,
-    overwrite_until: Option<u32>,
     last_transmission: Option<u32>,
+    /// Last controller state sent to the other half instead of colors
+    last_state: Option<ControllerState>,
+    /// Last overlays sent to the other half with controller state and time of transmission
+    last_overlays: Option<(u32, OverlaysState)>,
     retransmission_min_time: u32,
     modified: bool,
+    /// All LEDs are off regardless of generated or received colors
+    blackout: bool,
+    /// Number of LEDs lit by the countdown to reboot to bootloader
+    countdown: Option<usize>,
+    /// Pending dwell click shown by the indicator overlay
+    dwell: Option<DwellIndicator>,
+}
+
+/// Explicit colors of individual LEDs that take precedence over patterns
+struct LedOverrides {
+    mask: LedsBitset,
+    colors: [RGB8; NLEDS],
 }
 
 /// How we actually generate output colors
@@ -25,27 +62,35 @@ enum OutputMode {
 }
 
 impl LedOutput {
-    pub const fn new(retransmission_min_time: u32) -> Self {
+    pub const fn new(retransmission_min_time: u32, reactive: ReactiveConfig) -> Self {
         Self {
             this: PerSide { left: Leds::new(), right: Leds::new() },
             other: Leds::new(),
+            blended: Leds::new(),
+            reactive: ReactiveOverlay::new(reactive),
+            local_pressed: None,
+            overrides: PerSide { left: LedOverrides::new(), right: LedOverrides::new() },
+            overlays: Overlays::new(),
             mode: OutputMode::Controller,
             time: 0,
-            overwrite_until: None,
             last_transmission: None,
+            last_state: None,
+            last_overlays: None,
             retransmission_min_time,
             modified: false,
+            blackout: false,
+            countdown: None,
+            dwell: None,
         }
     }
 
-    /// Configure pattern overwrite for given duration
+    /// Play overlay on top of patterns and overrides until it finishes
     ///
-    /// This returns [`Leds`] which should be manually configured
-    /// by setting required colors. Normal patterns will not be used
-    /// ([`Leds`] will not be modified) for the duration of `ticks`.
-    pub fn set_overwrite(&mut self, ticks: u16) -> &mut PerSide<Leds> {
-        self.overwrite_until = Some(self.time.saturating_add(ticks as u32));
-        &mut self.this
+    /// Overlays are shown when generating colors from controller. The ones covering LEDs of
+    /// the other half are sent to it with controller state, see [`Self::get_overlays_for_transmission`].
+    pub fn play(&mut self, overlay: Overlay) {
+        self.overlays.play(self.time, overlay);
+        self.modified = true;
     }
 
     /// Set and use colors received from other board half
@@ -54,6 +99,135 @@ impl LedOutput {
         self.mode = OutputMode::FromOther;
     }
 
+    /// Update keys pressed on this half, used for local reactive lighting
+    pub fn set_local_pressed(&mut self, pressed: LedsBitset) {
+        self.local_pressed = Some(pressed);
+    }
+
+    /// Set color of a LED that overrides pattern output until cleared, `None` clears the override
+    ///
+    /// Overrides are only applied when generating colors from controller (on master).
+    pub fn set_override(&mut self, side: BoardSide, led: u8, color: Option<RGB8>) {
+        let overrides = &mut self.overrides[side];
+        overrides.mask.set(led, color.is_some());
+        if let Some(color) = color {
+            overrides.colors[led as usize] = color;
+        }
+        self.modified = true;
+    }
+
+    /// Set or remove overrides of a group of LEDs requested by host
+    pub fn apply_override_request(&mut self, request: &OverrideRequest) {
+        for side in BoardSide::EACH {
+            for led in 0..NLEDS as u8 {
+                if request.leds[side].get(led) {
+                    self.set_override(side, led, request.color);
+                }
+            }
+        }
+    }
+
+    /// Remove all LED color overrides and overlays, including notifications
+    pub fn clear_overrides(&mut self) {
+        self.overrides.for_each(|o| o.mask = LedsBitset::NONE);
+        self.overlays.clear();
+        self.countdown = None;
+        self.dwell = None;
+        self.modified = true;
+    }
+
+    /// Show notification from host as an overlay until it expires, zero duration removes it
+    pub fn notify(&mut self, notification: Notification) {
+        defmt::info!("Notification: {}", notification.id());
+        match notification.overlay() {
+            Some(overlay) => self.play(overlay),
+            None => {
+                self.overlays.stop(notification.id());
+                self.modified = true;
+            },
+        }
+    }
+
+    /// Turn all LEDs off until disabled, independently of brightness
+    ///
+    /// No new colors are generated during blackout.
+    pub fn set_blackout(&mut self, blackout: bool) {
+        if blackout != self.blackout {
+            defmt::info!("LED blackout: {=bool}", blackout);
+            // Make sure the other half gets current colors after blackout
+            self.modified = true;
+        }
+        self.blackout = blackout;
+    }
+
+    /// Show remaining time before reboot to bootloader on top of other colors
+    ///
+    /// The first LEDs in chain are lit proportionally to `remaining` (255 is all LEDs) and the
+    /// others are turned off, `None` disables.
+    pub fn set_countdown(&mut self, remaining: Option<u8>) {
+        let lit = remaining.map(|r| (r as usize * NLEDS).div_ceil(u8::MAX as usize));
+        if lit == self.countdown {
+            return;
+        }
+        if self.countdown.is_none() {
+            self.play(Overlay::all(&SOLID).tint(RGB8::default()).id(OverlayId::CountdownBackground));
+        }
+        self.countdown = lit;
+        match lit {
+            Some(lit) => {
+                let mask = LedsBitset(((1u64 << lit) - 1) as u32);
+                let leds = PerSide { left: mask, right: mask };
+                self.play(Overlay::new(&SOLID, leds).tint(COUNTDOWN_COLOR).id(OverlayId::Countdown));
+            },
+            None => {
+                self.overlays.stop(OverlayId::Countdown);
+                self.overlays.stop(OverlayId::CountdownBackground);
+                self.modified = true;
+            },
+        }
+    }
+
+    /// Show pending dwell click on the LED of indicator key
+    ///
+    /// The LED is lit while the pointer moves and fades out until the click, `None` disables.
+    pub fn set_dwell(&mut self, dwell: Option<DwellIndicator>) {
+        // Only start of a countdown matters, the overlay fades out on its own
+        let start = |d: &DwellIndicator| (d.key, d.count, d.remaining.is_some());
+        if dwell.as_ref().map(start) == self.dwell.as_ref().map(start) {
+            return;
+        }
+        self.dwell = dwell;
+        let led = dwell.and_then(|d| {
+            let (row, col) = d.key;
+            BoardSide::global_coords_valid(row, col)
+                .then(|| BoardSide::led_number(BoardSide::coords_to_local(d.key)))
+                .flatten()
+                .map(|led| (d, led))
+        });
+        match led {
+            Some((dwell, led)) => {
+                let mut leds = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+                leds[BoardSide::from_coords(dwell.key)].set(led, true);
+                let overlay = Overlay::new(&SOLID, leds).tint(DWELL_COLOR).id(OverlayId::Dwell);
+                match dwell.remaining {
+                    Some(remaining) => self.play(overlay.duration(remaining).fade_out()),
+                    None => self.play(overlay),
+                }
+            },
+            None => {
+                self.overlays.stop(OverlayId::Dwell);
+                self.modified = true;
+            },
+        }
+    }
+
+    /// Play overlays received from the other half on top of locally generated colors
+    ///
+    /// Used together with controller state of the other half, see [`Self::get_overlays_for_transmission`].
+    pub fn sync_overlays(&mut self, time: u32, side: BoardSide, state: OverlaysState) {
+        self.overlays.sync(time, side, state);
+    }
+
     /// Check if we're currently using colors from controller
     pub fn using_from_controller(&self) -> bool {
         matches!(self.mode, OutputMode::Controller)
@@ -66,28 +240,47 @@ impl LedOutput {
 
     /// Generate colors for current time
     pub fn tick(&mut self, time: u32, controller: &mut LedController) {
-        if let Some(until) = self.overwrite_until {
-            // FIXME: if time hits u32 limit (unlikely, ~50 days) then we might skip the overwrite
-            if time > until || until == u32::MAX  {
-                self.overwrite_until = None;
-            }
+        self.time = time;
+
+        if let Some(pressed) = self.local_pressed.take() {
+            self.reactive.set_pressed(time, pressed);
         }
 
-        if self.overwrite_until.is_none() {
-            if let OutputMode::Controller = self.mode {
+        match self.mode {
+            OutputMode::Controller => if !self.blackout {
                 let modified = controller.tick(time, &mut self.this);
-                if !(modified.left.is_none() && modified.right.is_none()) {
+                for side in BoardSide::EACH {
+                    let overrides = &self.overrides[side];
+                    // Pattern changes of overridden LEDs are not visible
+                    if !(modified[side] & !overrides.mask).is_none() {
+                        self.modified = true;
+                    }
+                    overrides.apply(&mut self.this[side], controller);
+                }
+                if !self.overlays.is_empty() {
+                    self.overlays.render(time, &mut self.this, controller);
+                    // Keep sending animated colors, this also restores pattern colors when the
+                    // last overlay finishes
                     self.modified = true;
                 }
-            }
+                // Only slave gets local pressed keys, master shows reactive patterns from config
+                self.reactive.apply(time, &mut self.this[controller.side()].colors, controller);
+            },
+            OutputMode::FromOther => {
+                self.blended.colors = self.other.colors;
+                self.reactive.apply(time, &mut self.blended.colors, controller);
+            },
         }
     }
 
     /// Get current color values for given board side, when got colors from other half side is ignored
     pub fn current(&self, side: BoardSide) -> &Leds {
+        if self.blackout {
+            return &BLANK;
+        }
         match self.mode {
             OutputMode::Controller => &self.this[side],
-            OutputMode::FromOther => &self.other,
+            OutputMode::FromOther => &self.blended,
         }
     }
 
@@ -102,8 +295,313 @@ impl LedOutput {
         }
     }
 
+    /// Get controller state for transmission to other board half, used instead of colors
+    /// when the other half can generate colors on its own
+    ///
+    /// Like [`Self::get_for_transmission`] this avoids sending duplicates when not needed.
+    /// Color overrides are not transmitted. Changes are sent also when LED transmission is
+    /// disabled by power state (so that the other half follows e.g. deep sleep), but then
+    /// the state is not retransmitted periodically.
+    pub fn get_state_for_transmission(&mut self, time: u32, state: ControllerState) -> Option<ControllerState> {
+        let retransmit = state.power.led_transmission_enabled() && self.should_retransmit(time);
+        if self.last_state.as_ref() != Some(&state) || retransmit {
+            self.last_transmission = Some(time);
+            self.last_state = Some(state.clone());
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    /// Get overlays covering LEDs of the other half for transmission with controller state
+    ///
+    /// Sent when the set of overlays changes and periodically, so that the other half
+    /// catches up even if some messages are lost.
+    pub fn get_overlays_for_transmission(&mut self, time: u32, side: BoardSide) -> Option<OverlaysState> {
+        let state = self.overlays.state(time, side);
+        let send = match &self.last_overlays {
+            None => true,
+            Some((last, prev)) => !state.same_overlays(prev)
+                || time.wrapping_sub(*last) > self.retransmission_min_time,
+        };
+        if send {
+            self.last_overlays = Some((time, state.clone()));
+            Some(state)
+        } else {
+            None
+        }
+    }
+
     fn should_retransmit(&self, time: u32) -> bool{
         self.last_transmission.map_or(true,
             |last| time.wrapping_sub(last) > self.retransmission_min_time)
     }
 }
+
+impl LedOverrides {
+    const fn new() -> Self {
+        Self { mask: LedsBitset::NONE, colors: [RGB8::new(0, 0, 0); NLEDS] }
+    }
+
+    fn apply(&self, leds: &mut Leds, controller: &LedController) {
+        if self.mask.is_none() {
+            return;
+        }
+        for (i, (led, color)) in leds.colors.iter_mut().zip(self.colors.iter()).enumerate() {
+            if self.mask.get(i as u8) {
+                *led = controller.output_color(*color);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::leds::{LedConfigurations, CMD_LED_OVERRIDE};
+
+    const REACTIVE: ReactiveConfig = ReactiveConfig { enabled: false, color: RGB8::new(0, 0, 0), fade: 0 };
+
+    #[test]
+    fn overrides() {
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let mut out = LedOutput::new(1000, REACTIVE);
+        out.tick(0, &mut ctl);
+        assert_eq!(out.get_for_transmission(0, BoardSide::Right).map(|l| l.colors[2]), Some(RGB8::new(0, 0, 0)));
+
+        out.set_override(BoardSide::Right, 2, Some(RGB8::new(255, 0, 0)));
+        out.tick(1, &mut ctl);
+        assert_eq!(out.current(BoardSide::Left).colors[2], RGB8::new(0, 0, 0));
+        assert_eq!(out.get_for_transmission(1, BoardSide::Right).map(|l| l.colors[2]), Some(RGB8::new(255, 0, 0)));
+        out.tick(2, &mut ctl);
+        assert!(out.get_for_transmission(2, BoardSide::Right).is_none());
+
+        out.clear_overrides();
+        out.tick(3, &mut ctl);
+        assert_eq!(out.get_for_transmission(3, BoardSide::Right).map(|l| l.colors[2]), Some(RGB8::new(0, 0, 0)));
+    }
+
+    #[test]
+    fn override_request() {
+        use crate::keyboard::hid::RAW_REPORT_SIZE;
+
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let mut out = LedOutput::new(1000, REACTIVE);
+
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[..8].copy_from_slice(&[CMD_LED_OVERRIDE, 1, 0, 255, 0, 1, 0, 11]);
+        out.apply_override_request(&OverrideRequest::from_report(&report).unwrap());
+        out.tick(0, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(0, 255, 0));
+        assert_eq!(out.current(BoardSide::Left).colors[5], RGB8::new(0, 0, 0));
+
+        report[1] = 0;
+        out.apply_override_request(&OverrideRequest::from_report(&report).unwrap());
+        out.tick(1, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(0, 0, 0));
+    }
+
+    #[test]
+    fn notifications() {
+        use crate::keyboard::hid::RAW_REPORT_SIZE;
+        use crate::keyboard::ticks::TickRate;
+
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let mut out = LedOutput::new(1000, REACTIVE);
+        out.set_override(BoardSide::Right, 5, Some(RGB8::new(255, 0, 0)));
+        out.tick(0, &mut ctl);
+
+        // Solid green on key (0, 11) for 1 second at 10 Hz
+        let mut report = [0; RAW_REPORT_SIZE];
+        report[..10].copy_from_slice(&[1, 0, 0, 0, 255, 0, 1, 1, 0, 11]);
+        out.notify(Notification::from_report(&report, TickRate::new(10)).unwrap());
+        out.tick(1, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(0, 255, 0));
+        assert_eq!(out.current(BoardSide::Right).colors[4], RGB8::new(0, 0, 0));
+        out.tick(9, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(0, 255, 0));
+        // Expired, override is visible again
+        out.tick(10, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(255, 0, 0));
+
+        out.notify(Notification::from_report(&report, TickRate::new(10)).unwrap());
+        out.tick(11, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(0, 255, 0));
+        // Zero duration removes notification with the same id
+        report[6] = 0;
+        out.notify(Notification::from_report(&report, TickRate::new(10)).unwrap());
+        out.tick(12, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(255, 0, 0));
+
+        report[6] = 1;
+        out.notify(Notification::from_report(&report, TickRate::new(10)).unwrap());
+        out.clear_overrides();
+        out.tick(13, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::new(0, 0, 0));
+    }
+
+    #[test]
+    fn overlays() {
+        use crate::keyboard::leds::overlay::SOLID;
+
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let mut out = LedOutput::new(1000, REACTIVE);
+        out.set_override(BoardSide::Left, 2, Some(RGB8::new(255, 0, 0)));
+        out.tick(0, &mut ctl);
+
+        let mut leds = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+        leds.left.set(2, true);
+        out.play(Overlay::new(&SOLID, leds).tint(RGB8::new(0, 0, 255)).duration(5));
+        out.tick(1, &mut ctl);
+        assert_eq!(out.current(BoardSide::Left).colors[2], RGB8::new(0, 0, 255));
+        assert_eq!(out.current(BoardSide::Left).colors[1], RGB8::new(0, 0, 0));
+        assert!(out.get_for_transmission(1, BoardSide::Left).is_some());
+        // Finished, override is visible again and transmitted
+        out.tick(5, &mut ctl);
+        assert_eq!(out.get_for_transmission(5, BoardSide::Left).map(|l| l.colors[2]), Some(RGB8::new(255, 0, 0)));
+
+        out.play(Overlay::all(&SOLID));
+        out.clear_overrides();
+        out.tick(6, &mut ctl);
+        assert!(out.current(BoardSide::Left).colors.iter().all(|c| *c == RGB8::new(0, 0, 0)));
+    }
+
+    #[test]
+    fn reactive_on_local_colors() {
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Right, &configs, &[]);
+        ctl.set_brightness(255);
+        let color = RGB8::new(0, 255, 0);
+        let mut out = LedOutput::new(1000, ReactiveConfig { enabled: true, color, fade: 0 });
+        let mut pressed = LedsBitset::NONE;
+        pressed.set(3, true);
+        out.set_local_pressed(pressed);
+        out.tick(0, &mut ctl);
+        // Only applied on this half, generated with controller state of the other half
+        assert_eq!(out.current(BoardSide::Right).colors[3], ctl.output_color(color));
+        assert_eq!(out.current(BoardSide::Right).colors[2], RGB8::new(0, 0, 0));
+        assert_eq!(out.current(BoardSide::Left).colors[3], RGB8::new(0, 0, 0));
+
+        out.set_local_pressed(LedsBitset::NONE);
+        out.tick(1, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[3], RGB8::new(0, 0, 0));
+    }
+
+    #[test]
+    fn blackout() {
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let mut out = LedOutput::new(1000, REACTIVE);
+        out.set_override(BoardSide::Left, 2, Some(RGB8::new(255, 0, 0)));
+        out.tick(0, &mut ctl);
+        assert_eq!(out.current(BoardSide::Left).colors[2], RGB8::new(255, 0, 0));
+
+        out.set_blackout(true);
+        out.tick(1, &mut ctl);
+        assert!(out.current(BoardSide::Left).colors.iter().all(|c| *c == RGB8::new(0, 0, 0)));
+        // Also when using colors from the other half
+        out.use_from_other_half(&[RGB8::new(0, 0, 255); NLEDS]);
+        out.tick(2, &mut ctl);
+        assert!(out.current(BoardSide::Left).colors.iter().all(|c| *c == RGB8::new(0, 0, 0)));
+
+        out.set_blackout(false);
+        out.tick(3, &mut ctl);
+        assert_eq!(out.current(BoardSide::Left).colors[2], RGB8::new(0, 0, 255));
+    }
+
+    #[test]
+    fn countdown() {
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let color = ctl.output_color(COUNTDOWN_COLOR);
+        let mut out = LedOutput::new(1000, REACTIVE);
+        out.set_override(BoardSide::Right, 0, Some(RGB8::new(255, 0, 0)));
+        let mut lit = |out: &mut LedOutput, t, remaining| {
+            out.set_countdown(remaining);
+            out.tick(t, &mut ctl);
+            let colors = &out.current(BoardSide::Right).colors;
+            assert!(colors.iter().all(|c| *c == color || *c == RGB8::default()));
+            colors.iter().take_while(|c| **c == color).count()
+        };
+        assert_eq!(lit(&mut out, 0, Some(255)), NLEDS);
+        assert_eq!(lit(&mut out, 1, Some(128)), NLEDS / 2 + 1);
+        assert_eq!(lit(&mut out, 2, Some(1)), 1);
+        assert_eq!(lit(&mut out, 3, Some(0)), 0);
+
+        out.set_countdown(None);
+        out.tick(4, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[0], RGB8::new(255, 0, 0));
+        // Shown on the other half with controller state
+        out.set_countdown(Some(255));
+        out.tick(5, &mut ctl);
+        let mut other = LedOutput::new(1000, REACTIVE);
+        other.sync_overlays(5, BoardSide::Right, out.get_overlays_for_transmission(5, BoardSide::Right).unwrap());
+        let mut ctl = LedController::new(BoardSide::Right, &configs, &[]);
+        ctl.set_brightness(255);
+        other.tick(5, &mut ctl);
+        assert!(other.current(BoardSide::Right).colors.iter().all(|c| *c == color));
+    }
+
+    #[test]
+    fn dwell_indicator() {
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let color = ctl.output_color(DWELL_COLOR);
+        let mut out = LedOutput::new(1000, REACTIVE);
+        let indicator = |count, remaining| Some(DwellIndicator { key: (0, 11), count, remaining });
+        out.set_dwell(indicator(0, None));
+        out.tick(0, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], color);
+        out.set_dwell(indicator(1, Some(100)));
+        out.tick(10, &mut ctl);
+        // Later updates of the same countdown do not restart fading
+        out.set_dwell(indicator(1, Some(50)));
+        out.tick(50, &mut ctl);
+        let faded = out.current(BoardSide::Right).colors[5];
+        assert!(faded != color && faded != RGB8::default());
+        out.tick(110, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::default());
+        out.set_dwell(indicator(2, None));
+        out.tick(120, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], color);
+        out.set_dwell(None);
+        out.tick(121, &mut ctl);
+        assert_eq!(out.current(BoardSide::Right).colors[5], RGB8::default());
+
+        // Keys without LED are ignored
+        out.set_dwell(Some(DwellIndicator { key: (4, 4), count: 0, remaining: None }));
+        out.tick(122, &mut ctl);
+        assert!(out.current(BoardSide::Left).colors.iter().all(|c| *c == RGB8::default()));
+    }
+
+    #[test]
+    fn overlays_for_transmission() {
+        use crate::keyboard::leds::overlay::SOLID;
+
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        let mut out = LedOutput::new(100, REACTIVE);
+        out.tick(0, &mut ctl);
+        assert!(out.get_overlays_for_transmission(0, BoardSide::Right).is_some());
+        assert!(out.get_overlays_for_transmission(1, BoardSide::Right).is_none());
+        out.play(Overlay::all(&SOLID).duration(50));
+        assert!(out.get_overlays_for_transmission(2, BoardSide::Right).is_some());
+        assert!(out.get_overlays_for_transmission(3, BoardSide::Right).is_none());
+        // Periodic retransmission
+        assert!(out.get_overlays_for_transmission(103, BoardSide::Right).is_some());
+        // Finished overlay removal is sent
+        out.tick(104, &mut ctl);
+        assert!(out.get_overlays_for_transmission(104, BoardSide::Right).is_some());
+    }
+}
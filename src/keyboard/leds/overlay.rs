@@ -0,0 +1,464 @@
+use rgb::RGB8;
+use serde::{Serialize, Deserialize};
+
+use crate::bsp::sides::{BoardSide, PerSide};
+use crate::bsp::NLEDS;
+use super::output::Leds;
+use super::pattern::ColorGenerator;
+use super::{LedController, LedsBitset, Pattern, Phase, Repeat, Transition, Interpolation};
+
+/// Maximum number of overlays played at the same time, the oldest one is replaced
+pub const MAX_OVERLAYS: usize = 6;
+
+/// White without animation, to be used with [`Overlay::tint`] and [`Overlay::duration`]
+pub static SOLID: Pattern = Pattern {
+    repeat: Repeat::Wrap,
+    transitions: &[Transition { color: RGB8::new(255, 255, 255), duration: 0, interpolation: Interpolation::Piecewise }],
+    phase: Phase { x: 0.0, y: 0.0 },
+};
+
+/// White blinking with 500 ms period, to be used with [`Overlay::tint`] and [`Overlay::duration`]
+pub static BLINK: Pattern = Pattern {
+    repeat: Repeat::Wrap,
+    transitions: &[
+        Transition { color: RGB8::new(255, 255, 255), duration: 250, interpolation: Interpolation::Piecewise },
+        Transition { color: RGB8::new(0, 0, 0), duration: 250, interpolation: Interpolation::Piecewise },
+    ],
+    phase: Phase { x: 0.0, y: 0.0 },
+};
+
+/// White fading in and out with 2 s period, to be used with [`Overlay::tint`] and [`Overlay::duration`]
+pub static PULSE: Pattern = Pattern {
+    repeat: Repeat::Wrap,
+    transitions: &[
+        Transition { color: RGB8::new(255, 255, 255), duration: 1000, interpolation: Interpolation::Linear },
+        Transition { color: RGB8::new(0, 0, 0), duration: 1000, interpolation: Interpolation::Linear },
+    ],
+    phase: Phase { x: 0.0, y: 0.0 },
+};
+
+/// Patterns of overlays that can be sent to the other half, identified by index
+///
+/// Both halves run the same firmware when overlays are synchronized, but pattern addresses
+/// are not sent over the link to avoid depending on that.
+static SYNCED_PATTERNS: [&Pattern; 3] = [&SOLID, &BLINK, &PULSE];
+
+/// Identifies overlays of the same kind, a new one replaces the one that is playing
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum OverlayId {
+    /// Notification from host with given id
+    Notification(u8),
+    /// LEDs lit during DFU detach countdown
+    Countdown,
+    /// LEDs turned off during DFU detach countdown
+    CountdownBackground,
+    /// Dwell click indicator
+    Dwell,
+    /// Feedback after joystick plane change
+    JoystickPlane,
+}
+
+/// Pattern played once on a group of LEDs on top of controller output
+///
+/// Plays a single pass through pattern transitions (ignoring `repeat`) unless a duration is
+/// given, which is required for patterns with endless transitions. Phase is not used, all
+/// LEDs show the same color.
+#[derive(Clone)]
+pub struct Overlay {
+    pattern: &'static Pattern,
+    leds: PerSide<LedsBitset>,
+    tint: Option<RGB8>,
+    duration: Option<u32>,
+    id: Option<OverlayId>,
+    fade: bool,
+}
+
+/// Overlays currently played, newer ones are drawn on top
+pub struct Overlays {
+    active: heapless::Vec<Playing, MAX_OVERLAYS>,
+    /// Sequence number of the next overlay, identifies it on both halves
+    next_seq: u8,
+}
+
+struct Playing {
+    overlay: Overlay,
+    generator: ColorGenerator<'static>,
+    start: u32,
+    last: u32,
+    /// Ticks after which the overlay is removed, `None` for endless
+    duration: Option<u32>,
+    seq: u8,
+    /// Played because the other half plays it, see [`Overlays::sync`]
+    remote: bool,
+}
+
+/// Overlays played on master that cover LEDs of the other half, see [`Overlays::sync`]
+///
+/// Overlays using patterns other than [`SOLID`], [`BLINK`] and [`PULSE`] are not included.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug))]
+pub struct OverlaysState {
+    overlays: heapless::Vec<SyncedOverlay, MAX_OVERLAYS>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug))]
+struct SyncedOverlay {
+    seq: u8,
+    /// Ticks since the overlay started
+    elapsed: u32,
+    /// Index in [`SYNCED_PATTERNS`]
+    pattern: u8,
+    /// LEDs of the receiving half
+    leds: LedsBitset,
+    tint: Option<RGB8>,
+    duration: Option<u32>,
+    id: Option<OverlayId>,
+    fade: bool,
+}
+
+impl Overlay {
+    pub const fn new(pattern: &'static Pattern, leds: PerSide<LedsBitset>) -> Self {
+        Self { pattern, leds, tint: None, duration: None, id: None, fade: false }
+    }
+
+    /// Play on all LEDs of both halves
+    pub const fn all(pattern: &'static Pattern) -> Self {
+        Self::new(pattern, PerSide { left: LedsBitset::ALL, right: LedsBitset::ALL })
+    }
+
+    /// Multiply pattern colors by `color`, so that a single pattern can be used with any color
+    pub const fn tint(mut self, color: RGB8) -> Self {
+        self.tint = Some(color);
+        self
+    }
+
+    /// Play for given number of ticks instead of a single pass through the pattern
+    pub const fn duration(mut self, ticks: u32) -> Self {
+        self.duration = Some(ticks);
+        self
+    }
+
+    /// Replace overlay with the same id when played, see [`Overlays::stop`]
+    pub const fn id(mut self, id: OverlayId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Dim colors linearly to black at the end of the overlay, has no effect on endless ones
+    pub const fn fade_out(mut self) -> Self {
+        self.fade = true;
+        self
+    }
+
+    /// Ticks of a single pass through the pattern, `None` if any transition is endless
+    fn pass_duration(&self) -> Option<u32> {
+        self.pattern.transitions.iter()
+            .try_fold(0u32, |sum, t| (t.duration != 0).then(|| sum + t.duration as u32))
+    }
+
+    fn color(&self, color: RGB8) -> RGB8 {
+        let Some(tint) = self.tint else {
+            return color;
+        };
+        let scale = |c: u8, t: u8| (c as u16 * t as u16 / u8::MAX as u16) as u8;
+        RGB8::new(scale(color.r, tint.r), scale(color.g, tint.g), scale(color.b, tint.b))
+    }
+}
+
+impl Playing {
+    fn color(&mut self, time: u32) -> RGB8 {
+        let delta = time.wrapping_sub(self.last).try_into().unwrap_or(u16::MAX);
+        self.last = time;
+        let color = self.overlay.color(self.generator.tick(delta));
+        match self.duration.filter(|_| self.overlay.fade) {
+            Some(duration) => {
+                let remaining = duration.saturating_sub(time.wrapping_sub(self.start)) as u64;
+                let scale = |c: u8| (c as u64 * remaining / duration.max(1) as u64) as u8;
+                RGB8::new(scale(color.r), scale(color.g), scale(color.b))
+            },
+            None => color,
+        }
+    }
+}
+
+impl Overlays {
+    pub const fn new() -> Self {
+        Self { active: heapless::Vec::new(), next_seq: 0 }
+    }
+
+    /// Start playing overlay at `time`
+    pub fn play(&mut self, time: u32, overlay: Overlay) {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        self.start(time, overlay, seq, false);
+    }
+
+    fn start(&mut self, time: u32, overlay: Overlay, seq: u8, remote: bool) {
+        if let Some(id) = overlay.id {
+            self.stop(id);
+        }
+        if self.active.is_full() {
+            self.active.remove(0);
+        }
+        let duration = overlay.duration.or_else(|| overlay.pass_duration());
+        let generator = ColorGenerator::new(overlay.pattern);
+        self.active.push(Playing { overlay, generator, start: time, last: time, duration, seq, remote }).ok();
+    }
+
+    /// Remove overlay with given id if it is playing
+    pub fn stop(&mut self, id: OverlayId) {
+        self.active.retain(|p| p.overlay.id != Some(id));
+    }
+
+    /// Remove all overlays
+    pub fn clear(&mut self) {
+        self.active.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// Remove finished overlays and draw the others on top of `leds`
+    pub fn render(&mut self, time: u32, leds: &mut PerSide<Leds>, controller: &LedController) {
+        self.active.retain(|p| p.duration.map_or(true, |d| time.wrapping_sub(p.start) < d));
+        for playing in self.active.iter_mut() {
+            let color = controller.output_color(playing.color(time));
+            for side in BoardSide::EACH {
+                for led in 0..NLEDS as u8 {
+                    if playing.overlay.leds[side].get(led) {
+                        leds[side].colors[led as usize] = color;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Overlays covering LEDs of the other half (on `side`) to be played there too
+    pub fn state(&self, time: u32, side: BoardSide) -> OverlaysState {
+        let overlays = self.active.iter()
+            .filter(|p| !p.remote && !p.overlay.leds[side].is_none())
+            .filter_map(|p| {
+                let pattern = SYNCED_PATTERNS.iter().position(|s| core::ptr::eq(*s, p.overlay.pattern))?;
+                Some(SyncedOverlay {
+                    seq: p.seq,
+                    elapsed: time.wrapping_sub(p.start),
+                    pattern: pattern as u8,
+                    leds: p.overlay.leds[side],
+                    tint: p.overlay.tint,
+                    duration: p.overlay.duration,
+                    id: p.overlay.id,
+                    fade: p.overlay.fade,
+                })
+            })
+            .collect();
+        OverlaysState { overlays }
+    }
+
+    /// Play the same overlays as the other half on LEDs of this half (on `side`)
+    ///
+    /// Overlays received before keep playing, the ones no longer played on the other half
+    /// are removed and new ones start at the same point as there. Local overlays are kept.
+    pub fn sync(&mut self, time: u32, side: BoardSide, state: OverlaysState) {
+        self.active.retain(|p| !p.remote || state.overlays.iter().any(|s| s.seq == p.seq));
+        for synced in state.overlays {
+            if self.active.iter().any(|p| p.remote && p.seq == synced.seq) {
+                continue;
+            }
+            let Some(&pattern) = SYNCED_PATTERNS.get(synced.pattern as usize) else {
+                continue;
+            };
+            let mut leds = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+            leds[side] = synced.leds;
+            let overlay = Overlay {
+                pattern,
+                leds,
+                tint: synced.tint,
+                duration: synced.duration,
+                id: synced.id,
+                fade: synced.fade,
+            };
+            self.start(time.wrapping_sub(synced.elapsed), overlay, synced.seq, true);
+        }
+    }
+}
+
+impl OverlaysState {
+    /// Sequence number, elapsed time, pattern, LEDs, tint, duration, id and flag, as varints
+    const OVERLAY_MAX_SIZE: usize = 1 + 5 + 1 + 5 + 4 + 6 + 3 + 1;
+    /// Length prefix and overlays
+    pub const POSTCARD_MAX_SIZE: usize = 1 + MAX_OVERLAYS * Self::OVERLAY_MAX_SIZE;
+
+    /// Check if both contain the same overlays, regardless of elapsed time
+    pub fn same_overlays(&self, other: &Self) -> bool {
+        self.overlays.len() == other.overlays.len()
+            && self.overlays.iter().zip(other.overlays.iter()).all(|(a, b)| a.seq == b.seq)
+    }
+}
+
+impl Default for Overlays {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::leds::LedConfigurations;
+
+    static SHORT_BLINK: Pattern = Pattern {
+        repeat: Repeat::Wrap,
+        transitions: &[
+            Transition { color: RGB8::new(255, 255, 255), duration: 10, interpolation: Interpolation::Piecewise },
+            Transition { color: RGB8::new(0, 0, 0), duration: 10, interpolation: Interpolation::Piecewise },
+        ],
+        phase: Phase { x: 0.0, y: 0.0 },
+    };
+
+    fn render(overlays: &mut Overlays, time: u32) -> PerSide<Leds> {
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(255);
+        let mut leds = PerSide { left: Leds::new(), right: Leds::new() };
+        overlays.render(time, &mut leds, &ctl);
+        leds
+    }
+
+    #[test]
+    fn single_pass() {
+        let mut overlays = Overlays::new();
+        let mut leds = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+        leds.right.set(3, true);
+        overlays.play(100, Overlay::new(&SHORT_BLINK, leds).tint(RGB8::new(255, 0, 0)));
+        let out = render(&mut overlays, 105);
+        assert_eq!(out.right.colors[3], RGB8::new(255, 0, 0));
+        assert_eq!(out.right.colors[2], RGB8::default());
+        assert!(out.left.colors.iter().all(|c| *c == RGB8::default()));
+        assert_eq!(render(&mut overlays, 115).right.colors[3], RGB8::default());
+        assert!(!overlays.is_empty());
+        // Wrap is ignored, pattern is played once
+        render(&mut overlays, 120);
+        assert!(overlays.is_empty());
+    }
+
+    #[test]
+    fn endless_pattern() {
+        let mut overlays = Overlays::new();
+        overlays.play(0, Overlay::all(&SOLID));
+        render(&mut overlays, 100_000);
+        assert!(!overlays.is_empty());
+        overlays.clear();
+
+        overlays.play(0, Overlay::all(&SOLID).tint(RGB8::new(0, 0, 255)).duration(50));
+        assert!(render(&mut overlays, 49).left.colors.iter().all(|c| *c == RGB8::new(0, 0, 255)));
+        render(&mut overlays, 50);
+        assert!(overlays.is_empty());
+    }
+
+    #[test]
+    fn newest_on_top() {
+        let mut overlays = Overlays::new();
+        overlays.play(0, Overlay::all(&SOLID).tint(RGB8::new(255, 0, 0)).duration(10));
+        overlays.play(0, Overlay::all(&SOLID).tint(RGB8::new(0, 255, 0)).duration(5));
+        assert_eq!(render(&mut overlays, 1).left.colors[0], RGB8::new(0, 255, 0));
+        assert_eq!(render(&mut overlays, 5).left.colors[0], RGB8::new(255, 0, 0));
+    }
+
+    #[test]
+    fn oldest_replaced() {
+        let mut overlays = Overlays::new();
+        for _ in 0..MAX_OVERLAYS + 1 {
+            overlays.play(0, Overlay::all(&SOLID));
+        }
+        assert_eq!(overlays.active.len(), MAX_OVERLAYS);
+    }
+
+    #[test]
+    fn replace_and_stop_by_id() {
+        let mut overlays = Overlays::new();
+        let red = Overlay::all(&SOLID).tint(RGB8::new(255, 0, 0)).id(OverlayId::Notification(1));
+        overlays.play(0, red);
+        overlays.play(0, Overlay::all(&SOLID).tint(RGB8::new(0, 0, 255)).id(OverlayId::Notification(2)));
+        overlays.play(0, Overlay::all(&SOLID).tint(RGB8::new(0, 255, 0)).id(OverlayId::Notification(1)));
+        assert_eq!(overlays.active.len(), 2);
+        assert_eq!(render(&mut overlays, 1).left.colors[0], RGB8::new(0, 255, 0));
+        overlays.stop(OverlayId::Notification(1));
+        assert_eq!(render(&mut overlays, 2).left.colors[0], RGB8::new(0, 0, 255));
+        overlays.stop(OverlayId::Dwell);
+        assert_eq!(overlays.active.len(), 1);
+    }
+
+    #[test]
+    fn fade_out() {
+        let mut overlays = Overlays::new();
+        overlays.play(0, Overlay::all(&SOLID).tint(RGB8::new(0, 200, 0)).duration(100).fade_out());
+        let playing = &mut overlays.active[0];
+        assert_eq!(playing.color(0), RGB8::new(0, 200, 0));
+        assert_eq!(playing.color(50), RGB8::new(0, 100, 0));
+        assert_eq!(playing.color(99), RGB8::new(0, 2, 0));
+        render(&mut overlays, 100);
+        assert!(overlays.is_empty());
+    }
+
+    #[test]
+    fn sync_to_other_half() {
+        let mut master = Overlays::new();
+        let mut leds = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+        leds.right.set(3, true);
+        master.play(100, Overlay::new(&SOLID, leds).tint(RGB8::new(255, 0, 0)).duration(50));
+        let mut leds = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+        leds.left.set(1, true);
+        leds.right.set(4, true);
+        master.play(110, Overlay::new(&BLINK, leds).duration(1000));
+        // Only left side, not sent to the other half
+        master.play(110, Overlay::new(&SOLID, PerSide { left: LedsBitset::ALL, right: LedsBitset::NONE }));
+        // Pattern unknown to the other half
+        master.play(110, Overlay::all(&SHORT_BLINK));
+
+        let mut slave = Overlays::new();
+        let state = master.state(120, BoardSide::Right);
+        assert_eq!(state.overlays.len(), 2);
+        slave.sync(5000, BoardSide::Right, state.clone());
+        let out = render(&mut slave, 5000);
+        assert_eq!(out.right.colors[3], RGB8::new(255, 0, 0));
+        assert_eq!(out.right.colors[4], RGB8::new(255, 255, 255));
+        assert!(out.left.colors.iter().all(|c| *c == RGB8::default()));
+
+        // Retransmission does not restart overlays
+        assert!(master.state(130, BoardSide::Right).same_overlays(&state));
+        slave.sync(5010, BoardSide::Right, master.state(130, BoardSide::Right));
+        assert_eq!(slave.active.len(), 2);
+        // Red one finishes at the same time as on master
+        assert_eq!(render(&mut slave, 5029).right.colors[3], RGB8::new(255, 0, 0));
+        assert_eq!(render(&mut slave, 5030).right.colors[3], RGB8::default());
+        assert_eq!(slave.active.len(), 1);
+
+        // Overlays removed on master are removed, local ones are kept
+        slave.play(5030, Overlay::all(&SOLID).tint(RGB8::new(0, 0, 255)));
+        master.clear();
+        slave.sync(5040, BoardSide::Right, master.state(140, BoardSide::Right));
+        assert_eq!(render(&mut slave, 5040).right.colors[4], RGB8::new(0, 0, 255));
+        assert_eq!(slave.active.len(), 1);
+    }
+
+    #[test]
+    fn state_max_size() {
+        let mut overlays = Overlays::new();
+        for i in 0..MAX_OVERLAYS as u8 {
+            let overlay = Overlay::all(&PULSE)
+                .tint(RGB8::new(255, 255, 255))
+                .duration(u32::MAX)
+                .id(OverlayId::Notification(u8::MAX - i))
+                .fade_out();
+            overlays.play(0, overlay);
+        }
+        let state = overlays.state(u32::MAX, BoardSide::Left);
+        assert_eq!(state.overlays.len(), MAX_OVERLAYS);
+        let mut buf = [0; 256];
+        let len = postcard::to_slice(&state, &mut buf).unwrap().len();
+        assert!(len <= OverlaysState::POSTCARD_MAX_SIZE, "{} > {}", len, OverlaysState::POSTCARD_MAX_SIZE);
+    }
+}
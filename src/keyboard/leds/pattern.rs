@@ -1,27 +1,81 @@
 use rgb::{RGB8, ComponentMap};
+use serde::{Serialize, Deserialize};
 
 use crate::bsp::sides::PerSide;
 use crate::bsp::{NLEDS, sides::BoardSide};
 use crate::keyboard::actions::Inc;
+use crate::keyboard::power::PowerState;
+use crate::keyboard::ticks::TickRate;
+use crate::keyboard::training::TrainingConfig;
 use crate::utils::CircularIter;
 use super::output::Leds;
-use super::{LedConfig, Pattern, Repeat, Transition, Interpolation, LedConfigurations, LedsBitset};
+use super::{LedConfig, Pattern, Phase, Repeat, Transition, Interpolation, LedConfigurations, LedsBitset, BrightnessLimit};
 use super::condition::{KeyboardState, RuleKeys, KeyActionCache};
+use super::night;
+use super::heartbeat::{Heartbeat, HeartbeatConfig};
+
+/// Pattern used for pressed keys in key tester mode
+static KEY_TESTER_PATTERN: Pattern = Pattern {
+    repeat: Repeat::Wrap,
+    transitions: &[Transition { color: RGB8::new(255, 255, 255), duration: 0, interpolation: Interpolation::Piecewise }],
+    phase: Phase { x: 0.0, y: 0.0 },
+};
+
+/// Pattern flashing the hint key in training mode
+static TRAINING_HINT_PATTERN: Pattern = Pattern {
+    repeat: Repeat::Wrap,
+    transitions: &[
+        Transition { color: RGB8::new(255, 255, 255), duration: 150, interpolation: Interpolation::Piecewise },
+        Transition { color: RGB8::new(0, 0, 0), duration: 150, interpolation: Interpolation::Piecewise },
+    ],
+    phase: Phase { x: 0.0, y: 0.0 },
+};
 
 /// Generates LED colors according to current [`LedConfig`]
 pub struct LedController<'a> {
     side: BoardSide,
     config: CircularIter<'a, LedConfig>,
+    /// Rules applied on top of the current configuration, indexed by layer
+    layer_configs: &'a [LedConfig],
     actions: &'a [KeyActionCache],
+    /// Modifiable copy of `actions` used instead of it when the keymap can be modified
+    remapped_actions: Option<&'a mut [KeyActionCache]>,
     patterns: PerSide<[ColorGenerator<'a>; NLEDS]>,
     pattern_candidates: PerSide<[Option<&'a Pattern>; NLEDS]>,
     brightness: u8,
+    /// Brightness raised to maximum while boost key is held, `brightness` is kept for release
+    boost: bool,
+    /// Per-LED brightness limits (same for both halves)
+    max_brightness: PerSide<[u8; NLEDS]>,
+    /// LEDs not dimmed in training mode (same for both halves)
+    training_keys: PerSide<LedsBitset>,
+    training_brightness: u8,
+    /// LEDs currently dimmed by training mode
+    dimmed: PerSide<LedsBitset>,
+    power: PowerState,
+    heartbeat: Option<Heartbeat>,
+    night_mode: bool,
+    /// Only generate colors for this half (the other one generates its colors on its own)
+    local_only: bool,
     last_time: Option<u32>, // for calculating time delta from last tick
+    /// Last keyboard state, needed to re-evaluate rules on changes not reflected in state
+    state: Option<KeyboardState>,
+    rules_outdated: bool,
+}
+
+/// State of [`LedController`] sent to the other half so that it can generate colors on its own
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControllerState {
+    pub keyboard: KeyboardState,
+    pub config: u8,
+    pub brightness: u8,
+    pub power: PowerState,
+    pub night_mode: bool,
 }
 
 /// Generates the color for a single LED depending on current time
 #[derive(Default)]
-struct ColorGenerator<'a> {
+pub(super) struct ColorGenerator<'a> {
     pattern: Option<PatternIter<'a>>,
     remaining_time: u16,  // down-counter from transition.duration
     once_should_reset: bool,
@@ -42,11 +96,24 @@ impl<'a> LedController<'a> {
         Self {
             side,
             config: CircularIter::new(configurations),
+            layer_configs: &[],
             actions,
+            remapped_actions: None,
             patterns: Default::default(),
             pattern_candidates: Default::default(),
             brightness: Self::INITIAL_BRIGHTNESS,
+            boost: false,
+            max_brightness: PerSide { left: [u8::MAX; NLEDS], right: [u8::MAX; NLEDS] },
+            training_keys: PerSide { left: LedsBitset::ALL, right: LedsBitset::ALL },
+            training_brightness: u8::MAX,
+            dimmed: Default::default(),
+            power: PowerState::Active,
+            heartbeat: None,
+            night_mode: false,
+            local_only: false,
             last_time: None,
+            state: None,
+            rules_outdated: false,
         }
     }
 
@@ -62,16 +129,34 @@ impl<'a> LedController<'a> {
 
     /// Update currently applicable patterns based on keyboard state changes
     pub fn update_patterns(&mut self, time: u32, state_change: Option<KeyboardState>) {
+        self.update_patterns_delayed(time, state_change, 0)
+    }
+
+    /// Same as [`Self::update_patterns`], but new patterns start advanced by `delay`
+    fn update_patterns_delayed(&mut self, time: u32, state_change: Option<KeyboardState>, delay: u32) {
+        if let Some(state) = state_change {
+            self.state = Some(state);
+            self.rules_outdated = true;
+        }
+
         // Updating currently used patterns is costly (>500 us), but we only need
         // to update them when keyboard state changed.
-        if let Some(state) = state_change {
+        if let Some(state) = self.state.as_mut().filter(|_| self.rules_outdated) {
+            self.rules_outdated = false;
+            state.brightness = self.effective_brightness();
+
+            let sides = Self::sides(self.side, self.local_only);
+
             // Reset pattern candidates
             self.pattern_candidates.for_each(|side| side.fill(None));
 
             // Scan the rules that we might consider, rules on end of list overwrite previous ones.
-            for rule in self.config.current().iter() {
-                for side in BoardSide::EACH {
-                    let leds = rule.condition.applies_to(self.side, &state, side, self.actions);
+            // Rules of the current layer come last, so keys they do not cover keep base patterns.
+            let layer_rules = self.layer_configs.get(state.layer as usize).copied().unwrap_or_default();
+            for rule in self.config.current().iter().chain(layer_rules) {
+                for &side in sides {
+                    let actions = self.remapped_actions.as_deref().unwrap_or(self.actions);
+                    let leds = rule.condition.applies_to(self.side, state, side, actions);
                     // Optimization: avoid iteration over keys when not needed
                     if leds.is_none() {
                         // Not applicable to any led - skip
@@ -80,7 +165,7 @@ impl<'a> LedController<'a> {
                         self.pattern_candidates[side].fill(Some(&rule.pattern));
                     } else {
                         // More complicated situation - scan all leds
-                        rule.keys.for_each_led(|led_num| {
+                        rule.keys.for_each_led(side, |led_num| {
                             if leds.is_pressed(led_num) {
                                 self.pattern_candidates[side][led_num as usize] = Some(&rule.pattern);
                             }
@@ -88,12 +173,42 @@ impl<'a> LedController<'a> {
                     }
                 }
             }
+
+            // Key tester ignores all rules and only shows pressed keys
+            if state.key_tester {
+                for &side in sides {
+                    for (led, candidate) in self.pattern_candidates[side].iter_mut().enumerate() {
+                        *candidate = state.pressed[side].get(led as u8).then_some(&KEY_TESTER_PATTERN);
+                    }
+                }
+            }
+
+            // Training mode dims keys outside of the teaching set and flashes the hint key
+            self.dimmed = Default::default();
+            if state.training && !state.key_tester {
+                let hint = state.training_hint
+                    .filter(|&(row, col)| BoardSide::global_coords_valid(row, col))
+                    .and_then(|key| {
+                        let led = BoardSide::led_number(BoardSide::coords_to_local(key))?;
+                        Some((BoardSide::from_coords(key), led))
+                    });
+                for &side in sides {
+                    let mut dimmed = !self.training_keys[side];
+                    if let Some((_, led)) = hint.filter(|(hint_side, _)| *hint_side == side) {
+                        dimmed.set(led, false);
+                        self.pattern_candidates[side][led as usize] = Some(&TRAINING_HINT_PATTERN);
+                    }
+                    self.dimmed[side] = dimmed;
+                }
+            }
         }
 
         let time_delta = self.next_time_delta(time);
-        for side in BoardSide::EACH {
+        for &side in Self::sides(self.side, self.local_only) {
             for led in 0..NLEDS {
-                self.patterns[side][led].update(time_delta, self.pattern_candidates[side][led]);
+                let candidate = self.pattern_candidates[side][led];
+                let offset = |pattern: &Pattern| pattern.phase_offset(side, led as u8) + delay;
+                self.patterns[side][led].update_shifted(time_delta, candidate, offset);
             }
         }
     }
@@ -102,16 +217,22 @@ impl<'a> LedController<'a> {
     pub fn tick(&mut self, time: u32, leds: &mut PerSide<Leds>) -> PerSide<LedsBitset> {
         let time_delta = self.next_time_delta(time);
         let mut modified: PerSide<LedsBitset> = Default::default();
+        let brightness = self.output_brightness();
+        let night_mode = self.night_mode;
+        let game_mode = self.state.as_ref().map_or(false, |s| s.game_mode);
 
-        for side in BoardSide::EACH {
+        for &side in Self::sides(self.side, self.local_only) {
             debug_assert_eq!(self.patterns[side].len(), leds[side].colors.len());
             let patterns = self.patterns[side].iter_mut();
             let leds = leds[side].colors.iter_mut();
-
-            for (i, (pattern, led)) in patterns.zip(leds).enumerate() {
-                let new = pattern.tick(time_delta)
-                    .map(|channel| Self::dimmed(channel, self.brightness))
-                    .map(Leds::gamma_correction);
+            let dimmed = self.dimmed[side];
+
+            for (i, ((pattern, led), max)) in patterns.zip(leds).zip(self.max_brightness[side]).enumerate() {
+                let max = if dimmed.get(i as u8) { max.min(self.training_brightness) } else { max };
+                let color = pattern.tick(time_delta);
+                // Patterns still advance so that Once patterns finish, but colors are static
+                let color = if game_mode { pattern.static_color() } else { color };
+                let new = Self::adjusted(color, brightness.min(max), night_mode);
                 if new != *led {
                     modified[side].set(i as u8, true);
                 }
@@ -119,19 +240,113 @@ impl<'a> LedController<'a> {
             }
         }
 
+        let suspended = matches!(self.power, PowerState::Suspend | PowerState::DeepSleep);
+        if let Some(heartbeat) = self.heartbeat.as_mut() {
+            let (side, i) = heartbeat.led();
+            if let Some(color) = heartbeat.tick(time_delta, suspended) {
+                if Self::sides(self.side, self.local_only).contains(&side) {
+                    let new = Self::adjusted(color, u8::MAX, night_mode);
+                    let led = &mut leds[side].colors[i as usize];
+                    if new != *led {
+                        modified[side].set(i, true);
+                    }
+                    *led = new;
+                }
+            }
+        }
+
         modified
     }
 
+    /// Sides for which colors are generated
+    fn sides(this: BoardSide, local_only: bool) -> &'static [BoardSide] {
+        match (local_only, this) {
+            (false, _) => &BoardSide::EACH,
+            (true, BoardSide::Left) => &[BoardSide::Left],
+            (true, BoardSide::Right) => &[BoardSide::Right],
+        }
+    }
+
+    /// Board half that this controller runs on
+    pub fn side(&self) -> BoardSide {
+        self.side
+    }
+
+    /// Generate colors only for this half when the other one uses its own controller
+    ///
+    /// This halves the cost of rule evaluation and color generation. Colors of the other
+    /// half are left unchanged.
+    pub fn set_local_only(&mut self, local_only: bool) {
+        // Patterns of the other half need to be evaluated again
+        self.rules_outdated |= local_only != self.local_only;
+        self.local_only = local_only;
+    }
+
+    /// Apply current brightness and gamma correction to a color
+    pub fn output_color(&self, color: RGB8) -> RGB8 {
+        Self::adjusted(color, self.output_brightness(), self.night_mode)
+    }
+
+    /// Global brightness with boost applied
+    fn effective_brightness(&self) -> u8 {
+        if self.boost { u8::MAX } else { self.brightness }
+    }
+
+    /// Brightness after applying boost, power state and night mode limits
+    fn output_brightness(&self) -> u8 {
+        let brightness = self.power.led_brightness(self.effective_brightness());
+        if self.night_mode {
+            brightness.min(night::MAX_BRIGHTNESS)
+        } else {
+            brightness
+        }
+    }
+
+    fn adjusted(color: RGB8, brightness: u8, night_mode: bool) -> RGB8 {
+        let color = if night_mode { night::warm(color) } else { color };
+        color.map(|channel| Self::dimmed(channel, brightness))
+            .map(Leds::gamma_correction)
+    }
+
     fn dimmed(color: u8, brightness: u8) -> u8 {
         (((brightness as u16 + 1) * color as u16) >> 8) as u8
     }
 
     /// Change current configuration
     ///
-    /// Note that [`Self::update_patterns`] must be called to actually
-    /// reset patterns to use the new configuration.
+    /// Patterns will be switched to the new configuration on next [`Self::update_patterns`]
+    /// even without any keyboard state change.
     pub fn cycle_config(&mut self, inc: Inc) {
         inc.update(&mut self.config);
+        self.rules_outdated = true;
+    }
+
+    /// Set rules applied on top of any configuration while given layer is active
+    ///
+    /// Index in `configs` is the layer number. Only the keys matched by layer rules are
+    /// changed, the others use patterns from the current configuration.
+    pub fn set_layer_configs(&mut self, configs: &'a [LedConfig]) {
+        self.layer_configs = configs;
+        self.rules_outdated = true;
+    }
+
+    /// Use a modifiable copy of key action caches, initialized with the same values
+    ///
+    /// Needed when the keymap can be modified at runtime, see [`Self::set_key_actions`].
+    pub fn set_key_actions_buffer(&mut self, buf: &'a mut [KeyActionCache]) {
+        debug_assert_eq!(buf.len(), self.actions.len());
+        self.remapped_actions = Some(buf);
+    }
+
+    /// Update cache of key actions on given layer after keys have been remapped
+    pub fn set_key_actions(&mut self, layer: u8, actions: KeyActionCache) {
+        match self.remapped_actions.as_mut().and_then(|all| all.get_mut(layer as usize)) {
+            Some(cache) => {
+                *cache = actions;
+                self.rules_outdated = true;
+            },
+            None => defmt::warn!("Cannot update key actions of layer {=u8}", layer),
+        }
     }
 
     /// Get current global brightness
@@ -141,16 +356,117 @@ impl<'a> LedController<'a> {
 
     /// Change global brightness
     pub fn set_brightness(&mut self, brightness: u8) {
+        // Brightness may be used in rule conditions
+        self.rules_outdated |= brightness != self.brightness;
         self.brightness = brightness;
     }
+
+    /// Temporarily use maximum brightness, the global brightness is used again when disabled
+    ///
+    /// Power state, night mode and per-key limits still apply. Brightness changes during
+    /// the boost modify the global brightness that will be restored.
+    pub fn set_brightness_boost(&mut self, boost: bool) {
+        self.rules_outdated |= boost != self.boost;
+        self.boost = boost;
+    }
+
+    /// Set per-key brightness limits, the lowest limit is used for keys with multiple ones
+    pub fn set_brightness_limits(&mut self, limits: &[BrightnessLimit]) {
+        self.max_brightness = PerSide { left: [u8::MAX; NLEDS], right: [u8::MAX; NLEDS] };
+        for limit in limits {
+            for side in BoardSide::EACH {
+                limit.keys.for_each_led(side, |led| {
+                    let max = &mut self.max_brightness[side][led as usize];
+                    *max = (*max).min(limit.max);
+                });
+            }
+        }
+    }
+
+    /// Configure teaching set and dimming used in training mode
+    pub fn set_training(&mut self, config: &TrainingConfig) {
+        let mut keys = PerSide { left: LedsBitset::NONE, right: LedsBitset::NONE };
+        for side in BoardSide::EACH {
+            Some(config.keys).for_each_led(side, |led| keys[side].set(led, true));
+        }
+        self.training_keys = keys;
+        self.training_brightness = config.dim_brightness;
+    }
+
+    /// Configure LED pulsing while suspended
+    pub fn set_heartbeat(&mut self, config: HeartbeatConfig, tick_rate: TickRate) {
+        self.heartbeat = Heartbeat::new(config, tick_rate);
+    }
+
+    /// Get power state used to limit brightness
+    pub fn power_state(&self) -> PowerState {
+        self.power
+    }
+
+    /// Apply power state limits on top of the global brightness
+    pub fn set_power_state(&mut self, power: PowerState) {
+        self.power = power;
+    }
+
+    /// Get state for synchronization with the other half, `None` until keyboard state is known
+    pub fn controller_state(&self) -> Option<ControllerState> {
+        self.state.as_ref().map(|keyboard| ControllerState {
+            keyboard: keyboard.clone(),
+            config: self.config.index() as u8,
+            // Slave does not need to know about the boost
+            brightness: self.effective_brightness(),
+            power: self.power,
+            night_mode: self.night_mode,
+        })
+    }
+
+    /// Use state received from the other half
+    ///
+    /// Master changed its state `latency` ago, so patterns started by the change are advanced
+    /// by that time to stay in phase with the ones on master.
+    pub fn set_controller_state(&mut self, time: u32, state: ControllerState, latency: u32) {
+        if state.config as usize != self.config.index() {
+            self.config.set_index(state.config as usize);
+            self.rules_outdated = true;
+        }
+        self.set_brightness(state.brightness);
+        self.set_power_state(state.power);
+        self.set_night_mode(state.night_mode);
+        let changed = self.state.as_ref() != Some(&state.keyboard);
+        self.update_patterns_delayed(time, changed.then_some(state.keyboard), latency);
+    }
+
+    /// Whether night mode is enabled
+    pub fn night_mode(&self) -> bool {
+        self.night_mode
+    }
+
+    /// Enable warmer colors and limited brightness on top of the current configuration
+    pub fn set_night_mode(&mut self, enabled: bool) {
+        self.night_mode = enabled;
+    }
 }
 
 impl<'a> ColorGenerator<'a> {
-    /// Set new pattern and reset its start time
-    fn reset(&mut self, pattern: Option<&'a Pattern>) {
+    /// Generator starting at the beginning of `pattern`
+    pub fn new(pattern: &'a Pattern) -> Self {
+        let mut generator = Self::default();
+        generator.reset(Some(pattern), 0);
+        generator
+    }
+
+    /// Set new pattern and reset its start time, then skip `offset` ms of the pattern
+    fn reset(&mut self, pattern: Option<&'a Pattern>, mut offset: u32) {
         self.pattern = pattern.map(PatternIter::new);
         self.once_should_reset = false;
         self.remaining_time = Self::initial_remaining_time(self.pattern.as_ref());
+        if let Some(pattern) = self.pattern.as_mut() {
+            while offset > 0 {
+                let step = offset.min(u16::MAX as u32) as u16;
+                Self::advance_pattern(&mut self.remaining_time, step, pattern);
+                offset -= step as u32;
+            }
+        }
     }
 
     fn initial_remaining_time(pattern_iter: Option<&PatternIter<'a>>) -> u16 {
@@ -169,6 +485,11 @@ impl<'a> ColorGenerator<'a> {
 
     /// Update pattern if it is different than the current one
     pub fn update(&mut self, time_delta: u16, pattern: Option<&'a Pattern>) {
+        self.update_shifted(time_delta, pattern, |_| 0)
+    }
+
+    /// Same as [`Self::update`], but new pattern starts shifted by `offset` ms, see [`Phase`]
+    pub fn update_shifted(&mut self, time_delta: u16, pattern: Option<&'a Pattern>, offset: impl FnOnce(&'a Pattern) -> u32) {
         let keep = match (self.pattern.as_ref(), pattern) {
             (Some(this), Some(other)) => {
                 // Compare patterns by pointer address to determine if they are different.
@@ -202,7 +523,7 @@ impl<'a> ColorGenerator<'a> {
             (None, Some(_)) => false,
         };
         if !keep {
-            self.reset(pattern);
+            self.reset(pattern, pattern.map_or(0, offset));
         } else if let Some(pattern) = self.pattern.as_mut() {
             Self::advance_pattern(&mut self.remaining_time, time_delta, pattern);
         }
@@ -229,7 +550,7 @@ impl<'a> ColorGenerator<'a> {
     }
 
     /// Interpolate between two colors: c1 happens at t1, c2 at t1+duration
-    fn interpolate(time_delta: u16, duration: u16, c1: RGB8, c2: RGB8) -> RGB8 {
+    pub(super) fn interpolate(time_delta: u16, duration: u16, c1: RGB8, c2: RGB8) -> RGB8 {
         // Must hold any u8 +1 bit for sign
         type Fix16 = fixed::types::U8F8;
         type Fix32 = fixed::types::U24F8;
@@ -310,6 +631,13 @@ impl<'a> ColorGenerator<'a> {
         Some(color)
     }
 
+    /// Color of the first pattern transition, without any animation
+    pub fn static_color(&self) -> RGB8 {
+        self.pattern.as_ref()
+            .and_then(|pattern| pattern.pattern().transitions.first())
+            .map_or(RGB8::new(0, 0, 0), |t| t.color)
+    }
+
     /// Generate color for the current time by advancing pattern time by given time delta
     pub fn tick(&mut self, time_delta: u16) -> RGB8 {
         self.pattern.as_mut()
@@ -323,6 +651,45 @@ impl<'a> ColorGenerator<'a> {
     }
 }
 
+impl Pattern {
+    /// Duration of a single cycle of a repeating pattern
+    ///
+    /// Returns `None` for patterns that do not repeat, i.e. [`Repeat::Once`] or patterns with
+    /// an endless transition (duration 0).
+    fn cycle_time(&self) -> Option<u32> {
+        if self.transitions.iter().any(|t| t.duration == 0) {
+            return None;
+        }
+        let sum: u32 = self.transitions.iter().map(|t| t.duration as u32).sum();
+        match (&self.repeat, self.transitions) {
+            (Repeat::Once, _) | (_, []) => None,
+            (Repeat::Wrap, _) => Some(sum),
+            (Repeat::Reflect, [_]) => Some(sum),
+            // First and last transitions are not repeated when changing direction
+            (Repeat::Reflect, [first, .., last]) => Some(2 * sum - first.duration as u32 - last.duration as u32),
+        }
+    }
+
+    /// Time in ms by which the pattern is shifted on given LED according to [`Phase`]
+    ///
+    /// Phase is specified in milliseconds per millimeter of key position relative to the
+    /// reference key (see [`BoardSide::key_position`]). Only repeating patterns are shifted,
+    /// the result is always less than [`Self::cycle_time`].
+    pub fn phase_offset(&self, side: BoardSide, led: u8) -> u32 {
+        if self.phase == (Phase { x: 0.0, y: 0.0 }) {
+            return 0;
+        }
+        match self.cycle_time() {
+            Some(cycle) => {
+                let (x, y) = side.key_position(BoardSide::led_coords(led));
+                let offset = (self.phase.x * x + self.phase.y * y) as i32;
+                offset.rem_euclid(cycle as i32) as u32
+            },
+            None => 0,
+        }
+    }
+}
+
 impl<'a> PatternIter<'a> {
     pub fn new(pattern: &'a Pattern) -> Self {
         Self {
@@ -399,11 +766,45 @@ impl<'a> PatternIter<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::keyboard::leds::Phase;
+    use crate::keyboard::leds::{Condition, LedRule, Phase, Role};
     use std::vec::Vec;
 
     use super::*;
 
+    const WHITE: RGB8 = RGB8::new(255, 255, 255);
+
+    /// Pattern with a single constant color
+    const fn solid(color: RGB8) -> Pattern {
+        Pattern {
+            repeat: Repeat::Wrap,
+            transitions: &[Transition { color, duration: 0, interpolation: Interpolation::Piecewise }],
+            phase: Phase { x: 0.0, y: 0.0 },
+        }
+    }
+
+    /// All keys lit with solid white
+    const ALL_WHITE: LedConfig = &[LedRule { keys: None, condition: Condition::Always, pattern: solid(WHITE) }];
+
+    /// State of a master half connected to USB, on the base layer
+    fn keyboard_state() -> KeyboardState {
+        KeyboardState {
+            leds: Default::default(),
+            usb_on: true,
+            role: Role::Master,
+            layer: 0,
+            pressed: Default::default(),
+            allow_bootloader: false,
+            key_tester: false,
+            mouse_buttons: 0,
+            game_mode: false,
+            brightness: 0,
+            brightness_preset: None,
+            joystick_enabled: true,
+            training: false,
+            training_hint: None,
+        }
+    }
+
     // Verify tuples (prev_index, curr_index, is_rev), .advance() in between.
     fn test_pattern_iter(transitions_count: usize, repeat: Repeat, expect: &[(Option<usize>, Option<usize>, bool)]) {
         static TRANSITIONS: &[Transition] = &[
@@ -606,6 +1007,32 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn phase_shifts_repeating_patterns() {
+        static TRANSITIONS: &[Transition] = &[
+            Transition { color: RGB8::new(1, 1, 1), duration: 10, interpolation: Interpolation::Piecewise },
+            Transition { color: RGB8::new(2, 2, 2), duration: 10, interpolation: Interpolation::Piecewise },
+            Transition { color: RGB8::new(3, 3, 3), duration: 10, interpolation: Interpolation::Piecewise },
+        ];
+        let pattern = |repeat| Pattern { repeat, transitions: TRANSITIONS, phase: Phase { x: 1.0, y: 0.0 } };
+        let (wave, reflect, once) = (pattern(Repeat::Wrap), pattern(Repeat::Reflect), pattern(Repeat::Once));
+
+        // Key at x=19.05 mm on the left, x=-19.05 mm on the right
+        let led = BoardSide::led_number((3, 1)).unwrap();
+        assert_eq!(wave.phase_offset(BoardSide::Left, led), 19);
+        assert_eq!(wave.phase_offset(BoardSide::Right, led), 30 - 19);
+        assert_eq!(reflect.phase_offset(BoardSide::Right, led), 40 - 19);
+        assert_eq!(once.phase_offset(BoardSide::Left, led), 0);
+
+        let mut generator = ColorGenerator::default();
+        generator.update_shifted(0, Some(&wave), |p| p.phase_offset(BoardSide::Left, led));
+        assert_eq!(generator.remaining_time, 1);
+        assert_eq!(generator.tick(0), RGB8::new(2, 2, 2));
+        // Same pattern is not shifted again
+        generator.update_shifted(1, Some(&wave), |_| 5);
+        assert_eq!(generator.tick(0), RGB8::new(3, 3, 3));
+    }
+
     #[test]
     fn pattern_executor_keep_until_finished_if_finite() {
         // New pattern should not be set if the current one is Repeat::Once.
@@ -842,6 +1269,181 @@ mod tests {
         }
     }
 
+    #[test]
+    fn local_only() {
+        static CONFIGS: LedConfigurations = &[ALL_WHITE];
+        let state = keyboard_state();
+
+        let mut ctl = LedController::new(BoardSide::Right, &CONFIGS, &[]);
+        let mut leds = PerSide { left: Leds::new(), right: Leds::new() };
+        let white = ctl.output_color(WHITE);
+        ctl.set_local_only(true);
+        ctl.update_patterns(0, Some(state));
+        ctl.tick(1, &mut leds);
+        assert!(leds.right.colors.iter().all(|c| *c == white));
+        assert!(leds.left.colors.iter().all(|c| *c == RGB8::new(0, 0, 0)));
+
+        ctl.set_local_only(false);
+        ctl.update_patterns(2, None);
+        ctl.tick(3, &mut leds);
+        assert!(leds.left.colors.iter().all(|c| *c == white));
+    }
+
+    #[test]
+    fn layer_configs() {
+        use crate::keyboard::leds::Keys;
+        const RED: RGB8 = RGB8::new(255, 0, 0);
+        const LAYER_1: LedConfig = &[LedRule { keys: Some(&Keys::Rows(&[0])), condition: Condition::Always, pattern: solid(RED) }];
+        static CONFIGS: LedConfigurations = &[ALL_WHITE];
+        static LAYER_CONFIGS: LedConfigurations = &[&[], LAYER_1];
+        let mut state = KeyboardState { layer: 1, ..keyboard_state() };
+
+        let mut ctl = LedController::new(BoardSide::Left, &CONFIGS, &[]);
+        ctl.set_layer_configs(LAYER_CONFIGS);
+        let mut leds = PerSide { left: Leds::new(), right: Leds::new() };
+        let (white, red) = (ctl.output_color(WHITE), ctl.output_color(RED));
+        let mut row_0 = LedsBitset::NONE;
+        Some(&Keys::Rows(&[0])).for_each_led(BoardSide::Left, |led| row_0.set(led, true));
+
+        ctl.update_patterns(0, Some(state.clone()));
+        ctl.tick(1, &mut leds);
+        for (i, color) in leds.left.colors.iter().enumerate() {
+            assert_eq!(*color, if row_0.get(i as u8) { red } else { white });
+        }
+
+        // Layers without rules or out of range fall back to the configuration
+        for layer in [0, 2] {
+            state.layer = layer;
+            ctl.update_patterns(2, Some(state.clone()));
+            ctl.tick(3, &mut leds);
+            assert!(leds.left.colors.iter().all(|c| *c == white));
+        }
+    }
+
+    #[test]
+    fn brightness_limits() {
+        use crate::keyboard::leds::Keys;
+        static CONFIGS: LedConfigurations = &[ALL_WHITE];
+        static LIMITS: [BrightnessLimit; 2] = [
+            BrightnessLimit { keys: Some(&Keys::Rows(&[0, 1])), max: 100 },
+            BrightnessLimit { keys: Some(&Keys::Rows(&[1])), max: 50 },
+        ];
+        let state = keyboard_state();
+
+        let mut ctl = LedController::new(BoardSide::Left, &CONFIGS, &[]);
+        let mut leds = PerSide { left: Leds::new(), right: Leds::new() };
+        ctl.set_brightness(200);
+        ctl.set_brightness_limits(&LIMITS);
+        ctl.update_patterns(0, Some(state));
+        ctl.tick(1, &mut leds);
+        let color = |b| LedController::adjusted(WHITE, b, false);
+        for side in [&leds.left, &leds.right] {
+            // Rows 0 and 1 are limited (lower limit wins), other rows use global brightness
+            assert!(side.colors[0..6].iter().all(|c| *c == color(100)));
+            assert!(side.colors[6..12].iter().all(|c| *c == color(50)));
+            assert!(side.colors[12..].iter().all(|c| *c == color(200)));
+        }
+
+        // Limits only decrease brightness
+        ctl.set_brightness(20);
+        ctl.tick(2, &mut leds);
+        assert!(leds.left.colors.iter().all(|c| *c == color(20)));
+    }
+
+    #[test]
+    fn brightness_boost() {
+        let state = keyboard_state();
+        let configs: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &configs, &[]);
+        ctl.set_brightness(40);
+        ctl.set_brightness_boost(true);
+        assert_eq!(ctl.output_color(WHITE), WHITE);
+        assert_eq!(ctl.brightness(), 40);
+        // Slave gets the boosted brightness
+        ctl.update_patterns(0, Some(state));
+        assert_eq!(ctl.controller_state().map(|s| s.brightness), Some(u8::MAX));
+        // Night mode still limits brightness
+        ctl.set_night_mode(true);
+        assert_eq!(ctl.output_color(WHITE), LedController::adjusted(WHITE, night::MAX_BRIGHTNESS, true));
+
+        ctl.set_night_mode(false);
+        ctl.set_brightness(60);
+        ctl.set_brightness_boost(false);
+        assert_eq!(ctl.output_color(WHITE), LedController::adjusted(WHITE, 60, false));
+        assert_eq!(ctl.controller_state().map(|s| s.brightness), Some(60));
+    }
+
+    #[test]
+    fn training_mode() {
+        use crate::keyboard::leds::Keys;
+        static CONFIGS: LedConfigurations = &[ALL_WHITE];
+        static TRAINING: TrainingConfig = TrainingConfig { keys: &Keys::Rows(&[2]), dim_brightness: 10, hint_duration_ms: 100 };
+        let state = KeyboardState { training: true, training_hint: Some((0, 11)), ..keyboard_state() };
+
+        let mut ctl = LedController::new(BoardSide::Left, &CONFIGS, &[]);
+        let mut leds = PerSide { left: Leds::new(), right: Leds::new() };
+        ctl.set_brightness(200);
+        ctl.set_training(&TRAINING);
+        ctl.update_patterns(0, Some(state.clone()));
+        ctl.tick(1, &mut leds);
+        let color = |b| LedController::adjusted(WHITE, b, false);
+        for side in [&leds.left, &leds.right] {
+            assert!(side.colors[12..18].iter().all(|c| *c == color(200)));
+            assert!(side.colors[18..].iter().all(|c| *c == color(10)));
+        }
+        // Hint key on the right half flashes at full brightness
+        assert_eq!(leds.right.colors[5], color(200));
+        assert_eq!(leds.left.colors[5], color(10));
+        ctl.update_patterns(200, None);
+        ctl.tick(200, &mut leds);
+        assert_eq!(leds.right.colors[5], RGB8::new(0, 0, 0));
+
+        ctl.update_patterns(300, Some(KeyboardState { training: false, ..state }));
+        ctl.tick(300, &mut leds);
+        assert!(leds.left.colors.iter().all(|c| *c == color(200)));
+    }
+
+    #[test]
+    fn remapped_key_actions() {
+        use keyberon::action::{k, Action};
+        use keyberon::key_code::KeyCode;
+        use crate::bsp::{NCOLS, NROWS};
+        use crate::keyboard::leds::KeyAction;
+        const RED: RGB8 = RGB8::new(255, 0, 0);
+        const NO: Action<()> = Action::NoOp;
+        const LAYER: [[Action<()>; 2 * NCOLS]; NROWS] = [[NO; 2 * NCOLS]; NROWS];
+        static CONFIGS: LedConfigurations = &[&[
+            LedRule { keys: None, condition: Condition::Always, pattern: solid(WHITE) },
+            LedRule { keys: None, condition: Condition::KeyAction(KeyAction::KeyCode), pattern: solid(RED) },
+        ]];
+        let mut layer = LAYER;
+        layer[0][0] = k(KeyCode::A);
+        let led = BoardSide::led_number((0, 0)).unwrap() as usize;
+
+        let actions = [KeyActionCache::new(&LAYER)];
+        let mut buf = [KeyActionCache::new(&LAYER)];
+        let mut ctl = LedController::new(BoardSide::Left, &CONFIGS, &actions);
+        let mut leds = PerSide { left: Leds::new(), right: Leds::new() };
+        let (white, red) = (ctl.output_color(WHITE), ctl.output_color(RED));
+        ctl.update_patterns(0, Some(keyboard_state()));
+        ctl.tick(1, &mut leds);
+        assert!(leds.left.colors.iter().all(|c| *c == white));
+
+        // Ignored without a modifiable copy
+        ctl.set_key_actions(0, KeyActionCache::new(&layer));
+        ctl.update_patterns(2, None);
+        ctl.tick(3, &mut leds);
+        assert!(leds.left.colors.iter().all(|c| *c == white));
+
+        ctl.set_key_actions_buffer(&mut buf);
+        ctl.set_key_actions(0, KeyActionCache::new(&layer));
+        ctl.update_patterns(4, None);
+        ctl.tick(5, &mut leds);
+        for (i, color) in leds.left.colors.iter().enumerate() {
+            assert_eq!(*color, if i == led { red } else { white });
+        }
+    }
+
     #[allow(dead_code)]
     #[derive(Debug, Default)]
     struct ErrorStats {
@@ -0,0 +1,54 @@
+use crate::bsp::persistent::Persistent;
+
+#[link_section = ".uninit.ghanima.preset"]
+static STATE: Persistent<u8, 0x6272_6900> = Persistent::new();
+
+/// Convert brightness preset in percent to global brightness value
+pub const fn brightness(percent: u8) -> u8 {
+    let percent = if percent > 100 { 100 } else { percent };
+    (percent as u16 * u8::MAX as u16 / 100) as u8
+}
+
+/// Index of the next preset when cycling through `n` presets
+pub fn next(current: Option<u8>, n: usize) -> Option<u8> {
+    if n == 0 {
+        return None;
+    }
+    Some(current.map_or(0, |i| (i as usize + 1) % n) as u8)
+}
+
+/// Brightness preset from before the last software reset, none after power loss
+pub fn load() -> Option<u8> {
+    STATE.load()
+}
+
+/// Preserve brightness preset over system reset
+pub fn store(preset: Option<u8>) {
+    match preset {
+        Some(preset) => STATE.store(preset),
+        None => STATE.clear(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_to_brightness() {
+        assert_eq!(brightness(0), 0);
+        assert_eq!(brightness(25), 63);
+        assert_eq!(brightness(100), 255);
+        assert_eq!(brightness(150), 255);
+    }
+
+    #[test]
+    fn cycle_presets() {
+        assert_eq!(next(None, 4), Some(0));
+        assert_eq!(next(Some(2), 4), Some(3));
+        assert_eq!(next(Some(3), 4), Some(0));
+        // Configuration changed and index is out of range
+        assert_eq!(next(Some(7), 4), Some(0));
+        assert_eq!(next(None, 0), None);
+    }
+}
@@ -0,0 +1,160 @@
+use rgb::RGB8;
+
+use crate::bsp::NLEDS;
+use super::{LedController, LedsBitset};
+use super::pattern::ColorGenerator;
+
+/// Configuration of reactive lighting generated locally on slave
+#[derive(Clone, Copy)]
+pub struct ReactiveConfig {
+    pub enabled: bool,
+    /// Color of pressed keys
+    pub color: RGB8,
+    /// Duration of fading back to received colors after key release, in LED ticks
+    pub fade: u16,
+}
+
+/// Overlay highlighting keys pressed on this half
+///
+/// Slave only displays colors received from master, so reactive patterns are delayed by the
+/// serial link round-trip. This overlay is applied on top of the received colors immediately.
+pub struct ReactiveOverlay {
+    config: ReactiveConfig,
+    pressed: LedsBitset,
+    fading: LedsBitset,
+    released_at: [u32; NLEDS],
+}
+
+impl ReactiveOverlay {
+    pub const fn new(config: ReactiveConfig) -> Self {
+        Self {
+            config,
+            pressed: LedsBitset::NONE,
+            fading: LedsBitset::NONE,
+            released_at: [0; NLEDS],
+        }
+    }
+
+    /// Update local keys state
+    pub fn set_pressed(&mut self, time: u32, pressed: LedsBitset) {
+        if !self.config.enabled {
+            return;
+        }
+        let released = self.pressed & !pressed;
+        for led in 0..NLEDS as u8 {
+            if released.get(led) {
+                self.released_at[led as usize] = time;
+            }
+        }
+        self.fading = (self.fading | released) & !pressed;
+        self.pressed = pressed;
+    }
+
+    /// Apply overlay on top of given colors
+    ///
+    /// Highlight color is adjusted by controller brightness, power state and night mode
+    /// the same way as pattern colors.
+    pub fn apply(&mut self, time: u32, colors: &mut [RGB8; NLEDS], controller: &LedController) {
+        if (self.pressed | self.fading).is_none() {
+            return;
+        }
+        let highlight = controller.output_color(self.config.color);
+        for (led, color) in colors.iter_mut().enumerate() {
+            let i = led as u8;
+            if self.pressed.get(i) {
+                *color = highlight;
+            } else if self.fading.get(i) {
+                let elapsed = time.wrapping_sub(self.released_at[led]);
+                if elapsed >= self.config.fade as u32 {
+                    self.fading.set(i, false);
+                } else {
+                    *color = ColorGenerator::interpolate(elapsed as u16, self.config.fade, highlight, *color);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::sides::BoardSide;
+    use crate::keyboard::leds::LedConfigurations;
+    use crate::keyboard::power::PowerState;
+
+    const CONFIG: ReactiveConfig = ReactiveConfig {
+        enabled: true,
+        color: RGB8::new(200, 200, 200),
+        fade: 10,
+    };
+    const BASE: RGB8 = RGB8::new(0, 0, 100);
+
+    fn keys(leds: &[u8]) -> LedsBitset {
+        let mut set = LedsBitset::NONE;
+        leds.iter().for_each(|l| set.set(*l, true));
+        set
+    }
+
+    fn controller() -> LedController<'static> {
+        static CONFIGS: LedConfigurations = &[];
+        let mut ctl = LedController::new(BoardSide::Left, &CONFIGS, &[]);
+        ctl.set_brightness(255);
+        ctl
+    }
+
+    #[test]
+    fn highlight_and_fade() {
+        let ctl = controller();
+        let highlight = ctl.output_color(CONFIG.color);
+        let mut overlay = ReactiveOverlay::new(CONFIG);
+        let mut colors = [BASE; NLEDS];
+        overlay.set_pressed(0, keys(&[1, 3]));
+        overlay.apply(0, &mut colors, &ctl);
+        assert_eq!(colors[0], BASE);
+        assert_eq!(colors[1], highlight);
+        assert_eq!(colors[3], highlight);
+
+        overlay.set_pressed(100, keys(&[3]));
+        let mut colors = [BASE; NLEDS];
+        overlay.apply(105, &mut colors, &ctl);
+        assert_eq!(colors[1], ColorGenerator::interpolate(5, 10, highlight, BASE));
+        assert_ne!(colors[1], BASE);
+        assert_eq!(colors[3], highlight);
+
+        let mut colors = [BASE; NLEDS];
+        overlay.apply(110, &mut colors, &ctl);
+        assert_eq!(colors[1], BASE);
+        assert!(!overlay.fading.get(1));
+    }
+
+    #[test]
+    fn disabled() {
+        let mut overlay = ReactiveOverlay::new(ReactiveConfig { enabled: false, ..CONFIG });
+        let mut colors = [BASE; NLEDS];
+        overlay.set_pressed(0, keys(&[1]));
+        overlay.apply(0, &mut colors, &controller());
+        assert_eq!(colors, [BASE; NLEDS]);
+    }
+
+    #[test]
+    fn highlight_follows_controller_output() {
+        let mut ctl = controller();
+        let mut overlay = ReactiveOverlay::new(CONFIG);
+        overlay.set_pressed(0, keys(&[1]));
+
+        ctl.set_brightness(128);
+        let mut colors = [BASE; NLEDS];
+        overlay.apply(0, &mut colors, &ctl);
+        assert_eq!(colors[1], ctl.output_color(CONFIG.color));
+        assert!(colors[1].r < CONFIG.color.r);
+
+        ctl.set_night_mode(true);
+        overlay.apply(0, &mut colors, &ctl);
+        assert_eq!(colors[1], ctl.output_color(CONFIG.color));
+        assert!(colors[1].b < colors[1].r);
+
+        ctl.set_power_state(PowerState::DeepSleep);
+        overlay.apply(0, &mut colors, &ctl);
+        assert_eq!(colors[1], RGB8::default());
+    }
+}
@@ -0,0 +1,351 @@
+use serde::{Serialize, Deserialize};
+use postcard::experimental::max_size::MaxSize;
+use defmt::Format;
+
+use crate::bsp::sides::BoardSide;
+use super::ticks::TickRate;
+
+/// Baud rates that can be used on the serial link, the first one is used on startup
+pub const BAUD_RATES: [u32; 4] = [115_200, 230_400, 460_800, 921_600];
+
+/// Baud rate used until a higher one is negotiated
+pub const SAFE_BAUD_RATE: u32 = BAUD_RATES[0];
+
+/// Period of keep-alive messages when running above the safe baud rate
+const PING_PERIOD_MS: u32 = 250;
+/// Fall back to safe baud rate if nothing has been received for that long
+const SILENCE_TIMEOUT_MS: u32 = 4 * PING_PERIOD_MS;
+/// Retry proposal if it hasn't been accepted within this time
+const PROPOSE_TIMEOUT_MS: u32 = 500;
+/// Time window for counting reception errors
+const ERROR_WINDOW_MS: u32 = 1000;
+/// Number of errors within [`ERROR_WINDOW_MS`] that triggers fallback
+const ERROR_THRESHOLD: u32 = 5;
+
+/// Baud rate negotiation messages
+#[derive(Serialize, Deserialize, MaxSize, Format, PartialEq, Clone, Copy)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Message {
+    /// Propose to switch to given baud rate (index in [`BAUD_RATES`])
+    Propose(u8),
+    /// Accept given baud rate, sender will switch after transmitting this message
+    Accept(u8),
+    /// Keep-alive message
+    Ping,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+enum State {
+    /// Waiting for link activity before negotiating
+    Idle,
+    /// Waiting for the other half to accept our proposal
+    Proposed { timeout: u32 },
+    /// Nothing more to negotiate
+    Established,
+}
+
+/// Serial link bring-up
+///
+/// Both halves start at [`SAFE_BAUD_RATE`]. The left half proposes the highest baud rate
+/// it supports and the right half accepts the minimum of the proposed one and its own
+/// maximum. When the link seems broken (no messages or many reception errors) both halves
+/// independently fall back to the safe baud rate and lower their maximum, so the next
+/// negotiation will select a lower baud rate.
+pub struct Link {
+    side: BoardSide,
+    max: u8,
+    current: u8,
+    state: State,
+    pending: Option<u8>,
+    time: u32,
+    last_rx: Option<u32>,
+    last_ping: u32,
+    window_start: u32,
+    window_errors: Option<u32>,
+    // Timing constants converted to ticks
+    ping_period: u32,
+    silence_timeout: u32,
+    propose_timeout: u32,
+    error_window: u32,
+}
+
+impl Link {
+    /// Create link bring-up logic allowing baud rates up to `max_baud_rate`
+    pub fn new(side: BoardSide, max_baud_rate: u32, rate: TickRate) -> Self {
+        let max = BAUD_RATES.iter()
+            .rposition(|b| *b <= max_baud_rate)
+            .unwrap_or(0) as u8;
+        Self {
+            side,
+            max,
+            current: 0,
+            state: State::Idle,
+            pending: None,
+            time: 0,
+            last_rx: None,
+            last_ping: 0,
+            window_start: 0,
+            window_errors: None,
+            ping_period: rate.from_ms(PING_PERIOD_MS),
+            silence_timeout: rate.from_ms(SILENCE_TIMEOUT_MS),
+            propose_timeout: rate.from_ms(PROPOSE_TIMEOUT_MS),
+            error_window: rate.from_ms(ERROR_WINDOW_MS),
+        }
+    }
+
+    /// Baud rate currently in use
+    pub fn baud_rate(&self) -> u32 {
+        BAUD_RATES[self.current as usize]
+    }
+
+    /// Baud rate that should be applied as soon as transmitter is idle
+    pub fn pending_baud_rate(&self) -> Option<u32> {
+        self.pending.map(|i| BAUD_RATES[i as usize])
+    }
+
+    /// Notify that the pending baud rate has been applied
+    pub fn baud_rate_applied(&mut self) {
+        if let Some(i) = self.pending.take() {
+            defmt::info!("Serial baud rate: {=u32}", BAUD_RATES[i as usize]);
+            // Give the other half some time before assuming the link is broken
+            self.last_rx = Some(self.time);
+        }
+    }
+
+    fn switch(&mut self, index: u8) {
+        self.current = index;
+        self.pending = Some(index);
+    }
+
+    /// Process received message
+    pub fn on_rx(&mut self, msg: Message) -> Option<Message> {
+        match msg {
+            Message::Propose(i) => {
+                let i = i.min(self.max);
+                self.switch(i);
+                self.state = State::Established;
+                Some(Message::Accept(i))
+            },
+            Message::Accept(i) => {
+                if matches!(self.state, State::Proposed { .. }) {
+                    self.switch(i.min(self.max));
+                    self.state = State::Established;
+                }
+                None
+            },
+            Message::Ping => None,
+        }
+    }
+
+    /// Advance time by one tick
+    ///
+    /// `received` should be true if any valid message has been received since the last tick,
+    /// `errors` is the total number of reception errors (may overflow).
+    pub fn tick(&mut self, received: bool, errors: u32) -> Option<Message> {
+        self.time = self.time.wrapping_add(1);
+        if received {
+            self.last_rx = Some(self.time);
+        }
+        let since_rx = self.last_rx.map(|t| self.time.wrapping_sub(t));
+
+        // Count errors in a fixed time window
+        let window_errors = *self.window_errors.get_or_insert(errors);
+        let too_many_errors = errors.wrapping_sub(window_errors) >= ERROR_THRESHOLD;
+        if self.time.wrapping_sub(self.window_start) >= self.error_window {
+            self.window_start = self.time;
+            self.window_errors = Some(errors);
+        }
+
+        // Do nothing until the previous switch has been applied
+        if self.pending.is_some() {
+            return None;
+        }
+
+        let silent = since_rx.map_or(true, |t| t >= self.silence_timeout);
+        if self.current != 0 && (silent || too_many_errors) {
+            defmt::warn!("Serial link broken (silent={=bool}), falling back", silent);
+            self.max = self.current - 1;
+            self.switch(0);
+            self.state = State::Idle;
+            self.window_errors = None;
+            return None;
+        }
+
+        match self.state {
+            State::Idle => {
+                // Only left side proposes, and only when we know that the other half is there
+                let active = since_rx.map_or(false, |t| t < self.ping_period);
+                if self.side == BoardSide::Left && active && self.current < self.max {
+                    self.state = State::Proposed { timeout: self.propose_timeout };
+                    return Some(Message::Propose(self.max));
+                }
+            },
+            State::Proposed { timeout } => {
+                self.state = if timeout == 0 {
+                    State::Idle
+                } else {
+                    State::Proposed { timeout: timeout - 1 }
+                };
+            },
+            State::Established => {},
+        }
+
+        // Keep the link alive so that the other half can detect problems
+        if self.current != 0 && self.time.wrapping_sub(self.last_ping) >= self.ping_period {
+            self.last_ping = self.time;
+            return Some(Message::Ping);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ticks equal milliseconds
+    const RATE: TickRate = TickRate::new(1000);
+
+    fn apply(link: &mut Link) -> Option<u32> {
+        let baud = link.pending_baud_rate();
+        link.baud_rate_applied();
+        baud
+    }
+
+    fn establish(left: &mut Link, right: &mut Link) {
+        // Left needs to hear from right first
+        assert_eq!(left.tick(true, 0), Some(Message::Propose(left.max)));
+        let accept = right.on_rx(Message::Propose(left.max)).unwrap();
+        left.on_rx(accept);
+        apply(left);
+        apply(right);
+    }
+
+    #[test]
+    fn max_from_config() {
+        assert_eq!(Link::new(BoardSide::Left, 460_800, RATE).max, 2);
+        assert_eq!(Link::new(BoardSide::Left, 500_000, RATE).max, 2);
+        assert_eq!(Link::new(BoardSide::Left, 9600, RATE).max, 0);
+        assert_eq!(Link::new(BoardSide::Left, 2_000_000, RATE).max, 3);
+    }
+
+    #[test]
+    fn starts_at_safe_rate() {
+        let link = Link::new(BoardSide::Left, 921_600, RATE);
+        assert_eq!(link.baud_rate(), SAFE_BAUD_RATE);
+        assert_eq!(link.pending_baud_rate(), None);
+    }
+
+    #[test]
+    fn left_waits_for_activity() {
+        let mut left = Link::new(BoardSide::Left, 921_600, RATE);
+        for _ in 0..1000 {
+            assert_eq!(left.tick(false, 0), None);
+        }
+        assert_eq!(left.tick(true, 0), Some(Message::Propose(3)));
+    }
+
+    #[test]
+    fn right_never_proposes() {
+        let mut right = Link::new(BoardSide::Right, 921_600, RATE);
+        for _ in 0..1000 {
+            assert!(!matches!(right.tick(true, 0), Some(Message::Propose(_))));
+        }
+    }
+
+    #[test]
+    fn negotiate_minimum() {
+        let mut left = Link::new(BoardSide::Left, 921_600, RATE);
+        let mut right = Link::new(BoardSide::Right, 460_800, RATE);
+        establish(&mut left, &mut right);
+        assert_eq!(left.baud_rate(), 460_800);
+        assert_eq!(right.baud_rate(), 460_800);
+    }
+
+    #[test]
+    fn switch_waits_until_applied() {
+        let mut left = Link::new(BoardSide::Left, 921_600, RATE);
+        let mut right = Link::new(BoardSide::Right, 921_600, RATE);
+        assert_eq!(right.on_rx(Message::Propose(3)), Some(Message::Accept(3)));
+        assert_eq!(right.pending_baud_rate(), Some(921_600));
+        for _ in 0..2 * SILENCE_TIMEOUT_MS {
+            assert_eq!(right.tick(false, 0), None);
+        }
+        assert_eq!(apply(&mut right), Some(921_600));
+        assert_eq!(apply(&mut left), None);
+    }
+
+    #[test]
+    fn fallback_on_silence() {
+        let mut left = Link::new(BoardSide::Left, 921_600, RATE);
+        let mut right = Link::new(BoardSide::Right, 921_600, RATE);
+        establish(&mut left, &mut right);
+        for _ in 0..SILENCE_TIMEOUT_MS - 1 {
+            right.tick(false, 0);
+        }
+        assert_eq!(right.pending_baud_rate(), None);
+        right.tick(false, 0);
+        assert_eq!(apply(&mut right), Some(SAFE_BAUD_RATE));
+        assert_eq!(right.max, 2);
+    }
+
+    #[test]
+    fn fallback_on_errors() {
+        let mut left = Link::new(BoardSide::Left, 921_600, RATE);
+        let mut right = Link::new(BoardSide::Right, 921_600, RATE);
+        establish(&mut left, &mut right);
+        let mut errors = 0;
+        for _ in 0..ERROR_THRESHOLD - 1 {
+            errors += 1;
+            left.tick(true, errors);
+        }
+        assert_eq!(left.pending_baud_rate(), None);
+        left.tick(true, errors + 1);
+        assert_eq!(apply(&mut left), Some(SAFE_BAUD_RATE));
+
+        // Next negotiation uses lower baud rate
+        establish(&mut left, &mut right);
+        assert_eq!(left.baud_rate(), 460_800);
+        assert_eq!(right.baud_rate(), 460_800);
+    }
+
+    #[test]
+    fn errors_spread_over_time_ignored() {
+        let mut left = Link::new(BoardSide::Left, 921_600, RATE);
+        let mut right = Link::new(BoardSide::Right, 921_600, RATE);
+        establish(&mut left, &mut right);
+        let mut errors = 0;
+        for i in 0..10 * ERROR_WINDOW_MS {
+            if i % (ERROR_WINDOW_MS / 2) == 0 {
+                errors += 1;
+            }
+            left.tick(true, errors);
+        }
+        assert_eq!(left.pending_baud_rate(), None);
+        assert_eq!(left.baud_rate(), 921_600);
+    }
+
+    #[test]
+    fn pings_when_above_safe_rate() {
+        let mut left = Link::new(BoardSide::Left, 921_600, RATE);
+        let mut right = Link::new(BoardSide::Right, 921_600, RATE);
+        establish(&mut left, &mut right);
+        let pings = (0..10 * PING_PERIOD_MS)
+            .filter(|_| right.tick(true, 0) == Some(Message::Ping))
+            .count();
+        assert_eq!(pings, 10);
+    }
+
+    #[test]
+    fn ping_period_in_ms() {
+        let mut left = Link::new(BoardSide::Left, 921_600, TickRate::new(2000));
+        let mut right = Link::new(BoardSide::Right, 921_600, TickRate::new(2000));
+        establish(&mut left, &mut right);
+        let pings = (0..10 * 2 * PING_PERIOD_MS)
+            .filter(|_| right.tick(true, 0) == Some(Message::Ping))
+            .count();
+        assert_eq!(pings, 10);
+    }
+}
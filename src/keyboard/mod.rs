@@ -4,60 +4,169 @@
 //! Contains firmware extensions such as communication between keyboard halves
 //! and handling of custom events.
 
+/// Persistent accessibility options
+pub mod accessibility;
 /// Special keyboard actions
 pub mod actions;
+/// Runtime statistics queries from host software
+pub mod diagnostics;
 /// Keyboard related USB HID classes
 pub mod hid;
+/// Tracing of hold-tap decisions for tuning timeouts
+mod hold_tap;
+/// USB device abstraction used by keyboard logic
+mod host;
+/// Log of recent key events for diagnostics
+pub mod event_log;
+/// Explaining how layout resolves key presses
+mod explain;
+/// Latency-oriented game mode
+pub mod game_mode;
+/// Persistent joystick enable state
+pub mod joystick;
 /// Keyboard matrix scanner with debouncing
 mod keys;
+/// Keymap queries and modifications from host software
+pub mod keymap;
+/// Key press to USB report latency measurements
+pub mod latency;
 /// Keyboard lightning control and configuration
 pub mod leds;
+/// Serial link bring-up between keyboard halves
+pub mod link;
 /// Mouse emulation
 pub mod mouse;
 /// Messages sent between keyboard halves
-mod msg;
+pub mod msg;
+/// Automatic NumLock management
+pub mod num_lock;
+/// Power state management shared by all subsystems
+pub mod power;
+/// Protocol version negotiation between keyboard halves
+pub mod protocol;
 /// Role negotiation between keyboard halves
 mod role;
+/// OS-dependent key chords of shortcut actions
+pub mod shortcuts;
+/// Host-side simulation of both keyboard halves
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+/// Resolution of simultaneously pressed opposite direction keys
+pub mod socd;
+/// Opt-in key usage statistics persisted to flash
+pub mod stats;
+/// Key tester mode for board assembly QA
+mod tester;
+/// Conversions between keyboard ticks and real time
+pub mod ticks;
+/// Synchronization of LED animation time between halves
+mod time_sync;
+/// Layout training mode with LED hints
+pub mod training;
+/// Typing text by emulating key presses
+mod typing;
 
 use rtic::mutex_prelude::*;
+use ufmt::{uWrite, uwrite};
+use keyberon::key_code::KeyCode;
 use keyberon::layout::{self, Event};
-use serde::{Serialize, Deserialize};
 
 use usb_device::UsbError;
 use usb_device::device::UsbDeviceState;
 use usbd_human_interface_device::UsbHidError;
 use crate::bsp::sides::{BoardSide, PerSide};
-use crate::bsp::usb::Usb;
-use crate::bsp::{NCOLS, NROWS, LedColors};
+use crate::bsp::{NCOLS, NROWS, LedColors, ident, image};
+use crate::hal_ext::clock::Instant;
+use crate::hal_ext::watchdog::WatchdogConfig;
 use crate::ioqueue;
-use crate::utils::OptionChanges as _;
+use crate::utils::{OptionChanges as _, Watermark};
 use role::Role;
 use actions::{Action, LedAction, Inc};
 use keyberon::layout::CustomEvent;
 use keys::PressedKeys;
 use hid::KeyCodeIterExt as _;
 
-pub use keys::Keys;
+pub use keys::{Keys, KeyMatrix, HwMatrix, MatrixHealth, DebounceConfig, AccessibilityConfig};
+pub use host::UsbHost;
 pub use leds::{LedController, LedOutput, KeyboardState, KeyActionCache};
+pub use power::PowerState;
 
 const MAX_PACKET_SIZE: usize = ioqueue::max_packet_size::<msg::Message>();
 
+/// Duration of USB remote wake up signalling, must be within 1-15 ms
+const USB_WAKE_UP: ticks::Millis = ticks::Millis(9);
+
+/// Period of sending all pressed keys from slave, limits how long a key can stay stuck
+/// after a lost key event
+const PRESSED_RESYNC_MS: u32 = 500;
+
 /// Transmitter queue of packets for communication between keyboard halves
 pub type Transmitter<const N: usize> = ioqueue::Transmitter<'static, msg::Message, N, { MAX_PACKET_SIZE }>;
 /// Receiver queue of packets for communication between keyboard halves
 pub type Receiver<const N: usize> = ioqueue::Receiver<msg::Message, N, { MAX_PACKET_SIZE }>;
 
 /// Split keyboard logic
-pub struct Keyboard<const L: usize> {
-    keys: keys::Keys,
+pub struct Keyboard<const L: usize, M = HwMatrix> {
+    keys: keys::Keys<M>,
     fsm: role::Fsm,
+    link: link::Link,
+    protocol: protocol::Protocol,
     layout: layout::Layout<{ 2 * NCOLS }, NROWS, L, Action>,
+    keymap: keymap::Keymap<L>,
     mouse: mouse::Mouse,
     state: Option<KeyboardState>,
-    prev_usb_state: UsbDeviceState,
+    power: power::PowerManager,
+    vbus: power::Vbus,
     pressed: PerSide<PressedKeys>,
     keyboard_reports: hid::HidReportQueue<hid::KeyboardReport, 8>,
-    consumer_reports: hid::HidReportQueue<hid::ConsumerReport, 1>,
+    auto_repeat: hid::AutoRepeat,
+    num_lock: num_lock::NumLock,
+    consumer_reports: hid::HidReportQueue<hid::ConsumerReport, 2>,
+    consumer_repeat: hid::ConsumerRepeat,
+    typist: typing::Typist,
+    joystick_enabled: bool,
+    /// Joystick moved while not being sampled, handled as activity on next tick
+    joystick_wake: bool,
+    event_log: event_log::EventLog,
+    key_stats: stats::KeyStats<L>,
+    latency: latency::LatencyMeter,
+    tester: Option<tester::KeyTester>,
+    game_mode: game_mode::GameMode<L>,
+    explainer: explain::KeyExplainer<L>,
+    hold_tap_trace: hold_tap::HoldTapTrace,
+    /// Action of the last joystick flick, to be released on the next tick
+    flick_release: Option<&'static keyberon::action::Action<Action>>,
+    trainer: training::Trainer<L>,
+    slow_keys: keys::SlowKeys,
+    bounce_keys: keys::BounceKeys,
+    socd: socd::Socd,
+    shortcuts: shortcuts::Shortcuts,
+    brightness_presets: &'static [u8],
+    brightness_preset: Option<u8>,
+    leds_blackout: bool,
+    time_sync: time_sync::TimeSync,
+    /// Result of firmware image verification at boot
+    image: Option<image::ImageCrc>,
+    /// Notifications from host waiting for LED update
+    notifications: heapless::Vec<leds::Notification, { leds::MAX_NOTIFICATIONS }>,
+    /// LED color overrides from host waiting for LED update
+    led_overrides: heapless::Vec<leds::OverrideRequest, { leds::MAX_NOTIFICATIONS }>,
+    /// Statistics query from host waiting for a response
+    stats_request: Option<diagnostics::StatsRequest>,
+    /// LED colors query from host waiting for a response
+    colors_request: Option<diagnostics::ColorsRequest>,
+    /// Panic report query from host waiting for a response
+    panic_request: Option<diagnostics::PanicRequest>,
+    /// Prescaler query or change from host waiting to be applied
+    prescaler_request: Option<diagnostics::PrescalerRequest>,
+    /// Answer to keymap request from host waiting to be sent
+    keymap_response: Option<hid::RawReport>,
+    tick_rate: ticks::TickRate,
+    ms_counter: ticks::MsCounter,
+    time: u32,
+    last_resync: Option<u32>,
+    key_seq_tx: u8,
+    key_seq_rx: msg::KeySequence,
 }
 
 /// Keyboard configuration
@@ -68,63 +177,167 @@ pub struct KeyboardConfig<const L: usize> {
     pub mouse: &'static mouse::MouseConfig,
     /// Configuration of RGB LED lightning
     pub leds: leds::LedConfigurations,
+    /// Rules applied on top of the current LED configuration while given layer (index) is active
+    pub layer_leds: leds::LedConfigurations,
     /// Timeout for polling the other half about role negotiation
     pub timeout: u32,
     /// Do not jump to bootloader until FirmwareAction::AllowBootloader is pressed
     pub bootload_strict: bool,
+    /// Maximum baud rate of serial link between halves
+    pub serial_baud_rate: u32,
+    /// Frequency of keyboard ticks, other timing values in configuration are given in ticks
+    pub tick_frequency_hz: u32,
+    /// Key matrix debouncing
+    pub debounce: DebounceConfig,
+    /// Timing of accessibility options
+    pub accessibility: AccessibilityConfig,
+    /// Default periods of periodic tasks
+    pub prescalers: Prescalers,
+    /// Firmware key auto-repeat
+    pub auto_repeat: hid::AutoRepeatConfig,
+    /// Accelerated repetition of held consumer keys
+    pub consumer_repeat: hid::ConsumerRepeatConfig,
+    /// Automatic NumLock enabling
+    pub num_lock: num_lock::NumLockMode,
+    /// Reactive lighting generated on slave for its own keys
+    pub reactive: leds::ReactiveConfig,
+    /// LED brightness presets in percent selected by [`LedAction::BrightnessPreset`]
+    pub brightness_presets: &'static [u8],
+    /// Per-key limits of LED brightness, the lowest one is used if keys overlap
+    pub led_brightness_limits: &'static [leds::BrightnessLimit],
+    /// LED pulsing while USB is suspended
+    pub suspend_heartbeat: leds::HeartbeatConfig,
+    /// Layout training mode
+    pub training: training::TrainingConfig,
+    /// Resolution of simultaneous presses of opposite direction keys
+    pub socd: socd::SocdConfig,
+    /// Initial OS profile of shortcut actions
+    pub os_profile: shortcuts::OsProfile,
+    /// Translation of shortcut key chords for the host keyboard layout
+    pub host_keys: shortcuts::HostKeys,
+    /// Watchdog selection and timing
+    pub watchdog: WatchdogConfig,
+}
+
+/// Periods of periodic tasks in multiples of a "tick", 0 disables the task
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Prescalers {
+    /// LED colors generation and transmission
+    pub leds: u32,
+    /// Joystick sampling
+    pub joystick: u32,
+    /// Mouse emulation and mouse reports
+    pub mouse: u32,
+    /// Periodic debug reports
+    pub debug: u32,
+}
+
+/// Periodic task with runtime-configurable prescaler
+#[derive(PartialEq, Clone, Copy, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum PrescalerTask {
+    Leds = 0,
+    Joystick = 1,
+    Mouse = 2,
+    Debug = 3,
 }
 
 /// Deferred update of LED controller state
 pub struct LedControllerUpdate {
     state: Option<KeyboardState>,
     config: Option<Inc>,
-    brightness: Option<BrightnessUpdate>,
+    brightness: Option<Inc>,
+    brightness_value: Option<u8>,
+    /// Start or end of brightness boost
+    brightness_boost: Option<bool>,
+    power: Option<PowerState>,
+    clear_overrides: bool,
+    toggle_night_mode: bool,
+    /// Generate colors only for this half because the other one runs its own controller
+    local_only: bool,
+    /// Controller state received from master (slave only)
+    remote: Option<leds::ControllerState>,
+    /// Estimated time since master sent [`Self::remote`]
+    remote_latency: u32,
+    /// Overlays received from master (slave only)
+    overlays: Option<leds::OverlaysState>,
+    /// Keys pressed on this half if changed, for local reactive lighting (slave only)
+    local_pressed: Option<PressedKeys>,
+    /// Cache of key actions on a layer with remapped keys, see [`keymap::Keymap::take_key_actions`]
+    key_actions: Option<(u8, KeyActionCache)>,
 }
 
 pub enum LedsUpdate {
     Controller(LedControllerUpdate),
-    FromOther(Option<LedColors>),
-}
-
-/// Deferred update of LED controller state
-#[derive(Clone, Serialize, Deserialize, PartialEq)]
-enum BrightnessUpdate {
-    Up,
-    Down,
-    Disable,
-    Enable,
+    /// Colors received from master and keys pressed on this half if changed
+    FromOther(Option<LedColors>, Option<PressedKeys>),
 }
 
-impl From<Inc> for BrightnessUpdate {
-    fn from(inc: Inc) -> Self {
-        match inc {
-            Inc::Up => Self::Up,
-            Inc::Down => Self::Down,
-        }
-    }
-}
-
-impl<const L: usize> Keyboard<L> {
+impl<const L: usize, M: KeyMatrix> Keyboard<L, M> {
     /// Crate new keyboard with given layout and negotiation timeout specified in "ticks"
     /// (see [`Self::tick`])
-    pub fn new(keys: keys::Keys, config: &KeyboardConfig<L>) -> Self {
+    pub fn new(keys: keys::Keys<M>, config: &KeyboardConfig<L>) -> Self {
         let side = *keys.side();
+        let tick_rate = ticks::TickRate::new(config.tick_frequency_hz);
         let fsm = role::Fsm::with(side, config.timeout);
+        let link = link::Link::new(side, config.serial_baud_rate, tick_rate);
         let layout = layout::Layout::new(config.layers);
-        let mouse = mouse::Mouse::new(config.mouse);
+        let mouse = mouse::Mouse::new(config.mouse, tick_rate);
         let pressed = Default::default();
         let keyboard_reports = hid::HidReportQueue::new();
         let consumer_reports = hid::HidReportQueue::new();
         Self {
             keys,
             fsm,
+            link,
+            protocol: protocol::Protocol::new(protocol::Version::current(), tick_rate),
             layout,
+            keymap: keymap::Keymap::new(config.layers),
             mouse,
             state: None,
             pressed,
             keyboard_reports,
+            auto_repeat: hid::AutoRepeat::new(config.auto_repeat),
+            num_lock: num_lock::NumLock::new(config.num_lock, tick_rate),
             consumer_reports,
-            prev_usb_state: UsbDeviceState::Default,
+            consumer_repeat: hid::ConsumerRepeat::new(config.consumer_repeat),
+            power: power::PowerManager::new(tick_rate),
+            vbus: power::Vbus::Unknown,
+            typist: typing::Typist::new(),
+            joystick_enabled: true,
+            joystick_wake: false,
+            event_log: event_log::EventLog::new(),
+            key_stats: stats::KeyStats::new(),
+            latency: latency::LatencyMeter::new(),
+            tester: None,
+            game_mode: game_mode::GameMode::new(config.layers),
+            explainer: explain::KeyExplainer::new(config.layers),
+            hold_tap_trace: hold_tap::HoldTapTrace::new(),
+            flick_release: None,
+            trainer: training::Trainer::new(config.layers, &config.training, tick_rate),
+            slow_keys: keys::SlowKeys::new(&config.accessibility, tick_rate),
+            bounce_keys: keys::BounceKeys::new(&config.accessibility, tick_rate),
+            socd: socd::Socd::new(config.socd),
+            shortcuts: shortcuts::Shortcuts::new(config.os_profile, config.host_keys),
+            brightness_presets: config.brightness_presets,
+            brightness_preset: None,
+            leds_blackout: false,
+            time_sync: time_sync::TimeSync::new(tick_rate),
+            image: None,
+            notifications: heapless::Vec::new(),
+            led_overrides: heapless::Vec::new(),
+            stats_request: None,
+            colors_request: None,
+            panic_request: None,
+            prescaler_request: None,
+            keymap_response: None,
+            tick_rate,
+            ms_counter: ticks::MsCounter::new(tick_rate),
+            time: 0,
+            last_resync: None,
+            key_seq_tx: 0,
+            key_seq_rx: msg::KeySequence::new(),
         }
     }
 
@@ -133,95 +346,496 @@ impl<const L: usize> Keyboard<L> {
         self.fsm.role()
     }
 
+    /// Store result of firmware image verification, the CRC is announced to the other half
+    pub fn set_image_crc(&mut self, image: image::ImageCrc) {
+        self.protocol.set_firmware(image.crc);
+        self.image = Some(image);
+    }
+
+    /// Force given role instead of the negotiated one, `None` to disable
+    pub fn force_role(&mut self, role: Option<Role>) {
+        self.fsm.force_role(role);
+    }
+
+    /// Get key matrix health statistics
+    pub fn matrix_health(&self) -> &MatrixHealth {
+        self.keys.health()
+    }
+
+    /// Get log of recent key events
+    pub fn event_log(&self) -> &event_log::EventLog {
+        &self.event_log
+    }
+
+    /// Get key usage statistics
+    pub fn key_stats(&self) -> &stats::KeyStats<L> {
+        &self.key_stats
+    }
+
+    pub fn key_stats_mut(&mut self) -> &mut stats::KeyStats<L> {
+        &mut self.key_stats
+    }
+
+    /// Enable/disable key press latency measurements
+    pub fn set_latency_measurement(&mut self, enabled: bool) {
+        self.latency.set_enabled(enabled);
+    }
+
+    /// Get latency statistics since last call, None if measurements are disabled
+    pub fn take_latency_stats(&mut self) -> Option<latency::LatencyStats> {
+        self.latency.is_enabled().then(|| self.latency.take_stats())
+    }
+
+    /// Log hold-tap decisions of given key (global coordinates), `None` disables tracing
+    pub fn set_hold_tap_trace(&mut self, key: Option<(u8, u8)>) {
+        self.hold_tap_trace.set_key(key);
+    }
+
+    /// Check if joystick readings are used
+    pub fn joystick_enabled(&self) -> bool {
+        self.joystick_enabled
+    }
+
+    /// Enable/disable use of joystick readings, the state is preserved over system reset
+    pub fn set_joystick_enabled(&mut self, enabled: bool) {
+        if enabled != self.joystick_enabled {
+            defmt::info!("Joystick: {=bool}", enabled);
+        }
+        self.joystick_enabled = enabled;
+        joystick::store(enabled);
+        if !enabled {
+            self.mouse.update_joystick((0, 0));
+        }
+    }
+
+    /// Get accessibility options
+    pub fn accessibility(&self) -> accessibility::Options {
+        accessibility::Options {
+            slow_keys: self.slow_keys.is_enabled(),
+            bounce_keys: self.bounce_keys.is_enabled(),
+        }
+    }
+
+    /// Set accessibility options, these are preserved over system reset
+    pub fn set_accessibility(&mut self, options: accessibility::Options) {
+        self.slow_keys.set_enabled(options.slow_keys);
+        self.bounce_keys.set_enabled(options.bounce_keys);
+        accessibility::store(options);
+    }
+
+    /// Get current power state
+    pub fn power_state(&self) -> PowerState {
+        self.power.state()
+    }
+
+    /// Number of key event packets from the other half that have been lost
+    pub fn lost_key_packets(&self) -> u32 {
+        self.key_seq_rx.lost()
+    }
+
+    /// Restore joystick divider preserved over system reset, see [`joystick::load_divider`]
+    pub fn restore_joystick_divider(&mut self, divider: Option<u16>) {
+        if let Some(divider) = divider {
+            defmt::info!("Joystick divider restored: {=u16}", divider);
+            self.mouse.set_joystick_divider(divider);
+        }
+    }
+
+    /// Whether LED controller state should be sent to the other half instead of colors
+    ///
+    /// Requires the other half to run the same protocol version with the same configuration,
+    /// otherwise it falls back to sending colors.
+    pub fn remote_leds(&self) -> bool {
+        self.fsm.role() == Role::Master && self.protocol.peer_matches()
+    }
+
+    /// Convert local timer tick to LED animation time, synchronized with master on slave
+    ///
+    /// Keyboard runs on every timer tick, so both use the same time unit.
+    pub fn led_time(&mut self, t: u32) -> u32 {
+        self.time_sync.led_time(t)
+    }
+
+    /// State of pending dwell click, see [`mouse::Mouse::dwell_indicator`]
+    pub fn dwell_indicator(&self) -> Option<mouse::DwellIndicator> {
+        self.mouse.dwell_indicator()
+    }
+
+    /// New joystick plane if it changed since the last call, see [`mouse::Mouse::take_plane_change`]
+    pub fn take_joystick_plane_change(&mut self) -> Option<mouse::Plane> {
+        self.mouse.take_plane_change()
+    }
+
+    /// Check if all LEDs should be turned off
+    pub fn leds_blackout(&self) -> bool {
+        self.leds_blackout
+    }
+
+    /// Take statistics query received from host, to be answered using [`diagnostics::StatsResponse`]
+    pub fn take_stats_request(&mut self) -> Option<diagnostics::StatsRequest> {
+        self.stats_request.take()
+    }
+
+    /// Take LED colors query received from host, to be answered with colors from [`LedOutput::current`]
+    pub fn take_colors_request(&mut self) -> Option<diagnostics::ColorsRequest> {
+        self.colors_request.take()
+    }
+
+    /// Take panic report query received from host, to be answered with [`crate::bsp::panic::last`]
+    pub fn take_panic_request(&mut self) -> Option<diagnostics::PanicRequest> {
+        self.panic_request.take()
+    }
+
+    /// Take prescaler query received from host, to be applied to [`Prescalers`] and answered
+    pub fn take_prescaler_request(&mut self) -> Option<diagnostics::PrescalerRequest> {
+        self.prescaler_request.take()
+    }
+
+    /// Take answer to keymap request received from host, to be sent as a raw HID report
+    pub fn take_keymap_response(&mut self) -> Option<hid::RawReport> {
+        self.keymap_response.take()
+    }
+
+    /// Copy keymap to RAM buffer so that host software can modify it until reboot
+    pub fn enable_runtime_keymap(&mut self, buf: &'static mut core::mem::MaybeUninit<keymap::Layers<L>>) {
+        let layers = self.keymap.enable(buf);
+        self.set_layers(layers);
+    }
+
+    /// Switch all users of the keymap to given layers
+    ///
+    /// Layout is rebuilt, so it drops references to actions of held keys, keeping only the
+    /// default layer, which is the current one when no keys are held.
+    fn set_layers(&mut self, layers: &'static keymap::Layers<L>) {
+        let default_layer = self.layout.current_layer();
+        self.layout = layout::Layout::new(layers);
+        self.layout.set_default_layer(default_layer);
+        self.game_mode.set_layers(layers);
+        self.explainer.set_layers(layers);
+        self.trainer.set_layers(layers);
+        self.hold_tap_trace.clear_pending();
+    }
+
+    /// Modify the keymap while no one holds references to its layers
+    ///
+    /// Users of the layers are switched to the configured ones for the time of the modification,
+    /// which loses layout state, so this should only be done when no keys are held.
+    fn modify_keymap<T>(&mut self, modify: impl FnOnce(&mut keymap::Keymap<L>) -> T) -> T {
+        self.set_layers(self.keymap.configured());
+        let result = modify(&mut self.keymap);
+        self.set_layers(self.keymap.layers());
+        result
+    }
+
+    /// Counters of keyboard and consumer HID report queues
+    pub fn hid_queue_stats(&self) -> [hid::QueueStats; 2] {
+        [*self.keyboard_reports.stats(), *self.consumer_reports.stats()]
+    }
+
+    /// Maximum usage of keyboard and consumer HID report queues
+    pub fn hid_queue_watermarks(&self) -> [Watermark; 2] {
+        [*self.keyboard_reports.watermark(), *self.consumer_reports.watermark()]
+    }
+
+    pub fn reset_hid_queue_stats(&mut self) {
+        self.keyboard_reports.reset_stats();
+        self.consumer_reports.reset_stats();
+    }
+
+    pub fn reset_hid_queue_watermarks(&mut self) {
+        self.keyboard_reports.reset_watermark();
+        self.consumer_reports.reset_watermark();
+    }
+
+    /// Take notifications received from host, to be shown with [`LedOutput::notify`]
+    pub fn take_notifications(&mut self) -> heapless::Vec<leds::Notification, { leds::MAX_NOTIFICATIONS }> {
+        core::mem::take(&mut self.notifications)
+    }
+
+    /// Take LED color overrides received from host, see [`LedOutput::apply_override_request`]
+    pub fn take_led_overrides(&mut self) -> heapless::Vec<leds::OverrideRequest, { leds::MAX_NOTIFICATIONS }> {
+        core::mem::take(&mut self.led_overrides)
+    }
+
+    /// Serial baud rate that should be applied when transmitter is idle
+    pub fn pending_baud_rate(&self) -> Option<u32> {
+        self.link.pending_baud_rate()
+    }
+
+    /// Notify that the pending serial baud rate has been applied
+    pub fn baud_rate_applied(&mut self) {
+        self.link.baud_rate_applied()
+    }
+
     /// Periodic keyboard events processing
     ///
     /// This should be called in a fixed period to update internal state, handle communication
     /// between keyboard halves and resolve key events depending on keyboard layout. Returns
     /// [`KeyboardState`] to be passed to the LED controller - possibly a lower priority task.
+    /// `now` is the timestamp of the current tick, used where timing must not depend on
+    /// skipped calls.
     pub fn tick<const TX: usize, const RX: usize>(
         &mut self,
-        mut crc: impl Mutex<T = <msg::Message as ioqueue::Packet>::Checksum>,
+        now: u32,
+        crc: &mut <msg::Message as ioqueue::Packet>::Checksum,
         mut tx: impl Mutex<T = Transmitter<TX>>,
         mut rx: impl Mutex<T = Receiver<RX>>,
-        mut usb: impl Mutex<T = &'static mut Usb>,
+        mut usb: impl Mutex<T = impl UsbHost>,
     ) -> LedsUpdate
     {
+        self.time = self.time.wrapping_add(1);
+        let elapsed_ms = self.ms_counter.tick(1);
+
         // Retrieve USB state
-        let (usb_state, keyboard_leds, allow_bootloader) = usb.lock(|usb| (
-            usb.dev.state(),
+        let (usb_state, vbus, keyboard_leds, allow_bootloader) = usb.lock(|usb| (
+            usb.state(),
+            usb.vbus(),
             usb.keyboard_leds(),
-            usb.dfu.ops().is_allowed()
+            usb.bootloader_allowed(),
         ));
-        let prev_usb_state = self.prev_usb_state;
-        self.prev_usb_state = usb_state;
 
-        // First update USB state in FSM
-        if let Some(msg) = self.fsm.usb_state(usb_state == UsbDeviceState::Configured) {
-            (&mut crc, &mut tx).lock(|crc, tx| tx.send(crc, msg));
+        // Commands from host software
+        if let Some(report) = usb.lock(|usb| usb.read_raw_report()) {
+            self.raw_hid_command(&report);
+        }
+
+        if vbus != self.vbus {
+            defmt::info!("VBUS: {}", vbus);
+            self.vbus = vbus;
         }
 
-        // Store forced LED colors update from master
+        // First update USB state in FSM; a host that suspended the bus is still connected,
+        // while a charger never enumerates the device, so there is no point in becoming master
+        let host_on = match usb_state {
+            UsbDeviceState::Configured => true,
+            UsbDeviceState::Suspend => vbus == power::Vbus::Present,
+            _ => false,
+        };
+        if let Some(msg) = self.fsm.usb_state(host_on) {
+            tx.lock(|tx| tx.send(crc, msg));
+        }
+
+        // Store forced LED colors or controller state update from master
         let mut led_colors = None;
+        let mut led_state = None;
+        let mut led_overlays = None;
 
         // Process RX data
         let mut was_key_event = false;  // check events as any key should trigger usb wakeup from suspend
-        while let Some(msg) = (&mut crc, &mut rx).lock(|crc, rx| rx.read(crc)) {
+        let mut was_rx = false;
+        while let Some(msg) = rx.lock(|rx| rx.read(crc)) {
+            was_rx = true;
             match msg {
                 msg::Message::Role(msg) => {
                     defmt::info!("Got role::Message: {}", msg);
+                    // Roles may be renegotiated after the link was lost or the other half was
+                    // restarted, resynchronize pressed keys and key event sequence numbers
+                    self.last_resync = None;
+                    self.key_seq_rx.reset();
                     if let Some(msg) =  self.fsm.on_rx(msg) {
-                        (&mut crc, &mut tx).lock(|crc, tx| tx.send(crc, msg));
+                        tx.lock(|tx| tx.send(crc, msg));
+                    }
+                    // Other half may have been restarted so make sure it uses eager debouncing
+                    // and keeps LEDs off
+                    if self.fsm.role() == Role::Master && self.game_mode.is_enabled() {
+                        tx.lock(|tx| tx.send(crc, msg::Message::GameMode(true)));
+                    }
+                    if self.fsm.role() == Role::Master && self.leds_blackout {
+                        tx.lock(|tx| tx.send(crc, msg::Message::Blackout(true)));
+                    }
+                    // Slave drops its remapped keys and master sends its own ones again
+                    match self.fsm.role() {
+                        Role::Master => self.keymap.resync(),
+                        Role::Slave if self.keymap.is_modified() => {
+                            // SAFETY: users of the layers are switched away in modify_keymap
+                            self.modify_keymap(|keymap| unsafe { keymap.reset_all() });
+                        },
+                        Role::Slave => {},
                     }
                 },
                 msg::Message::Key(event) => {
                     was_key_event = true;
-                    match event {
-                        Event::Press(i, j) => defmt::info!("Got KeyPress({=u8}, {=u8})", i, j),
-                        Event::Release(i, j) => defmt::info!("Got KeyRelease({=u8}, {=u8})", i, j),
+                    self.remote_key_event(event);
+                },
+                // Batches from firmware older than KEY_EVENTS_VERSION have no sequence number,
+                // so they cannot be parsed correctly; periodic pressed keys resync covers them
+                msg::Message::Keys(_) if !self.protocol.peer_has_protocol(protocol::KEY_EVENTS_VERSION) => {
+                    defmt::warn!("Ignoring key events in old format");
+                },
+                msg::Message::Keys(events) => {
+                    was_key_event = true;
+                    let lost = self.key_seq_rx.on_rx(events.seq());
+                    if lost != 0 {
+                        defmt::warn!("Lost {=u8} key event packets, requesting resync", lost);
+                        tx.lock(|tx| tx.send(crc, msg::Message::PressedRequest));
                     }
-                    // Update pressed keys for the other half
-                    self.pressed[self.keys.side().other()]
-                        .update_keys_on_event(event.transform(|i, j| BoardSide::coords_to_local((i, j))));
-                    // Only master uses key events from the other half
-                    if self.fsm.role() == Role::Master {
-                        self.layout.event(event);
+                    events.iter().for_each(|event| self.remote_key_event(event));
+                },
+                msg::Message::PressedRequest => {
+                    self.last_resync = None;
+                },
+                msg::Message::Pressed(pressed) => {
+                    // Generate events for any keys that differ due to lost messages
+                    let side = self.keys.side().other();
+                    for (led, state) in self.pressed[side].changes(pressed) {
+                        let (i, j) = side.coords_to_global(BoardSide::led_coords(led));
+                        defmt::warn!("Pressed keys out of sync at ({=u8}, {=u8})", i, j);
+                        was_key_event = true;
+                        self.remote_key_event(if state { Event::Press(i, j) } else { Event::Release(i, j) });
                     }
                 },
                 msg::Message::Leds(colors) => {
                     led_colors = Some(colors);
                 },
+                msg::Message::Link(msg) => {
+                    if let Some(msg) = self.link.on_rx(msg) {
+                        tx.lock(|tx| tx.send(crc, msg));
+                    }
+                },
+                msg::Message::GameMode(enabled) => {
+                    self.keys.set_eager_debounce(enabled);
+                },
+                msg::Message::Blackout(enabled) => {
+                    self.leds_blackout = enabled;
+                },
+                msg::Message::Version(version) => {
+                    self.protocol.on_rx(version);
+                },
+                msg::Message::LedState(state) => {
+                    led_state = Some(state);
+                },
+                msg::Message::Overlays(state) => {
+                    led_overlays = Some(state);
+                },
+                msg::Message::Firmware(image_crc) => {
+                    self.protocol.on_rx_firmware(image_crc);
+                },
+                msg::Message::Time { request, time } => {
+                    self.time_sync.on_rx(request, time, self.time);
+                },
+                msg::Message::TimeRequest(request) => {
+                    tx.lock(|tx| tx.send(crc, msg::Message::Time { request, time: self.time }));
+                },
+                // Only master remaps keys, so changes received by master are stale
+                msg::Message::Keymap(change) => if self.fsm.role() == Role::Slave && self.keymap.is_enabled() {
+                    // SAFETY: users of the layers are switched away in modify_keymap
+                    self.modify_keymap(|keymap| unsafe { keymap.apply(change) });
+                },
+                // Skipped by the receiver
+                msg::Message::Unknown => {},
+            }
+        }
+
+        // Serial link bring-up, errors that indicate bad line conditions
+        let rx_errors = rx.lock(|rx| {
+            let stats = rx.stats();
+            stats.checksum_errors.wrapping_add(stats.cobs_errors)
+        });
+        if let Some(msg) = self.link.tick(was_rx, rx_errors) {
+            tx.lock(|tx| tx.send(crc, msg));
+        }
+
+        // Slave announces its version so that master can use newer protocol features.
+        // Master announces too, so that slave knows if it can send batched key events
+        if let Some(version) = self.protocol.tick(true) {
+            tx.lock(|tx| tx.send(crc, msg::Message::Version(version)));
+            if let Some(firmware) = self.protocol.firmware() {
+                tx.lock(|tx| tx.send(crc, msg::Message::Firmware(firmware)));
+            }
+        }
+
+        // Slave generating its own LED colors needs master time to keep animations in phase
+        let sync_time = self.fsm.role() == Role::Slave && self.protocol.peer_matches();
+        if sync_time && self.time % self.tick_rate.from_ms(time_sync::SYNC_PERIOD_MS) == 0 {
+            tx.lock(|tx| tx.send(crc, msg::Message::TimeRequest(self.time)));
+        }
+
+        // Master sends remapped keys one per tick, the other half must use the same layers
+        if self.fsm.role() == Role::Master && self.protocol.peer_matches() {
+            if let Some(change) = self.keymap.take_change() {
+                tx.lock(|tx| tx.send(crc, msg::Message::Keymap(change)));
             }
         }
 
         // Advance FSM time, process timeouts
         if let Some(msg) = self.fsm.tick() {
-            (&mut crc, &mut tx).lock(|crc, tx| tx.send(crc, msg));
+            tx.lock(|tx| tx.send(crc, msg));
         }
 
-        // Scan keys and push all events
-        for event in self.keys.scan() {
+        // Stop scanning when host is sleeping, key press interrupt will resume it
+        self.keys.set_idle_allowed(self.power.state().matrix_idle_allowed());
+
+        // Scan keys and push all events, slave sends them in batches if master supports it
+        let batch_events = self.protocol.peer_has_protocol(protocol::KEY_EVENTS_VERSION);
+        let mut events = msg::KeyEvents::new(self.key_seq_tx);
+        let mut was_local_event = false;
+        for event in self.keys.scan(now) {
             was_key_event = true;
+            was_local_event = true;
+            self.event_log.push(self.time, event);
+            if let Event::Press(..) = event {
+                self.latency.on_press(self.keyboard_reports.next_seq(), Instant::now);
+            }
             match self.fsm.role() {
                 // Master should handle keyboard logic
-                Role::Master => self.layout.event(event),
+                Role::Master => {
+                    Self::test_key(&self.tester, &mut self.typist, event);
+                    self.layout_event(event);
+                },
                 // Slave should only send key events to master
                 Role::Slave => {
                     let (i, j) = event.coord();
                     defmt::info!("Send Key({=u8}, {=u8})", i, j);
-                    (&mut crc, &mut tx).lock(|crc, tx| tx.send(crc, event));
+                    if !batch_events {
+                        tx.lock(|tx| tx.send(crc, event));
+                    } else if !events.push(event) {
+                        // Sequence number is incremented even if the queue is full to detect the loss
+                        self.key_seq_tx = self.key_seq_tx.wrapping_add(1);
+                        let full = core::mem::replace(&mut events, msg::KeyEvents::new(self.key_seq_tx));
+                        tx.lock(|tx| tx.send(crc, full));
+                        events.push(event);
+                    }
                 },
             }
         }
+        if !events.is_empty() {
+            self.key_seq_tx = self.key_seq_tx.wrapping_add(1);
+            tx.lock(|tx| tx.send(crc, events));
+        }
 
         // Update pressed keys state after scan
         self.pressed[*self.keys.side()] = self.keys.pressed();
 
-        // Process USB wake up FIXME: assumes keyboard tick is 1 kHz
-        usb.lock(|usb| usb.wake_up_update(was_key_event, 9));
+        // Slave periodically sends all pressed keys after the events, so master can fix lost ones
+        if self.fsm.role() == Role::Slave {
+            let period = self.tick_rate.from_ms(PRESSED_RESYNC_MS);
+            if self.last_resync.map_or(true, |t| self.time.wrapping_sub(t) >= period) {
+                self.last_resync = Some(self.time);
+                let pressed = self.pressed[*self.keys.side()];
+                tx.lock(|tx| tx.send(crc, msg::Message::Pressed(pressed)));
+            }
+        }
+
+        // Process USB wake up
+        let joystick_wake = core::mem::take(&mut self.joystick_wake);
+        usb.lock(|usb| usb.wake_up_update(was_key_event || joystick_wake, USB_WAKE_UP, elapsed_ms));
+
+        // Update power state, joystick movement also counts as user activity
+        let activity = was_key_event || joystick_wake || self.mouse.joystick_active();
+        let power_change = self.power.tick(usb_state, vbus, activity);
 
         if self.fsm.role() == Role::Slave {
-            // Slave just uses the LED update from master
-            LedsUpdate::FromOther(led_colors)
+            // Local reactive overlay is applied on top of colors in both modes
+            let pressed = was_local_event.then(|| self.pressed[*self.keys.side()]);
+            if led_state.is_some() || led_overlays.is_some() {
+                // Generate colors locally using state from master
+                let latency = self.time_sync.latency();
+                let key_actions = self.keymap.take_key_actions();
+                LedsUpdate::Controller(LedControllerUpdate::from_other(led_state, led_overlays, pressed, latency, key_actions))
+            } else {
+                // Slave just uses the LED update from master
+                LedsUpdate::FromOther(led_colors, pressed)
+            }
         } else {
             // Master keeps track of the actual keyboard state
 
@@ -235,6 +849,15 @@ impl<const L: usize> Keyboard<L> {
                 },
                 pressed: self.pressed.clone(),
                 allow_bootloader,
+                key_tester: self.tester.is_some(),
+                mouse_buttons: self.mouse.buttons(),
+                game_mode: self.game_mode.is_enabled(),
+                // Filled in by LED controller
+                brightness: 0,
+                brightness_preset: self.brightness_preset,
+                joystick_enabled: self.joystick_enabled,
+                training: self.trainer.is_enabled(),
+                training_hint: self.trainer.hint(),
             };
 
             // Collect state
@@ -242,64 +865,248 @@ impl<const L: usize> Keyboard<L> {
                 state: self.state.if_changed(&state).cloned(),
                 config: None,
                 brightness: None,
+                brightness_value: None,
+                brightness_boost: None,
+                power: power_change,
+                clear_overrides: false,
+                toggle_night_mode: false,
+                local_only: self.remote_leds(),
+                remote: None,
+                remote_latency: 0,
+                overlays: None,
+                local_pressed: None,
+                key_actions: self.keymap.take_key_actions(),
             };
 
-            // TODO: auto-enable NumLock by checking leds state
+            self.key_stats.tick(self.layout.current_layer(), elapsed_ms);
+
+            // Tap NumLock if host has it disabled while it should be enabled
+            let num_lock_tap = usb_state == UsbDeviceState::Configured
+                && self.num_lock.tick(keyboard_leds.num_lock(), self.layout.current_layer());
+
+            // Presses of keys held long enough with slow keys enabled
+            self.trainer.tick();
+            self.bounce_keys.tick();
+            for event in self.slow_keys.tick() {
+                self.resolve_event(event);
+            }
+
             // Advance keyboard time
             let custom = self.layout.tick();
+            if self.hold_tap_trace.key().is_some() {
+                let layout = &self.layout;
+                let output = hold_tap::LayoutOutput {
+                    pressed: |kc| layout.keycodes().any(|k| k == kc),
+                    layer: layout.current_layer(),
+                    custom: match &custom {
+                        CustomEvent::Press(action) => Some(*action),
+                        _ => None,
+                    },
+                };
+                self.hold_tap_trace.tick(&output, self.time);
+            }
             // self.keyboard_reports.push(self.layout.keycodes().collect());
-            if let Some((action, pressed)) = custom.transposed() {
+            // Joystick flick acts as a key tap, its action is released on the next tick
+            let flick = match self.flick_release.take() {
+                Some(action) => Some((action, false)),
+                None => self.mouse.take_flick().map(|action| {
+                    self.flick_release = Some(action);
+                    (action, true)
+                }),
+            };
+            // Key codes of a flick are added to keyboard reports below
+            let flick = flick.and_then(|(action, pressed)| match action {
+                keyberon::action::Action::Custom(custom) => Some((custom, pressed)),
+                _ => None,
+            });
+            let testing = self.tester.is_some();
+            let custom = custom.transposed().into_iter().chain(flick)
+                // In key tester mode only allow to exit the mode
+                .filter(|(action, _)| !testing || matches!(action,
+                    Action::Firmware(actions::FirmwareAction::KeyTester | actions::FirmwareAction::KeyTesterTyping)));
+            for (action, pressed) in custom {
                 match action {
+                    Action::Led(LedAction::BrightnessBoost) => update.brightness_boost = Some(pressed),
                     Action::Led(led) => if !pressed {  // only on release
                         match led {
                             LedAction::Cycle(inc) => update.config = Some(*inc),
-                            LedAction::Brightness(inc) => update.brightness = Some((*inc).into()),
+                            LedAction::Brightness(inc) => {
+                                update.brightness = Some(*inc);
+                                self.select_brightness_preset(None);
+                            },
+                            LedAction::ClearOverrides => update.clear_overrides = true,
+                            LedAction::NightMode => update.toggle_night_mode = true,
+                            LedAction::Blackout => {
+                                self.leds_blackout = !self.leds_blackout;
+                                let msg = msg::Message::Blackout(self.leds_blackout);
+                                tx.lock(|tx| tx.send(crc, msg));
+                            },
+                            LedAction::BrightnessPreset => {
+                                let n = self.brightness_presets.len();
+                                if let Some(i) = leds::preset::next(self.brightness_preset, n) {
+                                    let percent = self.brightness_presets[i as usize];
+                                    update.brightness_value = Some(leds::preset::brightness(percent));
+                                    self.select_brightness_preset(Some(i));
+                                }
+                            },
+                            // Handled on both press and release
+                            LedAction::BrightnessBoost => {},
                         }
                     },
                     Action::Mouse(mouse) => self.mouse.handle_action(mouse, pressed),
+                    Action::Shortcut(shortcut) => self.shortcuts.handle_action(shortcut, pressed),
                     Action::Consumer(key) => {
                         let mut report = hid::ConsumerReport::default();
                         if pressed {
                             report.codes[0] = *key;
                         }
                         self.consumer_reports.push(report);
+                        self.consumer_repeat.set_key(*key, pressed);
+                    },
+                    Action::Firmware(actions::FirmwareAction::TypeInfo) => if pressed {
+                        let role = match self.fsm.role() {
+                            Role::Master => "master",
+                            Role::Slave => "slave",
+                        };
+                        let layer = self.layout.current_layer();
+                        let (image, peer_image) = (self.image, self.protocol.peer_firmware());
+                        if let Some(text) = self.typist.text() {
+                            // Text is truncated if it does not fit, which is still useful
+                            ident::FirmwareInfo::current().write_text(text)
+                                .and_then(|_| match image {
+                                    Some(image) => {
+                                        text.write_str(" ")?;
+                                        image.write_text(text)
+                                    },
+                                    None => Ok(()),
+                                })
+                                .and_then(|_| match peer_image {
+                                    Some(peer) => {
+                                        text.write_str(" peer=")?;
+                                        ident::write_hex_u32(text, peer)
+                                    },
+                                    None => Ok(()),
+                                })
+                                .and_then(|_| uwrite!(text, " role={} layer={}\n", role, layer))
+                                .and_then(|_| ident::HardwareInfo::read().write_text(text))
+                                .ok();
+                        }
+                    },
+                    Action::Firmware(actions::FirmwareAction::ToggleEventLog) => if pressed {
+                        let enabled = !self.event_log.is_enabled();
+                        defmt::info!("Key event log: {=bool}", enabled);
+                        self.event_log.set_enabled(enabled);
+                    },
+                    Action::Firmware(actions::FirmwareAction::KeyStats) => if pressed {
+                        self.key_stats.set_enabled(!self.key_stats.is_enabled());
+                    },
+                    Action::Firmware(actions::FirmwareAction::WipeKeyStats) => if pressed {
+                        self.key_stats.wipe();
+                    },
+                    Action::Firmware(actions::FirmwareAction::KeyTester) => if pressed {
+                        self.toggle_key_tester(false);
+                    },
+                    Action::Firmware(actions::FirmwareAction::KeyTesterTyping) => if pressed {
+                        self.toggle_key_tester(true);
+                    },
+                    Action::Firmware(actions::FirmwareAction::ToggleJoystick) => if pressed {
+                        self.set_joystick_enabled(!self.joystick_enabled);
+                    },
+                    Action::Firmware(actions::FirmwareAction::GameMode) => if pressed {
+                        let enabled = !self.game_mode.is_enabled();
+                        self.game_mode.set_enabled(enabled);
+                        self.keys.set_eager_debounce(enabled);
+                        tx.lock(|tx| tx.send(crc, msg::Message::GameMode(enabled)));
+                    },
+                    Action::Firmware(actions::FirmwareAction::SlowKeys) => if pressed {
+                        let options = self.accessibility();
+                        self.set_accessibility(accessibility::Options { slow_keys: !options.slow_keys, ..options });
+                    },
+                    Action::Firmware(actions::FirmwareAction::BounceKeys) => if pressed {
+                        let options = self.accessibility();
+                        self.set_accessibility(accessibility::Options { bounce_keys: !options.bounce_keys, ..options });
+                    },
+                    Action::Firmware(actions::FirmwareAction::ExplainKey) => if pressed {
+                        self.explainer.arm();
+                    },
+                    Action::Firmware(actions::FirmwareAction::Training) => if pressed {
+                        self.trainer.set_enabled(!self.trainer.is_enabled());
+                    },
+                    Action::Firmware(actions::FirmwareAction::SwapRole) => if pressed {
+                        if let Some(msg) = self.fsm.swap_role() {
+                            tx.lock(|tx| tx.send(crc, msg));
+                        }
+                    },
+                    Action::Firmware(actions::FirmwareAction::AllowBootloader) => if pressed {
+                        usb.lock(|usb| usb.allow_bootloader());
+                    },
+                    Action::Firmware(actions::FirmwareAction::JumpToBootloader) => if pressed {
+                        usb.lock(|usb| usb.reboot(true));
+                    },
+                    Action::Firmware(actions::FirmwareAction::Reboot) => if pressed {
+                        usb.lock(|usb| usb.reboot(false));
+                    },
+                    Action::Firmware(actions::FirmwareAction::InfiniteLoop) => if pressed {
+                        loop {}
                     },
-                    Action::Firmware(fw) => if pressed {
-                        usb.lock(|usb| {
-                            let bus = usb.dev.bus();
-                            let dfu_boot = usb.dfu.ops_mut();
-                            match fw {
-                                actions::FirmwareAction::AllowBootloader => dfu_boot.set_allowed(true),
-                                actions::FirmwareAction::JumpToBootloader => dfu_boot.reboot(true, Some(bus)),
-                                actions::FirmwareAction::Reboot => dfu_boot.reboot(false, Some(bus)),
-                                actions::FirmwareAction::InfiniteLoop => loop {},
-                            }
-                        });
-                    }
                 };
 
             }
 
-            // Advance mouse emulation time
-            self.mouse.tick();
+            // Repeat held consumer key by releasing it for one report
+            if let Some(key) = self.consumer_repeat.tick() {
+                self.consumer_reports.push(hid::ConsumerReport::default());
+                let mut report = hid::ConsumerReport::default();
+                report.codes[0] = key;
+                self.consumer_reports.push(report);
+            }
 
-            // Advance usbd-human-interface-device keyboard time FIXME: assumes 1 kHz
-            usb.lock(|usb| {
-                let keyboard: &hid::KeyboardInterface<'_, _> = usb.hid.interface();
-                keyboard.tick().ok();
-            });
+            // Advance usbd-human-interface-device keyboard time in 1 ms steps
+            usb.lock(|usb| (0..elapsed_ms.0).for_each(|_| usb.hid_tick()));
 
-            // Push next report
-            self.keyboard_reports.push(hid::KeyboardReport::new(self.layout.keycodes().into_page()));
+            if let Some(tester) = self.tester.as_mut() {
+                tester.on_keycodes(self.layout.keycodes());
+            }
+
+            // Push next report, when typing text wait until previous reports are sent
+            if self.typist.is_typing() {
+                if self.keyboard_reports.is_empty() {
+                    if let Some(report) = self.typist.next_report() {
+                        self.keyboard_reports.push(report);
+                    }
+                }
+            } else if self.tester.is_some() {
+                // No normal reports in key tester mode
+                self.keyboard_reports.push(hid::KeyboardReport::new([]));
+            } else {
+                // Key codes of a flick are held for a single tick, like its custom action
+                let flick_keys: &[KeyCode] = match self.flick_release {
+                    Some(keyberon::action::Action::KeyCode(kc)) => core::slice::from_ref(kc),
+                    Some(keyberon::action::Action::MultipleKeyCodes(kcs)) => kcs,
+                    _ => &[],
+                };
+                let layout = || {
+                    let layout = self.layout.keycodes().chain(flick_keys.iter().copied());
+                    self.shortcuts.keycodes(self.game_mode.keycodes(layout))
+                };
+                self.socd.update(layout());
+                let keycodes = || self.socd.keycodes(layout());
+                if num_lock_tap {
+                    let keycodes = keycodes().chain(core::iter::once(KeyCode::NumLock));
+                    self.keyboard_reports.push(hid::KeyboardReport::new(keycodes.into_page()));
+                }
+                // Repeat by releasing the key for one report
+                if let Some(kc) = self.auto_repeat.tick(keycodes()) {
+                    let keycodes = keycodes().filter(|k| *k != kc);
+                    self.keyboard_reports.push(hid::KeyboardReport::new(keycodes.into_page()));
+                }
+                self.keyboard_reports.push(hid::KeyboardReport::new(keycodes().into_page()));
+            }
 
             // Push USB reports
             if usb_state == UsbDeviceState::Configured {
-                usb.lock(|usb| {
-                    let keyboard: &hid::KeyboardInterface<'_, _> = usb.hid.interface();
-                    let consumer: &hid::ConsumerInterface<'_, _> = usb.hid.interface();
-                    let mouse: &hid::MouseInterface<'_, _> = usb.hid.interface();
-
-                    self.keyboard_reports.send(|r| keyboard.write_report(r)
+                let written = usb.lock(|usb| {
+                    let written = self.keyboard_reports.send(|r| usb.write_keyboard_report(r)
                         .or_else(|e| match e {
                             UsbHidError::WouldBlock => Err(UsbError::WouldBlock),
                             UsbHidError::Duplicate => Ok(()),
@@ -308,40 +1115,232 @@ impl<const L: usize> Keyboard<L> {
                         })
                         .map(|_| 1));
 
-                    self.consumer_reports.send(|r| consumer.write_report(r));
-
-                    // Try to push USB mouse report
-                    self.mouse.push_report(|r| {
-                        match mouse.write_report(r) {
-                            Ok(_) => true,
-                            Err(e) => match e {
-                                UsbHidError::WouldBlock | UsbHidError::UsbError(UsbError::WouldBlock) => false,
-                                UsbHidError::Duplicate => false,
-                                _ => panic!("Unexpected UsbHidError"),
-                            },
-                        }
-                    });
+                    self.consumer_reports.send(|r| usb.write_consumer_report(r));
+                    written
                 });
+                if let Some(seq) = written {
+                    self.latency.on_report_written(seq, Instant::now);
+                }
             } else {
                 self.keyboard_reports.clear();
                 self.consumer_reports.clear();
             }
 
-            // Disable LEDs when entering suspend mode
-            match (prev_usb_state, usb_state) {
-                (UsbDeviceState::Suspend, UsbDeviceState::Suspend) => {},
-                (_, UsbDeviceState::Suspend) => update.brightness = Some(BrightnessUpdate::Disable),
-                (UsbDeviceState::Suspend, _) => update.brightness = Some(BrightnessUpdate::Enable),
-                _ => {},
+            LedsUpdate::Controller(update)
+        }
+    }
+
+    /// Periodic mouse emulation processing
+    ///
+    /// Runs independently of [`Self::tick`] so that mouse reports can be generated at their own
+    /// rate. `period` is the number of ticks since the previous call and is used to scale the
+    /// accumulated movement, so speeds from configuration do not depend on the report rate.
+    pub fn mouse_tick(&mut self, period: u32, mut usb: impl Mutex<T = impl UsbHost>) {
+        if self.fsm.role() != Role::Master {
+            return;
+        }
+
+        // Advance mouse emulation time
+        self.mouse.tick(period.try_into().unwrap_or(u16::MAX));
+
+        usb.lock(|usb| {
+            if usb.state() != UsbDeviceState::Configured {
+                return;
             }
+            // Try to push USB mouse report
+            self.mouse.push_report(|r| {
+                match usb.write_mouse_report(r) {
+                    Ok(_) => true,
+                    Err(e) => match e {
+                        UsbHidError::WouldBlock | UsbHidError::UsbError(UsbError::WouldBlock) => false,
+                        UsbHidError::Duplicate => false,
+                        _ => panic!("Unexpected UsbHidError"),
+                    },
+                }
+            });
+        });
+    }
 
-            LedsUpdate::Controller(update)
+    /// Handle key event received from the other half
+    fn remote_key_event(&mut self, event: Event) {
+        match event {
+            Event::Press(i, j) => defmt::info!("Got KeyPress({=u8}, {=u8})", i, j),
+            Event::Release(i, j) => defmt::info!("Got KeyRelease({=u8}, {=u8})", i, j),
+        }
+        self.event_log.push(self.time, event);
+        // Update pressed keys for the other half
+        self.pressed[self.keys.side().other()]
+            .update_keys_on_event(event.transform(|i, j| BoardSide::coords_to_local((i, j))));
+        // Only master uses key events from the other half
+        if self.fsm.role() == Role::Master {
+            Self::test_key(&self.tester, &mut self.typist, event);
+            self.layout_event(event);
+        }
+    }
+
+    /// Pass key event to layout through accessibility filters (master only)
+    fn layout_event(&mut self, event: Event) {
+        let event = self.bounce_keys.event(event).and_then(|e| self.slow_keys.event(e));
+        if let Some(event) = event {
+            self.resolve_event(event);
+        }
+    }
+
+    /// Pass key event that went through accessibility filters to the layout
+    fn resolve_event(&mut self, event: Event) {
+        let layer = self.layout.current_layer();
+        if let Event::Press(i, j) = event {
+            if self.trainer.is_enabled() && !self.explainer.is_armed() {
+                let (_, action) = self.explainer.resolve((i, j), layer);
+                self.trainer.on_press((i, j), layer, action);
+            }
+        }
+        let event = match self.explainer.event(event, layer) {
+            explain::Explained::Pass(event) => event,
+            explain::Explained::Press(explanation) => {
+                let (i, j) = explanation.coord;
+                defmt::info!("Key ({=u8}, {=u8}) resolved on layer {=usize} (current {=usize})",
+                    i, j, explanation.resolved, explanation.layer);
+                if let Some(text) = self.typist.text() {
+                    // Text is truncated if it does not fit, which is still useful
+                    explanation.write_text(text).ok();
+                }
+                return;
+            },
+            explain::Explained::Release => return,
+        };
+        if let Event::Press(i, j) = event {
+            self.key_stats.on_press((i, j));
+        }
+        if let Some(event) = self.game_mode.event(event, layer) {
+            if self.hold_tap_trace.key().is_some() {
+                let action = hold_tap::layer_action(self.keymap.layers(), layer, event.coord());
+                self.hold_tap_trace.event(event, action, layer, self.time);
+            }
+            self.layout.event(event);
+        }
+    }
+
+    /// Handle key event in key tester mode (master only)
+    fn test_key(tester: &Option<tester::KeyTester>, typist: &mut typing::Typist, event: Event) {
+        if let Some(text) = tester.as_ref().and_then(|t| t.on_event(event)) {
+            if !typist.append(&text) {
+                defmt::warn!("Key tester: typing buffer full");
+            }
+        }
+    }
+
+    /// Select brightness preset and preserve it over system reset
+    fn select_brightness_preset(&mut self, preset: Option<u8>) {
+        if preset != self.brightness_preset {
+            defmt::info!("Brightness preset: {}", preset);
+            self.brightness_preset = preset;
+            leds::preset::store(preset);
+        }
+    }
+
+    /// Restore brightness preset from before reset, returns the brightness to be applied
+    pub fn restore_brightness_preset(&mut self, preset: Option<u8>) -> Option<u8> {
+        let percent = *self.brightness_presets.get(preset? as usize)?;
+        self.brightness_preset = preset;
+        Some(leds::preset::brightness(percent))
+    }
+
+    fn toggle_key_tester(&mut self, typing: bool) {
+        self.tester = match self.tester {
+            Some(_) => None,
+            None => Some(tester::KeyTester::new(typing)),
+        };
+        defmt::info!("Key tester: {=bool} (typing: {=bool})", self.tester.is_some(), typing);
+    }
+
+    fn raw_hid_command(&mut self, report: &hid::RawReport) {
+        if report[0] == diagnostics::CMD_STATS {
+            match diagnostics::StatsRequest::from_report(report) {
+                Ok(request) => self.stats_request = Some(request),
+                Err(e) => defmt::warn!("Invalid stats request: {}", e),
+            }
+            return;
+        }
+        if report[0] == diagnostics::CMD_LED_COLORS {
+            match diagnostics::ColorsRequest::from_report(report) {
+                Ok(request) => self.colors_request = Some(request),
+                Err(e) => defmt::warn!("Invalid LED colors request: {}", e),
+            }
+            return;
+        }
+        if report[0] == diagnostics::CMD_PANIC {
+            match diagnostics::PanicRequest::from_report(report) {
+                Ok(request) => self.panic_request = Some(request),
+                Err(e) => defmt::warn!("Invalid panic report request: {}", e),
+            }
+            return;
+        }
+        if report[0] == diagnostics::CMD_PRESCALER {
+            match diagnostics::PrescalerRequest::from_report(report) {
+                Ok(request) => self.prescaler_request = Some(request),
+                Err(e) => defmt::warn!("Invalid prescaler request: {}", e),
+            }
+            return;
+        }
+        if report[0] == leds::CMD_LED_OVERRIDE {
+            match leds::OverrideRequest::from_report(report) {
+                // Keep the order of requests, new ones are dropped if LEDs have not been updated yet
+                Ok(request) => if self.led_overrides.push(request).is_err() {
+                    defmt::warn!("LED override dropped, too many pending");
+                },
+                Err(e) => defmt::warn!("Invalid LED override request: {}", e),
+            }
+            return;
+        }
+        if report[0] == keymap::CMD_KEYMAP {
+            match keymap::Request::from_report(report, L) {
+                Ok(request) => {
+                    let idle = self.pressed.left.is_none() && self.pressed.right.is_none()
+                        && self.layout.keycodes().next().is_none();
+                    let response = if idle && request.modifies() && self.keymap.is_enabled() {
+                        // SAFETY: users of the layers are switched away in modify_keymap
+                        self.modify_keymap(|keymap| unsafe { keymap.handle(request, idle) })
+                    } else {
+                        // SAFETY: layers are not modified by queries or when keys are held
+                        unsafe { self.keymap.handle(request, idle) }
+                    };
+                    self.keymap_response = Some(response);
+                },
+                Err(e) => defmt::warn!("Invalid keymap request: {}", e),
+            }
+            return;
+        }
+        match leds::Notification::from_report(report, self.tick_rate) {
+            Ok(notification) => {
+                // Keep the latest ones if LEDs have not been updated in the meantime
+                if self.notifications.is_full() {
+                    self.notifications.remove(0);
+                }
+                self.notifications.push(notification).ok();
+            },
+            Err(e) => defmt::warn!("Invalid raw HID report: {}", e),
         }
     }
 
     /// Set new joystick reading values
     pub fn update_joystick(&mut self, xy: (i16, i16)) {
-        self.mouse.update_joystick(xy);
+        if self.joystick_enabled {
+            self.mouse.update_joystick(xy);
+        }
+    }
+
+    /// Distance from calibrated zero at which joystick movement should wake up the keyboard, per axis
+    pub fn joystick_wake_deadzone(&self) -> (u16, u16) {
+        self.mouse.joystick_wake_deadzone()
+    }
+
+    /// Joystick left its dead zone while it was not being sampled (e.g. in deep sleep)
+    pub fn joystick_wake(&mut self) {
+        if self.joystick_enabled {
+            defmt::info!("Joystick wake up");
+            self.joystick_wake = true;
+        }
     }
 }
 
@@ -349,26 +1348,106 @@ impl LedControllerUpdate {
     const BRIGHTNESS_LEVELS: u8 = 8;
     const BRIGHTNESS_INC: u8 = u8::MAX / Self::BRIGHTNESS_LEVELS;
 
-    /// Perform LED controller update
-    pub fn apply(self, time: u32, leds: &mut LedController) {
+    fn from_other(
+        state: Option<leds::ControllerState>,
+        overlays: Option<leds::OverlaysState>,
+        local_pressed: Option<PressedKeys>,
+        remote_latency: u32,
+        key_actions: Option<(u8, KeyActionCache)>,
+    ) -> Self {
+        Self {
+            state: None,
+            config: None,
+            brightness: None,
+            brightness_value: None,
+            brightness_boost: None,
+            power: None,
+            clear_overrides: false,
+            toggle_night_mode: false,
+            local_only: true,
+            remote: state,
+            remote_latency,
+            overlays,
+            local_pressed,
+            key_actions,
+        }
+    }
+
+    /// Perform LED controller and output update
+    pub fn apply(self, time: u32, leds: &mut LedController, output: &mut LedOutput) {
+        leds.set_local_only(self.local_only);
+        if let Some((layer, actions)) = self.key_actions {
+            leds.set_key_actions(layer, actions);
+        }
+        if self.remote.is_some() || self.overlays.is_some() {
+            // Slave follows master state and plays its overlays
+            if let Some(state) = self.remote {
+                leds.set_controller_state(time, state, self.remote_latency);
+            }
+            if let Some(overlays) = self.overlays {
+                output.sync_overlays(time, leds.side(), overlays);
+            }
+            if let Some(pressed) = self.local_pressed {
+                output.set_local_pressed(pressed);
+            }
+            return;
+        }
+        if self.clear_overrides {
+            output.clear_overrides();
+        }
         if let Some(inc) = self.config {
             leds.cycle_config(inc);
         }
         if let Some(inc) = self.brightness {
             let new = match inc {
-                BrightnessUpdate::Up => leds.brightness().saturating_add(Self::BRIGHTNESS_INC),
-                BrightnessUpdate::Down => leds.brightness().saturating_sub(Self::BRIGHTNESS_INC),
-                BrightnessUpdate::Disable => 0,
-                BrightnessUpdate::Enable => LedController::INITIAL_BRIGHTNESS,
+                Inc::Up => leds.brightness().saturating_add(Self::BRIGHTNESS_INC),
+                Inc::Down => leds.brightness().saturating_sub(Self::BRIGHTNESS_INC),
             };
             leds.set_brightness(new);
         }
+        if let Some(brightness) = self.brightness_value {
+            leds.set_brightness(brightness);
+        }
+        if let Some(boost) = self.brightness_boost {
+            leds.set_brightness_boost(boost);
+        }
+        if let Some(power) = self.power {
+            leds.set_power_state(power);
+        }
+        if self.toggle_night_mode {
+            let enabled = !leds.night_mode();
+            defmt::info!("Night mode: {}", enabled);
+            leds.set_night_mode(enabled);
+            leds::night::store(enabled);
+        }
         leds.update_patterns(time, self.state);
     }
 
     /// Determine this update is meaningful (there is any change)
     pub fn any_change(&self) -> bool {
          self.state.is_some() || self.config.is_some() || self.brightness.is_some()
+             || self.brightness_value.is_some() || self.brightness_boost.is_some() || self.power.is_some()
+             || self.clear_overrides || self.toggle_night_mode || self.remote.is_some()
+             || self.overlays.is_some() || self.key_actions.is_some()
+    }
+}
+
+impl Prescalers {
+    /// Check if a task with given prescaler should run in tick `t`
+    ///
+    /// The offset is used to avoid running all tasks in the same tick.
+    pub fn is_due(t: u32, prescaler: u32, offset: u32) -> bool {
+        prescaler != 0 && t % prescaler == offset % prescaler
+    }
+
+    /// Prescaler of given task
+    pub fn get_mut(&mut self, task: PrescalerTask) -> &mut u32 {
+        match task {
+            PrescalerTask::Leds => &mut self.leds,
+            PrescalerTask::Joystick => &mut self.joystick,
+            PrescalerTask::Mouse => &mut self.mouse,
+            PrescalerTask::Debug => &mut self.debug,
+        }
     }
 }
 
@@ -387,3 +1466,17 @@ impl<T> CustomEventExt<T> for CustomEvent<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescaler_due_ticks() {
+        let due: std::vec::Vec<_> = (0..30).filter(|t| Prescalers::is_due(*t, 10, 2)).collect();
+        assert_eq!(due, [2, 12, 22]);
+        // Offset larger than prescaler must not disable the task
+        assert!((0..30).all(|t| Prescalers::is_due(t, 1, 3)));
+        assert!((0..30).all(|t| !Prescalers::is_due(t, 0, 3)));
+    }
+}
@@ -1,7 +1,8 @@
 use bitfield::bitfield;
 
-use super::actions::{MouseAction, MouseButton, MouseMovement};
+use super::actions::{Action, MouseAction, MouseButton, MouseMovement, SpeedTier, Inc};
 use super::hid::MouseReport;
+use super::ticks::TickRate;
 
 /// USB mouse emulation
 pub struct Mouse {
@@ -10,6 +11,19 @@ pub struct Mouse {
     xy: PlaneAccumulator<'static>,
     scroll: PlaneAccumulator<'static>,
     joystick: Joystick<'static>,
+    precision: bool,
+    precision_scale: u8,
+    angle_snap: u8,
+    /// Bitmask of held speed tier keys, see [`SpeedTier::mask`]
+    tiers_held: u8,
+    tiers: &'static SpeedTiers,
+    scroll_interval: u16,
+    /// Ticks since the last report with scroll movement
+    since_scroll: u16,
+    dwell: Dwell<'static>,
+    flick: Flick<'static>,
+    /// Joystick plane changed since last [`Mouse::take_plane_change`]
+    plane_changed: bool,
 }
 
 /// Speed profiles for mouse emulation
@@ -19,6 +33,78 @@ pub struct MouseConfig {
     pub wheel: AxisConfig,
     pub pan: AxisConfig,
     pub joystick: JoystickConfig,
+    /// Output scale in percent applied while precision mode key is held
+    pub precision_scale: u8,
+    /// Pointer movement snaps to the exact axis if the speed along the other axis is at most this
+    /// percentage of it (18% is about 10 degrees), 0 disables snapping
+    pub angle_snap: u8,
+    /// Pointer speeds used while speed tier keys are held
+    pub speed_tiers: SpeedTiers,
+    /// Emission of accumulated wheel values
+    pub scroll: ScrollConfig,
+    /// Automatic clicking when joystick pointer stops
+    pub dwell: DwellConfig,
+    /// Actions triggered by quick joystick flicks
+    pub flick: FlickConfig,
+}
+
+/// Scroll quantization and rate limiting
+///
+/// Hosts differ in how they treat large wheel values, so limiting lines per report and
+/// report rate makes scrolling feel similar everywhere. Values accumulated between reports
+/// are summed and the part exceeding the limit is dropped.
+pub struct ScrollConfig {
+    /// Maximum wheel value in a single report, 0 means no limit
+    pub max_lines: u8,
+    /// Minimum number of ticks between reports with wheel movement
+    pub min_interval: u16,
+}
+
+/// Speed profiles of pointer movement keys selected at runtime
+///
+/// Similar to QMK mouse key accelerations: while [`MouseAction::SpeedTier`] key is held, X and Y
+/// use the profile of its tier instead of the ones from [`AxisConfig`]. Usually these are
+/// constant speed profiles. If multiple tier keys are held then the slowest one is used.
+/// This is independent of precision mode, which scales the output of any profile.
+pub struct SpeedTiers {
+    pub slow: &'static SpeedProfile,
+    pub medium: &'static SpeedProfile,
+    pub fast: &'static SpeedProfile,
+}
+
+/// Dwell clicking configuration
+///
+/// Meant for users who cannot easily press keys while holding the joystick. Enabled at
+/// runtime with [`MouseAction::DwellClick`].
+pub struct DwellConfig {
+    /// Time from when the joystick pointer stops moving to the left click in milliseconds
+    pub delay_ms: u16,
+    /// Key (global coordinates) with the LED showing the remaining time
+    pub indicator: (u8, u8),
+}
+
+/// Joystick flick gestures
+///
+/// A flick is a quick tilt of the joystick: it leaves the rest position, reaches `threshold`
+/// within `rise_time` and returns to rest within `max_duration`. Slow deflections and held
+/// tilts used for pointer movement are ignored, and `cooldown` filters out the stick bouncing
+/// back through the center. Action mapped to the direction is pressed and released like a
+/// key tap. Joystick movement is not reported while a possible flick is in progress, so fast
+/// movements of the pointer start with a delay of up to `rise_time`.
+pub struct FlickConfig {
+    /// Deflection (after drift compensation) that has to be reached, 0 disables gestures
+    pub threshold: u16,
+    /// Maximum time from leaving the rest position to reaching `threshold`, in ticks
+    pub rise_time: u16,
+    /// Maximum time from leaving the rest position to returning to it, in ticks
+    pub max_duration: u16,
+    /// Minimum time between two gestures, in ticks
+    pub cooldown: u16,
+    /// Key code, key chord (`MultipleKeyCodes`) or custom action, other actions are ignored
+    pub left: Option<&'static keyberon::action::Action<Action>>,
+    pub right: Option<&'static keyberon::action::Action<Action>>,
+    pub up: Option<&'static keyberon::action::Action<Action>>,
+    pub down: Option<&'static keyberon::action::Action<Action>>,
 }
 
 /// Configuration for single movement axis
@@ -62,30 +148,105 @@ pub struct JoystickConfig {
     pub invert_y: bool,
 }
 
+/// Number of fractional bits of estimated joystick center
+const CENTER_FRAC_BITS: u32 = 8;
+/// Joystick center moving average weight is 1/2^N
+const CENTER_AVG_SHIFT: u32 = 8;
+
 /// Joystick data
 struct Joystick<'a> {
     x: i16,
     y: i16,
+    /// Estimated resting position of raw readings used for drift compensation (fixed point)
+    center: (i32, i32),
     x_acc: DivAccumulator,
     y_acc: DivAccumulator,
-    // TODO: set from joystick config, change via custom actions
+    // TODO: set initial plane from joystick config
     plane: Plane,
     config: &'a JoystickConfig,
 }
 
-// Movement plane
-enum Plane {
+/// Movement plane controlled by the joystick
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Plane {
+    /// Pointer movement
     Xy,
-    #[allow(dead_code)]
+    /// Wheel and pan
     Scroll,
 }
 
+/// Left click emitted after the joystick pointer rests for configured time
+///
+/// Only pointer movement arms the click, so there is a single click after each movement
+/// and the pointer at rest does not generate further clicks.
+struct Dwell<'a> {
+    enabled: bool,
+    /// Ticks since the pointer stopped, `None` when not armed
+    still: Option<u32>,
+    /// Ticks from when the pointer stops to the click
+    delay: u32,
+    /// Pointer was moving on the last tick
+    moving: bool,
+    /// Number of times the pointer stopped, distinguishes consecutive countdowns
+    stops: u8,
+    /// Click waiting to be sent in the next report
+    click: bool,
+    config: &'a DwellConfig,
+}
+
+/// State of a pending dwell click shown on the indicator LED, see [`Mouse::dwell_indicator`]
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct DwellIndicator {
+    /// Key (global coordinates) with the indicator LED
+    pub key: (u8, u8),
+    /// Changes with each new countdown
+    pub count: u8,
+    /// Ticks until the click, `None` while the pointer is moving
+    pub remaining: Option<u32>,
+}
+
+/// Detection of joystick flick gestures, see [`FlickConfig`]
+struct Flick<'a> {
+    state: FlickState,
+    /// Ticks left until the next gesture can be detected
+    cooldown: u16,
+    /// Gesture waiting to be taken
+    detected: Option<FlickDirection>,
+    config: &'a FlickConfig,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+enum FlickState {
+    Rest,
+    /// Joystick left the rest position given number of ticks ago
+    Rising(u16),
+    /// Threshold reached in given direction, ticks since leaving rest
+    Tilted(FlickDirection, u16),
+    /// Movement is not a flick, wait until the joystick is back at rest
+    Ignored,
+}
+
+/// Direction of a joystick flick, in pointer coordinates
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum FlickDirection {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
 /// Movement emulation on a 2D plane
 struct PlaneAccumulator<'a> {
     x: AxisAccumulator<'a>,
     y: AxisAccumulator<'a>,
     x_config: &'a AxisConfig,
     y_config: &'a AxisConfig,
+    /// Maximum absolute value on each axis per report
+    limit: Option<i8>,
 }
 
 /// Movement emulation along single axis
@@ -102,6 +263,11 @@ bitfield! {
     pub left, set_left: 0;
     pub right, set_right: 1;
     pub mid, set_mid: 2;
+    pub back, set_back: 3;
+    pub forward, set_forward: 4;
+    pub button6, set_button6: 5;
+    pub button7, set_button7: 6;
+    pub button8, set_button8: 7;
 }
 
 bitfield! {
@@ -120,13 +286,23 @@ bitfield! {
 
 impl Mouse {
     /// Instantiate with given speed profiles
-    pub const fn new(config: &'static MouseConfig) -> Self {
+    pub const fn new(config: &'static MouseConfig, tick_rate: TickRate) -> Self {
         Self {
             buttons: MouseButtons(0),
             movement: MovementButtons(0),
-            xy: PlaneAccumulator::new(&config.x, &config.y),
-            scroll: PlaneAccumulator::new(&config.pan, &config.wheel),
+            xy: PlaneAccumulator::new(&config.x, &config.y, 0),
+            scroll: PlaneAccumulator::new(&config.pan, &config.wheel, config.scroll.max_lines),
             joystick: Joystick::new(&config.joystick),
+            precision: false,
+            precision_scale: config.precision_scale,
+            angle_snap: config.angle_snap,
+            tiers_held: 0,
+            tiers: &config.speed_tiers,
+            scroll_interval: config.scroll.min_interval,
+            since_scroll: u16::MAX,
+            dwell: Dwell::new(&config.dwell, tick_rate),
+            flick: Flick::new(&config.flick),
+            plane_changed: false,
         }
     }
 
@@ -138,6 +314,11 @@ impl Mouse {
                     MouseButton::Left => self.buttons.set_left(pressed),
                     MouseButton::Mid => self.buttons.set_mid(pressed),
                     MouseButton::Right => self.buttons.set_right(pressed),
+                    MouseButton::Back => self.buttons.set_back(pressed),
+                    MouseButton::Forward => self.buttons.set_forward(pressed),
+                    MouseButton::Button6 => self.buttons.set_button6(pressed),
+                    MouseButton::Button7 => self.buttons.set_button7(pressed),
+                    MouseButton::Button8 => self.buttons.set_button8(pressed),
                 };
             },
             MouseAction::Move(movement) => match movement {
@@ -152,15 +333,73 @@ impl Mouse {
             },
             // TODO: sensitivity; no need for runtime if we have so much options in config?
             MouseAction::Sensitivity(_) => defmt::warn!("Mouse sensitivity not supported"),
+            MouseAction::JoystickSensitivity(inc) => if pressed {
+                let divider = self.joystick.change_sensitivity(*inc);
+                super::joystick::store_divider(divider);
+            },
+            MouseAction::Precision => self.precision = pressed,
+            MouseAction::DwellClick => if pressed {
+                self.dwell.set_enabled(!self.dwell.enabled);
+            },
+            MouseAction::SpeedTier(tier) => {
+                if pressed {
+                    self.tiers_held |= tier.mask();
+                } else {
+                    self.tiers_held &= !tier.mask();
+                }
+                self.update_speed_tier();
+            },
+            MouseAction::JoystickPlane => if pressed {
+                self.joystick.cycle_plane();
+                self.plane_changed = true;
+            },
         }
     }
 
-    /// Advance time and accumulate state
-    pub fn tick(&mut self) {
+    /// Take action mapped to the joystick flick detected since the last call
+    pub fn take_flick(&mut self) -> Option<&'static keyberon::action::Action<Action>> {
+        let direction = self.flick.detected.take()?;
+        defmt::info!("Joystick flick: {}", direction);
+        self.flick.config.action(direction)
+    }
+
+    /// Get new joystick plane if it has been changed since the last call
+    pub fn take_plane_change(&mut self) -> Option<Plane> {
+        core::mem::take(&mut self.plane_changed).then_some(self.joystick.plane)
+    }
+
+    /// Switch pointer profiles according to the held tier keys
+    fn update_speed_tier(&mut self) {
+        let tier = [SpeedTier::Slow, SpeedTier::Medium, SpeedTier::Fast].into_iter()
+            .find(|tier| self.tiers_held & tier.mask() != 0);
+        let (x, y) = match tier {
+            Some(SpeedTier::Slow) => (self.tiers.slow, self.tiers.slow),
+            Some(SpeedTier::Medium) => (self.tiers.medium, self.tiers.medium),
+            Some(SpeedTier::Fast) => (self.tiers.fast, self.tiers.fast),
+            None => (self.xy.x_config.profile, self.xy.y_config.profile),
+        };
+        self.xy.x.set_profile(x);
+        self.xy.y.set_profile(y);
+    }
+
+    /// Advance time by `dt` ticks and accumulate state
+    ///
+    /// Mouse reports may be generated at a different rate than keyboard ticks, so movement
+    /// is accumulated proportionally to the time elapsed since the previous call.
+    pub fn tick(&mut self, dt: u16) {
         let m = &self.movement;
-        self.xy.tick(m.up(), m.down(), m.left(), m.right());
-        self.scroll.tick(m.wheel_up(), m.wheel_down(), m.pan_left(), m.pan_right());
-        self.joystick.tick();
+        self.xy.tick(m.up(), m.down(), m.left(), m.right(), dt);
+        self.scroll.tick(m.wheel_up(), m.wheel_down(), m.pan_left(), m.pan_right(), dt);
+        self.joystick.tick(dt);
+        self.since_scroll = self.since_scroll.saturating_add(dt);
+        let pointer_moving = self.joystick.active() && matches!(self.joystick.plane, Plane::Xy);
+        self.dwell.tick(pointer_moving, dt);
+        self.flick.tick((self.joystick.x, self.joystick.y), !self.joystick.active(), dt);
+    }
+
+    /// Restore joystick divider changed at runtime, see [`MouseAction::JoystickSensitivity`]
+    pub fn set_joystick_divider(&mut self, divider: u16) {
+        self.joystick.set_divider(divider);
     }
 
     /// Store latest joystick readings
@@ -168,18 +407,62 @@ impl Mouse {
         self.joystick.set(x, y);
     }
 
+    /// Get bitmask of held buttons, see [`MouseButton::mask`]
+    pub fn buttons(&self) -> u8 {
+        self.buttons.0
+    }
+
+    /// Check if joystick is deflected enough to move the cursor
+    pub fn joystick_active(&self) -> bool {
+        self.joystick.active()
+    }
+
+    /// Dead zone for waking up on joystick movement, see [`Joystick::wake_deadzone`]
+    pub fn joystick_wake_deadzone(&self) -> (u16, u16) {
+        self.joystick.wake_deadzone()
+    }
+
+    /// State of pending dwell click
+    ///
+    /// `None` when dwell clicking is disabled or there is no pending click.
+    pub fn dwell_indicator(&self) -> Option<DwellIndicator> {
+        self.dwell.indicator()
+    }
+
+    /// Output scale in percent
+    fn scale(&self) -> u8 {
+        if self.precision { self.precision_scale } else { 100 }
+    }
+
+    /// Scroll values can only be reported after minimum interval from the last scroll report
+    fn scroll_ready(&self) -> bool {
+        self.since_scroll >= self.scroll_interval
+    }
+
+    /// Joystick values if it moves in given plane and is not making a flick
+    fn joystick_values(&self, plane: Plane, scale: u8) -> Option<(i8, i8)> {
+        (self.joystick.active() && self.joystick.plane == plane && !self.flick.in_progress())
+            .then(|| (self.joystick.x_acc.get(scale), self.joystick.y_acc.get(scale)))
+    }
+
     fn get_speeds(&self) -> (i8, i8, i8, i8) {
-        let (mut x, mut y) = self.xy.get();
-        let (mut pan, mut wheel) = self.scroll.get();
-        if self.joystick.active() {
-            let (joy_x, joy_y) = (self.joystick.x_acc.get(), self.joystick.y_acc.get());
-            let (px, py) = match self.joystick.plane {
-                Plane::Xy => (&mut x, &mut y),
-                Plane::Scroll => (&mut pan, &mut wheel),
-            };
-            *px = px.saturating_add(joy_x);
-            *py = py.saturating_add(joy_y);
+        let scale = self.scale();
+        let (mut x, mut y) = self.xy.get(scale);
+        if let Some((joy_x, joy_y)) = self.joystick_values(Plane::Xy, scale) {
+            x = x.saturating_add(joy_x);
+            y = y.saturating_add(joy_y);
         }
+        // Joystick scrolling is subject to the same limits as scrolling with keys
+        let (pan, wheel) = if !self.scroll_ready() {
+            (0, 0)
+        } else if let Some((joy_x, joy_y)) = self.joystick_values(Plane::Scroll, scale) {
+            let (pan, wheel) = self.scroll.get(scale);
+            self.scroll.limit((pan.saturating_add(joy_x), wheel.saturating_add(joy_y)))
+        } else {
+            self.scroll.get(scale)
+        };
+        // Snapped part of movement is consumed as usual, so it is dropped instead of accumulated
+        let (x, y) = snap_to_axis((x, y), self.angle_snap);
         (x, y, pan, wheel)
     }
 
@@ -188,8 +471,9 @@ impl Mouse {
         where F: FnOnce(&MouseReport) -> bool
     {
         let (x, y, pan, wheel) = self.get_speeds();
+        let dwell_click = if self.dwell.click { MouseButton::Left.mask() } else { 0 };
         let report = MouseReport {
-            buttons: self.buttons.0,
+            buttons: self.buttons.0 | dwell_click,
             x,
             y,
             vertical_wheel: wheel,
@@ -197,10 +481,64 @@ impl Mouse {
         };
 
         if push(&report) {
-            self.xy.consume();
-            self.scroll.consume();
-            self.joystick.x_acc.consume();
-            self.joystick.y_acc.consume();
+            let scale = self.scale();
+            self.xy.consume(scale);
+            let scroll_ready = self.scroll_ready();
+            if scroll_ready {
+                if (pan, wheel) != (0, 0) {
+                    self.since_scroll = 0;
+                }
+                self.scroll.consume(scale);
+            }
+            // In scroll plane joystick values accumulate until scrolling is allowed, movement
+            // during a flick is dropped
+            if scroll_ready || self.joystick.plane == Plane::Xy || self.flick.in_progress() {
+                self.joystick.x_acc.consume(scale);
+                self.joystick.y_acc.consume(scale);
+            }
+            // Button is released in the next report
+            self.dwell.click = false;
+        }
+    }
+}
+
+/// Drop movement along the minor axis if it is at most `ratio` percent of the major one
+fn snap_to_axis((x, y): (i8, i8), ratio: u8) -> (i8, i8) {
+    if ratio == 0 {
+        return (x, y);
+    }
+    let (ax, ay) = (x.unsigned_abs() as u16, y.unsigned_abs() as u16);
+    if ay * 100 <= ax * ratio as u16 {
+        (x, 0)
+    } else if ax * 100 <= ay * ratio as u16 {
+        (0, y)
+    } else {
+        (x, y)
+    }
+}
+
+impl MouseButton {
+    /// Bit of this button in HID report
+    pub const fn mask(&self) -> u8 {
+        match self {
+            Self::Left => 1 << 0,
+            Self::Right => 1 << 1,
+            Self::Mid => 1 << 2,
+            Self::Back => 1 << 3,
+            Self::Forward => 1 << 4,
+            Self::Button6 => 1 << 5,
+            Self::Button7 => 1 << 6,
+            Self::Button8 => 1 << 7,
+        }
+    }
+}
+
+impl SpeedTier {
+    const fn mask(&self) -> u8 {
+        match self {
+            Self::Slow => 1 << 0,
+            Self::Medium => 1 << 1,
+            Self::Fast => 1 << 2,
         }
     }
 }
@@ -222,26 +560,146 @@ impl SpeedProfile {
     }
 }
 
+impl<'a> Dwell<'a> {
+    pub const fn new(config: &'a DwellConfig, tick_rate: TickRate) -> Self {
+        let delay = tick_rate.from_ms(config.delay_ms as u32);
+        Self { enabled: false, still: None, delay, moving: false, stops: 0, click: false, config }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        defmt::info!("Dwell click: {=bool}", enabled);
+        self.enabled = enabled;
+        self.still = None;
+        self.click = false;
+    }
+
+    pub fn tick(&mut self, moving: bool, dt: u16) {
+        if !self.enabled {
+            return;
+        }
+        if moving {
+            self.still = Some(0);
+            self.moving = true;
+        } else if let Some(still) = self.still {
+            if self.moving {
+                self.moving = false;
+                self.stops = self.stops.wrapping_add(1);
+            }
+            let still = still.saturating_add(dt as u32);
+            if still >= self.delay {
+                self.still = None;
+                self.click = true;
+            } else {
+                self.still = Some(still);
+            }
+        }
+    }
+
+    pub fn indicator(&self) -> Option<DwellIndicator> {
+        self.still.map(|still| DwellIndicator {
+            key: self.config.indicator,
+            count: self.stops,
+            remaining: (!self.moving).then(|| self.delay.saturating_sub(still)),
+        })
+    }
+}
+
+impl FlickConfig {
+    pub fn action(&self, direction: FlickDirection) -> Option<&'static keyberon::action::Action<Action>> {
+        match direction {
+            FlickDirection::Left => self.left,
+            FlickDirection::Right => self.right,
+            FlickDirection::Up => self.up,
+            FlickDirection::Down => self.down,
+        }
+    }
+}
+
+impl<'a> Flick<'a> {
+    pub const fn new(config: &'a FlickConfig) -> Self {
+        Self { state: FlickState::Rest, cooldown: 0, detected: None, config }
+    }
+
+    /// Joystick movement may still turn out to be a flick
+    pub fn in_progress(&self) -> bool {
+        matches!(self.state, FlickState::Rising(_) | FlickState::Tilted(..))
+    }
+
+    /// Update with joystick position, `rest` means that it is within the dead zone
+    pub fn tick(&mut self, (x, y): (i16, i16), rest: bool, dt: u16) {
+        self.cooldown = self.cooldown.saturating_sub(dt);
+        if self.config.threshold == 0 {
+            return;
+        }
+        self.state = match self.state {
+            FlickState::Tilted(direction, _) if rest => {
+                if self.cooldown == 0 {
+                    self.detected = Some(direction);
+                    self.cooldown = self.config.cooldown;
+                }
+                FlickState::Rest
+            },
+            _ if rest => FlickState::Rest,
+            FlickState::Ignored => FlickState::Ignored,
+            FlickState::Rest => self.rising(0, (x, y)),
+            FlickState::Rising(elapsed) => self.rising(elapsed.saturating_add(dt), (x, y)),
+            FlickState::Tilted(direction, elapsed) => {
+                let elapsed = elapsed.saturating_add(dt);
+                if elapsed > self.config.max_duration {
+                    FlickState::Ignored
+                } else {
+                    FlickState::Tilted(direction, elapsed)
+                }
+            },
+        };
+    }
+
+    fn rising(&self, elapsed: u16, (x, y): (i16, i16)) -> FlickState {
+        let (ax, ay) = (x.unsigned_abs(), y.unsigned_abs());
+        if elapsed > self.config.rise_time {
+            FlickState::Ignored
+        } else if ax.max(ay) < self.config.threshold {
+            FlickState::Rising(elapsed)
+        } else {
+            let direction = match (ax >= ay, x < 0, y < 0) {
+                (true, true, _) => FlickDirection::Left,
+                (true, false, _) => FlickDirection::Right,
+                (false, _, true) => FlickDirection::Up,
+                (false, _, false) => FlickDirection::Down,
+            };
+            FlickState::Tilted(direction, elapsed)
+        }
+    }
+}
+
 impl<'a> PlaneAccumulator<'a> {
-    pub const fn new(x: &'a AxisConfig, y: &'a AxisConfig) -> Self {
+    /// Create plane with given limit of values per report, 0 means no limit
+    pub const fn new(x: &'a AxisConfig, y: &'a AxisConfig, limit: u8) -> Self {
         Self {
             x: AxisAccumulator::new(x.profile),
             y: AxisAccumulator::new(y.profile),
             x_config: x,
             y_config: y,
+            limit: if limit == 0 { None } else { Some(if limit > i8::MAX as u8 { i8::MAX } else { limit as i8 }) },
         }
     }
 
-    pub fn tick(&mut self, up: bool, down: bool, left: bool, right: bool) {
+    pub fn tick(&mut self, up: bool, down: bool, left: bool, right: bool, dt: u16) {
         let reset = !(up || down || left || right);
         let dir_x = Self::direction(right, left, self.x_config.invert);
         let dir_y = Self::direction(down, up, self.y_config.invert);
-        self.x.tick(reset, dir_x);
-        self.y.tick(reset, dir_y);
+        self.x.tick(reset, dir_x, dt);
+        self.y.tick(reset, dir_y, dt);
     }
 
-    pub fn get(&self) -> (i8, i8) {
-        let (x, y) = (self.x.accumulated.get(), self.y.accumulated.get());
+    /// Clamp values to the limit per report
+    pub fn limit(&self, (x, y): (i8, i8)) -> (i8, i8) {
+        let limit = |v: i8| self.limit.map_or(v, |l| v.clamp(-l, l));
+        (limit(x), limit(y))
+    }
+
+    pub fn get(&self, scale: u8) -> (i8, i8) {
+        let (x, y) = self.limit((self.x.accumulated.get(scale), self.y.accumulated.get(scale)));
         // Generate 2D speed value if we are moving in both directions
         if x != 0 && y != 0 {
             (Self::mul_inv_sqrt2(x), Self::mul_inv_sqrt2(y))
@@ -250,9 +708,10 @@ impl<'a> PlaneAccumulator<'a> {
         }
     }
 
-    pub fn consume(&mut self) {
-        self.x.accumulated.consume();
-        self.y.accumulated.consume();
+    /// Consume reported values, the part exceeding limit is dropped
+    pub fn consume(&mut self, scale: u8) {
+        self.x.accumulated.consume(scale);
+        self.y.accumulated.consume(scale);
     }
 
     /// Calculate x * sqrt(2) (181/256=0.70703125 vs 1/sqrt(2)=0.707106781)
@@ -279,6 +738,9 @@ impl<'a> PlaneAccumulator<'a> {
 }
 
 /// Accumulate values to read at lower resolution depending on divider.
+///
+/// Values are read with additional scale in percent. Consuming only subtracts the part
+/// that has been read, so small movements are not lost even with low scale.
 struct DivAccumulator {
     value: i32,
     divider: u16,
@@ -293,13 +755,17 @@ impl DivAccumulator {
         self.value = self.value.saturating_add(value);
     }
 
-    pub fn get(&self) -> i8 {
-        (self.value / self.div())
-            .clamp(i8::MIN as i32, i8::MAX as i32) as i8
+    pub fn get(&self, scale: u8) -> i8 {
+        (self.value as i64 * scale as i64 / (self.div() as i64 * 100))
+            .clamp(i8::MIN as i64, i8::MAX as i64) as i8
     }
 
-    pub fn consume(&mut self) {
-        let rounded = self.get() as i32 * self.div();
+    pub fn consume(&mut self, scale: u8) {
+        // Scale 0 blocks all output, keep accumulating until scale changes
+        if scale == 0 {
+            return;
+        }
+        let rounded = (self.get(scale) as i64 * self.div() as i64 * 100 / scale as i64) as i32;
         // Avoid loosing small accumulated values by only subtracting the consumed value
         if rounded.abs() > self.value.abs() {
             self.value = 0;
@@ -320,16 +786,29 @@ impl<'a> AxisAccumulator<'a> {
         Self { profile, time: 0, accumulated: DivAccumulator::new(profile.divider) }
     }
 
-    pub fn tick(&mut self, reset: bool, dir: i32) {
+    /// Change profile keeping the time since key press, so acceleration continues
+    pub fn set_profile(&mut self, profile: &'a SpeedProfile) {
+        if core::ptr::eq(self.profile, profile) {
+            return;
+        }
+        // Rescale the accumulated value so that the pending movement stays the same
+        let (old, new) = (self.accumulated.div(), profile.divider.max(1) as i32);
+        let value = self.accumulated.value as i64 * new as i64 / old as i64;
+        self.accumulated.value = value.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        self.accumulated.divider = profile.divider;
+        self.profile = profile;
+    }
+
+    pub fn tick(&mut self, reset: bool, dir: i32, dt: u16) {
         if reset {
             self.time = 0;
         }
 
-        // Accumulate
+        // Accumulate distance travelled during `dt` at current speed
         let speed = dir * self.profile.get_speed(self.time) as i32;
-        self.accumulated.accumulate(speed);
+        self.accumulated.accumulate(speed.saturating_mul(dt as i32));
 
-        self.time = self.time.saturating_add(1);
+        self.time = self.time.saturating_add(dt);
     }
 }
 
@@ -338,6 +817,7 @@ impl<'a> Joystick<'a> {
         Self {
             x: 0,
             y: 0,
+            center: (0, 0),
             x_acc: DivAccumulator::new(config.divider),
             y_acc: DivAccumulator::new(config.divider),
             plane: Plane::Xy,
@@ -349,7 +829,20 @@ impl<'a> Joystick<'a> {
         self.x.unsigned_abs() >= self.config.min || self.y.unsigned_abs() >= self.config.min
     }
 
+    /// Distance of raw readings from calibrated zero that surely counts as deflection
+    ///
+    /// Takes drift of the resting position into account, so that drift alone does not wake up.
+    /// Returned separately for each axis of raw readings (before swapping/inversion).
+    pub fn wake_deadzone(&self) -> (u16, u16) {
+        let deadzone = |c: i32| {
+            let drift = (c >> CENTER_FRAC_BITS).unsigned_abs().min(u16::MAX as u32) as u16;
+            self.config.min.saturating_add(drift)
+        };
+        (deadzone(self.center.0), deadzone(self.center.1))
+    }
+
     pub fn set(&mut self, x: i16, y: i16) {
+        let (x, y) = self.compensate_drift(x, y);
         let x = if self.config.invert_x { -x } else { x };
         let y = if self.config.invert_y { -y } else { y };
         let (x, y) = if self.config.swap_axes {
@@ -361,12 +854,59 @@ impl<'a> Joystick<'a> {
         self.y = y;
     }
 
-    pub fn tick(&mut self) {
+    /// Subtract estimated center from raw readings
+    ///
+    /// Center is tracked with a slow moving average of readings that are within the dead zone,
+    /// so gradual drift of the resting position is compensated without manual calibration.
+    fn compensate_drift(&mut self, x: i16, y: i16) -> (i16, i16) {
+        let center = |c: i32| c >> CENTER_FRAC_BITS;
+        let compensated = |v: i16, c: i32| (v as i32 - center(c)).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let (dx, dy) = (compensated(x, self.center.0), compensated(y, self.center.1));
+        let resting = dx.unsigned_abs() < self.config.min && dy.unsigned_abs() < self.config.min;
+        if resting {
+            let avg = |c: &mut i32, v: i16| *c += (((v as i32) << CENTER_FRAC_BITS) - *c) >> CENTER_AVG_SHIFT;
+            avg(&mut self.center.0, x);
+            avg(&mut self.center.1, y);
+        }
+        (dx, dy)
+    }
+
+    /// Switch to the next movement plane
+    pub fn cycle_plane(&mut self) {
+        self.plane = match self.plane {
+            Plane::Xy => Plane::Scroll,
+            Plane::Scroll => Plane::Xy,
+        };
+        defmt::info!("Joystick plane: {}", self.plane);
+    }
+
+    /// Change divider by about 25% per step, higher sensitivity means lower divider
+    ///
+    /// Returns the new divider.
+    pub fn change_sensitivity(&mut self, inc: Inc) -> u16 {
+        let divider = self.x_acc.divider.max(1);
+        let step = (divider / 4).max(1);
+        let divider = match inc {
+            Inc::Up => divider.saturating_sub(step).max(1),
+            Inc::Down => divider.saturating_add(step),
+        };
+        defmt::info!("Joystick divider: {=u16}", divider);
+        self.set_divider(divider);
+        divider
+    }
+
+    /// Override divider from configuration
+    pub fn set_divider(&mut self, divider: u16) {
+        self.x_acc.divider = divider.max(1);
+        self.y_acc.divider = divider.max(1);
+    }
+
+    pub fn tick(&mut self, dt: u16) {
         if !self.active() {
             return
         }
         let clamped = |val: i16| {
-            (val.signum() * (val.unsigned_abs().min(self.config.max)) as i16) as i32
+            (val.signum() * (val.unsigned_abs().min(self.config.max)) as i16) as i32 * dt as i32
         };
         self.x_acc.accumulate(clamped(self.x));
         self.y_acc.accumulate(clamped(self.y));
@@ -375,8 +915,426 @@ impl<'a> Joystick<'a> {
 
 #[cfg(test)]
 mod tests {
+    use keyberon::action::Action as KeyAction;
+    use keyberon::key_code::KeyCode;
     use super::*;
 
+    const RATE: TickRate = TickRate::new(1000);
+
+    #[test]
+    fn joystick_sensitivity() {
+        let config = JoystickConfig { min: 0, max: 100, divider: 100, swap_axes: false, invert_x: false, invert_y: false };
+        let mut joy = Joystick::new(&config);
+        assert_eq!(joy.change_sensitivity(Inc::Up), 75);
+        assert_eq!((joy.x_acc.divider, joy.y_acc.divider), (75, 75));
+        joy.change_sensitivity(Inc::Down);
+        assert_eq!(joy.x_acc.divider, 93);
+        for _ in 0..50 {
+            joy.change_sensitivity(Inc::Up);
+        }
+        assert_eq!(joy.x_acc.divider, 1);
+        joy.change_sensitivity(Inc::Down);
+        assert_eq!(joy.x_acc.divider, 2);
+        joy.set_divider(0);
+        assert_eq!((joy.x_acc.divider, joy.y_acc.divider), (1, 1));
+    }
+
+    #[test]
+    fn joystick_drift_compensation() {
+        let config = JoystickConfig { min: 100, max: 1000, divider: 100, swap_axes: false, invert_x: false, invert_y: false };
+        let mut joy = Joystick::new(&config);
+        // Resting position slowly converges to the drifted center
+        for _ in 0..2000 {
+            joy.set(80, -60);
+        }
+        assert!(joy.x.abs() <= 1 && joy.y.abs() <= 1, "{} {}", joy.x, joy.y);
+        // Deflection is relative to the estimated center and does not move it
+        let center = joy.center;
+        joy.set(580, -60);
+        assert!((joy.x - 500).abs() <= 1);
+        assert_eq!(joy.center, center);
+        // Wake up threshold includes the drift
+        let (x, y) = joy.wake_deadzone();
+        assert!((179..=181).contains(&x) && (159..=161).contains(&y), "{} {}", x, y);
+    }
+
+    #[test]
+    fn extended_buttons_in_report() {
+        let mut mouse = Mouse::new(crate::config::CONFIG.mouse, RATE);
+        mouse.handle_action(&MouseAction::Click(MouseButton::Back), true);
+        mouse.handle_action(&MouseAction::Click(MouseButton::Button8), true);
+        mouse.handle_action(&MouseAction::Click(MouseButton::Left), true);
+        mouse.handle_action(&MouseAction::Click(MouseButton::Left), false);
+        let mut buttons = None;
+        mouse.push_report(|r| {
+            buttons = Some(r.buttons);
+            true
+        });
+        assert_eq!(buttons, Some(0b1000_1000));
+        assert_eq!(mouse.buttons(), MouseButton::Back.mask() | MouseButton::Button8.mask());
+    }
+
+    #[test]
+    fn precision_mode() {
+        let mut acc = DivAccumulator::new(10);
+        acc.accumulate(100);
+        assert_eq!(acc.get(100), 10);
+        assert_eq!(acc.get(25), 2);
+        acc.consume(25);
+        // Remainder is kept for next reports
+        assert_eq!(acc.value, 100 - 80);
+        assert_eq!(acc.get(25), 0);
+        acc.accumulate(20);
+        assert_eq!(acc.get(25), 1);
+        acc.consume(25);
+        assert_eq!(acc.value, 0);
+    }
+
+    #[test]
+    fn precision_action() {
+        let mut mouse = Mouse::new(crate::config::CONFIG.mouse, RATE);
+        assert_eq!(mouse.scale(), 100);
+        mouse.handle_action(&MouseAction::Precision, true);
+        assert_eq!(mouse.scale(), crate::config::CONFIG.mouse.precision_scale);
+        mouse.handle_action(&MouseAction::Precision, false);
+        assert_eq!(mouse.scale(), 100);
+    }
+
+    #[test]
+    fn scroll_limits() {
+        const PROFILE: SpeedProfile = SpeedProfile {
+            divider: 1,
+            delay: 0,
+            acceleration_time: 0,
+            start_speed: 5,
+            max_speed: 5,
+        };
+        const AXIS: AxisConfig = AxisConfig { invert: false, profile: &PROFILE };
+        static CONFIG: MouseConfig = MouseConfig {
+            x: AXIS,
+            y: AXIS,
+            wheel: AXIS,
+            pan: AXIS,
+            joystick: JoystickConfig { min: 1, max: 100, divider: 100, swap_axes: false, invert_x: false, invert_y: false },
+            precision_scale: 100,
+            angle_snap: 0,
+            speed_tiers: SpeedTiers { slow: &PROFILE, medium: &PROFILE, fast: &PROFILE },
+            scroll: ScrollConfig { max_lines: 2, min_interval: 3 },
+            dwell: DwellConfig { delay_ms: 4, indicator: (0, 0) },
+            flick: NO_FLICK,
+        };
+        let mut mouse = Mouse::new(&CONFIG, RATE);
+        mouse.handle_action(&MouseAction::Move(MouseMovement::WheelDown), true);
+        let mut wheel = std::vec::Vec::new();
+        for _ in 0..6 {
+            mouse.tick(1);
+            mouse.push_report(|r| {
+                wheel.push(r.vertical_wheel);
+                true
+            });
+        }
+        assert_eq!(wheel, [2, 0, 0, 2, 0, 0]);
+        // Values are not consumed if report could not be sent
+        mouse.tick(1);
+        mouse.push_report(|_| false);
+        mouse.push_report(|r| {
+            assert_eq!(r.vertical_wheel, 2);
+            true
+        });
+    }
+
+    #[test]
+    fn joystick_scroll_limits() {
+        const PROFILE: SpeedProfile = SpeedProfile {
+            divider: 1,
+            delay: 0,
+            acceleration_time: 0,
+            start_speed: 5,
+            max_speed: 5,
+        };
+        const AXIS: AxisConfig = AxisConfig { invert: false, profile: &PROFILE };
+        static CONFIG: MouseConfig = MouseConfig {
+            x: AXIS,
+            y: AXIS,
+            wheel: AXIS,
+            pan: AXIS,
+            joystick: JoystickConfig { min: 1, max: 100, divider: 10, swap_axes: false, invert_x: false, invert_y: false },
+            precision_scale: 100,
+            angle_snap: 0,
+            speed_tiers: SpeedTiers { slow: &PROFILE, medium: &PROFILE, fast: &PROFILE },
+            scroll: ScrollConfig { max_lines: 2, min_interval: 3 },
+            dwell: DwellConfig { delay_ms: 4, indicator: (0, 0) },
+            flick: NO_FLICK,
+        };
+        let mut mouse = Mouse::new(&CONFIG, RATE);
+        mouse.joystick.cycle_plane();
+        mouse.update_joystick((0, 50));
+        let mut reports = std::vec::Vec::new();
+        for _ in 0..6 {
+            mouse.tick(1);
+            mouse.push_report(|r| {
+                reports.push((r.y, r.vertical_wheel));
+                true
+            });
+        }
+        // 5 lines per tick, limited and not reported more often than the interval allows
+        assert_eq!(reports, [(0, 2), (0, 0), (0, 0), (0, 2), (0, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn speed_tiers() {
+        const fn constant(speed: u16) -> SpeedProfile {
+            SpeedProfile { divider: 10, delay: 0, acceleration_time: 0, start_speed: speed, max_speed: speed }
+        }
+        const DEFAULT: SpeedProfile = constant(20);
+        const SLOW: SpeedProfile = constant(10);
+        const MEDIUM: SpeedProfile = constant(40);
+        const FAST: SpeedProfile = SpeedProfile { divider: 5, ..constant(80) };
+        const AXIS: AxisConfig = AxisConfig { invert: false, profile: &DEFAULT };
+        static CONFIG: MouseConfig = MouseConfig {
+            x: AXIS,
+            y: AXIS,
+            wheel: AXIS,
+            pan: AXIS,
+            joystick: JoystickConfig { min: 1, max: 100, divider: 100, swap_axes: false, invert_x: false, invert_y: false },
+            precision_scale: 50,
+            angle_snap: 0,
+            speed_tiers: SpeedTiers { slow: &SLOW, medium: &MEDIUM, fast: &FAST },
+            scroll: ScrollConfig { max_lines: 0, min_interval: 0 },
+            dwell: DwellConfig { delay_ms: 4, indicator: (0, 0) },
+            flick: NO_FLICK,
+        };
+        let mut mouse = Mouse::new(&CONFIG, RATE);
+        let mut step = |mouse: &mut Mouse| {
+            mouse.tick(1);
+            let mut x = 0;
+            mouse.push_report(|r| {
+                x = r.x;
+                true
+            });
+            x
+        };
+        mouse.handle_action(&MouseAction::Move(MouseMovement::Right), true);
+        assert_eq!(step(&mut mouse), 2);
+        mouse.handle_action(&MouseAction::SpeedTier(SpeedTier::Fast), true);
+        assert_eq!(step(&mut mouse), 16);
+        // Slowest of the held tiers wins
+        mouse.handle_action(&MouseAction::SpeedTier(SpeedTier::Slow), true);
+        assert_eq!(step(&mut mouse), 1);
+        mouse.handle_action(&MouseAction::SpeedTier(SpeedTier::Slow), false);
+        mouse.handle_action(&MouseAction::SpeedTier(SpeedTier::Medium), true);
+        assert_eq!(step(&mut mouse), 4);
+        // Precision mode still applies
+        mouse.handle_action(&MouseAction::Precision, true);
+        assert_eq!(step(&mut mouse), 2);
+        mouse.handle_action(&MouseAction::Precision, false);
+        mouse.handle_action(&MouseAction::SpeedTier(SpeedTier::Medium), false);
+        mouse.handle_action(&MouseAction::SpeedTier(SpeedTier::Fast), false);
+        assert_eq!(step(&mut mouse), 2);
+    }
+
+    #[test]
+    fn accumulator_profile_change() {
+        const A: SpeedProfile = SpeedProfile { divider: 10, delay: 0, acceleration_time: 0, start_speed: 1, max_speed: 1 };
+        const B: SpeedProfile = SpeedProfile { divider: 100, ..A };
+        let mut acc = AxisAccumulator::new(&A);
+        acc.accumulated.accumulate(25);
+        acc.set_profile(&B);
+        assert_eq!(acc.accumulated.divider, 100);
+        assert_eq!(acc.accumulated.value, 250);
+        assert_eq!(acc.accumulated.get(100), 2);
+
+        // Saturates instead of wrapping
+        const C: SpeedProfile = SpeedProfile { divider: 10000, ..A };
+        acc.accumulated.value = i32::MAX / 2;
+        acc.set_profile(&C);
+        assert_eq!(acc.accumulated.value, i32::MAX);
+        acc.set_profile(&A);
+        acc.accumulated.value = i32::MIN / 2;
+        acc.set_profile(&C);
+        assert_eq!(acc.accumulated.value, i32::MIN);
+    }
+
+    #[test]
+    fn angle_snapping() {
+        assert_eq!(snap_to_axis((10, 1), 0), (10, 1));
+        assert_eq!(snap_to_axis((10, 1), 18), (10, 0));
+        assert_eq!(snap_to_axis((-10, -2), 18), (-10, 0));
+        assert_eq!(snap_to_axis((-2, 127), 18), (0, 127));
+        assert_eq!(snap_to_axis((-128, 23), 18), (-128, 0));
+        assert_eq!(snap_to_axis((10, 2), 18), (10, 2));
+        assert_eq!(snap_to_axis((5, -5), 18), (5, -5));
+        assert_eq!(snap_to_axis((0, 0), 18), (0, 0));
+    }
+
+    #[test]
+    fn dwell_click() {
+        static CONFIG: DwellConfig = DwellConfig { delay_ms: 4, indicator: (1, 2) };
+        let mut dwell = Dwell::new(&CONFIG, RATE);
+        let indicator = |count, remaining| Some(DwellIndicator { key: (1, 2), count, remaining });
+        dwell.tick(true, 1);
+        assert_eq!(dwell.indicator(), None);
+
+        dwell.set_enabled(true);
+        // Not armed until the pointer moves
+        dwell.tick(false, 10);
+        assert_eq!(dwell.indicator(), None);
+        dwell.tick(true, 1);
+        assert_eq!(dwell.indicator(), indicator(0, None));
+        dwell.tick(false, 2);
+        assert_eq!(dwell.indicator(), indicator(1, Some(2)));
+        // Movement restarts the countdown
+        dwell.tick(true, 1);
+        dwell.tick(false, 3);
+        assert_eq!(dwell.indicator(), indicator(2, Some(1)));
+        assert!(!dwell.click);
+        dwell.tick(false, 1);
+        assert!(dwell.click);
+        assert_eq!(dwell.indicator(), None);
+
+        dwell.set_enabled(false);
+        assert!(!dwell.click);
+    }
+
+    #[test]
+    fn dwell_click_report() {
+        let mut mouse = Mouse::new(&TEST_CONFIG, RATE);
+        mouse.handle_action(&MouseAction::DwellClick, true);
+        mouse.handle_action(&MouseAction::DwellClick, false);
+        mouse.dwell.still = Some(0);
+        mouse.tick(TEST_CONFIG.dwell.delay_ms);
+        let mut buttons = std::vec::Vec::new();
+        mouse.push_report(|_| false);
+        for _ in 0..2 {
+            mouse.push_report(|r| {
+                buttons.push(r.buttons);
+                true
+            });
+        }
+        assert_eq!(buttons, [MouseButton::Left.mask(), 0]);
+
+        mouse.handle_action(&MouseAction::DwellClick, true);
+        assert_eq!(mouse.dwell_indicator(), None);
+    }
+
+    #[test]
+    fn joystick_plane() {
+        let mut mouse = Mouse::new(&TEST_CONFIG, RATE);
+        let report = |mouse: &mut Mouse| {
+            mouse.update_joystick((4000, 0));
+            mouse.tick(1);
+            let mut x_pan = (0, 0);
+            mouse.push_report(|r| {
+                x_pan = (r.x, r.horizontal_wheel);
+                true
+            });
+            x_pan
+        };
+        assert_eq!(mouse.take_plane_change(), None);
+        let (x, pan) = report(&mut mouse);
+        assert!(x > 0 && pan == 0);
+
+        mouse.handle_action(&MouseAction::JoystickPlane, true);
+        mouse.handle_action(&MouseAction::JoystickPlane, false);
+        assert_eq!(mouse.take_plane_change(), Some(Plane::Scroll));
+        assert_eq!(mouse.take_plane_change(), None);
+        let (x, pan) = report(&mut mouse);
+        assert!(x == 0 && pan > 0);
+
+        mouse.handle_action(&MouseAction::JoystickPlane, true);
+        assert_eq!(mouse.take_plane_change(), Some(Plane::Xy));
+    }
+
+    const NO_FLICK: FlickConfig = FlickConfig {
+        threshold: 0, rise_time: 0, max_duration: 0, cooldown: 0, left: None, right: None, up: None, down: None,
+    };
+
+    const TEST_PROFILE: SpeedProfile = SpeedProfile {
+        divider: 1,
+        delay: 0,
+        acceleration_time: 0,
+        start_speed: 5,
+        max_speed: 5,
+    };
+    const TEST_AXIS: AxisConfig = AxisConfig { invert: false, profile: &TEST_PROFILE };
+    /// Independent of the keyboard configuration, joystick moves 1/10 of the reading per tick
+    static TEST_CONFIG: MouseConfig = MouseConfig {
+        x: TEST_AXIS,
+        y: TEST_AXIS,
+        wheel: TEST_AXIS,
+        pan: TEST_AXIS,
+        joystick: JoystickConfig { min: 1, max: 100, divider: 10, swap_axes: false, invert_x: false, invert_y: false },
+        precision_scale: 100,
+        angle_snap: 0,
+        speed_tiers: SpeedTiers { slow: &TEST_PROFILE, medium: &TEST_PROFILE, fast: &TEST_PROFILE },
+        scroll: ScrollConfig { max_lines: 0, min_interval: 0 },
+        dwell: DwellConfig { delay_ms: 4, indicator: (0, 0) },
+        flick: NO_FLICK,
+    };
+
+    #[test]
+    fn flick_gestures() {
+        static NEXT: KeyAction = KeyAction::Custom(Action::Mouse(MouseAction::JoystickPlane));
+        static CONFIG: FlickConfig = FlickConfig {
+            threshold: 1000, rise_time: 3, max_duration: 10, cooldown: 20, right: Some(&NEXT), ..NO_FLICK
+        };
+        let mut flick = Flick::new(&CONFIG);
+        let mut detect = |positions: &[(i16, i16)]| {
+            for &(x, y) in positions {
+                flick.tick((x, y), x.abs() < 100 && y.abs() < 100, 1);
+            }
+            flick.detected.take()
+        };
+
+        assert_eq!(detect(&[(0, 0), (500, 0), (1200, 300), (400, 0), (0, 0)]), Some(FlickDirection::Right));
+        // Bounce back through the center within cooldown
+        assert_eq!(detect(&[(-1500, 0), (0, 0)]), None);
+        assert_eq!(detect(&[(0, 0); 20]), None);
+        assert_eq!(detect(&[(-200, -300), (-900, -1400), (0, 0)]), Some(FlickDirection::Up));
+        assert!(CONFIG.action(FlickDirection::Up).is_none());
+        assert_eq!(detect(&[(0, 0); 20]), None);
+        // Too slow to reach the threshold
+        assert_eq!(detect(&[(200, 0), (400, 0), (600, 0), (800, 0), (1000, 0), (0, 0)]), None);
+        // Held for pointer movement
+        assert_eq!(detect(&[(0, 0), (0, 2000)]), None);
+        assert_eq!(detect(&[(0, 2000); 11]), None);
+        assert_eq!(detect(&[(0, 0)]), None);
+        assert_eq!(detect(&[(0, 2000), (0, 0)]), Some(FlickDirection::Down));
+    }
+
+    #[test]
+    fn flick_action() {
+        static WORKSPACE: KeyAction = KeyAction::MultipleKeyCodes(&[KeyCode::LCtrl, KeyCode::LAlt, KeyCode::Left].as_slice());
+        static CONFIG: FlickConfig = FlickConfig {
+            threshold: 1000, rise_time: 3, max_duration: 10, cooldown: 0, left: Some(&WORKSPACE), ..NO_FLICK
+        };
+        /// Check if the pointer moves
+        fn move_joystick(mouse: &mut Mouse, positions: &[(i16, i16)]) -> bool {
+            let mut moved = false;
+            for &xy in positions {
+                mouse.update_joystick(xy);
+                mouse.tick(1);
+                mouse.push_report(|report| {
+                    moved |= report.x != 0;
+                    true
+                });
+            }
+            moved
+        }
+        let mut mouse = Mouse::new(&TEST_CONFIG, RATE);
+        mouse.flick = Flick::new(&CONFIG);
+
+        // Pointer does not move during the flick
+        assert!(!move_joystick(&mut mouse, &[(-2000, 0), (-2000, 0), (0, 0)]));
+        assert!(matches!(mouse.take_flick(), Some(&KeyAction::MultipleKeyCodes(&&[KeyCode::LCtrl, KeyCode::LAlt, KeyCode::Left]))));
+        assert!(mouse.take_flick().is_none());
+        // Held tilt is not a flick, movement starts once it exceeds max duration
+        assert!(!move_joystick(&mut mouse, &[(-2000, 0); 10]));
+        assert!(move_joystick(&mut mouse, &[(-2000, 0); 2]));
+        assert!(!move_joystick(&mut mouse, &[(0, 0)]));
+        assert!(mouse.take_flick().is_none());
+    }
+
     #[test]
     fn accumulator_basic() {
         let profile = SpeedProfile {
@@ -387,19 +1345,19 @@ mod tests {
             max_speed: 30,
         };
         let mut acc = AxisAccumulator::new(&profile);
-        assert_eq!(acc.accumulated.get(), 0);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10 + 20);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10 + 20 + 30);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10 + 20 + 30 + 30);
-        acc.accumulated.consume();
-        assert_eq!(acc.accumulated.get(), 0);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 30);
+        assert_eq!(acc.accumulated.get(100), 0);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20 + 30);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20 + 30 + 30);
+        acc.accumulated.consume(100);
+        assert_eq!(acc.accumulated.get(100), 0);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 30);
     }
 
     #[test]
@@ -412,17 +1370,17 @@ mod tests {
             max_speed: 30,
         };
         let mut acc = AxisAccumulator::new(&profile);
-        assert_eq!(acc.accumulated.get(), 0);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10 + 20);
-        acc.tick(false, -1);
-        assert_eq!(acc.accumulated.get(), 10 + 20 - 30);
-        acc.tick(false, -1);
-        assert_eq!(acc.accumulated.get(), 10 + 20 - 30 - 30);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10 + 20 - 30 - 30 + 30);
+        assert_eq!(acc.accumulated.get(100), 0);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20);
+        acc.tick(false, -1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20 - 30);
+        acc.tick(false, -1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20 - 30 - 30);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20 - 30 - 30 + 30);
     }
 
     #[test]
@@ -435,16 +1393,16 @@ mod tests {
             max_speed: 30,
         };
         let mut acc = AxisAccumulator::new(&profile);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 0);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 0);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10 + 20);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 10 + 20 + 30);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 0);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 0);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 10 + 20 + 30);
     }
 
     #[test]
@@ -458,11 +1416,11 @@ mod tests {
         };
         let mut acc = AxisAccumulator::new(&profile);
         for _ in 0..5 {
-            acc.tick(false, 1);
+            acc.tick(false, 1, 1);
         }
-        assert_eq!(acc.accumulated.get(), 10 + 20 + 30 + 30 + 30);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 127);
+        assert_eq!(acc.accumulated.get(100), 10 + 20 + 30 + 30 + 30);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 127);
     }
 
     #[test]
@@ -476,9 +1434,9 @@ mod tests {
         };
         let mut acc = AxisAccumulator::new(&profile);
         for _ in 0..10 {
-            acc.tick(false, 1);
+            acc.tick(false, 1, 1);
         }
-        assert_eq!(acc.accumulated.get(), 10 / 2);
+        assert_eq!(acc.accumulated.get(100), 10 / 2);
     }
 
     #[test]
@@ -491,9 +1449,9 @@ mod tests {
             max_speed: 50,
         };
         let mut acc = AxisAccumulator::new(&profile);
-        acc.tick(false, 1);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), 100);
+        acc.tick(false, 1, 1);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), 100);
     }
 
     #[test]
@@ -506,14 +1464,14 @@ mod tests {
             max_speed: 100,
         };
         let mut acc = AxisAccumulator::new(&profile);
-        acc.tick(false, 1);
-        acc.tick(false, 1);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), ((50_i32 + 75 + 100) / 10) as i8);
-        acc.tick(true, 1);
-        assert_eq!(acc.accumulated.get(), ((50_i32 + 75 + 100 + 50) / 10) as i8);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), ((50_i32 + 75 + 100 + 50 + 75) / 10) as i8);
+        acc.tick(false, 1, 1);
+        acc.tick(false, 1, 1);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), ((50_i32 + 75 + 100) / 10) as i8);
+        acc.tick(true, 1, 1);
+        assert_eq!(acc.accumulated.get(100), ((50_i32 + 75 + 100 + 50) / 10) as i8);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), ((50_i32 + 75 + 100 + 50 + 75) / 10) as i8);
     }
 
     #[test]
@@ -526,14 +1484,14 @@ mod tests {
             max_speed: 100,
         };
         let mut acc = AxisAccumulator::new(&profile);
-        acc.tick(false, 1);
-        acc.tick(false, 1);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), ((50_i32 + 75 + 100) / 10) as i8);
-        acc.tick(false, 0);
-        assert_eq!(acc.accumulated.get(), ((50_i32 + 75 + 100) / 10) as i8);
-        acc.tick(false, 1);
-        assert_eq!(acc.accumulated.get(), ((50_i32 + 75 + 100 + 100) / 10) as i8);
+        acc.tick(false, 1, 1);
+        acc.tick(false, 1, 1);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), ((50_i32 + 75 + 100) / 10) as i8);
+        acc.tick(false, 0, 1);
+        assert_eq!(acc.accumulated.get(100), ((50_i32 + 75 + 100) / 10) as i8);
+        acc.tick(false, 1, 1);
+        assert_eq!(acc.accumulated.get(100), ((50_i32 + 75 + 100 + 100) / 10) as i8);
     }
 
     #[test]
@@ -561,9 +1519,29 @@ mod tests {
         ];
         let mut acc = AxisAccumulator::new(&profile);
         for (i, val) in seq.into_iter().enumerate() {
-            acc.tick(false, 1);
-            assert_eq!(acc.accumulated.get(), val, "At i = {}", i);
-            acc.accumulated.consume();
+            acc.tick(false, 1, 1);
+            assert_eq!(acc.accumulated.get(100), val, "At i = {}", i);
+            acc.accumulated.consume(100);
         }
     }
+
+    #[test]
+    fn accumulator_longer_period() {
+        let profile = SpeedProfile {
+            divider: 1,
+            delay: 2,
+            acceleration_time: 4,
+            start_speed: 10,
+            max_speed: 30,
+        };
+        let mut acc = AxisAccumulator::new(&profile);
+        acc.tick(false, 1, 2);
+        assert_eq!(acc.accumulated.get(100), 0);
+        // Distance during the whole period is computed from speed at its start
+        acc.tick(false, -1, 2);
+        assert_eq!(acc.accumulated.get(100), -2 * 10);
+        acc.tick(false, 1, 4);
+        assert_eq!(acc.accumulated.get(100), -2 * 10 + 4 * 20);
+        assert_eq!(acc.time, 8);
+    }
 }
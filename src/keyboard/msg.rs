@@ -6,10 +6,18 @@ use keyberon::layout::Event;
 use crate::utils::max;
 use crate::{hal_ext::crc::Crc, bsp::LedColors};
 use crate::ioqueue;
-use super::role;
-use super::leds::Leds;
+use super::{keymap, link, role, protocol};
+use super::leds::{Leds, ControllerState, OverlaysState};
+use super::keys::PressedKeys;
+
+/// Maximum number of key events in a single [`Message::Keys`]
+pub const MAX_KEY_EVENTS: usize = 8;
 
 /// Messages used in communication between keyboard halves
+///
+/// Serialized as variant index followed by variant data. Each message is sent in a separate
+/// frame, so a receiver running older firmware can skip variants that it does not know.
+/// New variants must be added just before [`Message::Unknown`] to keep existing indices.
 #[derive(Serialize, Deserialize, PartialEq)]
 pub enum Message {
     /// Negotiation of roles of each half
@@ -20,8 +28,57 @@ pub enum Message {
     /// Send LED colors from half connected to USB to the other on
     #[serde(with = "BigArray")]
     Leds(LedColors),
+    /// Serial link baud rate negotiation
+    Link(link::Message),
+    /// Game mode state sent from master, slave switches to eager debouncing
+    GameMode(bool),
+    /// LED blackout state sent from master, slave turns its LEDs off too
+    Blackout(bool),
+    /// Firmware version periodically announced by slave
+    Version(protocol::Version),
+    /// LED controller state sent from master instead of colors if slave supports it
+    LedState(ControllerState),
+    /// Multiple key events from a single scan, used instead of [`Message::Key`] if supported
+    Keys(KeyEvents),
+    /// Keys currently pressed on slave, periodically sent to correct lost key events
+    Pressed(PressedKeys),
+    /// Sent by master when it detects lost key events, slave responds with [`Message::Pressed`]
+    PressedRequest,
+    /// CRC of firmware image, announced together with [`Message::Version`]
+    Firmware(u32),
+    /// Keyboard time of master in response to [`Message::TimeRequest`], used by slave to
+    /// synchronize LED animations
+    Time { request: u32, time: u32 },
+    /// Overlays played on master, sent together with [`Message::LedState`]
+    Overlays(OverlaysState),
+    /// Request for [`Message::Time`] sent by slave with its keyboard time
+    TimeRequest(u32),
+    /// Key remapped by host on master, slave applies it to its own keymap
+    Keymap(keymap::Change),
+    /// Any message from a newer firmware version, variant data is ignored; never sent
+    #[serde(other)]
+    Unknown,
+}
+
+/// Batch of key events sent in a single frame to reduce per-packet overhead
+///
+/// Batches are numbered, so that the receiver can detect lost ones, see [`KeySequence`].
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct KeyEvents {
+    seq: u8,
+    events: heapless::Vec<KeyEvent, MAX_KEY_EVENTS>,
 }
 
+/// Tracks sequence numbers of received [`KeyEvents`] to detect lost batches
+#[derive(Default)]
+pub struct KeySequence {
+    expected: Option<u8>,
+    lost: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+struct KeyEvent(#[serde(with = "EventDef")] Event);
+
 // Work around Event not implementing Serialize: https://serde.rs/remote-derive.html
 #[derive(Serialize, Deserialize, MaxSize)]
 #[serde(remote = "Event")]
@@ -31,17 +88,76 @@ enum EventDef {
     Release(u8, u8),
 }
 
+impl KeyEvents {
+    /// Sequence number, length prefix (varint) and events
+    const POSTCARD_MAX_SIZE: usize = 2 + MAX_KEY_EVENTS * EventDef::POSTCARD_MAX_SIZE;
+
+    pub fn new(seq: u8) -> Self {
+        Self { seq, events: heapless::Vec::new() }
+    }
+
+    pub fn seq(&self) -> u8 {
+        self.seq
+    }
+
+    /// Add an event, returns false if the batch is full
+    pub fn push(&mut self, event: Event) -> bool {
+        self.events.push(KeyEvent(event)).is_ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        self.events.iter().map(|e| e.0)
+    }
+}
+
+impl KeySequence {
+    pub const fn new() -> Self {
+        Self { expected: None, lost: 0 }
+    }
+
+    /// Process sequence number of a received batch, returns number of batches lost before it
+    pub fn on_rx(&mut self, seq: u8) -> u8 {
+        let lost = self.expected.map_or(0, |e| seq.wrapping_sub(e));
+        self.expected = Some(seq.wrapping_add(1));
+        self.lost = self.lost.saturating_add(lost as u32);
+        lost
+    }
+
+    /// Forget the expected sequence number, e.g. when the other half may have been restarted
+    pub fn reset(&mut self) {
+        self.expected = None;
+    }
+
+    /// Total number of lost batches
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+}
+
 // Manual implementation on the whole enum because we have foreign types in variants
 // that don't implement MaxSize so we cannot even implement it for them.
+// ControllerState and PressedKeys are much smaller than LED colors (verified in tests),
+// OverlaysState is the largest message.
 impl MaxSize for Message {
     const POSTCARD_MAX_SIZE: usize = 1 + max(
-        max(role::Message::POSTCARD_MAX_SIZE, EventDef::POSTCARD_MAX_SIZE),
-        3 * 28,
+        max(
+            max(role::Message::POSTCARD_MAX_SIZE, EventDef::POSTCARD_MAX_SIZE),
+            max(link::Message::POSTCARD_MAX_SIZE, protocol::Version::POSTCARD_MAX_SIZE),
+        ),
+        max(max(KeyEvents::POSTCARD_MAX_SIZE, 3 * 28), OverlaysState::POSTCARD_MAX_SIZE),
     );
 }
 
 impl ioqueue::Packet for Message {
     type Checksum = Crc;
+
+    fn is_unknown(&self) -> bool {
+        matches!(self, Message::Unknown)
+    }
 }
 
 impl From<role::Message> for Message {
@@ -50,12 +166,24 @@ impl From<role::Message> for Message {
     }
 }
 
+impl From<link::Message> for Message {
+    fn from(msg: link::Message) -> Self {
+        Message::Link(msg)
+    }
+}
+
 impl From<Event> for Message {
     fn from(event: Event) -> Self {
         Message::Key(event)
     }
 }
 
+impl From<KeyEvents> for Message {
+    fn from(events: KeyEvents) -> Self {
+        Message::Keys(events)
+    }
+}
+
 impl From<LedColors> for Message {
     fn from(colors: LedColors) -> Self {
         Message::Leds(colors)
@@ -68,12 +196,27 @@ impl From<&Leds> for Message {
     }
 }
 
+impl From<ControllerState> for Message {
+    fn from(state: ControllerState) -> Self {
+        Message::LedState(state)
+    }
+}
+
+impl From<OverlaysState> for Message {
+    fn from(state: OverlaysState) -> Self {
+        Message::Overlays(state)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rgb::RGB8;
 
     use super::*;
+    use crate::bsp::sides::PerSide;
     use crate::ioqueue::packet::PacketSer;
+    use crate::keyboard::leds::{KeyboardState, LedsBitset};
+    use crate::keyboard::PowerState;
 
     #[test]
     fn message_max_size() {
@@ -81,9 +224,52 @@ mod tests {
             Message::Role(role::Message::EstablishMaster),
             Message::Role(role::Message::ReleaseMaster),
             Message::Role(role::Message::Ack),
+            Message::Role(role::Message::Handover),
             Message::Key(Event::Press(10, 11)),
             Message::Key(Event::Release(10, 11)),
             Message::Leds(LedColors::default()),
+            Message::Link(link::Message::Propose(3)),
+            Message::Link(link::Message::Accept(3)),
+            Message::Link(link::Message::Ping),
+            Message::GameMode(true),
+            Message::Blackout(true),
+            Message::Version(protocol::Version { protocol: u8::MAX, config_checksum: u32::MAX, board_revision: u8::MAX }),
+            Message::LedState(ControllerState {
+                keyboard: KeyboardState {
+                    leds: Default::default(),
+                    usb_on: true,
+                    role: role::Role::Master,
+                    layer: u8::MAX,
+                    pressed: PerSide { left: LedsBitset::with_all(true), right: LedsBitset::with_all(true) },
+                    allow_bootloader: true,
+                    key_tester: true,
+                    mouse_buttons: u8::MAX,
+                    game_mode: true,
+                    brightness: u8::MAX,
+                    brightness_preset: Some(u8::MAX),
+                    joystick_enabled: true,
+                    training: true,
+                    training_hint: Some((u8::MAX, u8::MAX)),
+                },
+                config: u8::MAX,
+                brightness: u8::MAX,
+                power: PowerState::DeepSleep,
+                night_mode: true,
+            }),
+            Message::Pressed(PressedKeys::ALL),
+            Message::PressedRequest,
+            Message::Firmware(u32::MAX),
+            Message::Time { request: u32::MAX, time: u32::MAX },
+            Message::TimeRequest(u32::MAX),
+            Message::Keymap(keymap::Change {
+                key: keymap::Key { layer: u8::MAX, row: u8::MAX, col: u8::MAX },
+                action: Some([u8::MAX, u8::MAX]),
+            }),
+            Message::Keys({
+                let mut events = KeyEvents::new(u8::MAX);
+                while events.push(Event::Release(u8::MAX, u8::MAX)) {}
+                events
+            }),
         ];
         let mut buf = [0; 256];
 
@@ -93,6 +279,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn message_unknown_variant() {
+        // Variant index from the future with some data that would not be valid for any variant
+        let (msg, _) = postcard::take_from_bytes::<Message>(&[0x7f, 0xff, 0xff, 0x01]).unwrap();
+        assert!(msg == Message::Unknown);
+        // Known variants still fail on invalid data
+        assert!(postcard::from_bytes::<Message>(&[0x01, 0x05]).is_err());
+    }
+
     fn verify_serialization(msg: Message, expected: &[u8]) {
         let mut buf = [0; 89];
         let mut checksum = Crc::new_mock();
@@ -116,6 +311,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn message_ser_keys() {
+        let mut events = KeyEvents::new(3);
+        assert!(events.push(Event::Press(5, 6)));
+        assert!(events.push(Event::Release(7, 8)));
+        verify_serialization(Message::Keys(events),
+            // Message::Keys, seq, length, (Event::*, i, j)..., crc16_L, crc16_H
+            &[0x08, 3, 2, 0x00, 5, 6, 0x01, 7, 8, 0x90, 0x5b]
+        );
+    }
+
+    #[test]
+    fn key_sequence_gaps() {
+        let mut seq = KeySequence::new();
+        assert_eq!(seq.on_rx(5), 0);
+        assert_eq!(seq.on_rx(6), 0);
+        assert_eq!(seq.on_rx(9), 2);
+        assert_eq!(seq.lost(), 2);
+        // Wrapping around
+        assert_eq!(seq.on_rx(255), 245);
+        assert_eq!(seq.on_rx(0), 0);
+        seq.reset();
+        assert_eq!(seq.on_rx(100), 0);
+        assert_eq!(seq.lost(), 247);
+    }
+
+    #[test]
+    fn key_events_full() {
+        let mut events = KeyEvents::default();
+        for i in 0..MAX_KEY_EVENTS {
+            assert!(events.push(Event::Press(0, i as u8)));
+        }
+        assert!(!events.push(Event::Press(1, 0)));
+        assert_eq!(events.iter().count(), MAX_KEY_EVENTS);
+    }
+
     #[test]
     fn message_ser_role_establish_master() {
         verify_serialization(Message::Role(role::Message::EstablishMaster),
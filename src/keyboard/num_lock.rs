@@ -0,0 +1,121 @@
+use super::ticks::TickRate;
+
+/// Time to wait for host to update keyboard LEDs after a NumLock tap
+const TAP_COOLDOWN_MS: u32 = 250;
+/// Number of taps without effect after which we stop trying until NumLock is no longer wanted
+///
+/// Host may ignore the taps (e.g. it has no NumLock LED state at all), so retrying forever
+/// would keep toggling its state. Cooldown doubles after each attempt.
+const MAX_ATTEMPTS: u8 = 4;
+
+/// When NumLock should be enabled automatically
+#[derive(Clone, Copy)]
+pub enum NumLockMode {
+    /// NumLock is fully controlled by the user
+    Off,
+    /// Keep NumLock always enabled
+    On,
+    /// Keep NumLock enabled while one of the given layers is active (e.g. numpad layer)
+    Layers(&'static [u8]),
+}
+
+/// Keeps host NumLock state enabled according to [`NumLockMode`]
+pub struct NumLock {
+    mode: NumLockMode,
+    /// Ticks left until host LED state can be trusted again after a tap
+    cooldown: u32,
+    /// Taps sent since NumLock has been seen enabled or not wanted
+    attempts: u8,
+    /// Initial cooldown in ticks
+    tap_cooldown: u32,
+}
+
+impl NumLockMode {
+    fn wanted(&self, layer: usize) -> bool {
+        match self {
+            Self::Off => false,
+            Self::On => true,
+            Self::Layers(layers) => layers.iter().any(|l| *l as usize == layer),
+        }
+    }
+}
+
+impl NumLock {
+    pub const fn new(mode: NumLockMode, rate: TickRate) -> Self {
+        Self { mode, cooldown: 0, attempts: 0, tap_cooldown: rate.from_ms(TAP_COOLDOWN_MS) }
+    }
+
+    /// Advance time, returns true if NumLock tap should be sent to the host
+    pub fn tick(&mut self, num_lock_on: bool, layer: usize) -> bool {
+        if self.cooldown > 0 {
+            self.cooldown -= 1;
+            false
+        } else if num_lock_on || !self.mode.wanted(layer) {
+            self.attempts = 0;
+            false
+        } else if self.attempts < MAX_ATTEMPTS {
+            defmt::info!("Enabling NumLock");
+            self.cooldown = self.tap_cooldown << self.attempts;
+            self.attempts += 1;
+            if self.attempts == MAX_ATTEMPTS {
+                defmt::warn!("NumLock not enabled by host, giving up");
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ticks equal milliseconds
+    const RATE: TickRate = TickRate::new(1000);
+
+    #[test]
+    fn mode_off() {
+        let mut nl = NumLock::new(NumLockMode::Off, RATE);
+        assert!(!nl.tick(false, 0));
+        assert!(!nl.tick(false, 3));
+    }
+
+    #[test]
+    fn mode_on_with_cooldown() {
+        let mut nl = NumLock::new(NumLockMode::On, RATE);
+        assert!(nl.tick(false, 0));
+        // Host has not updated LEDs yet
+        for _ in 0..TAP_COOLDOWN_MS {
+            assert!(!nl.tick(false, 0));
+        }
+        // Host ignored the tap, try again after a longer cooldown
+        assert!(nl.tick(false, 0));
+        for _ in 0..2 * TAP_COOLDOWN_MS {
+            nl.tick(true, 0);
+        }
+        assert!(!nl.tick(true, 0));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut nl = NumLock::new(NumLockMode::Layers(&[1]), RATE);
+        let mut taps = 0;
+        for _ in 0..TAP_COOLDOWN_MS << (MAX_ATTEMPTS + 1) {
+            taps += nl.tick(false, 1) as u8;
+        }
+        assert_eq!(taps, MAX_ATTEMPTS);
+
+        // Leaving the layer resets the attempts
+        assert!(!nl.tick(false, 0));
+        assert!(nl.tick(false, 1));
+    }
+
+    #[test]
+    fn mode_layers() {
+        let mut nl = NumLock::new(NumLockMode::Layers(&[2, 4]), RATE);
+        assert!(!nl.tick(false, 0));
+        assert!(!nl.tick(false, 3));
+        assert!(nl.tick(false, 4));
+    }
+}
@@ -0,0 +1,210 @@
+use defmt::Format;
+use serde::{Serialize, Deserialize};
+use usb_device::device::UsbDeviceState;
+
+use super::ticks::TickRate;
+
+/// Time without activity before dimming LEDs
+pub const IDLE_DIM_TIMEOUT_MS: u32 = 60_000;
+/// Time without activity before USB suspend turns into deep sleep
+pub const DEEP_SLEEP_TIMEOUT_MS: u32 = 30_000;
+
+/// Global power state that all subsystems follow
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug))]
+pub enum PowerState {
+    /// Normal operation
+    Active,
+    /// No user activity for a while, LEDs are dimmed
+    IdleDim,
+    /// Host suspended the USB bus, LEDs are disabled and key scanning waits for interrupt
+    Suspend,
+    /// Prolonged suspend, additionally stops joystick sampling and LED data transmission
+    DeepSleep,
+}
+
+impl PowerState {
+    /// Limit user-selected LED brightness according to power state
+    pub fn led_brightness(&self, brightness: u8) -> u8 {
+        match self {
+            Self::Active => brightness,
+            Self::IdleDim => brightness / 4,
+            Self::Suspend | Self::DeepSleep => 0,
+        }
+    }
+
+    /// Whether key matrix scanning may be stopped until a key press interrupt
+    pub fn matrix_idle_allowed(&self) -> bool {
+        matches!(self, Self::Suspend | Self::DeepSleep)
+    }
+
+    /// Whether joystick should be sampled
+    pub fn joystick_enabled(&self) -> bool {
+        !matches!(self, Self::DeepSleep)
+    }
+
+    /// Whether LED colors should be transmitted to the other half
+    pub fn led_transmission_enabled(&self) -> bool {
+        !matches!(self, Self::DeepSleep)
+    }
+}
+
+/// Presence of 5 V on the USB connector, sensed independently of USB enumeration
+#[derive(Clone, Copy, PartialEq, Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Vbus {
+    /// No 5 V on the USB connector, the half is powered by the other one
+    Absent,
+    /// 5 V from a dedicated charger, no host will ever enumerate the device
+    Charger,
+    /// 5 V present and no charger detected, so a host is most likely connected
+    Present,
+    /// Board cannot sense VBUS and no charger has been detected
+    Unknown,
+}
+
+impl Vbus {
+    /// Determine state from VBUS sense pin level (if available) and data lines
+    ///
+    /// Dedicated chargers short D+ and D-, so with our D+ pull-up both lines are high (SE1),
+    /// which never happens on a bus driven by a host.
+    pub fn sense(pin: Option<bool>, dp: bool, dm: bool) -> Self {
+        match (pin, dp && dm) {
+            (Some(false), _) => Self::Absent,
+            (_, true) => Self::Charger,
+            (Some(true), false) => Self::Present,
+            (None, false) => Self::Unknown,
+        }
+    }
+
+    /// Whether a host may be connected, i.e. USB suspend means that the host is sleeping
+    pub fn host_possible(&self) -> bool {
+        matches!(self, Self::Present | Self::Unknown)
+    }
+}
+
+/// Power state machine driven by USB state, VBUS and user activity
+///
+/// Should be advanced on every keyboard tick. Subsystems subscribe to the changes by
+/// checking [`PowerManager::state`] or reacting on the transitions returned from
+/// [`PowerManager::tick`].
+pub struct PowerManager {
+    state: PowerState,
+    /// Ticks since last user activity
+    inactive: u32,
+    /// Timeouts converted to ticks
+    idle_dim_timeout: u32,
+    deep_sleep_timeout: u32,
+}
+
+impl PowerManager {
+    pub const fn new(rate: TickRate) -> Self {
+        Self {
+            state: PowerState::Active,
+            inactive: 0,
+            idle_dim_timeout: rate.from_ms(IDLE_DIM_TIMEOUT_MS),
+            deep_sleep_timeout: rate.from_ms(DEEP_SLEEP_TIMEOUT_MS),
+        }
+    }
+
+    /// Get current power state
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+
+    /// Advance time, returns new state on transitions
+    ///
+    /// Without a host (e.g. powered from a charger) USB is never configured, so bus suspend
+    /// is ignored and the state only depends on user activity.
+    pub fn tick(&mut self, usb_state: UsbDeviceState, vbus: Vbus, activity: bool) -> Option<PowerState> {
+        self.inactive = if activity { 0 } else { self.inactive.saturating_add(1) };
+
+        let suspended = usb_state == UsbDeviceState::Suspend && vbus.host_possible();
+        let new = match (suspended, self.state) {
+            (true, PowerState::Suspend | PowerState::DeepSleep)
+                if self.inactive >= self.deep_sleep_timeout => PowerState::DeepSleep,
+            (true, _) => PowerState::Suspend,
+            _ if self.inactive >= self.idle_dim_timeout => PowerState::IdleDim,
+            _ => PowerState::Active,
+        };
+
+        (new != self.state).then(|| {
+            defmt::info!("Power state: {} -> {}", self.state, new);
+            self.state = new;
+            new
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ticks equal milliseconds
+    const RATE: TickRate = TickRate::new(1000);
+
+    fn run(pm: &mut PowerManager, usb_state: UsbDeviceState, ticks: u32) -> Option<PowerState> {
+        let mut last = None;
+        for _ in 0..ticks {
+            last = pm.tick(usb_state, Vbus::Unknown, false).or(last);
+        }
+        last
+    }
+
+    #[test]
+    fn dim_on_inactivity() {
+        // Timeout in ticks depends on tick rate
+        let mut pm = PowerManager::new(TickRate::new(500));
+        assert_eq!(run(&mut pm, UsbDeviceState::Configured, IDLE_DIM_TIMEOUT_MS / 2 - 1), None);
+        assert_eq!(pm.tick(UsbDeviceState::Configured, Vbus::Unknown, false), Some(PowerState::IdleDim));
+        assert_eq!(pm.tick(UsbDeviceState::Configured, Vbus::Unknown, true), Some(PowerState::Active));
+        assert_eq!(pm.tick(UsbDeviceState::Configured, Vbus::Unknown, false), None);
+    }
+
+    #[test]
+    fn suspend_then_deep_sleep() {
+        let mut pm = PowerManager::new(RATE);
+        assert_eq!(pm.tick(UsbDeviceState::Suspend, Vbus::Unknown, false), Some(PowerState::Suspend));
+        assert_eq!(run(&mut pm, UsbDeviceState::Suspend, DEEP_SLEEP_TIMEOUT_MS - 2), None);
+        assert_eq!(pm.tick(UsbDeviceState::Suspend, Vbus::Unknown, false), Some(PowerState::DeepSleep));
+        assert_eq!(run(&mut pm, UsbDeviceState::Suspend, 100), None);
+    }
+
+    #[test]
+    fn wake_from_deep_sleep() {
+        let mut pm = PowerManager::new(RATE);
+        run(&mut pm, UsbDeviceState::Suspend, DEEP_SLEEP_TIMEOUT_MS + 1);
+        assert_eq!(pm.state(), PowerState::DeepSleep);
+        // Key press while host is still sleeping
+        assert_eq!(pm.tick(UsbDeviceState::Suspend, Vbus::Unknown, true), Some(PowerState::Suspend));
+        // Host resumed
+        assert_eq!(pm.tick(UsbDeviceState::Configured, Vbus::Unknown, false), Some(PowerState::Active));
+    }
+
+    #[test]
+    fn charger_never_suspends() {
+        let mut pm = PowerManager::new(RATE);
+        assert_eq!(pm.tick(UsbDeviceState::Suspend, Vbus::Charger, false), None);
+        assert_eq!(pm.tick(UsbDeviceState::Suspend, Vbus::Present, false), Some(PowerState::Suspend));
+        // Charger detected while suspended
+        assert_eq!(pm.tick(UsbDeviceState::Suspend, Vbus::Charger, false), Some(PowerState::Active));
+    }
+
+    #[test]
+    fn vbus_sensing() {
+        assert_eq!(Vbus::sense(Some(false), true, false), Vbus::Absent);
+        assert_eq!(Vbus::sense(Some(true), true, false), Vbus::Present);
+        assert_eq!(Vbus::sense(Some(true), true, true), Vbus::Charger);
+        assert_eq!(Vbus::sense(None, true, true), Vbus::Charger);
+        assert_eq!(Vbus::sense(None, true, false), Vbus::Unknown);
+        assert_eq!(Vbus::sense(None, false, false), Vbus::Unknown);
+    }
+
+    #[test]
+    fn brightness_limits() {
+        assert_eq!(PowerState::Active.led_brightness(200), 200);
+        assert_eq!(PowerState::IdleDim.led_brightness(200), 50);
+        assert_eq!(PowerState::Suspend.led_brightness(200), 0);
+        assert_eq!(PowerState::DeepSleep.led_brightness(200), 0);
+    }
+}
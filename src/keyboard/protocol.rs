@@ -0,0 +1,216 @@
+use serde::{Serialize, Deserialize};
+use postcard::experimental::max_size::MaxSize;
+use defmt::Format;
+
+use super::ticks::TickRate;
+
+/// Version of the protocol between halves, increased when new messages are added
+///
+/// Also increased on any change of [`super::leds::ControllerState`] or [`super::leds::OverlaysState`]
+/// format, which are only sent to the other half when it uses exactly the same version, see
+/// [`Protocol::peer_matches`].
+pub const PROTOCOL_VERSION: u8 = 12;
+/// First version that accepts batched key events in [`super::msg::Message::Keys`]
+///
+/// Version 4 used batches without sequence numbers, which have a different format.
+pub const KEY_EVENTS_VERSION: u8 = 9;
+
+/// Period of version announcements
+const ANNOUNCE_PERIOD_MS: u32 = 500;
+/// Forget the version of the other half if it hasn't been announced for that long
+const PEER_TIMEOUT_MS: u32 = 3 * ANNOUNCE_PERIOD_MS;
+
+/// Firmware version information of a keyboard half
+#[derive(Serialize, Deserialize, MaxSize, Format, PartialEq, Clone, Copy)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Version {
+    pub protocol: u8,
+    /// Features that depend on configuration require both halves to use the same one
+    pub config_checksum: u32,
+    /// PCB revision of the half, see [`crate::bsp::board::Board::revision`]
+    pub board_revision: u8,
+}
+
+/// Tracks protocol version of the other half
+///
+/// Both halves periodically announce their versions. Firmware that does not know the version
+/// message never announces anything, so the other half falls back to the oldest protocol.
+/// Older firmware only announced version from slave.
+///
+/// CRC of the firmware image is announced in a separate message, so that firmware which does
+/// not know it can still parse the version.
+pub struct Protocol {
+    this: Version,
+    firmware: Option<u32>,
+    time: u32,
+    last_announce: Option<u32>,
+    peer: Option<(Version, u32)>,
+    peer_firmware: Option<u32>,
+    /// Announce period in ticks
+    announce_period: u32,
+    /// Peer timeout in ticks
+    peer_timeout: u32,
+}
+
+impl Version {
+    pub const fn current() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            config_checksum: crate::config::CHECKSUM,
+            board_revision: crate::bsp::board::BOARD.revision,
+        }
+    }
+}
+
+impl Protocol {
+    pub const fn new(this: Version, rate: TickRate) -> Self {
+        Self {
+            this,
+            firmware: None,
+            time: 0,
+            last_announce: None,
+            peer: None,
+            peer_firmware: None,
+            announce_period: rate.from_ms(ANNOUNCE_PERIOD_MS),
+            peer_timeout: rate.from_ms(PEER_TIMEOUT_MS),
+        }
+    }
+
+    /// Set CRC of our firmware image, it is announced together with the version
+    pub fn set_firmware(&mut self, crc: u32) {
+        self.firmware = Some(crc);
+    }
+
+    /// CRC of our firmware image, if known
+    pub fn firmware(&self) -> Option<u32> {
+        self.firmware
+    }
+
+    /// CRC of firmware image of the other half, if announced
+    pub fn peer_firmware(&self) -> Option<u32> {
+        self.peer_firmware
+    }
+
+    /// Store version announced by the other half
+    pub fn on_rx(&mut self, version: Version) {
+        if self.peer.map(|(v, _)| v) != Some(version) {
+            defmt::info!("Other half version: {}", version);
+            if version.board_revision != self.this.board_revision {
+                defmt::warn!("Other half uses different board revision: {=u8}", version.board_revision);
+            }
+        }
+        self.peer = Some((version, self.time));
+    }
+
+    /// Store firmware image CRC announced by the other half
+    pub fn on_rx_firmware(&mut self, crc: u32) {
+        if self.peer_firmware != Some(crc) {
+            if self.firmware.map_or(false, |this| this != crc) {
+                defmt::warn!("Other half runs different firmware image: {=u32:08x}", crc);
+            } else {
+                defmt::info!("Other half firmware image: {=u32:08x}", crc);
+            }
+        }
+        self.peer_firmware = Some(crc);
+    }
+
+    /// Advance time, returns our version if it should be announced
+    pub fn tick(&mut self, announce: bool) -> Option<Version> {
+        self.time = self.time.wrapping_add(1);
+        if let Some((_, t)) = self.peer {
+            if self.time.wrapping_sub(t) > self.peer_timeout {
+                defmt::warn!("Other half version timed out");
+                self.peer = None;
+                self.peer_firmware = None;
+            }
+        }
+        let due = self.last_announce.map_or(true, |t| self.time.wrapping_sub(t) >= self.announce_period);
+        (announce && due).then(|| {
+            self.last_announce = Some(self.time);
+            self.this
+        })
+    }
+
+    /// Check if the other half supports given protocol version, regardless of configuration
+    pub fn peer_has_protocol(&self, protocol: u8) -> bool {
+        self.peer.map_or(false, |(v, _)| v.protocol >= protocol)
+    }
+
+    /// Check if the other half supports given protocol version and uses the same configuration
+    pub fn peer_supports(&self, protocol: u8) -> bool {
+        self.peer.map_or(false, |(v, _)| {
+            v.protocol >= protocol && v.config_checksum == self.this.config_checksum
+        })
+    }
+
+    /// Check if the other half uses exactly the same protocol version and configuration
+    ///
+    /// Needed for data that both halves must interpret the same way, e.g. LED controller state.
+    pub fn peer_matches(&self) -> bool {
+        self.peer.map_or(false, |(v, _)| {
+            v.protocol == self.this.protocol && v.config_checksum == self.this.config_checksum
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ticks equal milliseconds
+    const RATE: TickRate = TickRate::new(1000);
+    const V1: Version = Version { protocol: 1, config_checksum: 0x1234, board_revision: 1 };
+
+    #[test]
+    fn announce_periodically() {
+        let mut proto = Protocol::new(V1, RATE);
+        assert_eq!(proto.tick(true), Some(V1));
+        for _ in 1..ANNOUNCE_PERIOD_MS {
+            assert_eq!(proto.tick(true), None);
+        }
+        assert_eq!(proto.tick(true), Some(V1));
+        assert_eq!(proto.tick(false), None);
+    }
+
+    #[test]
+    fn peer_support() {
+        let mut proto = Protocol::new(V1, RATE);
+        assert!(!proto.peer_supports(0));
+        proto.on_rx(V1);
+        assert!(proto.peer_supports(1));
+        assert!(!proto.peer_supports(2));
+        proto.on_rx(Version { config_checksum: 0x4321, ..V1 });
+        assert!(!proto.peer_supports(1));
+        assert!(proto.peer_has_protocol(1));
+        assert!(!proto.peer_has_protocol(2));
+    }
+
+    #[test]
+    fn peer_match() {
+        let mut proto = Protocol::new(V1, RATE);
+        assert!(!proto.peer_matches());
+        proto.on_rx(V1);
+        assert!(proto.peer_matches());
+        // Newer peer supports our version, but not necessarily the same data formats
+        proto.on_rx(Version { protocol: 2, ..V1 });
+        assert!(proto.peer_supports(1));
+        assert!(!proto.peer_matches());
+        proto.on_rx(Version { config_checksum: 0x4321, ..V1 });
+        assert!(!proto.peer_matches());
+    }
+
+    #[test]
+    fn peer_timeout() {
+        let mut proto = Protocol::new(V1, RATE);
+        proto.on_rx(V1);
+        proto.on_rx_firmware(0xabcd);
+        for _ in 0..PEER_TIMEOUT_MS {
+            proto.tick(false);
+        }
+        assert!(proto.peer_supports(1));
+        assert_eq!(proto.peer_firmware(), Some(0xabcd));
+        proto.tick(false);
+        assert!(!proto.peer_supports(1));
+        assert_eq!(proto.peer_firmware(), None);
+    }
+}
@@ -15,12 +15,15 @@ pub enum Message {
     EstablishMaster,
     /// Signalize that USB connection is lost and master state can be released
     ReleaseMaster,
-    /// Acknowledge other board's EstablishMaster request
+    /// Acknowledge other board's EstablishMaster or Handover request
     Ack,
+    /// Master offers its role to the other board, which should take it if it has USB
+    Handover,
 }
 
 /// Describes current role of keyboard half
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug))]
 pub enum Role {
     /// Board should act as master: process keyboard events, send USB HID reports,
     /// send commands to slave over serial, etc.
@@ -50,6 +53,17 @@ statemachine! {
         AsMaster + UsbOff / send_release_master = AsMaster,
         AsMaster + EstablishMaster [no_usb] / send_ack = AsSlave,
         WantsMaster + ReleaseMaster / send_establish_master = WantsMaster,
+
+        // Manual role swap, master keeps its role until the other half accepts
+        AsMaster + SwapRole / send_handover = HandingOver,
+        HandingOver + Ack = AsSlave,
+        HandingOver + ReleaseMaster = AsMaster,
+        HandingOver + Timeout = AsMaster,
+        HandingOver + UsbOff / send_release_master = AsMaster,
+        WantsMaster + Handover / send_ack = AsMaster,
+        AsSlave + Handover / send_release_master = AsSlave,
+        // After a swap we may be a slave with USB, take over when master releases its role
+        AsSlave + ReleaseMaster [has_usb] / send_establish_master = WantsMaster,
     }
 }
 
@@ -60,6 +74,7 @@ pub struct Context {
     message: Option<Message>,
     timeout: u32,
     timeout_cnt: Option<u32>,
+    forced: Option<Role>,
 }
 
 impl Context {
@@ -93,10 +108,21 @@ impl StateMachineContext for Context {
         Ok(())
     }
 
+    fn send_handover(&mut self) -> Result<(), ()> {
+        defmt::info!("Send Handover");
+        self.start_timeout();
+        self.send(Message::Handover);
+        Ok(())
+    }
+
     fn no_usb<'a>(&self) -> Result<bool, ()>  {
         if !self.usb_on { Ok(true) } else { Err(()) }
     }
 
+    fn has_usb(&self) -> Result<bool, ()>  {
+        if self.usb_on { Ok(true) } else { Err(()) }
+    }
+
     fn resign(&self) -> Result<bool, ()>  {
         match self.side {
             BoardSide::Left => Err(()),
@@ -116,6 +142,7 @@ impl StateMachine<Context> {
             message: None,
             timeout_cnt: None,
             timeout,
+            forced: None,
         })
     }
 
@@ -141,11 +168,20 @@ impl StateMachine<Context> {
             Message::Ack => Events::Ack,
             Message::EstablishMaster => Events::EstablishMaster,
             Message::ReleaseMaster => Events::ReleaseMaster,
+            Message::Handover => Events::Handover,
         };
         self.process_event(event).ok();
         self.context.message.take()
     }
 
+    /// Request handing over master role to the other board
+    ///
+    /// The other board takes the role only if it has USB connection, else we stay master.
+    pub fn swap_role(&mut self) -> Option<Message> {
+        self.process_event(Events::SwapRole).ok();
+        self.context.message.take()
+    }
+
     /// Advance time by one tick
     pub fn tick(&mut self) -> Option<Message> {
         // If timeout hasn't been set then nothing to do
@@ -162,10 +198,18 @@ impl StateMachine<Context> {
         }
     }
 
+    /// Override negotiated role (for debugging), `None` restores negotiated role
+    pub fn force_role(&mut self, role: Option<Role>) {
+        self.context.forced = role;
+    }
+
     /// Get current role of this board
     pub fn role(&self) -> Role {
+        if let Some(role) = &self.context.forced {
+            return role.clone();
+        }
         match *self.state() {
-            States::AsMaster => Role::Master,
+            States::AsMaster | States::HandingOver => Role::Master,
             States::WantsMaster if self.context.is_alone => Role::Master,
             _ => Role::Slave,
         }
@@ -185,6 +229,7 @@ mod tests {
                 States::AsSlave => States::AsSlave,
                 States::WantsMaster => States::WantsMaster,
                 States::AsMaster => States::AsMaster,
+                States::HandingOver => States::HandingOver,
             }
         }
     }
@@ -195,6 +240,7 @@ mod tests {
                 States::AsSlave => "AsSlave",
                 States::WantsMaster => "WantsMaster",
                 States::AsMaster => "AsMaster",
+                States::HandingOver => "HandingOver",
             };
             f.debug_struct(string).finish()
         }
@@ -209,6 +255,8 @@ mod tests {
                 Events::ReleaseMaster => "ReleaseMaster",
                 Events::Timeout => "Timeout",
                 Events::Ack => "Ack",
+                Events::SwapRole => "SwapRole",
+                Events::Handover => "Handover",
             };
             f.debug_struct(string).finish()
         }
@@ -260,6 +308,7 @@ mod tests {
         #[allow(dead_code)]
         Inject(Dir, Message),
         Usb(Dir, bool),
+        Swap(Dir),
     }
 
     fn scenario<const N: usize>(timeout: u32, steps: [Step; N]) {
@@ -307,6 +356,14 @@ mod tests {
                     };
                     maybe_tx(dir, ch, to_drop, fsm.usb_state(on));
                 },
+                Step::Swap(dir) => {
+                    println!("Swap {}", dir);
+                    let (fsm, ch, to_drop) = match dir {
+                        Dir::Left => (&mut left, &mut ch.left_to_right, &mut drop_next.0),
+                        Dir::Right => (&mut right, &mut ch.right_to_left, &mut drop_next.1),
+                    };
+                    maybe_tx(dir, ch, to_drop, fsm.swap_role());
+                },
                 Step::DropNext(dir, msg) => {
                     match dir {
                         Dir::Left => &mut drop_next.0,
@@ -318,6 +375,7 @@ mod tests {
                         Message::EstablishMaster,
                         Message::ReleaseMaster,
                         Message::Ack,
+                        Message::Handover,
                     ];
                     for msg in msgs {
                         match dir {
@@ -417,6 +475,53 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn manual_swap() {
+        scenario(3, [
+            Tick(AsSlave, AsSlave),
+            Usb(Left, true),
+            Tick(WantsMaster, AsSlave),
+            Tick(AsMaster, AsSlave),
+            Usb(Right, true),
+            Tick(AsMaster, WantsMaster),
+            Swap(Left),  // L sends Handover
+            Tick(HandingOver, AsMaster),  // R accepts and sends Ack
+            Tick(AsSlave, AsMaster),
+            Usb(Right, false),  // R sends ReleaseMaster, L with USB takes over
+            Tick(WantsMaster, AsSlave),
+            Tick(AsMaster, AsSlave),
+        ]);
+    }
+
+    #[test]
+    fn manual_swap_without_usb() {
+        scenario(3, [
+            Tick(AsSlave, AsSlave),
+            Usb(Left, true),
+            Tick(WantsMaster, AsSlave),
+            Tick(AsMaster, AsSlave),
+            Swap(Left),  // R has no USB so it declines with ReleaseMaster
+            Tick(HandingOver, AsSlave),
+            Tick(AsMaster, AsSlave),
+        ]);
+    }
+
+    #[test]
+    fn manual_swap_timeout() {
+        scenario(3, [
+            Tick(AsSlave, AsSlave),
+            Usb(Left, true),
+            Tick(WantsMaster, AsSlave),
+            Tick(AsMaster, AsSlave),
+            DropNext(Left, Message::Handover),
+            Swap(Left),  // timeout=3
+            Tick(HandingOver, AsSlave),  // -> 2
+            Tick(HandingOver, AsSlave),  // -> 1
+            Tick(HandingOver, AsSlave),  // -> 0
+            Tick(AsMaster, AsSlave),  // timeout, keep master role
+        ]);
+    }
+
     #[test]
     fn establish_master_timeout() {
         scenario(3, [
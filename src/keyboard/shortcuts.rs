@@ -0,0 +1,199 @@
+use keyberon::key_code::KeyCode;
+
+use super::actions::{Shortcut, ShortcutAction};
+
+/// Maximum number of shortcuts held at the same time, further ones are ignored
+const MAX_HELD: usize = 4;
+
+/// Operating system of the host, determines key chords of shortcuts
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub enum OsProfile {
+    Windows,
+    MacOs,
+    Linux,
+}
+
+/// Key codes translated for the host OS keyboard layout
+///
+/// Pairs of (key code as written, key code sent), listing only keys that differ from US QWERTY.
+/// Layers are translated when generating configuration, but shortcut chords are built here.
+pub type HostKeys = &'static [(KeyCode, KeyCode)];
+
+/// Shortcuts that send different key chords depending on the OS profile
+///
+/// Held shortcuts are translated to key codes when building keyboard reports, so the chord
+/// follows the OS profile even if it is changed while a shortcut key is held.
+pub struct Shortcuts {
+    profile: OsProfile,
+    host_keys: HostKeys,
+    held: heapless::Vec<Shortcut, MAX_HELD>,
+}
+
+impl OsProfile {
+    fn next(self) -> Self {
+        match self {
+            Self::Windows => Self::MacOs,
+            Self::MacOs => Self::Linux,
+            Self::Linux => Self::Windows,
+        }
+    }
+}
+
+impl Shortcuts {
+    pub const fn new(profile: OsProfile, host_keys: HostKeys) -> Self {
+        Self { profile, host_keys, held: heapless::Vec::new() }
+    }
+
+    pub fn profile(&self) -> OsProfile {
+        self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: OsProfile) {
+        defmt::info!("OS profile: {}", profile);
+        self.profile = profile;
+    }
+
+    pub fn handle_action(&mut self, action: &ShortcutAction, pressed: bool) {
+        match action {
+            ShortcutAction::Key(shortcut) => {
+                let pos = self.held.iter().position(|s| s == shortcut);
+                match (pos, pressed) {
+                    (None, true) => {
+                        self.held.push(*shortcut).ok();
+                    },
+                    (Some(i), false) => {
+                        self.held.swap_remove(i);
+                    },
+                    _ => {},
+                }
+            },
+            ShortcutAction::Profile(profile) => if pressed {
+                self.set_profile(*profile);
+            },
+            ShortcutAction::CycleProfile => if pressed {
+                self.set_profile(self.profile.next());
+            },
+        }
+    }
+
+    /// Modify key codes from layout by adding key chords of held shortcuts
+    pub fn keycodes<'a>(&'a self, layout: impl Iterator<Item = KeyCode> + 'a) -> impl Iterator<Item = KeyCode> + 'a {
+        let profile = self.profile;
+        let chords = self.held.iter()
+            .flat_map(move |s| chord(*s, profile).iter())
+            .map(|kc| self.host_key(*kc));
+        layout.chain(chords)
+    }
+
+    /// Key code to send so that the host layout produces `kc`
+    fn host_key(&self, kc: KeyCode) -> KeyCode {
+        self.host_keys.iter()
+            .find(|(written, _)| *written == kc)
+            .map_or(kc, |(_, sent)| *sent)
+    }
+}
+
+/// Key chord that performs the shortcut on given OS
+fn chord(shortcut: Shortcut, profile: OsProfile) -> &'static [KeyCode] {
+    use KeyCode as K;
+    use OsProfile::MacOs;
+    match (shortcut, profile) {
+        (Shortcut::Copy, MacOs) => &[K::LGui, K::C],
+        (Shortcut::Copy, _) => &[K::LCtrl, K::C],
+        (Shortcut::Cut, MacOs) => &[K::LGui, K::X],
+        (Shortcut::Cut, _) => &[K::LCtrl, K::X],
+        (Shortcut::Paste, MacOs) => &[K::LGui, K::V],
+        (Shortcut::Paste, _) => &[K::LCtrl, K::V],
+        (Shortcut::Undo, MacOs) => &[K::LGui, K::Z],
+        (Shortcut::Undo, _) => &[K::LCtrl, K::Z],
+        (Shortcut::Redo, MacOs) => &[K::LGui, K::LShift, K::Z],
+        (Shortcut::Redo, OsProfile::Windows) => &[K::LCtrl, K::Y],
+        (Shortcut::Redo, OsProfile::Linux) => &[K::LCtrl, K::LShift, K::Z],
+        (Shortcut::SelectAll, MacOs) => &[K::LGui, K::A],
+        (Shortcut::SelectAll, _) => &[K::LCtrl, K::A],
+        (Shortcut::Save, MacOs) => &[K::LGui, K::S],
+        (Shortcut::Save, _) => &[K::LCtrl, K::S],
+        (Shortcut::Find, MacOs) => &[K::LGui, K::F],
+        (Shortcut::Find, _) => &[K::LCtrl, K::F],
+        (Shortcut::WordLeft, MacOs) => &[K::LAlt, K::Left],
+        (Shortcut::WordLeft, _) => &[K::LCtrl, K::Left],
+        (Shortcut::WordRight, MacOs) => &[K::LAlt, K::Right],
+        (Shortcut::WordRight, _) => &[K::LCtrl, K::Right],
+        (Shortcut::LineStart, MacOs) => &[K::LGui, K::Left],
+        (Shortcut::LineStart, _) => &[K::Home],
+        (Shortcut::LineEnd, MacOs) => &[K::LGui, K::Right],
+        (Shortcut::LineEnd, _) => &[K::End],
+        (Shortcut::DeleteWord, MacOs) => &[K::LAlt, K::BSpace],
+        (Shortcut::DeleteWord, _) => &[K::LCtrl, K::BSpace],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::*;
+
+    fn keycodes(shortcuts: &Shortcuts, layout: &[KeyCode]) -> Vec<KeyCode> {
+        shortcuts.keycodes(layout.iter().copied()).collect()
+    }
+
+    #[test]
+    fn chord_per_profile() {
+        let mut shortcuts = Shortcuts::new(OsProfile::Windows, &[]);
+        shortcuts.handle_action(&ShortcutAction::Key(Shortcut::Copy), true);
+        assert_eq!(keycodes(&shortcuts, &[KeyCode::A]), [KeyCode::A, KeyCode::LCtrl, KeyCode::C]);
+        // Profile change applies to held shortcuts
+        shortcuts.handle_action(&ShortcutAction::Profile(OsProfile::MacOs), true);
+        assert_eq!(keycodes(&shortcuts, &[]), [KeyCode::LGui, KeyCode::C]);
+        shortcuts.handle_action(&ShortcutAction::Key(Shortcut::Copy), false);
+        assert!(keycodes(&shortcuts, &[]).is_empty());
+    }
+
+    #[test]
+    fn chord_for_host_layout() {
+        // QWERTZ swaps Y and Z
+        static QWERTZ: HostKeys = &[(KeyCode::Z, KeyCode::Y), (KeyCode::Y, KeyCode::Z)];
+        let mut shortcuts = Shortcuts::new(OsProfile::Windows, QWERTZ);
+        shortcuts.handle_action(&ShortcutAction::Key(Shortcut::Undo), true);
+        assert_eq!(keycodes(&shortcuts, &[KeyCode::Z]), [KeyCode::Z, KeyCode::LCtrl, KeyCode::Y]);
+        shortcuts.handle_action(&ShortcutAction::Key(Shortcut::Undo), false);
+        shortcuts.handle_action(&ShortcutAction::Key(Shortcut::Redo), true);
+        assert_eq!(keycodes(&shortcuts, &[]), [KeyCode::LCtrl, KeyCode::Z]);
+    }
+
+    #[test]
+    fn redo_differs_between_all() {
+        let redo = |profile| chord(Shortcut::Redo, profile);
+        assert_ne!(redo(OsProfile::Windows), redo(OsProfile::Linux));
+        assert_ne!(redo(OsProfile::Windows), redo(OsProfile::MacOs));
+        assert_ne!(redo(OsProfile::Linux), redo(OsProfile::MacOs));
+    }
+
+    #[test]
+    fn cycle_profile() {
+        let mut shortcuts = Shortcuts::new(OsProfile::Linux, &[]);
+        shortcuts.handle_action(&ShortcutAction::CycleProfile, false);
+        assert_eq!(shortcuts.profile(), OsProfile::Linux);
+        shortcuts.handle_action(&ShortcutAction::CycleProfile, true);
+        assert_eq!(shortcuts.profile(), OsProfile::Windows);
+        shortcuts.handle_action(&ShortcutAction::CycleProfile, true);
+        assert_eq!(shortcuts.profile(), OsProfile::MacOs);
+    }
+
+    #[test]
+    fn held_limit() {
+        let mut shortcuts = Shortcuts::new(OsProfile::Linux, &[]);
+        let all = [Shortcut::Copy, Shortcut::Cut, Shortcut::Paste, Shortcut::Undo, Shortcut::Save];
+        for s in all {
+            shortcuts.handle_action(&ShortcutAction::Key(s), true);
+        }
+        // Repeated press is not counted twice
+        shortcuts.handle_action(&ShortcutAction::Key(Shortcut::Copy), true);
+        assert_eq!(keycodes(&shortcuts, &[]).len(), 2 * MAX_HELD);
+        for s in all {
+            shortcuts.handle_action(&ShortcutAction::Key(s), false);
+        }
+        assert!(keycodes(&shortcuts, &[]).is_empty());
+    }
+}
@@ -0,0 +1,829 @@
+use core::cell::RefCell;
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use bbqueue::{BBBuffer, Consumer, Producer};
+use rtic::Exclusive;
+use usb_device::UsbError;
+use usb_device::device::UsbDeviceState;
+use usbd_human_interface_device::UsbHidError;
+
+use crate::bsp::{NCOLS, NROWS, sides::{BoardSide, PerSide}};
+use crate::hal_ext::crc::Crc;
+use super::power::Vbus;
+use super::leds::Role;
+use super::{hid, Keyboard, KeyboardConfig, KeyMatrix, Keys, KeyActionCache, LedController, LedOutput, LedsUpdate};
+use super::{Transmitter, Receiver, UsbHost, ticks::Millis};
+use super::ticks::TickRate;
+
+/// Size of serial link queues, large enough to never overflow during a single tick
+const QUEUE_SIZE: usize = 1024;
+/// Same as in firmware
+const LED_RETRANSMISSION_MIN_TIME: u32 = 100;
+
+#[derive(Default)]
+struct MatrixState {
+    keys: [[bool; NCOLS]; NROWS],
+    wake_armed: bool,
+}
+
+/// Key matrix with keys controlled by the simulation
+///
+/// Clones share the same state, so one handle is owned by [`Keys`] and the other is used
+/// to press keys.
+#[derive(Clone, Default)]
+pub struct SimMatrix(Rc<RefCell<MatrixState>>);
+
+impl SimMatrix {
+    /// Set state of a key in side-local coordinates, key press triggers wake up
+    pub fn set(&self, (row, col): (u8, u8), pressed: bool) {
+        let mut state = self.0.borrow_mut();
+        state.keys[row as usize][col as usize] = pressed;
+        if pressed {
+            state.wake_armed = false;
+        }
+    }
+}
+
+impl KeyMatrix for SimMatrix {
+    fn read(&mut self) -> [[bool; NCOLS]; NROWS] {
+        self.0.borrow().keys
+    }
+
+    fn arm_wake(&mut self) {
+        self.0.borrow_mut().wake_armed = true;
+    }
+
+    fn disarm_wake(&mut self) {
+        self.0.borrow_mut().wake_armed = false;
+    }
+
+    fn is_wake_armed(&self) -> bool {
+        self.0.borrow().wake_armed
+    }
+
+    fn any_key_down(&self) -> bool {
+        self.0.borrow().keys.iter().flatten().any(|pressed| *pressed)
+    }
+}
+
+/// Fake USB device that records all reports sent to host
+pub struct SimUsb {
+    /// Device state, controlled by the simulation
+    pub state: UsbDeviceState,
+    /// Presence of 5 V on the USB connector, controlled by the simulation
+    pub vbus: Vbus,
+    /// Keyboard LEDs, controlled by the simulation
+    pub leds: hid::KeyboardLeds,
+    pub bootloader_allowed: bool,
+    /// Requested reboots, `true` when rebooting to bootloader
+    pub reboots: Vec<bool>,
+    /// Whether remote wake up signalling is active
+    pub remote_wakeup: bool,
+    pub keyboard_reports: Vec<hid::KeyboardReport>,
+    pub consumer_reports: Vec<hid::ConsumerReport>,
+    pub mouse_reports: Vec<hid::MouseReport>,
+    /// Report written by host software to raw HID interface, controlled by the simulation
+    pub raw_report: Option<hid::RawReport>,
+    wake_up_remaining: Millis,
+}
+
+impl SimUsb {
+    pub fn new() -> Self {
+        Self {
+            state: UsbDeviceState::Default,
+            vbus: Vbus::Unknown,
+            leds: Default::default(),
+            bootloader_allowed: false,
+            reboots: Vec::new(),
+            remote_wakeup: false,
+            keyboard_reports: Vec::new(),
+            consumer_reports: Vec::new(),
+            mouse_reports: Vec::new(),
+            raw_report: None,
+            wake_up_remaining: Millis(0),
+        }
+    }
+}
+
+impl Default for SimUsb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbHost for SimUsb {
+    fn state(&self) -> UsbDeviceState {
+        self.state
+    }
+
+    fn vbus(&mut self) -> Vbus {
+        self.vbus
+    }
+
+    fn keyboard_leds(&self) -> hid::KeyboardLeds {
+        self.leds
+    }
+
+    fn bootloader_allowed(&self) -> bool {
+        self.bootloader_allowed
+    }
+
+    fn allow_bootloader(&mut self) {
+        self.bootloader_allowed = true;
+    }
+
+    fn reboot(&mut self, bootloader: bool) {
+        self.reboots.push(bootloader);
+    }
+
+    fn wake_up_update(&mut self, wake_up: bool, duration: Millis, elapsed: Millis) {
+        if wake_up && self.wake_up_remaining == Millis(0) {
+            self.wake_up_remaining = duration;
+        } else {
+            self.wake_up_remaining = self.wake_up_remaining.saturating_sub(elapsed);
+        }
+        self.remote_wakeup = self.wake_up_remaining != Millis(0);
+    }
+
+    fn hid_tick(&mut self) {}
+
+    fn write_keyboard_report(&mut self, report: &hid::KeyboardReport) -> Result<(), UsbHidError> {
+        self.keyboard_reports.push(*report);
+        Ok(())
+    }
+
+    fn write_consumer_report(&mut self, report: &hid::ConsumerReport) -> Result<usize, UsbError> {
+        self.consumer_reports.push(*report);
+        Ok(1)
+    }
+
+    fn write_mouse_report(&mut self, report: &hid::MouseReport) -> Result<(), UsbHidError> {
+        self.mouse_reports.push(*report);
+        Ok(())
+    }
+
+    fn read_raw_report(&mut self) -> Option<hid::RawReport> {
+        self.raw_report.take()
+    }
+}
+
+/// Single keyboard half running the same logic as `keyboard_tick` and LED tasks in firmware
+///
+/// LEDs are updated on every tick (firmware uses lower frequency) and serial baud rate
+/// changes are applied immediately.
+pub struct SimHalf<const L: usize> {
+    pub keyboard: Keyboard<L, SimMatrix>,
+    pub usb: SimUsb,
+    pub leds: LedController<'static>,
+    pub output: LedOutput,
+    matrix: SimMatrix,
+    side: BoardSide,
+    time: u32,
+    crc: Crc,
+    tx: Transmitter<QUEUE_SIZE>,
+    rx: Receiver<QUEUE_SIZE>,
+    /// Serial data sent by this half
+    line_out: Consumer<'static, QUEUE_SIZE>,
+    /// Serial data to be received by this half
+    line_in: Producer<'static, QUEUE_SIZE>,
+}
+
+impl<const L: usize> SimHalf<L> {
+    pub fn new(side: BoardSide, config: &'static KeyboardConfig<L>) -> Self {
+        let (tx, line_out) = Box::leak(Box::new(BBBuffer::<QUEUE_SIZE>::new())).try_split().unwrap();
+        let (line_in, rx) = Box::leak(Box::new(BBBuffer::<QUEUE_SIZE>::new())).try_split().unwrap();
+        let actions: &'static [KeyActionCache; L] = Box::leak(Box::new(KeyActionCache::for_layers(config.layers)));
+        let matrix = SimMatrix::default();
+        let keys = Keys::new(side, matrix.clone(), &config.debounce, TickRate::new(config.tick_frequency_hz));
+        Self {
+            keyboard: Keyboard::new(keys, config),
+            usb: SimUsb::new(),
+            leds: {
+                let mut leds = LedController::new(side, &config.leds, actions);
+                leds.set_layer_configs(config.layer_leds);
+                leds.set_brightness_limits(config.led_brightness_limits);
+                leds.set_heartbeat(config.suspend_heartbeat, TickRate::new(config.tick_frequency_hz));
+                leds.set_training(&config.training);
+                leds
+            },
+            output: LedOutput::new(LED_RETRANSMISSION_MIN_TIME, config.reactive),
+            matrix,
+            side,
+            time: 0,
+            crc: Crc::new_mock(),
+            tx: Transmitter::new(tx),
+            rx: Receiver::new(rx),
+            line_out,
+            line_in,
+        }
+    }
+
+    /// Board side of this half
+    pub fn side(&self) -> BoardSide {
+        self.side
+    }
+
+    /// Key matrix of this half
+    pub fn matrix(&self) -> &SimMatrix {
+        &self.matrix
+    }
+
+    /// Current colors of LEDs on this half
+    pub fn colors(&self) -> &[rgb::RGB8] {
+        &self.output.current(self.side).colors
+    }
+
+    fn tick(&mut self) {
+        self.time = self.time.wrapping_add(1);
+
+        let update = self.keyboard.tick(
+            self.time,
+            &mut self.crc,
+            Exclusive(&mut self.tx),
+            Exclusive(&mut self.rx),
+            Exclusive(&mut self.usb),
+        );
+        self.keyboard.mouse_tick(1, Exclusive(&mut self.usb));
+
+        if self.keyboard.pending_baud_rate().is_some() {
+            self.keyboard.baud_rate_applied();
+        }
+
+        let led_time = self.keyboard.led_time(self.time);
+        match update {
+            LedsUpdate::Controller(update) => {
+                update.apply(led_time, &mut self.leds, &mut self.output);
+                self.output.use_from_controller();
+            },
+            LedsUpdate::FromOther(colors, pressed) => {
+                if let Some(colors) = colors {
+                    self.output.use_from_other_half(&colors);
+                }
+                if let Some(pressed) = pressed {
+                    self.output.set_local_pressed(pressed);
+                }
+            },
+        }
+
+        self.keyboard.take_notifications().into_iter().for_each(|n| self.output.notify(n));
+        self.output.set_blackout(self.keyboard.leds_blackout());
+        self.output.set_dwell(self.keyboard.dwell_indicator());
+        self.output.tick(led_time, &mut self.leds);
+        let transmit = self.leds.power_state().led_transmission_enabled();
+        if self.output.using_from_controller() {
+            if self.keyboard.remote_leds() {
+                let state = self.leds.controller_state()
+                    .and_then(|state| self.output.get_state_for_transmission(led_time, state));
+                if let Some(state) = state {
+                    self.tx.send(&mut self.crc, state);
+                }
+                if transmit {
+                    if let Some(overlays) = self.output.get_overlays_for_transmission(led_time, self.side.other()) {
+                        self.tx.send(&mut self.crc, overlays);
+                    }
+                }
+            } else if transmit {
+                if let Some(colors) = self.output.get_for_transmission(led_time, self.side.other()) {
+                    self.tx.send(&mut self.crc, colors);
+                }
+            }
+        }
+    }
+}
+
+/// Faults injected on the virtual serial link in one direction
+#[derive(Clone, Copy, PartialEq, Default)]
+#[cfg_attr(test, derive(Debug))]
+pub struct LinkFaults {
+    /// Probability of losing a packet in percent
+    pub loss: u8,
+    /// Number of ticks by which packets are delayed
+    pub delay: u32,
+}
+
+/// Virtual serial link in one direction
+///
+/// Data is split into packets on COBS frame delimiters, so faults affect whole packets and
+/// the receiver never sees line errors. Losses use a fixed pseudo-random sequence, so that
+/// simulations are repeatable.
+struct Link {
+    faults: LinkFaults,
+    /// Beginning of a packet that has not been fully transmitted yet
+    partial: Vec<u8>,
+    /// Packets in transit with their delivery time
+    in_transit: VecDeque<(u32, Vec<u8>)>,
+    rng: u32,
+    dropped: u32,
+}
+
+impl Link {
+    fn new(seed: u32) -> Self {
+        Self {
+            faults: LinkFaults::default(),
+            partial: Vec::new(),
+            in_transit: VecDeque::new(),
+            rng: seed.max(1),
+            dropped: 0,
+        }
+    }
+
+    /// Take all data sent until `time` and deliver packets that are due
+    fn transfer(&mut self, time: u32, from: &mut Consumer<'static, QUEUE_SIZE>, to: &mut Producer<'static, QUEUE_SIZE>) {
+        while let Ok(read) = from.read() {
+            let n = read.len();
+            for &byte in read.iter() {
+                self.partial.push(byte);
+                if byte == 0 {
+                    let packet = core::mem::take(&mut self.partial);
+                    if self.lose() {
+                        self.dropped += 1;
+                    } else {
+                        self.in_transit.push_back((time + self.faults.delay, packet));
+                    }
+                }
+            }
+            read.release(n);
+        }
+
+        while let Some((due, packet)) = self.in_transit.front() {
+            if *due > time {
+                break;
+            }
+            // Retry in the next tick if the receiver is full
+            let Ok(mut write) = to.grant_exact(packet.len()) else {
+                break;
+            };
+            write.copy_from_slice(packet);
+            write.commit(packet.len());
+            self.in_transit.pop_front();
+        }
+    }
+
+    fn lose(&mut self) -> bool {
+        if self.faults.loss == 0 {
+            return false;
+        }
+        // xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng % 100 < self.faults.loss as u32
+    }
+}
+
+/// Simulation step
+#[derive(Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Step {
+    /// Advance time by given number of ticks
+    Tick(u32),
+    /// Press key at given global coordinates
+    Press(u8, u8),
+    /// Release key at given global coordinates
+    Release(u8, u8),
+    /// Change USB state of given half
+    Usb(BoardSide, UsbDeviceState),
+    /// Set faults of the link from given half to the other one
+    Link(BoardSide, LinkFaults),
+}
+
+impl Step {
+    /// Parse a single script line, returns `None` for empty lines and comments
+    ///
+    /// Syntax: `tick N`, `press ROW COL`, `release ROW COL`, `usb left|right STATE`, where STATE
+    /// is one of `default`, `addressed`, `configured` or `suspend`, and `link left|right LOSS DELAY`
+    /// with loss of packets sent by given half in percent and their delay in ticks. Comments
+    /// start with `#`.
+    pub fn parse(line: &str) -> Result<Option<Self>, &'static str> {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some(cmd) => cmd,
+            None => return Ok(None),
+        };
+        let mut num = || -> Result<u32, &'static str> {
+            words.next().ok_or("Missing argument")?.parse().map_err(|_| "Invalid number")
+        };
+        let mut coord = || -> Result<u8, &'static str> {
+            num()?.try_into().map_err(|_| "Coordinate out of range")
+        };
+        let side = |word: Option<&str>| match word {
+            Some("left") => Ok(BoardSide::Left),
+            Some("right") => Ok(BoardSide::Right),
+            _ => Err("Invalid board side"),
+        };
+        let step = match cmd {
+            "tick" => Self::Tick(num()?),
+            "press" => Self::Press(coord()?, coord()?),
+            "release" => Self::Release(coord()?, coord()?),
+            "usb" => {
+                let mut words = line.split_whitespace().skip(1);
+                let side = side(words.next())?;
+                let state = match words.next() {
+                    Some("default") => UsbDeviceState::Default,
+                    Some("addressed") => UsbDeviceState::Addressed,
+                    Some("configured") => UsbDeviceState::Configured,
+                    Some("suspend") => UsbDeviceState::Suspend,
+                    _ => return Err("Invalid USB state"),
+                };
+                Self::Usb(side, state)
+            },
+            "link" => {
+                let mut words = line.split_whitespace().skip(1);
+                let side = side(words.next())?;
+                let mut num = || -> Result<u32, &'static str> {
+                    words.next().ok_or("Missing argument")?.parse().map_err(|_| "Invalid number")
+                };
+                let loss = num()?;
+                if loss > 100 {
+                    return Err("Loss out of range");
+                }
+                Self::Link(side, LinkFaults { loss: loss as u8, delay: num()? })
+            },
+            _ => return Err("Unknown command"),
+        };
+        Ok(Some(step))
+    }
+}
+
+/// Simulation of both keyboard halves connected with a virtual serial link
+///
+/// Allows to test keyboard logic (role negotiation, layers, LED rules) off-hardware.
+/// Each half uses its own fake USB device which is disconnected initially. The link is
+/// perfect unless faults are injected with [`Sim::set_link_faults`].
+pub struct Sim<const L: usize> {
+    pub halves: PerSide<SimHalf<L>>,
+    /// Links indexed by the transmitting half
+    links: PerSide<Link>,
+    time: u32,
+}
+
+impl<const L: usize> Sim<L> {
+    pub fn new(config: &'static KeyboardConfig<L>) -> Self {
+        Self {
+            halves: PerSide {
+                left: SimHalf::new(BoardSide::Left, config),
+                right: SimHalf::new(BoardSide::Right, config),
+            },
+            links: PerSide { left: Link::new(1), right: Link::new(2) },
+            time: 0,
+        }
+    }
+
+    /// Number of ticks since start
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    /// Advance time by one tick, serial data is delivered to the other half before next tick
+    /// unless delayed by link faults
+    pub fn tick(&mut self) {
+        self.time = self.time.wrapping_add(1);
+        let PerSide { left, right } = &mut self.halves;
+        left.tick();
+        right.tick();
+        self.links.left.transfer(self.time, &mut left.line_out, &mut right.line_in);
+        self.links.right.transfer(self.time, &mut right.line_out, &mut left.line_in);
+    }
+
+    /// Run given number of ticks
+    pub fn run(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Set key state at given global coordinates
+    pub fn set_key(&mut self, (row, col): (u8, u8), pressed: bool) {
+        let side = BoardSide::from_coords((row, col));
+        self.halves[side].matrix().set(BoardSide::coords_to_local((row, col)), pressed);
+    }
+
+    /// Set USB state of given half
+    pub fn set_usb(&mut self, side: BoardSide, state: UsbDeviceState) {
+        self.halves[side].usb.state = state;
+    }
+
+    /// Inject faults on the link from given half to the other one
+    pub fn set_link_faults(&mut self, from: BoardSide, faults: LinkFaults) {
+        self.links[from].faults = faults;
+    }
+
+    /// Number of packets sent by given half that have been lost
+    pub fn dropped_packets(&self, from: BoardSide) -> u32 {
+        self.links[from].dropped
+    }
+
+    /// Get the half that currently acts as master (the first one if both think so)
+    pub fn master(&self) -> Option<BoardSide> {
+        BoardSide::EACH.into_iter()
+            .find(|side| self.halves[*side].keyboard.role() == Role::Master)
+    }
+
+    /// Execute a single simulation step
+    pub fn step(&mut self, step: &Step) {
+        match *step {
+            Step::Tick(n) => self.run(n),
+            Step::Press(i, j) => self.set_key((i, j), true),
+            Step::Release(i, j) => self.set_key((i, j), false),
+            Step::Usb(side, state) => self.set_usb(side, state),
+            Step::Link(side, faults) => self.set_link_faults(side, faults),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keyberon::action::Action;
+    use keyberon::key_code::KeyCode;
+    use crate::config::{CONFIG, N_LAYERS};
+    use crate::keyboard::hid::KeyCodeIterExt as _;
+
+    use super::*;
+
+    fn keycode(layer: usize, (i, j): (u8, u8)) -> KeyCode {
+        match CONFIG.layers[layer][i as usize][j as usize] {
+            Action::KeyCode(kc) => kc,
+            _ => panic!("Not a key code at ({}, {})", i, j),
+        }
+    }
+
+    /// Number of ticks of [`Sim::run`] for given time
+    fn ms(ms: u32) -> u32 {
+        TickRate::new(CONFIG.tick_frequency_hz).from_ms(ms)
+    }
+
+    fn connected(side: BoardSide) -> Sim<N_LAYERS> {
+        let mut sim = Sim::new(&CONFIG);
+        sim.set_usb(side, UsbDeviceState::Configured);
+        sim.run(50);
+        sim
+    }
+
+    #[test]
+    fn parse_script() {
+        assert_eq!(Step::parse("  # comment"), Ok(None));
+        assert_eq!(Step::parse("tick 100"), Ok(Some(Step::Tick(100))));
+        assert_eq!(Step::parse("press 1 7 # U"), Ok(Some(Step::Press(1, 7))));
+        assert_eq!(Step::parse("release 4 2"), Ok(Some(Step::Release(4, 2))));
+        assert_eq!(Step::parse("usb right suspend"),
+            Ok(Some(Step::Usb(BoardSide::Right, UsbDeviceState::Suspend))));
+        assert_eq!(Step::parse("link left 20 5"),
+            Ok(Some(Step::Link(BoardSide::Left, LinkFaults { loss: 20, delay: 5 }))));
+        assert_eq!(Step::parse("link right 101 0"), Err("Loss out of range"));
+        assert_eq!(Step::parse("link up 0 0"), Err("Invalid board side"));
+        assert_eq!(Step::parse("press 1"), Err("Missing argument"));
+        assert_eq!(Step::parse("press 1 300"), Err("Coordinate out of range"));
+        assert_eq!(Step::parse("jump"), Err("Unknown command"));
+    }
+
+    #[test]
+    fn role_negotiation() {
+        let mut sim = connected(BoardSide::Left);
+        assert_eq!(sim.halves.left.keyboard.role(), Role::Master);
+        assert_eq!(sim.halves.right.keyboard.role(), Role::Slave);
+
+        // Master role is handed over when the other half gets USB
+        sim.set_usb(BoardSide::Left, UsbDeviceState::Default);
+        sim.run(50);
+        sim.set_usb(BoardSide::Right, UsbDeviceState::Configured);
+        sim.run(50);
+        assert_eq!(sim.halves.left.keyboard.role(), Role::Slave);
+        assert_eq!(sim.halves.right.keyboard.role(), Role::Master);
+    }
+
+    #[test]
+    fn key_from_slave_reported_by_master() {
+        let mut sim = connected(BoardSide::Left);
+        let key = (1, 7);
+        assert_eq!(BoardSide::from_coords(key), BoardSide::Right);
+        let expected = hid::KeyboardReport::new(core::iter::once(keycode(0, key)).into_page());
+        let empty = hid::KeyboardReport::new([]);
+
+        sim.set_key(key, true);
+        sim.run(20);
+        assert_eq!(sim.halves.left.usb.keyboard_reports.last(), Some(&expected));
+        sim.set_key(key, false);
+        sim.run(20);
+        assert_eq!(sim.halves.left.usb.keyboard_reports.last(), Some(&empty));
+        assert!(sim.halves.right.usb.keyboard_reports.is_empty());
+    }
+
+    #[test]
+    fn remote_wakeup_duration() {
+        let mut sim = connected(BoardSide::Left);
+        sim.set_key((1, 1), true);
+        let mut wakeup_ticks = 0;
+        for _ in 0..50 {
+            sim.tick();
+            wakeup_ticks += sim.halves.left.usb.remote_wakeup as u32;
+        }
+        // Configuration uses 1 kHz ticks
+        assert_eq!(CONFIG.tick_frequency_hz, 1000);
+        assert_eq!(wakeup_ticks, crate::keyboard::USB_WAKE_UP.0);
+    }
+
+    #[test]
+    fn slave_uses_state_from_master() {
+        let mut sim = connected(BoardSide::Right);
+        sim.run(LED_RETRANSMISSION_MIN_TIME * 2);
+        assert!(sim.halves.right.keyboard.remote_leds());
+        assert!(sim.halves.right.output.using_from_controller());
+        assert!(sim.halves.left.output.using_from_controller());
+        assert!(sim.halves.left.leds.controller_state().is_some());
+        assert!(sim.halves.left.leds.controller_state() == sim.halves.right.leds.controller_state());
+    }
+
+    #[test]
+    fn notification_shown_on_slave() {
+        let mut sim = connected(BoardSide::Right);
+        sim.halves.right.leds.set_brightness(255);
+        sim.run(LED_RETRANSMISSION_MIN_TIME * 2);
+        assert!(sim.halves.right.keyboard.remote_leds());
+
+        // Solid magenta on all keys for 1 second
+        let mut report = [0; hid::RAW_REPORT_SIZE];
+        report[..8].copy_from_slice(&[1, 0, 0, 255, 0, 255, 1, 0]);
+        sim.halves.right.usb.raw_report = Some(report);
+        sim.run(50);
+        for half in [&sim.halves.left, &sim.halves.right] {
+            let color = half.leds.output_color(rgb::RGB8::new(255, 0, 255));
+            assert!(half.colors().iter().all(|c| *c == color));
+        }
+
+        // Expires on both halves
+        sim.run(1000);
+        for half in [&sim.halves.left, &sim.halves.right] {
+            let color = half.leds.output_color(rgb::RGB8::new(255, 0, 255));
+            assert!(half.colors().iter().any(|c| *c != color));
+        }
+    }
+
+    #[test]
+    fn countdown_shown_on_slave() {
+        let mut sim = connected(BoardSide::Right);
+        sim.halves.right.leds.set_brightness(255);
+        sim.run(LED_RETRANSMISSION_MIN_TIME * 2);
+
+        sim.halves.right.output.set_countdown(Some(u8::MAX));
+        sim.run(50);
+        let lit = sim.halves.right.colors()[0];
+        assert!(lit != rgb::RGB8::default());
+        for half in [&sim.halves.left, &sim.halves.right] {
+            assert!(half.colors().iter().all(|c| *c == lit));
+        }
+
+        // Half of the LEDs are turned off
+        sim.halves.right.output.set_countdown(Some(u8::MAX / 2));
+        sim.run(50);
+        for half in [&sim.halves.left, &sim.halves.right] {
+            assert_eq!(half.colors()[0], lit);
+            assert_eq!(half.colors()[half.colors().len() - 1], rgb::RGB8::default());
+        }
+    }
+
+    #[test]
+    fn heartbeat_shown_on_slave_in_deep_sleep() {
+        let heartbeat = CONFIG.suspend_heartbeat;
+        let side = BoardSide::from_coords(heartbeat.key);
+        let led = BoardSide::led_number(BoardSide::coords_to_local(heartbeat.key)).unwrap() as usize;
+        let mut sim = connected(side.other());
+        sim.halves[side.other()].usb.vbus = Vbus::Present;
+        sim.run(LED_RETRANSMISSION_MIN_TIME * 2);
+        assert!(sim.halves[side.other()].keyboard.remote_leds());
+
+        sim.set_usb(side.other(), UsbDeviceState::Suspend);
+        sim.run(ms(super::super::power::DEEP_SLEEP_TIMEOUT_MS + 100));
+        for half in [&sim.halves.left, &sim.halves.right] {
+            assert!(half.leds.power_state() == super::super::PowerState::DeepSleep);
+        }
+
+        // Indicator keeps pulsing during a whole period, other LEDs stay off
+        let mut lit = false;
+        for _ in 0..ms(heartbeat.period_ms as u32) {
+            sim.tick();
+            let colors = sim.halves[side].colors();
+            lit |= colors[led] != rgb::RGB8::default();
+            assert!(colors.iter().enumerate().all(|(i, c)| i == led || *c == rgb::RGB8::default()));
+        }
+        assert!(lit);
+    }
+
+    #[test]
+    fn led_time_in_phase_with_master() {
+        let mut sim = Sim::new(&CONFIG);
+        // Slave has been running for longer
+        sim.halves.left.time = 12_345;
+        sim.halves.left.keyboard.time = 12_345;
+        sim.set_usb(BoardSide::Right, UsbDeviceState::Configured);
+        for side in BoardSide::EACH {
+            sim.set_link_faults(side, LinkFaults { loss: 0, delay: 10 });
+        }
+        sim.run(ms(3 * super::super::time_sync::SYNC_PERIOD_MS));
+        let PerSide { left, right } = &mut sim.halves;
+        let master = right.keyboard.led_time(right.time);
+        let slave = left.keyboard.led_time(left.time);
+        // Latency is compensated up to tick granularity of message processing
+        assert!(master.abs_diff(slave) <= 1, "master {} slave {}", master, slave);
+    }
+
+    #[test]
+    fn link_delay_and_loss() {
+        let (mut tx, mut line_out) = Box::leak(Box::new(BBBuffer::<QUEUE_SIZE>::new())).try_split().unwrap();
+        let (mut line_in, mut rx) = Box::leak(Box::new(BBBuffer::<QUEUE_SIZE>::new())).try_split().unwrap();
+        let mut send = |data: &[u8]| {
+            let mut grant = tx.grant_exact(data.len()).unwrap();
+            grant.copy_from_slice(data);
+            grant.commit(data.len());
+        };
+        let mut receive = || rx.read().map(|grant| {
+            let data = grant.to_vec();
+            grant.release(data.len());
+            data
+        }).unwrap_or_default();
+
+        let mut link = Link::new(1);
+        link.faults = LinkFaults { loss: 0, delay: 2 };
+        send(&[1, 2, 0, 3]);
+        link.transfer(10, &mut line_out, &mut line_in);
+        link.transfer(11, &mut line_out, &mut line_in);
+        assert!(receive().is_empty());
+        link.transfer(12, &mut line_out, &mut line_in);
+        assert_eq!(receive(), [1, 2, 0]);
+        // Packet is delayed since its end has been transmitted
+        send(&[0]);
+        link.transfer(13, &mut line_out, &mut line_in);
+        assert!(receive().is_empty());
+        link.transfer(15, &mut line_out, &mut line_in);
+        assert_eq!(receive(), [3, 0]);
+
+        link.faults = LinkFaults { loss: 100, delay: 0 };
+        send(&[4, 0, 5, 0]);
+        link.transfer(16, &mut line_out, &mut line_in);
+        assert!(receive().is_empty());
+        assert_eq!(link.dropped, 2);
+    }
+
+    #[test]
+    fn key_forwarding_with_packet_loss() {
+        let mut sim = connected(BoardSide::Left);
+        sim.set_link_faults(BoardSide::Right, LinkFaults { loss: 30, delay: 3 });
+        for key in [(1, 7), (2, 8), (1, 9), (2, 8)] {
+            sim.set_key(key, true);
+            sim.run(30);
+            sim.set_key(key, false);
+            sim.run(30);
+        }
+        sim.set_key((1, 7), true);
+        sim.run(30);
+        assert!(sim.dropped_packets(BoardSide::Right) > 0);
+
+        // Lost presses and releases are fixed by periodic resynchronization of pressed keys
+        sim.set_link_faults(BoardSide::Right, LinkFaults::default());
+        sim.run(ms(super::super::PRESSED_RESYNC_MS + 100));
+        let expected = hid::KeyboardReport::new(core::iter::once(keycode(0, (1, 7))).into_page());
+        assert_eq!(sim.halves.left.usb.keyboard_reports.last(), Some(&expected));
+        sim.set_key((1, 7), false);
+        sim.run(20);
+        assert_eq!(sim.halves.left.usb.keyboard_reports.last(), Some(&hid::KeyboardReport::new([])));
+    }
+
+    #[test]
+    fn led_state_after_link_outage() {
+        let mut sim = connected(BoardSide::Right);
+        sim.run(LED_RETRANSMISSION_MIN_TIME * 2);
+        assert!(sim.halves.right.keyboard.remote_leds());
+
+        // State changes on master do not reach slave
+        sim.set_link_faults(BoardSide::Right, LinkFaults { loss: 100, delay: 0 });
+        sim.set_key((1, 7), true);
+        sim.run(50);
+        assert!(sim.halves.left.leds.controller_state() != sim.halves.right.leds.controller_state());
+
+        // Master retransmits its state periodically
+        sim.set_link_faults(BoardSide::Right, LinkFaults::default());
+        sim.run(LED_RETRANSMISSION_MIN_TIME * 2);
+        assert!(sim.halves.left.leds.controller_state() == sim.halves.right.leds.controller_state());
+    }
+
+    #[test]
+    fn role_flapping() {
+        let mut sim = Sim::new(&CONFIG);
+        for side in BoardSide::EACH {
+            sim.set_link_faults(side, LinkFaults { loss: 0, delay: 5 });
+        }
+        for side in [BoardSide::Left, BoardSide::Right, BoardSide::Left, BoardSide::Right] {
+            sim.set_usb(side, UsbDeviceState::Configured);
+            sim.run(100);
+            assert_eq!(sim.halves[side].keyboard.role(), Role::Master);
+            assert_eq!(sim.halves[side.other()].keyboard.role(), Role::Slave);
+            // Master keeps its role until the other half gets USB
+            sim.set_usb(side, UsbDeviceState::Default);
+            sim.run(100);
+            assert_eq!(sim.halves[side].keyboard.role(), Role::Master);
+        }
+    }
+}
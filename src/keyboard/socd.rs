@@ -0,0 +1,151 @@
+use keyberon::key_code::KeyCode;
+
+/// Maximum number of key pairs handled, configurations with more pairs are rejected
+pub const MAX_PAIRS: usize = 8;
+
+/// Resolution of simultaneous opposite cardinal directions (SOCD)
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum SocdMode {
+    /// Key pressed most recently overrides the other one ("snap tap")
+    LastWins,
+    /// Neither key is reported while both are held
+    Neutral,
+    /// Key pressed first is kept until released
+    FirstWins,
+}
+
+/// Pair of keys for opposite directions, e.g. A/D or W/S
+pub struct SocdPair {
+    pub keys: [KeyCode; 2],
+    pub mode: SocdMode,
+}
+
+/// Configuration of SOCD resolution for designated key pairs
+pub type SocdConfig = &'static [SocdPair];
+
+#[derive(Clone, Copy)]
+struct PairState {
+    held: [bool; 2],
+    /// Index of the key that has been pressed most recently
+    last: usize,
+}
+
+/// Resolves simultaneous presses of opposite direction keys before report generation
+pub struct Socd {
+    pairs: SocdConfig,
+    state: [PairState; MAX_PAIRS],
+}
+
+impl PairState {
+    fn update(&mut self, held: [bool; 2]) {
+        for (i, (was, is)) in self.held.iter().zip(held).enumerate() {
+            if is && !was {
+                self.last = i;
+            }
+        }
+        self.held = held;
+    }
+
+    /// Check if key with given index should be suppressed
+    fn suppressed(&self, mode: SocdMode, i: usize) -> bool {
+        if !(self.held[0] && self.held[1]) {
+            return false;
+        }
+        match mode {
+            SocdMode::LastWins => i != self.last,
+            SocdMode::Neutral => true,
+            SocdMode::FirstWins => i == self.last,
+        }
+    }
+}
+
+impl Socd {
+    pub const fn new(pairs: SocdConfig) -> Self {
+        Self {
+            pairs,
+            state: [PairState { held: [false; 2], last: 0 }; MAX_PAIRS],
+        }
+    }
+
+    fn pairs(&self) -> impl Iterator<Item = (&SocdPair, &PairState)> {
+        self.pairs.iter().zip(self.state.iter())
+    }
+
+    /// Update state of key pairs from currently active key codes, needs to be called once per tick
+    pub fn update(&mut self, keycodes: impl Iterator<Item = KeyCode>) {
+        let mut held = [[false; 2]; MAX_PAIRS];
+        for kc in keycodes {
+            for (pair, held) in self.pairs.iter().zip(held.iter_mut()) {
+                for (key, held) in pair.keys.iter().zip(held.iter_mut()) {
+                    *held |= *key == kc;
+                }
+            }
+        }
+        for (state, held) in self.state.iter_mut().zip(held) {
+            state.update(held);
+        }
+    }
+
+    /// Filter out key codes suppressed by SOCD resolution
+    pub fn keycodes<'a>(&'a self, keycodes: impl Iterator<Item = KeyCode> + 'a) -> impl Iterator<Item = KeyCode> + 'a {
+        keycodes.filter(move |kc| {
+            !self.pairs().any(|(pair, state)| {
+                pair.keys.iter()
+                    .position(|key| key == kc)
+                    .map_or(false, |i| state.suppressed(pair.mode, i))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::*;
+
+    static PAIRS: &[SocdPair] = &[
+        SocdPair { keys: [KeyCode::A, KeyCode::D], mode: SocdMode::LastWins },
+        SocdPair { keys: [KeyCode::W, KeyCode::S], mode: SocdMode::Neutral },
+        SocdPair { keys: [KeyCode::Left, KeyCode::Right], mode: SocdMode::FirstWins },
+    ];
+
+    fn report(socd: &mut Socd, keycodes: &[KeyCode]) -> Vec<KeyCode> {
+        socd.update(keycodes.iter().copied());
+        socd.keycodes(keycodes.iter().copied()).collect()
+    }
+
+    #[test]
+    fn last_wins() {
+        let mut socd = Socd::new(PAIRS);
+        assert_eq!(report(&mut socd, &[KeyCode::A]), [KeyCode::A]);
+        assert_eq!(report(&mut socd, &[KeyCode::A, KeyCode::D]), [KeyCode::D]);
+        assert_eq!(report(&mut socd, &[KeyCode::D]), [KeyCode::D]);
+        // Re-pressed while the other one is still held
+        assert_eq!(report(&mut socd, &[KeyCode::A, KeyCode::D]), [KeyCode::A]);
+        assert_eq!(report(&mut socd, &[KeyCode::A]), [KeyCode::A]);
+    }
+
+    #[test]
+    fn neutral() {
+        let mut socd = Socd::new(PAIRS);
+        assert_eq!(report(&mut socd, &[KeyCode::W, KeyCode::Space]), [KeyCode::W, KeyCode::Space]);
+        assert_eq!(report(&mut socd, &[KeyCode::W, KeyCode::Space, KeyCode::S]), [KeyCode::Space]);
+        assert_eq!(report(&mut socd, &[KeyCode::S]), [KeyCode::S]);
+    }
+
+    #[test]
+    fn first_wins() {
+        let mut socd = Socd::new(PAIRS);
+        assert_eq!(report(&mut socd, &[KeyCode::Right]), [KeyCode::Right]);
+        assert_eq!(report(&mut socd, &[KeyCode::Left, KeyCode::Right]), [KeyCode::Right]);
+        assert_eq!(report(&mut socd, &[KeyCode::Left]), [KeyCode::Left]);
+    }
+
+    #[test]
+    fn pairs_independent() {
+        let mut socd = Socd::new(PAIRS);
+        report(&mut socd, &[KeyCode::A, KeyCode::W]);
+        assert_eq!(report(&mut socd, &[KeyCode::A, KeyCode::W, KeyCode::D]), [KeyCode::W, KeyCode::D]);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::bsp::{NCOLS, NROWS};
+use super::ticks::Millis;
+
+/// Minimum time between writes of statistics to flash when keys are being pressed
+pub const FLUSH_PERIOD: Millis = Millis(60 * 60 * 1000);
+
+/// First word of a valid record, change when record layout changes
+const RECORD_MAGIC: u32 = 0x6b73_7401;
+/// Value of erased flash
+const ERASED: u32 = 0xffff_ffff;
+
+/// Opt-in key usage statistics for data-driven keymap optimization
+///
+/// Counts presses of each key and time spent on each layer. Collection is disabled by default
+/// for privacy. Counters are kept in RAM and periodically written to flash as records, each
+/// record appended after the previous one so that the page is erased only when it is full.
+/// Writing to flash itself is left to the caller, see [`Self::take_flush`] and [`Self::record`].
+pub struct KeyStats<const L: usize> {
+    enabled: bool,
+    presses: [[u32; 2 * NCOLS]; NROWS],
+    layer_ms: [u32; L],
+    /// Keys have been pressed since the last flush
+    dirty: bool,
+    since_flush: Millis,
+    flush: Option<Flush>,
+}
+
+/// Pending write of statistics to flash
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Flush {
+    /// Append a record after the previous ones
+    Append,
+    /// Erase all previous records before writing
+    Erase,
+}
+
+impl<const L: usize> KeyStats<L> {
+    /// Number of words in a record: magic, enabled, presses, layer times, checksum
+    pub const RECORD_LEN: usize = 2 + NROWS * 2 * NCOLS + L + 1;
+
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            presses: [[0; 2 * NCOLS]; NROWS],
+            layer_ms: [0; L],
+            dirty: false,
+            since_flush: Millis(0),
+            flush: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enable/disable collection, the state is written to flash immediately
+    pub fn set_enabled(&mut self, enabled: bool) {
+        defmt::info!("Key statistics: {=bool}", enabled);
+        self.enabled = enabled;
+        self.request(Flush::Append);
+    }
+
+    /// Clear all counters and erase previous records from flash
+    pub fn wipe(&mut self) {
+        defmt::info!("Wiping key statistics");
+        self.presses = [[0; 2 * NCOLS]; NROWS];
+        self.layer_ms = [0; L];
+        self.request(Flush::Erase);
+    }
+
+    fn request(&mut self, flush: Flush) {
+        if self.flush != Some(Flush::Erase) {
+            self.flush = Some(flush);
+        }
+    }
+
+    /// Count press of a key in global coordinates
+    pub fn on_press(&mut self, (i, j): (u8, u8)) {
+        if !self.enabled {
+            return;
+        }
+        if let Some(count) = self.presses.get_mut(i as usize).and_then(|row| row.get_mut(j as usize)) {
+            *count = count.saturating_add(1);
+            self.dirty = true;
+        }
+    }
+
+    /// Advance time while `layer` is active
+    pub fn tick(&mut self, layer: usize, elapsed: Millis) {
+        if !self.enabled {
+            return;
+        }
+        if let Some(ms) = self.layer_ms.get_mut(layer) {
+            *ms = ms.saturating_add(elapsed.0);
+        }
+        self.since_flush = Millis(self.since_flush.0.saturating_add(elapsed.0));
+        if self.dirty && self.since_flush >= FLUSH_PERIOD {
+            self.request(Flush::Append);
+        }
+    }
+
+    /// Check if statistics should be written to flash
+    pub fn flush_pending(&self) -> bool {
+        self.flush.is_some()
+    }
+
+    /// Pending flush, taken with [`Self::take_flush`] when the record has been written
+    pub fn pending_flush(&self) -> Option<Flush> {
+        self.flush
+    }
+
+    /// Flash page has been erased, so the pending flush only needs to append the record
+    pub fn page_erased(&mut self) {
+        if self.flush.is_some() {
+            self.flush = Some(Flush::Append);
+        }
+    }
+
+    /// Take pending flush, the caller should then write [`Self::record`] to flash
+    pub fn take_flush(&mut self) -> Option<Flush> {
+        let flush = self.flush.take();
+        if flush.is_some() {
+            self.dirty = false;
+            self.since_flush = Millis(0);
+        }
+        flush
+    }
+
+    /// Number of presses of keys that have been pressed at least once
+    pub fn presses(&self) -> impl Iterator<Item = ((u8, u8), u32)> + '_ {
+        self.presses.iter().enumerate()
+            .flat_map(|(i, row)| row.iter().enumerate().map(move |(j, n)| ((i as u8, j as u8), *n)))
+            .filter(|(_, n)| *n != 0)
+    }
+
+    /// Total time spent on each layer
+    pub fn layer_time(&self) -> impl Iterator<Item = Millis> + '_ {
+        self.layer_ms.iter().map(|ms| Millis(*ms))
+    }
+
+    fn data(&self) -> impl Iterator<Item = u32> + Clone + '_ {
+        [RECORD_MAGIC, self.enabled as u32].into_iter()
+            .chain(self.presses.iter().flatten().copied())
+            .chain(self.layer_ms.iter().copied())
+    }
+
+    /// Serialize statistics as a flash record of [`Self::RECORD_LEN`] words
+    pub fn record(&self) -> impl Iterator<Item = u32> + '_ {
+        let checksum = checksum(self.data());
+        self.data().chain(core::iter::once(checksum))
+    }
+
+    /// Restore statistics from a flash record, returns `false` if the record is invalid
+    pub fn load(&mut self, record: &[u32]) -> bool {
+        if record.len() != Self::RECORD_LEN || !is_valid(record) {
+            return false;
+        }
+        let data = &record[..record.len() - 1];
+        let mut words = data[2..].iter();
+        self.enabled = data[1] != 0;
+        self.presses.iter_mut().flatten()
+            .chain(self.layer_ms.iter_mut())
+            .zip(&mut words)
+            .for_each(|(value, word)| *value = *word);
+        true
+    }
+}
+
+impl<const L: usize> Default for KeyStats<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Latest valid record in a flash page with records of `len` words
+///
+/// A record is torn if power is lost while it is being written, then the previous one is used.
+pub fn latest_record(page: &[u32], len: usize) -> Option<&[u32]> {
+    page.chunks_exact(len)
+        .take_while(|slot| slot.iter().any(|w| *w != ERASED))
+        .filter(|record| is_valid(record))
+        .last()
+}
+
+/// Offset in words of the first erased record slot, `None` if the page must be erased
+pub fn free_slot(page: &[u32], len: usize) -> Option<usize> {
+    page.chunks_exact(len)
+        .position(|slot| slot.iter().all(|w| *w == ERASED))
+        .map(|i| i * len)
+}
+
+/// Check record magic and checksum
+fn is_valid(record: &[u32]) -> bool {
+    match record {
+        [RECORD_MAGIC, data @ .., sum] => checksum([RECORD_MAGIC].into_iter().chain(data.iter().copied())) == *sum,
+        _ => false,
+    }
+}
+
+fn checksum(words: impl Iterator<Item = u32>) -> u32 {
+    words.fold(0x5a5a_5a5a, |acc, w| acc.rotate_left(7) ^ w)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::*;
+
+    #[test]
+    fn opt_in() {
+        let mut stats = KeyStats::<2>::new();
+        stats.on_press((0, 1));
+        stats.tick(1, Millis(10));
+        assert_eq!(stats.presses().count(), 0);
+        assert_eq!(stats.take_flush(), None);
+
+        stats.set_enabled(true);
+        assert_eq!(stats.take_flush(), Some(Flush::Append));
+        stats.on_press((0, 1));
+        stats.on_press((0, 1));
+        stats.on_press((4, 11));
+        // Out of range
+        stats.on_press((5, 0));
+        stats.tick(1, Millis(10));
+        stats.tick(0, Millis(5));
+        assert_eq!(stats.presses().collect::<Vec<_>>(), [((0, 1), 2), ((4, 11), 1)]);
+        assert_eq!(stats.layer_time().collect::<Vec<_>>(), [Millis(5), Millis(10)]);
+    }
+
+    #[test]
+    fn periodic_flush() {
+        let mut stats = KeyStats::<2>::new();
+        stats.set_enabled(true);
+        stats.take_flush();
+        // Not flushed without key presses
+        stats.tick(0, FLUSH_PERIOD);
+        assert_eq!(stats.take_flush(), None);
+        stats.on_press((1, 1));
+        stats.tick(0, Millis(1));
+        assert_eq!(stats.take_flush(), Some(Flush::Append));
+        stats.on_press((1, 1));
+        stats.tick(0, Millis(FLUSH_PERIOD.0 - 1));
+        assert_eq!(stats.take_flush(), None);
+        stats.tick(0, Millis(1));
+        assert_eq!(stats.take_flush(), Some(Flush::Append));
+    }
+
+    #[test]
+    fn wipe() {
+        let mut stats = KeyStats::<2>::new();
+        stats.set_enabled(true);
+        stats.on_press((1, 1));
+        stats.wipe();
+        stats.set_enabled(true);
+        assert_eq!(stats.take_flush(), Some(Flush::Erase));
+        assert_eq!(stats.presses().count(), 0);
+        assert!(stats.is_enabled());
+    }
+
+    #[test]
+    fn erase_then_append() {
+        let mut stats = KeyStats::<2>::new();
+        stats.page_erased();
+        assert_eq!(stats.pending_flush(), None);
+        stats.wipe();
+        assert_eq!(stats.pending_flush(), Some(Flush::Erase));
+        stats.page_erased();
+        assert_eq!(stats.pending_flush(), Some(Flush::Append));
+        assert_eq!(stats.take_flush(), Some(Flush::Append));
+        assert_eq!(stats.pending_flush(), None);
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let mut stats = KeyStats::<3>::new();
+        stats.set_enabled(true);
+        stats.on_press((2, 3));
+        stats.tick(2, Millis(100));
+        let record: Vec<u32> = stats.record().collect();
+        assert_eq!(record.len(), KeyStats::<3>::RECORD_LEN);
+
+        let mut loaded = KeyStats::<3>::new();
+        assert!(loaded.load(&record));
+        assert!(loaded.is_enabled());
+        assert_eq!(loaded.presses().collect::<Vec<_>>(), [((2, 3), 1)]);
+        assert_eq!(loaded.layer_time().nth(2), Some(Millis(100)));
+
+        let mut corrupted = record.clone();
+        corrupted[5] ^= 1;
+        assert!(!KeyStats::<3>::new().load(&corrupted));
+        // Different number of layers
+        assert!(!KeyStats::<2>::new().load(&record));
+    }
+
+    /// Valid record of 3 words
+    fn record(value: u32) -> [u32; 3] {
+        [RECORD_MAGIC, value, checksum([RECORD_MAGIC, value].into_iter())]
+    }
+
+    #[test]
+    fn record_slots() {
+        let len = 3;
+        let mut page = [ERASED; 11];
+        assert_eq!(latest_record(&page, len), None);
+        assert_eq!(free_slot(&page, len), Some(0));
+        page[..3].copy_from_slice(&record(1));
+        assert_eq!(latest_record(&page, len), Some(&record(1)[..]));
+        assert_eq!(free_slot(&page, len), Some(3));
+        page[3..6].copy_from_slice(&record(2));
+        assert_eq!(latest_record(&page, len), Some(&record(2)[..]));
+        // Torn record falls back to the previous one, next record is appended after it
+        page[6..9].copy_from_slice(&[RECORD_MAGIC, 3, ERASED]);
+        assert_eq!(latest_record(&page, len), Some(&record(2)[..]));
+        // Remaining 2 words cannot hold a record
+        assert_eq!(free_slot(&page, len), None);
+    }
+}
@@ -0,0 +1,85 @@
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Event;
+use ufmt::uwrite;
+
+/// Key tester mode used for QA of newly assembled boards
+///
+/// While active, normal keyboard reports are not sent. Each key press is logged with
+/// its global coordinates and the key codes that it would generate. Optionally the
+/// coordinates are typed as text, so that the test can be done without a debug probe.
+/// LEDs of pressed keys are lit white by the LED controller.
+pub struct KeyTester {
+    typing: bool,
+    /// Bitmask of key codes active during previous tick
+    prev_keycodes: [u32; 8],
+}
+
+impl KeyTester {
+    pub const fn new(typing: bool) -> Self {
+        Self { typing, prev_keycodes: [0; 8] }
+    }
+
+    /// Log key event, returns text to be typed if typing is enabled
+    pub fn on_event(&self, event: Event) -> Option<heapless::String<8>> {
+        let (i, j) = match event {
+            Event::Press(i, j) => (i, j),
+            Event::Release(..) => return None,
+        };
+        defmt::info!("Key tester: press ({=u8}, {=u8})", i, j);
+        self.typing.then(|| {
+            let mut text = heapless::String::new();
+            // Cannot fail: at most "255,255\n"
+            uwrite!(text, "{},{}\n", i, j).ok();
+            text
+        })
+    }
+
+    /// Log key codes that became active since last call
+    pub fn on_keycodes(&mut self, keycodes: impl Iterator<Item = KeyCode>) {
+        let mut current = [0u32; 8];
+        for kc in keycodes {
+            let kc = kc as u8;
+            current[kc as usize / 32] |= 1 << (kc % 32);
+        }
+        for kc in Self::new_keycodes(&self.prev_keycodes, &current) {
+            defmt::info!("Key tester: keycode 0x{=u8:02x}", kc);
+        }
+        self.prev_keycodes = current;
+    }
+
+    fn new_keycodes<'a>(prev: &'a [u32; 8], current: &'a [u32; 8]) -> impl Iterator<Item = u8> + 'a {
+        (0..=u8::MAX).filter(move |kc| {
+            let (word, bit) = (*kc as usize / 32, kc % 32);
+            let new = current[word] & !prev[word];
+            new & (1 << bit) != 0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn type_coordinates() {
+        let tester = KeyTester::new(true);
+        assert_eq!(tester.on_event(Event::Press(4, 11)).unwrap().as_str(), "4,11\n");
+        assert_eq!(tester.on_event(Event::Release(4, 11)), None);
+        assert_eq!(KeyTester::new(false).on_event(Event::Press(0, 0)), None);
+    }
+
+    #[test]
+    fn detect_new_keycodes() {
+        let mut tester = KeyTester::new(false);
+        tester.on_keycodes([KeyCode::A, KeyCode::LShift].into_iter());
+        let prev = tester.prev_keycodes;
+        let mut current = [0; 8];
+        for kc in [KeyCode::A, KeyCode::B] {
+            current[kc as usize / 32] |= 1 << (kc as u8 % 32);
+        }
+        let new: Vec<_> = KeyTester::new_keycodes(&prev, &current).collect();
+        assert_eq!(new, [KeyCode::B as u8]);
+    }
+}
@@ -0,0 +1,147 @@
+/// Frequency of keyboard ticks used to convert between ticks and real time
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct TickRate {
+    hz: u32,
+}
+
+/// Duration in milliseconds
+///
+/// Used for values passed to code that works in real time (e.g. USB) to avoid confusing
+/// them with ticks, which have different length depending on [`TickRate`].
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Millis(pub u32);
+
+/// Counts whole milliseconds elapsed over consecutive ticks
+///
+/// Used to drive code that expects to be called every 1 ms (e.g. USB classes) when tick
+/// period is different. Remainder is carried over, so no time is lost over long periods.
+pub struct MsCounter {
+    rate: TickRate,
+    remainder: u32,
+}
+
+/// Counts whole ticks elapsed over measured real time
+///
+/// Inverse of [`MsCounter`], used by tasks that are not driven by the tick timer but still
+/// need to express elapsed time in ticks. Remainder is carried over as in [`MsCounter`].
+pub struct TickCounter {
+    rate: TickRate,
+    remainder: u64,
+}
+
+impl TickRate {
+    pub const fn new(hz: u32) -> Self {
+        // Avoid division by 0
+        Self { hz: if hz == 0 { 1 } else { hz } }
+    }
+
+    pub const fn hz(&self) -> u32 {
+        self.hz
+    }
+
+    /// Number of ticks taking approximately given time, but at least 1 tick
+    pub const fn from_ms(&self, ms: u32) -> u32 {
+        let ticks = (ms as u64 * self.hz as u64 / 1000) as u32;
+        if ticks == 0 { 1 } else { ticks }
+    }
+
+    /// Time taken by given number of ticks in milliseconds, rounded up
+    pub const fn to_ms(&self, ticks: u32) -> u32 {
+        ((ticks as u64 * 1000 + self.hz as u64 - 1) / self.hz as u64) as u32
+    }
+}
+
+impl Millis {
+    /// Value for APIs that use 16-bit milliseconds, saturating on overflow
+    pub const fn as_u16(&self) -> u16 {
+        if self.0 > u16::MAX as u32 { u16::MAX } else { self.0 as u16 }
+    }
+
+    pub const fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+}
+
+impl MsCounter {
+    pub const fn new(rate: TickRate) -> Self {
+        Self { rate, remainder: 0 }
+    }
+
+    /// Advance time by given number of ticks, returns number of whole milliseconds elapsed
+    pub fn tick(&mut self, ticks: u32) -> Millis {
+        let total = self.remainder as u64 + ticks as u64 * 1000;
+        let hz = self.rate.hz() as u64;
+        self.remainder = (total % hz) as u32;
+        Millis((total / hz) as u32)
+    }
+}
+
+impl TickCounter {
+    pub const fn new(rate: TickRate) -> Self {
+        Self { rate, remainder: 0 }
+    }
+
+    /// Advance time by given number of microseconds, returns number of whole ticks elapsed
+    pub fn advance(&mut self, us: u32) -> u32 {
+        let total = self.remainder + us as u64 * self.rate.hz() as u64;
+        self.remainder = total % 1_000_000;
+        (total / 1_000_000) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_from_ms() {
+        assert_eq!(TickRate::new(1000).from_ms(9), 9);
+        assert_eq!(TickRate::new(2000).from_ms(9), 18);
+        assert_eq!(TickRate::new(500).from_ms(9), 4);
+        assert_eq!(TickRate::new(500).from_ms(1), 1);
+        assert_eq!(TickRate::new(0).from_ms(1000), 1);
+    }
+
+    #[test]
+    fn ms_from_ticks() {
+        assert_eq!(TickRate::new(1000).to_ms(9), 9);
+        assert_eq!(TickRate::new(2000).to_ms(9), 5);
+        assert_eq!(TickRate::new(500).to_ms(9), 18);
+        assert_eq!(TickRate::new(3000).to_ms(1), 1);
+    }
+
+    #[test]
+    fn count_ms() {
+        let count = |hz, n| {
+            let mut counter = MsCounter::new(TickRate::new(hz));
+            (0..n).map(|_| counter.tick(1).0).collect::<std::vec::Vec<_>>()
+        };
+        assert_eq!(count(1000, 3), [1, 1, 1]);
+        assert_eq!(count(500, 3), [2, 2, 2]);
+        assert_eq!(count(2000, 4), [0, 1, 0, 1]);
+        assert_eq!(count(3000, 6), [0, 0, 1, 0, 0, 1]);
+        let mut counter = MsCounter::new(TickRate::new(1000));
+        assert_eq!(counter.tick(10), Millis(10));
+    }
+
+    #[test]
+    fn count_ticks() {
+        let count = |hz, us, n| {
+            let mut counter = TickCounter::new(TickRate::new(hz));
+            (0..n).map(|_| counter.advance(us)).collect::<std::vec::Vec<_>>()
+        };
+        assert_eq!(count(1000, 1000, 3), [1, 1, 1]);
+        assert_eq!(count(1000, 400, 5), [0, 0, 1, 0, 1]);
+        assert_eq!(count(2000, 1000, 2), [2, 2]);
+        assert_eq!(count(1000, 2_500, 2), [2, 3]);
+    }
+
+    #[test]
+    fn millis_u16() {
+        assert_eq!(Millis(1000).as_u16(), 1000);
+        assert_eq!(Millis(70_000).as_u16(), u16::MAX);
+        assert_eq!(Millis(5).saturating_sub(Millis(9)), Millis(0));
+    }
+}
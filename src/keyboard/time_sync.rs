@@ -0,0 +1,145 @@
+use super::ticks::TickRate;
+
+/// Period of time synchronization requests sent by slave
+pub const SYNC_PERIOD_MS: u32 = 1000;
+/// Error above which LED time is set to master time instead of slewing towards it
+pub const RESYNC_THRESHOLD_MS: u32 = 500;
+
+/// Synchronization of LED animation time between halves
+///
+/// Each half counts time using its own oscillator, so animations generated locally on slave
+/// slowly drift apart from the ones on master. Slave periodically requests keyboard time of
+/// master and adjusts the offset of its LED time so that it follows master time.
+///
+/// Link latency is estimated as half of the round-trip time of a request, assuming that it is
+/// the same in both directions, and master time is advanced by it. The latency is also used
+/// to start patterns on master state changes in phase with master, see [`Self::latency`].
+/// Corrections are applied gradually and LED time never goes backwards, it is held instead
+/// until local time catches up. Only when synchronization is (re)established it jumps.
+pub struct TimeSync {
+    /// Added to local time to get LED time
+    offset: u32,
+    /// Offset has been established from master time
+    synced: bool,
+    /// Last LED time returned, used to keep it monotonic
+    last: Option<u32>,
+    /// Estimated one-way link latency in ticks
+    latency: u32,
+    /// [`RESYNC_THRESHOLD_MS`] in ticks
+    resync_threshold: u32,
+}
+
+impl TimeSync {
+    pub const fn new(tick_rate: TickRate) -> Self {
+        Self {
+            offset: 0,
+            synced: false,
+            last: None,
+            latency: 0,
+            resync_threshold: tick_rate.from_ms(RESYNC_THRESHOLD_MS),
+        }
+    }
+
+    /// Handle `master` time received at local time `local` in response to request sent at `request`
+    pub fn on_rx(&mut self, request: u32, master: u32, local: u32) {
+        let round_trip = local.wrapping_sub(request);
+        if round_trip > self.resync_threshold {
+            // Response to a lost request or the link is congested, latency estimate would be off
+            return;
+        }
+        self.latency = round_trip / 2;
+        let master = master.wrapping_add(self.latency);
+        let error = master.wrapping_sub(local.wrapping_add(self.offset)) as i32;
+        if self.synced && error.unsigned_abs() <= self.resync_threshold {
+            // Rounds towards zero, so 1 tick of jitter is ignored
+            self.offset = self.offset.wrapping_add_signed(error / 2);
+        } else {
+            defmt::info!("Time sync: offset {=u32}, latency {=u32}", master.wrapping_sub(local), self.latency);
+            self.offset = master.wrapping_sub(local);
+            self.synced = true;
+            self.last = None;
+        }
+    }
+
+    /// Estimated time between sending a message by master and receiving it
+    pub fn latency(&self) -> u32 {
+        self.latency
+    }
+
+    /// Convert local time to LED time
+    pub fn led_time(&mut self, local: u32) -> u32 {
+        let time = local.wrapping_add(self.offset);
+        let time = match self.last {
+            Some(last) if (time.wrapping_sub(last) as i32) < 0 => last,
+            _ => time,
+        };
+        self.last = Some(time);
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: TickRate = TickRate::new(1000);
+
+    #[test]
+    fn follows_master_time() {
+        let mut sync = TimeSync::new(RATE);
+        assert_eq!(sync.led_time(100), 100);
+        // Response takes 4 ticks, so master time is 2 ticks later when received
+        sync.on_rx(96, 50_000, 100);
+        assert_eq!(sync.latency(), 2);
+        assert_eq!(sync.led_time(101), 50_003);
+        // Same rate, nothing to correct
+        sync.on_rx(1096, 51_000, 1100);
+        assert_eq!(sync.led_time(1100), 51_002);
+    }
+
+    #[test]
+    fn compensate_slower_clock() {
+        let mut sync = TimeSync::new(RATE);
+        sync.on_rx(0, 0, 0);
+        // Master advances 1000 ticks while we only 990
+        let mut local = 0;
+        for i in 1..=20 {
+            local += 990;
+            sync.on_rx(local, i * 1000, local);
+        }
+        let error = (20 * 1000 - sync.led_time(local) as i32).abs();
+        assert!(error <= 20, "error = {}", error);
+    }
+
+    #[test]
+    fn monotonic_when_slowing_down() {
+        let mut sync = TimeSync::new(RATE);
+        sync.on_rx(0, 1000, 0);
+        assert_eq!(sync.led_time(100), 1100);
+        // We are 20 ticks ahead of master
+        sync.on_rx(100, 1080, 100);
+        assert_eq!(sync.led_time(101), 1100);
+        assert_eq!(sync.led_time(109), 1100);
+        assert_eq!(sync.led_time(111), 1101);
+    }
+
+    #[test]
+    fn resync_on_large_error() {
+        let mut sync = TimeSync::new(RATE);
+        sync.on_rx(0, 1000, 0);
+        // Master rebooted, time jumps back
+        sync.on_rx(100, 10, 100);
+        assert_eq!(sync.led_time(100), 10);
+        sync.on_rx(110, 20, 110);
+        assert_eq!(sync.led_time(110), 20);
+    }
+
+    #[test]
+    fn ignore_late_response() {
+        let mut sync = TimeSync::new(RATE);
+        sync.on_rx(0, 1000, 0);
+        sync.on_rx(100, 5000, 100 + RESYNC_THRESHOLD_MS + 1);
+        assert_eq!(sync.latency(), 0);
+        assert_eq!(sync.led_time(2000), 3000);
+    }
+}
@@ -0,0 +1,192 @@
+use keyberon::action::Action;
+use keyberon::layout::Layers;
+
+use crate::bsp::{NCOLS, NROWS};
+use super::actions;
+use super::leds::Keys;
+use super::ticks::TickRate;
+
+/// Configuration of layout training mode
+pub struct TrainingConfig {
+    /// Teaching set of keys (e.g. home row) that is not dimmed
+    pub keys: &'static Keys,
+    /// Maximum brightness of LEDs outside of the teaching set
+    pub dim_brightness: u8,
+    /// How long the hint is shown after a wrong-layer press in milliseconds
+    pub hint_duration_ms: u16,
+}
+
+/// Layout training mode for users learning a new layout
+///
+/// LEDs outside of the teaching set are dimmed by the LED controller. When a key that does
+/// nothing on the current layer is pressed, but it has an action on another layer, the key
+/// that activates that layer (e.g. a layer hold-tap) is flashed as a hint until the next key
+/// press or until the hint times out.
+pub struct Trainer<const L: usize> {
+    layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>,
+    enabled: bool,
+    /// Key that should have been held, in global coordinates
+    hint: Option<(u8, u8)>,
+    /// Ticks remaining until the hint is cleared
+    hint_remaining: u32,
+    /// Hint duration in ticks
+    hint_duration: u32,
+}
+
+impl<const L: usize> Trainer<L> {
+    pub const fn new(
+        layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>,
+        config: &TrainingConfig,
+        tick_rate: TickRate,
+    ) -> Self {
+        let hint_duration = tick_rate.from_ms(config.hint_duration_ms as u32);
+        Self { layers, enabled: false, hint: None, hint_remaining: 0, hint_duration }
+    }
+
+    /// Use different layers, e.g. a copy that can be modified at runtime
+    pub fn set_layers(&mut self, layers: &'static Layers<{ 2 * NCOLS }, NROWS, L, actions::Action>) {
+        self.layers = layers;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        defmt::info!("Training mode: {=bool}", enabled);
+        self.enabled = enabled;
+        self.hint = None;
+    }
+
+    /// Key to flash as a hint
+    pub fn hint(&self) -> Option<(u8, u8)> {
+        self.hint
+    }
+
+    /// Advance time, clears the hint after its duration
+    pub fn tick(&mut self) {
+        if self.hint.is_some() {
+            self.hint_remaining = self.hint_remaining.saturating_sub(1);
+            if self.hint_remaining == 0 {
+                self.hint = None;
+            }
+        }
+    }
+
+    /// Handle key press with `action` resolved on the current `layer`
+    pub fn on_press(&mut self, (i, j): (u8, u8), layer: usize, action: &Action<actions::Action>) {
+        if !self.enabled {
+            return;
+        }
+        self.hint = None;
+        if !is_empty(action) {
+            return;
+        }
+        self.hint = self.layer_key_for((i, j), layer);
+        if let Some((row, col)) = self.hint {
+            defmt::info!("Key ({=u8}, {=u8}) needs layer key ({=u8}, {=u8})", i, j, row, col);
+            self.hint_remaining = self.hint_duration;
+        }
+    }
+
+    /// Find a key on `layer` that activates another layer on which the key has an action
+    fn layer_key_for(&self, (i, j): (u8, u8), layer: usize) -> Option<(u8, u8)> {
+        let current = self.layers.get(layer)?;
+        self.layers.iter().enumerate()
+            .filter(|(l, _)| *l != layer)
+            .filter(|(_, keys)| keys.get(i as usize)
+                .and_then(|row| row.get(j as usize))
+                .map_or(false, |action| !is_empty(action)))
+            .find_map(|(l, _)| Self::find_layer_key(current, l))
+    }
+
+    /// Coordinates of the first key on `layer` that activates `target` layer
+    fn find_layer_key(layer: &[[Action<actions::Action>; 2 * NCOLS]; NROWS], target: usize) -> Option<(u8, u8)> {
+        layer.iter().enumerate().find_map(|(i, row)| {
+            row.iter().position(|action| activates(action, target)).map(|j| (i as u8, j as u8))
+        })
+    }
+}
+
+/// Action does nothing on its own layer
+fn is_empty<T>(action: &Action<T>) -> bool {
+    matches!(action, Action::NoOp | Action::Trans)
+}
+
+/// Check if pressing a key with given action can activate `layer`
+fn activates<T>(action: &Action<T>, layer: usize) -> bool {
+    match action {
+        Action::Layer(l) | Action::DefaultLayer(l) => *l == layer,
+        Action::HoldTap(ht) => activates(&ht.hold, layer) || activates(&ht.tap, layer),
+        Action::MultipleActions(acts) => acts.iter().any(|a| activates(a, layer)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keyberon::action::{k, l, HoldTapAction, HoldTapConfig};
+    use keyberon::key_code::KeyCode;
+    use super::*;
+
+    const HT: Action<actions::Action> = Action::HoldTap(&HoldTapAction {
+        timeout: 200,
+        hold: l(2),
+        tap: k(KeyCode::Space),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    });
+
+    const NO: Action<actions::Action> = Action::NoOp;
+    const ROW: [Action<actions::Action>; 2 * NCOLS] = [NO; 2 * NCOLS];
+    const LAYER: [[Action<actions::Action>; 2 * NCOLS]; NROWS] = [ROW; NROWS];
+
+    static LAYERS: Layers<{ 2 * NCOLS }, NROWS, 3, actions::Action> = {
+        let mut layers = [LAYER; 3];
+        layers[0][0][0] = k(KeyCode::A);
+        layers[0][4][2] = HT;
+        layers[1][1][1] = k(KeyCode::B);
+        layers[2][0][1] = k(KeyCode::Kb1);
+        layers[2][0][0] = Action::Trans;
+        layers
+    };
+
+    const CONFIG: TrainingConfig = TrainingConfig { keys: &Keys::Rows(&[2]), dim_brightness: 10, hint_duration_ms: 3 };
+    const RATE: TickRate = TickRate::new(1000);
+
+    #[test]
+    fn hint_layer_key() {
+        let mut trainer = Trainer::new(&LAYERS, &CONFIG, RATE);
+        trainer.on_press((0, 1), 0, &NO);
+        assert_eq!(trainer.hint(), None);
+
+        trainer.set_enabled(true);
+        trainer.on_press((0, 0), 0, &LAYERS[0][0][0]);
+        assert_eq!(trainer.hint(), None);
+        // Hold-tap key of layer 2
+        trainer.on_press((0, 1), 0, &NO);
+        assert_eq!(trainer.hint(), Some((4, 2)));
+        // No layer key for layer 1
+        trainer.on_press((1, 1), 0, &NO);
+        assert_eq!(trainer.hint(), None);
+        // Nothing on any layer
+        trainer.on_press((3, 3), 0, &NO);
+        assert_eq!(trainer.hint(), None);
+    }
+
+    #[test]
+    fn hint_timeout() {
+        let mut trainer = Trainer::new(&LAYERS, &CONFIG, RATE);
+        trainer.set_enabled(true);
+        trainer.on_press((0, 1), 0, &Action::Trans);
+        trainer.tick();
+        trainer.tick();
+        assert_eq!(trainer.hint(), Some((4, 2)));
+        trainer.tick();
+        assert_eq!(trainer.hint(), None);
+
+        trainer.on_press((0, 1), 0, &NO);
+        trainer.set_enabled(false);
+        assert_eq!(trainer.hint(), None);
+    }
+}
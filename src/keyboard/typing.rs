@@ -0,0 +1,212 @@
+use keyberon::key_code::KeyCode;
+
+use super::hid::{KeyboardReport, KeyCodeIterExt as _};
+
+/// Maximum length of text that can be typed at once
+pub const TEXT_MAX_LEN: usize = 192;
+
+/// Types ASCII text by generating keyboard reports
+///
+/// Each character is sent as a press report followed by an empty (release) report,
+/// so the same character can be repeated. Characters that cannot be typed using
+/// US layout are skipped.
+pub struct Typist {
+    text: heapless::String<TEXT_MAX_LEN>,
+    pos: usize,
+    pressed: bool,
+}
+
+impl Typist {
+    pub const fn new() -> Self {
+        Self {
+            text: heapless::String::new(),
+            pos: 0,
+            pressed: false,
+        }
+    }
+
+    /// Check if there is text being typed
+    pub fn is_typing(&self) -> bool {
+        self.pressed || self.pos < self.text.len()
+    }
+
+    /// Get empty text buffer to write to, or None if still typing previous text
+    ///
+    /// Typing starts on next call to [`Self::next_report`].
+    pub fn text(&mut self) -> Option<&mut heapless::String<TEXT_MAX_LEN>> {
+        if self.is_typing() {
+            None
+        } else {
+            self.text.clear();
+            self.pos = 0;
+            Some(&mut self.text)
+        }
+    }
+
+    /// Append text to be typed after the current one, returns false if there is not enough space
+    pub fn append(&mut self, text: &str) -> bool {
+        if !self.is_typing() {
+            self.text.clear();
+            self.pos = 0;
+        }
+        self.text.push_str(text).is_ok()
+    }
+
+    /// Generate next report, returns None when there is nothing more to type
+    pub fn next_report(&mut self) -> Option<KeyboardReport> {
+        if self.pressed {
+            self.pressed = false;
+            return Some(KeyboardReport::new(core::iter::empty::<KeyCode>().into_page()));
+        }
+
+        while let Some(c) = self.text.as_bytes().get(self.pos) {
+            self.pos += 1;
+            if let Some((key, shift)) = ascii_to_key(*c) {
+                self.pressed = true;
+                let keys = [shift.then_some(KeyCode::LShift), Some(key)];
+                return Some(KeyboardReport::new(keys.into_iter().flatten().into_page()));
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for Typist {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Convert ASCII character to a key code and shift state assuming US keyboard layout
+pub fn ascii_to_key(c: u8) -> Option<(KeyCode, bool)> {
+    use KeyCode::*;
+    const LETTERS: [KeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [Kb0, Kb1, Kb2, Kb3, Kb4, Kb5, Kb6, Kb7, Kb8, Kb9];
+    let key = match c {
+        b'a'..=b'z' => (LETTERS[(c - b'a') as usize], false),
+        b'A'..=b'Z' => (LETTERS[(c - b'A') as usize], true),
+        b'0'..=b'9' => (DIGITS[(c - b'0') as usize], false),
+        b'\n' => (Enter, false),
+        b'\t' => (Tab, false),
+        b' ' => (Space, false),
+        b'!' => (Kb1, true),
+        b'@' => (Kb2, true),
+        b'#' => (Kb3, true),
+        b'$' => (Kb4, true),
+        b'%' => (Kb5, true),
+        b'^' => (Kb6, true),
+        b'&' => (Kb7, true),
+        b'*' => (Kb8, true),
+        b'(' => (Kb9, true),
+        b')' => (Kb0, true),
+        b'-' => (Minus, false),
+        b'_' => (Minus, true),
+        b'=' => (Equal, false),
+        b'+' => (Equal, true),
+        b'[' => (LBracket, false),
+        b'{' => (LBracket, true),
+        b']' => (RBracket, false),
+        b'}' => (RBracket, true),
+        b'\\' => (Bslash, false),
+        b'|' => (Bslash, true),
+        b';' => (SColon, false),
+        b':' => (SColon, true),
+        b'\'' => (Quote, false),
+        b'"' => (Quote, true),
+        b'`' => (Grave, false),
+        b'~' => (Grave, true),
+        b',' => (Comma, false),
+        b'<' => (Comma, true),
+        b'.' => (Dot, false),
+        b'>' => (Dot, true),
+        b'/' => (Slash, false),
+        b'?' => (Slash, true),
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use usbd_human_interface_device::page::Keyboard::*;
+
+    use super::*;
+
+    fn type_all(text: &str) -> Vec<KeyboardReport> {
+        let mut typist = Typist::new();
+        typist.text().unwrap().push_str(text).unwrap();
+        let mut reports = Vec::new();
+        while let Some(r) = typist.next_report() {
+            reports.push(r);
+        }
+        assert!(!typist.is_typing());
+        reports
+    }
+
+    #[test]
+    fn ascii_mapping() {
+        assert_eq!(ascii_to_key(b'a'), Some((KeyCode::A, false)));
+        assert_eq!(ascii_to_key(b'Z'), Some((KeyCode::Z, true)));
+        assert_eq!(ascii_to_key(b'0'), Some((KeyCode::Kb0, false)));
+        assert_eq!(ascii_to_key(b'7'), Some((KeyCode::Kb7, false)));
+        assert_eq!(ascii_to_key(b':'), Some((KeyCode::SColon, true)));
+        assert_eq!(ascii_to_key(0x7f), None);
+    }
+
+    #[test]
+    fn type_text() {
+        let empty = KeyboardReport::new([]);
+        assert_eq!(type_all("aB:"), [
+            KeyboardReport::new([A]),
+            empty.clone(),
+            KeyboardReport::new([LeftShift, B]),
+            empty.clone(),
+            KeyboardReport::new([LeftShift, Semicolon]),
+            empty.clone(),
+        ]);
+    }
+
+    #[test]
+    fn repeated_chars_released() {
+        let empty = KeyboardReport::new([]);
+        assert_eq!(type_all("aa"), [
+            KeyboardReport::new([A]),
+            empty.clone(),
+            KeyboardReport::new([A]),
+            empty.clone(),
+        ]);
+    }
+
+    #[test]
+    fn skip_unsupported() {
+        assert_eq!(type_all("\x01a\x02").len(), 2);
+    }
+
+    #[test]
+    fn append_while_typing() {
+        let mut typist = Typist::new();
+        assert!(typist.append("a"));
+        typist.next_report().unwrap();
+        assert!(typist.append("b"));
+        let mut n = 1;
+        while typist.next_report().is_some() {
+            n += 1;
+        }
+        assert_eq!(n, 4);
+        assert!(!typist.append(&"x".repeat(TEXT_MAX_LEN + 1)));
+    }
+
+    #[test]
+    fn no_new_text_while_typing() {
+        let mut typist = Typist::new();
+        typist.text().unwrap().push_str("ab").unwrap();
+        typist.next_report().unwrap();
+        assert!(typist.text().is_none());
+        while typist.next_report().is_some() {}
+        assert!(typist.text().is_some());
+    }
+}
@@ -14,7 +14,8 @@
 // Use std when running tests, see: https://stackoverflow.com/a/28186509
 // Make sure to use different target when testing, e.g.
 //   cargo test --target x86_64-unknown-linux-gnu
-#[cfg(test)]
+// Simulator (see `keyboard::sim`) also runs on host.
+#[cfg(any(test, feature = "sim"))]
 #[macro_use]
 extern crate std;
 
@@ -30,4 +31,7 @@ pub mod utils;
 /// Build metadata generated using "built" package
 pub mod build_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
+
+    /// Build date as YYYY-MM-DD (UTC)
+    pub const BUILD_DATE: &str = include!(concat!(env!("OUT_DIR"), "/build_date.rs"));
 }
@@ -3,48 +3,93 @@
 #![no_main]
 #![no_std]
 
+#[cfg(debug_assertions)]
 use panic_probe as _;
+#[cfg(not(feature = "defmt-usb"))]
 use defmt_rtt as _;
 use stm32f0xx_hal as hal;
 use ghanima as lib;
 
+/// In release builds store panic report and reset, so that it can be examined after reboot
+#[cfg(not(debug_assertions))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    unsafe { lib::bsp::panic::store(info) };
+    lib::hal_ext::reboot::reboot(lib::hal_ext::reboot::RebootReason::Panic, false, None)
+}
+
 #[rtic::app(device = crate::hal::pac, dispatchers = [CEC_CAN, USART3_4])]
 mod app {
     use core::mem::MaybeUninit;
     use cortex_m::interrupt::free as ifree;
     use super::hal;
     use hal::prelude::*;
+    use systick_monotonic::ExtU64;
     use usb_device::class_prelude::UsbBusAllocator;
     use bbqueue::BBBuffer;
 
     use super::lib;
     use lib::def_tasks_debug;
     use lib::bsp::{self, debug, joystick, ws2812b, usb, usb::Usb, sides::BoardSide, LedColors};
-    use lib::hal_ext::{crc, spi, reboot, uart, watchdog, dma::{DmaSplit, DmaTx}};
+    use lib::hal_ext::{clock, crc, flash, spi, reboot, reset, uart, watchdog, dma::{self, DmaSplit}};
     use lib::{keyboard, config, ioqueue};
+    use lib::utils::{OptionChanges as _, Watermark};
 
     // MCU clock frequencies
     const SYSCLK_MHZ: u32 = 48;
     const PCLK_MHZ: u32 = 24;
     const CRYSTAL_CLK_MHZ: u32 = 12;
 
-    /// Base frequency of a "tick"
-    const TICK_FREQUENCY_HZ: u32 = 1000;
-    // Prescalers that define task frequencies in multiples of a "tick"
+    /// Base frequency of a "tick" from configuration, timing constants below are converted
+    /// from milliseconds
+    const TICK_RATE: keyboard::ticks::TickRate = keyboard::ticks::TickRate::new(config::CONFIG.tick_frequency_hz);
+    // Prescaler that defines keyboard task frequency in multiples of a "tick", other tasks use
+    // runtime prescalers with defaults from configuration. Keyboard timing in configuration
+    // is specified in keyboard ticks, so this should stay 1 unless configuration is adjusted.
     const KEYBOARD_PRESCALER: u32 = 1;
-    const LEDS_PRESCALER: u32 = 10;
-    const JOY_PRESCALER: u32 = 10;
-    const DEBUG_PRESCALER: u32 = 1000;
 
     const ERROR_LED_DURATION_MS: u32 = 1000;
-    const DEBOUNCE_COUNT: u16 = 5;
+    const JOYSTICK_PLANE_LED_DURATION_MS: u32 = 300;
+    // How often to check if mouse emulation has been re-enabled when its prescaler is 0
+    const MOUSE_DISABLED_POLL_MS: u32 = 100;
+
+    const WATCHDOG: watchdog::WatchdogConfig = config::CONFIG.watchdog;
+    // "watchdog" feature forces the watchdog on regardless of configuration
+    const WATCHDOG_ENABLED: bool = cfg!(feature = "watchdog") || WATCHDOG.enabled;
+    // Writing key statistics erases a flash page right after feeding, see flush_key_stats
+    const _: () = assert!(!WATCHDOG_ENABLED || WATCHDOG.window_end_ms > flash::PAGE_ERASE_MAX_MS,
+        "Watchdog period must be longer than flash page erase");
+
+    /// Tasks monitored for progress, watchdog is not fed when any of them stalls.
+    /// UART data is processed from keyboard_tick, so it is covered by that task.
+    enum Monitored {
+        Timer,
+        Keyboard,
+        Leds,
+    }
+    const MONITORED_TASKS: usize = 3;
+    /// Number of consecutive runs a monitored task may miss before it is considered stalled
+    const TASK_DEADLINE_PERIODS: u32 = 100;
+    /// Lower bound of task deadlines, so that short periods do not make monitoring too strict
+    const TASK_DEADLINE_MIN_MS: u32 = 100;
+
+    /// Maximum time between runs of a monitored task that runs every `period` ticks
+    const fn task_deadline_ms(period: u32) -> u32 {
+        let deadline = TICK_RATE.to_ms(period.saturating_mul(TASK_DEADLINE_PERIODS));
+        if deadline < TASK_DEADLINE_MIN_MS { TASK_DEADLINE_MIN_MS } else { deadline }
+    }
 
-    const WATCHDOG_WINDOW_START_MS: u32 = 30;
-    const WATCHDOG_WINDOW_END_MS: u32 = 60;
+    /// Initial deadlines of monitored tasks, order must match [`Monitored`]
+    const TASK_DEADLINES_MS: [u32; MONITORED_TASKS] = [
+        task_deadline_ms(1),
+        task_deadline_ms(KEYBOARD_PRESCALER),
+        task_deadline_ms(config::CONFIG.prescalers.leds),
+    ];
 
     // Small value as this is mostly to avoid sending the same colors 3-4 times in the row when
     // colors keep changing due to interpolation
-    const LED_RETRANSMISSION_MIN_TIME: u32 = 100;
+    const LED_RETRANSMISSION_MIN_MS: u32 = 100;
 
     def_tasks_debug! {
         struct TaskCounters {
@@ -52,6 +97,7 @@ mod app {
             usb_poll => b'U',
             keyboard => b'k',
             joystick => b'j',
+            mouse => b'm',
             leds_state_update => b's',
             led_colors_force => b'f',
             led_spi_output => b'l',
@@ -65,14 +111,17 @@ mod app {
     const TX_QUEUE_SIZE: usize = 400;
     const RX_QUEUE_SIZE: usize = 600;
 
-    const SERIAL_BAUD_RATE: u32 = 460_800;
     const RX_DMA_TMP_BUF_SIZE: usize = 128;
 
+    // LED data is streamed through ping-pong buffer, each half must be refilled before DMA
+    // finishes the other one (8 LEDs per half: 96 bytes, 256 us at 3 MHz)
+    const LED_SPI_BUF_SIZE: usize = 2 * 8 * ws2812b::LED_SIZE;
+
     type SerialTx = uart::Tx<TX_QUEUE_SIZE>;
     type SerialTxQueue = keyboard::Transmitter<TX_QUEUE_SIZE>;
     type SerialRx = uart::Rx<RX_QUEUE_SIZE, &'static mut [u8; RX_DMA_TMP_BUF_SIZE]>;
     type SerialRxQueue = keyboard::Receiver<RX_QUEUE_SIZE>;
-    type Leds = ws2812b::Leds<{ bsp::NLEDS }>;
+    type LedSerializer = ws2812b::Serializer<{ bsp::NLEDS }>;
     type Keyboard = keyboard::Keyboard<{ config::N_LAYERS }>;
 
     // Using &'static mut to avoid unnecessary stack allocations, see:
@@ -82,23 +131,31 @@ mod app {
         board_side: BoardSide,
         usb: &'static mut Usb,
         spi_tx: spi::SpiTx,
+        led_serializer: LedSerializer,
         serial_tx: SerialTx,
         serial_tx_queue: SerialTxQueue,
         serial_rx: SerialRx,
         serial_rx_queue: SerialRxQueue,
-        crc: crc::Crc,
         led_controller: &'static mut keyboard::LedController<'static>,
         led_output: keyboard::LedOutput,
         led_forced_colors: Option<LedColors>,  // instead of queue we override last
+        led_local_pressed: Option<keyboard::leds::LedsBitset>,
+        dfu_countdown: Option<u8>,
         keyboard: &'static mut Keyboard,
+        prescalers: keyboard::Prescalers,
         tasks: TaskCounters,
+        health: watchdog::TaskHealth<MONITORED_TASKS>,
     }
 
     #[local]
     struct Local {
         timer: hal::timers::Timer<hal::pac::TIM15>,
         joy: joystick::Joystick,
-        watchdog: watchdog::WindowWatchdog,
+        watchdog: watchdog::Watchdog,
+        keyboard_crc: crc::Crc,
+        leds_crc: crc::Crc,
+        console: Option<debug::shell::Console>,
+        flash: flash::Flash,
     }
 
     #[monotonic(binds = SysTick, default = true)]
@@ -109,10 +166,9 @@ mod app {
     #[cortex_m_rt::pre_init]
     unsafe fn pre_init() {
         reboot::maybe_jump_bootloader();
-        if cfg!(feature = "stack-usage") {
-            // Use some margin as it seems we're actually corrupting some "theoretically free" stack
-            debug::mem::free_stack_fill(0x40);
-        }
+        // Needed for stack watermark tracking.
+        // Use some margin as it seems we're actually corrupting some "theoretically free" stack
+        debug::mem::free_stack_fill(0x40);
     }
 
     #[init(local = [
@@ -120,7 +176,7 @@ mod app {
         led_controller: MaybeUninit<keyboard::LedController<'static>> = MaybeUninit::uninit(),
         keyboard: MaybeUninit<keyboard::Keyboard<{ config::N_LAYERS }>> = MaybeUninit::uninit(),
         usb_bus: Option<UsbBusAllocator<hal::usb::UsbBusType>> = None,
-        led_buf: [u8; Leds::BUFFER_SIZE] = [0; Leds::BUFFER_SIZE],
+        led_buf: [u8; LED_SPI_BUF_SIZE] = [0; LED_SPI_BUF_SIZE],
         serial_tx_bbb: BBBuffer<TX_QUEUE_SIZE> = BBBuffer::new(),
         serial_rx_bbb: BBBuffer<RX_QUEUE_SIZE> = BBBuffer::new(),
         serial_rx_buf: [u8; RX_DMA_TMP_BUF_SIZE] = [0; RX_DMA_TMP_BUF_SIZE],
@@ -132,7 +188,7 @@ mod app {
 
         // Automatically enter sleep mode when leaving an ISR
         // Disable when watchdog is active, so that we always enter idle task to feed it.
-        if cfg!(feature = "idle-sleep") && !cfg!(feature = "watchdog") {
+        if cfg!(feature = "idle-sleep") && !WATCHDOG_ENABLED {
             core.SCB.set_sleeponexit();
         }
 
@@ -149,31 +205,53 @@ mod app {
         };
         let mut rcc = clk_config.freeze(&mut dev.FLASH);
 
-        // Check if a watchdog reset occured, clear the flags
-        let was_watchdog_reset = watchdog::reset_flags::was_window_watchdog(&mut rcc);
-        watchdog::reset_flags::clear(&mut rcc);
+        // Microsecond timestamps, needed for delays (keeps running when handle is dropped)
+        clock::MicrosClock::new(dev.TIM2, &mut rcc);
+
+        // Check reset cause, clear the flags and count it in backup registers
+        let backup = reset::BackupAccess::enable(&mut dev.PWR, &mut rcc);
+        let (reset_flags, reset_counters) = reset::on_boot(&backup, &mut rcc);
+        let reset_cause = reset_flags.cause();
+        defmt::info!("Reset cause: {} ({}), counters: {}", reset_cause, reset_flags, reset_counters);
+        defmt::info!("Reboot counters: {}", reboot::RebootCounters::load());
+
+        // Reading whole flash takes a while, do it before starting watchdog
+        let image = bsp::image::ImageCrc::verify();
+        defmt::info!("Firmware image: {}", image);
 
         // Watchdog
-        const PARAMS: watchdog::WindowParams = watchdog::WindowParams::new(
-            PCLK_MHZ * 1_000_000,
-            WATCHDOG_WINDOW_START_MS * 1000,
-            WATCHDOG_WINDOW_END_MS * 1000,
-        );
-        let mut watchdog = watchdog::WindowWatchdog::new(dev.WWDG, PARAMS);
-        if cfg!(feature = "watchdog") {
-            watchdog.stop_on_debug(true, &mut dev.DBGMCU, &mut rcc);
-            watchdog.start(&mut rcc);
+        // Evaluated at compile time, so invalid timing results in compilation error
+        const WINDOW_PARAMS: Option<watchdog::WindowParams> = WATCHDOG.window_params(PCLK_MHZ * 1_000_000);
+        const INDEPENDENT_PARAMS: Option<watchdog::IndependentParams> = WATCHDOG.independent_params();
+        let mut watchdog = if !WATCHDOG_ENABLED {
+            watchdog::Watchdog::Disabled
+        } else if let Some(params) = WINDOW_PARAMS {
+            let mut wwdg = watchdog::WindowWatchdog::new(dev.WWDG, params);
+            wwdg.stop_on_debug(true, &mut dev.DBGMCU, &mut rcc);
+            wwdg.start(&mut rcc);
+            watchdog::Watchdog::Window(wwdg)
+        } else if let Some(params) = INDEPENDENT_PARAMS {
+            let mut iwdg = watchdog::IndependentWatchdog::new(dev.IWDG, params);
+            iwdg.stop_on_debug(true, &mut dev.DBGMCU, &mut rcc);
+            iwdg.start();
+            watchdog::Watchdog::Independent(iwdg)
+        } else {
+            watchdog::Watchdog::Disabled
         };
 
         // Pinout
         let gpioa = dev.GPIOA.split(&mut rcc);
         let gpiob = dev.GPIOB.split(&mut rcc);
-        let gpioc = dev.GPIOC.split(&mut rcc);
 
         // Debugging
         let debug_tx = ifree(|cs| gpioa.pa2.into_alternate_af1(cs));
         let debug_rx = ifree(|cs| gpioa.pa3.into_alternate_af1(cs));
-        debug::tasks::init(dev.USART2, (debug_tx, debug_rx), &mut rcc);
+        let console = if cfg!(feature = "debug-shell") {
+            Some(debug::shell::Console::new(dev.USART2, (debug_tx, debug_rx), &mut rcc))
+        } else {
+            debug::tasks::init(dev.USART2, (debug_tx, debug_rx), &mut rcc);
+            None
+        };
 
         // DMA
         let dma = dev.DMA1.split(&mut rcc);
@@ -182,25 +260,16 @@ mod app {
         let mut crc = crc::Crc::new(dev.CRC, &mut rcc);
 
         // Determine board side
-        let board_side = ifree(|cs| gpiob.pb13.into_floating_input(cs));
-        let board_side = BoardSide::get(board_side);
+        let board_side = unsafe { bsp::board::SIDE_DETECTION.detect() }.unwrap_or_else(|| {
+            defmt::error!("Board side not programmed in option bytes, assuming left");
+            BoardSide::Left
+        });
 
         // Keyboard matrix
-        let cols = ifree(|cs| [
-            gpiob.pb1.into_pull_up_input(cs).downgrade(),
-            gpiob.pb0.into_pull_up_input(cs).downgrade(),
-            gpioa.pa7.into_pull_up_input(cs).downgrade(),
-            gpioa.pa6.into_pull_up_input(cs).downgrade(),
-            gpioa.pa5.into_pull_up_input(cs).downgrade(),
-            gpioa.pa4.into_pull_up_input(cs).downgrade(),
-        ]);
-        let rows =  ifree(|cs| [
-            gpiob.pb6.into_push_pull_output(cs).downgrade(),
-            gpiob.pb7.into_push_pull_output(cs).downgrade(),
-            gpioc.pc13.into_push_pull_output(cs).downgrade(),
-            gpioc.pc14.into_push_pull_output(cs).downgrade(),
-            gpioc.pc15.into_push_pull_output(cs).downgrade(),
-        ]);
+        // Pins are taken from board description, HAL pins from `gpiox.split()` must not be used for these.
+        defmt::info!("Board: {=str}", bsp::board::BOARD.name);
+        let (cols, rows) = unsafe { bsp::board::matrix_pins() };
+        let aux_keys = unsafe { bsp::board::aux_key_pins() };
 
         // UARTs
         let board_tx = ifree(|cs| gpioa.pa9.into_alternate_af1(cs));
@@ -210,7 +279,7 @@ mod app {
             (board_tx, board_rx),
             (dma.ch2, dma.ch3),
             (cx.local.serial_tx_bbb, cx.local.serial_rx_bbb, cx.local.serial_rx_buf),
-            SERIAL_BAUD_RATE.bps(),
+            keyboard::link::SAFE_BAUD_RATE.bps(),
             &mut rcc,
         ).split();
 
@@ -225,7 +294,7 @@ mod app {
         let mut spi_tx = spi::SpiTx::new(dev.SPI2, rgb_tx, dma.ch5, &mut cx.local.led_buf[..], 3.mhz(), &mut rcc);
 
         // configure periodic timer
-        let mut timer = hal::timers::Timer::tim15(dev.TIM15, TICK_FREQUENCY_HZ.hz(), &mut rcc);
+        let mut timer = hal::timers::Timer::tim15(dev.TIM15, TICK_RATE.hz().hz(), &mut rcc);
         timer.listen(hal::timers::Event::TimeOut);
 
         // USB
@@ -244,60 +313,118 @@ mod app {
                 bootload_strict: config::CONFIG.bootload_strict,
                 serial_num: cx.local.usb_string,
                 device_id: bsp::get_device_id(&mut dev.FLASH),
+                uid: bsp::ident::Uid::read(),
+                vbus: bsp::vbus::VbusSense::new(),
             };
             cx.local.usb.as_mut_ptr().write(Usb::new(cfg));
             &mut *cx.local.usb.as_mut_ptr()
         };
 
+        // Flash programming for key usage statistics
+        let flash = flash::Flash::new(dev.FLASH);
+
         // Use const version to decrease binary size by ~700 B
         const KEY_ACTION_CACHE: [keyboard::KeyActionCache; config::N_LAYERS] =
             keyboard::KeyActionCache::const_for_layers(&config::CONFIG.layers);
 
+        // Configuration, possibly with parts loaded from a blob appended to firmware image
+        #[cfg(feature = "config-blob")]
+        let (kb_config, key_action_cache) = match unsafe { config::blob::load(config::CONFIG) } {
+            Ok(loaded) => loaded,
+            Err(config::blob::Error::Missing) => {
+                defmt::info!("No configuration blob, using built-in configuration");
+                (&config::CONFIG, &KEY_ACTION_CACHE)
+            },
+            Err(e) => {
+                defmt::warn!("Invalid configuration blob, using built-in configuration: {}", e);
+                (&config::CONFIG, &KEY_ACTION_CACHE)
+            },
+        };
+        #[cfg(not(feature = "config-blob"))]
+        let (kb_config, key_action_cache) = (&config::CONFIG, &KEY_ACTION_CACHE);
+
         // LED controller
-        let mut led_output = keyboard::LedOutput::new(LED_RETRANSMISSION_MIN_TIME);
+        let mut led_output = keyboard::LedOutput::new(TICK_RATE.from_ms(LED_RETRANSMISSION_MIN_MS), config::CONFIG.reactive);
         let led_controller = unsafe {
             cx.local.led_controller.as_mut_ptr().write(
-                keyboard::LedController::new(board_side, &config::CONFIG.leds, &KEY_ACTION_CACHE)
+                keyboard::LedController::new(board_side, &kb_config.leds, key_action_cache)
             );
             &mut *cx.local.led_controller.as_mut_ptr()
         };
+        led_controller.set_layer_configs(kb_config.layer_leds);
+        led_controller.set_night_mode(keyboard::leds::night::load());
+        led_controller.set_brightness_limits(config::CONFIG.led_brightness_limits);
+        led_controller.set_heartbeat(config::CONFIG.suspend_heartbeat, TICK_RATE);
+        led_controller.set_training(&config::CONFIG.training);
 
         // I/O queue (need to use this trick anyway because the constructors new/default are non-const).
         let mut serial_tx_queue = keyboard::Transmitter::new(serial_tx_queue);
         let serial_rx_queue = keyboard::Receiver::new(serial_rx_queue);
 
         // Keyboard
-        let keys = keyboard::Keys::new(board_side, cols, rows, DEBOUNCE_COUNT);
+        let matrix_wake = bsp::matrix_wake::MatrixWake::new(dev.SYSCFG, dev.EXTI, &mut rcc);
+        let matrix = keyboard::HwMatrix::new(cols, rows, aux_keys, matrix_wake);
+        let keys = keyboard::Keys::new(board_side, matrix, &config::CONFIG.debounce, TICK_RATE);
         let keyboard = unsafe {
-            cx.local.keyboard.as_mut_ptr().write(keyboard::Keyboard::new(keys, &config::CONFIG));
+            cx.local.keyboard.as_mut_ptr().write(keyboard::Keyboard::new(keys, kb_config));
             &mut *cx.local.keyboard.as_mut_ptr()
         };
+        keyboard.set_image_crc(image);
+        #[cfg(feature = "runtime-keymap")]
+        {
+            static mut KEYMAP: MaybeUninit<keyboard::keymap::Layers<{ config::N_LAYERS }>> = MaybeUninit::uninit();
+            keyboard.enable_runtime_keymap(unsafe { &mut *(&raw mut KEYMAP) });
+            // LED rules matching key actions follow remapped keys
+            static mut KEY_ACTIONS: MaybeUninit<[keyboard::KeyActionCache; config::N_LAYERS]> = MaybeUninit::uninit();
+            let actions = unsafe { &mut *(&raw mut KEY_ACTIONS) };
+            led_controller.set_key_actions_buffer(actions.write(keyboard::KeyActionCache::for_layers(kb_config.layers)));
+        }
+        keyboard.set_joystick_enabled(keyboard::joystick::load());
+        keyboard.restore_joystick_divider(keyboard::joystick::load_divider());
+        keyboard.set_accessibility(keyboard::accessibility::load());
+        if bsp::stats_flash::load(keyboard.key_stats_mut()) {
+            defmt::info!("Key statistics loaded, enabled: {=bool}", keyboard.key_stats().is_enabled());
+        }
+        if let Some(brightness) = keyboard.restore_brightness_preset(keyboard::leds::preset::load()) {
+            led_controller.set_brightness(brightness);
+        }
 
         // If there was abnormal reset, signalize it using LEDs
-        if was_watchdog_reset {
-            defmt::error!("Watchdog triggered system reset");
-            let ticks = ERROR_LED_DURATION_MS * 1000 / TICK_FREQUENCY_HZ / KEYBOARD_PRESCALER;
-            led_output.set_overwrite(ticks as u16)
-                .for_each(|side| {
-                    for (i, led) in side.colors.iter_mut().enumerate() {
-                        led.r = if i % 4 == 0 { 255 } else { 0 };
-                        led.g = 0;
-                        led.b = 0;
-                    }
-                });
+        // Watchdog/low-power: every 4th LED red, panic: red/blue every 2nd LED,
+        // corrupted firmware image: every 3rd LED magenta
+        let error_leds = if let Some(report) = bsp::panic::on_boot() {
+            defmt::error!("Reset after panic: {}", report);
+            Some((2, rgb::RGB8::new(255, 0, 0), rgb::RGB8::new(0, 0, 255)))
+        } else if reset_cause.is_abnormal() {
+            defmt::error!("Abnormal system reset: {}", reset_cause);
+            Some((4, rgb::RGB8::new(255, 0, 0), rgb::RGB8::default()))
+        } else if let bsp::image::Integrity::Corrupted { .. } = image.integrity {
+            defmt::error!("Firmware image corrupted");
+            Some((3, rgb::RGB8::new(255, 0, 255), rgb::RGB8::default()))
+        } else {
+            None
+        };
+        if let Some((every, on, off)) = error_leds {
+            use keyboard::leds::{Overlay, LedsBitset, SOLID};
+            let ticks = TICK_RATE.from_ms(ERROR_LED_DURATION_MS);
+            let mut mask = LedsBitset::NONE;
+            for i in (0..bsp::NLEDS).step_by(every) {
+                mask.set(i as u8, true);
+            }
+            let on_leds = bsp::sides::PerSide { left: mask, right: mask };
+            let off_leds = bsp::sides::PerSide { left: !mask, right: !mask };
+            led_output.play(Overlay::new(&SOLID, off_leds).tint(off).duration(ticks));
+            led_output.play(Overlay::new(&SOLID, on_leds).tint(on).duration(ticks));
         }
 
         // Send a first transfer ASAP with all LEDs in initial state
-        {
-            led_output.tick(0, led_controller);
-            // Send colors for this side over SPI
-            spi_tx.push(|buf| led_output.current(board_side).serialize_to_slice(buf))
-                .map_err(drop).unwrap();
-            spi_tx.start().map_err(drop).unwrap();
-            // Send colors for other side
-            // FIXME: will it work if USB is not ready yet?
-            serial_tx_queue.send(&mut crc, led_output.current(board_side.other()));
-        }
+        led_output.tick(0, led_controller);
+        // Send colors for this side over SPI
+        let mut led_serializer = led_output.current(board_side).serializer();
+        spi_tx.start(|buf| led_serializer.fill(buf)).map_err(drop).unwrap();
+        // Send colors for other side
+        // FIXME: will it work if USB is not ready yet?
+        serial_tx_queue.send(&mut crc, led_output.current(board_side.other()));
 
         if !joy.detect() {
             defmt::warn!("Joystick not detected");
@@ -310,7 +437,11 @@ mod app {
 
         debug::tasks::trace::run(|| defmt::info!("Liftoff!"));
 
-        watchdog.maybe_feed();
+        // Monotonic timer starts counting from 0 after init
+        watchdog.start_feeding(0);
+
+        // Mouse emulation is not driven by the tick timer, it re-schedules itself
+        mouse_tick::spawn().ok();
 
         if cfg!(feature = "stack-usage") {
             debug::mem::print_stack_info();
@@ -320,37 +451,58 @@ mod app {
             board_side,
             usb,
             spi_tx,
+            led_serializer,
             serial_tx,
             serial_tx_queue,
             serial_rx,
             serial_rx_queue,
-            crc,
             led_controller,
             led_output,
             led_forced_colors: None,
+            led_local_pressed: None,
+            dfu_countdown: None,
             keyboard,
+            prescalers: config::CONFIG.prescalers,
             tasks: Default::default(),
+            health: watchdog::TaskHealth::new(TASK_DEADLINES_MS),
         };
 
         let local = Local {
             timer,
             joy,
             watchdog,
+            keyboard_crc: crc.handle(),
+            leds_crc: crc,
+            console,
+            flash,
         };
 
         (shared, local, init::Monotonics(mono))
     }
 
-    #[task(binds = TIM15, priority = 4, local = [timer, t: u32 = 0], shared = [&tasks])]
+    /// Current time in milliseconds for task health monitoring
+    fn now_ms() -> u32 {
+        (monotonics::now().ticks() * 1000 / MONO_HZ as u64) as u32
+    }
+
+    #[task(binds = TIM15, priority = 4, local = [timer, t: u32 = 0], shared = [prescalers, &tasks, &health])]
     fn tick(cx: tick::Context) {
         let tick::LocalResources { timer, t } = cx.local;
-        let tick::SharedResources { tasks } = cx.shared;
+        let tick::SharedResources { mut prescalers, tasks, health } = cx.shared;
         tasks.timer(|| {
             // Clears interrupt flag
             if timer.wait().is_ok() {
                 // Spawn periodic tasks. Ignore error if we're too slow. Don't always compare
                 // to 0 to avoid situations that all tasks are being run at the same tick.
                 *t += 1;
+                let p = prescalers.lock(|p| *p);
+
+                let now = now_ms();
+                health.checkin(Monitored::Timer as usize, now);
+                // LED task is not being run at all when its prescaler is 0
+                health.set_monitored(Monitored::Leds as usize, p.leds != 0, now);
+                // LED prescaler may be changed at runtime
+                health.set_deadline(Monitored::Leds as usize, task_deadline_ms(p.leds));
 
                 if *t % KEYBOARD_PRESCALER == 0 {
                     if keyboard_tick::spawn(*t).is_err() {
@@ -358,19 +510,19 @@ mod app {
                     }
                 }
 
-                if *t % JOY_PRESCALER == 1 {
+                if keyboard::Prescalers::is_due(*t, p.joystick, 1) {
                     if read_joystick::spawn().is_err() {
                         defmt::warn!("Spawn failed: read_joystick");
                     };
                 }
 
-                if *t % LEDS_PRESCALER == 2 {
+                if keyboard::Prescalers::is_due(*t, p.leds, 2) {
                     if leds_tick::spawn(*t).is_err() {
                         defmt::warn!("Spawn failed: leds_tick");
                     };
                 }
 
-                if *t % DEBUG_PRESCALER == 3 {
+                if keyboard::Prescalers::is_due(*t, p.debug, 3) {
                     if debug_report::spawn().is_err() {
                         defmt::warn!("Spawn failed: debug_report");
                     }
@@ -397,41 +549,98 @@ mod app {
 
     #[task(
         priority = 2, capacity = 1,
-        shared = [serial_tx, serial_tx_queue, serial_rx_queue, crc, usb, keyboard, led_forced_colors, &tasks],
-        local = [prev_leds_update: Option<keyboard::LedControllerUpdate> = None],
+        shared = [serial_tx, serial_tx_queue, serial_rx_queue, usb, keyboard, led_forced_colors, led_local_pressed, dfu_countdown, prescalers, &tasks, &health],
+        local = [
+            keyboard_crc,
+            prev_leds_update: Option<keyboard::LedControllerUpdate> = None,
+            dfu_ms: keyboard::ticks::MsCounter = keyboard::ticks::MsCounter::new(TICK_RATE),
+        ],
     )]
     fn keyboard_tick(cx: keyboard_tick::Context, t: u32) {
         let keyboard_tick::SharedResources {
             mut serial_tx,
             serial_tx_queue,
             serial_rx_queue,
-            mut crc,
             mut usb,
             mut keyboard,
             mut led_forced_colors,
+            mut led_local_pressed,
+            mut dfu_countdown,
+            mut prescalers,
             tasks,
+            health,
         } = cx.shared;
 
         tasks.keyboard(|| {
+            health.checkin(Monitored::Keyboard as usize, now_ms());
+
             // Bootloader reboot may happen here
-            usb.lock(|usb| usb.dfu.tick(KEYBOARD_PRESCALER.try_into().unwrap()));
+            let countdown = usb.lock(|usb| {
+                let elapsed = cx.local.dfu_ms.tick(KEYBOARD_PRESCALER);
+                usb.dfu_tick(elapsed);
+                usb.flush_logs();
+                usb.dfu_countdown()
+            });
+            dfu_countdown.lock(|c| *c = countdown);
 
             // Run main keyboard logic
-            let leds_update = keyboard.lock(|keyboard| keyboard.tick(&mut crc, serial_tx_queue, serial_rx_queue, usb));
+            let leds_update = keyboard.lock(|keyboard| keyboard.tick(t, cx.local.keyboard_crc, serial_tx_queue, serial_rx_queue, usb));
 
             // Transmit any serial messages
             serial_tx.lock(|tx| tx.tick());
 
+            // Answer statistics queries from host software in a lower priority task
+            if let Some(request) = keyboard.lock(|kb| kb.take_stats_request()) {
+                if send_stats::spawn(request).is_err() {
+                    defmt::warn!("Spawn failed: send_stats");
+                }
+            }
+            if let Some(request) = keyboard.lock(|kb| kb.take_colors_request()) {
+                if send_led_colors::spawn(request).is_err() {
+                    defmt::warn!("Spawn failed: send_led_colors");
+                }
+            }
+            if let Some(request) = keyboard.lock(|kb| kb.take_panic_request()) {
+                if send_raw_report::spawn(request.response(bsp::panic::last())).is_err() {
+                    defmt::warn!("Spawn failed: send_raw_report");
+                }
+            }
+            if let Some(request) = keyboard.lock(|kb| kb.take_prescaler_request()) {
+                let prescaler = prescalers.lock(|p| request.apply(p));
+                if send_raw_report::spawn(request.response(prescaler)).is_err() {
+                    defmt::warn!("Spawn failed: send_raw_report");
+                }
+            }
+            if let Some(report) = keyboard.lock(|kb| kb.take_keymap_response()) {
+                if send_raw_report::spawn(report).is_err() {
+                    defmt::warn!("Spawn failed: send_raw_report");
+                }
+            }
+
+            // Apply serial baud rate change after all previous data has been transmitted
+            if let Some(baud) = keyboard.lock(|kb| kb.pending_baud_rate()) {
+                if serial_tx.lock(|tx| tx.set_baud_rate(baud.bps())).is_ok() {
+                    keyboard.lock(|kb| kb.baud_rate_applied());
+                }
+            }
+
             // Send LED patterns update for processing later
             match leds_update {
                 keyboard::LedsUpdate::Controller(update) => {
+                    let t = keyboard.lock(|kb| kb.led_time(t));
                     if update_leds_state::spawn(t, update).is_err() {
                         defmt::error!("Spawn failed: update_leds_state");
                     }
                 },
-                keyboard::LedsUpdate::FromOther(colors) => {
+                keyboard::LedsUpdate::FromOther(colors, pressed) => {
+                    let spawn = colors.is_some() || pressed.is_some();
                     if let Some(colors) = colors {
                         led_forced_colors.lock(|c| c.replace(colors));
+                    }
+                    if let Some(pressed) = pressed {
+                        led_local_pressed.lock(|p| p.replace(pressed));
+                    }
+                    if spawn {
                         force_led_colors::spawn().ok();
                     }
                 },
@@ -439,6 +648,157 @@ mod app {
         });
     }
 
+    /// Write the next part of key usage statistics to flash
+    ///
+    /// CPU stalls on instruction fetches while flash is being erased (up to ~40 ms) or programmed
+    /// (a few ms), so it does not matter that the keyboard is locked for the whole time. The stall
+    /// also holds off feeding the watchdog, so this is spawned from idle right after feeding it,
+    /// and the page erase and programming are done in separate runs, each one after a feed.
+    /// The LED DMA interrupt would not be able to refill buffers in time, so the write is done only
+    /// between LED transfers, holding off the next one. If a transfer is in progress the flush
+    /// stays pending and is retried after the next feed.
+    #[task(priority = 1, shared = [keyboard, spi_tx], local = [flash])]
+    fn flush_key_stats(cx: flush_key_stats::Context) {
+        let flush_key_stats::SharedResources { keyboard, spi_tx } = cx.shared;
+        (keyboard, spi_tx).lock(|kb, spi_tx| {
+            if !spi_tx.is_ready() {
+                return;
+            }
+            let stats = kb.key_stats_mut();
+            if let Err(e) = bsp::stats_flash::store(cx.local.flash, stats) {
+                defmt::error!("Writing key statistics failed: {}", e);
+                stats.take_flush();
+            }
+        });
+    }
+
+    /// Send statistics requested over raw HID
+    #[task(
+        priority = 1,
+        capacity = 1,
+        shared = [usb, keyboard, serial_tx, serial_rx, serial_rx_queue, &tasks],
+        local = [task_counts: debug::counters::CounterDeltas<{ TaskCounters::N_COUNTERS }> = debug::counters::CounterDeltas::new()],
+    )]
+    fn send_stats(cx: send_stats::Context, request: keyboard::diagnostics::StatsRequest) {
+        use keyboard::diagnostics::{StatsPage, StatsResponse};
+
+        let send_stats::LocalResources { task_counts } = cx.local;
+        let send_stats::SharedResources { mut usb, mut keyboard, mut serial_tx, mut serial_rx, mut serial_rx_queue, tasks } = cx.shared;
+        let report = match request.page {
+            StatsPage::Link | StatsPage::Line => (&mut serial_rx, &mut serial_rx_queue).lock(|serial_rx, rx| {
+                // Line errors are counted by UART and only moved to stats on request
+                rx.add_line_errors(&serial_rx.take_line_errors());
+                let report = match request.page {
+                    StatsPage::Link => StatsResponse::link(rx.stats()),
+                    _ => StatsResponse::line(&rx.stats().line),
+                };
+                if request.reset {
+                    rx.reset_stats();
+                }
+                report
+            }),
+            StatsPage::HidQueues => keyboard.lock(|kb| {
+                let report = StatsResponse::hid_queues(&kb.hid_queue_stats());
+                if request.reset {
+                    kb.reset_hid_queue_stats();
+                }
+                report
+            }),
+            StatsPage::Tasks => {
+                let counters = task_counts.update(tasks.counters());
+                StatsResponse::tasks(&counters)
+            },
+            StatsPage::Queues => {
+                (&mut serial_tx, &mut serial_rx_queue, &mut keyboard).lock(|tx, rx, kb| {
+                    let report = StatsResponse::queues(&queue_watermarks(tx, rx, kb));
+                    if request.reset {
+                        tx.reset_watermark();
+                        rx.reset_watermark();
+                        kb.reset_hid_queue_watermarks();
+                    }
+                    report
+                })
+            },
+        };
+        usb.lock(|usb| usb.write_raw_report(&report));
+    }
+
+    /// Maximum usage of serial TX, serial RX, keyboard and consumer HID report queues
+    fn queue_watermarks(tx: &SerialTx, rx: &SerialRxQueue, kb: &Keyboard) -> [Watermark; 4] {
+        let [kbd, consumer] = kb.hid_queue_watermarks();
+        [*tx.watermark(), *rx.watermark(), kbd, consumer]
+    }
+
+    /// Short flash of all LEDs after joystick plane change: cyan for pointer, amber for scrolling
+    ///
+    /// Played on both halves, a quick change replaces the previous flash.
+    fn joystick_plane_overlay(plane: keyboard::mouse::Plane) -> keyboard::leds::Overlay {
+        use keyboard::{leds::{Overlay, OverlayId, SOLID}, mouse::Plane};
+        let color = match plane {
+            Plane::Xy => rgb::RGB8::new(0, 200, 255),
+            Plane::Scroll => rgb::RGB8::new(255, 140, 0),
+        };
+        Overlay::all(&SOLID)
+            .tint(color)
+            .duration(TICK_RATE.from_ms(JOYSTICK_PLANE_LED_DURATION_MS))
+            .id(OverlayId::JoystickPlane)
+    }
+
+    /// Send snapshot of currently displayed LED colors requested over raw HID
+    #[task(priority = 1, capacity = 1, shared = [usb, led_output])]
+    fn send_led_colors(cx: send_led_colors::Context, request: keyboard::diagnostics::ColorsRequest) {
+        let send_led_colors::SharedResources { mut usb, mut led_output } = cx.shared;
+        let report = led_output.lock(|out| request.response(&out.current(request.side).colors));
+        usb.lock(|usb| usb.write_raw_report(&report));
+    }
+
+    /// Send answer to host software that has been prepared by keyboard logic
+    #[task(priority = 1, capacity = 1, shared = [usb])]
+    fn send_raw_report(cx: send_raw_report::Context, report: keyboard::hid::RawReport) {
+        let send_raw_report::SharedResources { mut usb } = cx.shared;
+        usb.lock(|usb| usb.write_raw_report(&report));
+    }
+
+    /// Mouse emulation running on its own schedule, independent of the tick timer
+    ///
+    /// The task re-schedules itself using the monotonic timer, period comes from the mouse
+    /// prescaler. Elapsed time is measured, so that mouse speeds stay correct even when
+    /// the task is delayed by higher priority tasks.
+    #[task(
+        priority = 2,
+        capacity = 1,
+        shared = [usb, keyboard, prescalers, &tasks],
+        local = [
+            last: Option<clock::Instant> = None,
+            ticks: keyboard::ticks::TickCounter = keyboard::ticks::TickCounter::new(TICK_RATE),
+        ],
+    )]
+    fn mouse_tick(cx: mouse_tick::Context) {
+        let mouse_tick::SharedResources { usb, mut keyboard, mut prescalers, tasks } = cx.shared;
+        let mouse_tick::LocalResources { last, ticks } = cx.local;
+
+        // Schedule next run first, so that processing time does not extend the period
+        let prescaler = prescalers.lock(|p| p.mouse);
+        let period_ms = if prescaler == 0 {
+            MOUSE_DISABLED_POLL_MS
+        } else {
+            (prescaler as u64 * 1000 / TICK_RATE.hz() as u64).max(1) as u32
+        };
+        if mouse_tick::spawn_after((period_ms as u64).millis()).is_err() {
+            defmt::error!("Spawn failed: mouse_tick");
+        }
+
+        let now = clock::Instant::now();
+        let elapsed = last.replace(now).map(|last| ticks.advance((now - last).as_micros()));
+        if prescaler == 0 {
+            return;
+        }
+
+        tasks.mouse(|| {
+            keyboard.lock(|kb| kb.mouse_tick(elapsed.unwrap_or(prescaler), usb));
+        });
+    }
+
     #[task(priority = 1, shared = [keyboard, &tasks], local = [joy, certainty: u8 = 0])]
     fn read_joystick(cx: read_joystick::Context) {
         let read_joystick::LocalResources { joy, certainty } = cx.local;
@@ -447,6 +807,33 @@ mod app {
             const MAX: u8 = 10;
             const MARGIN: u8 = 2;
 
+            // No need to sample ADC when the whole keyboard sleeps or joystick is disabled
+            let (sampling, deep_sleep, deadzone) = keyboard.lock(|kb| (
+                kb.power_state().joystick_enabled() && kb.joystick_enabled(),
+                kb.power_state() == keyboard::PowerState::DeepSleep && kb.joystick_enabled(),
+                kb.joystick_wake_deadzone(),
+            ));
+            if !sampling {
+                // In deep sleep let ADC watch the joystick, so that moving it wakes the keyboard.
+                // ADC interrupt spawns this task when triggered.
+                if joy.wake_triggered() {
+                    if let Err(e) = joy.disarm_wake() {
+                        defmt::warn!("Joystick wake disarm: {}", e);
+                    }
+                    keyboard.lock(|kb| kb.joystick_wake());
+                } else if deep_sleep && !joy.is_wake_armed() && *certainty >= MAX - MARGIN {
+                    if let Err(e) = joy.arm_wake(deadzone) {
+                        defmt::warn!("Joystick wake arm: {}", e);
+                    }
+                }
+                return;
+            }
+            if joy.is_wake_armed() {
+                if let Err(e) = joy.disarm_wake() {
+                    defmt::warn!("Joystick wake disarm: {}", e);
+                }
+            }
+
             // When we are not certain that joystick exists use zeroes
             let xy = if *certainty >= MAX - MARGIN {
                 joy.read_xy()
@@ -477,111 +864,453 @@ mod app {
             tasks,
         } = cx.shared;
         tasks.leds_state_update(|| {
-            led_controller.lock(|ledctl| update.apply(t, ledctl));
-            led_output.lock(|out| out.use_from_controller());
+            (&mut led_controller, &mut led_output).lock(|ledctl, out| {
+                update.apply(t, ledctl, out);
+                out.use_from_controller();
+            });
         });
     }
 
-    #[task(priority = 1, shared = [led_output, led_forced_colors, &tasks])]
+    #[task(priority = 1, shared = [led_output, led_forced_colors, led_local_pressed, &tasks])]
     fn force_led_colors(cx: force_led_colors::Context) {
-        let force_led_colors::SharedResources { mut led_output, mut led_forced_colors, tasks } = cx.shared;
+        let force_led_colors::SharedResources {
+            mut led_output,
+            mut led_forced_colors,
+            mut led_local_pressed,
+            tasks,
+        } = cx.shared;
         tasks.led_colors_force(|| {
             if let Some(colors) = led_forced_colors.lock(|c| c.take()) {
                 led_output.lock(|out| out.use_from_other_half(&colors));
             }
+            if let Some(pressed) = led_local_pressed.lock(|p| p.take()) {
+                led_output.lock(|out| out.set_local_pressed(pressed));
+            }
         });
     }
 
-    #[task(priority = 1, shared = [&board_side, spi_tx, serial_tx_queue, crc, led_controller, led_output, &tasks])]
+    #[task(priority = 1, shared = [&board_side, spi_tx, led_serializer, serial_tx_queue, keyboard, led_controller, led_output, dfu_countdown, &tasks, &health], local = [leds_crc])]
     fn leds_tick(cx: leds_tick::Context, t: u32) {
         let leds_tick::SharedResources {
             board_side,
             mut spi_tx,
-            serial_tx_queue,
-            crc,
-            led_controller,
+            mut led_serializer,
+            mut serial_tx_queue,
+            mut keyboard,
+            mut led_controller,
             mut led_output,
+            mut dfu_countdown,
             tasks,
+            health,
         } = cx.shared;
 
         tasks.led_spi_output(|| {
-            // Generate LED colors
-            (&mut led_output, led_controller).lock(|out, ctl| {
+            health.checkin(Monitored::Leds as usize, now_ms());
+
+            // Generate LED colors, using time synchronized with master
+            let (t, blackout, dwell, notifications, overrides, plane) = keyboard.lock(|kb| {
+                let plane = kb.take_joystick_plane_change();
+                (kb.led_time(t), kb.leds_blackout(), kb.dwell_indicator(), kb.take_notifications(), kb.take_led_overrides(), plane)
+            });
+            let countdown = dfu_countdown.lock(|c| *c);
+            (&mut led_output, &mut led_controller).lock(|out, ctl| {
+                notifications.into_iter().for_each(|n| out.notify(n));
+                overrides.iter().for_each(|o| out.apply_override_request(o));
+                if let Some(plane) = plane {
+                    out.play(joystick_plane_overlay(plane));
+                }
+                out.set_blackout(blackout);
+                out.set_dwell(dwell);
+                out.set_countdown(countdown);
                 out.tick(t, ctl);
             });
 
-            // Send colors for other side over UART, drop message if queue is full
+            // Send colors for other side over UART, drop message if queue is full; in deep
+            // sleep the other half already received its last (disabled) colors
+            let transmit = led_controller.lock(|ctl| ctl.power_state().led_transmission_enabled());
+            // When supported send much smaller controller state and let the other half generate colors
+            let state = keyboard.lock(|kb| kb.remote_leds())
+                .then(|| led_controller.lock(|ctl| ctl.controller_state()));
             led_output.lock(|out| {
                 if out.using_from_controller() {
-                    if let Some(colors) = out.get_for_transmission(t, board_side.other()) {
-                        (crc, serial_tx_queue).lock(|crc, tx| tx.send(crc, colors));
+                    if let Some(state) = state {
+                        // Entering deep sleep must still reach the other half, e.g. for heartbeat
+                        if let Some(state) = state.and_then(|s| out.get_state_for_transmission(t, s)) {
+                            serial_tx_queue.lock(|tx| tx.send(cx.local.leds_crc, state));
+                        }
+                        if transmit {
+                            if let Some(overlays) = out.get_overlays_for_transmission(t, board_side.other()) {
+                                serial_tx_queue.lock(|tx| tx.send(cx.local.leds_crc, overlays));
+                            }
+                        }
+                    } else if transmit {
+                        if let Some(colors) = out.get_for_transmission(t, board_side.other()) {
+                            serial_tx_queue.lock(|tx| tx.send(cx.local.leds_crc, colors));
+                        }
                     }
                 }
             });
 
-            // Send in separate lock to decrease time when serial tx is locked
-            led_output.lock(|out| {
-                let colors = out.current(*board_side);
-
-                // Prepare data to be sent and start DMA transfer.
-                // `leds` must be kept locked because we're serializing from reference.
-                spi_tx.lock(|spi_tx| {
-                    // Fails on first call because we start an immediate transfer in init()
-                    // TODO: try to use .serialize()
-                    let ok = spi_tx.push(|buf| colors.serialize_to_slice(buf)).is_ok();
-
-                    if !ok {
-                        defmt::warn!("Trying to serialize new data but DMA transfer is not finished");
-                    } else {
-                        spi_tx.start()
-                            .map_err(drop)
-                            .expect("If we were able to serialize we must be able to start!");
-                    }
-                });
+            // Take a snapshot of colors, so that LEDs stay locked only for a copy
+            let serializer = led_output.lock(|out| out.current(*board_side).serializer());
+
+            // Start DMA transfer, data is serialized in chunks as DMA consumes them
+            (&mut spi_tx, &mut led_serializer).lock(|spi_tx, led_serializer| {
+                // Fails on first call because we start an immediate transfer in init()
+                if !spi_tx.is_ready() {
+                    defmt::warn!("Trying to serialize new data but DMA transfer is not finished");
+                } else {
+                    *led_serializer = serializer;
+                    spi_tx.start(|buf| led_serializer.fill(buf))
+                        .map_err(drop)
+                        .expect("If transfer is finished we must be able to start!");
+                }
             });
         });
     }
 
     #[task(
         priority = 1,
-        shared = [serial_rx_queue, &tasks],
-        local = [stats: Option<ioqueue::Stats> = None]
+        shared = [serial_tx, serial_rx, serial_rx_queue, keyboard, &tasks],
+        local = [
+            stats: Option<ioqueue::Stats> = None,
+            stack: debug::mem::StackWatermark = debug::mem::StackWatermark::new(),
+            last_report: Option<clock::Instant> = None,
+            queues: Option<[Watermark; 4]> = None,
+            task_counts: debug::counters::CounterDeltas<{ TaskCounters::N_COUNTERS }> = debug::counters::CounterDeltas::new(),
+        ]
     )]
     fn debug_report(cx: debug_report::Context) {
-        let debug_report::LocalResources { stats } = cx.local;
-        let debug_report::SharedResources { mut serial_rx_queue, tasks } = cx.shared;
+        let debug_report::LocalResources { stats, stack, queues, task_counts, .. } = cx.local;
+        let debug_report::SharedResources { mut serial_tx, mut serial_rx, mut serial_rx_queue, mut keyboard, tasks } = cx.shared;
 
         tasks.debug_report(|| {
             let old = stats.get_or_insert_with(|| Default::default());
-            let new = serial_rx_queue.lock(|rx| rx.stats().clone());
+            let line_errors = serial_rx.lock(|rx| rx.take_line_errors());
+            let new = serial_rx_queue.lock(|rx| {
+                rx.add_line_errors(&line_errors);
+                rx.stats().clone()
+            });
             if &new != old {
                 defmt::warn!("RX stats: {}", new);
                 *old = new;
             }
 
             if cfg!(feature = "task-counters") {
-                defmt::info!("tim={=u16} usb={=u16} kbd={=u16} joy={=u16} ledsU={=u16} ledsF={=u16} ledsT={=u16} dma_spi={=u16} dma_uart={=u16} uart={=u16} idle={=u16}",
-                    tasks.timer.pop(), tasks.usb_poll.pop(), tasks.keyboard.pop(), tasks.joystick.pop(), tasks.leds_state_update.pop(), tasks.led_colors_force.pop(),
-                    tasks.led_spi_output.pop(), tasks.dma_spi_interrupt.pop(), tasks.dma_uart_interrupt.pop(), tasks.uart_interrupt.pop(), tasks.idle.pop(),
+                // In order of TaskCounters
+                let c = task_counts.update(tasks.counters());
+                defmt::info!("tim={=u16} usb={=u16} kbd={=u16} joy={=u16} mouse={=u16} ledsU={=u16} ledsF={=u16} ledsT={=u16} dma_spi={=u16} dma_uart={=u16} uart={=u16} idle={=u16}",
+                    c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7], c[8], c[9], c[10], c[11],
+                );
+            }
+
+            #[cfg(feature = "task-profiling")]
+            {
+                use debug::counters::Load;
+
+                let t = [
+                    tasks.timer.pop_time(), tasks.usb_poll.pop_time(), tasks.keyboard.pop_time(), tasks.joystick.pop_time(), tasks.mouse.pop_time(),
+                    tasks.leds_state_update.pop_time(), tasks.led_colors_force.pop_time(), tasks.led_spi_output.pop_time(), tasks.dma_spi_interrupt.pop_time(),
+                    tasks.dma_uart_interrupt.pop_time(), tasks.uart_interrupt.pop_time(),
+                ];
+                defmt::info!("exec us (min/avg/max): tim={} usb={} kbd={} joy={} mouse={} ledsU={} ledsF={} ledsT={} dma_spi={} dma_uart={} uart={}",
+                    t[0], t[1], t[2], t[3], t[4], t[5], t[6], t[7], t[8], t[9], t[10],
                 );
+
+                // First report has no reference, statistics were collected since boot
+                let now = clock::Instant::now();
+                if let Some(period) = cx.local.last_report.replace(now).map(|last| now.duration_since(last)) {
+                    let load = |i: usize| Load::new(t[i].busy(), period);
+                    let free = Load::headroom(&t, period);
+                    defmt::info!("cpu load: tim={} usb={} kbd={} joy={} mouse={} ledsU={} ledsF={} ledsT={} dma_spi={} dma_uart={} uart={} free={}",
+                        load(0), load(1), load(2), load(3), load(4), load(5), load(6), load(7), load(8), load(9), load(10),
+                        free,
+                    );
+                    if free.permille() < 100 {
+                        defmt::warn!("CPU close to saturation");
+                    }
+                }
             }
 
+            let watermarks = (&mut serial_tx, &mut serial_rx_queue, &mut keyboard)
+                .lock(|tx, rx, kb| queue_watermarks(tx, rx, kb));
+            if let Some([tx, rx, kbd, consumer]) = queues.if_changed(&watermarks) {
+                defmt::info!("Queue max usage: tx={=u16}/{=u16} rx={=u16}/{=u16} kbd={=u16}/{=u16} consumer={=u16}/{=u16}",
+                    tx.max(), tx.capacity(), rx.max(), rx.capacity(),
+                    kbd.max(), kbd.capacity(), consumer.max(), consumer.capacity(),
+                );
+                if [tx, rx, kbd, consumer].iter().any(|wm| wm.percent() >= 90) {
+                    defmt::warn!("Queue close to overflow");
+                }
+            }
+
+            if let Some(latency) = keyboard.lock(|kb| kb.take_latency_stats()) {
+                defmt::info!("Key latency: {}", latency);
+            }
+
+            stack.report();
+
             if cfg!(feature = "stack-usage") {
                 debug::mem::print_stack_info();
             }
         });
     }
 
-    #[task(binds = DMA1_CH4_5_6_7, priority = 4, shared = [spi_tx, &tasks])]
+    #[task(
+        binds = USART2,
+        priority = 1,
+        shared = [serial_rx_queue, spi_tx, keyboard, led_controller, led_output, prescalers, &tasks],
+        local = [
+            console,
+            line: debug::shell::LineBuffer = debug::shell::LineBuffer::new(),
+            task_counts: debug::counters::CounterDeltas<{ TaskCounters::N_COUNTERS }> = debug::counters::CounterDeltas::new(),
+        ],
+    )]
+    fn debug_shell(cx: debug_shell::Context) {
+        use ufmt::{uwrite, uwriteln};
+        use debug::shell::{Command, ParseError};
+
+        let debug_shell::LocalResources { console, line, task_counts } = cx.local;
+        let debug_shell::SharedResources {
+            mut serial_rx_queue,
+            mut spi_tx,
+            mut keyboard,
+            mut led_controller,
+            mut led_output,
+            mut prescalers,
+            tasks,
+        } = cx.shared;
+        let console = match console {
+            Some(console) => console,
+            None => return,
+        };
+
+        while let Some(byte) = console.read() {
+            // Echo back
+            uwrite!(console, "{}", byte as char).ok();
+
+            let result = match line.push(byte) {
+                Some(result) => result,
+                None => continue,
+            };
+            uwrite!(console, "\r\n").ok();
+
+            match result {
+                Ok(Command::Help) => uwrite!(console, "{}", debug::shell::HELP).ok(),
+                Ok(Command::Stats) => {
+                    let s = serial_rx_queue.lock(|rx| rx.stats().clone());
+                    uwriteln!(console, "queue_overflows={} acc_overflows={} cobs={} checksum={} deser={} retransmissions={} unknown={}\r",
+                        s.queue_overflows, s.accumulator_overflows, s.cobs_errors, s.checksum_errors,
+                        s.deser_errors, s.ignored_retransmissions, s.unknown_packets).ok();
+                    uwriteln!(console, "framing={} noise={} parity={} overrun={} dropped={} spurious_dma={}\r",
+                        s.line.framing, s.line.noise, s.line.parity, s.line.overrun, s.line.dropped_bytes,
+                        s.line.spurious_dma).ok();
+                    let lost = keyboard.lock(|kb| kb.lost_key_packets());
+                    let spi_errors = spi_tx.lock(|spi_tx| spi_tx.errors());
+                    uwriteln!(console, "lost_key_packets={} led_spi_errors={}\r", lost, spi_errors).ok();
+                    let [kbd, consumer] = keyboard.lock(|kb| kb.hid_queue_stats());
+                    uwriteln!(console, "hid_kbd={}/{}/{} hid_consumer={}/{}/{} (pushed/sent/dropped)\r",
+                        kbd.pushed, kbd.sent, kbd.dropped, consumer.pushed, consumer.sent, consumer.dropped).ok();
+                    if cfg!(feature = "task-counters") {
+                        let counts = task_counts.update(tasks.counters());
+                        for (name, count) in TaskCounters::NAMES.iter().zip(counts) {
+                            uwrite!(console, "{}={} ", name, count).ok();
+                        }
+                        uwrite!(console, "\r\n").ok();
+                    }
+                    None
+                },
+                Ok(Command::StatsReset) => {
+                    serial_rx_queue.lock(|rx| rx.reset_stats());
+                    keyboard.lock(|kb| kb.reset_hid_queue_stats());
+                    None
+                },
+                Ok(Command::Config) => {
+                    uwrite!(console, "config=").ok();
+                    bsp::ident::write_hex_u32(console, config::CHECKSUM).ok();
+                    uwrite!(console, "\r\n").ok()
+                },
+                Ok(Command::Brightness(value)) => {
+                    let brightness = led_controller.lock(|leds| {
+                        if let Some(value) = value {
+                            leds.set_brightness(value);
+                        }
+                        leds.brightness()
+                    });
+                    uwriteln!(console, "brightness={}\r", brightness).ok()
+                },
+                Ok(Command::LedOverride(side, led, color)) => {
+                    led_output.lock(|out| out.set_override(side, led, color));
+                    None
+                },
+                Ok(Command::LedClear) => {
+                    led_output.lock(|out| out.clear_overrides());
+                    None
+                },
+                Ok(Command::Joystick(enabled)) => {
+                    let enabled = keyboard.lock(|kb| {
+                        kb.set_joystick_enabled(enabled.unwrap_or(!kb.joystick_enabled()));
+                        kb.joystick_enabled()
+                    });
+                    uwriteln!(console, "joystick={}\r", enabled).ok()
+                },
+                Ok(Command::Prescaler(task, value)) => {
+                    let prescaler = prescalers.lock(|p| {
+                        let prescaler = p.get_mut(task);
+                        if let Some(value) = value {
+                            *prescaler = value;
+                        }
+                        *prescaler
+                    });
+                    uwriteln!(console, "prescaler={}\r", prescaler).ok()
+                },
+                Ok(Command::Role(role)) => {
+                    let forced = role.is_some();
+                    let role = keyboard.lock(|kb| {
+                        kb.force_role(role);
+                        kb.role()
+                    });
+                    let name = match role {
+                        keyboard::leds::Role::Master => "master",
+                        keyboard::leds::Role::Slave => "slave",
+                    };
+                    uwriteln!(console, "role={} forced={}\r", name, forced).ok()
+                },
+                Ok(Command::Events) => {
+                    // Copy to avoid blocking keyboard task when printing
+                    let (enabled, events) = keyboard.lock(|kb| {
+                        let log = kb.event_log();
+                        (log.is_enabled(), log.iter().copied().collect::<heapless::Vec<_, { keyboard::event_log::EVENT_LOG_LEN }>>())
+                    });
+                    if !enabled {
+                        uwrite!(console, "event log disabled\r\n").ok();
+                    }
+                    for e in events.iter() {
+                        let (kind, (i, j)) = match e.event {
+                            keyberon::layout::Event::Press(i, j) => ("press", (i, j)),
+                            keyberon::layout::Event::Release(i, j) => ("release", (i, j)),
+                        };
+                        uwriteln!(console, "{} {} {} {}\r", e.time, kind, i, j).ok();
+                    }
+                    None
+                },
+                Ok(Command::Health) => {
+                    type Keys = heapless::Vec<((u8, u8), u8), { bsp::NROWS * bsp::NCOLS }>;
+                    let (stuck, chatter) = keyboard.lock(|kb| {
+                        let health = kb.matrix_health();
+                        let stuck: Keys = health.stuck().map(|ij| (ij, 0)).collect();
+                        let chatter: Keys = health.chatter().collect();
+                        (stuck, chatter)
+                    });
+                    for ((i, j), _) in stuck.iter() {
+                        uwriteln!(console, "stuck {} {}\r", i, j).ok();
+                    }
+                    for ((i, j), cnt) in chatter.iter() {
+                        uwriteln!(console, "chatter {} {}: {}\r", i, j, cnt).ok();
+                    }
+                    None
+                },
+                Ok(Command::KeyStats) => {
+                    // Copy to avoid blocking keyboard task when printing
+                    type Presses = heapless::Vec<((u8, u8), u32), { 2 * bsp::NROWS * bsp::NCOLS }>;
+                    type LayerTime = heapless::Vec<keyboard::ticks::Millis, { config::N_LAYERS }>;
+                    let (enabled, presses, layers) = keyboard.lock(|kb| {
+                        let stats = kb.key_stats();
+                        let presses: Presses = stats.presses().collect();
+                        let layers: LayerTime = stats.layer_time().collect();
+                        (stats.is_enabled(), presses, layers)
+                    });
+                    if !enabled {
+                        uwrite!(console, "key statistics disabled\r\n").ok();
+                    }
+                    for (l, time) in layers.iter().enumerate() {
+                        uwriteln!(console, "layer {}: {} ms\r", l, time.0).ok();
+                    }
+                    for ((i, j), n) in presses.iter() {
+                        uwriteln!(console, "key {} {}: {}\r", i, j, n).ok();
+                    }
+                    None
+                },
+                Ok(Command::KeyStatsWipe) => {
+                    keyboard.lock(|kb| kb.key_stats_mut().wipe());
+                    None
+                },
+                Ok(Command::Latency(enabled)) => {
+                    keyboard.lock(|kb| kb.set_latency_measurement(enabled));
+                    uwriteln!(console, "latency={}\r", enabled).ok()
+                },
+                Ok(Command::HoldTap(key)) => {
+                    keyboard.lock(|kb| kb.set_hold_tap_trace(key));
+                    match key {
+                        Some((row, col)) => uwriteln!(console, "holdtap={},{}\r", row, col).ok(),
+                        None => uwriteln!(console, "holdtap=off\r").ok(),
+                    }
+                },
+                Ok(Command::Resets) => {
+                    let counters = reset::ResetCounters::load();
+                    for cause in reset::ResetCause::ALL {
+                        uwriteln!(console, "{}={}\r", cause.name(), counters.get(cause)).ok();
+                    }
+                    let reboots = reboot::RebootCounters::load();
+                    for reason in reboot::RebootReason::ALL {
+                        uwriteln!(console, "reboot-{}={}\r", reason.name(), reboots.get(reason)).ok();
+                    }
+                    uwriteln!(console, "reboot-watchdog={}\r", reboots.watchdog()).ok();
+                    None
+                },
+                Err(ParseError::UnknownCommand) => uwrite!(console, "unknown command, try: help\r\n").ok(),
+                Err(ParseError::InvalidArgument) => uwrite!(console, "invalid arguments\r\n").ok(),
+                Err(ParseError::LineTooLong) => uwrite!(console, "line too long\r\n").ok(),
+                Err(ParseError::Empty) => None,
+            };
+        }
+    }
+
+    /// Key press while matrix is idle (columns on EXTI lines 0-1)
+    ///
+    /// Wakes up the MCU, keyboard task resumes scanning when it notices that interrupts got masked.
+    #[task(binds = EXTI0_1, priority = 2)]
+    fn matrix_wake_0_1(_: matrix_wake_0_1::Context) {
+        bsp::matrix_wake::on_interrupt();
+    }
+
+    /// Joystick left its dead zone while being watched by ADC
+    #[task(binds = ADC_COMP, priority = 2)]
+    fn joystick_wake(_: joystick_wake::Context) {
+        // Only fails if already pending, which handles the wake up as well
+        if joystick::on_interrupt() {
+            read_joystick::spawn().ok();
+        }
+    }
+
+    /// Auxiliary key press while matrix is idle (EXTI lines 2-3)
+    #[task(binds = EXTI2_3, priority = 2)]
+    fn matrix_wake_2_3(_: matrix_wake_2_3::Context) {
+        bsp::matrix_wake::on_interrupt();
+    }
+
+    /// Key press while matrix is idle (columns on EXTI lines 4-15)
+    #[task(binds = EXTI4_15, priority = 2)]
+    fn matrix_wake_4_15(_: matrix_wake_4_15::Context) {
+        bsp::matrix_wake::on_interrupt();
+    }
+
+    #[task(binds = DMA1_CH4_5_6_7, priority = 4, shared = [spi_tx, led_serializer, &tasks])]
     fn dma_spi_callback(cx: dma_spi_callback::Context) {
-        let dma_spi_callback::SharedResources { mut spi_tx, tasks } = cx.shared;
+        let dma_spi_callback::SharedResources { spi_tx, led_serializer, tasks } = cx.shared;
         tasks.dma_spi_interrupt(|| {
-            spi_tx.lock(|spi_tx|
-                spi_tx.on_interrupt()
-                    .as_option()
-                    .transpose()
-                    .expect("SPI DMA error")
-            );
+            let errors = (spi_tx, led_serializer).lock(|spi_tx, led_serializer| {
+                if spi_tx.on_interrupt(|buf| led_serializer.fill(buf)) == dma::InterruptResult::Error {
+                    // Single LED frame is not worth a panic, drop it and continue
+                    spi_tx.recover();
+                    Some(spi_tx.errors())
+                } else {
+                    None
+                }
+            });
+            if let Some(errors) = errors {
+                defmt::warn!("SPI DMA error, LED frame dropped (errors: {=u32})", errors);
+            }
         });
     }
 
@@ -604,11 +1333,15 @@ mod app {
                 }
 
                 if rx_done.or(tx_done).is_none() {
-                    // This happens sometimes, not sure why but it seems that TX half-transfer
-                    // interrupt triggers this handler even though it is disabled.
+                    // Interrupt re-triggered before flags were cleared or caused by an event with
+                    // disabled interrupt (TX half transfer). Count it (reported with RX stats)
+                    // and make sure that no unexpected interrupt stays enabled.
                     let dma = unsafe { &*hal::pac::DMA1::ptr() };
-                    defmt::warn!("No UART DMA handled: ISR={0=28..32:04b}_{0=24..28:04b}__{0=20..24:04b}_{0=16..20:04b}__{0=12..16:04b}_{0=8..12:04b}__{0=4..8:04b}_{0=0..4:04b}",
-                        dma.isr.read().bits());
+                    let isr = dma.isr.read().bits();
+                    rx.count_spurious_dma();
+                    let cleared = tx.recover_dma();
+                    defmt::debug!("No UART DMA handled (cleared={1=bool}): ISR={0=28..32:04b}_{0=24..28:04b}__{0=20..24:04b}_{0=16..20:04b}__{0=12..16:04b}_{0=8..12:04b}__{0=4..8:04b}_{0=0..4:04b}",
+                        isr, cleared);
                 }
             });
         });
@@ -625,14 +1358,31 @@ mod app {
         });
     }
 
-    #[idle(local = [watchdog], shared = [&tasks])]
+    #[idle(local = [watchdog, stalled: bool = false], shared = [keyboard, &tasks, &health])]
     fn idle(cx: idle::Context) -> ! {
-        let idle::LocalResources { watchdog } = cx.local;
-        let idle::SharedResources { tasks } = cx.shared;
+        let idle::LocalResources { watchdog, stalled } = cx.local;
+        let idle::SharedResources { mut keyboard, tasks, health } = cx.shared;
 
         loop {
             tasks.idle();
-            watchdog.maybe_feed();
+
+            // Withhold feeding when any task stalled, so that watchdog resets the MCU
+            let now = now_ms();
+            let fed = match health.stalled(now) {
+                None => watchdog.maybe_feed(now),
+                Some(task) if !*stalled => {
+                    defmt::error!("Task stalled: {=usize}", task);
+                    *stalled = true;
+                    false
+                },
+                Some(_) => false,
+            };
+
+            // Flash writes stall the CPU, so start them right after a feed, leaving the whole
+            // watchdog period for the stall
+            if (fed || !WATCHDOG_ENABLED) && keyboard.lock(|kb| kb.key_stats().flush_pending()) {
+                flush_key_stats::spawn().ok();
+            }
 
             if cfg!(feature = "idle-sleep") {
                 rtic::export::wfi();
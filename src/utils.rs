@@ -63,6 +63,16 @@ impl<'a, T> CircularIter<'a, T> {
     pub fn current(&self) -> &'a T {
         &self.slice[self.index]
     }
+
+    /// Get index of the current element
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Point at element with given index, wrapping around if out of range
+    pub fn set_index(&mut self, index: usize) {
+        self.index = index % self.slice.len();
+    }
 }
 
 impl<'a, T> Iterator for CircularIter<'a, T> {
@@ -108,6 +118,46 @@ where
     }
 }
 
+/// Maximum of sampled usage of a queue or buffer with given capacity
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Watermark {
+    max: u16,
+    capacity: u16,
+}
+
+impl Watermark {
+    pub const fn new(capacity: usize) -> Self {
+        Self { max: 0, capacity: capacity as u16 }
+    }
+
+    /// Record current usage, return true if it is higher than all the previous ones
+    pub fn update(&mut self, used: usize) -> bool {
+        let used = used.min(u16::MAX as usize) as u16;
+        let higher = used > self.max;
+        self.max = self.max.max(used);
+        higher
+    }
+
+    /// Maximum usage since creation or the last [`Self::reset`]
+    pub fn max(&self) -> u16 {
+        self.max
+    }
+
+    pub fn capacity(&self) -> u16 {
+        self.capacity
+    }
+
+    /// Maximum usage in percent of capacity
+    pub fn percent(&self) -> u8 {
+        (self.max as u32 * 100).checked_div(self.capacity as u32).unwrap_or(0) as u8
+    }
+
+    pub fn reset(&mut self) {
+        self.max = 0;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,4 +194,20 @@ mod tests {
         assert_eq!(val.if_changed(&30), None);
         assert_eq!(val.if_changed(&30), None);
     }
+
+    #[test]
+    fn watermark() {
+        let mut wm = Watermark::new(400);
+        assert_eq!(wm.max(), 0);
+        assert!(wm.update(100));
+        assert!(!wm.update(100));
+        assert!(!wm.update(50));
+        assert!(wm.update(300));
+        assert_eq!(wm.max(), 300);
+        assert_eq!(wm.percent(), 75);
+        wm.reset();
+        assert_eq!(wm.max(), 0);
+        assert_eq!(wm.capacity(), 400);
+        assert_eq!(Watermark::new(0).percent(), 0);
+    }
 }